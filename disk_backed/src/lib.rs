@@ -44,6 +44,7 @@ use rustc_serialize::Encodable;
 use std::collections::hash_map::DefaultHasher;
 use std::error::Error;
 use std::fmt;
+use std::fs;
 use std::fs::OpenOptions;
 use std::hash::Hash;
 use std::hash::Hasher;
@@ -53,6 +54,7 @@ use std::ops::Deref;
 use std::ops::DerefMut;
 use std::path::Path;
 use std::path::PathBuf;
+use std::process;
 
 /// Simple enum for operations, to give more context in error messages
 #[derive(Debug, Clone, Copy)]
@@ -113,16 +115,26 @@ impl Error for DiskBackError {
 type Result<T> = ::std::result::Result<T, DiskBackError>;
 
 /// Convenience function to write serializable data
+///
+/// The data is written to a temporary file that is then renamed over the
+/// original, so readers never see a partly-written file.
 fn write<T>(desc: &str, path: &Path, data: &T) -> Result<()>
 where
     T: Encodable,
 {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(format!(".tmp.{}", process::id()));
+    let temp_path = PathBuf::from(temp_path);
     OpenOptions::new()
         .write(true)
         .create(true)
         .truncate(true)
-        .open(path)
-        .and_then(|mut file| writeln!(file, "{}", json::as_pretty_json(data)))
+        .open(&temp_path)
+        .and_then(|mut file| {
+            writeln!(file, "{}", json::as_pretty_json(data))?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temp_path, path))
         .map_err(|e| DiskBackError::new(Op::Write, desc, path, e))
 }
 
@@ -172,6 +184,7 @@ where
 /// value
 /// - `read_or_default()` reads the file from disk if it exists, or uses the
 /// type's Default
+/// - `in_memory()` uses a provided value, and never touches the disk
 ///
 /// Each constructor takes a `desc` argument. This should be a short
 /// human-readable description of what the data is. It will be used in log and
@@ -194,7 +207,8 @@ where
     T: Encodable + Decodable + Hash,
 {
     desc: String,
-    path: PathBuf,
+    /// None if the data is kept in memory only
+    path: Option<PathBuf>,
     data: T,
     disk_hash: u64,
 }
@@ -206,7 +220,7 @@ where
     fn construct(desc: &str, path: PathBuf, data: T) -> Self {
         DiskBacked {
             desc: desc.to_owned(),
-            path: path,
+            path: Some(path),
             disk_hash: hash(&data),
             data: data,
         }
@@ -216,12 +230,24 @@ where
     pub fn init(desc: &str, path: PathBuf, data: T) -> Self {
         DiskBacked {
             desc: desc.to_owned(),
-            path: path,
+            path: Some(path),
             disk_hash: hash(&data) + 1, // Ensure dirty state
             data: data,
         }
     }
 
+    /// Keep the data in memory only, with no backing file
+    ///
+    /// Flushing, writing, and reloading do nothing.
+    pub fn in_memory(desc: &str, data: T) -> Self {
+        DiskBacked {
+            desc: desc.to_owned(),
+            path: None,
+            disk_hash: hash(&data),
+            data: data,
+        }
+    }
+
     /// Initialize from disk, or return Err if the file does not exist
     pub fn read(desc: &str, path: PathBuf) -> Result<Self> {
         debug!("Reading   {}: {}", desc, path.display());
@@ -239,23 +265,43 @@ where
 
     /// Flush the data to disk, if it hash been updated
     pub fn flush(&mut self) -> Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
         let new_hash = hash(&self.data);
         if new_hash != self.disk_hash {
-            debug!("Flushing  {}: {}", self.desc, self.path.display());
-            write(&self.desc, &self.path, &self.data)?;
+            debug!("Flushing  {}: {}", self.desc, path.display());
+            write(&self.desc, path, &self.data)?;
             self.disk_hash = new_hash;
         } else {
-            trace!("Unchanged {}: {}", self.desc, self.path.display());
+            trace!("Unchanged {}: {}", self.desc, path.display());
+        }
+        Ok(())
+    }
+
+    /// Re-read the data from disk, discarding any unflushed changes
+    ///
+    /// Use this before changing data that another process may have updated
+    /// since it was read. If the file does not exist, the data is kept.
+    pub fn reload(&mut self) -> Result<()> {
+        if let Some(ref path) = self.path {
+            if path.exists() {
+                debug!("Reloading {}: {}", self.desc, path.display());
+                self.data = read(&self.desc, path)?;
+                self.disk_hash = hash(&self.data);
+            }
         }
         Ok(())
     }
 
     /// Write the data to disk, whether it has been updated or not
     pub fn write(&mut self) -> Result<()> {
-        let new_hash = hash(&self.data);
-        debug!("Writing   {}: {}", self.desc, self.path.display());
-        write(&self.desc, &self.path, &self.data)?;
-        self.disk_hash = new_hash;
+        if let Some(ref path) = self.path {
+            debug!("Writing   {}: {}", self.desc, path.display());
+            write(&self.desc, path, &self.data)?;
+            self.disk_hash = hash(&self.data);
+        }
         Ok(())
     }
 }
@@ -508,4 +554,34 @@ mod tests {
         assert!(db.is_err(), "should give error on read if corrupt file");
         // panic!(format!("{}", db.err().unwrap()));
     }
+
+    #[test]
+    fn test_reload() {
+        let temp = TempDir::new("test_disk_backed").unwrap();
+        let path = temp.path().join("backing_file");
+
+        let mut first =
+            DiskBacked::init("string", path.to_owned(), "first".to_owned());
+        first.flush().unwrap();
+
+        let mut second =
+            DiskBacked::<String>::read("string", path.to_owned()).unwrap();
+        second.push_str(" and second");
+        second.flush().unwrap();
+
+        first.reload().unwrap();
+        assert_eq!(first, "first and second", "should see other writer");
+        let leftovers: Vec<_> = fs::read_dir(temp.path()).unwrap().collect();
+        assert_eq!(leftovers.len(), 1, "should not leave temporary files");
+    }
+
+    #[test]
+    fn test_in_memory() {
+        let mut db = DiskBacked::in_memory("string", "first".to_owned());
+        db.push_str(" and second");
+        db.flush().unwrap();
+        db.write().unwrap();
+        db.reload().unwrap();
+        assert_eq!(db, "first and second", "should keep changes in memory");
+    }
 }
@@ -1,4 +1,7 @@
-//! Conversions to human-readable numbers using SI prefixes
+//! Conversions to and from human-readable byte sizes, with base-2 or SI
+//! prefixes, and of durations, transfer rates, and dates
+
+use std::time::Duration;
 
 /// Converts an integer into a human-readable byte size with base-2 prefix
 ///
@@ -37,17 +40,90 @@
 /// ```
 ///
 pub fn human_bytes<N: Into<u64>>(num: N) -> String {
+    human_bytes_with(num, Base::Binary, 1)
+}
+
+/// Converts an integer into a human-readable byte size with SI prefix
+///
+/// Like `human_bytes`, but in powers of 1000, as disk makers count.
+///
+/// ```
+/// use human_readable::human_bytes_si;
+///
+/// assert_eq!(human_bytes_si(999_u32),             "999 bytes");
+/// assert_eq!(human_bytes_si(1000_u32),               "1.0 kB");
+/// assert_eq!(human_bytes_si(1024_u32),               "1.0 kB");
+/// assert_eq!(human_bytes_si(1_500_000_u32),          "1.5 MB");
+/// assert_eq!(human_bytes_si(4_000_000_000_u64),      "4.0 GB");
+/// assert_eq!(human_bytes_si(u64::max_value()),      "18.4 EB");
+/// ```
+///
+pub fn human_bytes_si<N: Into<u64>>(num: N) -> String {
+    human_bytes_with(num, Base::Decimal, 1)
+}
+
+/// Which multiple each prefix stands for
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub enum Base {
+    /// Powers of 1024, with base-2 prefixes: KiB, MiB, GiB...
+    Binary,
+    /// Powers of 1000, with SI prefixes: kB, MB, GB...
+    Decimal,
+}
+
+impl Base {
+    fn step(&self) -> u64 {
+        match *self {
+            Base::Binary => 1024,
+            Base::Decimal => 1000,
+        }
+    }
 
-    let (size, prefix) = human_bytes_f(num);
+    fn prefixes(&self) -> &'static [&'static str] {
+        match *self {
+            Base::Binary => &["", "Ki", "Mi", "Gi", "Ti", "Pi", "Ei"],
+            Base::Decimal => &["", "k", "M", "G", "T", "P", "E"],
+        }
+    }
+}
+
+/// Converts an integer into a human-readable byte size, with the given base
+/// and number of decimal places
+///
+/// ```
+/// use human_readable::Base;
+/// use human_readable::human_bytes_with;
+///
+/// assert_eq!(human_bytes_with(1536_u32, Base::Binary, 2),  "1.50 KiB");
+/// assert_eq!(human_bytes_with(1536_u32, Base::Binary, 0),     "2 KiB");
+/// assert_eq!(human_bytes_with(1234_u32, Base::Decimal, 3), "1.234 kB");
+///
+/// // Plain bytes never have decimal places
+/// assert_eq!(human_bytes_with(999_u32, Base::Decimal, 3), "999 bytes");
+/// ```
+///
+pub fn human_bytes_with<N: Into<u64>>(num: N,
+                                      base: Base,
+                                      precision: usize)
+                                      -> String {
+
+    let (size, prefix) = scale_bytes(num, base);
     match prefix {
         "" => format!("{:0.0} bytes", size),
-        _ => format!("{:0.1} {}B", size, prefix),
+        _ => format!("{:0.*} {}B", precision, size, prefix),
     }
 }
 
 pub fn human_bytes_f<N: Into<u64>>(num: N) -> (f64, &'static str) {
+    scale_bytes(num, Base::Binary)
+}
+
+/// Scales a byte count to the largest prefix it reaches, giving the scaled
+/// number and the prefix
+pub fn scale_bytes<N: Into<u64>>(num: N, base: Base) -> (f64, &'static str) {
 
-    let prefixes = ["", "ki", "Mi", "Gi", "Ti", "Pi", "Ei"];
+    let prefixes = base.prefixes();
+    let step = base.step();
     let pindex_limit = prefixes.len() - 1;
     let num: u64 = num.into();
 
@@ -55,12 +131,276 @@ pub fn human_bytes_f<N: Into<u64>>(num: N) -> (f64, &'static str) {
     let mut rem = 0;
     let mut pindex = 0;
 
-    while mant >= 1024 && pindex < pindex_limit {
-        rem = mant & 0b0011_1111_1111_u64;
-        mant >>= 10;
+    while mant >= step && pindex < pindex_limit {
+        rem = mant % step;
+        mant /= step;
         pindex += 1;
     }
 
-    let f = (mant as f64) + (rem as f64 / 1024_f64);
+    let f = (mant as f64) + (rem as f64 / step as f64);
     (f, prefixes[pindex])
 }
+
+/// Parses a byte size with an optional base-2 suffix, like `500k` or `2MiB`
+///
+/// Suffixes are case-insensitive, and may be given as `k`, `kB`, or `KiB`,
+/// all meaning 1024 bytes. Fractions are allowed with a suffix.
+///
+/// ```
+/// use human_readable::parse_bytes;
+///
+/// assert_eq!(parse_bytes("1000"),   Some(1000));
+/// assert_eq!(parse_bytes("500k"),   Some(500 * 1024));
+/// assert_eq!(parse_bytes("1.5M"),   Some(1536 * 1024));
+/// assert_eq!(parse_bytes("2 MiB"),  Some(2 << 20));
+/// assert_eq!(parse_bytes("1gb"),    Some(1 << 30));
+///
+/// assert_eq!(parse_bytes(""),       None);
+/// assert_eq!(parse_bytes("fast"),   None);
+/// assert_eq!(parse_bytes("-1k"),    None);
+/// assert_eq!(parse_bytes("1.5"),    None);
+/// ```
+///
+pub fn parse_bytes(s: &str) -> Option<u64> {
+    parse_bytes_in(s, Base::Binary)
+}
+
+/// Parses a byte size with an optional SI suffix, like `500k` or `1.5 GB`
+///
+/// This is the inverse of `human_bytes_si`: `k` and `kB` mean 1000 bytes.
+/// Base-2 suffixes such as `KiB` still mean 1024.
+///
+/// ```
+/// use human_readable::parse_bytes_si;
+///
+/// assert_eq!(parse_bytes_si("500k"),    Some(500_000));
+/// assert_eq!(parse_bytes_si("1.5 GB"),  Some(1_500_000_000));
+/// assert_eq!(parse_bytes_si("1.5 GiB"), Some(3 << 29));
+/// assert_eq!(parse_bytes_si("10"),      Some(10));
+/// assert_eq!(parse_bytes_si("1.5"),     None);
+/// ```
+///
+pub fn parse_bytes_si(s: &str) -> Option<u64> {
+    parse_bytes_in(s, Base::Decimal)
+}
+
+/// Parses a byte size, with suffixes that are not base-2 in the given base
+fn parse_bytes_in(s: &str, base: Base) -> Option<u64> {
+
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_digit(10) && c != '.')
+        .unwrap_or(s.len());
+    let (number, suffix) = s.split_at(split);
+    let mut suffix = suffix.trim().to_lowercase();
+    let mut step = base.step();
+    if suffix.ends_with("ib") && suffix.len() == 3 {
+        suffix.truncate(1);
+        step = Base::Binary.step();
+    } else if suffix.ends_with("b") {
+        suffix.pop();
+    }
+
+    let prefixes = ["", "k", "m", "g", "t", "p", "e"];
+    let pindex = match prefixes.iter().position(|p| *p == suffix) {
+        Some(pindex) => pindex,
+        None => return None,
+    };
+    if pindex == 0 {
+        return number.parse().ok();
+    }
+    match number.parse::<f64>() {
+        Ok(f) if f >= 0.0 => {
+            Some((f * (step as f64).powi(pindex as i32)) as u64)
+        }
+        _ => None,
+    }
+}
+
+/// Formats a duration in its one or two largest units
+///
+/// Under a second is given in milliseconds, and under a minute in seconds to
+/// one decimal place. Longer durations are truncated to two whole units.
+///
+/// ```
+/// use human_readable::human_duration;
+/// use std::time::Duration;
+///
+/// assert_eq!(human_duration(Duration::from_millis(0)),          "0ms");
+/// assert_eq!(human_duration(Duration::from_millis(12)),        "12ms");
+/// assert_eq!(human_duration(Duration::from_millis(1500)),      "1.5s");
+/// assert_eq!(human_duration(Duration::from_millis(59_940)),   "59.9s");
+/// assert_eq!(human_duration(Duration::from_secs(133)),       "2m 13s");
+/// assert_eq!(human_duration(Duration::from_secs(3600)),       "1h 0m");
+/// assert_eq!(human_duration(Duration::from_secs(90_061)),     "1d 1h");
+/// ```
+///
+pub fn human_duration(duration: Duration) -> String {
+
+    let secs = duration.as_secs();
+    match secs {
+        0 => format!("{}ms", duration.subsec_nanos() / 1_000_000),
+        _ if secs < 60 => format!("{:0.1}s", float_secs(duration)),
+        _ if secs < 3600 => format!("{}m {}s", secs / 60, secs % 60),
+        _ if secs < 86400 => {
+            format!("{}h {}m", secs / 3600, secs % 3600 / 60)
+        }
+        _ => format!("{}d {}h", secs / 86400, secs % 86400 / 3600),
+    }
+}
+
+/// Formats the rate of moving a number of bytes over a duration
+///
+/// If no time has passed, the rate is unknown, shown as `-/s`.
+///
+/// ```
+/// use human_readable::human_rate;
+/// use std::time::Duration;
+///
+/// assert_eq!(human_rate(3_u32 << 20, Duration::from_secs(2)), "1.5 MiB/s");
+/// assert_eq!(human_rate(100_u32, Duration::from_millis(500)), "200 bytes/s");
+/// assert_eq!(human_rate(100_u32, Duration::from_secs(0)), "-/s");
+/// ```
+///
+pub fn human_rate<N: Into<u64>>(bytes: N, duration: Duration) -> String {
+
+    let secs = float_secs(duration);
+    if secs <= 0.0 {
+        return "-/s".to_owned();
+    }
+    format!("{}/s", human_bytes((bytes.into() as f64 / secs) as u64))
+}
+
+fn float_secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+/// Formats a time in seconds since the Unix epoch as a UTC date and time
+///
+/// ```
+/// use human_readable::human_date;
+///
+/// assert_eq!(human_date(0),             "1970-01-01 00:00:00");
+/// assert_eq!(human_date(1_500_000_000), "2017-07-14 02:40:00");
+/// assert_eq!(human_date(951_782_400),   "2000-02-29 00:00:00");
+/// ```
+///
+pub fn human_date(secs: u64) -> String {
+
+    let (year, month, day) = civil_from_days(secs / 86400);
+    let time = secs % 86400;
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            year,
+            month,
+            day,
+            time / 3600,
+            time % 3600 / 60,
+            time % 60)
+}
+
+/// Parses a UTC date, with an optional time, into seconds since the epoch
+///
+/// This is the inverse of `human_date`. The date is `YYYY-MM-DD`, and the
+/// time, if given, is `HH:MM` or `HH:MM:SS`, after a space or a `T`. A date
+/// alone means midnight at its start.
+///
+/// ```
+/// use human_readable::parse_date;
+///
+/// assert_eq!(parse_date("1970-01-01"),          Some(0));
+/// assert_eq!(parse_date("2017-07-14 02:40:00"), Some(1_500_000_000));
+/// assert_eq!(parse_date("2017-07-14T02:40"),    Some(1_500_000_000));
+/// assert_eq!(parse_date("2000-02-29"),          Some(951_782_400));
+///
+/// assert_eq!(parse_date(""),                    None);
+/// assert_eq!(parse_date("yesterday"),           None);
+/// assert_eq!(parse_date("2017-13-01"),          None);
+/// assert_eq!(parse_date("2001-02-29"),          None);
+/// assert_eq!(parse_date("1969-12-31"),          None);
+/// assert_eq!(parse_date("2017-07-14 25:00"),    None);
+/// ```
+///
+pub fn parse_date(s: &str) -> Option<u64> {
+
+    let s = s.trim();
+    let (date, time) = match s.find(|c| c == ' ' || c == 'T') {
+        Some(split) => (&s[..split], Some(&s[split + 1..])),
+        None => (s, None),
+    };
+    let date = match parse_fields(date, '-') {
+        Some(ref fields) if fields.len() == 3 => fields.clone(),
+        _ => return None,
+    };
+    let (year, month, day) = (date[0], date[1], date[2]);
+    if year < 1970 || month < 1 || month > 12 || day < 1 ||
+       day > days_in_month(year, month) {
+        return None;
+    }
+    let time = match time.map(|time| parse_fields(time, ':')) {
+        None => vec![0, 0, 0],
+        Some(Some(ref fields)) if fields.len() == 2 => {
+            vec![fields[0], fields[1], 0]
+        }
+        Some(Some(ref fields)) if fields.len() == 3 => fields.clone(),
+        Some(_) => return None,
+    };
+    let (hour, minute, second) = (time[0], time[1], time[2]);
+    if hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+    Some(days_from_civil(year, month, day) * 86400 + hour * 3600 +
+         minute * 60 + second)
+}
+
+/// Splits a string of numbers on a separator, or None if any isn't a number
+fn parse_fields(s: &str, separator: char) -> Option<Vec<u64>> {
+    s.split(separator)
+        .map(|field| match field.chars().all(|c| c.is_digit(10)) {
+            true => field.parse().ok(),
+            false => None,
+        })
+        .collect()
+}
+
+fn is_leap_year(year: u64) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: u64, month: u64) -> u64 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days from the epoch to a date, which must not be before it
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let mut days = day - 1;
+    for y in 1970..year {
+        days += if is_leap_year(y) { 366 } else { 365 };
+    }
+    for m in 1..month {
+        days += days_in_month(year, m);
+    }
+    days
+}
+
+/// Date (year, month, day) of a number of days after the epoch
+fn civil_from_days(mut days: u64) -> (u64, u64, u64) {
+    let mut year = 1970;
+    loop {
+        let in_year = if is_leap_year(year) { 366 } else { 365 };
+        if days < in_year {
+            break;
+        }
+        days -= in_year;
+        year += 1;
+    }
+    let mut month = 1;
+    while days >= days_in_month(year, month) {
+        days -= days_in_month(year, month);
+        month += 1;
+    }
+    (year, month, days + 1)
+}
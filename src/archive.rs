@@ -0,0 +1,770 @@
+//! Writing a revision's files to tar or zip archives
+//!
+//! File contents are copied straight from the object store into the archive
+//! stream, so a snapshot can be published without extracting it to disk.
+//!
+//! Tar archives use the POSIX ustar layout, with GNU extensions for long
+//! names and for files too large for the ustar size field. Zip archives store
+//! files without compression (media files rarely compress well) and are
+//! limited to 4 GiB per file and in total, and to 65534 entries, since Zip64
+//! is not supported.
+//!
+//! Entries get the modification times and permissions recorded in their
+//! trees (see `FileMetadata`). Where none were recorded, because the
+//! repository does not preserve metadata, they get a fixed time and ordinary
+//! file or directory permissions.
+
+use byteorder::LittleEndian;
+use byteorder::WriteBytesExt;
+use dag::FileMetadata;
+use dag::ObjectKey;
+use dag::ObjectType;
+use error::*;
+use object_store::ObjectStore;
+use object_store::ObjectWalkNode;
+use progress::ProgressCounter;
+use progress::ProgressWriter;
+use progress::std_err_watch;
+use std::io;
+use std::io::Write;
+use std::str::FromStr;
+use std::thread;
+use walker::NodeLookup;
+
+/// Archive file formats
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum ArchiveFormat {
+    Tar,
+    Zip,
+}
+
+impl ArchiveFormat {
+    /// Guess the format from an output file name, defaulting to tar
+    pub fn for_file_name(name: &str) -> Self {
+        if name.to_lowercase().ends_with(".zip") {
+            ArchiveFormat::Zip
+        } else {
+            ArchiveFormat::Tar
+        }
+    }
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "tar" => Ok(ArchiveFormat::Tar),
+            "zip" => Ok(ArchiveFormat::Zip),
+            _ => bail!("Unknown archive format: {}", s),
+        }
+    }
+}
+
+/// Counts of what was written to an archive
+#[derive(Debug,Clone,Copy,Default,PartialEq,Eq)]
+pub struct ArchiveStats {
+    pub files: u64,
+    pub dirs: u64,
+    pub bytes: u64,
+}
+
+/// One file or directory to write to an archive
+struct Entry {
+    path: String,
+    file: Option<(ObjectKey, u64)>,
+    metadata: Option<FileMetadata>,
+}
+
+/// Write the files of a tree to an archive
+///
+/// Each path in the archive is given the prefix, which should usually end
+/// with a slash, such as `photos-2017/`. The tree may also be a commit, or a
+/// single file, which is then named by the prefix alone.
+pub fn write_archive<W: Write>(store: &ObjectStore,
+                               tree: &ObjectKey,
+                               format: ArchiveFormat,
+                               prefix: &str,
+                               writer: W)
+                               -> Result<ArchiveStats> {
+    let mut entries = Vec::new();
+    if !prefix.is_empty() && prefix.ends_with('/') {
+        entries.push(Entry {
+            path: prefix.to_owned(),
+            file: None,
+            metadata: None,
+        });
+    }
+    list_entries(store, tree, prefix, &mut entries)?;
+    let mut stats = ArchiveStats::default();
+    for entry in &entries {
+        match entry.file {
+            Some((_, size)) => {
+                stats.files += 1;
+                stats.bytes += size;
+            }
+            None => stats.dirs += 1,
+        }
+    }
+
+    let prog = ProgressCounter::arc("Archiving", stats.bytes);
+    let prog_clone = prog.clone();
+    let prog_thread = thread::spawn(move || std_err_watch(prog_clone));
+
+    let result = match format {
+        ArchiveFormat::Tar => {
+            write_entries(store, &entries, &prog, TarWriter { writer: writer })
+        }
+        ArchiveFormat::Zip => {
+            write_entries(store,
+                          &entries,
+                          &prog,
+                          ZipWriter {
+                              writer: CountingWriter {
+                                  writer: writer,
+                                  count: 0,
+                              },
+                              central: Vec::new(),
+                          })
+        }
+    };
+
+    prog.finish();
+    prog_thread.join().unwrap();
+    result.map(|_| stats)
+}
+
+/// List the entries of a tree in order, directories before their contents
+fn list_entries(store: &ObjectStore,
+                tree: &ObjectKey,
+                prefix: &str,
+                entries: &mut Vec<Entry>)
+                -> Result<()> {
+    let node: ObjectWalkNode = store.lookup_node(*tree)?;
+    if node.object_type == ObjectType::Commit {
+        let tree = store.open_commit(tree)?.tree;
+        return list_entries(store, &tree, prefix, entries);
+    }
+    if node.object_type != ObjectType::Tree {
+        // A single file: name it by the prefix alone
+        if prefix.is_empty() || prefix.ends_with('/') {
+            bail!("A file needs a prefix to name it in the archive");
+        }
+        entries.push(Entry {
+            path: prefix.to_owned(),
+            file: Some((*tree, node.file_size)),
+            metadata: None,
+        });
+        return Ok(());
+    }
+    let tree = store.open_tree(tree)?;
+    for (name, hash) in tree.iter() {
+        let metadata = tree.metadata.get(name).cloned();
+        let name = name.to_str()
+            .ok_or_else(|| format!("Bad UTF-8 in name: {:?}", name))?;
+        let path = format!("{}{}", prefix, name);
+        let node: ObjectWalkNode = store.lookup_node(*hash)?;
+        if node.object_type == ObjectType::Tree {
+            let dir = format!("{}/", path);
+            entries.push(Entry {
+                path: dir.clone(),
+                file: None,
+                metadata: metadata,
+            });
+            list_entries(store, hash, &dir, entries)?;
+        } else {
+            entries.push(Entry {
+                path: path,
+                file: Some((*hash, node.file_size)),
+                metadata: metadata,
+            });
+        }
+    }
+    Ok(())
+}
+
+fn write_entries<A: ArchiveWriter>(store: &ObjectStore,
+                                   entries: &[Entry],
+                                   progress: &ProgressCounter,
+                                   mut archive: A)
+                                   -> Result<()> {
+    for entry in entries {
+        match entry.file {
+            Some((hash, size)) => {
+                archive.add_file(&entry.path,
+                              size,
+                              entry.metadata.as_ref(),
+                              &mut |writer| {
+                        let mut writer = ProgressWriter::new(writer, progress);
+                        store.copy_blob_content(&hash, &mut writer)
+                    })?;
+                progress.add_file();
+            }
+            None => archive.add_dir(&entry.path, entry.metadata.as_ref())?,
+        }
+    }
+    archive.finish()
+}
+
+/// Callback that writes a file's content
+type ContentFn<'a> = FnMut(&mut Write) -> Result<()> + 'a;
+
+trait ArchiveWriter {
+    /// Add a directory, whose path ends with a slash
+    fn add_dir(&mut self,
+               path: &str,
+               metadata: Option<&FileMetadata>)
+               -> Result<()>;
+    /// Add a file of the given size, with content written by a callback
+    fn add_file(&mut self,
+                path: &str,
+                size: u64,
+                metadata: Option<&FileMetadata>,
+                content: &mut ContentFn)
+                -> Result<()>;
+    /// Write any trailer and flush
+    fn finish(&mut self) -> Result<()>;
+}
+
+
+const TAR_BLOCK: usize = 512;
+const TAR_NAME_LEN: usize = 100;
+
+struct TarWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> TarWriter<W> {
+    fn write_header(&mut self,
+                    path: &str,
+                    size: u64,
+                    metadata: Option<&FileMetadata>,
+                    entry_type: u8)
+                    -> Result<()> {
+        if path.len() > TAR_NAME_LEN {
+            // GNU long name: an extra entry holding the full name
+            let mut name = path.as_bytes().to_vec();
+            name.push(0);
+            self.write_header("././@LongLink", name.len() as u64, None, b'L')?;
+            self.writer.write_all(&name)?;
+            self.pad(name.len() as u64)?;
+        }
+
+        let mut header = [0u8; TAR_BLOCK];
+        let name = path.as_bytes();
+        let name_len = name.len().min(TAR_NAME_LEN);
+        header[..name_len].copy_from_slice(&name[..name_len]);
+        let (mode, mtime) = match metadata {
+            Some(meta) => (meta.mode as u64 & 0o7777, meta.mtime_secs),
+            None if entry_type == b'5' => (0o755, 0),
+            None => (0o644, 0),
+        };
+        tar_octal(&mut header[100..108], mode);
+        tar_octal(&mut header[108..116], 0);
+        tar_octal(&mut header[116..124], 0);
+        tar_size(&mut header[124..136], size);
+        tar_size(&mut header[136..148], mtime);
+        header[148..156].copy_from_slice(b"        ");
+        header[156] = entry_type;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+
+        let checksum = header.iter().map(|&b| b as u64).sum::<u64>();
+        tar_octal(&mut header[148..155], checksum);
+        self.writer.write_all(&header)?;
+        Ok(())
+    }
+
+    /// Pad content to a whole number of blocks
+    fn pad(&mut self, size: u64) -> Result<()> {
+        let remainder = (size % TAR_BLOCK as u64) as usize;
+        if remainder != 0 {
+            self.writer.write_all(&[0u8; TAR_BLOCK][remainder..])?;
+        }
+        Ok(())
+    }
+}
+
+/// Write a zero-padded octal number with a terminating NUL
+fn tar_octal(field: &mut [u8], value: u64) {
+    let digits = field.len() - 1;
+    let text = format!("{:0width$o}", value, width = digits);
+    field[..digits].copy_from_slice(&text.as_bytes()[text.len() - digits..]);
+    field[digits] = 0;
+}
+
+/// Write a size, using GNU base-256 if it is too big for octal
+fn tar_size(field: &mut [u8], size: u64) {
+    if size < 1 << (3 * (field.len() - 1)) {
+        tar_octal(field, size);
+    } else {
+        for b in field.iter_mut() {
+            *b = 0;
+        }
+        let len = field.len();
+        for i in 0..8 {
+            field[len - 1 - i] = (size >> (8 * i)) as u8;
+        }
+        field[0] = 0x80;
+    }
+}
+
+impl<W: Write> ArchiveWriter for TarWriter<W> {
+    fn add_dir(&mut self,
+               path: &str,
+               metadata: Option<&FileMetadata>)
+               -> Result<()> {
+        self.write_header(path, 0, metadata, b'5')
+    }
+
+    fn add_file(&mut self,
+                path: &str,
+                size: u64,
+                metadata: Option<&FileMetadata>,
+                content: &mut ContentFn)
+                -> Result<()> {
+        self.write_header(path, size, metadata, b'0')?;
+        let mut counter = CountingWriter {
+            writer: &mut self.writer,
+            count: 0,
+        };
+        content(&mut counter)?;
+        if counter.count != size {
+            bail!("Size of {} changed while archiving: expected {}, wrote {}",
+                  path,
+                  size,
+                  counter.count);
+        }
+        self.pad(size)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.writer.write_all(&[0u8; TAR_BLOCK * 2])?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+
+/// Zip general purpose flags: sizes in data descriptor, UTF-8 names
+const ZIP_FLAGS: u16 = 0x0008 | 0x0800;
+/// Zip version 2.0, made on Unix
+const ZIP_VERSION_MADE_BY: u16 = 0x0314;
+const ZIP_VERSION_NEEDED: u16 = 20;
+/// MS-DOS date for 1980-01-01, the earliest a zip can hold
+const ZIP_DOS_DATE: u16 = (1 << 5) | 1;
+/// Seconds from the Unix epoch to 1980-01-01
+const ZIP_DOS_EPOCH: u64 = 315_532_800;
+const ZIP_MAX: u64 = 0xFFFF_FFFF;
+/// Most entries without Zip64, which takes 0xFFFF to mean the count is
+/// elsewhere
+const ZIP_MAX_ENTRIES: usize = 0xFFFE;
+const ZIP_MAX_NAME: usize = 0xFFFF;
+
+struct ZipEntry {
+    path: String,
+    crc: u32,
+    size: u32,
+    offset: u32,
+    is_dir: bool,
+    /// MS-DOS time and date
+    time: (u16, u16),
+    /// Unix permission bits
+    mode: u32,
+}
+
+/// Convert seconds since the Unix epoch (UTC) to MS-DOS time and date
+///
+/// Times before 1980, which MS-DOS dates cannot hold, become 1980-01-01.
+fn dos_time(secs: u64) -> (u16, u16) {
+    if secs < ZIP_DOS_EPOCH {
+        return (0, ZIP_DOS_DATE);
+    }
+    let (days, secs) = (secs / 86400, secs % 86400);
+    let time = (secs / 3600) << 11 | (secs % 3600 / 60) << 5 | (secs % 60 / 2);
+
+    // Civil date from days since the epoch, in 400-year eras starting
+    // on March 1st, as in Howard Hinnant's `civil_from_days`
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 -
+                       day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era -
+                      (365 * year_of_era + year_of_era / 4 -
+                       year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    // Seven bits of year: the latest date is in 2107
+    let year = (year - 1980).min(127);
+    (time as u16, (year << 9 | month << 5 | day) as u16)
+}
+
+struct ZipWriter<W: Write> {
+    writer: CountingWriter<W>,
+    central: Vec<ZipEntry>,
+}
+
+impl<W: Write> ZipWriter<W> {
+    fn add_entry(&mut self,
+                 path: &str,
+                 is_dir: bool,
+                 metadata: Option<&FileMetadata>,
+                 content: &mut ContentFn)
+                 -> Result<()> {
+        let offset = self.writer.count;
+        if offset > ZIP_MAX {
+            bail!("Archive is too large for zip format. Use tar instead.");
+        }
+        if self.central.len() >= ZIP_MAX_ENTRIES {
+            bail!("Archive has too many entries for zip format (at most {}). \
+                   Use tar instead.",
+                  ZIP_MAX_ENTRIES);
+        }
+        if path.len() > ZIP_MAX_NAME {
+            bail!("Name of {} bytes is too long for zip format (at most {}). \
+                   Use tar instead.",
+                  path.len(),
+                  ZIP_MAX_NAME);
+        }
+        let (time, mode) = match metadata {
+            Some(meta) => (dos_time(meta.mtime_secs), meta.mode & 0o7777),
+            None if is_dir => ((0, ZIP_DOS_DATE), 0o755),
+            None => ((0, ZIP_DOS_DATE), 0o644),
+        };
+        let w = &mut self.writer;
+        w.write_u32::<LittleEndian>(0x04034b50)?;
+        w.write_u16::<LittleEndian>(ZIP_VERSION_NEEDED)?;
+        w.write_u16::<LittleEndian>(ZIP_FLAGS)?;
+        w.write_u16::<LittleEndian>(0)?; // stored, no compression
+        w.write_u16::<LittleEndian>(time.0)?;
+        w.write_u16::<LittleEndian>(time.1)?;
+        w.write_u32::<LittleEndian>(0)?; // crc, in data descriptor
+        w.write_u32::<LittleEndian>(0)?; // compressed size
+        w.write_u32::<LittleEndian>(0)?; // uncompressed size
+        w.write_u16::<LittleEndian>(path.len() as u16)?;
+        w.write_u16::<LittleEndian>(0)?; // extra field length
+        w.write_all(path.as_bytes())?;
+
+        let (crc, size) = {
+            let mut crc_writer = CrcWriter {
+                writer: &mut *w,
+                crc: Crc32::new(),
+                count: 0,
+            };
+            content(&mut crc_writer)?;
+            (crc_writer.crc.finish(), crc_writer.count)
+        };
+        if size > ZIP_MAX {
+            bail!("{} is too large for zip format. Use tar instead.", path);
+        }
+
+        w.write_u32::<LittleEndian>(0x08074b50)?;
+        w.write_u32::<LittleEndian>(crc)?;
+        w.write_u32::<LittleEndian>(size as u32)?;
+        w.write_u32::<LittleEndian>(size as u32)?;
+
+        self.central.push(ZipEntry {
+            path: path.to_owned(),
+            crc: crc,
+            size: size as u32,
+            offset: offset as u32,
+            is_dir: is_dir,
+            time: time,
+            mode: mode,
+        });
+        Ok(())
+    }
+}
+
+impl<W: Write> ArchiveWriter for ZipWriter<W> {
+    fn add_dir(&mut self,
+               path: &str,
+               metadata: Option<&FileMetadata>)
+               -> Result<()> {
+        self.add_entry(path, true, metadata, &mut |_| Ok(()))
+    }
+
+    fn add_file(&mut self,
+                path: &str,
+                _size: u64,
+                metadata: Option<&FileMetadata>,
+                content: &mut ContentFn)
+                -> Result<()> {
+        self.add_entry(path, false, metadata, content)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        let start = self.writer.count;
+        let w = &mut self.writer;
+        for entry in &self.central {
+            let (mode, dos_attr) = if entry.is_dir {
+                (0o040000 | entry.mode, 0x10)
+            } else {
+                (0o100000 | entry.mode, 0)
+            };
+            w.write_u32::<LittleEndian>(0x02014b50)?;
+            w.write_u16::<LittleEndian>(ZIP_VERSION_MADE_BY)?;
+            w.write_u16::<LittleEndian>(ZIP_VERSION_NEEDED)?;
+            w.write_u16::<LittleEndian>(ZIP_FLAGS)?;
+            w.write_u16::<LittleEndian>(0)?; // stored
+            w.write_u16::<LittleEndian>(entry.time.0)?;
+            w.write_u16::<LittleEndian>(entry.time.1)?;
+            w.write_u32::<LittleEndian>(entry.crc)?;
+            w.write_u32::<LittleEndian>(entry.size)?;
+            w.write_u32::<LittleEndian>(entry.size)?;
+            w.write_u16::<LittleEndian>(entry.path.len() as u16)?;
+            w.write_u16::<LittleEndian>(0)?; // extra field length
+            w.write_u16::<LittleEndian>(0)?; // comment length
+            w.write_u16::<LittleEndian>(0)?; // disk number
+            w.write_u16::<LittleEndian>(0)?; // internal attributes
+            w.write_u32::<LittleEndian>(mode << 16 | dos_attr)?;
+            w.write_u32::<LittleEndian>(entry.offset)?;
+            w.write_all(entry.path.as_bytes())?;
+        }
+        let size = w.count - start;
+        if start > ZIP_MAX || size > ZIP_MAX {
+            bail!("Archive is too large for zip format. Use tar instead.");
+        }
+        let count = self.central.len() as u16;
+        w.write_u32::<LittleEndian>(0x06054b50)?;
+        w.write_u16::<LittleEndian>(0)?; // this disk
+        w.write_u16::<LittleEndian>(0)?; // disk with central directory
+        w.write_u16::<LittleEndian>(count)?;
+        w.write_u16::<LittleEndian>(count)?;
+        w.write_u32::<LittleEndian>(size as u32)?;
+        w.write_u32::<LittleEndian>(start as u32)?;
+        w.write_u16::<LittleEndian>(0)?; // comment length
+        w.flush()?;
+        Ok(())
+    }
+}
+
+
+/// Writer wrapper that counts bytes written
+struct CountingWriter<W: Write> {
+    writer: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+    fn flush(&mut self) -> io::Result<()> { self.writer.flush() }
+}
+
+/// Writer wrapper that calculates a CRC-32 and counts bytes written
+struct CrcWriter<W: Write> {
+    writer: W,
+    crc: Crc32,
+    count: u64,
+}
+
+impl<W: Write> Write for CrcWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.crc.update(&buf[..written]);
+        self.count += written as u64;
+        Ok(written)
+    }
+    fn flush(&mut self) -> io::Result<()> { self.writer.flush() }
+}
+
+lazy_static!{
+    static ref CRC_TABLE: [u32; 256] = {
+        let mut table = [0u32; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            let mut c = i as u32;
+            for _ in 0..8 {
+                c = if c & 1 != 0 { 0xEDB88320 ^ (c >> 1) } else { c >> 1 };
+            }
+            *entry = c;
+        }
+        table
+    };
+}
+
+/// CRC-32 (IEEE), as used by zip
+struct Crc32(u32);
+
+impl Crc32 {
+    fn new() -> Self { Crc32(0xFFFF_FFFF) }
+    fn update(&mut self, buf: &[u8]) {
+        for &b in buf {
+            self.0 = CRC_TABLE[((self.0 ^ b as u32) & 0xFF) as usize] ^
+                     (self.0 >> 8);
+        }
+    }
+    fn finish(&self) -> u32 { !self.0 }
+}
+
+
+#[cfg(test)]
+mod test {
+    use dag::Blob;
+    use object_store::test::create_temp_repository;
+    use super::*;
+
+    fn read_octal(field: &[u8]) -> u64 {
+        let text = String::from_utf8_lossy(field);
+        u64::from_str_radix(text.trim_matches('\0').trim(), 8).unwrap()
+    }
+
+    #[test]
+    fn test_crc32() {
+        let mut crc = Crc32::new();
+        crc.update(b"123456789");
+        assert_eq!(crc.finish(), 0xCBF43926);
+    }
+
+    #[test]
+    fn test_tar_size_fields() {
+        let mut field = [0u8; 12];
+        tar_size(&mut field, 0o777);
+        assert_eq!(&field, b"00000000777\0");
+        tar_size(&mut field, 1 << 40);
+        assert_eq!(field[0], 0x80);
+        assert_eq!(&field[6..], &[1, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_dos_time() {
+        assert_eq!(dos_time(0), (0, ZIP_DOS_DATE));
+        assert_eq!(dos_time(ZIP_DOS_EPOCH), (0, ZIP_DOS_DATE));
+        assert_eq!(dos_time(1497530096), (0x645c, 0x4acf));
+        assert_eq!(dos_time(951782400), (0, 0x285d), "Leap day");
+    }
+
+    #[test]
+    fn test_write_tar() {
+        let (_temp, store) = create_temp_repository().unwrap();
+        let hello = store.store_object(&Blob::from("Hello!")).unwrap();
+        let long_name = "x".repeat(150);
+        let subdir = store.store_object(&tree_object!{
+                long_name.as_str() => hello,
+            })
+            .unwrap();
+        let mut root = tree_object!{
+            "hello.txt" => hello,
+            "sub" => subdir,
+        };
+        root.insert_metadata("hello.txt",
+                             FileMetadata {
+                                 mtime_secs: 1497530096,
+                                 mtime_nanos: 0,
+                                 mode: 0o600,
+                             });
+        let root = store.store_object(&root).unwrap();
+
+        let mut out = Vec::new();
+        let stats =
+            write_archive(&store, &root, ArchiveFormat::Tar, "snap/", &mut out)
+                .unwrap();
+        assert_eq!(stats,
+                   ArchiveStats {
+                       files: 2,
+                       dirs: 2,
+                       bytes: 12,
+                   });
+
+        // Walk the headers
+        let mut names = Vec::new();
+        let mut pos = 0;
+        let mut long_name_next = None;
+        while out[pos] != 0 {
+            let header = &out[pos..pos + TAR_BLOCK];
+            let size = read_octal(&header[124..136]);
+            let mut check = header.to_vec();
+            check[148..156].copy_from_slice(b"        ");
+            assert_eq!(read_octal(&header[148..155]),
+                       check.iter().map(|&b| b as u64).sum::<u64>());
+            let content = &out[pos + TAR_BLOCK..pos + TAR_BLOCK +
+                                                size as usize];
+            let name = long_name_next.take().unwrap_or_else(|| {
+                String::from_utf8_lossy(&header[..100])
+                    .trim_matches('\0')
+                    .to_owned()
+            });
+            match header[156] {
+                b'L' => {
+                    long_name_next = Some(String::from_utf8_lossy(content)
+                        .trim_matches('\0')
+                        .to_owned())
+                }
+                b'0' => {
+                    assert_eq!(content, b"Hello!");
+                    let (mode, mtime) = match name.as_str() {
+                        "snap/hello.txt" => (0o600, 1497530096),
+                        _ => (0o644, 0),
+                    };
+                    assert_eq!(read_octal(&header[100..108]), mode);
+                    assert_eq!(read_octal(&header[136..148]), mtime);
+                    names.push(name);
+                }
+                _ => names.push(name),
+            }
+            pos += TAR_BLOCK + (size as usize + TAR_BLOCK - 1) / TAR_BLOCK *
+                               TAR_BLOCK;
+        }
+        assert_eq!(names,
+                   vec!["snap/".to_owned(),
+                        "snap/hello.txt".to_owned(),
+                        "snap/sub/".to_owned(),
+                        format!("snap/sub/{}", long_name)]);
+        assert_eq!(out.len() - pos, TAR_BLOCK * 2, "Should end with trailer");
+    }
+
+    #[test]
+    fn test_write_zip() {
+        let (_temp, store) = create_temp_repository().unwrap();
+        let hello = store.store_object(&Blob::from("Hello!")).unwrap();
+        let root = store.store_object(&tree_object!{
+                "hello.txt" => hello,
+            })
+            .unwrap();
+
+        let mut out = Vec::new();
+        write_archive(&store, &root, ArchiveFormat::Zip, "", &mut out)
+            .unwrap();
+
+        assert_eq!(&out[..4], b"PK\x03\x04");
+        let name_end = 30 + "hello.txt".len();
+        assert_eq!(&out[30..name_end], b"hello.txt");
+        assert_eq!(&out[name_end..name_end + 6], b"Hello!");
+
+        let mut crc = Crc32::new();
+        crc.update(b"Hello!");
+        let descriptor = &out[name_end + 6..name_end + 22];
+        assert_eq!(&descriptor[..4], b"PK\x07\x08");
+        assert_eq!(&descriptor[4..8], &crc.finish().to_le_bytes());
+
+        let end = &out[out.len() - 22..];
+        assert_eq!(&end[..4], b"PK\x05\x06");
+        assert_eq!(end[10], 1, "One entry in central directory");
+    }
+
+    #[test]
+    fn test_zip_limits() {
+        let mut zip = ZipWriter {
+            writer: CountingWriter {
+                writer: io::sink(),
+                count: 0,
+            },
+            central: Vec::new(),
+        };
+        let long_name = "x".repeat(ZIP_MAX_NAME + 1);
+        assert!(zip.add_dir(&long_name, None).is_err(),
+                "Should refuse a name too long for its length field");
+        for i in 0..ZIP_MAX_ENTRIES {
+            zip.add_dir(&format!("{}/", i), None).unwrap();
+        }
+        assert!(zip.add_dir("one-too-many/", None).is_err(),
+                "Should refuse more entries than the count field holds");
+        zip.finish().unwrap();
+    }
+}
@@ -0,0 +1,211 @@
+//! Backup: mirroring the object store to dumb storage
+//!
+//! A backup is a plain copy of the object files and refs in the hidden
+//! directory, kept anywhere that can store files by name, such as another
+//! disk. Storage is reached through a `Backend`, so that others, such as an
+//! object storage bucket, can be added later.
+//!
+//! Object files never change once written, so each backup only uploads the
+//! objects that the target does not have yet. The refs, and the other files
+//! needed to read the objects, are uploaded after them every time, so that a
+//! backup interrupted partway still has refs that point to complete history.
+//! Objects removed from the store later are kept in the backup. Encrypted
+//! objects stay encrypted, along with the wrapped key.
+//!
+//! A backup to a directory is itself a bare store, so it can be opened or
+//! cloned to restore from.
+
+use error::*;
+use fsutil;
+use object_store::ObjectStore;
+use progress::ProgressCounter;
+use progress::std_err_watch;
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::thread;
+
+/// Files in the hidden directory that a backup needs besides the objects, in
+/// the order they are uploaded
+const METADATA_FILES: &'static [&'static str] =
+    &["config", "format", "key", "shallow", "absent", "refs"];
+
+/// Suffix of files being written by `DirBackend`, before they are renamed
+const PARTIAL_SUFFIX: &'static str = ".partial";
+
+/// Dumb storage that a backup is written to
+///
+/// Files are named by relative paths with `/` separators, such as
+/// `objects/ab/cdef`.
+pub trait Backend {
+    /// Names of all complete files already stored
+    fn list(&self) -> Result<HashSet<String>>;
+
+    /// Store content under a name
+    ///
+    /// Any file already there is replaced. A reader should never see a
+    /// partial file.
+    fn put(&self, name: &str, content: &mut io::Read) -> Result<()>;
+}
+
+/// Backend that keeps files in a local directory
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct DirBackend {
+    root: PathBuf,
+}
+
+impl DirBackend {
+    pub fn new(root: PathBuf) -> Self { DirBackend { root: root } }
+}
+
+impl Backend for DirBackend {
+    fn list(&self) -> Result<HashSet<String>> {
+        let mut names = HashSet::new();
+        let mut dirs = vec![self.root.clone()];
+        while let Some(dir) = dirs.pop() {
+            if !dir.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(&dir)? {
+                let entry = entry?;
+                if entry.file_type()?.is_dir() {
+                    dirs.push(entry.path());
+                    continue;
+                }
+                let name = backup_name(&self.root, &entry.path())?;
+                if !name.ends_with(PARTIAL_SUFFIX) {
+                    names.insert(name);
+                }
+            }
+        }
+        Ok(names)
+    }
+
+    fn put(&self, name: &str, content: &mut io::Read) -> Result<()> {
+        let dest = self.root.join(name);
+        fsutil::create_parents(&dest)?;
+        let mut partial = OsString::from(dest.as_os_str());
+        partial.push(PARTIAL_SUFFIX);
+        io::copy(content, &mut fs::File::create(&partial)?)
+            .chain_err(|| format!("Could not write {}", dest.display()))?;
+        fs::rename(&partial, &dest)?;
+        Ok(())
+    }
+}
+
+/// Name of a file in a backup, from its path and the root it is under
+fn backup_name(root: &Path, path: &Path) -> Result<String> {
+    let relative = path.strip_prefix(root)?;
+    let parts = relative.components()
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    Ok(parts.join("/"))
+}
+
+/// Counts of what a backup uploaded
+#[derive(Debug,Clone,Copy,Default,PartialEq,Eq)]
+pub struct BackupStats {
+    /// Objects uploaded
+    pub objects: u64,
+    /// Objects the backup already had
+    pub skipped: u64,
+    /// Size of the objects uploaded
+    pub bytes: u64,
+}
+
+/// Upload the objects that a backup lacks, then the refs
+pub fn backup<B: Backend>(store: &ObjectStore,
+                          backend: &B)
+                          -> Result<BackupStats> {
+    if store.is_in_memory() {
+        bail!("Cannot back up a store kept in memory");
+    }
+    let existing = backend.list()?;
+    let mut stats = BackupStats::default();
+    let mut new = Vec::new();
+    for name in store.object_names()? {
+        if existing.contains(&name) {
+            stats.skipped += 1;
+        } else {
+            let size = store.storage().stat(&name)?.size;
+            stats.bytes += size;
+            new.push((name, size));
+        }
+    }
+
+    let prog = ProgressCounter::arc("Backing up", stats.bytes);
+    let prog_clone = prog.clone();
+    let prog_thread = thread::spawn(move || std_err_watch(prog_clone));
+    let result = (|| {
+        for &(ref name, size) in &new {
+            backend.put(name, &mut store.storage().get(name)?)?;
+            prog.add(size);
+            prog.add_file();
+            stats.objects += 1;
+        }
+        for name in METADATA_FILES {
+            let path = store.path().join(name);
+            if path.is_file() {
+                backend.put(name, &mut fs::File::open(&path)?)?;
+            }
+        }
+        Ok(())
+    })();
+    prog.finish();
+    prog_thread.join().unwrap();
+    result.map(|_| stats)
+}
+
+
+#[cfg(test)]
+mod test {
+    use dag::Blob;
+    use dag::Commit;
+    use dag::Tree;
+    use super::*;
+
+    #[test]
+    fn test_backup() {
+        let temp = in_mem_tempdir!();
+        let mut store = ObjectStore::init(temp.path().join("store")).unwrap();
+        let backend = DirBackend::new(temp.path().join("backup"));
+
+        let commit = |store: &mut ObjectStore, content: &str| {
+            let blob = store.store_object(&Blob::from(content)).unwrap();
+            let mut tree = Tree::new();
+            tree.insert("file", blob);
+            let tree = store.store_object(&tree).unwrap();
+            let parents = store.try_find_ref("master").into_iter().collect();
+            let commit = Commit {
+                tree: tree,
+                parents: parents,
+                message: content.to_owned(),
+                time: None,
+                signature: None,
+            };
+            let hash = store.store_object(&commit).unwrap();
+            store.update_ref("master".to_owned(), hash).unwrap();
+            hash
+        };
+
+        let first = commit(&mut store, "one");
+        let stats = backup(&store, &backend).unwrap();
+        assert_eq!((stats.objects, stats.skipped), (3, 0));
+
+        let second = commit(&mut store, "two");
+        let stats = backup(&store, &backend).unwrap();
+        assert_eq!((stats.objects, stats.skipped),
+                   (3, 3),
+                   "Only new objects should be uploaded");
+        assert_eq!(backup(&store, &backend).unwrap().objects, 0);
+
+        let restored = ObjectStore::open(temp.path().join("backup")).unwrap();
+        assert_eq!(restored.try_find_ref("master"), Some(second));
+        assert_eq!(restored.open_commit(&second).unwrap().parents,
+                   vec![first]);
+        assert!(backend.list().unwrap().contains("refs"));
+    }
+}
@@ -0,0 +1,136 @@
+//! Throughput measurements, to tune a repository for the hardware at hand
+//!
+//! Hashing and chunking are the main costs of storing files, so `dmv bench
+//! hash` times each digest backend and hash algorithm, and then chunking with
+//! the repository's parameters, over the same sample data.
+
+use dag::HashAlgorithm;
+use dag::Hasher;
+use dag::digest_backends;
+use error::*;
+use human_readable::human_bytes;
+use human_readable::human_rate;
+use progress::StopWatch;
+use rolling_hash::ChunkParams;
+use rolling_hash::read_file_objects_with;
+use std::fmt;
+use std::time::Duration;
+
+/// Bytes given to the hasher at a time, like a buffered reader would
+const HASH_WRITE_SIZE: usize = 64 * 1024;
+
+/// How long one operation took over a number of bytes
+#[derive(Debug,Clone,PartialEq)]
+pub struct BenchResult {
+    pub what: String,
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl BenchResult {
+    pub fn bytes_per_sec(&self) -> f64 {
+        let secs = StopWatch::float_secs(&self.elapsed) as f64;
+        match secs > 0.0 {
+            true => self.bytes as f64 / secs,
+            false => 0.0,
+        }
+    }
+}
+
+impl fmt::Display for BenchResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "{:<32} {:>12} ({:.1} MB/s)",
+               self.what,
+               human_rate(self.bytes, self.elapsed),
+               self.bytes_per_sec() / 1e6)
+    }
+}
+
+/// Pseudo-random bytes, which neither compress nor chunk unusually
+pub fn sample_data(size: usize) -> Vec<u8> {
+    let mut state = 0x2545f4914f6cdd1d_u64;
+    (0..size)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 32) as u8
+        })
+        .collect()
+}
+
+/// Time hashing the data with each backend and algorithm
+pub fn bench_hashing(data: &[u8]) -> Vec<BenchResult> {
+    let mut results = Vec::new();
+    for backend in digest_backends() {
+        for &algorithm in &[HashAlgorithm::Sha1, HashAlgorithm::Sha256] {
+            let watch = StopWatch::new();
+            let mut hasher = Hasher::with_backend(algorithm, &*backend);
+            for block in data.chunks(HASH_WRITE_SIZE) {
+                hasher.input(block);
+            }
+            hasher.result();
+            results.push(BenchResult {
+                what: format!("hash {} ({})", algorithm, backend.name()),
+                bytes: data.len() as u64,
+                elapsed: watch.elapsed(),
+            });
+        }
+    }
+    results
+}
+
+/// Time breaking the data into chunks and hashing them
+pub fn bench_chunking(data: &[u8],
+                      params: &ChunkParams,
+                      algorithm: HashAlgorithm)
+                      -> Result<BenchResult> {
+    let watch = StopWatch::new();
+    for object in read_file_objects_with(data, params, algorithm) {
+        object?;
+    }
+    Ok(BenchResult {
+        what: format!("chunk {} ({} target)",
+                      algorithm,
+                      human_bytes(params.target_size() as u64)),
+        bytes: data.len() as u64,
+        elapsed: watch.elapsed(),
+    })
+}
+
+
+#[cfg(test)]
+mod test {
+    use dag::HashWriter;
+    use std::io;
+    use std::io::Write;
+    use super::*;
+
+    #[test]
+    fn test_bench() {
+        let data = sample_data(256 * 1024);
+        assert_eq!(data, sample_data(256 * 1024), "Should be repeatable");
+        assert!(data.iter().any(|&b| b != data[0]));
+
+        let results = bench_hashing(&data);
+        assert_eq!(results.len(), digest_backends().len() * 2);
+        assert!(results.iter().all(|r| r.bytes == data.len() as u64));
+
+        let params = ChunkParams::default();
+        let result = bench_chunking(&data, &params, HashAlgorithm::Sha256)
+            .unwrap();
+        assert!(result.what.starts_with("chunk sha256"), "{}", result);
+
+        // Every backend gives the same hashes
+        for backend in digest_backends() {
+            let mut hasher = Hasher::with_backend(HashAlgorithm::Sha256,
+                                                  &*backend);
+            hasher.input(&data);
+            let mut writer = HashWriter::wrap_with(io::sink(),
+                                                   HashAlgorithm::Sha256);
+            writer.write_all(&data).unwrap();
+            assert_eq!(hasher.result(), writer.hash(), "{}", backend.name());
+        }
+    }
+}
@@ -0,0 +1,261 @@
+//! Bisect: binary search of history for the commit that introduced a change
+//!
+//! Mark a commit bad, such as one where a file is corrupt or was replaced,
+//! and one or more earlier commits good. Bisect then checks out a commit
+//! halfway between them to be tested and marked in turn, halving the
+//! candidates each time, until only the first bad commit is left.
+//!
+//! The search is kept in the hidden directory, so it can span many commands,
+//! along with the branch that was checked out before it started, so that
+//! `bisect reset` can return there.
+
+use dag::ObjectKey;
+use disk_backed::DiskBacked;
+use error::*;
+use object_store::ObjectStore;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::fs;
+use std::path::PathBuf;
+use work_dir::WorkDir;
+
+/// State of a bisect in progress
+#[derive(Debug,Clone,Default,PartialEq,Eq,Hash,RustcEncodable,RustcDecodable)]
+pub struct BisectState {
+    /// Branch checked out when the bisect started, to return to at the end
+    pub orig_branch: Option<String>,
+    /// Commit checked out when the bisect started
+    pub orig_head: Option<ObjectKey>,
+    pub bad: Option<ObjectKey>,
+    pub good: Vec<ObjectKey>,
+}
+
+/// What to do next in a bisect
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum BisectStep {
+    /// A bad commit and a good commit must be marked before searching
+    NeedBad,
+    NeedGood,
+    /// Test this commit, after which about `remaining` commits will be left
+    Test { commit: ObjectKey, remaining: usize },
+    /// This is the first bad commit
+    Found(ObjectKey),
+}
+
+/// A bisect in progress, stored in a file in the hidden directory
+pub struct Bisect {
+    state: DiskBacked<BisectState>,
+}
+
+impl Bisect {
+    fn state_path(work_dir: &WorkDir) -> PathBuf {
+        work_dir.object_store.path().join("bisect")
+    }
+
+    /// Is a bisect in progress in this work dir?
+    pub fn in_progress(work_dir: &WorkDir) -> bool {
+        Self::state_path(work_dir).exists()
+    }
+
+    /// Start a new bisect, remembering what is checked out now
+    pub fn start(work_dir: &WorkDir) -> Result<Self> {
+        if Self::in_progress(work_dir) {
+            bail!("A bisect is already in progress. Run `dmv bisect reset` \
+                   to end it");
+        }
+        let head = match work_dir.head() {
+            Some(head) => head,
+            None => bail!("No commits to bisect"),
+        };
+        let state = BisectState {
+            orig_branch: work_dir.branch().map(|b| b.to_owned()),
+            orig_head: Some(head),
+            bad: None,
+            good: Vec::new(),
+        };
+        let mut state = DiskBacked::init("bisect state",
+                                         Self::state_path(work_dir),
+                                         state);
+        state.flush()?;
+        Ok(Bisect { state: state })
+    }
+
+    /// Open the bisect in progress
+    pub fn open(work_dir: &WorkDir) -> Result<Self> {
+        if !Self::in_progress(work_dir) {
+            bail!("No bisect in progress. Run `dmv bisect start` to begin");
+        }
+        let state = DiskBacked::read("bisect state",
+                                     Self::state_path(work_dir))?;
+        Ok(Bisect { state: state })
+    }
+
+    pub fn state(&self) -> &BisectState { &self.state }
+
+    /// Mark a commit as bad (has the change) or good (does not)
+    pub fn mark(&mut self, commit: ObjectKey, good: bool) -> Result<()> {
+        if good {
+            if !self.state.good.contains(&commit) {
+                self.state.good.push(commit);
+            }
+        } else {
+            self.state.bad = Some(commit);
+        }
+        self.state.flush().map_err(|e| e.into())
+    }
+
+    /// Work out the next commit to test, or the answer
+    pub fn next_step(&self, store: &ObjectStore) -> Result<BisectStep> {
+        let bad = match self.state.bad {
+            Some(bad) => bad,
+            None => return Ok(BisectStep::NeedBad),
+        };
+        if self.state.good.is_empty() {
+            return Ok(BisectStep::NeedGood);
+        }
+        let good_ancestors = ancestors(store, &self.state.good, None)?;
+        if good_ancestors.contains(&bad) {
+            bail!("Bad commit {} is an ancestor of a good commit", bad);
+        }
+        let candidates = ancestors(store, &[bad], Some(&good_ancestors))?;
+        if candidates.len() == 1 {
+            return Ok(BisectStep::Found(bad));
+        }
+
+        // Pick the candidate that splits the others most evenly: testing it
+        // rules out either its ancestors or everything else
+        let total = candidates.len();
+        let mut best = (0, bad);
+        for candidate in &candidates {
+            let below = ancestors(store, &[*candidate], Some(&good_ancestors))?
+                .len();
+            let score = ::std::cmp::min(below, total - below);
+            if score > best.0 || (score == best.0 && *candidate < best.1) {
+                best = (score, *candidate);
+            }
+        }
+        Ok(BisectStep::Test {
+            commit: best.1,
+            remaining: total - best.0 - 1,
+        })
+    }
+
+    /// End the bisect, checking out what was checked out before it started
+    pub fn reset(self, work_dir: &mut WorkDir) -> Result<()> {
+        let back_to = match (&self.state.orig_branch, &self.state.orig_head) {
+            (Some(branch), _) => Some(branch.to_owned()),
+            (None, Some(head)) => Some(head.to_hex()),
+            (None, None) => None,
+        };
+        if let Some(rev) = back_to {
+            work_dir.checkout(&rev.parse()?, false)?;
+        }
+        fs::remove_file(Self::state_path(work_dir))
+            .chain_err(|| "Could not remove bisect state")
+    }
+}
+
+/// Commits reachable from the given ones, stopping at excluded commits
+fn ancestors(store: &ObjectStore,
+             start: &[ObjectKey],
+             exclude: Option<&HashSet<ObjectKey>>)
+             -> Result<HashSet<ObjectKey>> {
+    let mut queue: VecDeque<ObjectKey> = start.iter().cloned().collect();
+    let mut seen = HashSet::new();
+    while let Some(hash) = queue.pop_front() {
+        if exclude.is_some_and(|exclude| exclude.contains(&hash)) {
+            continue;
+        }
+        if seen.insert(hash) {
+            queue.extend(store.commit_parents(&hash)?);
+        }
+    }
+    Ok(seen)
+}
+
+
+#[cfg(test)]
+mod test {
+    use dag::Commit;
+    use dag::ObjectKey;
+    use dag::Tree;
+    use object_store::test::create_temp_repository;
+    use super::*;
+    use work_dir::test::create_temp_work_dir;
+    use work_dir::test::read_wd_file;
+    use work_dir::test::write_wd_file;
+
+    #[test]
+    fn test_bisect_steps() {
+        let (temp, store) = create_temp_repository().unwrap();
+        let tree = store.store_object(&Tree::new()).unwrap();
+
+        // Linear history of 8 commits
+        let mut commits: Vec<ObjectKey> = Vec::new();
+        for i in 0..8 {
+            let commit = Commit {
+                tree: tree,
+                parents: commits.last().into_iter().cloned().collect(),
+                message: format!("Commit {}", i),
+                time: None,
+                signature: None,
+            };
+            commits.push(store.store_object(&commit).unwrap());
+        }
+
+        let mut bisect = Bisect {
+            state: DiskBacked::init("bisect state",
+                                    temp.path().join("bisect"),
+                                    BisectState::default()),
+        };
+        assert_eq!(bisect.next_step(&store).unwrap(), BisectStep::NeedBad);
+        bisect.mark(commits[7], false).unwrap();
+        assert_eq!(bisect.next_step(&store).unwrap(), BisectStep::NeedGood);
+        bisect.mark(commits[0], true).unwrap();
+
+        // Commit 5 introduced the change
+        let mut tested = 0;
+        loop {
+            match bisect.next_step(&store).unwrap() {
+                BisectStep::Test { commit, .. } => {
+                    let position = commits.iter()
+                        .position(|c| *c == commit)
+                        .unwrap();
+                    bisect.mark(commit, position < 5).unwrap();
+                    tested += 1;
+                }
+                BisectStep::Found(commit) => {
+                    assert_eq!(commit, commits[5]);
+                    break;
+                }
+                other => panic!("Unexpected step {:?}", other),
+            }
+        }
+        assert_eq!(tested, 3, "Should halve the candidates each step");
+
+        bisect.mark(commits[6], true).unwrap();
+        assert!(bisect.next_step(&store).is_err(),
+                "Good commit after bad should be an error");
+    }
+
+    #[test]
+    fn test_reset_keeps_local_changes() {
+        let (_temp, wd_path, mut wd) = create_temp_work_dir().unwrap();
+        write_wd_file(&wd_path, "foo", "one");
+        let one = wd.commit("One".to_owned(), false, None).unwrap().hash;
+        write_wd_file(&wd_path, "foo", "two");
+        wd.commit("Two".to_owned(), false, None).unwrap();
+
+        let bisect = Bisect::start(&wd).unwrap();
+        wd.checkout(&one.to_hex().parse().unwrap(), false).unwrap();
+        write_wd_file(&wd_path, "foo", "mine");
+        match bisect.reset(&mut wd) {
+            Err(Error(ErrorKind::CheckoutConflict(paths), _)) => {
+                assert_eq!(paths, vec![PathBuf::from("foo")])
+            }
+            other => panic!("Expected CheckoutConflict, got {:?}", other),
+        }
+        assert_eq!(read_wd_file(&wd_path, "foo"), "mine");
+        assert!(Bisect::in_progress(&wd), "Bisect should not have ended");
+    }
+}
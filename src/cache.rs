@@ -5,13 +5,17 @@ use disk_backed::DiskBacked;
 use encodable;
 use error::*;
 use rustc_serialize::{Encodable, Encoder, Decodable, Decoder};
-use std::cell::RefCell;
+use rustc_serialize::json;
 use std::collections::HashMap;
 use std::fmt;
+use std::fs;
 use std::fs::Metadata;
 use std::hash::Hash;
 use std::hash::Hasher;
+use std::io::Read;
 use std::path;
+use std::sync::Mutex;
+use std::time;
 
 
 /// Status of a file's cached hash
@@ -34,12 +38,33 @@ impl fmt::Display for CacheStatus {
 
 
 
+/// Version of the cache file format
+///
+/// - Version 1 was a bare map of file names to entries with size and mtime.
+/// - Version 2 wraps the map with this version number, and adds ctime and
+///   file ID to the entries.
+const CACHE_VERSION: u32 = 2;
+
 /// Data stored in the cache for each file
+///
+/// The ctime and file ID catch changes that keep the same size and mtime,
+/// such as two writes within the filesystem's timestamp resolution, or a file
+/// replaced by another with the same mtime. They are None for entries migrated
+/// from version 1 of the cache, and on platforms that do not provide them, in
+/// which case only the size and mtime are compared.
+///
+/// The last-used time decides which entries are evicted first when the cache
+/// is pruned. It is only updated once a day, so that checking a file does not
+/// rewrite its cache file every time. It is None for entries written before
+/// it was added, which are evicted first.
 #[derive(Clone,Hash,Eq,PartialEq,Debug,RustcEncodable,RustcDecodable)]
 pub struct CacheEntry {
     pub mtime: encodable::SystemTime,
     pub size: ObjectSize,
+    pub ctime: Option<encodable::SystemTime>,
+    pub file_id: Option<u64>,
     pub hash: ObjectKey,
+    pub last_used: Option<encodable::SystemTime>,
 }
 
 impl CacheEntry {
@@ -47,12 +72,25 @@ impl CacheEntry {
         CacheEntry {
             mtime: meta.modified().expect("metadata has no mod time").into(),
             size: meta.len(),
+            ctime: change_time(meta),
+            file_id: file_id(meta),
             hash: hash,
+            last_used: Some(time::SystemTime::now().into()),
         }
     }
     fn meta_match(&self, meta: &Metadata) -> bool {
+        fn same_if_known<T: PartialEq>(cached: &Option<T>,
+                                       actual: &Option<T>)
+                                       -> bool {
+            match (cached, actual) {
+                (Some(c), Some(a)) => c == a,
+                _ => true,
+            }
+        }
         self.size == meta.len() &&
-        *self.mtime == meta.modified().expect("metadata has no mod time")
+        *self.mtime == meta.modified().expect("metadata has no mod time") &&
+        same_if_known(&self.ctime, &change_time(meta)) &&
+        same_if_known(&self.file_id, &file_id(meta))
     }
     fn status(entry: Option<&CacheEntry>, meta: &Metadata) -> CacheStatus {
         match entry {
@@ -69,8 +107,51 @@ impl CacheEntry {
             _ => None,
         }
     }
+    /// Has it been long enough since the last use to record a new one?
+    fn use_is_stale(&self, now: time::SystemTime) -> bool {
+        match self.last_used {
+            None => true,
+            Some(ref last_used) => {
+                now.duration_since(**last_used)
+                    .map(|age| age.as_secs() >= LAST_USED_RESOLUTION_SECS)
+                    .unwrap_or(false)
+            }
+        }
+    }
+    /// Last use as seconds and nanos, for sorting, with unknown as oldest
+    fn last_used_key(&self) -> Option<(u64, u32)> {
+        self.last_used.as_ref().map(|t| t.secs_nanos_since_epoch())
+    }
 }
 
+/// How often to update an entry's last-used time
+const LAST_USED_RESOLUTION_SECS: u64 = 24 * 60 * 60;
+
+
+/// The time the file's inode last changed, which the user cannot set
+#[cfg(unix)]
+fn change_time(meta: &Metadata) -> Option<encodable::SystemTime> {
+    use std::os::unix::fs::MetadataExt;
+    if meta.ctime() < 0 {
+        return None;
+    }
+    Some(encodable::SystemTime::unix_epoch_plus(meta.ctime() as u64,
+                                                meta.ctime_nsec() as u32))
+}
+
+#[cfg(not(unix))]
+fn change_time(_meta: &Metadata) -> Option<encodable::SystemTime> { None }
+
+/// A number identifying the file on its device: the inode number on Unix
+#[cfg(unix)]
+fn file_id(meta: &Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(meta.ino())
+}
+
+#[cfg(not(unix))]
+fn file_id(_meta: &Metadata) -> Option<u64> { None }
+
 
 
 wrapper_struct!{
@@ -119,17 +200,43 @@ impl Encodable for HashCache {
     fn encode<S: Encoder>(&self,
                           s: &mut S)
                           -> ::std::result::Result<(), S::Error> {
-        Encodable::encode(&self.0, s)
+        s.emit_struct("HashCache", 2, |s| {
+            s.emit_struct_field("version", 0, |s| CACHE_VERSION.encode(s))?;
+            s.emit_struct_field("entries", 1, |s| self.0.encode(s))
+        })
     }
 }
 
 impl Decodable for HashCache {
     fn decode<D: Decoder>(d: &mut D) -> ::std::result::Result<Self, D::Error> {
-        let cache_map = <HashMap<_,_> as Decodable>::decode(d)?;
-        Ok(HashCache(cache_map))
+        d.read_struct("HashCache", 2, |d| {
+            let version: u32 =
+                d.read_struct_field("version", 0, Decodable::decode)?;
+            if version != CACHE_VERSION {
+                return Err(d.error(&format!("unknown cache version {}",
+                                            version)));
+            }
+            let cache_map =
+                d.read_struct_field("entries", 1, Decodable::decode)?;
+            Ok(HashCache(cache_map))
+        })
     }
 }
 
+/// Read a version 1 cache file, which was a bare map of entries
+///
+/// The entries' ctime and file ID fields are missing in version 1, so they
+/// decode as None.
+fn read_v1_cache(cache_path: &path::Path) -> Result<HashCache> {
+    let mut json = String::new();
+    fs::File::open(cache_path)?.read_to_string(&mut json)?;
+    let cache_map = json::decode(&json)
+        .chain_err(|| {
+            format!("Could not read cache file {}", cache_path.display())
+        })?;
+    Ok(HashCache(cache_map))
+}
+
 impl Hash for HashCache {
     fn hash<H: Hasher>(&self, state: &mut H) {
         for entry in &self.0 {
@@ -140,18 +247,50 @@ impl Hash for HashCache {
 
 
 
+/// Counts from `AllCaches::prune`
+#[derive(Debug,Clone,Copy,Default,PartialEq,Eq)]
+pub struct CachePruneStats {
+    /// Cache files examined
+    pub cache_files: usize,
+    /// Entries dropped because their file no longer exists
+    pub missing: usize,
+    /// Entries evicted, least recently used first, to fit the size limit
+    pub evicted: usize,
+    /// Entries left in the cache
+    pub kept: usize,
+    /// Bytes of cache files freed
+    pub reclaimed: u64,
+}
+
 /// Cache of caches
+///
+/// Locked, so that sibling directories can be walked on separate threads.
 pub struct AllCaches(// TODO: Use an actual cache that can purge entries
-                     RefCell<HashMap<path::PathBuf, DiskBacked<HashCache>>>);
+                     Mutex<HashMap<path::PathBuf, DiskBacked<HashCache>>>);
+
+impl Default for AllCaches {
+    fn default() -> Self { AllCaches::new() }
+}
 
 impl AllCaches {
-    pub fn new() -> Self { AllCaches(RefCell::new(HashMap::new())) }
+    pub fn new() -> Self { AllCaches(Mutex::new(HashMap::new())) }
 
     fn read_dir_cache(&self, dir_path: &path::Path) -> Result<()> {
-        if self.0.borrow().get(dir_path).is_none() {
+        let mut caches = self.0.lock().unwrap();
+        if caches.get(dir_path).is_none() {
             let cache_path = dir_path.join(constants::CACHE_FILE_NAME);
-            let cache_file = DiskBacked::read_or_default("cache", cache_path)?;
-            self.0.try_borrow_mut()?.insert(dir_path.into(), cache_file);
+            let cache_file =
+                match DiskBacked::read_or_default("cache", cache_path.clone()) {
+                    Ok(cache_file) => cache_file,
+                    Err(e) => {
+                        // Initialized as changed, so written back as current
+                        let cache = read_v1_cache(&cache_path).map_err(|_| e)?;
+                        debug!("Migrating cache from version 1: {}",
+                               cache_path.display());
+                        DiskBacked::init("cache", cache_path, cache)
+                    }
+                };
+            caches.insert(dir_path.into(), cache_file);
         }
         Ok(())
     }
@@ -160,7 +299,7 @@ impl AllCaches {
         let dir_path = file_path.parent_or_err()?;
         let file_name = file_path.file_name_or_err()?;
         self.read_dir_cache(dir_path)?;
-        let caches = self.0.try_borrow()?;
+        let caches = self.0.lock().unwrap();
         let cache = caches.get(dir_path).expect("just read cache");
         Ok(cache.get(file_name).cloned())
     }
@@ -181,7 +320,27 @@ impl AllCaches {
             return Ok(None);
         }
         let entry = self.get(file_path)?;
-        Ok(CacheEntry::check(entry.as_ref(), meta))
+        let hash = CacheEntry::check(entry.as_ref(), meta);
+        let now = time::SystemTime::now();
+        if hash.is_some() && entry.is_some_and(|e| e.use_is_stale(now)) {
+            self.touch(file_path, now)?;
+        }
+        Ok(hash)
+    }
+
+    /// Record that a file's entry was used
+    fn touch(&self,
+             file_path: &path::Path,
+             now: time::SystemTime)
+             -> Result<()> {
+        let dir_path = file_path.parent_or_err()?;
+        let file_name = file_path.file_name_or_err()?;
+        let mut caches = self.0.lock().unwrap();
+        let cache = caches.get_mut(dir_path).expect("just read cache");
+        if let Some(entry) = cache.0.get_mut(path::Path::new(file_name)) {
+            entry.last_used = Some(now.into());
+        }
+        Ok(())
     }
 
     pub fn insert(&self,
@@ -193,12 +352,139 @@ impl AllCaches {
         let dir_path = file_path.parent_or_err()?;
         let file_name = file_path.file_name_or_err()?;
         self.read_dir_cache(dir_path)?;
-        let mut caches = self.0.try_borrow_mut()?;
+        let mut caches = self.0.lock().unwrap();
         let cache = caches.get_mut(dir_path).expect("just read cache");
         Ok(cache.insert_entry(file_name.into(), meta, hash))
     }
 
-    pub fn flush(&mut self) { self.0.borrow_mut().clear() }
+    /// Forget a file's entry, such as after it was moved or removed
+    pub fn remove(&self, file_path: &path::Path) -> Result<()> {
+        let dir_path = file_path.parent_or_err()?;
+        let file_name = file_path.file_name_or_err()?;
+        self.read_dir_cache(dir_path)?;
+        let mut caches = self.0.lock().unwrap();
+        let cache = caches.get_mut(dir_path).expect("just read cache");
+        cache.0.remove(path::Path::new(file_name));
+        Ok(())
+    }
+
+    pub fn flush(&mut self) { self.0.lock().unwrap().clear() }
+
+    /// Clean up the cache files under a directory
+    ///
+    /// Entries for files that no longer exist are dropped. Then, if more than
+    /// `max_entries` are left in all the files together, the least recently
+    /// used are evicted. Cache files left empty are removed.
+    pub fn prune(&mut self,
+                 top: &path::Path,
+                 max_entries: Option<usize>)
+                 -> Result<CachePruneStats> {
+        let mut stats = CachePruneStats::default();
+        self.flush();
+        let mut dirs = Vec::new();
+        find_cache_dirs(top, &mut dirs)?;
+        stats.cache_files = dirs.len();
+
+        let mut size_before = 0;
+        for dir in &dirs {
+            size_before += dir.join(constants::CACHE_FILE_NAME)
+                .metadata()?
+                .len();
+            self.read_dir_cache(dir)?;
+            let mut caches = self.0.lock().unwrap();
+            let cache = caches.get_mut(dir).expect("just read cache");
+            let before = cache.0.len();
+            cache.0.retain(|name, _| {
+                dir.join(&**name)
+                    .symlink_metadata()
+                    .map(|meta| meta.is_file())
+                    .unwrap_or(false)
+            });
+            stats.missing += before - cache.0.len();
+            stats.kept += cache.0.len();
+        }
+
+        if let Some(max_entries) = max_entries {
+            if stats.kept > max_entries {
+                stats.evicted = self.evict_oldest(stats.kept - max_entries)?;
+                stats.kept -= stats.evicted;
+            }
+        }
+
+        let empty: Vec<path::PathBuf> = self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|&(_, cache)| cache.0.is_empty())
+            .map(|(dir, _)| dir.join(constants::CACHE_FILE_NAME))
+            .collect();
+        self.flush();
+        for cache_path in &empty {
+            debug!("Removing empty cache: {}", cache_path.display());
+            fs::remove_file(cache_path)?;
+        }
+
+        let mut size_after = 0;
+        for dir in &dirs {
+            if let Ok(meta) = dir.join(constants::CACHE_FILE_NAME).metadata() {
+                size_after += meta.len();
+            }
+        }
+        stats.reclaimed = size_before.saturating_sub(size_after);
+        Ok(stats)
+    }
+
+    /// Remove the least recently used entries from the loaded caches
+    fn evict_oldest(&self, count: usize) -> Result<usize> {
+        let mut caches = self.0.lock().unwrap();
+        let mut by_age = Vec::new();
+        for (dir, cache) in caches.iter() {
+            for (name, entry) in cache.0.iter() {
+                by_age.push((entry.last_used_key(),
+                             dir.to_owned(),
+                             name.to_path_buf()));
+            }
+        }
+        by_age.sort();
+        by_age.truncate(count);
+        for (_, dir, name) in &by_age {
+            caches.get_mut(dir)
+                .expect("cache was just listed")
+                .0
+                .remove(name.as_path());
+        }
+        Ok(by_age.len())
+    }
+}
+
+/// Number and total size of the cache files under a directory
+pub fn cache_files_size(top: &path::Path) -> Result<(usize, u64)> {
+    let mut dirs = Vec::new();
+    find_cache_dirs(top, &mut dirs)?;
+    let mut size = 0;
+    for dir in &dirs {
+        size += dir.join(constants::CACHE_FILE_NAME).metadata()?.len();
+    }
+    Ok((dirs.len(), size))
+}
+
+/// Find directories that have cache files, skipping the hidden directory
+fn find_cache_dirs(dir: &path::Path,
+                   found: &mut Vec<path::PathBuf>)
+                   -> Result<()> {
+    if dir.join(constants::CACHE_FILE_NAME).is_file() {
+        found.push(dir.to_owned());
+    }
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name() == constants::HIDDEN_DIR_NAME {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            find_cache_dirs(&entry.path(), found)?;
+        }
+    }
+    Ok(())
 }
 
 
@@ -208,7 +494,9 @@ mod test {
     use dag::ObjectKey;
     use encodable;
     use rustc_serialize::json;
+    use std::fs;
     use super::*;
+    use testutil;
 
     #[test]
     fn test_serialize_filecache() {
@@ -216,10 +504,95 @@ mod test {
         obj.insert(encodable::PathBuf::from("patha/x"), CacheEntry{
                 mtime: encodable::SystemTime::unix_epoch_plus(120, 55),
                 size: 12345,
+                ctime: Some(encodable::SystemTime::unix_epoch_plus(130, 0)),
+                file_id: Some(42),
             hash: ObjectKey::from("d3486ae9136e7856bc42212385ea797094475802"),
+                last_used: None,
         });
         let encoded = json::encode(&obj).unwrap();
         let decoded: HashCache = json::decode(&encoded).unwrap();
         assert_eq!(decoded, obj);
     }
+
+    #[test]
+    fn test_migrate_v1_cache() {
+        let temp = in_mem_tempdir!();
+        let cache_path = temp.path().join(constants::CACHE_FILE_NAME);
+        testutil::write_file(&cache_path,
+                             r#"{"foo": {"mtime": [120, 55], "size": 5,
+                             "hash": "d3486ae9136e7856bc42212385ea797094475802"
+                             }}"#)
+            .unwrap();
+
+        let mut caches = AllCaches::new();
+        let entry = caches.get(&temp.path().join("foo")).unwrap().unwrap();
+        assert_eq!(entry.mtime,
+                   encodable::SystemTime::unix_epoch_plus(120, 55));
+        assert_eq!(entry.ctime, None);
+        assert_eq!(entry.file_id, None);
+        caches.flush();
+
+        let json = testutil::read_file_to_string(&cache_path).unwrap();
+        assert!(json.contains("\"version\": 2"),
+                "should write back the current version, got: {}",
+                json);
+    }
+
+    #[test]
+    fn test_replaced_file_with_same_size_and_mtime() {
+        let temp = in_mem_tempdir!();
+        let path = temp.path().join("foo");
+        let other = temp.path().join("other");
+        testutil::write_file(&path, "aaaa").unwrap();
+        testutil::write_file(&other, "bbbb").unwrap();
+        let mtime = path.metadata().unwrap().modified().unwrap();
+        fs::File::open(&other).unwrap().set_modified(mtime).unwrap();
+
+        let hash = ObjectKey::from("d3486ae9136e7856bc42212385ea797094475802");
+        let caches = AllCaches::new();
+        caches.insert(path.clone(), &path.metadata().unwrap(), hash).unwrap();
+        assert_eq!(caches.check(&path, &path.metadata().unwrap()).unwrap(),
+                   Some(hash));
+
+        fs::rename(&other, &path).unwrap();
+        let meta = path.metadata().unwrap();
+        assert_eq!(meta.modified().unwrap(), mtime);
+        assert_eq!(caches.check(&path, &meta).unwrap(),
+                   None,
+                   "should notice the new file despite same size and mtime");
+    }
+
+    #[test]
+    fn test_prune() {
+        let temp = in_mem_tempdir!();
+        let hash = ObjectKey::from("d3486ae9136e7856bc42212385ea797094475802");
+        let mut caches = AllCaches::new();
+        for name in &["a", "b", "gone", "sub/c"] {
+            let path = temp.path().join(name);
+            testutil::write_file(&path, *name).unwrap();
+            caches.insert(path.clone(), &path.metadata().unwrap(), hash)
+                .unwrap();
+        }
+        fs::remove_file(temp.path().join("gone")).unwrap();
+        for &(name, secs) in &[("a", 100), ("b", 200)] {
+            let mut all = caches.0.lock().unwrap();
+            let cache = all.get_mut(temp.path()).unwrap();
+            cache.0.get_mut(path::Path::new(name)).unwrap().last_used =
+                Some(encodable::SystemTime::unix_epoch_plus(secs, 0));
+        }
+
+        let stats = caches.prune(temp.path(), Some(2)).unwrap();
+        assert_eq!((stats.cache_files, stats.missing, stats.evicted,
+                    stats.kept),
+                   (2, 1, 1, 2));
+        assert!(stats.reclaimed > 0);
+
+        let check = |name: &str| {
+            let path = temp.path().join(name);
+            caches.check(&path, &path.metadata().unwrap()).unwrap()
+        };
+        assert_eq!(check("a"), None, "Least recently used should be evicted");
+        assert_eq!(check("b"), Some(hash));
+        assert_eq!(check("sub/c"), Some(hash));
+    }
 }
@@ -1,42 +1,190 @@
 //! High-level commands
 
+use archive;
+use archive::ArchiveFormat;
+use backup;
+use backup::DirBackend;
+use bench;
+use bisect::Bisect;
+use bisect::BisectStep;
 use cache::AllCaches;
+use cache::cache_files_size;
+use cache::CacheStatus;
+use config::EncryptionScope;
+use config::RepoConfig;
+use constants::CACHE_FILE_NAME;
+use constants::HIDDEN_DIR_NAME;
+use constants::IGNORE_FILE_NAME;
+use constants::WATCH_STATUS_LINES;
+use dag::HashAlgorithm;
 use dag::ObjectCommon;
 use dag::ObjectHandle;
+use dag::ObjectKey;
+use derive;
 use error::*;
+use find_repo::requested_layout;
 use find_repo::RepoLayout;
 use find_repo::find_fs_transfer;
 use find_repo::find_object_store;
 use find_repo::find_work_dir;
+use fsutil;
+use git_stream;
+use grep;
+use grep::GrepMatch;
+use human_readable::human_bytes;
+use journal::Journal;
+use lock::LockHolder;
+use lock::LockMode;
+use lock::RepoLock;
+use media_type::MediaType;
+use messages::Msg;
+#[cfg(all(feature = "mount", target_os = "linux"))]
+use mount;
+use object_store::ChangeStats;
+use object_store::CommitFilter;
+use object_store::FsckOptions;
+use object_store::ObjectStore;
+use object_store::ObjectWalkNode;
+use object_store::RefMap;
+use parity::Parity;
+use pathspec::Pathspec;
+use query::FileQuery;
+use query::query_files;
+use rebase::Rebase;
+use regex::Regex;
+use repository::CommitOptions;
+use repository::LogEntry;
+use repository::Repository;
+use repository::StatusReport;
 use revisions::*;
+use rolling_hash::ChunkParams;
+use rolling_hash::MIN_CHUNK_TARGET_SIZE;
+use signing;
+use signing::Ed25519Signer;
+use signing::SignatureStatus;
+use stash;
+use status::Status;
+use status::StatusEntry;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::env::current_dir;
+use std::fs;
+use std::io;
+use std::io::Write;
 use std::path::Path;
 use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
+use store_format::FanOut;
+use transport;
+use walker::ErrorPolicy;
+use walker::NodeLookup;
+use work_dir::Head;
+use work_dir::LogGraph;
+use work_dir::StateEvent;
+use work_dir::StatusOptions;
+use work_dir::UpstreamStatus;
 use work_dir::WorkDir;
+use work_dir::describe_age;
 
-pub fn init() -> Result<()> {
-    let layout = RepoLayout::in_work_dir(current_dir()?);
-    WorkDir::init(layout)?;
+/// File argument that stands for stdin or stdout
+pub const STDIO_ARG: &'static str = "-";
+
+/// Does this file argument mean stdin or stdout?
+fn is_stdio(path: &Path) -> bool { path == Path::new(STDIO_ARG) }
+
+pub fn init(chunk_size: Option<usize>,
+            hash: Option<HashAlgorithm>,
+            preserve_metadata: bool,
+            extract_metadata: bool,
+            encryption: Option<EncryptionScope>,
+            fan_out: Option<FanOut>)
+            -> Result<()> {
+    let mut config = RepoConfig {
+        encryption: encryption,
+        ..RepoConfig::default()
+    };
+    if let Some(hash) = hash {
+        config.hash_algorithm = Some(hash);
+    }
+    if preserve_metadata {
+        config.preserve_metadata = Some(true);
+    }
+    if extract_metadata {
+        config.extract_metadata = Some(true);
+    }
+    if let Some(chunk_size) = chunk_size {
+        if chunk_size < MIN_CHUNK_TARGET_SIZE {
+            bail!(msg!(Msg::ChunkSizeTooSmall, MIN_CHUNK_TARGET_SIZE));
+        }
+        config.chunking = ChunkParams::for_target_size(chunk_size);
+    }
+    let layout = match requested_layout()? {
+        Some(layout) => layout,
+        None => RepoLayout::in_work_dir(current_dir()?),
+    };
+    let mut work_dir = WorkDir::init(layout, config)?;
+    if let Some(fan_out) = fan_out {
+        work_dir.object_store.repack(Some(fan_out))?;
+    }
     Ok(())
 }
 
 pub fn hash_object(path: PathBuf) -> Result<()> {
 
     let mut fs_transfer = find_fs_transfer()?;
-    let hash = fs_transfer.hash_obj_file(None, &path)?;
+    let _lock = fs_transfer.lock(LockMode::Exclusive, "hash-object")?;
+    let hash = if is_stdio(&path) {
+        let stdin = io::stdin();
+        fs_transfer.hash_reader(stdin.lock(), "<stdin>")?
+    } else {
+        fs_transfer.hash_obj_file(None, &path, &Pathspec::all())?
+    };
     println!("{} {}", hash, path.display());
     Ok(())
 }
 
-pub fn show_object(rev: &RevSpec, type_only: bool) -> Result<()> {
+/// Store a directory, printing the hash of each file and then of the whole
+///
+/// Like `hash_object`, this needs only an object store, and leaves refs and
+/// any work dir alone, so that scripts can add content and then decide what
+/// to do with the hashes.
+pub fn import_dir(dir: &Path) -> Result<()> {
+    let mut fs_transfer = find_fs_transfer()?;
+    let _lock = fs_transfer.lock(LockMode::Exclusive, "import-dir")?;
+    let hash = fs_transfer.import_dir(dir)?;
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    for (path, file_hash) in git_stream::flatten_tree(&fs_transfer, &hash)? {
+        writeln!(stdout, "{:x} {}", file_hash, dir.join(path).display())?;
+    }
+    writeln!(stdout, "{:x} {}", hash, dir.display())?;
+    Ok(())
+}
+
+pub fn show_object(rev: &RevSpec,
+                   type_only: bool,
+                   raw: bool,
+                   recursive: bool,
+                   depth: Option<usize>)
+                   -> Result<()> {
 
     let object_store = find_object_store()?;
 
     let (hash, _, _) = object_store.lookup(rev)?;
 
+    if recursive {
+        print!("{}", object_store.object_tree(hash, depth)?);
+        return Ok(());
+    }
     let handle = try!(object_store.open_object(&hash));
     if type_only {
         println!("{}", handle.header().object_type);
+    } else if raw {
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        handle.copy_raw_content(&mut stdout)?;
+        stdout.flush()?;
     } else {
         match handle {
             ObjectHandle::Blob(blobhandle) => {
@@ -47,7 +195,44 @@ pub fn show_object(rev: &RevSpec, type_only: bool) -> Result<()> {
                 print!("{}", object.pretty_print());
             }
         }
+        let media_type = object_store.media_type(&hash)?;
+        if media_type != MediaType::Unknown {
+            println!("Media type: {}", media_type);
+        }
+    }
+    Ok(())
+}
+
+/// Print a file's content from any revision, or list a directory
+pub fn show(rev: &RevSpec) -> Result<()> {
+    let object_store = find_object_store()?;
+    let _lock = shared_lock(&object_store, "show")?;
+    let (hash, _, _) = object_store.lookup(rev)?;
+
+    let stdout = io::stdout();
+    let mut stdout = io::BufWriter::new(stdout.lock());
+    match object_store.open_object(&hash)? {
+        ObjectHandle::Blob(_) |
+        ObjectHandle::ChunkedBlob(_) => {
+            object_store.copy_blob_content(&hash, &mut stdout)?;
+        }
+        ObjectHandle::Tree(_) => {
+            for (name, child) in object_store.open_tree(&hash)?.iter() {
+                let node: ObjectWalkNode = object_store.lookup_node(*child)?;
+                let name = name.to_string_lossy();
+                if node.object_type.is_treeish() {
+                    writeln!(stdout, "{}/", name)?;
+                } else {
+                    writeln!(stdout, "{}", name)?;
+                }
+            }
+        }
+        ObjectHandle::Commit(_) => {
+            let commit = object_store.open_commit(&hash)?;
+            writeln!(stdout, "{} {}", hash, commit.message)?;
+        }
     }
+    stdout.flush()?;
     Ok(())
 }
 
@@ -59,85 +244,711 @@ pub fn parents() -> Result<()> {
     Ok(())
 }
 
-pub fn ls_files(rev: Option<RevSpec>, verbose: bool) -> Result<()> {
+/// Print lines matching a pattern, in the work dir or in a revision
+///
+/// With `cached`, HEAD is searched rather than the work dir.
+pub fn grep(pattern: &str, rev: Option<RevSpec>, cached: bool) -> Result<()> {
+    let pattern = Regex::new(pattern)
+        .chain_err(|| format!("Bad pattern: {}", pattern))?;
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    let mut print = |prefix: &str, found: GrepMatch| -> Result<()> {
+        writeln!(stdout,
+                 "{}{}:{}:{}",
+                 prefix,
+                 found.path.display(),
+                 found.line_number,
+                 found.line)?;
+        Ok(())
+    };
+    let wd = find_work_dir()?;
+    let _lock = shared_lock(&wd, "grep")?;
+    match (rev, cached) {
+        (Some(rev), _) => {
+            let (tree, _, _) = wd.lookup(&rev)?;
+            let prefix = format!("{}:", rev);
+            grep::grep_tree(&wd, &tree, &pattern, |m| print(&prefix, m))
+        }
+        (None, true) => {
+            let mut tree = wd.head()
+                .ok_or_else(|| msg!(Msg::NoCommitSpecified))?;
+            if let Some(ref path) = wd.state.subtree {
+                tree = wd.lookup_rev_path(&tree, path)?;
+            }
+            grep::grep_tree(&wd, &tree, &pattern, |m| print("", m))
+        }
+        (None, false) => {
+            grep::grep_dir(&wd.file_store, wd.path(), &pattern, |m| {
+                print("", m)
+            })
+        }
+    }
+}
+
+pub fn ls_files(rev: Option<RevSpec>,
+                verbose: bool,
+                tree: bool,
+                sort_by_size: bool,
+                pathspec: &Pathspec)
+                -> Result<()> {
 
     match rev {
         Some(ref r) => {
             let object_store = &find_object_store()?;
+            let _lock = shared_lock(object_store, "ls-files")?;
             let (hash, _, _) = object_store.lookup(r)?;
-            print!("{}", object_store.ls_files(hash, verbose)?);
+            print_files(object_store,
+                        hash,
+                        verbose,
+                        tree,
+                        sort_by_size,
+                        pathspec)?;
         }
         None => {
             let wd = find_work_dir()?;
+            let _lock = shared_lock(&wd, "ls-files")?;
             let mut hash = wd.head()
-                .ok_or_else(|| "No commit specified and no parent commit")?;
+                .ok_or_else(|| msg!(Msg::NoCommitSpecified))?;
             if let &Some(ref path) = &wd.state.subtree {
                 hash = wd.object_store.lookup_rev_path(&hash, path)?;
             }
-            print!("{}", wd.ls_files(hash, verbose)?);
+            print_files(&wd, hash, verbose, tree, sort_by_size, pathspec)?;
         }
     };
     Ok(())
 }
 
+fn print_files(object_store: &ObjectStore,
+               hash: ObjectKey,
+               verbose: bool,
+               tree: bool,
+               sort_by_size: bool,
+               pathspec: &Pathspec)
+               -> Result<()> {
+    if tree {
+        print!("{}", object_store.size_tree(hash, sort_by_size)?);
+    } else {
+        print!("{}", object_store.ls_files(hash, verbose, pathspec)?);
+    }
+    Ok(())
+}
+
 pub fn extract_object(rev: &RevSpec, file_path: &Path) -> Result<()> {
 
     let fs_transfer = find_fs_transfer()?;
     let (hash, _, _) = fs_transfer.lookup(rev)?;
-    fs_transfer.extract_object(&hash, &file_path)
+    if is_stdio(file_path) {
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        fs_transfer.extract_to_writer(&hash, &mut stdout)
+    } else {
+        fs_transfer.extract_object(&hash, file_path)
+    }
+}
+
+pub fn archive(rev: &RevSpec,
+               output: Option<&Path>,
+               format: Option<ArchiveFormat>,
+               prefix: Option<&str>)
+               -> Result<()> {
+    let object_store = find_object_store()?;
+    let _lock = shared_lock(&object_store, "archive")?;
+    let (hash, _, _) = object_store.lookup(rev)?;
+
+    // A single file is named after its path, unless a prefix is given
+    let node: ObjectWalkNode = object_store.lookup_node(hash)?;
+    let prefix = match prefix {
+        Some(prefix) => prefix.to_owned(),
+        None if node.object_type.is_blobish() => {
+            rev.path
+                .as_ref()
+                .and_then(|p| p.file_name())
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_else(|| hash.to_hex())
+        }
+        None => String::new(),
+    };
+
+    let stats = match output {
+        Some(path) if !is_stdio(path) => {
+            let format = format.unwrap_or_else(|| {
+                ArchiveFormat::for_file_name(&path.to_string_lossy())
+            });
+            let file = fs::File::create(path)
+                .chain_err(|| format!("Could not create {}", path.display()))?;
+            archive::write_archive(&object_store,
+                                   &hash,
+                                   format,
+                                   &prefix,
+                                   io::BufWriter::new(file))?
+        }
+        _ => {
+            let stdout = io::stdout();
+            archive::write_archive(&object_store,
+                                   &hash,
+                                   format.unwrap_or(ArchiveFormat::Tar),
+                                   &prefix,
+                                   io::BufWriter::new(stdout.lock()))?
+        }
+    };
+    stderrln!("{}",
+              msg!(Msg::ArchivedFiles,
+                   stats.files,
+                   stats.dirs,
+                   human_bytes(stats.bytes)));
+    Ok(())
 }
 
-pub fn cache_status(file_path: PathBuf) -> Result<()> {
+pub fn cache_status(file_path: PathBuf, paranoid: bool) -> Result<()> {
     let cache = AllCaches::new();
     let cache_status = cache.status(&file_path, &file_path.metadata()?)?;
     println!("{} {}", cache_status, file_path.display());
+    if paranoid {
+        if let CacheStatus::Cached(cached) = cache_status {
+            let fs_transfer = find_fs_transfer()?;
+            let algorithm = fs_transfer.config().hash_algorithm();
+            let actual = fs_transfer.file_store
+                .rehash_file(&file_path, algorithm)?;
+            if actual != cached {
+                bail!(msg!(Msg::StaleCacheEntry,
+                           file_path.display(),
+                           cached,
+                           actual));
+            }
+            stderrln!("{}", msg!(Msg::CacheEntryVerified));
+        }
+    }
+    Ok(())
+}
+
+pub fn prime_cache() -> Result<()> {
+    let mut work_dir = find_work_dir()?;
+    let _lock = shared_lock(&work_dir, "prime-cache")?;
+    let stats = work_dir.prime_cache()?;
+    stderrln!("{}",
+              msg!(Msg::PrimedCache,
+                   stats.primed,
+                   stats.already_cached,
+                   stats.changed,
+                   stats.missing));
+    Ok(())
+}
+
+/// Rehash a sample of cached files, or all with None, to find corruption
+pub fn verify_work_dir(sample_percent: Option<u32>) -> Result<()> {
+    let work_dir = find_work_dir()?;
+    let _lock = shared_lock(&work_dir, "verify-work-dir")?;
+    let stats = work_dir.verify_files(sample_percent)?;
+    for (path, expected) in &stats.corrupt {
+        println!("{}", msg!(Msg::CorruptFile, path.display(), expected));
+    }
+    stderrln!("{}",
+              msg!(Msg::VerifiedFiles,
+                   stats.rehashed,
+                   stats.unchanged,
+                   stats.not_cached,
+                   stats.missing));
+    if !stats.corrupt.is_empty() {
+        bail!(msg!(Msg::WorkDirHasCorruptFiles, stats.corrupt.len()));
+    }
+    Ok(())
+}
+
+pub fn prune_cache(max_entries: usize) -> Result<()> {
+    let mut work_dir = find_work_dir()?;
+    let _lock = shared_lock(&work_dir, "prune-cache")?;
+    let top = work_dir.path().to_owned();
+    let stats = work_dir.file_store.cache.prune(&top, Some(max_entries))?;
+    stderrln!("{}",
+              msg!(Msg::PrunedCache,
+                   stats.cache_files,
+                   stats.missing,
+                   stats.evicted,
+                   stats.kept,
+                   human_bytes(stats.reclaimed)));
     Ok(())
 }
 
+/// Show changed files
+///
+/// `args` are up to two revisions to compare, then paths or patterns, and
+/// `paths` are more paths, given after `--`.
 pub fn status(show_ignored: bool,
-              rev1: Option<RevSpec>,
-              rev2: Option<RevSpec>)
+              args: &[String],
+              paths: &[String])
               -> Result<()> {
     let mut work_dir = find_work_dir()?;
-    work_dir.status(show_ignored, rev1, rev2)
+    let (revs, paths) = revs_then_paths(&work_dir, args, paths)?;
+    let pathspec = work_dir.pathspec(&paths)?;
+    let mut revs = revs.into_iter();
+    let (rev1, rev2) = (revs.next(), revs.next());
+    if rev1.is_none() {
+        let mut repo = Repository::from_work_dir(work_dir);
+        let report = repo.status_paths(show_ignored, &pathspec)?;
+        print_write_in_progress(&report.write_in_progress);
+        for line in describe_state(&report) {
+            stderrln!("{}", line);
+        }
+        stderrln!();
+        for entry in report.changes {
+            println!("{}", entry);
+        }
+        return Ok(());
+    }
+    let _lock = shared_lock(&work_dir, "status")?;
+    let options = StatusOptions {
+        show_ignored: show_ignored,
+        pathspec: pathspec,
+        errors: ErrorPolicy::Continue,
+    };
+    work_dir.status_each(&options, rev1, rev2, |entry| {
+        println!("{}", entry);
+        Ok(())
+    })
 }
 
-pub fn commit(message: String) -> Result<()> {
-    let mut work_dir = find_work_dir()?;
-    let (branch, hash) = work_dir.commit(message)?;
-    println!("{} is now {}", branch.unwrap_or("<detached head>"), hash);
+/// Split arguments into up to two revisions, then paths or patterns
+///
+/// An argument is taken as a revision if it can be looked up. Otherwise it
+/// and all after it are paths, which must exist or be patterns, so that a
+/// mistyped revision is not quietly taken as a path that matches nothing.
+/// An argument that could be either is refused. `more_paths`, given after
+/// `--`, are taken as they are.
+fn revs_then_paths(work_dir: &WorkDir,
+                   args: &[String],
+                   more_paths: &[String])
+                   -> Result<(Vec<RevSpec>, Vec<String>)> {
+    let mut revs = Vec::new();
+    let mut paths = Vec::new();
+    for arg in args {
+        let is_path = Path::new(arg).exists() ||
+                      arg.contains(['*', '?']);
+        if paths.is_empty() && revs.len() < 2 {
+            let rev = arg.parse::<RevSpec>().ok();
+            if let Some(rev) = rev.filter(|rev| work_dir.lookup(rev).is_ok()) {
+                if is_path {
+                    bail!("{} is both a revision and a path. Give paths \
+                           after -- to tell them apart",
+                          arg);
+                }
+                revs.push(rev);
+                continue;
+            }
+        }
+        if !is_path {
+            bail!("{} is not a revision or a path. Give paths that do not \
+                   exist after --",
+                  arg);
+        }
+        paths.push(arg.clone());
+    }
+    paths.extend(more_paths.iter().cloned());
+    Ok((revs, paths))
+}
+
+/// Lines describing the state of the work dir, for `status`
+fn describe_state(report: &StatusReport) -> Vec<String> {
+    let mut lines = vec![describe_branch(report)];
+    if let Some(ref subtree) = report.subtree {
+        lines.push(msg!(Msg::Subtree, subtree.display()));
+    }
+    let now = SystemTime::now();
+    for &(time, msg) in &[(report.last_commit, Msg::LastCommit),
+                          (report.last_checkout, Msg::LastCheckout),
+                          (report.last_sync, Msg::LastSync)] {
+        if let Some(time) = time {
+            let age = now.duration_since(time)
+                .unwrap_or(Duration::from_secs(0));
+            lines.push(msg!(msg, describe_age(age)));
+        }
+    }
+    lines.extend(report.upstream.as_ref().map(describe_upstream));
+    for (i, (parent, message)) in report.parents.iter().enumerate() {
+        let parent_name = match report.parents.len() {
+            1 => "HEAD".to_owned(),
+            _ => format!("P{}", i),
+        };
+        lines.push(format!("{}: {} {}", parent_name, parent, message));
+    }
+    lines
+}
+
+fn describe_branch(report: &StatusReport) -> String {
+    describe_head(report.head.as_ref())
+}
+
+/// Where HEAD is, as the first line of `status`
+fn describe_head(head: Option<&Head>) -> String {
+    match head {
+        Some(head) => head.to_string(),
+        None => msg!(Msg::OnBranch, msg!(Msg::DetachedHead)),
+    }
+}
+
+/// Describe how the current branch compares to its tracking ref
+fn describe_upstream(upstream: &UpstreamStatus) -> String {
+    let name = &upstream.name;
+    match (upstream.ahead, upstream.behind) {
+        (0, 0) => msg!(Msg::UpToDateWith, name),
+        (ahead, 0) => msg!(Msg::AheadOf, name, count_commits(ahead)),
+        (0, behind) => msg!(Msg::BehindOf, name, count_commits(behind)),
+        (ahead, behind) => msg!(Msg::DivergedFrom, name, ahead, behind),
+    }
+}
+
+fn count_commits(count: usize) -> String {
+    match count {
+        1 => "1 commit".to_owned(),
+        n => format!("{} commits", n),
+    }
+}
+
+/// Show a condensed status, and show it again whenever files change
+///
+/// After the first status, only the paths that changed are checked again,
+/// unless HEAD moved or an ignore file changed.
+pub fn status_watch(show_ignored: bool) -> Result<()> {
+    let mut repo = find_repository()?;
+    let mut watcher = fsutil::watch_dir(repo.work_dir().path(),
+                                        &[HIDDEN_DIR_NAME, CACHE_FILE_NAME])?;
+    let mut pathspec = Pathspec::all();
+    let mut changes: BTreeMap<PathBuf, StatusEntry> = BTreeMap::new();
+    let mut parents = None;
+    loop {
+        let mut report = repo.status_paths(show_ignored, &pathspec)?;
+        if parents.as_ref() != Some(&report.parents) && !pathspec.is_all() {
+            // HEAD moved, so everything must be compared again
+            pathspec = Pathspec::all();
+            continue;
+        }
+        parents = Some(report.parents.clone());
+        changes = changes.into_iter()
+            .filter(|(path, _)| !pathspec.matches(path))
+            .chain(report.changes.drain(..).map(|e| (e.path.clone(), e)))
+            .collect();
+        // Clear the screen and start at the top
+        print!("\x1b[H\x1b[2J");
+        print_write_in_progress(&report.write_in_progress);
+        println!("{}", describe_branch(&report));
+        if let Some(ref upstream) = report.upstream {
+            println!("{}", describe_upstream(upstream));
+        }
+        println!();
+        for entry in changes.values().take(WATCH_STATUS_LINES) {
+            println!("{}", entry);
+        }
+        if changes.len() > WATCH_STATUS_LINES {
+            let more = changes.len() - WATCH_STATUS_LINES;
+            println!("{}", msg!(Msg::MoreChanges, more));
+        }
+        println!();
+        println!("{}", msg!(Msg::WatchingForChanges, changes.len()));
+        io::stdout().flush()?;
+        let changed = watcher.wait()?;
+        pathspec = watch_pathspec(repo.work_dir().path(), &changed, &changes);
+    }
+}
+
+/// Paths to check again after a watcher saw changes
+///
+/// A change below a path already reported as changed, such as a file in a
+/// new directory, checks the whole reported path again. A change to the top
+/// of the work dir, or to an ignore file, checks everything.
+fn watch_pathspec(root: &Path,
+                  changed: &[PathBuf],
+                  changes: &BTreeMap<PathBuf, StatusEntry>)
+                  -> Pathspec {
+    let mut paths = BTreeSet::new();
+    for path in changed {
+        let path = match path.strip_prefix(root) {
+            Ok(path) if !path.as_os_str().is_empty() => path,
+            _ => return Pathspec::all(),
+        };
+        if path.file_name().is_some_and(|name| name == IGNORE_FILE_NAME) {
+            return Pathspec::all();
+        }
+        let reported = changes.keys().find(|p| path.starts_with(p));
+        paths.insert(reported.map_or(path, |p| p.as_path()).to_owned());
+    }
+    Pathspec::new(paths.iter().map(|path| path.to_string_lossy()))
+}
+
+pub fn commit(message: Option<String>,
+              amend: bool,
+              verbose: bool,
+              verify: bool,
+              sign: bool,
+              force: bool,
+              paths: &[String])
+              -> Result<()> {
+    let signer = match sign {
+        true => Some(signing::signer_from_env()?),
+        false => None,
+    };
+    let mut repo = find_repository()?;
+    let options = CommitOptions {
+        verify: verify,
+        signer: signer.as_deref(),
+        verbose: verbose,
+        pathspec: repo.work_dir().pathspec(paths)?,
+        force: force,
+    };
+    let outcome = match (amend, message) {
+        (true, message) => {
+            let old = repo.head();
+            let outcome = repo.amend(message, &options)?;
+            if let Some(old) = old {
+                stderrln!("{}", msg!(Msg::Amended, old));
+            }
+            outcome
+        }
+        (false, Some(message)) => repo.commit(message, &options)?,
+        (false, None) => bail!("A commit message is required"),
+    };
+    if let Some(count) = outcome.verified {
+        stderrln!("{}", msg!(Msg::VerifiedObjects, count));
+    }
+    for warning in &outcome.size_warnings {
+        stderrln!("{}", msg!(Msg::SizeWarning, warning));
+    }
+    let detached = msg!(Msg::DetachedHead);
+    println!("{}",
+             msg!(Msg::BranchIsNow,
+                  outcome.branch.as_ref().unwrap_or(&detached),
+                  outcome.hash));
+    Ok(())
+}
+
+/// Show the size policy, after changing any limits given
+///
+/// A limit of `Some(None)` removes that limit.
+pub fn policy(max_file_size: Option<Option<u64>>,
+              max_commit_size: Option<Option<u64>>,
+              refuse: Option<bool>)
+              -> Result<()> {
+    let mut object_store = find_object_store()?;
+    let mut policy = *object_store.size_policy();
+    if max_file_size.is_some() || max_commit_size.is_some() ||
+       refuse.is_some() {
+        let _lock = object_store.lock(LockMode::Exclusive, "policy")?;
+        policy.max_file_size = max_file_size.unwrap_or(policy.max_file_size);
+        policy.max_commit_size =
+            max_commit_size.unwrap_or(policy.max_commit_size);
+        policy.refuse = refuse.unwrap_or(policy.refuse);
+        object_store.set_size_policy(policy)?;
+    }
+    println!("{}", policy);
     Ok(())
 }
 
-pub fn log(hash_only: bool) -> Result<()> {
+/// Print the recorded moves of a ref, newest first
+///
+/// Without a name, the current branch is shown, or HEAD if detached.
+pub fn reflog(name: Option<&str>) -> Result<()> {
     let work_dir = find_work_dir()?;
-    work_dir.log(hash_only)
+    let _lock = shared_lock(&work_dir, "reflog")?;
+    let name = name.or(work_dir.branch()).unwrap_or("HEAD");
+    let now = SystemTime::now();
+    for entry in work_dir.reflog().entries(name) {
+        let age = now.duration_since(*entry.time)
+            .unwrap_or(Duration::from_secs(0));
+        let message = work_dir.open_commit(&entry.new)
+            .map(|commit| commit.message)
+            .unwrap_or_default();
+        match entry.old {
+            Some(old) => {
+                println!("{} {} ({} ago, was {}) {}",
+                         entry.new,
+                         entry.reason,
+                         describe_age(age),
+                         old,
+                         message)
+            }
+            None => {
+                println!("{} {} ({} ago) {}",
+                         entry.new,
+                         entry.reason,
+                         describe_age(age),
+                         message)
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Print commit history
+///
+/// History with gaps, from a filter or a range with a start, is printed as a
+/// plain list, since the graph would have loose ends.
+pub fn log(hash_only: bool,
+           range: Option<RevRange>,
+           filter: CommitFilter,
+           verify: bool,
+           stat: bool)
+           -> Result<()> {
+    let repo = find_repository()?;
+    let entries = repo.log(range.as_ref(), &filter)?;
+    print_write_in_progress(&entries.write_in_progress);
+    if hash_only {
+        for entry in entries {
+            println!("{:x}", entry.hash);
+        }
+        return Ok(());
+    }
+    let mut graph = match entries.is_complete() {
+        true => Some(LogGraph::new()),
+        false => None,
+    };
+    for entry in entries {
+        let mut line = describe_log_entry(&entry, verify)?;
+        if stat {
+            let stats = repo.work_dir().commit_change_stats(&entry.hash)?;
+            line.push(' ');
+            line.push_str(&describe_change_stats(&stats));
+        }
+        match graph {
+            Some(ref mut graph) => {
+                graph.print_commit(&entry.hash, &entry.commit.parents, &line)
+            }
+            None => println!("{}", line),
+        }
+    }
+    Ok(())
 }
 
-pub fn branch_list() -> Result<()> {
+/// One line for the log: hash, refs pointing to it, and message
+///
+/// With `verify`, the line ends with the result of checking the commit's
+/// signature.
+fn describe_log_entry(entry: &LogEntry, verify: bool) -> Result<String> {
+    let mut line = match entry.refs.len() {
+        0 => format!("{} {}", entry.hash, entry.commit.message),
+        _ => {
+            format!("{} ({}) {}",
+                    entry.hash,
+                    entry.refs.join(", "),
+                    entry.commit.message)
+        }
+    };
+    if verify {
+        let status = match entry.signature_status()? {
+            SignatureStatus::Unsigned => msg!(Msg::Unsigned),
+            SignatureStatus::Good(signer) => msg!(Msg::GoodSignature, signer),
+            SignatureStatus::Bad(reason) => msg!(Msg::BadSignature, reason),
+        };
+        line.push(' ');
+        line.push_str(&status);
+    }
+    Ok(line)
+}
+
+/// Counts of changed files, and the change in size, such as `+1.5 KiB`
+fn describe_change_stats(stats: &ChangeStats) -> String {
+    let sign = if stats.byte_delta < 0 { "-" } else { "+" };
+    let delta = format!("{}{}",
+                        sign,
+                        human_bytes(stats.byte_delta.unsigned_abs()));
+    msg!(Msg::LogStat, stats.added, stats.modified, stats.deleted, delta)
+}
+
+pub fn gen_signing_key(path: &Path) -> Result<()> {
+    let signer = Ed25519Signer::generate(path)?;
+    println!("{}", msg!(Msg::GeneratedSigningKey, path.display()));
+    println!("{}", signer.public_key());
+    Ok(())
+}
+
+pub fn branch_list(verbose: bool, contains: Option<&RevSpec>) -> Result<()> {
     let work_dir = find_work_dir()?;
-    for (name, _) in work_dir.refs() {
-        if work_dir.branch() == Some(name.as_str()) {
+    let is_branch = |name: &str| {
+        !transport::is_tracking_ref(name) && !stash::is_stash_ref(name)
+    };
+    let names = match contains {
+        Some(rev) => {
+            work_dir.refs_containing(&work_dir.lookup(rev)?.1, is_branch)?
+        }
+        None => {
+            work_dir.refs()
+                .keys()
+                .filter(|name| is_branch(name))
+                .cloned()
+                .collect()
+        }
+    };
+    let width = names.iter().map(|name| name.len()).max();
+    let head = work_dir.head();
+    let now = SystemTime::now();
+    for name in &names {
+        let hash = work_dir.refs()[name];
+        let current = work_dir.branch() == Some(name.as_str());
+        if current {
             print!("* ");
         } else {
             print!("  ");
         }
-        println!("{}", name)
+        if !verbose {
+            println!("{}", name);
+            continue;
+        }
+        let age = now.duration_since(work_dir.stored_time(&hash)?)
+            .unwrap_or(Duration::from_secs(0));
+        print!("{:width$} {} (stored {} ago)",
+               name,
+               hash,
+               describe_age(age),
+               width = width.unwrap_or(0));
+        if let (false, Some(head)) = (current, head) {
+            match work_dir.count_ahead_behind(&hash, &head)? {
+                (0, 0) => {}
+                (ahead, behind) => {
+                    print!(" {}", msg!(Msg::BranchVersusHead, ahead, behind))
+                }
+            }
+        }
+        if let Some((upstream, theirs)) = work_dir.upstream(name) {
+            match work_dir.count_ahead_behind(&hash, &theirs)? {
+                (0, 0) => print!(" {}", msg!(Msg::BranchTracking, upstream)),
+                (ahead, behind) => {
+                    let counts =
+                        msg!(Msg::BranchAheadBehind, upstream, ahead, behind);
+                    print!(" {}", counts)
+                }
+            }
+        }
+        let commit = work_dir.open_commit(&hash)?;
+        println!(" {}", commit.message.lines().next().unwrap_or(""));
     }
     Ok(())
 }
 
 pub fn branch_set(branch_name: RevNameBuf, target: RevSpec) -> Result<()> {
     let mut object_store = find_object_store()?;
+    let _lock = object_store.lock(LockMode::Exclusive, "branch")?;
     let (_, commit, _) = object_store.lookup(&target)?;
     object_store.update_ref(branch_name, commit)
 }
 
+/// Wait for a ref to move, then print where it points
+///
+/// No lock is held while waiting, or the command that moves the ref could
+/// not run.
+pub fn wait_for_ref(name: &str, from: Option<&RevSpec>) -> Result<()> {
+    let mut object_store = find_object_store()?;
+    let from = match from {
+        Some(rev) => Some(object_store.lookup(rev)?.1),
+        None => object_store.try_find_ref(name),
+    };
+    match object_store.wait_for_ref(name, from)? {
+        Some(hash) => println!("{}", hash),
+        None => stderrln!("{}", msg!(Msg::RefDeleted, name)),
+    }
+    Ok(())
+}
+
 pub fn branch_set_to_head(branch_name: RevNameBuf) -> Result<()> {
     let mut work_dir = find_work_dir()?;
-    work_dir.update_ref_to_head(branch_name.clone())?;
-    work_dir.checkout(&branch_name.parse()?)?;
-    Ok(())
+    let _lock = work_dir.lock(LockMode::Exclusive, "branch")?;
+    work_dir.attach_head(branch_name)
 }
 
 pub fn show_ref() -> Result<()> {
@@ -148,32 +959,1178 @@ pub fn show_ref() -> Result<()> {
     Ok(())
 }
 
-pub fn fsck() -> Result<()> {
+pub fn ls_remote(remote: &str) -> Result<()> {
     let object_store = find_object_store()?;
-    let bad = object_store.fsck()?;
-    for &(expected, actual) in &bad {
-        println!("Corrupt object {0}: expected {0:x}, actual {1:x}",
-                 expected,
-                 actual);
+    let mut transport = transport::connect_remote(&object_store, remote)?;
+    for (name, hash) in transport.list_refs()? {
+        println!("{:x} {}", hash, name);
     }
-    if bad.is_empty() {
-        println!("All objects OK");
-        Ok(())
+    Ok(())
+}
+
+/// Serve the repository at the given path on stdin and stdout
+///
+/// This is what runs on the other end of an ssh remote, so nothing else may
+/// be printed to stdout.
+pub fn serve_stdio(path: &Path) -> Result<()> {
+    let mut repo = transport::LocalTransport::open(path)?;
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    transport::serve(&mut repo, &mut stdin.lock(), &mut stdout.lock())
+}
+
+pub fn remote_list(verbose: bool) -> Result<()> {
+    let object_store = find_object_store()?;
+    for (name, remote) in object_store.remotes() {
+        match (verbose, &remote.branch) {
+            (false, _) => println!("{}", name),
+            (true, Some(branch)) => {
+                println!("{}\t{} ({})", name, remote.url, branch)
+            }
+            (true, None) => println!("{}\t{}", name, remote.url),
+        }
+    }
+    Ok(())
+}
+
+pub fn remote_add(name: &str, url: &str, branch: Option<&str>) -> Result<()> {
+    let mut object_store = find_object_store()?;
+    let _lock = object_store.lock(LockMode::Exclusive, "remote")?;
+    if !transport::is_valid_remote_name(name) {
+        bail!("Invalid remote name: {}", name);
+    }
+    if object_store.remotes().contains_key(name) {
+        bail!("Remote {} already exists", name);
+    }
+    let mut remote = transport::Remote::new(transport::absolute_url(url)?);
+    remote.branch = branch.map(|b| b.to_owned());
+    object_store.update_remote(name.to_owned(), remote)
+}
+
+pub fn remote_remove(name: &str) -> Result<()> {
+    let mut object_store = find_object_store()?;
+    let _lock = object_store.lock(LockMode::Exclusive, "remote")?;
+    object_store.remove_remote(name)?;
+    Ok(())
+}
+
+pub fn fetch(remote: &str,
+             depth: Option<usize>,
+             partial: bool,
+             limit_rate: Option<u64>)
+             -> Result<()> {
+    let mut object_store = find_object_store()?;
+    let _lock = object_store.lock(LockMode::Exclusive, "fetch")?;
+    let result = transport::fetch(&mut object_store,
+                                  remote,
+                                  depth,
+                                  partial,
+                                  limit_rate)?;
+    record_sync()?;
+    for update in &result.updates {
+        match update.old {
+            Some(old) => println!("{}..{} {}", old, update.new, update.name),
+            None => println!("{} {} (new)", update.new, update.name),
+        }
+    }
+    for &(ref name, old) in &result.pruned {
+        println!("{} {} (deleted)", old, name);
+    }
+    if !object_store.remotes().contains_key(remote) {
+        for (name, hash) in &result.remote_refs {
+            println!("{} {}", hash, name);
+        }
+        stderrln!("{}", msg!(Msg::RemoteNotNamed, remote));
+    }
+    stderrln!("{}",
+              msg!(Msg::FetchedObjects,
+                   result.stats.objects,
+                   human_bytes(result.stats.bytes)));
+    if !result.shallow.is_empty() {
+        stderrln!("{}", msg!(Msg::HistoryTruncated, result.shallow.len()));
+    }
+    if !result.absent.is_empty() {
+        let bytes = result.absent.iter().map(|&(_, size)| size).sum::<u64>();
+        stderrln!("{}",
+                  msg!(Msg::LeftOutContent,
+                       result.absent.len(),
+                       human_bytes(bytes)));
+    }
+    Ok(())
+}
+
+/// List files in a revision whose content was left out by a partial fetch
+pub fn missing(rev: &RevSpec) -> Result<()> {
+    let object_store = find_object_store()?;
+    let _lock = shared_lock(&object_store, "missing")?;
+    let (hash, _, _) = object_store.lookup(rev)?;
+    let missing = object_store.missing_content(&hash)?;
+    for (path, remotes) in &missing {
+        let remotes = remotes.iter().cloned().collect::<Vec<_>>();
+        println!("{} {}", path.display(), remotes.join(","));
+    }
+    stderrln!("{}", msg!(Msg::FilesMissingContent, missing.len()));
+    Ok(())
+}
+
+/// Show which remotes are known to have a file, given a revision or a path
+///
+/// The target is tried as a revision first, then as a path in HEAD.
+pub fn whereis(target: &str) -> Result<()> {
+    let work_dir = find_work_dir()?;
+    let _lock = shared_lock(&work_dir, "whereis")?;
+    let hash = match target.parse::<RevSpec>()
+        .and_then(|rev| work_dir.lookup(&rev)) {
+        Ok((hash, _, _)) => hash,
+        Err(_) => work_dir.lookup_head_path(Path::new(target))?,
+    };
+    let remotes = work_dir.locations().remotes_for(&hash);
+    for remote in &remotes {
+        println!("{}", remote);
+    }
+    if work_dir.missing_content(&hash)?.is_empty() {
+        stderrln!("{}", msg!(Msg::ContentHere, target));
     } else {
-        bail!("Repository has corrupt objects")
+        stderrln!("{}", msg!(Msg::ContentNotHere, target));
+    }
+    if remotes.is_empty() {
+        stderrln!("{}", msg!(Msg::NoKnownLocations, target));
+    }
+    Ok(())
+}
+
+/// Show which ignore pattern, if any, applies to each path, and its source
+pub fn check_ignore(paths: &[PathBuf]) -> Result<()> {
+    let work_dir = find_work_dir()?;
+    let cwd = current_dir()?;
+    for path in paths {
+        match work_dir.file_store.ignored.matching(&cwd.join(path)) {
+            Some((pattern, source)) => {
+                println!("{}: {}: {}",
+                         path.display(),
+                         source,
+                         pattern.display())
+            }
+            None => println!("{}", msg!(Msg::NotIgnored, path.display())),
+        }
+    }
+    Ok(())
+}
+
+/// Print the metadata extracted from a file, given `rev:path` or a path
+pub fn meta_show(target: &str) -> Result<()> {
+    let work_dir = find_work_dir()?;
+    let _lock = shared_lock(&work_dir, "meta")?;
+    let rev = target.parse::<RevSpec>().ok().filter(|rev| rev.path.is_some());
+    let (rev, path) = match rev {
+        Some(mut rev) => {
+            let path = rev.path.take().expect("filtered");
+            (work_dir.lookup(&rev)?.0, path)
+        }
+        None => work_dir.head_tree_path(Path::new(target))?,
+    };
+    match work_dir.lookup_media_metadata(&rev, &path)? {
+        Some(metadata) => print!("{}", metadata),
+        None => stderrln!("{}", msg!(Msg::NoMediaMetadata, target)),
     }
+    Ok(())
 }
 
-pub fn checkout(target: &RevSpec) -> Result<()> {
+/// Print or extract an object derived from a file, deriving it if needed
+///
+/// The target is `rev:path` or a path in HEAD, as for `meta_show`.
+pub fn derive(target: &str, name: &str, output: Option<&Path>) -> Result<()> {
     let mut work_dir = find_work_dir()?;
-    work_dir.checkout(target)
+    let _lock = work_dir.lock(LockMode::Exclusive, "derive")?;
+    let rev = target.parse::<RevSpec>().ok().filter(|rev| rev.path.is_some());
+    let source = match rev {
+        Some(rev) => work_dir.lookup(&rev)?.0,
+        None => work_dir.lookup_head_path(Path::new(target))?,
+    };
+    let derivers = work_dir.derivers();
+    let derived = derive::derive(&mut work_dir, &derivers, &source, name)?;
+    if !derived.cached {
+        stderrln!("{}", msg!(Msg::Derived, name, target));
+    }
+    match output {
+        Some(path) => work_dir.extract_object(&derived.key, path)?,
+        None => println!("{}", derived.key),
+    }
+    Ok(())
 }
 
-pub fn merge_base<'a, I: 'a>(revs: I) -> Result<()>
-    where I: Iterator<Item = &'a str>
-{
+/// Write a file's content to stdout, given a revision or a path in HEAD
+///
+/// With a range of an offset and optional length, only the chunks covering
+/// it are read.
+pub fn cat(target: &str, range: Option<(u64, Option<u64>)>) -> Result<()> {
+    let work_dir = find_work_dir()?;
+    let _lock = shared_lock(&work_dir, "cat")?;
+    let hash = match target.parse::<RevSpec>()
+        .and_then(|rev| work_dir.lookup(&rev)) {
+        Ok((hash, _, _)) => hash,
+        Err(_) => work_dir.lookup_head_path(Path::new(target))?,
+    };
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    match range {
+        None => work_dir.copy_blob_content(&hash, &mut stdout)?,
+        Some((offset, len)) => {
+            let len = len.unwrap_or(u64::MAX);
+            work_dir.copy_blob_range(&hash, offset, len, &mut stdout)?
+        }
+    }
+    stdout.flush()?;
+    Ok(())
+}
+
+/// Serve the tree of a revision at a mount point, until it is unmounted
+#[cfg(all(feature = "mount", target_os = "linux"))]
+pub fn mount(rev: &RevSpec, mountpoint: &Path) -> Result<()> {
     let object_store = find_object_store()?;
-    let ancestor = object_store.find_common_ancestor(revs)?;
+    let (hash, _, _) = object_store.lookup(rev)?;
+    let fs = mount::RevisionFs::new(&object_store, hash)?;
+    let device = mount::fuse_mount(mountpoint)?;
+    stderrln!("{}", msg!(Msg::Mounted, rev, mountpoint.display()));
+    fs.serve(device)
+}
+
+pub fn mv(from: &Path, to: &Path) -> Result<()> {
+    let mut work_dir = find_work_dir()?;
+    let _lock = work_dir.lock(LockMode::Exclusive, "mv")?;
+    let to = work_dir.move_path(from, to)?;
+    let from = work_dir.relative_path(from)?;
+    stderrln!("{}", msg!(Msg::Moved, from.display(), to.display()));
+    Ok(())
+}
+
+pub fn rm(paths: &[PathBuf], force: bool) -> Result<()> {
+    let mut work_dir = find_work_dir()?;
+    let _lock = work_dir.lock(LockMode::Exclusive, "rm")?;
+    for path in paths {
+        work_dir.remove_path(path, force)?;
+        stderrln!("{}", msg!(Msg::Removed, path.display()));
+    }
+    Ok(())
+}
+
+/// List, or with `force` remove, files in the work dir that are not in HEAD
+pub fn clean(dirs: bool, ignored: bool, force: bool) -> Result<()> {
+    let mut work_dir = find_work_dir()?;
+    let _lock = work_dir.lock(LockMode::Exclusive, "clean")?;
+    let found = work_dir.clean(dirs, ignored, force)?;
+    for entry in &found {
+        let path = match entry.is_dir {
+            true => format!("{}/", entry.path.display()),
+            false => entry.path.display().to_string(),
+        };
+        match force {
+            true => stderrln!("{}", msg!(Msg::Removed, path)),
+            false => println!("{}", msg!(Msg::WouldRemove, path)),
+        }
+    }
+    if !force && !found.is_empty() {
+        stderrln!("{}", msg!(Msg::CleanHint));
+    }
+    Ok(())
+}
+
+pub fn clone(source: &str,
+             dest: &Path,
+             depth: Option<usize>,
+             limit_rate: Option<u64>)
+             -> Result<()> {
+    if dest.exists() && dest.read_dir()?.next().is_some() {
+        bail!("Destination {} already exists and is not empty",
+              dest.display());
+    }
+    let dest = current_dir()?.join(dest);
+    let created = !dest.exists();
+    let layout = RepoLayout::in_work_dir(dest.clone());
+    let (work_dir, result) =
+        match WorkDir::clone_repo(source, layout, depth, limit_rate) {
+            Ok(cloned) => cloned,
+            Err(e) => {
+                // Leave the destination as it was: gone, or empty
+                let cleanup = if created {
+                    fs::remove_dir_all(&dest)
+                } else {
+                    remove_dir_contents(&dest)
+                };
+                if let Err(cleanup_err) = cleanup {
+                    warn!("Could not clean up {}: {}",
+                          dest.display(),
+                          cleanup_err);
+                }
+                return Err(e);
+            }
+        };
+    stderrln!("{}",
+              msg!(Msg::FetchedObjects,
+                   result.stats.objects,
+                   human_bytes(result.stats.bytes)));
+    if !result.shallow.is_empty() {
+        stderrln!("{}", msg!(Msg::HistoryTruncated, result.shallow.len()));
+    }
+    match work_dir.branch() {
+        Some(branch) if work_dir.head().is_some() => {
+            stderrln!("{}", msg!(Msg::OnBranch, branch));
+        }
+        _ => stderrln!("{}", msg!(Msg::ClonedEmpty, source)),
+    }
+    Ok(())
+}
+
+/// Remove everything inside a directory, but not the directory itself
+fn remove_dir_contents(dir: &Path) -> io::Result<()> {
+    for entry in dir.read_dir()? {
+        let path = entry?.path();
+        if path.is_dir() && !path.symlink_metadata()?.file_type().is_symlink() {
+            fs::remove_dir_all(&path)?;
+        } else {
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn fast_export(branches: Vec<&str>) -> Result<()> {
+    let object_store = find_object_store()?;
+    let _lock = shared_lock(&object_store, "fast-export")?;
+    let refs = if branches.is_empty() {
+        object_store.refs()
+            .iter()
+            .filter(|&(name, _)| !transport::is_tracking_ref(name))
+            .filter(|&(name, _)| !stash::is_stash_ref(name))
+            .map(|(name, hash)| (name.to_owned(), *hash))
+            .collect::<Vec<_>>()
+    } else {
+        let mut refs = Vec::new();
+        for name in branches {
+            let hash = object_store.try_find_ref(name)
+                .ok_or_else(|| format!("No such branch: {}", name))?;
+            refs.push((name.to_owned(), hash));
+        }
+        refs
+    };
+    let stdout = io::stdout();
+    let mut writer = io::BufWriter::new(stdout.lock());
+    let stats = git_stream::export(&object_store, &refs, &mut writer)?;
+    writer.flush()?;
+    stderrln!("{}", msg!(Msg::ExportedHistory, stats.commits, stats.files));
+    Ok(())
+}
+
+pub fn fast_import() -> Result<()> {
+    let mut object_store = find_object_store()?;
+    let _lock = object_store.lock(LockMode::Exclusive, "fast-import")?;
+    let stdin = io::stdin();
+    let result = git_stream::import(&mut object_store, stdin.lock())?;
+    for (name, hash) in &result.refs {
+        println!("{} {}", hash, name);
+    }
+    stderrln!("{}",
+              msg!(Msg::ImportedHistory,
+                   result.stats.commits,
+                   result.stats.files));
+    Ok(())
+}
+
+pub fn push(remote: &str,
+            branch: Option<&str>,
+            force: bool,
+            lease: Option<&str>,
+            limit_rate: Option<u64>)
+            -> Result<()> {
+    let mut object_store = find_object_store()?;
+    let _lock = object_store.lock(LockMode::Exclusive, "push")?;
+    let branch = match branch {
+        Some(branch) => branch.to_owned(),
+        None => {
+            let remote_branch = object_store.remotes()
+                .get(remote)
+                .and_then(|r| r.branch.clone());
+            let head = WorkDir::read_head(object_store.path())?;
+            match (head.and_then(|(_, branch)| branch), remote_branch) {
+                (Some(branch), _) => branch,
+                (None, Some(branch)) => branch,
+                (None, None) => bail!(msg!(Msg::NotOnBranch)),
+            }
+        }
+    };
+
+    let force = match (force, lease) {
+        (true, _) => transport::ForceMode::Force,
+        (false, None) => transport::ForceMode::FastForward,
+        (false, Some(lease)) => {
+            let (lease_ref, expected) = match lease.find(':') {
+                Some(i) => (&lease[..i], Some(&lease[i + 1..])),
+                None => (lease, None),
+            };
+            if lease_ref != branch {
+                bail!("Lease is for {}, but pushing {}", lease_ref, branch);
+            }
+            let expected = match expected {
+                Some(rev) => Some(lookup_lease_hash(&object_store, rev)?),
+                None => {
+                    let tracking =
+                        transport::tracking_ref_name(remote, &branch);
+                    object_store.try_find_ref(&tracking)
+                }
+            };
+            transport::ForceMode::WithLease(expected)
+        }
+    };
+
+    let result = transport::push(&mut object_store,
+                                 remote,
+                                 &branch,
+                                 force,
+                                 limit_rate)?;
+    record_sync()?;
+    match result.update {
+        Some(update) => {
+            match (update.old, result.forced) {
+                (Some(old), true) => {
+                    println!("{}...{} {} (forced update)",
+                             old,
+                             update.new,
+                             update.name)
+                }
+                (Some(old), false) => {
+                    println!("{}..{} {}", old, update.new, update.name)
+                }
+                (None, _) => println!("{} {} (new)", update.new, update.name),
+            }
+            stderrln!("{}",
+                      msg!(Msg::PushedObjects,
+                           result.stats.objects,
+                           human_bytes(result.stats.bytes)));
+        }
+        None => stderrln!("{}", msg!(Msg::PushUpToDate, branch)),
+    }
+    Ok(())
+}
+
+/// Write a bundle of a branch, for carrying to a repository with no connection
+///
+/// With a range `from..to`, the receiver must already have `from`, and the
+/// bundle leaves out everything it has.
+pub fn bundle_create(range: &RevRange, output: &Path) -> Result<()> {
+    let object_store = find_object_store()?;
+    let _lock = shared_lock(&object_store, "bundle")?;
+    let (_, to, name) = object_store.lookup(&range.to)?;
+    let name = name.ok_or_else(|| {
+            format!("{} is not a branch, so there is no ref to bundle",
+                    range.to)
+        })?;
+    let prerequisites = match range.from {
+        Some(ref from) => vec![object_store.lookup(from)?.1],
+        None => Vec::new(),
+    };
+    let refs = map!{ RefMap::new(), name => to, };
+    let stats = if is_stdio(output) {
+        let stdout = io::stdout();
+        let mut stdout = io::BufWriter::new(stdout.lock());
+        transport::create_bundle(&object_store,
+                                 &refs,
+                                 &prerequisites,
+                                 &mut stdout)?
+    } else {
+        let file = fs::File::create(output)
+            .chain_err(|| format!("Could not create {}", output.display()))?;
+        let mut file = io::BufWriter::new(file);
+        transport::create_bundle(&object_store,
+                                 &refs,
+                                 &prerequisites,
+                                 &mut file)?
+    };
+    stderrln!("{}",
+              msg!(Msg::BundledObjects,
+                   stats.objects,
+                   human_bytes(stats.bytes)));
+    Ok(())
+}
+
+/// Store the objects of a bundle, and update the branches it carries
+pub fn bundle_apply(path: &Path, force: bool) -> Result<()> {
+    let mut object_store = find_object_store()?;
+    let _lock = object_store.lock(LockMode::Exclusive, "bundle")?;
+    let applied = if is_stdio(path) {
+        let stdin = io::stdin();
+        transport::apply_bundle(&mut object_store, &mut stdin.lock(), force)?
+    } else {
+        let file = fs::File::open(path)
+            .chain_err(|| format!("Could not open {}", path.display()))?;
+        transport::apply_bundle(&mut object_store,
+                                &mut io::BufReader::new(file),
+                                force)?
+    };
+    for update in &applied.updates {
+        match update.old {
+            Some(old) => println!("{}..{} {}", old, update.new, update.name),
+            None => println!("{} {} (new)", update.new, update.name),
+        }
+    }
+    for update in &applied.rejected {
+        stderrln!("{}",
+                  msg!(Msg::BundleRefRejected,
+                       update.name,
+                       update.old.expect("only existing refs are rejected"),
+                       update.new));
+    }
+    stderrln!("{}",
+              msg!(Msg::AppliedObjects,
+                   applied.stats.objects,
+                   human_bytes(applied.stats.bytes)));
+    Ok(())
+}
+
+/// Take a shared lock, warning if a writer is active
+fn shared_lock(object_store: &ObjectStore, command: &str) -> Result<RepoLock> {
+    let lock = object_store.lock(LockMode::Shared, command)?;
+    print_write_in_progress(&lock.writer().cloned());
+    Ok(lock)
+}
+
+fn print_write_in_progress(writer: &Option<LockHolder>) {
+    if let Some(ref writer) = *writer {
+        stderrln!("{}", msg!(Msg::WriteInProgress, writer));
+    }
+}
+
+/// Record a fetch or push as the last sync, if there is a work dir to keep
+/// the record
+fn record_sync() -> Result<()> {
+    if let Ok(mut work_dir) = find_work_dir() {
+        work_dir.update_state(Some(StateEvent::Sync), |_| ())?;
+    }
+    Ok(())
+}
+
+fn find_repository() -> Result<Repository> {
+    Ok(Repository::from_work_dir(find_work_dir()?))
+}
+
+/// Resolve the expected hash of a lease, which may not exist locally
+fn lookup_lease_hash(object_store: &ObjectStore,
+                     rev: &str)
+                     -> Result<ObjectKey> {
+    ObjectKey::parse(rev).or_else(|_| {
+        let (_, hash, _) = object_store.lookup(&rev.parse()?)?;
+        Ok(hash)
+    })
+}
+
+/// Copy the objects that a backup directory lacks, then the refs
+pub fn backup(dest: &Path) -> Result<()> {
+    let object_store = find_object_store()?;
+    let _lock = shared_lock(&object_store, "backup")?;
+    let backend = DirBackend::new(dest.to_owned());
+    let stats = backup::backup(&object_store, &backend)?;
+    stderrln!("{}",
+              msg!(Msg::BackedUp,
+                   stats.objects,
+                   human_bytes(stats.bytes),
+                   dest.display(),
+                   stats.skipped));
+    Ok(())
+}
+
+pub fn repack(fan_out: Option<FanOut>) -> Result<()> {
+    let mut object_store = find_object_store()?;
+    let _lock = object_store.lock(LockMode::Exclusive, "repack")?;
+    let moved = object_store.repack(fan_out)?;
+    stderrln!("{}", msg!(Msg::Repacked, moved, object_store.fan_out()));
+    Ok(())
+}
+
+/// Cover objects not yet in a parity group
+pub fn parity_build(group_size: usize, parity: usize) -> Result<()> {
+    let object_store = find_object_store()?;
+    let _lock = object_store.lock(LockMode::Exclusive, "parity")?;
+    let stats = Parity::open(&object_store)?
+        .build(&object_store, group_size, parity)?;
+    stderrln!("{}",
+              msg!(Msg::ParityBuilt,
+                   stats.objects,
+                   stats.groups,
+                   human_bytes(stats.bytes)));
+    Ok(())
+}
+
+pub fn fsck(options: &FsckOptions, porcelain: bool) -> Result<()> {
+    let object_store = find_object_store()?;
+    let _lock = if options.repair {
+        object_store.lock(LockMode::Exclusive, "fsck")?
+    } else {
+        shared_lock(&object_store, "fsck")?
+    };
+    let parents = WorkDir::read_parents(object_store.path())?;
+    let report = object_store.fsck_with(options, &parents)?;
+    for &(expected, actual) in &report.corrupt {
+        if porcelain {
+            println!("corrupt {:x} {:x}", expected, actual);
+        } else {
+            println!("{}",
+                     msg!(Msg::CorruptObject,
+                          expected,
+                          format!("{:x}", expected),
+                          format!("{:x}", actual)));
+        }
+    }
+    for hash in &report.quarantined {
+        if porcelain {
+            println!("quarantined {:x}", hash);
+        } else {
+            println!("{}", msg!(Msg::QuarantinedObject, hash));
+        }
+    }
+    for hash in &report.reconstructed {
+        if porcelain {
+            println!("reconstructed {:x}", hash);
+        } else {
+            println!("{}", msg!(Msg::ReconstructedObject, hash));
+        }
+    }
+    for &(hash, recorded, actual) in &report.bad_chunk_sizes {
+        if porcelain {
+            println!("badsize {:x} {} {}", hash, recorded, actual);
+        } else {
+            println!("{}", msg!(Msg::BadChunkIndex, hash, recorded, actual));
+        }
+    }
+    for &(old, new) in &report.rewritten {
+        if porcelain {
+            println!("rewritten {:x} {:x}", old, new);
+        } else {
+            println!("{}", msg!(Msg::RewroteChunkIndex, old, new));
+        }
+    }
+    for &(hash, referrer) in &report.missing {
+        if porcelain {
+            println!("missing {:x} {:x}", hash, referrer);
+        } else {
+            println!("{}", msg!(Msg::MissingObject, hash, referrer));
+        }
+    }
+    for hash in &report.orphans {
+        let object_type = object_store.open_object(hash)
+            .map(|handle| handle.header().object_type.code())
+            .unwrap_or("????");
+        if porcelain {
+            println!("orphan {} {:x}", object_type, hash);
+        } else {
+            println!("{}", msg!(Msg::OrphanObject, object_type, hash));
+        }
+    }
+    if report.is_ok() {
+        if !porcelain {
+            println!("{}", msg!(Msg::AllObjectsOk));
+        }
+        Ok(())
+    } else if report.unrepaired_corrupt().next().is_some() {
+        bail!(msg!(Msg::RepoHasCorruptObjects))
+    } else if !report.missing.is_empty() {
+        bail!(msg!(Msg::RepoHasMissingObjects))
+    } else {
+        bail!(msg!(Msg::RepoHasBadChunkIndexes))
+    }
+}
+
+/// Summarize where the user is and suggest what to do next
+pub fn overview() -> Result<()> {
+    let work_dir = match find_work_dir() {
+        Ok(work_dir) => work_dir,
+        Err(ref e) if is_not_a_repository(e) => {
+            println!("{}", msg!(Msg::NotInRepo));
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    println!("{}", describe_head(work_dir.head_ref().as_ref()));
+    let mut repo = Repository::from_work_dir(work_dir);
+    match repo.work_dir().head() {
+        Some(head) => {
+            let commit = repo.work_dir().open_commit(&head)?;
+            println!("HEAD: {} {}", head, commit.message);
+            println!("{}", summarize_changes(&repo.status(false)?.changes));
+        }
+        None => println!("{}", msg!(Msg::NoCommitsHint)),
+    }
+    for warning in repo.work_dir().health_warnings(SystemTime::now()) {
+        println!("{}", warning);
+    }
+    Ok(())
+}
+
+fn is_not_a_repository(err: &Error) -> bool {
+    matches!(*err.root_kind(), ErrorKind::NotARepository(_))
+}
+
+/// One line counting changes by kind, for the overview
+fn summarize_changes(changes: &[StatusEntry]) -> String {
+    if changes.is_empty() {
+        return msg!(Msg::NoChanges);
+    }
+    let count = |kinds: &[Status]| {
+        changes.iter()
+            .filter(|entry| {
+                entry.status.first().is_some_and(|s| kinds.contains(s))
+            })
+            .count()
+    };
+    msg!(Msg::ChangesSummary,
+         changes.len(),
+         count(&[Status::Add]),
+         count(&[Status::Modified, Status::MaybeModified]),
+         count(&[Status::Delete]))
+}
+
+/// Show where the repository is kept, and how much is in it
+///
+/// Object counts come from the running totals, which are only recounted if
+/// asked or if they do not cover the whole store.
+pub fn info(recount: bool) -> Result<()> {
+    let work_dir = find_work_dir().ok();
+    let bare;
+    let object_store: &ObjectStore = match work_dir {
+        Some(ref work_dir) => work_dir,
+        None => {
+            bare = find_object_store()?;
+            &bare
+        }
+    };
+    let _lock = shared_lock(object_store, "info")?;
+
+    if let Some(ref work_dir) = work_dir {
+        println!("Work dir:  {}", work_dir.path().display());
+    }
+    println!("Store:     {}", object_store.path().display());
+    if let Some(ref work_dir) = work_dir {
+        let detached = msg!(Msg::DetachedHead);
+        println!("Branch:    {}", work_dir.branch().unwrap_or(&detached));
+        for parent in work_dir.parents() {
+            println!("Parent:    {}", parent);
+        }
+    }
+
+    let mut counts = object_store.object_counts();
+    if recount || !counts.complete {
+        stderrln!("{}", msg!(Msg::CountingObjects));
+        counts = object_store.recount_objects()?;
+    }
+    let total = counts.total();
+    println!();
+    println!("{:4}  {:>10} {:>10} {:>10}", "", "count", "content", "stored");
+    for (object_type, count) in &counts.by_type {
+        println!("{:4}: {:10} {:>10} {:>10}",
+                 object_type.code(),
+                 count.objects,
+                 human_bytes(count.content_bytes),
+                 human_bytes(count.stored_bytes));
+    }
+    println!("{:4}: {:10} {:>10} {:>10}",
+             "all",
+             total.objects,
+             human_bytes(total.content_bytes),
+             human_bytes(total.stored_bytes));
+    if counts.duplicate_bytes > 0 {
+        println!("{}",
+                 msg!(Msg::DuplicatesSkipped,
+                      human_bytes(counts.duplicate_bytes),
+                      format!("{:.1}", counts.dedup_ratio())));
+    }
+
+    println!();
+    for &(desc, name) in &[("Log stats", "changes"),
+                           ("Media types", "media"),
+                           ("Derived", "derived"),
+                           ("Locations", "locations")] {
+        if let Ok(meta) = object_store.path().join(name).metadata() {
+            println!("{:12} {:>10}", desc, human_bytes(meta.len()));
+        }
+    }
+    if let Some(ref work_dir) = work_dir {
+        let (files, size) = cache_files_size(work_dir.path())?;
+        println!("{:12} {:>10} in {} files",
+                 "Hash cache",
+                 human_bytes(size),
+                 files);
+    }
+    Ok(())
+}
+
+/// Report how much space deduplication is saving
+pub fn stats(top_n: usize) -> Result<()> {
+    let object_store = find_object_store()?;
+    let _lock = shared_lock(&object_store, "stats")?;
+    let stats = object_store.stats(top_n)?;
+
+    println!("{}",
+             msg!(Msg::StatsSummary,
+                  human_bytes(stats.logical_bytes),
+                  human_bytes(stats.stored_bytes),
+                  format!("{:.1}", stats.dedup_ratio())));
+    println!();
+    println!("{:4}  {:>10} {:>10} {:>10} {:>6} {:>10} {:>10}",
+             "",
+             "count",
+             "stored",
+             "logical",
+             "ratio",
+             "mean",
+             "std");
+    for (object_type, type_stats) in &stats.by_type {
+        println!("{:4}: {:10} {:>10} {:>10} {:5.1}x {:>10} {:>10}",
+                 object_type.code(),
+                 type_stats.sizes.count(),
+                 human_bytes(type_stats.stored_bytes),
+                 human_bytes(type_stats.logical_bytes),
+                 type_stats.dedup_ratio(),
+                 human_bytes(type_stats.sizes.mean().round() as u64),
+                 human_bytes(type_stats.sizes.std().round() as u64));
+    }
+    println!();
+    println!("{}", msg!(Msg::SharedChunks, stats.shared_chunks));
+    if !stats.largest_files.is_empty() {
+        println!();
+        println!("{}", msg!(Msg::LargestFiles));
+        for &(hash, ref name, size) in &stats.largest_files {
+            println!("{:>10} {} {}", human_bytes(size), hash, name);
+        }
+    }
+    Ok(())
+}
+
+/// Show the storage retained by each entry at the top of a revision's tree
+pub fn du(rev: Option<RevSpec>) -> Result<()> {
+    let usage = match rev {
+        Some(ref r) => {
+            let object_store = find_object_store()?;
+            let _lock = shared_lock(&object_store, "du")?;
+            let (hash, _, _) = object_store.lookup(r)?;
+            object_store.disk_usage(&hash)?
+        }
+        None => {
+            let wd = find_work_dir()?;
+            let _lock = shared_lock(&wd, "du")?;
+            let mut hash = wd.head()
+                .ok_or_else(|| msg!(Msg::NoCommitSpecified))?;
+            if let Some(path) = &wd.state.subtree {
+                hash = wd.object_store.lookup_rev_path(&hash, path)?;
+            }
+            wd.disk_usage(&hash)?
+        }
+    };
+
+    println!("{:>10} {:>10}  name", "retained", "total");
+    for entry in &usage.entries {
+        println!("{:>10} {:>10}  {}{}",
+                 human_bytes(entry.retained),
+                 human_bytes(entry.total),
+                 entry.name,
+                 if entry.is_dir { "/" } else { "" });
+    }
+    println!();
+    println!("{}",
+             msg!(Msg::DiskUsageSummary,
+                  human_bytes(usage.total),
+                  human_bytes(usage.shared)));
+    Ok(())
+}
+
+/// List files stored under more than one path, anywhere in history
+pub fn find_duplicates(min_size: u64) -> Result<()> {
+    let object_store = find_object_store()?;
+    let _lock = shared_lock(&object_store, "find-duplicates")?;
+    let groups = object_store.find_duplicates(min_size)?;
+    for group in &groups {
+        println!("{} {}", group.hash, human_bytes(group.size));
+        for &(ref path, commit) in &group.paths {
+            println!("    {}:{}", commit, path.display());
+        }
+    }
+    stderrln!("{}",
+              msg!(Msg::DuplicatesSummary,
+                   groups.len(),
+                   groups.iter().map(|g| g.paths.len()).sum::<usize>()));
+    Ok(())
+}
+
+/// Find files in history by size, media type, or metadata
+///
+/// Searches the history of the given revision, or of every ref.
+pub fn query(rev: Option<&RevSpec>, query: &FileQuery) -> Result<()> {
+    let object_store = find_object_store()?;
+    let _lock = shared_lock(&object_store, "query")?;
+    let starts = match rev {
+        Some(rev) => vec![object_store.lookup(rev)?.0],
+        None => object_store.refs().values().cloned().collect(),
+    };
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    let (mut count, mut total) = (0, 0);
+    query_files(&object_store, &starts, query, |found| {
+            count += 1;
+            total += found.size;
+            writeln!(stdout,
+                     "{}:{} {} {}",
+                     found.commit,
+                     found.path.display(),
+                     human_bytes(found.size),
+                     found.media_type)?;
+            Ok(())
+        })?;
+    stderrln!("{}", msg!(Msg::QueryMatches, count, human_bytes(total)));
+    Ok(())
+}
+
+/// Measure hashing and chunking throughput on sample data
+///
+/// Chunking uses the current repository's parameters, if there is one.
+pub fn bench_hash(size: usize) -> Result<()> {
+    let (params, algorithm) = match find_object_store() {
+        Ok(store) => (store.config().chunking, store.hash_algorithm()),
+        Err(_) => (ChunkParams::default(), HashAlgorithm::default()),
+    };
+    stderrln!("{}", msg!(Msg::BenchSample, human_bytes(size as u64)));
+    let data = bench::sample_data(size);
+    for result in bench::bench_hashing(&data) {
+        println!("{}", result);
+    }
+    println!("{}", bench::bench_chunking(&data, &params, algorithm)?);
+    Ok(())
+}
+
+/// Re-chunk stored files and report any whose chunks differ
+pub fn verify_chunking(rev: &RevSpec) -> Result<()> {
+    let object_store = find_object_store()?;
+    let _lock = shared_lock(&object_store, "verify-chunking")?;
+    let (hash, _, _) = object_store.lookup(rev)?;
+    let node: ObjectWalkNode = object_store.lookup_node(hash)?;
+    let files = if node.object_type.is_treeish() {
+        git_stream::flatten_tree(&object_store, &hash)?
+    } else {
+        let mut files = git_stream::FileMap::new();
+        files.insert(rev.to_string(), hash);
+        files
+    };
+
+    let mut drifted = 0;
+    for (path, hash) in &files {
+        let check = object_store.verify_chunking(hash)?;
+        if !check.matches() {
+            drifted += 1;
+            println!("{}",
+                     msg!(Msg::ChunkingDiffers,
+                          path,
+                          check.reproduced,
+                          check.rechunked,
+                          check.first_difference.unwrap_or(0)));
+        }
+    }
+    println!("{}",
+             msg!(Msg::ChunkingSummary, files.len() - drifted, files.len()));
+    if drifted > 0 {
+        bail!(msg!(Msg::ChunkingDrift, drifted));
+    }
+    Ok(())
+}
+
+pub fn doctor() -> Result<()> {
+    let work_dir = find_work_dir()?;
+    let warnings = work_dir.health_warnings(SystemTime::now());
+    for warning in &warnings {
+        println!("{}", warning);
+    }
+    if warnings.is_empty() {
+        println!("{}", msg!(Msg::NoProblemsFound));
+    }
+    Ok(())
+}
+
+pub fn checkout(target: &RevSpec, force: bool, detach: bool) -> Result<()> {
+    let mut repo = find_repository()?;
+    repo.checkout(target, force, detach)
+}
+
+/// Start a bisect, optionally marking a bad commit and good commits
+pub fn bisect_start(bad: Option<&RevSpec>, good: &[RevSpec]) -> Result<()> {
+    let mut work_dir = find_work_dir()?;
+    let _lock = work_dir.lock(LockMode::Exclusive, "bisect")?;
+    let mut bisect = Bisect::start(&work_dir)?;
+    if let Some(bad) = bad {
+        let (_, commit, _) = work_dir.lookup(bad)?;
+        bisect.mark(commit, false)?;
+    }
+    for good in good {
+        let (_, commit, _) = work_dir.lookup(good)?;
+        bisect.mark(commit, true)?;
+    }
+    bisect_next(&mut work_dir, &bisect)
+}
+
+/// Mark a commit (default: HEAD) as good or bad, and check out the next
+pub fn bisect_mark(rev: Option<&RevSpec>, good: bool) -> Result<()> {
+    let mut work_dir = find_work_dir()?;
+    let _lock = work_dir.lock(LockMode::Exclusive, "bisect")?;
+    let mut bisect = Bisect::open(&work_dir)?;
+    let commit = match rev {
+        Some(rev) => work_dir.lookup(rev)?.1,
+        None => work_dir.head().ok_or("No commit checked out")?,
+    };
+    bisect.mark(commit, good)?;
+    bisect_next(&mut work_dir, &bisect)
+}
+
+fn bisect_next(work_dir: &mut WorkDir, bisect: &Bisect) -> Result<()> {
+    match bisect.next_step(work_dir)? {
+        BisectStep::NeedBad => println!("{}", msg!(Msg::BisectNeedBad)),
+        BisectStep::NeedGood => println!("{}", msg!(Msg::BisectNeedGood)),
+        BisectStep::Test { commit, remaining } => {
+            println!("{}", msg!(Msg::Bisecting, remaining));
+            work_dir.checkout(&commit.to_hex().parse()?, false)?;
+            let message = work_dir.open_commit(&commit)?.message;
+            println!("{} {}", commit, message);
+        }
+        BisectStep::Found(commit) => {
+            let message = work_dir.open_commit(&commit)?.message;
+            let state = bisect.state();
+            let orig = state.orig_branch
+                .clone()
+                .or(state.orig_head.map(|h| h.to_string()))
+                .unwrap_or_default();
+            println!("{}", msg!(Msg::FirstBadCommit, commit.to_hex(), orig));
+            println!("{} {}", commit, message);
+        }
+    }
+    Ok(())
+}
+
+/// End a bisect, and check out what was checked out before it started
+pub fn bisect_reset() -> Result<()> {
+    let mut work_dir = find_work_dir()?;
+    let _lock = work_dir.lock(LockMode::Exclusive, "bisect")?;
+    Bisect::open(&work_dir)?.reset(&mut work_dir)?;
+    let detached = msg!(Msg::DetachedHead);
+    println!("{}",
+             msg!(Msg::BisectReset, work_dir.branch().unwrap_or(&detached)));
+    Ok(())
+}
+
+/// Start, continue, or abort a rebase of the current branch
+pub fn rebase(onto: Option<&RevSpec>, resume: bool, abort: bool) -> Result<()> {
+    let mut work_dir = find_work_dir()?;
+    let _lock = work_dir.lock(LockMode::Exclusive, "rebase")?;
+    if abort {
+        Rebase::open(&work_dir)?.abort(&mut work_dir)?;
+        let detached = msg!(Msg::DetachedHead);
+        println!("{}",
+                 msg!(Msg::RebaseAborted,
+                      work_dir.branch().unwrap_or(&detached)));
+        return Ok(());
+    }
+    let rebase = match (onto, resume) {
+        (_, true) => Rebase::open(&work_dir)?,
+        (Some(onto), false) => {
+            let (_, onto, _) = work_dir.lookup(onto)?;
+            Rebase::start(&mut work_dir, onto)?
+        }
+        (None, false) => bail!("No commit to rebase onto specified"),
+    };
+    let onto = rebase.state().onto;
+    match rebase.run(&mut work_dir) {
+        Ok(new_head) => {
+            let detached = msg!(Msg::DetachedHead);
+            println!("{}",
+                     msg!(Msg::Rebased,
+                          work_dir.branch().unwrap_or(&detached),
+                          onto,
+                          new_head));
+            Ok(())
+        }
+        Err(e) => {
+            if Rebase::in_progress(&work_dir) {
+                let state = Rebase::open(&work_dir)?.state().clone();
+                if let Some(stopped_at) = state.stopped_at {
+                    let message = work_dir.open_commit(&stopped_at)?.message;
+                    stderrln!("{}", e);
+                    bail!(msg!(Msg::RebaseStopped, stopped_at, message));
+                }
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Finish or roll back an interrupted checkout or merge
+pub fn recover(rollback: bool) -> Result<()> {
+    let mut work_dir = find_work_dir()?;
+    let _lock = work_dir.lock(LockMode::Exclusive, "recover")?;
+    let journal = Journal::open(&work_dir)?;
+    let op = journal.state().op.to_string();
+    if rollback {
+        journal.roll_back(&mut work_dir)?;
+        println!("{}", msg!(Msg::RolledBack, op));
+    } else {
+        journal.resume(&mut work_dir)?;
+        println!("{}", msg!(Msg::Resumed, op));
+    }
+    Ok(())
+}
+
+/// Save the changes in the work dir as a stash
+pub fn stash_save(message: Option<String>) -> Result<()> {
+    let mut work_dir = find_work_dir()?;
+    let _lock = work_dir.lock(LockMode::Exclusive, "stash")?;
+    let stash = stash::save(&mut work_dir, message)?;
+    println!("{}", msg!(Msg::Stashed, stash.name, stash.commit.message));
+    Ok(())
+}
+
+/// Apply a stash, by default the newest, and drop it
+pub fn stash_pop(name: Option<&str>) -> Result<()> {
+    let mut work_dir = find_work_dir()?;
+    let _lock = work_dir.lock(LockMode::Exclusive, "stash")?;
+    match stash::pop(&mut work_dir, name) {
+        Ok(stash) => {
+            println!("{}",
+                     msg!(Msg::Unstashed, stash.name, stash.commit.message));
+            Ok(())
+        }
+        Err(e @ Error(ErrorKind::MergeConflict(_), _)) => {
+            stderrln!("{}", msg!(Msg::UnstashConflict));
+            Err(e)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// List stashes, newest first
+pub fn stash_list() -> Result<()> {
+    let object_store = find_object_store()?;
+    for stash in stash::list(&object_store)? {
+        println!("{} {} {}",
+                 stash.name,
+                 stash.hash,
+                 stash.commit.message.lines().next().unwrap_or(""));
+    }
+    Ok(())
+}
+
+pub fn merge_base<'a, I: 'a>(revs: I) -> Result<()>
+    where I: Iterator<Item = &'a str>
+{
+    let object_store = find_object_store()?;
+    // A range stands for both of its ends
+    let mut ends = Vec::new();
+    for rev in revs {
+        let range: RevRange = rev.parse()?;
+        ends.extend(range.from.map(|from| from.to_string()));
+        ends.push(range.to.to_string());
+    }
+    let ancestor = object_store.find_common_ancestor(ends)?;
     if let Some(hash) = ancestor {
         println!("{}", hash);
     }
@@ -184,5 +2141,6 @@ pub fn merge<'a, I: 'a>(revs: I) -> Result<()>
     where I: Iterator<Item = &'a RevSpec>
 {
     let mut work_dir = find_work_dir()?;
+    let _lock = work_dir.lock(LockMode::Exclusive, "merge")?;
     work_dir.merge(revs)
 }
@@ -0,0 +1,118 @@
+//! Repository configuration, stored in the hidden directory
+
+use dag::HashAlgorithm;
+use rolling_hash::ChunkParams;
+
+/// Settings that are fixed for a repository when it is created
+///
+/// The config is written at init time, so that the repository keeps behaving
+/// consistently with its stored objects even if the program defaults change.
+///
+/// Fields added after the first release are optional, so that older config
+/// files can still be read. Use the accessor methods to get the effective
+/// value.
+#[derive(Debug,Clone,Hash,PartialEq,Eq,RustcEncodable,RustcDecodable)]
+pub struct RepoConfig {
+    pub chunking: ChunkParams,
+    pub hash_algorithm: Option<HashAlgorithm>,
+    pub preserve_metadata: Option<bool>,
+    pub encryption: Option<EncryptionScope>,
+    pub typed_chunking: Option<bool>,
+    pub extract_metadata: Option<bool>,
+}
+
+/// Which objects an encrypted repository encrypts
+///
+/// With `Content`, file contents (blobs and chunk indexes) are encrypted,
+/// but trees and commits are not, so history can be browsed without the
+/// passphrase. With `All`, names and messages are hidden too.
+#[derive(Debug,Clone,Copy,Hash,PartialEq,Eq,RustcEncodable,RustcDecodable)]
+pub enum EncryptionScope {
+    Content,
+    All,
+}
+
+impl RepoConfig {
+    /// Config implied for a repository that predates the config file
+    pub fn legacy() -> Self {
+        RepoConfig {
+            chunking: ChunkParams::default(),
+            hash_algorithm: None,
+            preserve_metadata: None,
+            encryption: None,
+            typed_chunking: None,
+            extract_metadata: None,
+        }
+    }
+
+    /// Hash algorithm for object keys (SHA-1 if not recorded)
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        self.hash_algorithm.unwrap_or(HashAlgorithm::Sha1)
+    }
+
+    /// Record and restore file modification times and permissions
+    pub fn preserve_metadata(&self) -> bool {
+        self.preserve_metadata.unwrap_or(false)
+    }
+
+    /// Choose chunking by each file's media type (see `media_type`)
+    ///
+    /// Off for older repositories, where it would change the hashes of files
+    /// that are already stored.
+    pub fn typed_chunking(&self) -> bool {
+        self.typed_chunking.unwrap_or(false)
+    }
+
+    /// Extract metadata, such as EXIF tags, from media files when committing
+    pub fn extract_metadata(&self) -> bool {
+        self.extract_metadata.unwrap_or(false)
+    }
+}
+
+/// Config for a new repository
+impl Default for RepoConfig {
+    fn default() -> Self {
+        RepoConfig {
+            chunking: ChunkParams::default(),
+            hash_algorithm: Some(HashAlgorithm::default()),
+            preserve_metadata: None,
+            encryption: None,
+            typed_chunking: Some(true),
+            extract_metadata: None,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use rustc_serialize::json;
+    use super::*;
+
+    #[test]
+    fn test_serialize_repo_config() {
+        let obj = RepoConfig {
+            chunking: ChunkParams::for_target_size(8192),
+            hash_algorithm: Some(HashAlgorithm::Sha256),
+            preserve_metadata: Some(true),
+            encryption: Some(EncryptionScope::Content),
+            typed_chunking: Some(true),
+            extract_metadata: Some(true),
+        };
+
+        let encoded = json::encode(&obj).unwrap();
+        let decoded: RepoConfig = json::decode(&encoded).unwrap();
+        assert_eq!(decoded, obj);
+    }
+
+    #[test]
+    fn test_old_config_uses_sha1() {
+        let encoded = r#"{"chunking":{"window_size":4096,"match_size":8192}}"#;
+        let decoded: RepoConfig = json::decode(encoded).unwrap();
+        assert_eq!(decoded.hash_algorithm(), HashAlgorithm::Sha1);
+        assert_eq!(decoded.preserve_metadata(), false);
+        assert_eq!(decoded.encryption, None);
+        assert_eq!(decoded.typed_chunking(), false);
+        assert_eq!(decoded.extract_metadata(), false);
+    }
+}
@@ -1,8 +1,42 @@
 pub const HIDDEN_DIR_NAME: &'static str = ".dmv";
 pub const CACHE_FILE_NAME: &'static str = ".dmv_cache";
+pub const IGNORE_FILE_NAME: &'static str = ".dmvignore";
 
 pub const DEFAULT_BRANCH_NAME: &'static str = "master";
 
+/// Days without a fetch or push before `doctor` warns about it
+pub const SYNC_WARNING_DAYS: u64 = 30;
+
+/// Most hash cache entries to keep, across all cache files, when pruning
+pub const DEFAULT_MAX_CACHE_ENTRIES: usize = 1_000_000;
+
+/// Percent of cached files that `verify-work-dir` rehashes, unless `--all`
+pub const DEFAULT_VERIFY_SAMPLE_PERCENT: u32 = 5;
+
+/// Objects in each parity group built by `dmv parity build`
+pub const DEFAULT_PARITY_GROUP_SIZE: usize = 16;
+
+/// Parity shards for each group, and so objects each group can lose
+pub const DEFAULT_PARITY_SHARDS: usize = 2;
+
+/// Seconds a hook may run before it is killed
+pub const DEFAULT_HOOK_TIMEOUT_SECS: u64 = 60;
+
+/// Seconds a deriver may run before it is killed, allowing for long videos
+pub const DEFAULT_DERIVE_TIMEOUT_SECS: u64 = 600;
+
+/// Seconds between progress lines when stderr is not a terminal
+pub const PLAIN_PROGRESS_INTERVAL_SECS: u64 = 5;
+
+/// Most trees whose children a store keeps in memory while walking
+pub const TREE_MEMO_LIMIT: usize = 100_000;
+
+/// Bytes at the start of a file that `grep` checks for NUL, to skip binaries
+pub const BINARY_CHECK_BYTES: usize = 8000;
+
+/// Most changed files to list in `status --watch`, to fit on one screen
+pub const WATCH_STATUS_LINES: usize = 20;
+
 pub const PROJECT_GIT_LOG: &'static str =
     include_str!(concat!(env!("OUT_DIR"), "/project_git_log.txt"));
 
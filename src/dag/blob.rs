@@ -41,7 +41,9 @@ impl ObjectCommon for Blob {
 }
 
 impl ReadObjectContent for Blob {
-    fn read_content<R: io::BufRead>(reader: &mut R) -> Result<Self> {
+    fn read_content<R: io::BufRead>(reader: &mut R,
+                                    _algorithm: HashAlgorithm)
+                                    -> Result<Self> {
         let mut content: Vec<u8> = Vec::new();
         try!(reader.read_to_end(&mut content));
         Ok(Blob { content: content })
@@ -80,7 +82,7 @@ mod test {
                    });
 
         // Read in object content
-        let readblob = Blob::read_content(&mut reader)
+        let readblob = Blob::read_content(&mut reader, HashAlgorithm::default())
             .expect("read rest of blob");
 
         assert_eq!(readblob,
@@ -41,23 +41,96 @@ impl ChunkedBlob {
     /// Because adding the blob requires calculating the hash, we give the blob
     /// back along with the hash, as a HashedObject. This way the hash can be
     /// reused.
-    pub fn add_blob(&mut self, blob: Blob) -> HashedObject {
+    pub fn add_blob(&mut self,
+                    blob: Blob,
+                    algorithm: HashAlgorithm)
+                    -> HashedObject {
         let size = blob.content_size();
-        let hashed = blob.to_hashed();
+        let hashed = blob.to_hashed_with(algorithm);
         self.add_chunk(size, hashed.hash().to_owned());
         hashed
     }
+
+    /// Sum of the sizes of all chunks
+    pub fn chunk_sum(&self) -> ObjectSize {
+        self.chunks.iter().map(|chunk| chunk.size).sum()
+    }
+
+    /// Check that the offsets and total size agree with the chunk sizes
+    pub fn validate(&self) -> Result<()> {
+        let mut offset = 0;
+        for (i, chunk) in self.chunks.iter().enumerate() {
+            if chunk.offset != offset {
+                bail!(ErrorKind::ChunkOffsetMismatch(i, chunk.offset, offset));
+            }
+            offset += chunk.size;
+        }
+        if self.total_size != offset {
+            bail!(ErrorKind::ChunkedBlobSizeMismatch(self.total_size, offset));
+        }
+        Ok(())
+    }
+
+    /// Rebuild the index with offsets and total size from the chunk sizes
+    pub fn with_corrected_sizes(&self) -> Self {
+        let mut corrected = ChunkedBlob::new();
+        for chunk in &self.chunks {
+            corrected.add_chunk(chunk.size, chunk.hash);
+        }
+        corrected
+    }
+
+    /// Read the index without validating sizes, to examine or repair it
+    pub fn read_content_unvalidated<R: io::BufRead>(reader: &mut R,
+                                                    algorithm: HashAlgorithm)
+                                                    -> Result<Self> {
+        let mut chunk_record_buf = vec![0u8; chunk_record_size(algorithm)];
+
+        let total_size = try!(read_object_size(reader));
+        let num_chunks = try!(read_object_size(reader));
+        let mut chunks: Vec<ChunkOffset> = Vec::new();
+        loop {
+            match reader.read_exact(&mut chunk_record_buf) {
+                Ok(()) => {
+                    let chunk_offset =
+                        object_size_from_bytes(&chunk_record_buf[0..8]);
+                    let chunk_size =
+                        object_size_from_bytes(&chunk_record_buf[8..16]);
+                    let chunk_hash =
+                        ObjectKey::from_bytes(&chunk_record_buf[16..]);
+
+                    chunks.push(ChunkOffset {
+                        offset: chunk_offset,
+                        size: chunk_size,
+                        hash: chunk_hash,
+                    });
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => bail!(e),
+            }
+        }
+        if chunks.len() as ObjectSize != num_chunks {
+            bail!("Only read {} of {} chunks", chunks.len(), num_chunks);
+        }
+        Ok(ChunkedBlob {
+            total_size: total_size,
+            chunks: chunks,
+        })
+    }
 }
 
-const CHUNK_RECORD_SIZE: usize = OBJECT_SIZE_BYTES * 2 + KEY_SIZE_BYTES;
+/// Size of a chunk record, with keys of the given algorithm
+fn chunk_record_size(algorithm: HashAlgorithm) -> usize {
+    OBJECT_SIZE_BYTES * 2 + algorithm.key_size_bytes()
+}
 
 impl ObjectCommon for ChunkedBlob {
     fn object_type(&self) -> ObjectType { ObjectType::ChunkedBlob }
 
     fn content_size(&self) -> ObjectSize {
-        (OBJECT_SIZE_BYTES * 2 +
-         self.chunks.len() *
-         CHUNK_RECORD_SIZE) as ObjectSize
+        self.chunks.iter().fold(OBJECT_SIZE_BYTES * 2, |acc, chunk| {
+            acc + chunk_record_size(chunk.hash.algorithm())
+        }) as ObjectSize
     }
 
     fn write_content(&self, writer: &mut io::Write) -> io::Result<()> {
@@ -106,39 +179,13 @@ Total file size:        {:>10}
 
 
 impl ReadObjectContent for ChunkedBlob {
-    fn read_content<R: io::BufRead>(reader: &mut R) -> Result<Self> {
-        let mut chunk_record_buf = [0u8; CHUNK_RECORD_SIZE];
-
-        let total_size = try!(read_object_size(reader));
-        let num_chunks = try!(read_object_size(reader));
-        let mut chunks: Vec<ChunkOffset> = Vec::new();
-        loop {
-            match reader.read_exact(&mut chunk_record_buf) {
-                Ok(()) => {
-                    let chunk_offset =
-                        object_size_from_bytes(&chunk_record_buf[0..8]);
-                    let chunk_size =
-                        object_size_from_bytes(&chunk_record_buf[8..16]);
-                    let chunk_hash =
-                        ObjectKey::from_bytes(&chunk_record_buf[16..]);
-
-                    chunks.push(ChunkOffset {
-                        offset: chunk_offset,
-                        size: chunk_size,
-                        hash: chunk_hash,
-                    });
-                }
-                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
-                Err(e) => bail!(e),
-            }
-        }
-        if chunks.len() as ObjectSize != num_chunks {
-            bail!("Only read {} of {} chunks", chunks.len(), num_chunks);
-        }
-        Ok(ChunkedBlob {
-            total_size: total_size,
-            chunks: chunks,
-        })
+    /// Read the index, checking that its sizes are consistent
+    fn read_content<R: io::BufRead>(reader: &mut R,
+                                    algorithm: HashAlgorithm)
+                                    -> Result<Self> {
+        let index = ChunkedBlob::read_content_unvalidated(reader, algorithm)?;
+        index.validate()?;
+        Ok(index)
     }
 }
 
@@ -226,9 +273,37 @@ mod test {
         assert_ne!(header.content_size, 0);
 
         // Read in object content
-        let readobject = ChunkedBlob::read_content(&mut reader)
+        let readobject = ChunkedBlob::read_content(&mut reader,
+                                                   HashAlgorithm::default())
             .expect("read object content");
 
         assert_eq!(readobject, chunked_blob);
     }
+
+    #[test]
+    fn test_read_chunkedblob_bad_size() {
+        let (_, _, mut chunked_blob) = create_random_chunkedblob();
+        let actual_size = chunked_blob.total_size;
+        chunked_blob.total_size += 10;
+
+        let mut output: Vec<u8> = Vec::new();
+        chunked_blob.write_to(&mut output).expect("write out chunked blob");
+        let mut reader = io::BufReader::new(output.as_slice());
+        ObjectHeader::read_from(&mut reader).expect("read header");
+
+        let result = ChunkedBlob::read_content(&mut reader,
+                                               HashAlgorithm::default());
+        match result {
+            Err(Error(ErrorKind::ChunkedBlobSizeMismatch(recorded, actual),
+                      _)) => {
+                assert_eq!(recorded, actual_size + 10);
+                assert_eq!(actual, actual_size);
+            }
+            other => panic!("Expected size mismatch, got {:?}", other),
+        }
+
+        let corrected = chunked_blob.with_corrected_sizes();
+        assert_eq!(corrected.total_size, actual_size);
+        assert!(corrected.validate().is_ok());
+    }
 }
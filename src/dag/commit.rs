@@ -1,19 +1,55 @@
 use std::io;
+use std::io::Read;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 use super::*;
 
+/// Byte that marks the commit time after the parents
+///
+/// Like `SIGNATURE_MARKER`, it can't start a UTF-8 message, so commits made
+/// before times were recorded read and hash as they always did.
+const TIME_MARKER: u8 = 0xfe;
+
+/// Byte that marks a signature block after the parents
+///
+/// It can never start a UTF-8 message, so commits without a signature are
+/// written (and hashed) exactly as before signatures existed.
+const SIGNATURE_MARKER: u8 = 0xff;
+
 /// A commit object: a Tree with parents and other metadata
 #[derive(Clone,Eq,PartialEq,Hash,Debug)]
 pub struct Commit {
     pub tree: ObjectKey,
     pub parents: Vec<ObjectKey>,
     pub message: String,
+    /// When the commit was made, in seconds since the Unix epoch, if recorded
+    pub time: Option<u64>,
+    /// Text signature block over the unsigned commit (see `signing`)
+    pub signature: Option<String>,
+}
+
+impl Commit {
+    /// The commit without its signature, which is what gets signed
+    pub fn unsigned(&self) -> Commit {
+        Commit { signature: None, ..self.clone() }
+    }
+
+    /// The current time, for a new commit
+    pub fn current_time() -> Option<u64> {
+        SystemTime::now().duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())
+    }
 }
 
 impl ObjectCommon for Commit {
     fn object_type(&self) -> ObjectType { ObjectType::Commit }
     fn content_size(&self) -> ObjectSize {
+        let time_size = self.time.map_or(0, |_| 1 + 8);
+        let signature_size = self.signature
+            .as_ref()
+            .map_or(0, |sig| 1 + 4 + sig.len());
         let content_size = OBJECT_SIZE_BYTES + 1 +
                            OBJECT_SIZE_BYTES * self.parents.len() +
+                           time_size + signature_size +
                            self.message.as_bytes().len();
         content_size as ObjectSize
     }
@@ -24,6 +60,15 @@ impl ObjectCommon for Commit {
         for parent in self.parents.iter() {
             try!(writer.write(parent.as_ref()));
         }
+        if let Some(time) = self.time {
+            writer.write_u8(TIME_MARKER)?;
+            writer.write_u64::<byteorder::BigEndian>(time)?;
+        }
+        if let Some(ref signature) = self.signature {
+            writer.write_u8(SIGNATURE_MARKER)?;
+            writer.write_u32::<byteorder::BigEndian>(signature.len() as u32)?;
+            writer.write_all(signature.as_bytes())?;
+        }
         try!(writer.write(self.message.as_bytes()));
 
         Ok(())
@@ -43,22 +88,31 @@ impl ObjectCommon for Commit {
 Object content size:    {:>10}
 Tree:       {:x}
 Parents:    {}
-
-{}
 ",
                human_readable::human_bytes(self.content_size()),
                self.tree,
-               parents_join,
-               self.message)
+               parents_join)
             .unwrap();
+        if let Some(time) = self.time {
+            writeln!(&mut output,
+                     "Time:       {}",
+                     human_readable::human_date(time))
+                .unwrap();
+        }
+        if let Some(ref signature) = self.signature {
+            write!(&mut output, "Signature:\n{}\n", signature).unwrap();
+        }
+        write!(&mut output, "\n{}\n", self.message).unwrap();
 
         output
     }
 }
 
 impl ReadObjectContent for Commit {
-    fn read_content<R: io::BufRead>(reader: &mut R) -> Result<Self> {
-        let tree = ObjectKey::read_from(reader)?;
+    fn read_content<R: io::BufRead>(reader: &mut R,
+                                    algorithm: HashAlgorithm)
+                                    -> Result<Self> {
+        let tree = ObjectKey::read_from(reader, algorithm)?;
 
         let mut num_parents_buf = [0u8; 1];
         try!(reader.read_exact(&mut num_parents_buf));
@@ -67,10 +121,25 @@ impl ReadObjectContent for Commit {
             Vec::with_capacity(num_parents as usize);
 
         for _ in 0..num_parents {
-            let parent = ObjectKey::read_from(reader)?;
+            let parent = ObjectKey::read_from(reader, algorithm)?;
             parents.push(parent);
         }
 
+        let mut time = None;
+        if reader.fill_buf()?.first() == Some(&TIME_MARKER) {
+            reader.consume(1);
+            time = Some(reader.read_u64::<byteorder::BigEndian>()?);
+        }
+
+        let mut signature = None;
+        if reader.fill_buf()?.first() == Some(&SIGNATURE_MARKER) {
+            reader.consume(1);
+            let len = reader.read_u32::<byteorder::BigEndian>()?;
+            let mut buf = Vec::with_capacity(len as usize);
+            reader.take(len as u64).read_to_end(&mut buf)?;
+            signature = Some(String::from_utf8(buf)?);
+        }
+
         let mut message = String::new();
         try!(reader.read_to_string(&mut message));
 
@@ -78,6 +147,8 @@ impl ReadObjectContent for Commit {
             tree: tree,
             parents: parents,
             message: message,
+            time: time,
+            signature: signature,
         })
     }
 }
@@ -100,6 +171,8 @@ mod test {
             tree: rng.gen(),
             parents: vec![rng.gen(), rng.gen(), rng.gen()],
             message: "Test Commit".to_owned(),
+            time: None,
+            signature: None,
         };
 
         // Write out
@@ -114,9 +187,71 @@ mod test {
         assert_ne!(header.content_size, 0);
 
         // Read in object content
-        let readobject = Commit::read_content(&mut reader)
+        let readobject = Commit::read_content(&mut reader,
+                                              HashAlgorithm::default())
             .expect("read object content");
 
         assert_eq!(readobject, object);
     }
+
+    #[test]
+    fn test_signed_commit() {
+        let mut rng = testutil::TestRand::default();
+        let unsigned = Commit {
+            tree: rng.gen(),
+            parents: vec![rng.gen()],
+            message: "Signed".to_owned(),
+            time: Some(1_500_000_000),
+            signature: None,
+        };
+        let signed = Commit {
+            signature: Some("ed25519 abc def".to_owned()),
+            ..unsigned.clone()
+        };
+
+        let mut output: Vec<u8> = Vec::new();
+        signed.write_to(&mut output).expect("write out object");
+        let mut reader = io::BufReader::new(output.as_slice());
+        let header = ObjectHeader::read_from(&mut reader).expect("read header");
+        assert_eq!(header.content_size, signed.content_size());
+        let readobject = Commit::read_content(&mut reader,
+                                              HashAlgorithm::default())
+            .expect("read object content");
+        assert_eq!(readobject, signed);
+
+        assert_eq!(signed.unsigned(), unsigned);
+        assert_ne!(signed.calculate_hash(), unsigned.calculate_hash());
+    }
+
+    #[test]
+    fn test_commit_time() {
+        let mut rng = testutil::TestRand::default();
+        let untimed = Commit {
+            tree: rng.gen(),
+            parents: vec![rng.gen()],
+            message: "Timed".to_owned(),
+            time: None,
+            signature: None,
+        };
+        let timed = Commit { time: Some(1_500_000_000), ..untimed.clone() };
+
+        let mut output: Vec<u8> = Vec::new();
+        timed.write_to(&mut output).expect("write out object");
+        let mut reader = io::BufReader::new(output.as_slice());
+        let header = ObjectHeader::read_from(&mut reader).expect("read header");
+        assert_eq!(header.content_size, timed.content_size());
+        let readobject = Commit::read_content(&mut reader,
+                                              HashAlgorithm::default())
+            .expect("read object content");
+        assert_eq!(readobject, timed);
+
+        // A commit without a time is written as before times were recorded
+        let mut output: Vec<u8> = Vec::new();
+        untimed.write_content(&mut output).expect("write out content");
+        let mut expected = untimed.tree.as_ref().to_vec();
+        expected.push(1);
+        expected.extend_from_slice(untimed.parents[0].as_ref());
+        expected.extend_from_slice(b"Timed");
+        assert_eq!(output, expected);
+    }
 }
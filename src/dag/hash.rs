@@ -1,9 +1,122 @@
 use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use crypto::sha2::Sha256;
+use error::*;
+use std::env;
 use std::io;
 use std::ops::Deref;
+use std::sync::Arc;
+use std::sync::RwLock;
 use super::*;
 
 
+/// Environment variable naming the digest backend to use
+pub const DIGEST_BACKEND_VAR: &'static str = "DMV_DIGEST_BACKEND";
+
+/// A source of digest implementations for the hash algorithms
+///
+/// Hashes are the same whatever the backend, so backends can be swapped
+/// freely, to use whichever is fastest on the hardware at hand.
+pub trait DigestBackend: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn digest(&self, algorithm: HashAlgorithm) -> Box<Digest + Send>;
+}
+
+/// Digests from the rust-crypto crate, built in and always available
+pub struct RustCryptoBackend;
+
+impl DigestBackend for RustCryptoBackend {
+    fn name(&self) -> &'static str { "rust-crypto" }
+    fn digest(&self, algorithm: HashAlgorithm) -> Box<Digest + Send> {
+        match algorithm {
+            HashAlgorithm::Sha1 => Box::new(Sha1::new()),
+            HashAlgorithm::Sha256 => Box::new(Sha256::new()),
+        }
+    }
+}
+
+/// The backends built into this binary
+pub fn digest_backends() -> Vec<Arc<DigestBackend>> {
+    vec![Arc::new(RustCryptoBackend)]
+}
+
+/// A built-in backend, by name
+pub fn digest_backend_named(name: &str) -> Result<Arc<DigestBackend>> {
+    match digest_backends().into_iter().find(|b| b.name() == name) {
+        Some(backend) => Ok(backend),
+        None => {
+            let names = digest_backends()
+                .iter()
+                .map(|b| b.name())
+                .collect::<Vec<_>>();
+            bail!("Unknown digest backend: {} (available: {})",
+                  name,
+                  names.join(", "))
+        }
+    }
+}
+
+lazy_static!{
+    static ref DIGEST_BACKEND: RwLock<Arc<DigestBackend>> = {
+        let backend = env::var(DIGEST_BACKEND_VAR)
+            .ok()
+            .and_then(|name| match digest_backend_named(&name) {
+                Ok(backend) => Some(backend),
+                Err(e) => {
+                    warn!("{}", e);
+                    None
+                }
+            })
+            .unwrap_or_else(|| Arc::new(RustCryptoBackend));
+        RwLock::new(backend)
+    };
+}
+
+/// The backend used for all hashing, chosen by `DMV_DIGEST_BACKEND`
+pub fn digest_backend() -> Arc<DigestBackend> {
+    DIGEST_BACKEND.read().unwrap().clone()
+}
+
+/// Use the given backend for all hashing from now on
+pub fn set_digest_backend(backend: Arc<DigestBackend>) {
+    *DIGEST_BACKEND.write().unwrap() = backend;
+}
+
+
+/// Incremental hash calculation, for any supported algorithm
+pub struct Hasher {
+    algorithm: HashAlgorithm,
+    digest: Box<Digest + Send>,
+}
+
+impl Hasher {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        Self::with_backend(algorithm, &*digest_backend())
+    }
+
+    /// A hasher using a particular backend, rather than the current one
+    pub fn with_backend(algorithm: HashAlgorithm,
+                        backend: &DigestBackend)
+                        -> Self {
+        Hasher {
+            algorithm: algorithm,
+            digest: backend.digest(algorithm),
+        }
+    }
+
+    pub fn algorithm(&self) -> HashAlgorithm { self.algorithm }
+
+    pub fn input(&mut self, bytes: &[u8]) { self.digest.input(bytes) }
+
+    /// Give the hash of all input so far
+    pub fn result(&mut self) -> ObjectKey {
+        let mut bytes = [0u8; MAX_KEY_SIZE_BYTES];
+        let len = self.algorithm.key_size_bytes();
+        self.digest.result(&mut bytes[..len]);
+        ObjectKey::from_bytes(&bytes[..len])
+    }
+}
+
 /// Wraps an existing writer and computes a hash of the bytes going into it
 pub struct HashWriter<W: io::Write> {
     writer: W,
@@ -11,18 +124,20 @@ pub struct HashWriter<W: io::Write> {
 }
 
 impl<W: io::Write> HashWriter<W> {
+    /// Wrap the writer, hashing with the default algorithm
     pub fn wrap(writer: W) -> Self {
+        Self::wrap_with(writer, HashAlgorithm::default())
+    }
+
+    /// Wrap the writer, hashing with the given algorithm
+    pub fn wrap_with(writer: W, algorithm: HashAlgorithm) -> Self {
         HashWriter {
             writer: writer,
-            hasher: Hasher::new(),
+            hasher: Hasher::new(algorithm),
         }
     }
 
-    pub fn hash(&mut self) -> ObjectKey {
-        let mut bytes = [0u8; KEY_SIZE_BYTES];
-        self.hasher.result(&mut bytes);
-        ObjectKey::from(bytes)
-    }
+    pub fn hash(&mut self) -> ObjectKey { self.hasher.result() }
 }
 
 impl<W: io::Write> io::Write for HashWriter<W> {
@@ -72,10 +187,18 @@ impl<O: Into<Object>> From<O> for HashedObject {
 /// ```
 pub trait ToHashed {
     fn to_hashed(self) -> HashedObject;
+    fn to_hashed_with(self, algorithm: HashAlgorithm) -> HashedObject;
 }
 
 impl<O: Into<Object>> ToHashed for O {
     fn to_hashed(self) -> HashedObject { HashedObject::from(self.into()) }
+    fn to_hashed_with(self, algorithm: HashAlgorithm) -> HashedObject {
+        let obj = self.into();
+        HashedObject {
+            hash: obj.calculate_hash_with(algorithm),
+            object: obj,
+        }
+    }
 }
 
 impl HashedObject {
@@ -93,7 +216,13 @@ impl ObjectCommon for HashedObject {
     fn write_content(&self, writer: &mut io::Write) -> io::Result<()> {
         self.deref().write_content(writer)
     }
-    fn calculate_hash(&self) -> ObjectKey { self.hash().to_owned() }
+    fn calculate_hash_with(&self, algorithm: HashAlgorithm) -> ObjectKey {
+        if self.hash.algorithm() == algorithm {
+            self.hash().to_owned()
+        } else {
+            self.deref().calculate_hash_with(algorithm)
+        }
+    }
     fn pretty_print(&self) -> String { self.deref().pretty_print() }
 }
 
@@ -111,7 +240,8 @@ mod test {
 
         let mut output: Vec<u8> = Vec::new();
         {
-            let mut hasher = HashWriter::wrap(&mut output);
+            let mut hasher = HashWriter::wrap_with(&mut output,
+                                                   HashAlgorithm::Sha1);
             hasher.write(input).expect("write input");
             assert_eq!(hasher.hash().to_hex(), expected_hash);
             hasher.flush().expect("flush hash writer");
@@ -119,4 +249,18 @@ mod test {
 
         assert_eq!(output, input);
     }
+
+    #[test]
+    fn test_hash_write_sha256() {
+        let input = b"Hello world!";
+        let expected_hash = concat!("c0535e4be2b79ffd93291305436bf889",
+                                    "314e4a3faec05ecffcbb7df31ad9e51a");
+
+        let mut hasher = HashWriter::wrap_with(io::sink(),
+                                               HashAlgorithm::Sha256);
+        hasher.write(input).expect("write input");
+        let hash = hasher.hash();
+        assert_eq!(hash.algorithm(), HashAlgorithm::Sha256);
+        assert_eq!(hash.to_hex(), expected_hash);
+    }
 }
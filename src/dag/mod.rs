@@ -57,6 +57,7 @@ pub fn object_size_from_bytes(buf: &[u8]) -> ObjectSize {
 
 /// Simple enum to represent the available object types
 #[derive(Clone,Copy,Eq,PartialEq,Ord,PartialOrd,Hash,Debug)]
+#[derive(RustcEncodable,RustcDecodable)]
 pub enum ObjectType {
     Blob,
     ChunkedBlob,
@@ -139,15 +140,23 @@ impl ObjectHeader {
     }
 
     pub fn read_content<R: io::BufRead>(&self,
-                                        reader: &mut R)
+                                        reader: &mut R,
+                                        algorithm: HashAlgorithm)
                                         -> Result<Object> {
         let object = match self.object_type {
-            ObjectType::Blob => Object::Blob(Blob::read_content(reader)?),
+            ObjectType::Blob => {
+                Object::Blob(Blob::read_content(reader, algorithm)?)
+            }
             ObjectType::ChunkedBlob => {
-                Object::ChunkedBlob(ChunkedBlob::read_content(reader)?)
+                Object::ChunkedBlob(ChunkedBlob::read_content(reader,
+                                                              algorithm)?)
+            }
+            ObjectType::Tree => {
+                Object::Tree(Tree::read_content(reader, algorithm)?)
+            }
+            ObjectType::Commit => {
+                Object::Commit(Commit::read_content(reader, algorithm)?)
             }
-            ObjectType::Tree => Object::Tree(Tree::read_content(reader)?),
-            ObjectType::Commit => Object::Commit(Commit::read_content(reader)?),
         };
         Ok(object)
     }
@@ -175,10 +184,18 @@ pub trait ObjectCommon {
         }
     }
 
-    /// Write object, header AND content, to the given writer
+    /// Write object, header AND content, hashing with the default algorithm
     fn write_to(&self, writer: &mut io::Write) -> io::Result<ObjectKey> {
+        self.write_to_with(writer, HashAlgorithm::default())
+    }
+
+    /// Write object, header AND content, to the given writer
+    fn write_to_with(&self,
+                     writer: &mut io::Write,
+                     algorithm: HashAlgorithm)
+                     -> io::Result<ObjectKey> {
         use std::io::Write;
-        let mut writer = HashWriter::wrap(writer);
+        let mut writer = HashWriter::wrap_with(writer, algorithm);
         try!(self.header().write_to(&mut writer));
         try!(self.write_content(&mut writer));
         try!(writer.flush());
@@ -188,9 +205,14 @@ pub trait ObjectCommon {
     /// Write content bytes to the given writer
     fn write_content(&self, writer: &mut io::Write) -> io::Result<()>;
 
-    /// Calculate the hash key for this object
+    /// Calculate the hash key for this object with the default algorithm
     fn calculate_hash(&self) -> ObjectKey {
-        self.write_to(&mut io::sink())
+        self.calculate_hash_with(HashAlgorithm::default())
+    }
+
+    /// Calculate the hash key for this object with the given algorithm
+    fn calculate_hash_with(&self, algorithm: HashAlgorithm) -> ObjectKey {
+        self.write_to_with(&mut io::sink(), algorithm)
             .expect("IO error should be impossible when writing to a sink")
     }
 
@@ -204,7 +226,11 @@ pub trait ObjectCommon {
 /// used as a trait object.
 pub trait ReadObjectContent: Sized {
     /// Read and parse content bytes from reader (after header)
-    fn read_content<R: io::BufRead>(reader: &mut R) -> Result<Self>;
+    ///
+    /// Embedded object keys are read as keys of the given algorithm.
+    fn read_content<R: io::BufRead>(reader: &mut R,
+                                    algorithm: HashAlgorithm)
+                                    -> Result<Self>;
 }
 
 /// A container that holds an object of any type
@@ -218,9 +244,32 @@ pub enum Object {
 
 impl Object {
     /// Reads the entire object, header and content, from the given file
-    pub fn read_from<R: io::BufRead>(reader: &mut R) -> Result<Self> {
+    pub fn read_from<R: io::BufRead>(reader: &mut R,
+                                     algorithm: HashAlgorithm)
+                                     -> Result<Self> {
         let header = try!(ObjectHeader::read_from(reader));
-        header.read_content(reader)
+        header.read_content(reader, algorithm)
+    }
+
+    /// Keys of all objects that this object refers to directly
+    pub fn child_keys(&self) -> Vec<ObjectKey> {
+        match *self {
+            Object::Blob(_) => vec![],
+            Object::ChunkedBlob(ref index) => {
+                index.chunks.iter().map(|c| c.hash).collect()
+            }
+            Object::Tree(ref tree) => {
+                tree.values()
+                    .chain(tree.media_metadata.values())
+                    .cloned()
+                    .collect()
+            }
+            Object::Commit(ref commit) => {
+                let mut children = commit.parents.clone();
+                children.push(commit.tree);
+                children
+            }
+        }
     }
 }
 
@@ -16,7 +16,7 @@ use super::*;
 /// type.
 ///
 /// ```
-/// use dmv::dag::{ObjectHandle, Object, ObjectCommon, Blob};
+/// use dmv::dag::{ObjectHandle, Object, ObjectCommon, Blob, HashAlgorithm};
 /// use std::io::Cursor;
 ///
 /// // Create a test blob "file"
@@ -25,7 +25,8 @@ use super::*;
 /// let file = Box::new(Cursor::new(file));
 ///
 /// // Read file
-/// let handle = ObjectHandle::read_header(file).unwrap();
+/// let handle = ObjectHandle::read_header(file, HashAlgorithm::default())
+///     .unwrap();
 /// match handle {
 ///     ObjectHandle::Blob(bh) => {
 ///         // Blob can copy content as a stream
@@ -65,26 +66,33 @@ pub enum ObjectHandle {
 ///
 pub struct RawHandle<O: ReadObjectContent> {
     header: ObjectHeader,
+    algorithm: HashAlgorithm,
     file: Box<BufRead>,
     phantom: PhantomData<O>,
 }
 
 impl ObjectHandle {
     /// Create an ObjectHandle by reading the header of the given file
-    pub fn read_header(mut file: Box<BufRead>) -> Result<Self> {
+    ///
+    /// Object keys in the content will be read as keys of the given algorithm.
+    pub fn read_header(mut file: Box<BufRead>,
+                       algorithm: HashAlgorithm)
+                       -> Result<Self> {
         let header = ObjectHeader::read_from(&mut file)?;
         let handle = match header.object_type {
             ObjectType::Blob => {
-                ObjectHandle::Blob(RawHandle::new(header, file))
+                ObjectHandle::Blob(RawHandle::new(header, algorithm, file))
             }
             ObjectType::ChunkedBlob => {
-                ObjectHandle::ChunkedBlob(RawHandle::new(header, file))
+                ObjectHandle::ChunkedBlob(RawHandle::new(header,
+                                                         algorithm,
+                                                         file))
             }
             ObjectType::Tree => {
-                ObjectHandle::Tree(RawHandle::new(header, file))
+                ObjectHandle::Tree(RawHandle::new(header, algorithm, file))
             }
             ObjectType::Commit => {
-                ObjectHandle::Commit(RawHandle::new(header, file))
+                ObjectHandle::Commit(RawHandle::new(header, algorithm, file))
             }
         };
         Ok(handle)
@@ -112,12 +120,31 @@ impl ObjectHandle {
         };
         Ok(obj)
     }
+
+    /// Stream-copy the raw content bytes (everything after the header)
+    ///
+    /// For a Blob this is the file data itself. For other types it is the
+    /// serialized object content, exactly as stored.
+    pub fn copy_raw_content<W: ?Sized + Write>(self,
+                                               writer: &mut W)
+                                               -> Result<()> {
+        match self {
+            ObjectHandle::Blob(raw) => raw.copy_raw_content(writer),
+            ObjectHandle::ChunkedBlob(raw) => raw.copy_raw_content(writer),
+            ObjectHandle::Tree(raw) => raw.copy_raw_content(writer),
+            ObjectHandle::Commit(raw) => raw.copy_raw_content(writer),
+        }
+    }
 }
 
 impl<O: ReadObjectContent> RawHandle<O> {
-    fn new(header: ObjectHeader, file: Box<BufRead>) -> Self {
+    fn new(header: ObjectHeader,
+           algorithm: HashAlgorithm,
+           file: Box<BufRead>)
+           -> Self {
         RawHandle {
             header: header,
+            algorithm: algorithm,
             file: file,
             phantom: PhantomData,
         }
@@ -128,7 +155,16 @@ impl<O: ReadObjectContent> RawHandle<O> {
 
     /// Read and parse the rest of the file, returning the appropriate object
     pub fn read_content(mut self) -> Result<O> {
-        O::read_content(&mut self.file)
+        O::read_content(&mut self.file, self.algorithm)
+    }
+
+    /// Stream-copy the raw content bytes to the given writer, unparsed
+    pub fn copy_raw_content<W: ?Sized + Write>(mut self,
+                                               writer: &mut W)
+                                               -> Result<()> {
+        use std::io::copy;
+        copy(&mut self.file, writer)?;
+        Ok(())
     }
 }
 
@@ -144,6 +180,13 @@ impl RawHandle<Blob> {
     }
 }
 
+impl RawHandle<ChunkedBlob> {
+    /// Read the index without validating sizes, to examine or repair it
+    pub fn read_content_unvalidated(mut self) -> Result<ChunkedBlob> {
+        ChunkedBlob::read_content_unvalidated(&mut self.file, self.algorithm)
+    }
+}
+
 impl<O: ReadObjectContent> fmt::Debug for RawHandle<O> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("RawHandle")
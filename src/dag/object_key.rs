@@ -1,4 +1,3 @@
-use crypto::sha1::Sha1;
 use regex::Regex;
 use rustc_serialize::Decodable;
 use rustc_serialize::Decoder;
@@ -12,27 +11,88 @@ use super::*;
 #[cfg(test)]
 use testutil::rand;
 
-/// Hash type
-pub type Hasher = Sha1;
+/// Hash algorithm used to calculate object keys
+///
+/// The algorithm is a repository-level setting, recorded in the repository
+/// config when the repository is created. Repositories created before the
+/// setting existed use SHA-1.
+#[derive(Debug,Clone,Copy,Eq,PartialEq,Ord,PartialOrd,Hash,
+         RustcEncodable,RustcDecodable)]
+pub enum HashAlgorithm {
+    Sha1,
+    Sha256,
+}
+
+impl HashAlgorithm {
+    /// Size of a key produced by this algorithm, in bytes
+    pub fn key_size_bytes(&self) -> usize {
+        match *self {
+            HashAlgorithm::Sha1 => 20,
+            HashAlgorithm::Sha256 => 32,
+        }
+    }
+
+    /// Number of hex digits required to represent a key of this algorithm
+    pub fn key_size_hex_digits(&self) -> usize { self.key_size_bytes() * 2 }
+
+    /// Identify the algorithm by the length of a full hex key
+    pub fn from_hex_len(len: usize) -> Option<Self> {
+        match len {
+            40 => Some(HashAlgorithm::Sha1),
+            64 => Some(HashAlgorithm::Sha256),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            HashAlgorithm::Sha1 => "sha1",
+            HashAlgorithm::Sha256 => "sha256",
+        }
+    }
+}
+
+/// SHA-256 is the default for new repositories
+impl Default for HashAlgorithm {
+    fn default() -> Self { HashAlgorithm::Sha256 }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
 
-/// Object key size in bits
-const KEY_SIZE_BITS: usize = 160;
+impl FromStr for HashAlgorithm {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sha1" => Ok(HashAlgorithm::Sha1),
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            _ => bail!("Unknown hash algorithm: {}", s),
+        }
+    }
+}
 
-/// Object key size in bytes
-pub const KEY_SIZE_BYTES: usize = KEY_SIZE_BITS / 8;
+/// Largest object key size in bytes, over all algorithms
+pub const MAX_KEY_SIZE_BYTES: usize = 32;
 
-/// Number of hex digits required to represent an object key
-pub const KEY_SIZE_HEX_DIGITS: usize = KEY_SIZE_BITS / 4;
+/// Number of hex digits required to represent the largest object key
+pub const MAX_KEY_SIZE_HEX_DIGITS: usize = MAX_KEY_SIZE_BYTES * 2;
 
 pub const KEY_SHORT_LEN: usize = 8;
 
 lazy_static!{
     pub static ref OBJECT_KEY_PAT:Regex = Regex::new(
-            &format!("^[[:xdigit:]]{{ {} }}$",KEY_SIZE_HEX_DIGITS)).unwrap();
+            "^([[:xdigit:]]{40}|[[:xdigit:]]{64})$").unwrap();
 }
 
 /// Hash key for an object
 ///
+/// The key is tagged with the algorithm that produced it. The algorithm can
+/// be identified from the length of the hex string, so the hex string alone
+/// is enough to parse a key.
+///
 /// On formatting:
 ///
 /// - Display ({}) gives a short hash (this is a lossy operation)
@@ -40,11 +100,14 @@ lazy_static!{
 /// - The conversion From<ObjectKey> for String also gives the full hash
 ///
 /// ```
+/// use dmv::dag::HashAlgorithm;
 /// use dmv::dag::ObjectKey;
 ///
 /// let id = ObjectKey::parse("da39a3ee5e6b4b0d3255bfef95601890afd80709")
 ///             .unwrap();
 ///
+/// assert_eq!(id.algorithm(), HashAlgorithm::Sha1);
+///
 /// assert_eq!(format!("{}", id), "da39a3ee");
 ///
 /// assert_eq!(format!("{:x}", id),
@@ -55,14 +118,19 @@ lazy_static!{
 /// ```
 ///
 #[derive(Copy,Clone,Eq,PartialEq,Ord,PartialOrd,Hash)]
-pub struct ObjectKey([u8; KEY_SIZE_BYTES]);
+pub struct ObjectKey {
+    algorithm: HashAlgorithm,
+    bytes: [u8; MAX_KEY_SIZE_BYTES],
+}
 
 impl ObjectKey {
     pub fn parse(hexstr: &str) -> Result<Self> {
         if !OBJECT_KEY_PAT.is_match(hexstr) {
             bail!(ErrorKind::BadObjectKey(hexstr.to_owned()));
         }
-        let mut buf = [0u8; KEY_SIZE_BYTES];
+        let algorithm = HashAlgorithm::from_hex_len(hexstr.len())
+            .expect("length should be checked by pattern");
+        let mut buf = [0u8; MAX_KEY_SIZE_BYTES];
         let mut i = 0;
         let mut high = true;
 
@@ -82,13 +150,19 @@ impl ObjectKey {
                 i += 1;
             }
         }
-        Ok(ObjectKey(buf))
+        Ok(ObjectKey {
+            algorithm: algorithm,
+            bytes: buf,
+        })
     }
 
+    /// The algorithm that produced this key
+    pub fn algorithm(&self) -> HashAlgorithm { self.algorithm }
+
     /// Give full hex string for this ObjectKey
     pub fn to_hex(&self) -> String {
-        let mut hex = String::with_capacity(KEY_SIZE_HEX_DIGITS);
-        for byte in &self.0 {
+        let mut hex = String::with_capacity(MAX_KEY_SIZE_HEX_DIGITS);
+        for byte in self.as_ref() {
             hex.push_str(&format!("{:02x}", byte));
         }
         hex
@@ -99,27 +173,39 @@ impl ObjectKey {
         self.to_hex()[..KEY_SHORT_LEN].to_owned()
     }
 
-    /// Creates an array from a byte slice (copy)
+    /// Creates a key from a byte slice (copy)
     ///
-    /// Will panic if the slice is the wrong length.
+    /// The algorithm is determined by the length of the slice. Will panic if
+    /// the slice is not the length of any supported algorithm's keys.
     ///
-    /// If the input is a byte array of the correct length (`[u8;
-    /// KEY_SIZE_BYTES]`), you can use From<[u8; KEY_SIZE_BYTES]> instead. That
-    /// conversion is guaranteed to succeed, and it consumes and uses the array,
-    /// rather than performing another allocation.
+    /// If the input is a byte array of the correct length (`[u8; 20]` or `[u8;
+    /// 32]`), you can use From instead. That conversion is guaranteed to
+    /// succeed.
     ///
     pub fn from_bytes(bytes: &[u8]) -> Self {
-        let mut key = [0; KEY_SIZE_BYTES];
-        key.copy_from_slice(bytes);
-        ObjectKey(key)
+        let algorithm = match HashAlgorithm::from_hex_len(bytes.len() * 2) {
+            Some(algorithm) => algorithm,
+            None => panic!("Bad object key length: {}", bytes.len()),
+        };
+        let mut key = [0; MAX_KEY_SIZE_BYTES];
+        key[..bytes.len()].copy_from_slice(bytes);
+        ObjectKey {
+            algorithm: algorithm,
+            bytes: key,
+        }
     }
 
-    /// Read the next bytes as an ObjectKey
-    pub fn read_from<R: Read>(reader: &mut R) -> Result<Self> {
-        let mut hash_buf = [0u8; KEY_SIZE_BYTES];
-        reader.read_exact(&mut hash_buf)
+    /// Read the next bytes as an ObjectKey of the given algorithm
+    pub fn read_from<R: Read>(reader: &mut R,
+                              algorithm: HashAlgorithm)
+                              -> Result<Self> {
+        let mut hash_buf = [0u8; MAX_KEY_SIZE_BYTES];
+        reader.read_exact(&mut hash_buf[..algorithm.key_size_bytes()])
             .chain_err(|| "Error reading ObjectKey")?;
-        Ok(ObjectKey::from(hash_buf))
+        Ok(ObjectKey {
+            algorithm: algorithm,
+            bytes: hash_buf,
+        })
     }
 }
 
@@ -127,8 +213,12 @@ impl<'a> From<&'a ObjectKey> for ObjectKey {
     fn from(hash: &'a ObjectKey) -> Self { hash.to_owned() }
 }
 
-impl From<[u8; KEY_SIZE_BYTES]> for ObjectKey {
-    fn from(arr: [u8; KEY_SIZE_BYTES]) -> Self { ObjectKey(arr) }
+impl From<[u8; 20]> for ObjectKey {
+    fn from(arr: [u8; 20]) -> Self { ObjectKey::from_bytes(&arr) }
+}
+
+impl From<[u8; 32]> for ObjectKey {
+    fn from(arr: [u8; 32]) -> Self { ObjectKey::from_bytes(&arr) }
 }
 
 impl FromStr for ObjectKey {
@@ -139,7 +229,7 @@ impl FromStr for ObjectKey {
 /// Create an ObjectKey with a recognizable pattern
 #[cfg(test)]
 pub fn object_key(num: u8) -> ObjectKey {
-    ObjectKey::from([num; KEY_SIZE_BYTES])
+    ObjectKey::from([num; 20])
 }
 
 // In test, a shortcut to parse a String literal and unwrap it
@@ -153,15 +243,16 @@ impl From<&'static str> for ObjectKey {
 #[cfg(test)]
 impl rand::Rand for ObjectKey {
     fn rand<R: rand::Rng>(rng: &mut R) -> Self {
-        let mut buf = [0u8; KEY_SIZE_BYTES];
-        rng.fill_bytes(&mut buf);
-        ObjectKey::from(buf)
+        let mut buf = [0u8; MAX_KEY_SIZE_BYTES];
+        let len = HashAlgorithm::default().key_size_bytes();
+        rng.fill_bytes(&mut buf[..len]);
+        ObjectKey::from_bytes(&buf[..len])
     }
 }
 
 impl fmt::LowerHex for ObjectKey {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        for byte in &self.0 {
+        for byte in self.as_ref() {
             try!(write!(f, "{:02x}", byte))
         }
         Ok(())
@@ -170,7 +261,7 @@ impl fmt::LowerHex for ObjectKey {
 
 impl fmt::UpperHex for ObjectKey {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        for byte in &self.0 {
+        for byte in self.as_ref() {
             try!(write!(f, "{:02X}", byte))
         }
         Ok(())
@@ -196,7 +287,9 @@ impl convert::From<ObjectKey> for String {
 }
 
 impl AsRef<[u8]> for ObjectKey {
-    fn as_ref(&self) -> &[u8] { &self.0 }
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes[..self.algorithm.key_size_bytes()]
+    }
 }
 
 impl Encodable for ObjectKey {
@@ -241,6 +334,26 @@ mod test {
         assert_eq!(into, hex, "Into converstion");
     }
 
+    #[test]
+    fn test_key_algorithm_by_length() {
+        let sha1 = ObjectKey::from("da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(sha1.algorithm(), HashAlgorithm::Sha1);
+        assert_eq!(sha1.as_ref().len(), 20);
+
+        let hex = concat!("e3b0c44298fc1c149afbf4c8996fb924",
+                          "27ae41e4649b934ca495991b7852b855");
+        let sha256 = ObjectKey::from(hex);
+        assert_eq!(sha256.algorithm(), HashAlgorithm::Sha256);
+        assert_eq!(sha256.as_ref().len(), 32);
+        assert_eq!(sha256.to_hex(), hex);
+        assert_eq!(ObjectKey::from_bytes(sha256.as_ref()), sha256);
+
+        let mut reader = sha256.as_ref();
+        let read = ObjectKey::read_from(&mut reader, HashAlgorithm::Sha256)
+            .unwrap();
+        assert_eq!(read, sha256);
+    }
+
     #[test]
     fn test_key_bad_hex() {
         let bad_inputs =
@@ -1,3 +1,6 @@
+use byteorder;
+use byteorder::ReadBytesExt;
+use byteorder::WriteBytesExt;
 use human_readable;
 use std::collections::BTreeMap;
 use std::ffi::OsString;
@@ -5,23 +8,74 @@ use std::io;
 use super::*;
 
 type PathKeyMap = BTreeMap<OsString, ObjectKey>;
+type PathMetadataMap = BTreeMap<OsString, FileMetadata>;
 
-wrapper_struct!{
-    /// DAG Object representing a directory
-    #[derive(Clone,Eq,PartialEq,Hash,Debug)]
-    pub struct Tree(PathKeyMap);
+/// DAG Object representing a directory
+///
+/// Entries may optionally carry file metadata (see `FileMetadata`), and
+/// links to objects holding metadata extracted from media files (see
+/// `exif`). A tree without either is written exactly as it was before they
+/// existed, so its key does not change.
+#[derive(Clone,Eq,PartialEq,Hash,Debug)]
+pub struct Tree {
+    entries: PathKeyMap,
+    /// Metadata for entries, by name
+    pub metadata: PathMetadataMap,
+    /// Extracted media metadata objects for entries, by name
+    pub media_metadata: PathKeyMap,
+}
+impl_deref_mut!(Tree => PathKeyMap, entries);
+
+impl Default for Tree {
+    fn default() -> Self { Tree::new() }
 }
 
 impl Tree {
-    pub fn new() -> Self { Tree(PathKeyMap::new()) }
+    pub fn new() -> Self {
+        Tree {
+            entries: PathKeyMap::new(),
+            metadata: PathMetadataMap::new(),
+            media_metadata: PathKeyMap::new(),
+        }
+    }
 
     pub fn insert<P>(&mut self, name: P, hash: ObjectKey)
         where P: Into<OsString>
     {
-        self.0.insert(name.into(), hash);
+        self.entries.insert(name.into(), hash);
+    }
+
+    /// Record metadata for an entry
+    pub fn insert_metadata<P>(&mut self, name: P, metadata: FileMetadata)
+        where P: Into<OsString>
+    {
+        self.metadata.insert(name.into(), metadata);
+    }
+
+    /// Link an entry to the object holding its extracted media metadata
+    pub fn insert_media_metadata<P>(&mut self, name: P, key: ObjectKey)
+        where P: Into<OsString>
+    {
+        self.media_metadata.insert(name.into(), key);
     }
 }
 
+/// File attributes recorded alongside a tree entry
+///
+/// Only recorded when the repository is configured to preserve metadata.
+#[derive(Clone,Copy,Eq,PartialEq,Hash,Debug)]
+pub struct FileMetadata {
+    /// Modification time, seconds since the Unix epoch
+    pub mtime_secs: u64,
+    /// Modification time, nanoseconds part
+    pub mtime_nanos: u32,
+    /// Unix permission bits
+    pub mode: u32,
+}
+
+/// Size of a metadata record, not counting the name
+const FILE_METADATA_BYTES: usize = OBJECT_SIZE_BYTES + 4 + 4;
+
 /// Create and populate a Tree object
 #[macro_export]
 macro_rules! tree_object {
@@ -32,20 +86,72 @@ macro_rules! tree_object {
 
 const TREE_ENTRY_SEPARATOR: u8 = b'\n';
 
+impl Tree {
+    /// Size of the marker that starts the metadata section
+    ///
+    /// The marker is an entry with an all-zero key and an empty name, which
+    /// cannot occur as a real entry.
+    fn metadata_marker_size(&self) -> usize {
+        if self.metadata.is_empty() && self.media_metadata.is_empty() {
+            0
+        } else {
+            self.entries
+                .values()
+                .next()
+                .map(|key| key.algorithm().key_size_bytes())
+                .unwrap_or(HashAlgorithm::default().key_size_bytes()) + 1
+        }
+    }
+}
+
 impl ObjectCommon for Tree {
     fn object_type(&self) -> ObjectType { ObjectType::Tree }
     fn content_size(&self) -> ObjectSize {
-        self.0.iter().fold(0, |acc, x| {
-            acc + KEY_SIZE_BYTES + x.0.as_os_str().len() + 1
-        }) as ObjectSize
+        let entries = self.entries.iter().fold(0, |acc, x| {
+            acc + x.1.as_ref().len() + x.0.as_os_str().len() + 1
+        });
+        let metadata = self.metadata.keys().fold(0, |acc, name| {
+            acc + name.as_os_str().len() + 1 + FILE_METADATA_BYTES
+        });
+        // Media metadata links follow an empty name that ends the section
+        let media = match self.media_metadata.is_empty() {
+            true => 0,
+            false => {
+                self.media_metadata.iter().fold(1, |acc, (name, key)| {
+                    acc + name.as_os_str().len() + 1 + key.as_ref().len()
+                })
+            }
+        };
+        (entries + self.metadata_marker_size() + metadata + media) as
+        ObjectSize
     }
 
     fn write_content(&self, writer: &mut io::Write) -> io::Result<()> {
-        for entry in &self.0 {
+        for entry in &self.entries {
             try!(writer.write(entry.1.as_ref()));
             try!(writer.write(entry.0.to_str().unwrap().as_bytes()));
             try!(writer.write(&[TREE_ENTRY_SEPARATOR]));
         }
+        if self.metadata_marker_size() > 0 {
+            let marker = vec![0u8; self.metadata_marker_size() - 1];
+            try!(writer.write(&marker));
+            try!(writer.write(&[TREE_ENTRY_SEPARATOR]));
+        }
+        for (name, meta) in &self.metadata {
+            try!(writer.write(name.to_str().unwrap().as_bytes()));
+            try!(writer.write(&[TREE_ENTRY_SEPARATOR]));
+            try!(write_object_size(writer, meta.mtime_secs));
+            try!(writer.write_u32::<byteorder::BigEndian>(meta.mtime_nanos));
+            try!(writer.write_u32::<byteorder::BigEndian>(meta.mode));
+        }
+        if !self.media_metadata.is_empty() {
+            try!(writer.write(&[TREE_ENTRY_SEPARATOR]));
+        }
+        for (name, key) in &self.media_metadata {
+            try!(writer.write(name.to_str().unwrap().as_bytes()));
+            try!(writer.write(&[TREE_ENTRY_SEPARATOR]));
+            try!(writer.write(key.as_ref()));
+        }
         Ok(())
     }
 
@@ -61,46 +167,66 @@ Object content size:    {:>10}
                human_readable::human_bytes(self.content_size()))
             .unwrap();
 
-        for entry in &self.0 {
+        for entry in &self.entries {
             write!(&mut output,
-                   "{:x} {}\n",
+                   "{:x} {}",
                    entry.1,
                    entry.0.to_str().unwrap())
                 .unwrap();
+            if let Some(meta) = self.metadata.get(entry.0) {
+                write!(&mut output,
+                       "  mode {:o}, mtime {}.{:09}",
+                       meta.mode,
+                       meta.mtime_secs,
+                       meta.mtime_nanos)
+                    .unwrap();
+            }
+            if let Some(key) = self.media_metadata.get(entry.0) {
+                write!(&mut output, "  media metadata {:x}", key).unwrap();
+            }
+            output.push('\n');
         }
         output
     }
 }
 
+/// Read a separator-terminated entry name
+fn read_entry_name<R: io::BufRead>(reader: &mut R) -> Result<String> {
+    let mut name_buf: Vec<u8> = Vec::new();
+    try!(reader.read_until(TREE_ENTRY_SEPARATOR, &mut name_buf));
+    name_buf.pop(); // Drop the string-ending separator
+    String::from_utf8(name_buf).map_err(|e| {
+        let err = e.utf8_error();
+        let bytes = e.into_bytes();
+        Error::from(format!("UTF-8 error: {}, bad bytes: {:?} (\"{}\")",
+                            err,
+                            bytes,
+                            String::from_utf8_lossy(&bytes)))
+    })
+}
+
 impl ReadObjectContent for Tree {
-    fn read_content<R: io::BufRead>(reader: &mut R) -> Result<Self> {
+    fn read_content<R: io::BufRead>(reader: &mut R,
+                                    algorithm: HashAlgorithm)
+                                    -> Result<Self> {
 
         let mut tree = Tree::new();
+        let key_size = algorithm.key_size_bytes();
 
         loop {
             // Read hash
-            let mut hash_buf = [0u8; KEY_SIZE_BYTES];
-            match reader.read_exact(&mut hash_buf) {
+            let mut hash_buf = [0u8; MAX_KEY_SIZE_BYTES];
+            match reader.read_exact(&mut hash_buf[..key_size]) {
                 Ok(()) => (),
-                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Ok(tree)
+                }
                 Err(e) => bail!(e),
             }
-            let hash = ObjectKey::from(hash_buf);
+            let hash = ObjectKey::from_bytes(&hash_buf[..key_size]);
 
             // Read name
-            let mut name_buf: Vec<u8> = Vec::new();
-            try!(reader.read_until(TREE_ENTRY_SEPARATOR, &mut name_buf));
-            name_buf.pop(); // Drop the string-ending separator
-            let name = String::from_utf8(name_buf).map_err(|e| {
-                    let err = e.utf8_error();
-                    let bytes = e.into_bytes();
-                    Error::from(format!("UTF-8 error: {}, bad bytes: {:?} \
-                                         (\"{}\")",
-                                        err,
-                                        bytes,
-                                        String::from_utf8_lossy(&bytes)))
-                })
-                .chain_err(|| {
+            let name = read_entry_name(reader).chain_err(|| {
                     let mut msg = format!("Could not read tree record #{}",
                                           tree.len());
                     if let Some(entry) = tree.iter().last() {
@@ -110,8 +236,39 @@ impl ReadObjectContent for Tree {
                     }
                     msg
                 })?;
+            if name.is_empty() && hash_buf[..key_size].iter().all(|&b| b == 0) {
+                break;
+            }
             tree.insert(name, hash);
         }
+
+        // Metadata section
+        loop {
+            let name = read_entry_name(reader)
+                .chain_err(|| "Could not read tree metadata record")?;
+            if name.is_empty() {
+                break;
+            }
+            let meta = FileMetadata {
+                mtime_secs: read_object_size(reader)?,
+                mtime_nanos: reader.read_u32::<byteorder::BigEndian>()?,
+                mode: reader.read_u32::<byteorder::BigEndian>()?,
+            };
+            tree.insert_metadata(name, meta);
+        }
+
+        // Media metadata section
+        loop {
+            let name = read_entry_name(reader)
+                .chain_err(|| "Could not read tree media metadata record")?;
+            if name.is_empty() {
+                break;
+            }
+            let mut hash_buf = [0u8; MAX_KEY_SIZE_BYTES];
+            reader.read_exact(&mut hash_buf[..key_size])?;
+            let key = ObjectKey::from_bytes(&hash_buf[..key_size]);
+            tree.insert_media_metadata(name, key);
+        }
         Ok(tree)
     }
 }
@@ -119,7 +276,7 @@ impl ReadObjectContent for Tree {
 impl IntoIterator for Tree {
     type Item = (OsString, ObjectKey);
     type IntoIter = <BTreeMap<OsString, ObjectKey> as IntoIterator>::IntoIter;
-    fn into_iter(self) -> Self::IntoIter { self.0.into_iter() }
+    fn into_iter(self) -> Self::IntoIter { self.entries.into_iter() }
 }
 
 #[cfg(test)]
@@ -153,12 +310,77 @@ mod test {
         assert_ne!(header.content_size, 0);
 
         // Read in object content
-        let readobject = Tree::read_content(&mut reader)
+        let readobject = Tree::read_content(&mut reader,
+                                            HashAlgorithm::default())
             .expect("read object content");
 
         assert_eq!(readobject, object);
     }
 
+    #[test]
+    fn test_write_tree_with_metadata() {
+        let mut rng = testutil::TestRand::default();
+
+        let mut object = tree_object!{
+            "foo" => rng.gen::<ObjectKey>(),
+            "bar" => rng.gen::<ObjectKey>(),
+        };
+        let plain_hash = object.calculate_hash();
+        object.insert_metadata("foo",
+                               FileMetadata {
+                                   mtime_secs: 1234567890,
+                                   mtime_nanos: 42,
+                                   mode: 0o644,
+                               });
+        assert_ne!(object.calculate_hash(), plain_hash);
+
+        let mut output: Vec<u8> = Vec::new();
+        object.write_to(&mut output).expect("write out object");
+
+        let mut reader = io::BufReader::new(output.as_slice());
+        let header = ObjectHeader::read_from(&mut reader).expect("read header");
+        assert_eq!(header.content_size as usize, output.len() - 12);
+
+        let readobject = Tree::read_content(&mut reader,
+                                            HashAlgorithm::default())
+            .expect("read object content");
+        assert_eq!(readobject, object);
+        assert_eq!(readobject.len(), 2);
+    }
+
+    #[test]
+    fn test_write_tree_with_media_metadata() {
+        let mut rng = testutil::TestRand::default();
+
+        let mut object = tree_object!{
+            "foo.jpg" => rng.gen::<ObjectKey>(),
+            "bar" => rng.gen::<ObjectKey>(),
+        };
+        object.insert_media_metadata("foo.jpg", rng.gen::<ObjectKey>());
+
+        let round_trip = |object: &Tree| {
+            let mut output: Vec<u8> = Vec::new();
+            object.write_to(&mut output).expect("write out object");
+            let mut reader = io::BufReader::new(output.as_slice());
+            let header = ObjectHeader::read_from(&mut reader)
+                .expect("read header");
+            assert_eq!(header.content_size as usize, output.len() - 12);
+            Tree::read_content(&mut reader, HashAlgorithm::default())
+                .expect("read object content")
+        };
+        assert_eq!(round_trip(&object), object);
+
+        // With file metadata as well
+        object.insert_metadata("bar",
+                               FileMetadata {
+                                   mtime_secs: 1234567890,
+                                   mtime_nanos: 42,
+                                   mode: 0o644,
+                               });
+        assert_eq!(round_trip(&object), object);
+        assert_eq!(Object::from(object).child_keys().len(), 3);
+    }
+
     #[test]
     fn test_tree_sort_by_name() {
         let tree = tree_object!{
@@ -0,0 +1,243 @@
+//! Objects derived from files, such as thumbnails, cached by source hash
+//!
+//! A deriver is an executable in `.dmv/derivers/<name>/`, named for the media
+//! type of the files it handles: the full type with `/` replaced by `-`
+//! (such as `image-jpeg`), else the kind (such as `image`), else `default`.
+//! It reads the file's content on stdin and writes the derived content to
+//! stdout, with these environment variables:
+//!
+//! - `DMV_SOURCE`: the hash of the file, in full hex
+//! - `DMV_MEDIA_TYPE`: its MIME type, such as `image/jpeg`
+//!
+//! The output is stored as a file object and recorded under the hash of the
+//! source and the deriver's name. Since a changed file has a new hash, the
+//! cache never needs invalidating: derived objects of old content are just
+//! not looked up. A deriver that runs longer than `DMV_DERIVE_TIMEOUT`
+//! seconds (default 600) is killed and counts as failed.
+
+use constants::DEFAULT_DERIVE_TIMEOUT_SECS;
+use dag::ObjectKey;
+use error::*;
+use human_readable::human_duration;
+use fs_transfer::FsTransfer;
+use hooks::is_executable;
+use hooks::wait_with_timeout;
+use media_type::MediaType;
+use object_store::ObjectWalkNode;
+use std::env;
+use std::fs;
+use std::io::BufReader;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::Stdio;
+use std::time::Duration;
+use walker::NodeLookup;
+
+/// Environment variable giving the deriver timeout in seconds
+pub const DERIVE_TIMEOUT_VAR: &'static str = "DMV_DERIVE_TIMEOUT";
+
+/// Name of the deriver used for files of any type without their own
+pub const DEFAULT_DERIVER: &'static str = "default";
+
+/// The derivers of one repository
+pub struct Derivers {
+    dir: PathBuf,
+    timeout: Duration,
+}
+
+impl Derivers {
+    /// Derivers in the given directory
+    pub fn new(dir: PathBuf) -> Self {
+        let timeout = env::var(DERIVE_TIMEOUT_VAR)
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .unwrap_or(DEFAULT_DERIVE_TIMEOUT_SECS);
+        Derivers {
+            dir: dir,
+            timeout: Duration::from_secs(timeout),
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Path of the program that derives `name` from files of a media type
+    pub fn find(&self, name: &str, media_type: MediaType) -> Option<PathBuf> {
+        let mime = media_type.mime();
+        let kind = mime.split('/').next().unwrap_or(mime);
+        let dir = self.dir.join(name);
+        [mime.replace('/', "-"), kind.to_owned(), DEFAULT_DERIVER.to_owned()]
+            .iter()
+            .map(|file_name| dir.join(file_name))
+            .find(|path| path.is_file() && is_executable(path))
+    }
+
+    /// Run a deriver, from one file to another
+    fn run(&self,
+           program: &Path,
+           name: &str,
+           source: &ObjectKey,
+           media_type: MediaType,
+           input: &Path,
+           output: &Path)
+           -> Result<()> {
+        debug!("Running {} deriver: {}", name, program.display());
+        let mut child = Command::new(program)
+            .stdin(Stdio::from(fs::File::open(input)?))
+            .stdout(Stdio::from(fs::File::create(output)?))
+            .env("DMV_SOURCE", source.to_hex())
+            .env("DMV_MEDIA_TYPE", media_type.mime())
+            .spawn()
+            .chain_err(|| format!("Could not run {} deriver", name))?;
+        match wait_with_timeout(&mut child, self.timeout)? {
+            Some(status) if !status.success() => {
+                bail!(ErrorKind::DeriveFailed(name.to_owned(),
+                                              status.to_string()))
+            }
+            Some(_) => Ok(()),
+            None => {
+                let why = format!("timed out after {}",
+                                  human_duration(self.timeout));
+                bail!(ErrorKind::DeriveFailed(name.to_owned(), why))
+            }
+        }
+    }
+}
+
+/// An object derived from a file
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Derived {
+    pub key: ObjectKey,
+    /// Was it found in the cache, rather than derived just now?
+    pub cached: bool,
+}
+
+/// Find or make the object derived from a file under the given name
+pub fn derive(fs_transfer: &mut FsTransfer,
+              derivers: &Derivers,
+              source: &ObjectKey,
+              name: &str)
+              -> Result<Derived> {
+    if name.is_empty() || name.contains('/') || name.starts_with('.') {
+        bail!("Bad derivation name: {:?}", name);
+    }
+    if let Some(key) = fs_transfer.derived(source, name) {
+        return Ok(Derived {
+            key: key,
+            cached: true,
+        });
+    }
+
+    let node: ObjectWalkNode = fs_transfer.lookup_node(*source)?;
+    if node.object_type.is_treeish() {
+        bail!("{} is a {:?}, but only files can be derived from",
+              source,
+              node.object_type);
+    }
+    let media_type = fs_transfer.detect_media_type(source)?;
+    let program = derivers.find(name, media_type)
+        .ok_or_else(|| {
+            ErrorKind::NoDeriver(name.to_owned(), media_type.to_string())
+        })?;
+
+    let input = fs_transfer.path().join("derive-input");
+    let output = fs_transfer.path().join("derive-output");
+    let result = fs::File::create(&input)
+        .map_err(|e| e.into())
+        .and_then(|mut file| fs_transfer.copy_blob_content(source, &mut file))
+        .and_then(|_| {
+            derivers.run(&program, name, source, media_type, &input, &output)
+        })
+        .and_then(|_| {
+            let reader = BufReader::new(fs::File::open(&output)?);
+            fs_transfer.hash_reader(reader, name)
+        });
+    fs::remove_file(&input).ok();
+    fs::remove_file(&output).ok();
+    let key = result?;
+
+    fs_transfer.record_derived(*source, name, key)?;
+    Ok(Derived {
+        key: key,
+        cached: false,
+    })
+}
+
+
+#[cfg(test)]
+mod test {
+    use dag::Blob;
+    use fs_transfer::FsTransfer;
+    use object_store::test::create_temp_repository;
+    use super::*;
+
+    #[cfg(unix)]
+    fn write_deriver(dir: &Path, name: &str, file_name: &str, script: &str) {
+        use std::io::Write;
+        use std::os::unix::fs::PermissionsExt;
+        let dir = dir.join(name);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(file_name);
+        let mut file = fs::File::create(&path).unwrap();
+        writeln!(file, "#!/bin/sh\n{}", script).unwrap();
+        drop(file);
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[cfg(unix)]
+    fn content(fs_transfer: &FsTransfer, key: &ObjectKey) -> Vec<u8> {
+        let mut content = Vec::new();
+        fs_transfer.copy_blob_content(key, &mut content).unwrap();
+        content
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_derive() {
+        let (temp, store) = create_temp_repository().unwrap();
+        let mut fs_transfer = FsTransfer::with_object_store(store);
+        let dir = temp.path().join("derivers");
+        let derivers = Derivers::new(dir.clone())
+            .with_timeout(Duration::from_millis(500));
+        let text = fs_transfer.store_object(&Blob::from("Hello!")).unwrap();
+        let jpeg = fs_transfer.store_object(&Blob::from(b"\xff\xd8\xff\xe0"
+                .to_vec()))
+            .unwrap();
+
+        match derive(&mut fs_transfer, &derivers, &text, "upper") {
+            Err(Error(ErrorKind::NoDeriver(..), _)) => (),
+            other => panic!("Expected NoDeriver, got {:?}", other),
+        }
+
+        write_deriver(&dir, "upper", "default", "tr a-z A-Z");
+        write_deriver(&dir, "upper", "image", "echo image");
+        let derived = derive(&mut fs_transfer, &derivers, &text, "upper")
+            .unwrap();
+        assert!(!derived.cached);
+        assert_eq!(content(&fs_transfer, &derived.key), b"HELLO!");
+        assert_eq!(fs_transfer.derived(&text, "upper"), Some(derived.key));
+
+        // Found by source hash, without running the deriver again
+        write_deriver(&dir, "upper", "default", "exit 1");
+        let again = derive(&mut fs_transfer, &derivers, &text, "upper")
+            .unwrap();
+        assert_eq!(again,
+                   Derived {
+                       key: derived.key,
+                       cached: true,
+                   });
+
+        // Each type uses its own deriver, and failures are reported
+        let derived = derive(&mut fs_transfer, &derivers, &jpeg, "upper")
+            .unwrap();
+        assert_eq!(content(&fs_transfer, &derived.key), b"image\n");
+        write_deriver(&dir, "slow", "default", "sleep 5");
+        match derive(&mut fs_transfer, &derivers, &text, "slow") {
+            Err(Error(ErrorKind::DeriveFailed(..), _)) => (),
+            other => panic!("Expected DeriveFailed, got {:?}", other),
+        }
+    }
+}
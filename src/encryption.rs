@@ -0,0 +1,326 @@
+//! Encryption of objects at rest
+//!
+//! An encrypted repository has a random repository key, which is stored in
+//! the `key` file wrapped (encrypted) by a key derived from a passphrase with
+//! scrypt. The repository key encrypts object files as they are written, and
+//! they are decrypted transparently as they are read, so everything above the
+//! object store, including transfers to other repositories, sees plaintext.
+//!
+//! A sealed object file is the marker `encr`, a 16-byte IV, the object file
+//! encrypted with AES-256 in CTR mode, and an HMAC-SHA256 tag over everything
+//! before it. The marker can't be mistaken for an object type, so plaintext
+//! and sealed objects can live side by side.
+//!
+//! The IV of an object is derived from its hash, so the same object always
+//! seals to the same bytes. This leaks nothing new, since object files are
+//! already named by the hash of their plaintext. It does mean that someone
+//! who can read the store can check whether it holds a file they already
+//! have, so hide the store if that matters.
+
+use config::EncryptionScope;
+use crypto::aes;
+use crypto::aes::KeySize;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::scrypt;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+use dag::ObjectKey;
+use dag::ObjectType;
+use error::*;
+use rustc_serialize::hex::FromHex;
+use rustc_serialize::hex::ToHex;
+use std::env;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+
+/// Marker at the start of a sealed object file
+pub const SEALED_MARKER: &'static [u8] = b"encr";
+
+/// Environment variable to take the passphrase from, instead of prompting
+pub const PASSPHRASE_VAR: &'static str = "DMV_PASSPHRASE";
+
+const IV_SIZE: usize = 16;
+const TAG_SIZE: usize = 32;
+const KEY_FILE_VERSION: u32 = 1;
+
+/// scrypt cost, as log2 of N (2^15 takes a fraction of a second, 32 MiB)
+#[cfg(not(test))]
+const KDF_LOG_N: u8 = 15;
+/// Cheap key derivation, so tests don't spend their time in scrypt
+#[cfg(test)]
+const KDF_LOG_N: u8 = 4;
+const KDF_R: u32 = 8;
+const KDF_P: u32 = 1;
+
+impl EncryptionScope {
+    /// Are objects of this type encrypted?
+    pub fn covers(&self, object_type: ObjectType) -> bool {
+        match (*self, object_type) {
+            (EncryptionScope::All, _) => true,
+            (EncryptionScope::Content, ObjectType::Blob) |
+            (EncryptionScope::Content, ObjectType::ChunkedBlob) => true,
+            (EncryptionScope::Content, _) => false,
+        }
+    }
+}
+
+/// Is this (the start of) a sealed object file?
+pub fn is_sealed(data: &[u8]) -> bool { data.starts_with(SEALED_MARKER) }
+
+/// A pair of keys for encryption and authentication
+#[derive(Clone)]
+pub struct RepoKey {
+    cipher: [u8; 32],
+    mac: [u8; 32],
+}
+
+impl RepoKey {
+    /// Generate a new random key
+    pub fn generate() -> Result<Self> {
+        let mut bytes = [0u8; 64];
+        random_bytes(&mut bytes)?;
+        Ok(Self::from_bytes(&bytes))
+    }
+
+    fn from_bytes(bytes: &[u8; 64]) -> Self {
+        let mut key = RepoKey {
+            cipher: [0u8; 32],
+            mac: [0u8; 32],
+        };
+        key.cipher.copy_from_slice(&bytes[..32]);
+        key.mac.copy_from_slice(&bytes[32..]);
+        key
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        self.cipher.iter().chain(self.mac.iter()).cloned().collect()
+    }
+
+    fn hmac(&self) -> Hmac<Sha256> { Hmac::new(Sha256::new(), &self.mac) }
+
+    /// Encrypt a raw object file, with an IV derived from its hash
+    pub fn seal_object(&self, key: &ObjectKey, raw: &[u8]) -> Vec<u8> {
+        let mut hmac = self.hmac();
+        hmac.input(b"iv ");
+        hmac.input(key.to_hex().as_bytes());
+        let iv = hmac.result().code()[..IV_SIZE].to_vec();
+        self.seal_with_iv(&iv, raw)
+    }
+
+    /// Encrypt other data kept beside the objects, with a random IV
+    pub fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let mut iv = [0u8; IV_SIZE];
+        random_bytes(&mut iv)?;
+        Ok(self.seal_with_iv(&iv, plaintext))
+    }
+
+    fn seal_with_iv(&self, iv: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let mut sealed = Vec::with_capacity(SEALED_MARKER.len() + IV_SIZE +
+                                            plaintext.len() +
+                                            TAG_SIZE);
+        sealed.extend_from_slice(SEALED_MARKER);
+        sealed.extend_from_slice(iv);
+        let start = sealed.len();
+        sealed.resize(start + plaintext.len(), 0);
+        aes::ctr(KeySize::KeySize256, &self.cipher, iv)
+            .process(plaintext, &mut sealed[start..]);
+        let mut hmac = self.hmac();
+        hmac.input(&sealed);
+        sealed.extend_from_slice(hmac.result().code());
+        sealed
+    }
+
+    /// Check and decrypt a sealed file
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        let header_size = SEALED_MARKER.len() + IV_SIZE;
+        if !is_sealed(sealed) || sealed.len() < header_size + TAG_SIZE {
+            bail!("Not a sealed file");
+        }
+        let (body, tag) = sealed.split_at(sealed.len() - TAG_SIZE);
+        let mut hmac = self.hmac();
+        hmac.input(body);
+        if !fixed_time_eq(hmac.result().code(), tag) {
+            bail!("Encrypted data is corrupt, or was sealed with another key");
+        }
+        let iv = &body[SEALED_MARKER.len()..header_size];
+        let ciphertext = &body[header_size..];
+        let mut plaintext = vec![0u8; ciphertext.len()];
+        aes::ctr(KeySize::KeySize256, &self.cipher, iv)
+            .process(ciphertext, &mut plaintext);
+        Ok(plaintext)
+    }
+}
+
+/// The repository key, wrapped by a passphrase, as stored on disk
+#[derive(Debug,Clone,PartialEq,Eq,Hash,RustcEncodable,RustcDecodable)]
+pub struct KeyFile {
+    pub version: u32,
+    pub kdf_log_n: u8,
+    pub kdf_r: u32,
+    pub kdf_p: u32,
+    /// scrypt salt, in hex
+    pub salt: String,
+    /// Sealed repository key, in hex
+    pub wrapped: String,
+}
+
+impl KeyFile {
+    /// Wrap a repository key with a passphrase
+    pub fn wrap(key: &RepoKey, passphrase: &str) -> Result<Self> {
+        let mut salt = [0u8; 16];
+        random_bytes(&mut salt)?;
+        let mut iv = [0u8; IV_SIZE];
+        random_bytes(&mut iv)?;
+        let mut key_file = KeyFile {
+            version: KEY_FILE_VERSION,
+            kdf_log_n: KDF_LOG_N,
+            kdf_r: KDF_R,
+            kdf_p: KDF_P,
+            salt: salt.to_hex(),
+            wrapped: String::new(),
+        };
+        let wrapping = key_file.derive(passphrase)?;
+        key_file.wrapped = wrapping.seal_with_iv(&iv, &key.to_bytes()).to_hex();
+        Ok(key_file)
+    }
+
+    /// Unwrap the repository key with a passphrase
+    pub fn unwrap(&self, passphrase: &str) -> Result<RepoKey> {
+        if self.version != KEY_FILE_VERSION {
+            bail!("Unknown key file version {}", self.version);
+        }
+        let wrapped = self.wrapped
+            .from_hex()
+            .chain_err(|| "Bad wrapped key in key file")?;
+        let bytes = self.derive(passphrase)?
+            .open(&wrapped)
+            .chain_err(|| ErrorKind::WrongPassphrase)?;
+        if bytes.len() != 64 {
+            bail!("Bad wrapped key in key file");
+        }
+        let mut array = [0u8; 64];
+        array.copy_from_slice(&bytes);
+        Ok(RepoKey::from_bytes(&array))
+    }
+
+    /// Derive the key that wraps the repository key
+    fn derive(&self, passphrase: &str) -> Result<RepoKey> {
+        let salt = self.salt.from_hex().chain_err(|| "Bad salt in key file")?;
+        let params =
+            scrypt::ScryptParams::new(self.kdf_log_n, self.kdf_r, self.kdf_p);
+        let mut derived = [0u8; 64];
+        scrypt::scrypt(passphrase.as_bytes(), &salt, &params, &mut derived);
+        Ok(RepoKey::from_bytes(&derived))
+    }
+}
+
+/// Fill a buffer from the operating system's random number generator
+#[cfg(unix)]
+pub fn random_bytes(buf: &mut [u8]) -> Result<()> {
+    fs::File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(buf))
+        .chain_err(|| "Could not read random bytes from /dev/urandom")
+}
+
+#[cfg(not(unix))]
+pub fn random_bytes(_buf: &mut [u8]) -> Result<()> {
+    bail!("Encryption is only supported on Unix-like systems")
+}
+
+/// Get the passphrase from the environment, or by prompting on the terminal
+pub fn read_passphrase(prompt: &str) -> Result<String> {
+    if let Ok(passphrase) = env::var(PASSPHRASE_VAR) {
+        return Ok(passphrase);
+    }
+    prompt_passphrase(prompt).chain_err(|| {
+        format!("Could not ask for a passphrase. Set {} instead",
+                PASSPHRASE_VAR)
+    })
+}
+
+/// Get a new passphrase, prompting twice to catch typos
+pub fn read_new_passphrase() -> Result<String> {
+    if let Ok(passphrase) = env::var(PASSPHRASE_VAR) {
+        return Ok(passphrase);
+    }
+    let passphrase = read_passphrase("New passphrase: ")?;
+    if passphrase.is_empty() {
+        bail!("Passphrase must not be empty");
+    }
+    if read_passphrase("Repeat passphrase: ")? != passphrase {
+        bail!("Passphrases do not match");
+    }
+    Ok(passphrase)
+}
+
+/// Prompt on the terminal, with echo turned off by `stty`
+#[cfg(unix)]
+fn prompt_passphrase(prompt: &str) -> Result<String> {
+    use std::process::Command;
+
+    let stty = |setting: &str| -> Result<()> {
+        let tty = fs::File::open("/dev/tty")?;
+        Command::new("stty").arg(setting).stdin(tty).status()?;
+        Ok(())
+    };
+    let mut tty = io::BufReader::new(fs::File::open("/dev/tty")?);
+    write!(io::stderr(), "{}", prompt)?;
+    stty("-echo")?;
+    let mut line = String::new();
+    let result = io::BufRead::read_line(&mut tty, &mut line);
+    stty("echo")?;
+    writeln!(io::stderr())?;
+    result?;
+    Ok(line.trim_end_matches(['\n', '\r']).to_owned())
+}
+
+#[cfg(not(unix))]
+fn prompt_passphrase(_prompt: &str) -> Result<String> {
+    bail!("No terminal to prompt on")
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_seal_and_open() {
+        let key = RepoKey::generate().unwrap();
+        let object =
+            ObjectKey::from("d3486ae9136e7856bc42212385ea797094475802");
+        let raw = b"blob\0\0\0\0\0\0\0\x06Hello!";
+
+        let sealed = key.seal_object(&object, raw);
+        assert!(is_sealed(&sealed));
+        assert!(!sealed.windows(6).any(|w| w == b"Hello!"),
+                "Content should not appear in sealed file");
+        assert_eq!(key.seal_object(&object, raw),
+                   sealed,
+                   "Same object should seal the same way");
+        assert_eq!(key.open(&sealed).unwrap(), raw.to_vec());
+
+        let mut tampered = sealed.clone();
+        tampered[SEALED_MARKER.len() + IV_SIZE] ^= 1;
+        assert!(key.open(&tampered).is_err());
+
+        let other = RepoKey::generate().unwrap();
+        assert!(other.open(&sealed).is_err());
+    }
+
+    #[test]
+    fn test_key_file() {
+        let key = RepoKey::generate().unwrap();
+        let key_file = KeyFile::wrap(&key, "correct horse").unwrap();
+        assert_eq!(key_file.unwrap("correct horse").unwrap().to_bytes(),
+                   key.to_bytes());
+        match key_file.unwrap("battery staple") {
+            Err(Error(ErrorKind::WrongPassphrase, _)) => (),
+            Err(e) => panic!("Wrong error: {}", e),
+            Ok(_) => panic!("Should reject wrong passphrase"),
+        }
+    }
+}
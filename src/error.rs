@@ -37,10 +37,164 @@ error_chain!{
             display("object not found in object store: {}", h)
         }
 
-        RevNameNotFound(r: String) {
+        WrongObjectType(h: ::dag::ObjectKey,
+                        expected: ::dag::ObjectType,
+                        actual: ::dag::ObjectType) {
+            description("object is not of the expected type")
+            display("{} is a {:?}. Expected a {:?}.", h, actual, expected)
+        }
+
+        RefNotFound(r: String) {
             description("ref or hash not found")
             display("ref or hash not found: {}", r)
         }
+
+        NotARepository(start: ::std::path::PathBuf) {
+            description("not in a repository")
+            display("could not find repository directory in \"{}\" or its \
+                     parents",
+                    start.display())
+        }
+
+        CheckoutConflict(paths: Vec<::std::path::PathBuf>) {
+            description("checkout would overwrite local changes")
+            display("checkout would overwrite local changes to:\n    {}\n\
+                     Commit or stash them first, or check out with --force \
+                     to discard them",
+                    paths.iter()
+                        .map(|p| p.display().to_string())
+                        .collect::<Vec<String>>()
+                        .join("\n    "))
+        }
+        MergeConflict(paths: Vec<::std::path::PathBuf>) {
+            description("merge conflict")
+            display("merge conflict, changed on both sides:\n    {}",
+                    paths.iter()
+                        .map(|p| p.display().to_string())
+                        .collect::<Vec<String>>()
+                        .join("\n    "))
+        }
+
+        ContentAbsent(what: String, remotes: Vec<String>) {
+            description("content was left out by a partial fetch")
+            display("content of {} is not in this repository, because it was \
+                     left out by a partial fetch. Fetch it from: {}",
+                    what,
+                    remotes.join(", "))
+        }
+
+        WrongPassphrase {
+            description("wrong passphrase")
+            display("wrong passphrase for the repository key")
+        }
+
+        HookFailed(hook: String, status: String) {
+            description("hook failed")
+            display("{} hook failed: {}", hook, status)
+        }
+
+        NoDeriver(name: String, media_type: String) {
+            description("no program to derive from this type of file")
+            display("no {} deriver for {} files", name, media_type)
+        }
+
+        DeriveFailed(name: String, status: String) {
+            description("deriver failed")
+            display("{} deriver failed: {}", name, status)
+        }
+
+        SizePolicyRefused(violations: Vec<String>) {
+            description("commit is over the repository's size limits")
+            display("commit refused by the size policy:\n    {}\nCommit with \
+                     --force to commit anyway",
+                    violations.join("\n    "))
+        }
+
+        UnsavedChanges(path: String, count: usize) {
+            description("files have content that is not committed")
+            display("{} has content that is not committed{}. Commit first, \
+                     or force",
+                    path,
+                    match *count {
+                        1 => String::new(),
+                        n => format!(" (as do {} other paths)", n - 1),
+                    })
+        }
+
+        CorruptTransfer(expected: ::dag::ObjectKey,
+                        actual: ::dag::ObjectKey,
+                        quarantine: ::std::path::PathBuf) {
+            description("received object does not match its hash")
+            display("received object {} does not match its hash (got {}), \
+                     quarantined at {}",
+                    expected,
+                    actual,
+                    quarantine.display())
+        }
+
+        CorruptObject(expected: ::dag::ObjectKey,
+                      actual: ::dag::ObjectKey,
+                      path: ::std::path::PathBuf) {
+            description("stored object does not match its hash")
+            display("stored object {} does not match its hash (got {}): {}",
+                    expected,
+                    actual,
+                    path.display())
+        }
+
+        ChunkedBlobSizeMismatch(recorded: ::dag::ObjectSize,
+                                actual: ::dag::ObjectSize) {
+            description("chunked blob size does not match its chunks")
+            display("chunked blob records a total size of {} bytes, but its \
+                     chunks add up to {} bytes",
+                    recorded,
+                    actual)
+        }
+
+        ChunkOffsetMismatch(index: usize,
+                            recorded: ::dag::ObjectSize,
+                            expected: ::dag::ObjectSize) {
+            description("chunked blob offset does not match its chunks")
+            display("chunk {} of chunked blob records offset {}, but the \
+                     chunks before it add up to {}",
+                    index,
+                    recorded,
+                    expected)
+        }
+
+        UnrepresentablePaths(problems: Vec<String>) {
+            description("tree has paths that this filesystem cannot hold")
+            display("refusing to extract, because some paths cannot be \
+                     written to this filesystem without clobbering or \
+                     mangling files:\n    {}",
+                    problems.join("\n    "))
+        }
+
+        RepoLocked(holder: String, lock_file: ::std::path::PathBuf) {
+            description("repository is locked by another command")
+            display("repository is locked by {}. If no other dmv command is \
+                     running, remove {}",
+                    holder,
+                    lock_file.display())
+        }
+    }
+}
+
+
+impl Error {
+    /// The innermost error of this crate's own kinds, under any context added
+    /// by `chain_err`
+    ///
+    /// Use this to handle an error by its kind, even when a caller has
+    /// wrapped it with a message.
+    pub fn root_kind(&self) -> &ErrorKind {
+        let mut kind = &self.0;
+        let mut next = self.1.next_error.as_ref();
+        while let Some(inner) = next.and_then(|e| e.downcast_ref::<Error>()) {
+            kind = &inner.0;
+            next = inner.1.next_error.as_ref();
+        }
+        kind
     }
 }
 
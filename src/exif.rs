@@ -0,0 +1,439 @@
+//! Extraction of searchable metadata, such as EXIF tags, from media files
+//!
+//! Only the few tags that are worth searching on are kept: when a photo was
+//! taken, with what camera, and where. They are stored as a small text object
+//! linked from the tree entry of the file, so they can be read without the
+//! file's content.
+
+use byteorder::BigEndian;
+use byteorder::ByteOrder;
+use byteorder::LittleEndian;
+use error::*;
+use media_type::MediaType;
+use media_type::SNIFF_SIZE;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// Bytes read from the start of a file to find its metadata
+///
+/// EXIF data is in a JPEG's APP1 segment, which cannot be larger than 64 KiB
+/// and comes before the image data.
+pub const METADATA_SCAN_SIZE: u64 = 128 * 1024;
+
+/// Metadata extracted from a media file
+#[derive(Debug,Clone,PartialEq,Default)]
+pub struct MediaMetadata {
+    /// As recorded by the camera, such as `2019:07:14 10:30:00`
+    pub date_taken: Option<String>,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    /// Latitude and longitude in degrees, negative for south and west
+    pub gps: Option<(f64, f64)>,
+}
+
+impl MediaMetadata {
+    pub fn is_empty(&self) -> bool { self == &MediaMetadata::default() }
+
+    /// A field by its name in the text form, such as `camera-model`
+    pub fn field(&self, name: &str) -> Option<String> {
+        match name {
+            "date-taken" => self.date_taken.clone(),
+            "camera-make" => self.camera_make.clone(),
+            "camera-model" => self.camera_model.clone(),
+            "gps" => {
+                self.gps.map(|(lat, lon)| format!("{:.6} {:.6}", lat, lon))
+            }
+            _ => None,
+        }
+    }
+
+    /// Extract metadata from the start of a file's content
+    pub fn extract(head: &[u8]) -> Option<Self> {
+        let metadata = match MediaType::sniff(head) {
+            MediaType::Jpeg => read_jpeg_exif(head),
+            _ => None,
+        };
+        metadata.and_then(|m| if m.is_empty() { None } else { Some(m) })
+    }
+
+    /// Extract metadata from a file, if it is of a type that has any
+    pub fn read_file(path: &Path) -> Result<Option<Self>> {
+        let mut file = File::open(path)?;
+        let mut head = Vec::new();
+        (&mut file).take(SNIFF_SIZE).read_to_end(&mut head)?;
+        if MediaType::sniff(&head) != MediaType::Jpeg {
+            return Ok(None);
+        }
+        file.take(METADATA_SCAN_SIZE - SNIFF_SIZE).read_to_end(&mut head)?;
+        Ok(MediaMetadata::extract(&head))
+    }
+
+    /// Parse the text form written by `Display`
+    ///
+    /// Unknown fields are skipped, so that newer versions can add fields.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut metadata = MediaMetadata::default();
+        for line in text.lines() {
+            let mut split = line.splitn(2, ": ");
+            let (field, value) = match (split.next(), split.next()) {
+                (Some(field), Some(value)) => (field, value.to_owned()),
+                _ => bail!("Bad metadata line: {}", line),
+            };
+            match field {
+                "date-taken" => metadata.date_taken = Some(value),
+                "camera-make" => metadata.camera_make = Some(value),
+                "camera-model" => metadata.camera_model = Some(value),
+                "gps" => {
+                    let coords = value.split(' ')
+                        .map(|c| c.parse::<f64>())
+                        .collect::<::std::result::Result<Vec<_>, _>>()
+                        .chain_err(|| format!("Bad GPS position: {}", value))?;
+                    match coords.as_slice() {
+                        &[lat, lon] => metadata.gps = Some((lat, lon)),
+                        _ => bail!("Bad GPS position: {}", value),
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(metadata)
+    }
+}
+
+impl fmt::Display for MediaMetadata {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref date) = self.date_taken {
+            writeln!(f, "date-taken: {}", date)?;
+        }
+        if let Some(ref make) = self.camera_make {
+            writeln!(f, "camera-make: {}", make)?;
+        }
+        if let Some(ref model) = self.camera_model {
+            writeln!(f, "camera-model: {}", model)?;
+        }
+        if let Some(gps) = self.field("gps") {
+            writeln!(f, "gps: {}", gps)?;
+        }
+        Ok(())
+    }
+}
+
+/// Find the EXIF segment of a JPEG and read its tags
+fn read_jpeg_exif(data: &[u8]) -> Option<MediaMetadata> {
+    let mut pos = 2; // Past the start-of-image marker
+    while pos + 4 <= data.len() {
+        if data[pos] != 0xff {
+            return None;
+        }
+        match data[pos + 1] {
+            0xff => {
+                // Fill byte before a marker
+                pos += 1;
+                continue;
+            }
+            0xda | 0xd9 => return None, // Image data or end of image
+            _ => {}
+        }
+        let len = BigEndian::read_u16(&data[pos + 2..]) as usize;
+        if len < 2 {
+            return None;
+        }
+        let segment = data.get(pos + 4..pos + 2 + len)?;
+        if data[pos + 1] == 0xe1 && segment.starts_with(b"Exif\0\0") {
+            return Tiff::new(&segment[6..]).map(|tiff| tiff.metadata());
+        }
+        pos += 2 + len;
+    }
+    None
+}
+
+const TAG_MAKE: u16 = 0x010f;
+const TAG_MODEL: u16 = 0x0110;
+const TAG_DATE_TIME: u16 = 0x0132;
+const TAG_EXIF_IFD: u16 = 0x8769;
+const TAG_GPS_IFD: u16 = 0x8825;
+const TAG_DATE_TIME_ORIGINAL: u16 = 0x9003;
+const TAG_GPS_LATITUDE_REF: u16 = 1;
+const TAG_GPS_LATITUDE: u16 = 2;
+const TAG_GPS_LONGITUDE_REF: u16 = 3;
+const TAG_GPS_LONGITUDE: u16 = 4;
+
+const TYPE_ASCII: u16 = 2;
+const TYPE_LONG: u16 = 4;
+const TYPE_RATIONAL: u16 = 5;
+
+/// One tag in an image file directory
+#[derive(Debug,Clone,Copy)]
+struct IfdEntry {
+    tag: u16,
+    kind: u16,
+    count: usize,
+    /// Position of the entry's value, or of the offset to its value
+    pos: usize,
+}
+
+/// The TIFF structure that holds EXIF tags
+struct Tiff<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    fn new(data: &'a [u8]) -> Option<Self> {
+        let big_endian = match data.get(0..2)? {
+            b"MM" => true,
+            b"II" => false,
+            _ => return None,
+        };
+        let tiff = Tiff {
+            data: data,
+            big_endian: big_endian,
+        };
+        match tiff.u16(2)? {
+            42 => Some(tiff),
+            _ => None,
+        }
+    }
+
+    fn u16(&self, at: usize) -> Option<u16> {
+        let bytes = self.data.get(at..at + 2)?;
+        Some(match self.big_endian {
+            true => BigEndian::read_u16(bytes),
+            false => LittleEndian::read_u16(bytes),
+        })
+    }
+
+    fn u32(&self, at: usize) -> Option<u32> {
+        let bytes = self.data.get(at..at + 4)?;
+        Some(match self.big_endian {
+            true => BigEndian::read_u32(bytes),
+            false => LittleEndian::read_u32(bytes),
+        })
+    }
+
+    /// Entries of the directory at the given offset
+    fn entries(&self, offset: usize) -> Vec<IfdEntry> {
+        let count = self.u16(offset).unwrap_or(0) as usize;
+        (0..count)
+            .map(|i| offset + 2 + i * 12)
+            .filter_map(|at| {
+                Some(IfdEntry {
+                    tag: self.u16(at)?,
+                    kind: self.u16(at + 2)?,
+                    count: self.u32(at + 4)? as usize,
+                    pos: at + 8,
+                })
+            })
+            .collect()
+    }
+
+    /// Position of an entry's value, which is inline if it fits
+    fn value_pos(&self, entry: &IfdEntry, item_size: usize) -> Option<usize> {
+        match entry.count.checked_mul(item_size)? {
+            0...4 => Some(entry.pos),
+            _ => self.u32(entry.pos).map(|offset| offset as usize),
+        }
+    }
+
+    fn ascii(&self, entry: &IfdEntry) -> Option<String> {
+        if entry.kind != TYPE_ASCII {
+            return None;
+        }
+        let pos = self.value_pos(entry, 1)?;
+        let bytes = self.data.get(pos..pos.checked_add(entry.count)?)?;
+        let text = String::from_utf8_lossy(bytes);
+        let text = text.trim_right_matches('\0').trim();
+        match text.is_empty() {
+            true => None,
+            false => Some(text.to_owned()),
+        }
+    }
+
+    fn offset(&self, entry: &IfdEntry) -> Option<usize> {
+        match (entry.kind, entry.count) {
+            (TYPE_LONG, 1) => self.u32(entry.pos).map(|o| o as usize),
+            _ => None,
+        }
+    }
+
+    /// Degrees, minutes, and seconds, as degrees
+    fn degrees(&self, entry: &IfdEntry) -> Option<f64> {
+        if entry.kind != TYPE_RATIONAL || entry.count != 3 {
+            return None;
+        }
+        let pos = self.value_pos(entry, 8)?;
+        let mut degrees = 0.0;
+        for (i, scale) in [1.0, 60.0, 3600.0].iter().enumerate() {
+            let numerator = self.u32(pos + i * 8)? as f64;
+            let denominator = self.u32(pos + i * 8 + 4)? as f64;
+            if denominator == 0.0 {
+                return None;
+            }
+            degrees += numerator / denominator / scale;
+        }
+        Some(degrees)
+    }
+
+    fn metadata(&self) -> MediaMetadata {
+        let mut metadata = MediaMetadata::default();
+        let mut date_time = None;
+        let (mut exif_ifd, mut gps_ifd) = (None, None);
+        let ifd0 = self.u32(4).unwrap_or(0) as usize;
+        for entry in self.entries(ifd0) {
+            match entry.tag {
+                TAG_MAKE => metadata.camera_make = self.ascii(&entry),
+                TAG_MODEL => metadata.camera_model = self.ascii(&entry),
+                TAG_DATE_TIME => date_time = self.ascii(&entry),
+                TAG_EXIF_IFD => exif_ifd = self.offset(&entry),
+                TAG_GPS_IFD => gps_ifd = self.offset(&entry),
+                _ => {}
+            }
+        }
+        if let Some(exif_ifd) = exif_ifd {
+            metadata.date_taken = self.entries(exif_ifd)
+                .iter()
+                .find(|e| e.tag == TAG_DATE_TIME_ORIGINAL)
+                .and_then(|e| self.ascii(e));
+        }
+        metadata.date_taken = metadata.date_taken.or(date_time);
+        if let Some(gps_ifd) = gps_ifd {
+            metadata.gps = self.gps(gps_ifd);
+        }
+        metadata
+    }
+
+    fn gps(&self, offset: usize) -> Option<(f64, f64)> {
+        let (mut lat, mut lon) = (None, None);
+        let (mut lat_sign, mut lon_sign) = (1.0, 1.0);
+        for entry in self.entries(offset) {
+            match entry.tag {
+                TAG_GPS_LATITUDE => lat = self.degrees(&entry),
+                TAG_GPS_LONGITUDE => lon = self.degrees(&entry),
+                TAG_GPS_LATITUDE_REF if self.ascii(&entry) ==
+                                        Some("S".to_owned()) => {
+                    lat_sign = -1.0
+                }
+                TAG_GPS_LONGITUDE_REF if self.ascii(&entry) ==
+                                         Some("W".to_owned()) => {
+                    lon_sign = -1.0
+                }
+                _ => {}
+            }
+        }
+        Some((lat? * lat_sign, lon? * lon_sign))
+    }
+}
+
+
+#[cfg(test)]
+pub mod test {
+    use byteorder::LittleEndian;
+    use byteorder::WriteBytesExt;
+    use super::*;
+
+    type Entry = (u16, u16, u32, Vec<u8>);
+
+    /// Write a little-endian image file directory that starts at `start`
+    fn ifd(start: usize, entries: &[Entry]) -> Vec<u8> {
+        let data_start = start + 2 + entries.len() * 12 + 4;
+        let mut out = Vec::new();
+        let mut data: Vec<u8> = Vec::new();
+        out.write_u16::<LittleEndian>(entries.len() as u16).unwrap();
+        for &(tag, kind, count, ref value) in entries {
+            out.write_u16::<LittleEndian>(tag).unwrap();
+            out.write_u16::<LittleEndian>(kind).unwrap();
+            out.write_u32::<LittleEndian>(count).unwrap();
+            if value.len() <= 4 {
+                let mut value = value.clone();
+                value.resize(4, 0);
+                out.extend(value);
+            } else {
+                let offset = data_start + data.len();
+                out.write_u32::<LittleEndian>(offset as u32).unwrap();
+                data.extend(value);
+            }
+        }
+        out.write_u32::<LittleEndian>(0).unwrap();
+        out.extend(data);
+        out
+    }
+
+    fn ascii(tag: u16, text: &str) -> Entry {
+        let mut value = text.as_bytes().to_vec();
+        value.push(0);
+        (tag, TYPE_ASCII, value.len() as u32, value)
+    }
+
+    fn long(tag: u16, n: usize) -> Entry {
+        let mut value = Vec::new();
+        value.write_u32::<LittleEndian>(n as u32).unwrap();
+        (tag, TYPE_LONG, 1, value)
+    }
+
+    fn dms(tag: u16, parts: [(u32, u32); 3]) -> Entry {
+        let mut value = Vec::new();
+        for &(numerator, denominator) in &parts {
+            value.write_u32::<LittleEndian>(numerator).unwrap();
+            value.write_u32::<LittleEndian>(denominator).unwrap();
+        }
+        (tag, TYPE_RATIONAL, 3, value)
+    }
+
+    /// A JPEG header with EXIF tags for a photo taken in Oslo
+    pub fn jpeg_with_exif() -> Vec<u8> {
+        let ifd0 = |exif: usize, gps: usize| {
+            ifd(8,
+                &[ascii(TAG_MAKE, "Canon"),
+                  ascii(TAG_MODEL, "Canon EOS 5D"),
+                  ascii(TAG_DATE_TIME, "2019:07:20 08:00:00"),
+                  long(TAG_EXIF_IFD, exif),
+                  long(TAG_GPS_IFD, gps)])
+        };
+        let exif_start = 8 + ifd0(0, 0).len();
+        let exif = ifd(exif_start,
+                       &[ascii(TAG_DATE_TIME_ORIGINAL, "2019:07:14 10:30:00")]);
+        let gps_start = exif_start + exif.len();
+        let lat = [(59, 1), (54, 1), (5004, 100)];
+        let lon = [(10, 1), (45, 1), (792, 100)];
+        let gps = ifd(gps_start,
+                      &[ascii(TAG_GPS_LATITUDE_REF, "N"),
+                        dms(TAG_GPS_LATITUDE, lat),
+                        ascii(TAG_GPS_LONGITUDE_REF, "W"),
+                        dms(TAG_GPS_LONGITUDE, lon)]);
+
+        let mut tiff = b"II\x2a\x00\x08\x00\x00\x00".to_vec();
+        tiff.extend(ifd0(exif_start, gps_start));
+        tiff.extend(exif);
+        tiff.extend(gps);
+
+        let mut jpeg = b"\xff\xd8\xff\xe0\x00\x04\x00\x00\xff\xe1".to_vec();
+        jpeg.write_u16::<BigEndian>((2 + 6 + tiff.len()) as u16).unwrap();
+        jpeg.extend_from_slice(b"Exif\0\0");
+        jpeg.extend(tiff);
+        jpeg.extend_from_slice(b"\xff\xda\x00\x02image data");
+        jpeg
+    }
+
+    #[test]
+    fn test_extract_exif() {
+        let metadata = MediaMetadata::extract(&jpeg_with_exif()).unwrap();
+        assert_eq!(metadata.date_taken,
+                   Some("2019:07:14 10:30:00".to_owned()));
+        assert_eq!(metadata.camera_make, Some("Canon".to_owned()));
+        assert_eq!(metadata.camera_model, Some("Canon EOS 5D".to_owned()));
+        let (lat, lon) = metadata.gps.unwrap();
+        assert!((lat - 59.9139).abs() < 1e-6, "{}", lat);
+        assert!((lon + 10.7522).abs() < 1e-6, "{}", lon);
+
+        let text = metadata.to_string();
+        assert_eq!(text.lines().last(), Some("gps: 59.913900 -10.752200"));
+        assert_eq!(MediaMetadata::parse(&text).unwrap().to_string(), text);
+
+        assert_eq!(MediaMetadata::extract(b"\xff\xd8\xff\xe0\x00\x02"), None);
+        assert_eq!(MediaMetadata::extract(b"Hello world!"), None);
+        // Truncated in the middle of the EXIF segment
+        assert_eq!(MediaMetadata::extract(&jpeg_with_exif()[..40]), None);
+    }
+}
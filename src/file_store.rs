@@ -1,18 +1,32 @@
 //! A filesystem parallel to the object_store, basis of a working directory
 
 use cache::AllCaches;
+use dag::FileMetadata;
+use dag::HashAlgorithm;
+use dag::ObjectCommon;
 use dag::ObjectKey;
+use dag::ObjectSize;
+use dag::ObjectType;
 use error::*;
+use filebuffer::FileBuffer;
+use human_readable::human_bytes;
 use ignore::IgnoreList;
+use media_type::MediaType;
+use media_type::read_typed_objects;
+use messages::Msg;
 use object_store::ObjectStore;
 use progress::*;
-use rolling_hash::read_file_objects;
+use rolling_hash::ChunkParams;
 use status::ComparableNode;
+use std::fmt;
 use std::fs::*;
+use std::io::BufRead;
 use std::io::Cursor;
 use std::io::Write;
 use std::path::Path;
 use std::path::PathBuf;
+use std::time::Duration;
+use std::time::UNIX_EPOCH;
 use walker::*;
 
 /// A path (file or dir) plus metadata, a cached hash if any, and ignore flag
@@ -36,10 +50,49 @@ impl Into<ComparableNode> for FileWalkNode {
     }
 }
 
+/// Counts of chunks that were already stored vs newly stored for a file
+#[derive(Debug,Clone,Copy,Default,PartialEq,Eq)]
+pub struct ChunkReuse {
+    pub reused: usize,
+    pub stored: usize,
+    pub reused_bytes: ObjectSize,
+    pub stored_bytes: ObjectSize,
+}
+
+impl ChunkReuse {
+    fn count(&mut self, already_stored: bool, size: ObjectSize) {
+        if already_stored {
+            self.reused += 1;
+            self.reused_bytes += size;
+        } else {
+            self.stored += 1;
+            self.stored_bytes += size;
+        }
+    }
+}
+
+/// The contents of a mapped file
+///
+/// An empty file is not actually mapped, and its buffer derefs to a slice
+/// made from a null pointer, which must not be used.
+fn file_bytes<'a>(file: &'a FileBuffer, meta: &Metadata) -> &'a [u8] {
+    match meta.len() {
+        0 => &[],
+        _ => file,
+    }
+}
+
 /// Filesystem parallel to the ObjectStore, reads files plus cache/ignore info
 pub struct FileStore {
     pub cache: AllCaches,
     pub ignored: IgnoreList,
+    pub chunk_params: ChunkParams,
+    /// Choose chunking by each file's media type
+    pub typed_chunking: bool,
+    /// Report chunk reuse for large files to the user, not just the log
+    pub verbose: bool,
+    /// Canonical paths that must never be hashed, such as the hidden dir
+    excluded: Vec<PathBuf>,
 }
 
 impl FileStore {
@@ -47,6 +100,49 @@ impl FileStore {
         FileStore {
             cache: AllCaches::new(),
             ignored: IgnoreList::default(),
+            chunk_params: ChunkParams::default(),
+            typed_chunking: false,
+            verbose: false,
+            excluded: Vec::new(),
+        }
+    }
+
+    /// Never walk into or hash the given path, however it is reached
+    ///
+    /// Unlike the ignore list, which matches names, this compares canonical
+    /// paths, so it also catches symlinks that lead into the excluded path.
+    pub fn exclude(&mut self, path: &Path) {
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_owned());
+        self.excluded.push(path);
+    }
+
+    /// Is the path inside an excluded path?
+    ///
+    /// Only directories and symlinks need to be resolved, because any other
+    /// path inside an excluded path is reached through one of those.
+    pub fn is_excluded(&self, path: &Path, meta: &Metadata) -> bool {
+        if self.excluded.is_empty() {
+            return false;
+        }
+        let is_symlink = path.symlink_metadata()
+            .map(|m| m.file_type().is_symlink())
+            .unwrap_or(false);
+        if !meta.is_dir() && !is_symlink {
+            return false;
+        }
+        match path.canonicalize() {
+            Ok(canonical) => {
+                let excluded = self.excluded
+                    .iter()
+                    .any(|ex| canonical.starts_with(ex));
+                if excluded {
+                    debug!("Excluding '{}' (resolves to '{}')",
+                           path.display(),
+                           canonical.display());
+                }
+                excluded
+            }
+            Err(_) => false,
         }
     }
 
@@ -56,13 +152,11 @@ impl FileStore {
                      object_store: &ObjectStore,
                      progress: &ProgressCounter)
                      -> Result<ObjectKey> {
-        use filebuffer::FileBuffer;
         let meta = file_path.metadata()?;
         let file =
             FileBuffer::open(&file_path).chain_err(|| {
                     format!("Could not read {}", file_path.display())
                 })?;
-        let file = ProgressReader::new(Cursor::new(&*file), progress);
 
         if let Ok(Some(hash)) = self.cache.check(file_path, &meta) {
             debug!("Already hashed: {} {}", hash, file_path.display());
@@ -70,17 +164,126 @@ impl FileStore {
         }
         debug!("Hashing {}", file_path.display());
 
+        let last_hash = self.hash_stream(Cursor::new(file_bytes(&file, &meta)),
+                                         &file_path.display(),
+                                         object_store,
+                                         progress)?;
+
+        self.cache
+            .insert(file_path.to_owned(), &meta, last_hash.to_owned())?;
+        progress.add_file();
+
+        Ok(last_hash)
+    }
+
+    /// Move a file or directory, carrying cached hashes to the new path
+    ///
+    /// Renaming changes a file's ctime, which would make its cache entry
+    /// look stale, so an entry that was current before the move is recorded
+    /// again after it. The files in a moved directory keep their entries,
+    /// because the directory's cache file moves with it.
+    pub fn move_path(&mut self, from: &Path, to: &Path) -> Result<()> {
+        let meta = from.symlink_metadata()?;
+        let cached = match meta.is_file() {
+            true => self.cache.check(from, &meta)?,
+            false => None,
+        };
+        // Write out loaded caches first, so none are written to old paths
+        self.cache.flush();
+        rename(from, to).chain_err(|| {
+                format!("Could not move {} to {}", from.display(), to.display())
+            })?;
+        if meta.is_file() {
+            self.cache.remove(from)?;
+        }
+        if let Some(hash) = cached {
+            debug!("Carrying cached hash {} to {}", hash, to.display());
+            self.cache.insert(to.to_owned(), &to.metadata()?, hash)?;
+        }
+        Ok(())
+    }
+
+    /// Remove a file or directory, and its cached hashes
+    pub fn remove_path(&mut self, path: &Path) -> Result<()> {
+        let meta = path.symlink_metadata()?;
+        if meta.is_dir() {
+            self.cache.flush();
+            remove_dir_all(path)?;
+        } else {
+            remove_file(path)?;
+            self.cache.remove(path)?;
+        }
+        Ok(())
+    }
+
+    /// Hash a file from scratch, without checking the cache or storing it
+    pub fn rehash_file(&self,
+                       file_path: &Path,
+                       algorithm: HashAlgorithm)
+                       -> Result<ObjectKey> {
+        let meta = file_path.metadata()?;
+        let file =
+            FileBuffer::open(file_path).chain_err(|| {
+                    format!("Could not read {}", file_path.display())
+                })?;
         let mut last_hash = None;
-        for object in read_file_objects(file) {
+        for object in read_typed_objects(Cursor::new(file_bytes(&file, &meta)),
+                                         &self.chunk_params,
+                                         self.typed_chunking,
+                                         algorithm)? {
+            last_hash = Some(object?.hash().to_owned());
+        }
+        Ok(last_hash.expect("Iterator always emits objects"))
+    }
+
+    /// Store a stream of bytes as a file object and return its hash
+    ///
+    /// The name is only used in messages.
+    pub fn hash_stream<R: BufRead>(&self,
+                                   reader: R,
+                                   name: &fmt::Display,
+                                   object_store: &ObjectStore,
+                                   progress: &ProgressCounter)
+                                   -> Result<ObjectKey> {
+        let file = ProgressReader::new(reader, progress);
+
+        let mut last_hash = None;
+        let mut last_type = None;
+        let mut reuse = ChunkReuse::default();
+        let algorithm = object_store.config().hash_algorithm();
+        let objects = read_typed_objects(file,
+                                         &self.chunk_params,
+                                         self.typed_chunking,
+                                         algorithm)?;
+        let media_type = objects.media_type;
+        for object in objects {
             let object = object?;
+            if object.object_type() == ObjectType::Blob {
+                reuse.count(object_store.has_object(object.hash()),
+                            object.content_size());
+            }
             object_store.store_object(&object)?;
             last_hash = Some(object.hash().to_owned());
+            last_type = Some(object.object_type());
         }
         let last_hash = last_hash.expect("Iterator always emits objects");
+        if media_type != MediaType::Unknown {
+            object_store.record_media_type(last_hash, media_type)?;
+        }
 
-        self.cache
-            .insert(file_path.to_owned(), &meta, last_hash.to_owned())?;
-
+        if last_type == Some(ObjectType::ChunkedBlob) {
+            let message = msg!(Msg::ChunkReuse,
+                               name,
+                               reuse.reused,
+                               reuse.reused + reuse.stored,
+                               reuse.stored,
+                               human_bytes(reuse.stored_bytes));
+            if self.verbose {
+                progress.note(message);
+            } else {
+                info!("{}", message);
+            }
+        }
         Ok(last_hash)
     }
 
@@ -115,9 +318,73 @@ impl FileStore {
         let out_file = out_file.into_inner();
 
         self.cache.insert(path.to_owned(), &out_file.metadata()?, *hash)?;
+        progress.add_file();
 
         Ok(())
     }
+
+    /// Set a file or directory's modification time and permissions
+    ///
+    /// If the path is a file with the given hash, the cache is updated too,
+    /// so that the new modification time does not force a re-hash.
+    pub fn restore_metadata(&self,
+                            path: &Path,
+                            meta: &FileMetadata,
+                            hash: Option<&ObjectKey>)
+                            -> Result<()> {
+        let mtime = UNIX_EPOCH +
+                    Duration::new(meta.mtime_secs, meta.mtime_nanos);
+        File::open(path)
+            .and_then(|file| file.set_modified(mtime))
+            .chain_err(|| {
+                format!("Could not set modification time of {}",
+                        path.display())
+            })?;
+        set_mode(path, meta.mode)?;
+        if let Some(hash) = hash {
+            self.cache.insert(path.to_owned(), &path.metadata()?, *hash)?;
+        }
+        Ok(())
+    }
+}
+
+/// Read the metadata to record for a file or directory
+pub fn file_metadata(path: &Path) -> Result<FileMetadata> {
+    let meta = path.metadata()?;
+    let mtime = meta.modified()?
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::new(0, 0));
+    Ok(FileMetadata {
+        mtime_secs: mtime.as_secs(),
+        mtime_nanos: mtime.subsec_nanos(),
+        mode: get_mode(&meta),
+    })
+}
+
+#[cfg(unix)]
+fn get_mode(meta: &Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn get_mode(meta: &Metadata) -> u32 {
+    if meta.permissions().readonly() { 0o555 } else { 0o755 }
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    set_permissions(path, Permissions::from_mode(mode))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    let mut perms = path.metadata()?.permissions();
+    perms.set_readonly(mode & 0o222 == 0);
+    set_permissions(path, perms)?;
+    Ok(())
 }
 
 
@@ -133,7 +400,8 @@ impl NodeLookup<PathBuf, FileWalkNode> for FileStore {
         let meta = path.metadata()?;
         Ok(FileWalkNode {
             hash: self.cache.check(&path, &meta)?,
-            ignored: self.ignored.ignores(path.as_path()),
+            ignored: self.ignored.ignores(path.as_path()) ||
+                     self.is_excluded(&path, &meta),
             path: path,
             metadata: meta,
         })
@@ -144,14 +412,13 @@ impl NodeReader<FileWalkNode> for FileStore {
     fn read_children(&self,
                      node: &FileWalkNode)
                      -> Result<ChildMap<FileWalkNode>> {
-        let mut children = ChildMap::new();
-        for entry in read_dir(&node.path)? {
-            let entry = entry?;
-            let path = entry.path();
-            let node = self.lookup_node(path.clone())?;
-            children.insert(name_for_path(path.as_path())?, node);
-        }
-        Ok(children)
+        all_children(self.read_children_each(node)?)
+    }
+
+    fn read_children_each(&self,
+                          node: &FileWalkNode)
+                          -> Result<ChildMap<Result<FileWalkNode>>> {
+        self.read_dir_each(&node.path)
     }
 }
 
@@ -168,14 +435,99 @@ impl NodeReader<ComparableNode> for FileStore {
     fn read_children(&self,
                      node: &ComparableNode)
                      -> Result<ChildMap<ComparableNode>> {
-        let mut children = ChildMap::new();
+        all_children(self.read_children_each(node)?)
+    }
+
+    fn read_children_each(&self,
+                          node: &ComparableNode)
+                          -> Result<ChildMap<Result<ComparableNode>>> {
         let fs_path = node.fs_path.as_ref().expect("File should have path");
-        for entry in read_dir(fs_path)? {
-            let entry = entry?;
-            let path = entry.path();
-            let node = self.lookup_node(path.clone())?;
-            children.insert(name_for_path(path.as_path())?, node);
+        self.read_dir_each(fs_path)
+    }
+}
+
+impl FileStore {
+    /// Look up each entry of a directory, keeping any error with its entry
+    fn read_dir_each<N>(&self, dir: &Path) -> Result<ChildMap<Result<N>>>
+        where Self: NodeLookup<PathBuf, N>
+    {
+        let mut children = ChildMap::new();
+        for entry in read_dir(dir)? {
+            let path = entry?.path();
+            let name = path.file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let node = name_for_path(path.as_path())
+                .and_then(|_| self.lookup_node(path));
+            children.insert(name, node);
         }
         Ok(children)
     }
 }
+
+
+#[cfg(test)]
+mod test {
+    use object_store::ObjectWalkNode;
+    use object_store::test::create_temp_repository;
+    use rolling_hash::CHUNK_TARGET_SIZE;
+    use std::io::Write;
+    use super::*;
+    use testutil::TestRand;
+
+    #[test]
+    fn test_hash_file_reports_chunk_reuse() {
+        let (temp, object_store) = create_temp_repository().unwrap();
+        let mut file_store = FileStore::new();
+        file_store.verbose = true;
+        let progress = ProgressCounter::arc("Storing", 0);
+
+        let path = temp.path().join("big");
+        let mut rng = TestRand::default();
+        let content = rng.gen_byte_vec(CHUNK_TARGET_SIZE * 4);
+        File::create(&path).unwrap().write_all(&content).unwrap();
+        file_store.hash_file(&path, &object_store, &*progress).unwrap();
+        let notes = progress.take_notes();
+        assert_eq!(notes.len(), 1);
+        assert!(notes[0].contains(" 0 of "), "{}", notes[0]);
+
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .and_then(|mut f| f.write_all(b"a little more"))
+            .unwrap();
+        file_store.hash_file(&path, &object_store, &*progress).unwrap();
+        let notes = progress.take_notes();
+        assert_eq!(notes.len(), 1);
+        assert!(!notes[0].contains(" 0 of "),
+                "Unchanged chunks should be reused: {}",
+                notes[0]);
+    }
+
+    #[test]
+    fn test_hash_file_by_media_type() {
+        let (temp, object_store) = create_temp_repository().unwrap();
+        let mut file_store = FileStore::new();
+        file_store.typed_chunking = true;
+        let progress = ProgressCounter::arc("Storing", 0);
+        let algorithm = object_store.hash_algorithm();
+
+        let path = temp.path().join("photo.jpg");
+        let mut content = b"\xff\xd8\xff\xe0".to_vec();
+        content.extend(TestRand::default().gen_byte_vec(CHUNK_TARGET_SIZE * 8));
+        File::create(&path).unwrap().write_all(&content).unwrap();
+        let hash = file_store.hash_file(&path, &object_store, &*progress)
+            .unwrap();
+
+        let node: ObjectWalkNode = object_store.lookup_node(hash).unwrap();
+        assert_eq!(node.object_type, ObjectType::Blob);
+        assert_eq!(object_store.media_type(&hash).unwrap(), MediaType::Jpeg);
+        assert_eq!(file_store.rehash_file(&path, algorithm).unwrap(), hash);
+        assert!(object_store.verify_chunking(&hash).unwrap().matches());
+
+        // Without typed chunking, the same content is chunked as usual
+        file_store.typed_chunking = false;
+        let untyped = file_store.rehash_file(&path, algorithm).unwrap();
+        assert!(untyped != hash);
+    }
+}
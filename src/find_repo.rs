@@ -1,15 +1,43 @@
 //! Functionality for finding WorkDir and/or ObjectStore at startup
+//!
+//! Normally the repository is found by looking for the hidden directory in
+//! the current directory and its parents. It can be given explicitly
+//! instead, in order of precedence:
+//!
+//! - With the `--repo` flag, naming the work dir, which is passed here by
+//!   `set_repo_path`
+//! - With the `DMV_DIR` environment variable, naming the hidden directory,
+//!   and `DMV_WORK_TREE`, naming the work dir. Either can be given alone:
+//!   the hidden directory defaults to the one in the work dir, and the work
+//!   dir defaults to the current directory.
 
 use constants::HIDDEN_DIR_NAME;
 use error::*;
 use fs_transfer::FsTransfer;
 use fsutil::up_from;
 use object_store::ObjectStore;
+use std::env;
 use std::env::current_dir;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::RwLock;
 use work_dir::WorkDir;
 
+/// Environment variable giving the hidden directory
+pub const DIR_VAR: &'static str = "DMV_DIR";
+
+/// Environment variable giving the work dir
+pub const WORK_TREE_VAR: &'static str = "DMV_WORK_TREE";
+
+lazy_static!{
+    static ref REPO_PATH: RwLock<Option<PathBuf>> = RwLock::new(None);
+}
+
+/// Use the repository with the given work dir, rather than searching
+pub fn set_repo_path(path: PathBuf) {
+    *REPO_PATH.write().unwrap() = Some(path);
+}
+
 /// A repository layout with a WorkDir and hidden directory
 pub struct RepoLayout {
     /// ObjectStore directory: the hidden directory inside the WorkDir
@@ -27,6 +55,44 @@ impl RepoLayout {
     }
 }
 
+/// The repository layout given by `--repo` or the environment, if any
+///
+/// Relative paths are taken from the current directory. The layout may not
+/// exist yet, as when initializing a new repository.
+pub fn requested_layout() -> Result<Option<RepoLayout>> {
+    let cwd = current_dir()?;
+    if let Some(ref path) = *REPO_PATH.read().unwrap() {
+        return Ok(Some(RepoLayout::in_work_dir(cwd.join(path))));
+    }
+    let osd = env::var_os(DIR_VAR).map(|dir| cwd.join(dir));
+    let wd = env::var_os(WORK_TREE_VAR).map(|dir| cwd.join(dir));
+    Ok(match (osd, wd) {
+        (None, None) => None,
+        (None, Some(wd)) => Some(RepoLayout::in_work_dir(wd)),
+        (Some(osd), wd) => {
+            Some(RepoLayout {
+                osd: osd,
+                wd: wd.unwrap_or(cwd),
+            })
+        }
+    })
+}
+
+/// Find the repository layout, as requested or by searching from the
+/// current directory
+fn current_repo() -> Result<RepoLayout> {
+    match requested_layout()? {
+        Some(layout) => {
+            if !layout.osd.is_dir() {
+                bail!("No repository at {}", layout.osd.display());
+            }
+            debug!("Using repo: {}", layout.osd.display());
+            Ok(layout)
+        }
+        None => find_repo(&current_dir()?),
+    }
+}
+
 /// Find the repository layout
 fn find_repo(start_path: &Path) -> Result<RepoLayout> {
     for path in up_from(&start_path) {
@@ -40,14 +106,18 @@ fn find_repo(start_path: &Path) -> Result<RepoLayout> {
             });
         }
     }
-    bail!("Could not find repository directory in \"{}\" or its parents",
-          start_path.display())
+    bail!(ErrorKind::NotARepository(start_path.to_owned()))
 }
 
 /// Find just an ObjectStore
+///
+/// `HEAD` is read from the work dir's state, for revision lookups.
 pub fn find_object_store() -> Result<ObjectStore> {
-    let start_dir = current_dir()?;
-    find_repo(&start_dir).and_then(|layout| ObjectStore::open(layout.osd))
+    let layout = current_repo()?;
+    let head = WorkDir::read_head(&layout.osd)?;
+    let mut object_store = ObjectStore::open(layout.osd)?;
+    object_store.set_head(head);
+    Ok(object_store)
 }
 
 /// Find ObjectStore and create an FsTransfer around it
@@ -57,6 +127,10 @@ pub fn find_fs_transfer() -> Result<FsTransfer> {
 
 /// Find entire WorkDir
 pub fn find_work_dir() -> Result<WorkDir> {
-    let start_dir = current_dir()?;
-    find_repo(&start_dir).and_then(|layout| WorkDir::open(layout))
+    current_repo().and_then(WorkDir::open)
+}
+
+/// Find entire WorkDir, in the given directory or its parents
+pub fn find_work_dir_from(start_dir: &Path) -> Result<WorkDir> {
+    find_repo(start_dir).and_then(WorkDir::open)
 }
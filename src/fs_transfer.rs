@@ -1,23 +1,40 @@
 //! Functionality for transfering files between filesystem and object store
 
+use constants::CACHE_FILE_NAME;
+use dag::Blob;
+use dag::FileMetadata;
 use dag::ObjectKey;
 use dag::ObjectSize;
+use dag::ObjectType;
 use dag::Tree;
 use error::*;
+use exif::MediaMetadata;
 use file_store::FileStore;
 use file_store::FileWalkNode;
+use file_store::file_metadata;
 use ignore::IgnoreList;
 use object_store::ObjectStore;
 use object_store::ObjectWalkNode;
+use object_store::default_jobs;
+use pathspec::Pathspec;
 use progress::ProgressCounter;
+use progress::ProgressSet;
+use progress::ProgressWriter;
 use progress::std_err_watch;
+use progress::std_err_watch_set;
 use status::*;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::ffi::OsStr;
 use std::fs::create_dir;
 use std::fs::remove_dir_all;
 use std::fs::remove_file;
+use std::io::BufRead;
 use std::io::Write;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::Arc;
 use std::thread;
 use walker::*;
 
@@ -26,6 +43,8 @@ use walker::*;
 pub struct FsTransfer {
     pub object_store: ObjectStore,
     pub file_store: FileStore,
+    /// Which names are safe to extract to the filesystem
+    pub name_rules: NameRules,
 }
 impl_deref_mut!(FsTransfer => ObjectStore, object_store);
 impl FsTransfer {
@@ -33,9 +52,16 @@ impl FsTransfer {
         let mut ignored = IgnoreList::default();
         ignored.insert(object_store.path());
 
+        let mut file_store = FileStore::new();
+        file_store.chunk_params = object_store.config().chunking;
+        file_store.typed_chunking = object_store.config().typed_chunking();
+        file_store.ignored = ignored;
+        file_store.exclude(object_store.path());
+
         FsTransfer {
             object_store: object_store,
-            file_store: FileStore::new(),
+            file_store: file_store,
+            name_rules: NameRules::for_platform(),
         }
     }
 
@@ -44,11 +70,28 @@ impl FsTransfer {
     }
 
     /// Hash by parent object key, path to hash, and estimated hash bytes
+    ///
+    /// Only paths matching the pathspec are hashed. The others keep their
+    /// version from the parent.
     pub fn hash_obj_file(&mut self,
                          parent: Option<ObjectKey>,
-                         path: &Path)
+                         path: &Path,
+                         pathspec: &Pathspec)
                          -> Result<ObjectKey> {
 
+        let hidden_dir = self.object_store.path();
+        let inside_hidden_dir = match (path.canonicalize(),
+                                       hidden_dir.canonicalize()) {
+            (Ok(path), Ok(hidden_dir)) => path.starts_with(hidden_dir),
+            _ => false,
+        };
+        if inside_hidden_dir {
+            bail!("Refusing to hash {}: it is inside the repository's hidden \
+                   directory {}",
+                  path.display(),
+                  hidden_dir.display());
+        }
+
         let parent =
             parent.and_then_try(|hash| self.object_store.lookup_node(hash))?;
         let path = Some(self.file_store.lookup_node(path.to_owned())?);
@@ -56,32 +99,156 @@ impl FsTransfer {
 
         let combo = (&self.object_store, &self.file_store);
 
-        // Estimate
-        let mut op = TransferEstimateOp::new();
-        combo.walk_node(&mut op, node.clone())?;
+        let progress = ProgressSet::arc();
+        let prog_clone = progress.clone();
+        let prog_thread = thread::spawn(move || std_err_watch_set(prog_clone));
+        let result = self.hash_and_store(combo, node, pathspec, &progress);
+        progress.finish();
+        prog_thread.join().unwrap();
+        result
+    }
 
-        // Store
-        let prog = ProgressCounter::arc("Storing", op.estimate());
-        let prog_clone = prog.clone();
+    /// Hash and store a directory that is not part of a work dir
+    ///
+    /// Unlike a commit, which needs something to record, an empty directory
+    /// is stored as an empty tree.
+    pub fn import_dir(&mut self, dir: &Path) -> Result<ObjectKey> {
+        if !dir.is_dir() {
+            bail!("Not a directory: {}", dir.display());
+        }
+        let node = Some(self.file_store.lookup_node(dir.to_owned())?);
+        if is_empty_dir(&node) {
+            return self.object_store.store_object(&Tree::new());
+        }
+        self.hash_obj_file(None, dir, &Pathspec::all())
+    }
 
+    /// Estimate, then hash and store, each as a phase of the progress set
+    fn hash_and_store(&self,
+                      combo: (&ObjectStore, &FileStore),
+                      node: CompareNode,
+                      pathspec: &Pathspec,
+                      progress: &ProgressSet)
+                      -> Result<ObjectKey> {
+        let estimating = progress.phase("Estimating", 0);
+        let mut op = TransferEstimateOp::with_progress(estimating.clone())
+            .limit_to(pathspec.clone());
+        combo.walk_node(&mut op, node.clone())?;
+        estimating.finish();
+
+        let storing = progress.phase("Storing", op.estimate());
         let mut op = HashAndStoreOp {
             fs_transfer: self,
-            progress: &*prog.clone(),
+            progress: &storing,
+            pathspec: pathspec,
+        };
+        let walk_options = WalkOptions {
+            jobs: default_jobs(),
+            ..WalkOptions::default()
         };
+        let hash = combo.walk_node_parallel(&mut op, node, &walk_options)?
+            .result
+            .ok_or_else(|| Error::from("Nothing to hash (all ignored?)"))?;
+        storing.finish();
+        Ok(hash)
+    }
 
+    /// Store a stream of bytes, such as stdin, as a file object
+    ///
+    /// The name is only used in messages.
+    pub fn hash_reader<R: BufRead>(&mut self,
+                                   reader: R,
+                                   name: &str)
+                                   -> Result<ObjectKey> {
+        let prog = ProgressCounter::arc("Storing", 0);
+        let prog_clone = prog.clone();
         let prog_thread = thread::spawn(move || std_err_watch(prog_clone));
 
-        let hash = combo.walk_node(&mut op, node)?
-            .ok_or_else(|| Error::from("Nothing to hash (all ignored?)"))?;
+        let hash = self.file_store
+            .hash_stream(reader, &name, &self.object_store, &prog)?;
 
         prog.finish();
         prog_thread.join().unwrap();
         Ok(hash)
     }
 
+    /// Write the contents of a file object to a stream, such as stdout
+    pub fn extract_to_writer(&self,
+                             hash: &ObjectKey,
+                             writer: &mut Write)
+                             -> Result<()> {
+        let obj: ObjectWalkNode = self.object_store
+            .lookup_node(hash.to_owned())?;
+        if obj.object_type.is_treeish() {
+            let error = ErrorKind::WrongObjectType(*hash,
+                                                   ObjectType::Blob,
+                                                   obj.object_type);
+            return Err(Error::from(error))
+                .chain_err(|| "Only files can be extracted to a stream");
+        }
+
+        let prog = ProgressCounter::arc("Extracting", obj.file_size);
+        let prog_clone = prog.clone();
+        let prog_thread = thread::spawn(move || std_err_watch(prog_clone));
+
+        {
+            let mut writer = ProgressWriter::new(writer, &prog);
+            self.object_store.copy_blob_content(hash, &mut writer)?;
+            writer.flush()?;
+        }
+        prog.add_file();
+
+        prog.finish();
+        prog_thread.join().unwrap();
+        Ok(())
+    }
+
     /// Extract a file or directory from the object store to the filesystem
     pub fn extract_object(&self, hash: &ObjectKey, path: &Path) -> Result<()> {
+        self.check_extractable(hash)?;
+        self.extract_checked(hash, path)
+    }
+
+    /// Check that an object can be extracted, before writing anything
+    ///
+    /// Fails if any names cannot be written on this platform, or if any
+    /// content was left out by a partial fetch.
+    pub fn check_extractable(&self, hash: &ObjectKey) -> Result<()> {
+        let obj: ObjectWalkNode = self.object_store
+            .lookup_node(hash.to_owned())?;
+
+        if obj.object_type.is_treeish() {
+            let mut op = NameCheckOp {
+                rules: self.name_rules,
+                problems: Vec::new(),
+            };
+            self.object_store.walk_node(&mut op, obj)?;
+            if !op.problems.is_empty() {
+                bail!(ErrorKind::UnrepresentablePaths(op.problems));
+            }
+        }
+
+        // Check that no content was left out by a partial fetch
+        if !self.object_store.absent().is_empty() {
+            let missing = self.object_store.missing_content(hash)?;
+            if let Some((first, _)) = missing.first() {
+                let what = match missing.len() {
+                    1 if first.as_os_str().is_empty() => hash.to_string(),
+                    1 => first.display().to_string(),
+                    n => format!("{} files, including {}", n, first.display()),
+                };
+                let remotes = missing.iter()
+                    .flat_map(|(_, remotes)| remotes.iter().cloned())
+                    .collect::<BTreeSet<_>>();
+                bail!(ErrorKind::ContentAbsent(what,
+                                               remotes.into_iter().collect()));
+            }
+        }
+        Ok(())
+    }
 
+    /// Extract an object that `check_extractable` has already checked
+    pub fn extract_checked(&self, hash: &ObjectKey, path: &Path) -> Result<()> {
         let obj: ObjectWalkNode = self.object_store
             .lookup_node(hash.to_owned())?;
         let file: Option<FileWalkNode> =
@@ -105,6 +272,8 @@ impl FsTransfer {
             object_store: &self.object_store,
             extract_root: path,
             progress: &prog,
+            preserve_metadata: self.config().preserve_metadata(),
+            metadata: HashMap::new(),
         };
         let node = (file, Some(obj));
         combo.walk_node(&mut op, node)
@@ -138,63 +307,293 @@ type CompareNode = (Option<ComparableNode>, Option<ComparableNode>);
 
 
 
-pub struct ComparePrintWalkOp<'s> {
-    writer: &'s mut Write,
-    show_ignored: bool,
+/// Reports each changed file between two trees
+///
+/// It can be forked to walk the top-level directories in parallel. Each fork
+/// gathers what it finds, and reports it when joined, so changes are
+/// reported in the same order either way.
+pub struct CompareWalkOp<'s> {
+    sink: &'s mut FnMut(StatusEntry) -> Result<()>,
+    found: CompareCollectOp,
+}
+impl<'s> CompareWalkOp<'s> {
+    pub fn new(sink: &'s mut FnMut(StatusEntry) -> Result<()>,
+               show_ignored: bool)
+               -> Self {
+        CompareWalkOp {
+            sink: sink,
+            found: CompareCollectOp {
+                show_ignored: show_ignored,
+                pathspec: Pathspec::all(),
+                root: None,
+                found: Vec::new(),
+            },
+        }
+    }
+    /// Report only paths matching the pathspec
+    pub fn limit_to(mut self, pathspec: Pathspec) -> Self {
+        self.found.pathspec = pathspec;
+        self
+    }
+    /// Walk the files under this directory, to tell unreadable ones' kind
+    pub fn in_dir(mut self, root: PathBuf) -> Self {
+        self.found.root = Some(root);
+        self
+    }
+    fn report_found(&mut self) -> Result<()> {
+        for entry in self.found.found.drain(..) {
+            (self.sink)(entry)?;
+        }
+        Ok(())
+    }
+}
+impl<'s> WalkOp<CompareNode> for CompareWalkOp<'s> {
+    type VisitResult = ();
+
+    fn should_descend(&mut self, ps: &Path, node: &CompareNode) -> bool {
+        self.found.should_descend(ps, node)
+    }
+    fn no_descend(&mut self,
+                  ps: &Path,
+                  node: CompareNode)
+                  -> Result<Option<Self::VisitResult>> {
+        self.found.no_descend(ps, node)?;
+        self.report_found()?;
+        Ok(None)
+    }
+    fn post_descend(&mut self,
+                    ps: &Path,
+                    node: CompareNode,
+                    children: ChildMap<Self::VisitResult>)
+                    -> Result<Option<Self::VisitResult>> {
+        self.found.post_descend(ps, node, children)?;
+        self.report_found()?;
+        Ok(None)
+    }
+    fn unreadable(&mut self, ps: &Path, error: &Error) -> Result<()> {
+        self.found.unreadable(ps, error)?;
+        self.report_found()
+    }
 }
-impl<'s> ComparePrintWalkOp<'s> {
-    pub fn new(writer: &'s mut Write, show_ignored: bool) -> Self {
-        ComparePrintWalkOp {
-            writer: writer,
-            show_ignored: show_ignored,
+impl<'s> ForkWalkOp<CompareNode> for CompareWalkOp<'s> {
+    type Fork = CompareCollectOp;
+
+    fn fork(&self) -> Self::Fork {
+        CompareCollectOp {
+            show_ignored: self.found.show_ignored,
+            pathspec: self.found.pathspec.clone(),
+            root: self.found.root.clone(),
+            found: Vec::new(),
         }
     }
+    fn join(&mut self, fork: Self::Fork) -> Result<()> {
+        self.found.found.extend(fork.found);
+        self.report_found()
+    }
+}
+
+/// Gathers each changed file between two trees, for `CompareWalkOp`
+pub struct CompareCollectOp {
+    show_ignored: bool,
+    pathspec: Pathspec,
+    root: Option<PathBuf>,
+    found: Vec<StatusEntry>,
+}
+impl CompareCollectOp {
     fn status(&self, node: &CompareNode, _ps: &Path) -> Status {
         ComparableNode::compare_pair(node)
     }
 }
-impl<'s> WalkOp<CompareNode> for ComparePrintWalkOp<'s> {
+impl WalkOp<CompareNode> for CompareCollectOp {
     type VisitResult = ();
 
     fn should_descend(&mut self, ps: &Path, node: &CompareNode) -> bool {
         let targ = node.1.as_ref();
         let is_treeish = targ.map(|n| n.is_treeish).unwrap_or(false);
         let included = self.status(&node, ps).is_included();
-        is_treeish && included
+        is_treeish && included && self.pathspec.matches_within(ps)
     }
     fn no_descend(&mut self,
                   ps: &Path,
                   node: CompareNode)
                   -> Result<Option<Self::VisitResult>> {
+        if !self.pathspec.matches(ps) {
+            return Ok(None);
+        }
         let status = ComparableNode::compare_pair(&node);
         let show = status != Status::Unchanged &&
                    (status != Status::Ignored || self.show_ignored);
-        let mut ps = ps.to_string_lossy();
-        if node.1.map(|n| n.is_treeish).unwrap_or(false) {
-            ps += "/";
-        }
         if show {
-            writeln!(self.writer, "{} {}", status.code(), ps)?;
+            let (src, targ) = node;
+            self.found.push(StatusEntry::from_nodes(ps.to_owned(),
+                                                    vec![status],
+                                                    &[src],
+                                                    targ.as_ref()));
         }
         Ok(None)
     }
+    fn post_descend(&mut self,
+                    ps: &Path,
+                    node: CompareNode,
+                    _children: ChildMap<Self::VisitResult>)
+                    -> Result<Option<Self::VisitResult>> {
+        // A new empty directory has no files to report, so report it instead
+        let status = ComparableNode::compare_pair(&node);
+        if status == Status::Add && is_empty_dir(&node.1) &&
+           self.pathspec.matches(ps) {
+            let (src, targ) = node;
+            self.found.push(StatusEntry::from_nodes(ps.to_owned(),
+                                                    vec![status],
+                                                    &[src],
+                                                    targ.as_ref()));
+        }
+        Ok(None)
+    }
+    fn unreadable(&mut self, ps: &Path, error: &Error) -> Result<()> {
+        if let Some(entry) =
+            unreadable_entry(&self.root, &self.pathspec, ps, error, 1) {
+            self.found.push(entry);
+        }
+        Ok(())
+    }
+}
+
+/// Entry for a path that a status walk could not read, if the pathspec
+/// covers it
+fn unreadable_entry(root: &Option<PathBuf>,
+                    pathspec: &Pathspec,
+                    ps: &Path,
+                    error: &Error,
+                    sources: usize)
+                    -> Option<StatusEntry> {
+    if !pathspec.matches(ps) && !pathspec.matches_within(ps) {
+        return None;
+    }
+    let full_path =
+        root.as_ref().map_or_else(|| ps.to_owned(), |root| root.join(ps));
+    warn!("Could not read {}: {}", full_path.display(), error);
+    let is_dir = root.is_some() && full_path.is_dir();
+    Some(StatusEntry::unreadable(ps.to_owned(), is_dir, sources))
+}
+
+/// Is the node a directory with nothing in it, besides dmv's cache?
+///
+/// Such a directory is stored as an empty tree, so that it is kept. One whose
+/// files are all ignored is not empty, and is dropped.
+fn is_empty_dir(node: &Option<ComparableNode>) -> bool {
+    node.as_ref()
+        .filter(|node| node.is_treeish)
+        .and_then(|node| node.fs_path.as_ref())
+        .and_then(|path| path.read_dir().ok())
+        .is_some_and(|mut entries| {
+            entries.all(|entry| {
+                entry.map(|entry| entry.file_name() == CACHE_FILE_NAME)
+                    .unwrap_or(false)
+            })
+        })
 }
 
 
 
 type MultiCompareNode = (Vec<Option<ComparableNode>>, Option<ComparableNode>);
 
-pub struct MultiComparePrintWalkOp<'s> {
-    writer: &'s mut Write,
-    show_ignored: bool,
+/// Reports each changed file between several trees and one other
+///
+/// Like `CompareWalkOp`, it can be forked to walk the top-level directories
+/// in parallel.
+pub struct MultiCompareWalkOp<'s> {
+    sink: &'s mut FnMut(StatusEntry) -> Result<()>,
+    found: MultiCompareCollectOp,
+}
+impl<'s> MultiCompareWalkOp<'s> {
+    pub fn new(sink: &'s mut FnMut(StatusEntry) -> Result<()>,
+               sources: usize,
+               show_ignored: bool)
+               -> Self {
+        MultiCompareWalkOp {
+            sink: sink,
+            found: MultiCompareCollectOp {
+                show_ignored: show_ignored,
+                pathspec: Pathspec::all(),
+                root: None,
+                sources: sources,
+                found: Vec::new(),
+            },
+        }
+    }
+    /// Report only paths matching the pathspec
+    pub fn limit_to(mut self, pathspec: Pathspec) -> Self {
+        self.found.pathspec = pathspec;
+        self
+    }
+    /// Walk the files under this directory, to tell unreadable ones' kind
+    pub fn in_dir(mut self, root: PathBuf) -> Self {
+        self.found.root = Some(root);
+        self
+    }
+    fn report_found(&mut self) -> Result<()> {
+        for entry in self.found.found.drain(..) {
+            (self.sink)(entry)?;
+        }
+        Ok(())
+    }
 }
-impl<'s> MultiComparePrintWalkOp<'s> {
-    pub fn new(writer: &'s mut Write, show_ignored: bool) -> Self {
-        MultiComparePrintWalkOp {
-            writer: writer,
-            show_ignored: show_ignored,
+impl<'s> WalkOp<MultiCompareNode> for MultiCompareWalkOp<'s> {
+    type VisitResult = ();
+
+    fn should_descend(&mut self, ps: &Path, node: &MultiCompareNode) -> bool {
+        self.found.should_descend(ps, node)
+    }
+    fn no_descend(&mut self,
+                  ps: &Path,
+                  node: MultiCompareNode)
+                  -> Result<Option<Self::VisitResult>> {
+        self.found.no_descend(ps, node)?;
+        self.report_found()?;
+        Ok(None)
+    }
+    fn post_descend(&mut self,
+                    ps: &Path,
+                    node: MultiCompareNode,
+                    children: ChildMap<Self::VisitResult>)
+                    -> Result<Option<Self::VisitResult>> {
+        self.found.post_descend(ps, node, children)?;
+        self.report_found()?;
+        Ok(None)
+    }
+    fn unreadable(&mut self, ps: &Path, error: &Error) -> Result<()> {
+        self.found.unreadable(ps, error)?;
+        self.report_found()
+    }
+}
+impl<'s> ForkWalkOp<MultiCompareNode> for MultiCompareWalkOp<'s> {
+    type Fork = MultiCompareCollectOp;
+
+    fn fork(&self) -> Self::Fork {
+        MultiCompareCollectOp {
+            show_ignored: self.found.show_ignored,
+            pathspec: self.found.pathspec.clone(),
+            root: self.found.root.clone(),
+            sources: self.found.sources,
+            found: Vec::new(),
         }
     }
+    fn join(&mut self, fork: Self::Fork) -> Result<()> {
+        self.found.found.extend(fork.found);
+        self.report_found()
+    }
+}
+
+/// Gathers each changed file between several trees and one other, for
+/// `MultiCompareWalkOp`
+pub struct MultiCompareCollectOp {
+    show_ignored: bool,
+    pathspec: Pathspec,
+    root: Option<PathBuf>,
+    sources: usize,
+    found: Vec<StatusEntry>,
+}
+impl MultiCompareCollectOp {
     fn status(&self, node: &MultiCompareNode, _ps: &Path) -> Vec<Status> {
         node.0
             .iter()
@@ -202,44 +601,100 @@ impl<'s> MultiComparePrintWalkOp<'s> {
             .collect()
     }
 }
-impl<'s> WalkOp<MultiCompareNode> for MultiComparePrintWalkOp<'s> {
+impl WalkOp<MultiCompareNode> for MultiCompareCollectOp {
     type VisitResult = ();
 
     fn should_descend(&mut self, ps: &Path, node: &MultiCompareNode) -> bool {
         let targ = node.1.as_ref();
         let is_treeish = targ.map(|n| n.is_treeish).unwrap_or(false);
         let included = self.status(&node, ps).iter().any(|s| s.is_included());
-        is_treeish && included
+        is_treeish && included && self.pathspec.matches_within(ps)
     }
     fn no_descend(&mut self,
                   ps: &Path,
                   node: MultiCompareNode)
                   -> Result<Option<Self::VisitResult>> {
+        if !self.pathspec.matches(ps) {
+            return Ok(None);
+        }
         let status = self.status(&node, &ps);
         let show = status.iter().any(|status| {
             *status != Status::Unchanged &&
             (*status != Status::Ignored || self.show_ignored)
         });
-        let mut ps = ps.to_string_lossy();
-        if node.1.map(|n| n.is_treeish).unwrap_or(false) {
-            ps += "/";
-        }
         if show {
-            for status in status {
-                write!(self.writer, "{}", status.code())?;
-            }
-            writeln!(self.writer, " {}", ps)?;
+            let (srcs, targ) = node;
+            self.found.push(StatusEntry::from_nodes(ps.to_owned(),
+                                                    status,
+                                                    &srcs,
+                                                    targ.as_ref()));
+        }
+        Ok(None)
+    }
+    fn post_descend(&mut self,
+                    ps: &Path,
+                    node: MultiCompareNode,
+                    _children: ChildMap<Self::VisitResult>)
+                    -> Result<Option<Self::VisitResult>> {
+        // A new empty directory has no files to report, so report it instead
+        let status = self.status(&node, ps)
+            .into_iter()
+            .map(|status| match status {
+                Status::Add => Status::Add,
+                _ => Status::Unchanged,
+            })
+            .collect::<Vec<_>>();
+        if status.contains(&Status::Add) && is_empty_dir(&node.1) &&
+           self.pathspec.matches(ps) {
+            let (srcs, targ) = node;
+            self.found.push(StatusEntry::from_nodes(ps.to_owned(),
+                                                    status,
+                                                    &srcs,
+                                                    targ.as_ref()));
         }
         Ok(None)
     }
+    fn unreadable(&mut self, ps: &Path, error: &Error) -> Result<()> {
+        let entry = unreadable_entry(&self.root,
+                                     &self.pathspec,
+                                     ps,
+                                     error,
+                                     self.sources);
+        self.found.extend(entry);
+        Ok(())
+    }
 }
 
 
 pub struct TransferEstimateOp {
     acc: ObjectSize,
+    progress: Option<Arc<ProgressCounter>>,
+    pathspec: Pathspec,
+}
+impl Default for TransferEstimateOp {
+    fn default() -> Self { TransferEstimateOp::new() }
 }
 impl TransferEstimateOp {
-    pub fn new() -> Self { TransferEstimateOp { acc: 0 } }
+    pub fn new() -> Self {
+        TransferEstimateOp {
+            acc: 0,
+            progress: None,
+            pathspec: Pathspec::all(),
+        }
+    }
+    /// Count the files and bytes found to transfer as they are found
+    pub fn with_progress(progress: Arc<ProgressCounter>) -> Self {
+        TransferEstimateOp {
+            acc: 0,
+            progress: Some(progress),
+            pathspec: Pathspec::all(),
+        }
+    }
+    /// Count only paths matching the pathspec
+    pub fn limit_to(mut self, pathspec: Pathspec) -> Self {
+        self.pathspec = pathspec;
+        self
+    }
     pub fn estimate(&self) -> ObjectSize { self.acc }
     fn status(&self, node: &CompareNode, _ps: &Path) -> Status {
         ComparableNode::compare_pair(node)
@@ -252,16 +707,23 @@ impl WalkOp<CompareNode> for TransferEstimateOp {
         let targ = node.1.as_ref();
         let is_treeish = targ.map(|n| n.is_treeish).unwrap_or(false);
         let included = self.status(&node, ps).is_included();
-        is_treeish && included
+        is_treeish && included && self.pathspec.matches_within(ps)
     }
     fn no_descend(&mut self,
                   ps: &Path,
                   node: CompareNode)
                   -> Result<Option<Self::VisitResult>> {
+        if !self.pathspec.matches(ps) {
+            return Ok(None);
+        }
         let status = ComparableNode::compare_pair(&node);
         let size = node.1.as_ref().map(|n| n.file_size).unwrap_or(0);
         if status.needs_transfer() {
             self.acc += size;
+            if let Some(ref progress) = self.progress {
+                progress.add(size);
+                progress.add_file();
+            }
         }
         trace!("{} {} -- {} to transfer, {} total",
                status.code(),
@@ -275,15 +737,65 @@ impl WalkOp<CompareNode> for TransferEstimateOp {
 
 
 
+/// Hashes and stores new and changed files, then the trees around them
+///
+/// It can be forked to hash the top-level directories in parallel. Each
+/// fork's results come back as the children of the top tree, so it has
+/// nothing else to join.
 pub struct HashAndStoreOp<'a, 'b> {
     fs_transfer: &'a FsTransfer,
     progress: &'b ProgressCounter,
+    /// Paths to hash, with the parent's version kept for all others
+    pathspec: &'a Pathspec,
+}
+impl<'a, 'b> HashAndStoreOp<'a, 'b> {
+    /// Find or extract the media metadata object for a tree entry
+    ///
+    /// The link is copied from the old tree if the file is unchanged, so
+    /// only new and changed files are read.
+    fn media_metadata(&self,
+                      old_tree: Option<&Tree>,
+                      dir_path: Option<&PathBuf>,
+                      name: &str,
+                      hash: ObjectKey)
+                      -> Result<Option<ObjectKey>> {
+        let name = OsStr::new(name);
+        if let Some(old_tree) = old_tree {
+            if old_tree.get(name) == Some(&hash) {
+                if let Some(link) = old_tree.media_metadata.get(name) {
+                    return Ok(Some(*link));
+                }
+            }
+        }
+        let path = match dir_path {
+            Some(dir_path) => dir_path.join(name),
+            None => return Ok(None),
+        };
+        if !path.is_file() {
+            return Ok(None);
+        }
+        match MediaMetadata::read_file(&path)? {
+            Some(metadata) => {
+                let blob = Blob::from(metadata.to_string().into_bytes());
+                Ok(Some(self.fs_transfer.store_object(&blob)?))
+            }
+            None => Ok(None),
+        }
+    }
 }
 impl<'a, 'b> WalkOp<CompareNode> for HashAndStoreOp<'a, 'b> {
     type VisitResult = ObjectKey;
 
-    fn should_descend(&mut self, _ps: &Path, node: &CompareNode) -> bool {
+    fn should_descend(&mut self, ps: &Path, node: &CompareNode) -> bool {
         let status = ComparableNode::compare_pair(node);
+        if !self.pathspec.matches(ps) {
+            // Look for matching paths in the work dir's version, or in the
+            // parent's if it is gone, to rebuild the tree around them
+            let node = node.1.as_ref().or(node.0.as_ref());
+            return node.is_some_and(|n| n.is_treeish) &&
+                   status != Status::Ignored &&
+                   self.pathspec.matches_within(ps);
+        }
         node.1.as_ref().map(|ref n| n.is_treeish).unwrap_or(false) &&
         status.is_included()
     }
@@ -292,6 +804,10 @@ impl<'a, 'b> WalkOp<CompareNode> for HashAndStoreOp<'a, 'b> {
                   ps: &Path,
                   node: CompareNode)
                   -> Result<Option<Self::VisitResult>> {
+        if !self.pathspec.matches(ps) {
+            debug!("{} - outside pathspec, keeping parent's", ps.display());
+            return Ok(node.0.as_ref().and_then(|n| n.hash));
+        }
         let status = ComparableNode::compare_pair(&node);
         let file_hash = node.1.as_ref().and_then(|n| n.hash);
         let file_path = node.1.as_ref().and_then(|n| n.fs_path.as_ref());
@@ -323,15 +839,65 @@ impl<'a, 'b> WalkOp<CompareNode> for HashAndStoreOp<'a, 'b> {
 
     fn post_descend(&mut self,
                     ps: &Path,
-                    _node: CompareNode,
+                    node: CompareNode,
                     children: ChildMap<Self::VisitResult>)
                     -> Result<Option<Self::VisitResult>> {
-        if children.is_empty() {
+        let is_root = ps.as_os_str().is_empty();
+        if children.is_empty() && !is_root && is_empty_dir(&node.1) {
+            if !self.pathspec.matches(ps) {
+                return Ok(node.0.as_ref().and_then(|n| n.hash));
+            }
+            debug!("  {} - keeping empty dir", ps.display());
+        } else if children.is_empty() {
             debug!("  {} - dropping empty dir", ps.display());
             return Ok(None);
         }
         let mut tree = Tree::new();
+        let dir_path = node.1.as_ref().and_then(|n| n.fs_path.as_ref());
+        let preserve = self.fs_transfer.config().preserve_metadata();
+        let extract = self.fs_transfer.config().extract_metadata();
+        let partial = !self.pathspec.is_all();
+        let old_tree = match node.0 {
+            Some(ComparableNode { is_treeish: true, hash: Some(hash), .. })
+                if extract || partial => {
+                Some(self.fs_transfer.open_tree(&hash)?)
+            }
+            _ => None,
+        };
         for (name, hash) in children {
+            if partial && !self.pathspec.matches(&ps.join(&name)) {
+                // Kept from the parent, so keep its recorded metadata too
+                if let Some(ref old_tree) = old_tree {
+                    let old_name = OsStr::new(&name);
+                    if let Some(metadata) = old_tree.metadata.get(old_name) {
+                        tree.insert_metadata(name.as_str(), *metadata);
+                    }
+                    if let Some(link) = old_tree.media_metadata.get(old_name) {
+                        tree.insert_media_metadata(name.as_str(), *link);
+                    }
+                }
+                tree.insert(name, hash);
+                continue;
+            }
+            match dir_path {
+                Some(dir_path) if preserve => {
+                    let child_path = dir_path.join(&name);
+                    if child_path.exists() {
+                        tree.insert_metadata(name.as_str(),
+                                             file_metadata(&child_path)?);
+                    }
+                }
+                _ => {}
+            }
+            if extract {
+                let link = self.media_metadata(old_tree.as_ref(),
+                                               dir_path,
+                                               &name,
+                                               hash)?;
+                if let Some(link) = link {
+                    tree.insert_media_metadata(name.as_str(), link);
+                }
+            }
             tree.insert(name, hash);
         }
         let hash = self.fs_transfer.store_object(&tree)?;
@@ -339,6 +905,18 @@ impl<'a, 'b> WalkOp<CompareNode> for HashAndStoreOp<'a, 'b> {
         Ok(Some(hash))
     }
 }
+impl<'a, 'b> ForkWalkOp<CompareNode> for HashAndStoreOp<'a, 'b> {
+    type Fork = Self;
+
+    fn fork(&self) -> Self::Fork {
+        HashAndStoreOp {
+            fs_transfer: self.fs_transfer,
+            progress: self.progress,
+            pathspec: self.pathspec,
+        }
+    }
+    fn join(&mut self, _fork: Self::Fork) -> Result<()> { Ok(()) }
+}
 
 
 
@@ -350,6 +928,19 @@ pub struct CheckoutOp<'a> {
     object_store: &'a ObjectStore,
     extract_root: &'a Path,
     progress: &'a ProgressCounter,
+    /// Restore recorded modification times and permissions
+    preserve_metadata: bool,
+    /// Metadata from the trees seen so far, by path relative to the root
+    metadata: HashMap<PathBuf, FileMetadata>,
+}
+impl<'a> CheckoutOp<'a> {
+    fn extract_path(&self, ps: &Path) -> PathBuf {
+        if ps == Path::new("") {
+            self.extract_root.to_owned()
+        } else {
+            self.extract_root.join(ps)
+        }
+    }
 }
 impl<'a> WalkOp<CheckoutNode> for CheckoutOp<'a> {
     type VisitResult = ();
@@ -358,11 +949,32 @@ impl<'a> WalkOp<CheckoutNode> for CheckoutOp<'a> {
         node.1.map(|n| n.object_type.is_treeish()).unwrap_or(false)
     }
 
-    fn pre_descend(&mut self, ps: &Path, _node: &CheckoutNode) -> Result<()> {
+    fn pre_descend(&mut self, ps: &Path, node: &CheckoutNode) -> Result<()> {
+        if self.preserve_metadata {
+            if let Some(obj) = node.1 {
+                let tree = self.object_store.open_tree(&obj.hash)?;
+                for (name, meta) in tree.metadata {
+                    self.metadata.insert(ps.join(name), meta);
+                }
+            }
+        }
         let path = self.extract_root.join(ps);
         create_dir_clobber(&path)
     }
 
+    fn post_descend(&mut self,
+                    ps: &Path,
+                    _node: CheckoutNode,
+                    _children: ChildMap<Self::VisitResult>)
+                    -> Result<Option<Self::VisitResult>> {
+        // Directory times change as children are written, so set them last
+        if let Some(meta) = self.metadata.remove(ps) {
+            let path = self.extract_path(ps);
+            self.file_store.restore_metadata(&path, &meta, None)?;
+        }
+        Ok(None)
+    }
+
     fn no_descend(&mut self,
                   ps: &Path,
                   node: CheckoutNode)
@@ -371,11 +983,7 @@ impl<'a> WalkOp<CheckoutNode> for CheckoutOp<'a> {
         let obj = node.1;
         let status = ComparableNode::compare_into(file.clone(), obj.clone());
 
-        let path = if ps == Path::new("") {
-            self.extract_root.to_owned()
-        } else {
-            self.extract_root.join(ps)
-        };
+        let path = self.extract_path(ps);
 
         if status == Status::Delete {
             let file = file.unwrap(); // safe to unwrap
@@ -397,6 +1005,9 @@ impl<'a> WalkOp<CheckoutNode> for CheckoutOp<'a> {
                             hash,
                             path.display())
                 })?;
+            if let Some(meta) = self.metadata.remove(ps) {
+                self.file_store.restore_metadata(&path, &meta, Some(&hash))?;
+            }
         }
 
         Ok(None)
@@ -405,15 +1016,131 @@ impl<'a> WalkOp<CheckoutNode> for CheckoutOp<'a> {
 
 
 
-type ThreeWayMergeNode = (Vec<Option<ObjectWalkNode>>, Option<FileWalkNode>);
-enum MergeSlot {
-    Common = 0,
-    Theirs = 1,
+/// Rules for which file names can be written to the filesystem
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct NameRules {
+    /// Names that differ only in case refer to the same file
+    pub case_insensitive: bool,
+    /// Windows reserved names and characters cannot be used
+    pub windows_names: bool,
+}
+
+impl NameRules {
+    /// Rules for the filesystems usually found on this platform
+    ///
+    /// macOS and Windows filesystems are case-insensitive by default.
+    pub fn for_platform() -> Self {
+        NameRules {
+            case_insensitive: cfg!(any(target_os = "macos", windows)),
+            windows_names: cfg!(windows),
+        }
+    }
+
+    /// Check one directory's entry names, returning a description of each
+    /// problem
+    ///
+    /// Names that could escape the directory are always rejected.
+    pub fn check_names<'a, I>(&self, dir: &Path, names: I) -> Vec<String>
+        where I: Iterator<Item = &'a str>
+    {
+        let mut problems = Vec::new();
+        let mut folded: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+        for name in names {
+            let path = dir.join(name);
+            if name.is_empty() || name == "." || name == ".." ||
+               name.contains('/') || name.contains('\0') {
+                problems.push(format!("{}: not a valid file name",
+                                      path.display()));
+                continue;
+            }
+            if self.windows_names {
+                if let Some(reason) = windows_name_problem(name) {
+                    problems.push(format!("{}: {}", path.display(), reason));
+                }
+            }
+            if self.case_insensitive {
+                folded.entry(name.to_lowercase()).or_default().push(name);
+            }
+        }
+        for (_, names) in folded.into_iter().filter(|(_, n)| n.len() > 1) {
+            let paths = names.iter()
+                .map(|name| dir.join(name).display().to_string())
+                .collect::<Vec<String>>()
+                .join(", ");
+            problems.push(format!("{}: names differ only in case", paths));
+        }
+        problems
+    }
+}
+
+/// Why a name cannot be used on Windows, if it cannot
+fn windows_name_problem(name: &str) -> Option<&'static str> {
+    const RESERVED: &'static [&'static str] =
+        &["CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5",
+          "COM6", "COM7", "COM8", "COM9", "LPT1", "LPT2", "LPT3", "LPT4",
+          "LPT5", "LPT6", "LPT7", "LPT8", "LPT9"];
+    let stem = name.split('.').next().unwrap_or(name).trim_end();
+    if RESERVED.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+        Some("reserved device name on Windows")
+    } else if name.chars()
+        .any(|c| c < ' ' || "<>:\"\\|?*".contains(c)) {
+        Some("contains a character not allowed on Windows")
+    } else if name.ends_with('.') || name.ends_with(' ') {
+        Some("ends with a dot or space, which Windows drops")
+    } else {
+        None
+    }
+}
+
+/// An operation that walks a Tree to find names that cannot be extracted
+pub struct NameCheckOp {
+    rules: NameRules,
+    problems: Vec<String>,
 }
+impl WalkOp<ObjectWalkNode> for NameCheckOp {
+    type VisitResult = ();
+
+    fn should_descend(&mut self, _ps: &Path, node: &ObjectWalkNode) -> bool {
+        node.object_type.is_treeish()
+    }
+
+    fn no_descend(&mut self,
+                  _ps: &Path,
+                  _node: ObjectWalkNode)
+                  -> Result<Option<Self::VisitResult>> {
+        Ok(Some(()))
+    }
+
+    fn post_descend(&mut self,
+                    ps: &Path,
+                    _node: ObjectWalkNode,
+                    children: ChildMap<Self::VisitResult>)
+                    -> Result<Option<Self::VisitResult>> {
+        let problems = self.rules
+            .check_names(ps, children.keys().map(|name| name.as_str()));
+        self.problems.extend(problems);
+        Ok(Some(()))
+    }
+}
+
+
+
+/// Common ancestor, then each of theirs, then the work dir
+type ThreeWayMergeNode = (Vec<Option<ObjectWalkNode>>, Option<FileWalkNode>);
+const COMMON_SLOT: usize = 0;
+
+/// Merges the changes from a common ancestor to one or more commits into the
+/// work dir, in one walk
+///
+/// A file changed by only one side takes that side's version. A file changed
+/// the same way by several sides is no conflict either. Any other file
+/// changed by more than one side is a conflict.
 pub struct ThreeWayMergeWalkOp<'a> {
     fs_transfer: &'a FsTransfer,
     base_path: &'a Path,
     progress: &'a ProgressCounter,
+    /// Paths changed on both sides, which are left as they are in the work dir
+    pub conflicts: Vec<PathBuf>,
 }
 impl<'a> ThreeWayMergeWalkOp<'a> {
     pub fn new(fs_transfer: &'a FsTransfer,
@@ -424,6 +1151,7 @@ impl<'a> ThreeWayMergeWalkOp<'a> {
             base_path: base_path,
             fs_transfer: fs_transfer,
             progress: progress,
+            conflicts: Vec::new(),
         }
     }
 }
@@ -440,7 +1168,7 @@ impl<'a> WalkOp<ThreeWayMergeNode> for ThreeWayMergeWalkOp<'a> {
 
         let wd_is_dir = is_dir(&node.1);
         let wd_is_ignore = &node.1.as_ref().map(|n| n.ignored).unwrap_or(false);
-        let theirs_is_tree = is_tree(&node.0[MergeSlot::Theirs as usize]);
+        let theirs_is_tree = node.0[COMMON_SLOT + 1..].iter().any(is_tree);
 
         wd_is_dir && !wd_is_ignore || theirs_is_tree
     }
@@ -465,20 +1193,26 @@ impl<'a> WalkOp<ThreeWayMergeNode> for ThreeWayMergeWalkOp<'a> {
                 Some(self.fs_transfer.hash_file(&path, &self.progress)?)
             }
         };
-        let common = node.0[MergeSlot::Common as usize].map(|n| n.hash);
-        let theirs = node.0[MergeSlot::Theirs as usize].map(|n| n.hash);
+        let common = node.0[COMMON_SLOT].map(|n| n.hash);
+        let mut theirs: Vec<Option<ObjectKey>> = Vec::new();
+        for t in node.0[COMMON_SLOT + 1..].iter().map(|n| n.map(|n| n.hash)) {
+            if t != common && !theirs.contains(&t) {
+                theirs.push(t);
+            }
+        }
 
         #[derive(Debug,Clone,Copy)]
         enum Action {
             KeepWd,
-            KeepTheirs,
+            KeepTheirs(Option<ObjectKey>),
             Conflict,
         }
 
-        let action = match (common, wd, theirs) {
-            (_, w, t) if w == t => Action::KeepWd,
-            (c, _, t) if c == t => Action::KeepWd,
-            (c, w, _) if c == w => Action::KeepTheirs,
+        // `theirs` now holds only the distinct changes from common
+        let action = match (common, wd, theirs.as_slice()) {
+            (_, _, &[]) => Action::KeepWd,
+            (_, w, &[t]) if w == t => Action::KeepWd,
+            (c, w, &[t]) if c == w => Action::KeepTheirs(t),
             (_, _, _) => Action::Conflict,
         };
 
@@ -491,7 +1225,7 @@ impl<'a> WalkOp<ThreeWayMergeNode> for ThreeWayMergeWalkOp<'a> {
 
         match action {
             Action::KeepWd => (),
-            Action::KeepTheirs => {
+            Action::KeepTheirs(theirs) => {
                 match theirs {
                     Some(t) => {
                         self.fs_transfer
@@ -504,13 +1238,7 @@ impl<'a> WalkOp<ThreeWayMergeNode> for ThreeWayMergeWalkOp<'a> {
                     }
                 }
             }
-            Action::Conflict => {
-                bail!("Conflict not implemented. common: {:?}, wd: {:?}, \
-                       theirs: {:?}",
-                      common,
-                      wd,
-                      theirs);
-            }
+            Action::Conflict => self.conflicts.push(ps.to_owned()),
         }
         Ok(None)
     }
@@ -538,6 +1266,8 @@ mod test {
     use dag::ObjectCommon;
     use dag::ObjectType;
     use rolling_hash::CHUNK_TARGET_SIZE;
+    use std::ffi::OsString;
+    use std::fs::create_dir_all;
     use super::*;
     use testutil;
     use testutil::tempdir::TempDir;
@@ -559,7 +1289,8 @@ mod test {
         testutil::write_file(&filepath, in_file).unwrap();
 
         // Hash input file
-        let hash = fs_transfer.hash_obj_file(None, &filepath).unwrap();
+        let hash = fs_transfer.hash_obj_file(None, &filepath, &Pathspec::all())
+            .unwrap();
 
         // Check the object type
         let obj = fs_transfer.open_object(&hash).unwrap();
@@ -581,6 +1312,13 @@ mod test {
                        .unwrap(),
                    CacheStatus::Cached(hash),
                    "Cache should be primed with extracted file's hash");
+
+        // Store and extract as streams
+        let stream_hash = fs_transfer.hash_reader(in_file, "<test>").unwrap();
+        assert_eq!(stream_hash, hash, "Stream should hash the same as file");
+        let mut out_stream = Vec::new();
+        fs_transfer.extract_to_writer(&hash, &mut out_stream).unwrap();
+        assert!(out_stream.as_slice() == in_file, "stream contents differ");
     }
 
     #[test]
@@ -600,6 +1338,50 @@ mod test {
         do_store_single_file_test(&in_file, ObjectType::ChunkedBlob);
     }
 
+    #[test]
+    fn test_hash_refuses_hidden_dir() {
+        let (temp, mut fs_transfer) = create_temp_repo("object_store");
+        let blob = fs_transfer.store_object(&Blob::from("Hello!")).unwrap();
+        let osd = temp.path().join("object_store");
+
+        let all = Pathspec::all();
+        assert!(fs_transfer.hash_obj_file(None, &osd, &all).is_err());
+        assert!(fs_transfer.hash_obj_file(None, &osd.join("objects"), &all)
+            .is_err());
+
+        // Hashing the parent directory skips the object store
+        testutil::write_file(&temp.path().join("foo"), "foo").unwrap();
+        let tree = fs_transfer.hash_obj_file(None, temp.path(), &all).unwrap();
+        let tree = fs_transfer.open_tree(&tree).unwrap();
+        assert_eq!(tree.keys().collect::<Vec<_>>(),
+                   vec![&OsString::from("foo")],
+                   "Object store should not be hashed");
+        assert!(fs_transfer.has_object(&blob));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hash_skips_symlink_to_hidden_dir() {
+        use std::os::unix::fs::symlink;
+
+        let (temp, mut fs_transfer) = create_temp_repo("repo/object_store");
+        fs_transfer.store_object(&Blob::from("Hello!")).unwrap();
+        let wd = temp.path().join("wd");
+        testutil::write_file(&wd.join("foo"), "foo").unwrap();
+        symlink(temp.path().join("repo/object_store"), wd.join("sneaky"))
+            .unwrap();
+        symlink(temp.path().join("repo"), wd.join("indirect")).unwrap();
+
+        let tree = fs_transfer.hash_obj_file(None, &wd, &Pathspec::all())
+            .unwrap();
+        let tree = fs_transfer.open_tree(&tree).unwrap();
+        assert!(tree.get(&OsString::from("foo")).is_some());
+        assert!(tree.get(&OsString::from("sneaky")).is_none(),
+                "Symlink to object store should be excluded");
+        assert!(tree.get(&OsString::from("indirect")).is_none(),
+                "Directory with nothing but the object store is empty");
+    }
+
     #[test]
     fn test_extract_object_object_not_found() {
         let (temp, fs_transfer) = create_temp_repo("object_store");
@@ -610,4 +1392,182 @@ mod test {
         let result = fs_transfer.extract_object(&hash, &out_file);
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_extract_tree_to_writer() {
+        let (temp, mut fs_transfer) = create_temp_repo("object_store");
+        testutil::write_file(&temp.path().join("wd/foo"), "foo").unwrap();
+        let tree = fs_transfer.hash_obj_file(None,
+                           &temp.path().join("wd"),
+                           &Pathspec::all())
+            .unwrap();
+
+        let mut out_stream = Vec::new();
+        let err = fs_transfer.extract_to_writer(&tree, &mut out_stream)
+            .unwrap_err();
+        assert_match!(err.root_kind(),
+                      &ErrorKind::WrongObjectType(h,
+                                                  ObjectType::Blob,
+                                                  ObjectType::Tree)
+                          if h == tree);
+        assert!(out_stream.is_empty());
+    }
+
+    #[test]
+    fn test_import_dir() {
+        let (temp, mut fs_transfer) = create_temp_repo("object_store");
+        testutil::write_file(&temp.path().join("in/foo"), "foo").unwrap();
+        testutil::write_file(&temp.path().join("in/sub/bar"), "bar").unwrap();
+        create_dir_all(temp.path().join("in/empty")).unwrap();
+        let tree = fs_transfer.import_dir(&temp.path().join("in")).unwrap();
+        let tree = fs_transfer.open_tree(&tree).unwrap();
+        assert_eq!(tree.len(), 3, "Should keep the empty subdirectory");
+
+        create_dir_all(temp.path().join("empty")).unwrap();
+        let empty = fs_transfer.import_dir(&temp.path().join("empty"))
+            .unwrap();
+        assert!(fs_transfer.open_tree(&empty).unwrap().is_empty());
+
+        let err = fs_transfer.import_dir(&temp.path().join("in/foo"));
+        assert!(err.is_err(), "Should refuse to import a file");
+    }
+
+    #[test]
+    fn test_check_names() {
+        let rules = NameRules {
+            case_insensitive: true,
+            windows_names: true,
+        };
+        let names = vec!["Photo.jpg", "photo.jpg", "ok.txt", "con.txt", "a?b",
+                         "..", "trailing."];
+        let problems = rules.check_names(Path::new("dir"), names.into_iter());
+        assert_eq!(problems,
+                   vec!["dir/con.txt: reserved device name on Windows",
+                        "dir/a?b: contains a character not allowed on Windows",
+                        "dir/..: not a valid file name",
+                        "dir/trailing.: ends with a dot or space, which \
+                         Windows drops",
+                        "dir/Photo.jpg, dir/photo.jpg: names differ only in \
+                         case"]);
+
+        let rules = NameRules {
+            case_insensitive: false,
+            windows_names: false,
+        };
+        let names = vec!["Photo.jpg", "photo.jpg", "con.txt", ".."];
+        let problems = rules.check_names(Path::new("dir"), names.into_iter());
+        assert_eq!(problems, vec!["dir/..: not a valid file name"]);
+    }
+
+    #[test]
+    fn test_extract_refuses_case_collision() {
+        let (temp, mut fs_transfer) = create_temp_repo("object_store");
+        let photo = fs_transfer.store_object(&Blob::from("photo")).unwrap();
+        let sub = fs_transfer.store_object(&tree_object!{
+                "Photo.jpg" => photo,
+                "photo.jpg" => photo,
+            })
+            .unwrap();
+        let tree = fs_transfer.store_object(&tree_object!{
+                "ok.txt" => photo,
+                "sub" => sub,
+            })
+            .unwrap();
+        fs_transfer.name_rules.case_insensitive = true;
+
+        let out = temp.path().join("out");
+        match fs_transfer.extract_object(&tree, &out) {
+            Err(Error(ErrorKind::UnrepresentablePaths(problems), _)) => {
+                assert_eq!(problems.len(), 1);
+                assert!(problems[0].contains("Photo.jpg"));
+            }
+            other => panic!("Expected unrepresentable paths, got {:?}", other),
+        }
+        assert!(!out.exists(), "Should not write anything");
+
+        fs_transfer.name_rules.case_insensitive = false;
+        fs_transfer.extract_object(&tree, &out).unwrap();
+        assert!(out.join("sub/photo.jpg").is_file());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_preserve_metadata() {
+        use config::RepoConfig;
+        use dag::FileMetadata;
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp = in_mem_tempdir!();
+        let mut config = RepoConfig::default();
+        config.preserve_metadata = Some(true);
+        let object_store =
+            ObjectStore::init_with_config(temp.path().join("object_store"),
+                                          config)
+                .unwrap();
+        let mut fs_transfer = FsTransfer::with_object_store(object_store);
+
+        let wd = temp.path().join("wd");
+        testutil::write_file(&wd.join("foo"), "foo").unwrap();
+        testutil::write_file(&wd.join("sub/bar"), "bar").unwrap();
+        let recorded = FileMetadata {
+            mtime_secs: 1000000000,
+            mtime_nanos: 500,
+            mode: 0o640,
+        };
+        fs_transfer.file_store
+            .restore_metadata(&wd.join("foo"), &recorded, None)
+            .unwrap();
+
+        let hash = fs_transfer.hash_obj_file(None, &wd, &Pathspec::all())
+            .unwrap();
+        let tree = fs_transfer.open_tree(&hash).unwrap();
+        assert_eq!(tree.metadata.get(&OsString::from("foo")),
+                   Some(&recorded));
+        assert!(tree.metadata.contains_key(&OsString::from("sub")));
+
+        let out = temp.path().join("out");
+        fs_transfer.extract_object(&hash, &out).unwrap();
+        let meta = fs::metadata(out.join("foo")).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o7777, 0o640);
+        assert_eq!(file_metadata(&out.join("foo")).unwrap(), recorded);
+    }
+
+    #[test]
+    fn test_extract_media_metadata() {
+        use config::RepoConfig;
+        use exif::test::jpeg_with_exif;
+        use std::fs;
+
+        let temp = in_mem_tempdir!();
+        let mut config = RepoConfig::default();
+        config.extract_metadata = Some(true);
+        let object_store =
+            ObjectStore::init_with_config(temp.path().join("object_store"),
+                                          config)
+                .unwrap();
+        let mut fs_transfer = FsTransfer::with_object_store(object_store);
+
+        let wd = temp.path().join("wd");
+        testutil::write_file(&wd.join("notes.txt"), "not a photo").unwrap();
+        fs::create_dir_all(wd.join("photos")).unwrap();
+        fs::write(wd.join("photos/a.jpg"), jpeg_with_exif()).unwrap();
+
+        let hash = fs_transfer.hash_obj_file(None, &wd, &Pathspec::all())
+            .unwrap();
+        let photo = Path::new("photos/a.jpg");
+        let metadata = fs_transfer.lookup_media_metadata(&hash, photo)
+            .unwrap()
+            .expect("metadata for the photo");
+        assert_eq!(metadata.camera_model, Some("Canon EOS 5D".to_owned()));
+        let notes = Path::new("notes.txt");
+        assert_eq!(fs_transfer.lookup_media_metadata(&hash, notes).unwrap(),
+                   None);
+
+        // Committing again, against the old tree, keeps the same link
+        let rehashed =
+            fs_transfer.hash_obj_file(Some(hash), &wd, &Pathspec::all())
+                .unwrap();
+        assert_eq!(rehashed, hash);
+    }
 }
@@ -1,8 +1,14 @@
 //! Convenience methods for working with the filesystem
 
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::ffi::OsStr;
 use std::fs;
 use std::io;
 use std::path;
+use std::thread;
+use std::time::Duration;
+use std::time::SystemTime;
 
 /// Create all parent directories of a path
 pub fn create_parents<P: AsRef<path::Path>>
@@ -42,10 +48,341 @@ pub fn is_empty_dir<P: AsRef<path::Path>>(path: &P) -> Result<bool, io::Error> {
     Ok(true)
 }
 
+/// Remove empty directories under a directory, but not the directory itself
+///
+/// Directories left empty by removing their empty subdirectories are removed
+/// too.
+pub fn remove_empty_dirs(dir: &path::Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            let path = entry.path();
+            remove_empty_dirs(&path)?;
+            if is_empty_dir(&path)? {
+                fs::remove_dir(&path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Something that blocks until files under a directory may have changed
+pub trait DirWatcher {
+    /// Wait for a change, then for things to settle down, and return the
+    /// paths that changed
+    ///
+    /// Returns after a change has been seen and no more have arrived for a
+    /// short while, so a burst of changes (like a copy of many files) only
+    /// wakes the caller once. If the watcher lost track of what changed, the
+    /// paths are just the root, and anything under it may have changed.
+    fn wait(&mut self) -> io::Result<Vec<path::PathBuf>>;
+}
+
+/// How long watchers wait for a burst of changes to end, and how often the
+/// polling watcher checks for changes
+const WATCH_INTERVAL_MS: u64 = 200;
+
+/// Watch a directory and everything under it
+///
+/// Changes to files or directories whose names start with one of the
+/// `ignore` prefixes are not reported, so that a watcher can ignore files
+/// written by the very command that is watching.
+///
+/// Uses inotify on Linux, and falls back to comparing file sizes and
+/// modification times on other platforms.
+pub fn watch_dir(root: &path::Path,
+                 ignore: &[&str])
+                 -> io::Result<Box<DirWatcher>> {
+    let ignore = ignore.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    #[cfg(target_os = "linux")]
+    {
+        match inotify::InotifyWatcher::new(root, ignore.clone()) {
+            Ok(watcher) => return Ok(Box::new(watcher)),
+            Err(e) => debug!("Could not use inotify, polling instead: {}", e),
+        }
+    }
+    Ok(Box::new(PollWatcher::new(root, ignore)?))
+}
+
+fn is_ignored(name: &OsStr, ignore: &[String]) -> bool {
+    let name = name.to_string_lossy();
+    ignore.iter().any(|prefix| name.starts_with(prefix.as_str()))
+}
+
+/// Watcher that rescans the directory tree, for platforms without inotify
+pub struct PollWatcher {
+    root: path::PathBuf,
+    ignore: Vec<String>,
+    snapshot: BTreeMap<path::PathBuf, (u64, Option<SystemTime>)>,
+}
+
+impl PollWatcher {
+    pub fn new(root: &path::Path, ignore: Vec<String>) -> io::Result<Self> {
+        let mut watcher = PollWatcher {
+            root: root.to_owned(),
+            ignore: ignore,
+            snapshot: BTreeMap::new(),
+        };
+        watcher.snapshot = watcher.scan()?;
+        Ok(watcher)
+    }
+
+    fn scan(&self)
+            -> io::Result<BTreeMap<path::PathBuf, (u64, Option<SystemTime>)>> {
+        let mut snapshot = BTreeMap::new();
+        let mut dirs = vec![self.root.clone()];
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(&dir)? {
+                let entry = entry?;
+                if is_ignored(&entry.file_name(), &self.ignore) {
+                    continue;
+                }
+                let meta = entry.metadata()?;
+                if meta.is_dir() {
+                    dirs.push(entry.path());
+                }
+                snapshot.insert(entry.path(),
+                                (meta.len(), meta.modified().ok()));
+            }
+        }
+        Ok(snapshot)
+    }
+}
+
+impl DirWatcher for PollWatcher {
+    fn wait(&mut self) -> io::Result<Vec<path::PathBuf>> {
+        let mut changed = BTreeSet::new();
+        loop {
+            thread::sleep(Duration::from_millis(WATCH_INTERVAL_MS));
+            let snapshot = self.scan()?;
+            if snapshot != self.snapshot {
+                for (path, stat) in &snapshot {
+                    if self.snapshot.get(path) != Some(stat) {
+                        changed.insert(path.clone());
+                    }
+                }
+                for path in self.snapshot.keys() {
+                    if !snapshot.contains_key(path) {
+                        changed.insert(path.clone());
+                    }
+                }
+                self.snapshot = snapshot;
+            } else if !changed.is_empty() {
+                return Ok(changed.into_iter().collect());
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod inotify {
+    use std::collections::BTreeSet;
+    use std::collections::HashMap;
+    use std::ffi::CString;
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::io;
+    use std::io::Read;
+    use std::os::raw::c_char;
+    use std::os::raw::c_int;
+    use std::os::raw::c_short;
+    use std::os::raw::c_ulong;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::FromRawFd;
+    use std::path::Path;
+    use std::path::PathBuf;
+    use super::DirWatcher;
+    use super::WATCH_INTERVAL_MS;
+    use super::is_ignored;
+
+    extern "C" {
+        fn inotify_init1(flags: c_int) -> c_int;
+        fn inotify_add_watch(fd: c_int,
+                             path: *const c_char,
+                             mask: u32)
+                             -> c_int;
+        fn poll(fds: *mut PollFd, nfds: c_ulong, timeout: c_int) -> c_int;
+    }
+
+    /// `struct pollfd`
+    #[repr(C)]
+    struct PollFd {
+        fd: c_int,
+        events: c_short,
+        revents: c_short,
+    }
+
+    const POLLIN: c_short = 0x1;
+
+    const IN_NONBLOCK: c_int = 0o4000;
+    const IN_CLOEXEC: c_int = 0o2000000;
+
+    const IN_MODIFY: u32 = 0x2;
+    const IN_ATTRIB: u32 = 0x4;
+    const IN_MOVED_FROM: u32 = 0x40;
+    const IN_MOVED_TO: u32 = 0x80;
+    const IN_CREATE: u32 = 0x100;
+    const IN_DELETE: u32 = 0x200;
+    const IN_Q_OVERFLOW: u32 = 0x4000;
+    const IN_ISDIR: u32 = 0x40000000;
+    const WATCH_MASK: u32 = IN_MODIFY | IN_ATTRIB | IN_MOVED_FROM |
+                            IN_MOVED_TO | IN_CREATE |
+                            IN_DELETE;
+
+    /// Size of the fixed part of `struct inotify_event`
+    const EVENT_HEADER_SIZE: usize = 16;
+
+    /// Watcher using Linux's inotify, with one watch per directory
+    pub struct InotifyWatcher {
+        root: PathBuf,
+        fd: c_int,
+        file: fs::File,
+        dirs: HashMap<c_int, PathBuf>,
+        ignore: Vec<String>,
+    }
+
+    impl InotifyWatcher {
+        pub fn new(root: &Path, ignore: Vec<String>) -> io::Result<Self> {
+            let fd = unsafe { inotify_init1(IN_NONBLOCK | IN_CLOEXEC) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut watcher = InotifyWatcher {
+                root: root.to_owned(),
+                fd: fd,
+                file: unsafe { fs::File::from_raw_fd(fd) },
+                dirs: HashMap::new(),
+                ignore: ignore,
+            };
+            watcher.add_tree(root)?;
+            Ok(watcher)
+        }
+
+        fn add_tree(&mut self, root: &Path) -> io::Result<()> {
+            let mut dirs = vec![root.to_owned()];
+            while let Some(dir) = dirs.pop() {
+                let path = CString::new(dir.as_os_str().as_bytes())?;
+                let wd = unsafe {
+                    inotify_add_watch(self.fd, path.as_ptr(), WATCH_MASK)
+                };
+                if wd < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                for entry in fs::read_dir(&dir)? {
+                    let entry = entry?;
+                    if entry.file_type()?.is_dir() &&
+                       !is_ignored(&entry.file_name(), &self.ignore) {
+                        dirs.push(entry.path());
+                    }
+                }
+                self.dirs.insert(wd, dir);
+            }
+            Ok(())
+        }
+
+        /// Block until there are events to read, or until the timeout (in
+        /// milliseconds, or -1 for none) passes, and report which
+        fn poll(&self, timeout: c_int) -> io::Result<bool> {
+            let mut fds = PollFd {
+                fd: self.fd,
+                events: POLLIN,
+                revents: 0,
+            };
+            loop {
+                match unsafe { poll(&mut fds, 1, timeout) } {
+                    n if n >= 0 => return Ok(n > 0),
+                    _ => {
+                        let e = io::Error::last_os_error();
+                        if e.kind() != io::ErrorKind::Interrupted {
+                            return Err(e);
+                        }
+                    }
+                }
+            }
+        }
+
+        /// Read all pending events, and add the paths they are about
+        fn read_events(&mut self,
+                       changed: &mut BTreeSet<PathBuf>)
+                       -> io::Result<()> {
+            let mut buf = [0u8; 4096];
+            loop {
+                let len = match self.file.read(&mut buf) {
+                    Ok(len) => len,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        return Ok(());
+                    }
+                    Err(e) => return Err(e),
+                };
+                let mut offset = 0;
+                while offset + EVENT_HEADER_SIZE <= len {
+                    let field = |i: usize| {
+                        let start = offset + i * 4;
+                        let mut bytes = [0u8; 4];
+                        bytes.copy_from_slice(&buf[start..start + 4]);
+                        u32::from_ne_bytes(bytes)
+                    };
+                    let (wd, mask, name_len) =
+                        (field(0) as c_int, field(1), field(3) as usize);
+                    let name_start = offset + EVENT_HEADER_SIZE;
+                    let name = buf[name_start..name_start + name_len]
+                        .split(|&b| b == 0)
+                        .next()
+                        .unwrap_or(&[]);
+                    let name = OsStr::from_bytes(name);
+                    offset = name_start + name_len;
+
+                    if mask & IN_Q_OVERFLOW != 0 {
+                        // Events were lost, so anything may have changed
+                        changed.insert(self.root.clone());
+                        continue;
+                    }
+                    if is_ignored(name, &self.ignore) {
+                        continue;
+                    }
+                    let path = match self.dirs.get(&wd) {
+                        Some(dir) => dir.join(name),
+                        None => continue,
+                    };
+                    let new_dir = mask & IN_ISDIR != 0 &&
+                                  mask & (IN_CREATE | IN_MOVED_TO) != 0;
+                    if new_dir {
+                        // It may already be gone again, which is fine
+                        let _ = self.add_tree(&path);
+                    }
+                    changed.insert(path);
+                }
+            }
+        }
+    }
+
+    impl DirWatcher for InotifyWatcher {
+        fn wait(&mut self) -> io::Result<Vec<PathBuf>> {
+            let mut changed = BTreeSet::new();
+            loop {
+                // Block until the first change, then until a quiet spell
+                let timeout = if changed.is_empty() {
+                    -1
+                } else {
+                    WATCH_INTERVAL_MS as c_int
+                };
+                if !self.poll(timeout)? {
+                    if changed.contains(&self.root) {
+                        return Ok(vec![self.root.clone()]);
+                    }
+                    return Ok(changed.into_iter().collect());
+                }
+                self.read_events(&mut changed)?;
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use std::path;
     use super::*;
+    use testutil;
 
     #[test]
     pub fn test_up_from_iterator() {
@@ -58,4 +395,42 @@ mod test {
         assert_eq!(iter.next().and_then(|p| p.to_str()), Some("/"));
         assert_eq!(iter.next().and_then(|p| p.to_str()), None);
     }
+
+    /// Make a change from another thread, and check that the watcher sees it
+    fn check_watcher<W: DirWatcher>(mut watcher: W, root: &path::Path) {
+        let dir = root.join("new_dir");
+        let file = dir.join("new_file");
+        let (dir_clone, file_clone) = (dir.clone(), file.clone());
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            fs::create_dir(&dir_clone).unwrap();
+            testutil::write_file(&file_clone, "hello").unwrap();
+        });
+        let changed = watcher.wait().unwrap();
+        writer.join().unwrap();
+        assert!(changed.contains(&dir), "Should report the new directory");
+        assert!(changed.iter().all(|path| path.starts_with(&dir)),
+                "Should report only paths in the new directory: {:?}",
+                changed);
+    }
+
+    #[test]
+    fn test_watch_dir() {
+        let temp = in_mem_tempdir!();
+        fs::create_dir(temp.path().join(".ignored")).unwrap();
+
+        let watcher = PollWatcher::new(temp.path(), vec![".ignored".into()])
+            .unwrap();
+        testutil::write_file(&temp.path().join(".ignored/x"), "x").unwrap();
+        assert_eq!(watcher.scan().unwrap(), watcher.snapshot);
+        check_watcher(watcher, temp.path());
+
+        #[cfg(target_os = "linux")]
+        {
+            let temp = in_mem_tempdir!();
+            let watcher = inotify::InotifyWatcher::new(temp.path(), vec![])
+                .unwrap();
+            check_watcher(watcher, temp.path());
+        }
+    }
 }
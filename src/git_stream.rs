@@ -0,0 +1,865 @@
+//! Conversion to and from git fast-import streams
+//!
+//! `export` writes commits, trees, and files in the format read by
+//! `git fast-import`, and `import` reads the format written by
+//! `git fast-export`, so that history can be moved between dmv and git.
+//!
+//! dmv commits don't record authors, time zones, or file modes. Exported
+//! commits get a fixed placeholder identity, with the commit's time in UTC (or
+//! the epoch, if it has none), and every file is a regular file. Imported
+//! commits keep the committer's time, but authors, time zones, modes, and tag
+//! messages are dropped, and symbolic links are stored as files containing
+//! their targets.
+//!
+//! Git can't represent empty directories, so they are left out of exports.
+
+use dag::Commit;
+use dag::HashedObject;
+use dag::ObjectKey;
+use dag::ObjectType;
+use dag::Tree;
+use error::*;
+use media_type::MediaType;
+use media_type::read_typed_objects;
+use object_store::DepthFirstCommitSort;
+use object_store::ObjectStore;
+use object_store::ObjectWalkNode;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::io;
+use std::io::BufRead;
+use std::io::Read;
+use std::io::Write;
+use transport::is_tracking_ref;
+use walker::NodeLookup;
+
+/// Identity given to exported commits, since dmv doesn't record one
+pub const EXPORT_IDENTITY: &'static str = "dmv <dmv@localhost>";
+
+/// Map of full file path to file object, for one commit
+pub type FileMap = BTreeMap<String, ObjectKey>;
+
+/// Counts of objects exported or imported
+#[derive(Debug,Clone,Default,PartialEq,Eq)]
+pub struct StreamStats {
+    pub commits: usize,
+    pub files: usize,
+}
+
+/// Result of an import: counts and the refs that were updated
+#[derive(Debug,Clone,Default,PartialEq,Eq)]
+pub struct ImportResult {
+    pub stats: StreamStats,
+    pub refs: Vec<(String, ObjectKey)>,
+}
+
+/// Git ref name for a dmv ref
+pub fn git_ref_name(name: &str) -> String {
+    if is_tracking_ref(name) {
+        format!("refs/{}", name)
+    } else {
+        format!("refs/heads/{}", name)
+    }
+}
+
+/// dmv ref name for a git ref
+pub fn local_ref_name(git_ref: &str) -> String {
+    git_ref.strip_prefix("refs/heads/")
+        .or_else(|| git_ref.strip_prefix("refs/"))
+        .unwrap_or(git_ref)
+        .to_owned()
+}
+
+/// List every file in a tree, with full paths
+pub fn flatten_tree(store: &ObjectStore, tree: &ObjectKey) -> Result<FileMap> {
+    let mut files = FileMap::new();
+    flatten_tree_into(store, tree, "", &mut files)?;
+    Ok(files)
+}
+
+fn flatten_tree_into(store: &ObjectStore,
+                     tree: &ObjectKey,
+                     prefix: &str,
+                     files: &mut FileMap)
+                     -> Result<()> {
+    for (name, hash) in store.open_tree(tree)?.iter() {
+        let name = name.to_str()
+            .ok_or_else(|| format!("Bad UTF-8 in name: {:?}", name))?;
+        let path = format!("{}{}", prefix, name);
+        let node: ObjectWalkNode = store.lookup_node(*hash)?;
+        if node.object_type.is_treeish() {
+            flatten_tree_into(store, hash, &format!("{}/", path), files)?;
+        } else {
+            files.insert(path, *hash);
+        }
+    }
+    Ok(())
+}
+
+/// Store Tree objects for a set of files and return the top tree's hash
+pub fn store_file_map(store: &ObjectStore,
+                      files: &FileMap)
+                      -> Result<ObjectKey> {
+    let mut tree = Tree::new();
+    let mut subdirs: BTreeMap<&str, FileMap> = BTreeMap::new();
+    for (path, hash) in files {
+        match path.find('/') {
+            Some(i) => {
+                subdirs.entry(&path[..i])
+                    .or_default()
+                    .insert(path[i + 1..].to_owned(), *hash);
+            }
+            None => tree.insert(path.as_str(), *hash),
+        }
+    }
+    for (name, subfiles) in subdirs {
+        tree.insert(name, store_file_map(store, &subfiles)?);
+    }
+    store.store_object(&tree)
+}
+
+/// Quote a path for a fast-import stream, if necessary
+fn quote_path(path: &str) -> String {
+    if !path.starts_with('"') && !path.contains('\n') {
+        return path.to_owned();
+    }
+    let mut quoted = String::from("\"");
+    for c in path.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Write the history of the given refs as a git fast-import stream
+pub fn export<W: Write>(store: &ObjectStore,
+                        refs: &[(String, ObjectKey)],
+                        writer: &mut W)
+                        -> Result<StreamStats> {
+    let mut exporter = Exporter {
+        store: store,
+        writer: writer,
+        marks: HashMap::new(),
+        next_mark: 1,
+        stats: StreamStats::default(),
+    };
+    for (name, hash) in refs {
+        let git_ref = git_ref_name(name);
+        let sorted = DepthFirstCommitSort::new(store, vec![*hash]).run()?;
+        for (hash, commit) in sorted {
+            if !exporter.marks.contains_key(&hash) {
+                exporter.export_commit(&git_ref, hash, commit)?;
+            }
+        }
+    }
+    for (name, hash) in refs {
+        writeln!(exporter.writer, "reset {}", git_ref_name(name))?;
+        writeln!(exporter.writer, "from :{}", exporter.marks[hash])?;
+        writeln!(exporter.writer)?;
+    }
+    writeln!(exporter.writer, "done")?;
+    Ok(exporter.stats)
+}
+
+struct Exporter<'a, W: Write + 'a> {
+    store: &'a ObjectStore,
+    writer: &'a mut W,
+    marks: HashMap<ObjectKey, usize>,
+    next_mark: usize,
+    stats: StreamStats,
+}
+
+impl<'a, W: Write> Exporter<'a, W> {
+    fn mark(&mut self, hash: ObjectKey) -> usize {
+        let mark = self.next_mark;
+        self.next_mark += 1;
+        self.marks.insert(hash, mark);
+        mark
+    }
+
+    fn export_blob(&mut self, hash: ObjectKey) -> Result<()> {
+        let node: ObjectWalkNode = self.store.lookup_node(hash)?;
+        let mark = self.mark(hash);
+        writeln!(self.writer, "blob")?;
+        writeln!(self.writer, "mark :{}", mark)?;
+        writeln!(self.writer, "data {}", node.file_size)?;
+        self.store.copy_blob_content(&hash, &mut *self.writer)?;
+        writeln!(self.writer)?;
+        self.stats.files += 1;
+        Ok(())
+    }
+
+    fn export_commit(&mut self,
+                     git_ref: &str,
+                     hash: ObjectKey,
+                     commit: Commit)
+                     -> Result<()> {
+        let files = flatten_tree(self.store, &commit.tree)?;
+        let parent_files = match commit.parents.first() {
+            Some(parent) => {
+                flatten_tree(self.store,
+                             &self.store.open_commit(parent)?.tree)?
+            }
+            None => FileMap::new(),
+        };
+
+        for hash in files.values() {
+            if !self.marks.contains_key(hash) {
+                self.export_blob(*hash)?;
+            }
+        }
+
+        if commit.parents.is_empty() {
+            // Start over, in case the ref already has commits in the stream
+            writeln!(self.writer, "reset {}", git_ref)?;
+        }
+        let mark = self.mark(hash);
+        writeln!(self.writer, "commit {}", git_ref)?;
+        writeln!(self.writer, "mark :{}", mark)?;
+        writeln!(self.writer,
+                 "committer {} {} +0000",
+                 EXPORT_IDENTITY,
+                 commit.time.unwrap_or(0))?;
+        writeln!(self.writer, "data {}", commit.message.len())?;
+        writeln!(self.writer, "{}", commit.message)?;
+        for (i, parent) in commit.parents.iter().enumerate() {
+            let command = if i == 0 { "from" } else { "merge" };
+            writeln!(self.writer, "{} :{}", command, self.marks[parent])?;
+        }
+        for path in parent_files.keys() {
+            if !files.contains_key(path) {
+                writeln!(self.writer, "D {}", quote_path(path))?;
+            }
+        }
+        for (path, hash) in &files {
+            if parent_files.get(path) != Some(hash) {
+                writeln!(self.writer,
+                         "M 100644 :{} {}",
+                         self.marks[hash],
+                         quote_path(path))?;
+            }
+        }
+        writeln!(self.writer)?;
+        self.stats.commits += 1;
+        Ok(())
+    }
+}
+
+
+/// Read a git fast-import stream, store its objects, and update refs
+pub fn import<R: BufRead>(store: &mut ObjectStore,
+                          reader: R)
+                          -> Result<ImportResult> {
+    let (stats, branches) = {
+        let mut importer = Importer {
+            store: &*store,
+            reader: reader,
+            pending: None,
+            marks: HashMap::new(),
+            branches: BTreeMap::new(),
+            stats: StreamStats::default(),
+        };
+        importer.run()?;
+        (importer.stats, importer.branches)
+    };
+
+    let mut result = ImportResult {
+        stats: stats,
+        refs: Vec::new(),
+    };
+    for (git_ref, tip) in branches {
+        if let Some(tip) = tip {
+            let name = local_ref_name(&git_ref);
+            store.update_ref(name.clone(), tip)?;
+            result.refs.push((name, tip));
+        }
+    }
+    Ok(result)
+}
+
+struct Importer<'a, R: BufRead> {
+    store: &'a ObjectStore,
+    reader: R,
+    pending: Option<String>,
+    marks: HashMap<u64, ObjectKey>,
+    branches: BTreeMap<String, Option<ObjectKey>>,
+    stats: StreamStats,
+}
+
+impl<'a, R: BufRead> Importer<'a, R> {
+    fn run(&mut self) -> Result<()> {
+        while let Some(line) = self.next_line()? {
+            let (command, arg) = split_command(&line);
+            match command {
+                "" => {}
+                "blob" => {
+                    self.import_blob()?;
+                }
+                "commit" => self.import_commit(arg)?,
+                "reset" => {
+                    let from = match self.optional_arg("from")? {
+                        Some(from) => self.resolve_commit(&from)?,
+                        None => None,
+                    };
+                    self.branches.insert(arg.to_owned(), from);
+                }
+                "tag" => self.import_tag(arg)?,
+                "done" => return Ok(()),
+                "checkpoint" | "progress" | "feature" | "option" => {
+                    debug!("Ignoring fast-import command: {}", line);
+                }
+                _ => bail!("Unsupported fast-import command: {}", line),
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the next line that isn't a comment, without the newline
+    fn next_line(&mut self) -> Result<Option<String>> {
+        if let Some(line) = self.pending.take() {
+            return Ok(Some(line));
+        }
+        loop {
+            match self.raw_line()? {
+                Some(ref line) if line.starts_with('#') => {}
+                line => return Ok(line),
+            }
+        }
+    }
+
+    /// Read the next line, without the newline
+    fn raw_line(&mut self) -> Result<Option<String>> {
+        let mut buf = Vec::new();
+        if self.reader.read_until(b'\n', &mut buf)? == 0 {
+            return Ok(None);
+        }
+        if buf.last() == Some(&b'\n') {
+            buf.pop();
+        }
+        String::from_utf8(buf)
+            .map(Some)
+            .chain_err(|| "Bad UTF-8 in fast-import stream")
+    }
+
+    /// Read an optional `<command> <arg>` line, leaving others for later
+    fn optional_arg(&mut self, command: &str) -> Result<Option<String>> {
+        match self.next_line()? {
+            Some(line) => {
+                let arg = {
+                    let (c, arg) = split_command(&line);
+                    if c == command { Some(arg.to_owned()) } else { None }
+                };
+                if arg.is_none() {
+                    self.pending = Some(line);
+                }
+                Ok(arg)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn optional_mark(&mut self) -> Result<Option<u64>> {
+        self.optional_arg("mark")?.and_then_try(|m| parse_mark(&m))
+    }
+
+    /// Read a `data` command and its content into memory
+    fn read_data(&mut self) -> Result<Vec<u8>> {
+        let mut content = Vec::new();
+        match self.data_header()? {
+            DataHeader::Exact(size) => {
+                (&mut self.reader).take(size).read_to_end(&mut content)?;
+                if content.len() as u64 != size {
+                    bail!("Unexpected end of fast-import stream in data");
+                }
+                self.skip_newline()?;
+            }
+            DataHeader::Delimited(content) => return Ok(content),
+        }
+        Ok(content)
+    }
+
+    fn data_header(&mut self) -> Result<DataHeader> {
+        let line = self.next_line()?.unwrap_or_default();
+        let (command, arg) = split_command(&line);
+        if command != "data" {
+            bail!("Expected data in fast-import stream, got: {}", line);
+        }
+        if let Some(delimiter) = arg.strip_prefix("<<") {
+            let mut lines = Vec::new();
+            loop {
+                match self.raw_line()? {
+                    Some(ref l) if l == delimiter => break,
+                    Some(l) => lines.push(l),
+                    None => bail!("Missing data delimiter {}", delimiter),
+                }
+            }
+            Ok(DataHeader::Delimited(lines.join("\n").into_bytes()))
+        } else {
+            let size = arg.parse()
+                .chain_err(|| format!("Bad data size: {}", arg))?;
+            Ok(DataHeader::Exact(size))
+        }
+    }
+
+    /// Skip the optional newline after data
+    fn skip_newline(&mut self) -> Result<()> {
+        let newline = self.reader.fill_buf()?.first() == Some(&b'\n');
+        if newline {
+            self.reader.consume(1);
+        }
+        Ok(())
+    }
+
+    /// Read a `data` command and store its content as a file
+    fn store_data(&mut self) -> Result<ObjectKey> {
+        let params = self.store.config().chunking;
+        let typed = self.store.config().typed_chunking();
+        let algorithm = self.store.hash_algorithm();
+        let (hash, media_type) = match self.data_header()? {
+            DataHeader::Exact(size) => {
+                let mut content = (&mut self.reader).take(size);
+                let objects = read_typed_objects(&mut content,
+                                                 &params,
+                                                 typed,
+                                                 algorithm)?;
+                let media_type = objects.media_type;
+                let hash = store_stream(self.store, objects)?;
+                if content.limit() != 0 {
+                    bail!("Unexpected end of fast-import stream in data");
+                }
+                self.skip_newline()?;
+                (hash, media_type)
+            }
+            DataHeader::Delimited(content) => {
+                let objects = read_typed_objects(content.as_slice(),
+                                                 &params,
+                                                 typed,
+                                                 algorithm)?;
+                let media_type = objects.media_type;
+                (store_stream(self.store, objects)?, media_type)
+            }
+        };
+        if media_type != MediaType::Unknown {
+            self.store.record_media_type(hash, media_type)?;
+        }
+        self.stats.files += 1;
+        Ok(hash)
+    }
+
+    fn import_blob(&mut self) -> Result<ObjectKey> {
+        let mark = self.optional_mark()?;
+        self.optional_arg("original-oid")?;
+        let hash = self.store_data()?;
+        if let Some(mark) = mark {
+            self.marks.insert(mark, hash);
+        }
+        Ok(hash)
+    }
+
+    fn import_commit(&mut self, git_ref: &str) -> Result<()> {
+        let mark = self.optional_mark()?;
+        self.optional_arg("original-oid")?;
+        self.optional_arg("author")?;
+        let time = self.optional_arg("committer")?
+            .and_then(|committer| identity_time(&committer));
+        self.optional_arg("encoding")?;
+        let message = String::from_utf8_lossy(&self.read_data()?).into_owned();
+
+        let mut parents = Vec::new();
+        match self.optional_arg("from")? {
+            Some(from) => parents.extend(self.resolve_commit(&from)?),
+            None => parents.extend(self.branch_tip(git_ref)),
+        }
+        while let Some(merge) = self.optional_arg("merge")? {
+            parents.extend(self.resolve_commit(&merge)?);
+        }
+        if parents.len() > u8::MAX as usize {
+            bail!("Too many parents for commit on {}", git_ref);
+        }
+
+        let mut files = match parents.first() {
+            Some(parent) => {
+                flatten_tree(self.store, &self.store.open_commit(parent)?.tree)?
+            }
+            None => FileMap::new(),
+        };
+        while let Some(line) = self.next_line()? {
+            if !self.file_change(&mut files, &line)? {
+                self.pending = Some(line);
+                break;
+            }
+        }
+
+        let commit = Commit {
+            tree: store_file_map(self.store, &files)?,
+            parents: parents,
+            message: message,
+            time: time,
+            signature: None,
+        };
+        let hash = self.store.store_object(&commit)?;
+        if let Some(mark) = mark {
+            self.marks.insert(mark, hash);
+        }
+        self.branches.insert(git_ref.to_owned(), Some(hash));
+        self.stats.commits += 1;
+        Ok(())
+    }
+
+    /// Apply a file change command, or return false if it isn't one
+    fn file_change(&mut self, files: &mut FileMap, line: &str) -> Result<bool> {
+        let (command, arg) = split_command(line);
+        match command {
+            "M" => {
+                let mut parts = arg.splitn(3, ' ');
+                let mode = parts.next().unwrap_or("");
+                let dataref = parts.next().unwrap_or("");
+                let (path, _) = parse_path(parts.next().unwrap_or(""), true)?;
+                match mode {
+                    "100644" | "644" | "100755" | "755" | "120000" => {}
+                    _ => bail!("Unsupported file mode {} for {}", mode, path),
+                }
+                let hash = match dataref {
+                    "inline" => self.store_data()?,
+                    _ => self.resolve_blob(dataref)?,
+                };
+                put_file(files, path, hash);
+            }
+            "D" => {
+                let (path, _) = parse_path(arg, true)?;
+                remove_path(files, &path);
+            }
+            "C" | "R" => {
+                let (src, rest) = parse_path(arg, false)?;
+                let (dest, _) = parse_path(rest, true)?;
+                let prefix = format!("{}/", src);
+                let moved = files.iter()
+                    .filter(|&(p, _)| p == &src || p.starts_with(&prefix))
+                    .map(|(p, h)| (format!("{}{}", dest, &p[src.len()..]), *h))
+                    .collect::<Vec<_>>();
+                if command == "R" {
+                    remove_path(files, &src);
+                }
+                for (path, hash) in moved {
+                    put_file(files, path, hash);
+                }
+            }
+            "deleteall" => files.clear(),
+            "N" => {
+                warn!("Skipping note, which dmv does not support");
+                if arg.starts_with("inline ") {
+                    self.read_data()?;
+                }
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    fn import_tag(&mut self, name: &str) -> Result<()> {
+        self.optional_mark()?;
+        let from = self.optional_arg("from")?
+            .ok_or_else(|| format!("Tag {} has no target", name))?;
+        let target = self.resolve_commit(&from)?;
+        self.optional_arg("original-oid")?;
+        self.optional_arg("tagger")?;
+        self.read_data()?;
+        self.branches.insert(format!("refs/tags/{}", name), target);
+        Ok(())
+    }
+
+    /// Current tip of a branch, from the stream or from the store
+    fn branch_tip(&self, git_ref: &str) -> Option<ObjectKey> {
+        match self.branches.get(git_ref) {
+            Some(tip) => *tip,
+            None => self.store.try_find_ref(&local_ref_name(git_ref)),
+        }
+    }
+
+    fn resolve(&self, reference: &str) -> Result<ObjectKey> {
+        if reference.starts_with(':') {
+            let mark = parse_mark(reference)?;
+            return self.marks
+                .get(&mark)
+                .cloned()
+                .ok_or_else(|| format!("Unknown mark {}", reference).into());
+        }
+        if let Some(&Some(tip)) = self.branches.get(reference) {
+            return Ok(tip);
+        }
+        let hash = ObjectKey::parse(reference)
+            .chain_err(|| format!("Unknown reference {}", reference))?;
+        if !self.store.has_object(&hash) {
+            bail!("Object {} is not in the repository (git hashes from \
+                   outside the stream can't be used)",
+                  reference);
+        }
+        Ok(hash)
+    }
+
+    /// Resolve a commit reference, or None for the null hash
+    fn resolve_commit(&self, reference: &str) -> Result<Option<ObjectKey>> {
+        if !reference.is_empty() && reference.chars().all(|c| c == '0') {
+            return Ok(None);
+        }
+        let hash = self.resolve(reference)?;
+        let node: ObjectWalkNode = self.store.lookup_node(hash)?;
+        if node.object_type != ObjectType::Commit {
+            bail!("{} is a {:?}, not a commit", reference, node.object_type);
+        }
+        Ok(Some(hash))
+    }
+
+    fn resolve_blob(&self, reference: &str) -> Result<ObjectKey> {
+        let hash = self.resolve(reference)?;
+        let node: ObjectWalkNode = self.store.lookup_node(hash)?;
+        if !node.object_type.is_blobish() {
+            bail!("{} is a {:?}, not a file", reference, node.object_type);
+        }
+        Ok(hash)
+    }
+}
+
+enum DataHeader {
+    Exact(u64),
+    Delimited(Vec<u8>),
+}
+
+/// Store the objects for a file and return the file's hash
+fn store_stream<I>(store: &ObjectStore, objects: I) -> Result<ObjectKey>
+    where I: Iterator<Item = io::Result<HashedObject>>
+{
+    let mut last_hash = None;
+    for object in objects {
+        last_hash = Some(store.store_object(&object?)?);
+    }
+    Ok(last_hash.expect("Iterator always emits objects"))
+}
+
+fn split_command(line: &str) -> (&str, &str) {
+    match line.find(' ') {
+        Some(i) => (&line[..i], &line[i + 1..]),
+        None => (line, ""),
+    }
+}
+
+/// Time from an identity such as `Name <email> 1500000000 +0200`
+///
+/// The number is already in seconds since the epoch, and the time zone only
+/// says how it was shown, so it is ignored. The epoch itself means no time,
+/// as `export` writes for commits without one, so those round-trip unchanged.
+fn identity_time(identity: &str) -> Option<u64> {
+    let date = match identity.rfind('>') {
+        Some(i) => &identity[i + 1..],
+        None => identity,
+    };
+    date.split_whitespace()
+        .next()
+        .and_then(|time| time.parse().ok())
+        .and_then(|time| if time == 0 { None } else { Some(time) })
+}
+
+fn parse_mark(mark: &str) -> Result<u64> {
+    if !mark.starts_with(':') {
+        bail!("Bad mark: {}", mark);
+    }
+    mark[1..].parse().chain_err(|| format!("Bad mark: {}", mark))
+}
+
+/// Parse a possibly-quoted path, returning it and the rest of the line
+///
+/// An unquoted path is the rest of the line if `last` is set, or else ends
+/// at the next space.
+fn parse_path(s: &str, last: bool) -> Result<(String, &str)> {
+    if !s.starts_with('"') {
+        if last {
+            return Ok((s.to_owned(), ""));
+        }
+        return match s.find(' ') {
+            Some(i) => Ok((s[..i].to_owned(), &s[i + 1..])),
+            None => bail!("Expected two paths: {}", s),
+        };
+    }
+    let mut bytes = Vec::new();
+    let mut chars = s[1..].char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => {
+                let path = String::from_utf8(bytes)
+                    .chain_err(|| format!("Bad UTF-8 in path: {}", s))?;
+                let rest = &s[i + 2..];
+                return Ok((path, rest.strip_prefix(' ').unwrap_or(rest)));
+            }
+            '\\' => {
+                let (_, e) = chars.next()
+                    .ok_or_else(|| format!("Bad quoted path: {}", s))?;
+                match e {
+                    'n' => bytes.push(b'\n'),
+                    't' => bytes.push(b'\t'),
+                    e if e.is_digit(8) => {
+                        // Three octal digits for one byte of UTF-8
+                        let mut octal = e.to_digit(8).unwrap_or(0);
+                        for _ in 0..2 {
+                            let d = chars.next()
+                                .and_then(|(_, d)| d.to_digit(8))
+                                .ok_or_else(|| {
+                                    format!("Bad quoted path: {}", s)
+                                })?;
+                            octal = octal * 8 + d;
+                        }
+                        bytes.push(octal as u8);
+                    }
+                    e => {
+                        let mut buf = [0; 4];
+                        bytes.extend(e.encode_utf8(&mut buf).as_bytes());
+                    }
+                }
+            }
+            c => {
+                let mut buf = [0; 4];
+                bytes.extend(c.encode_utf8(&mut buf).as_bytes());
+            }
+        }
+    }
+    bail!("Unterminated quoted path: {}", s)
+}
+
+/// Add a file, replacing anything in its way
+fn put_file(files: &mut FileMap, path: String, hash: ObjectKey) {
+    remove_path(files, &path);
+    for (i, _) in path.match_indices('/') {
+        files.remove(&path[..i]);
+    }
+    files.insert(path, hash);
+}
+
+/// Remove a file, or a directory and everything in it
+fn remove_path(files: &mut FileMap, path: &str) {
+    let prefix = format!("{}/", path);
+    let doomed = files.keys()
+        .filter(|p| *p == path || p.starts_with(&prefix))
+        .cloned()
+        .collect::<Vec<_>>();
+    for p in doomed {
+        files.remove(&p);
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use dag::Blob;
+    use object_store::test::create_temp_repository;
+    use rolling_hash::CHUNK_TARGET_SIZE;
+    use rolling_hash::read_file_objects;
+    use super::*;
+    use testutil::TestRand;
+
+    #[test]
+    fn test_export_import_round_trip() {
+        let (_src_temp, src) = create_temp_repository().unwrap();
+        let (_dest_temp, mut dest) = create_temp_repository().unwrap();
+
+        let mut rng = TestRand::default();
+        let big = rng.gen_byte_vec(CHUNK_TARGET_SIZE * 3);
+        let big_hash = store_stream(&src, read_file_objects(big.as_slice()))
+            .unwrap();
+        let small = src.store_object(&Blob::from("Hello!")).unwrap();
+        let other = src.store_object(&Blob::from("Other")).unwrap();
+
+        let mut files = FileMap::new();
+        files.insert("big".to_owned(), big_hash);
+        files.insert("dir/small".to_owned(), small);
+        let c1 = src.store_object(&Commit {
+                tree: store_file_map(&src, &files).unwrap(),
+                parents: vec![],
+                message: "First\n".to_owned(),
+                time: None,
+                signature: None,
+            })
+            .unwrap();
+        files.remove("big");
+        files.insert("dir/sub/with space".to_owned(), other);
+        let c2 = src.store_object(&Commit {
+                tree: store_file_map(&src, &files).unwrap(),
+                parents: vec![c1],
+                message: "Second".to_owned(),
+                time: Some(1_500_000_000),
+                signature: None,
+            })
+            .unwrap();
+
+        let mut stream = Vec::new();
+        let stats = export(&src, &[("master".to_owned(), c2)], &mut stream)
+            .unwrap();
+        assert_eq!(stats,
+                   StreamStats {
+                       commits: 2,
+                       files: 3,
+                   });
+
+        let result = import(&mut dest, stream.as_slice()).unwrap();
+        assert_eq!(result.stats, stats);
+        assert_eq!(result.refs, vec![("master".to_owned(), c2)]);
+        assert_eq!(dest.try_find_ref("master"), Some(c2));
+        assert!(dest.has_object(&big_hash), "Chunked file should be rebuilt");
+    }
+
+    #[test]
+    fn test_import_git_stream() {
+        let (_temp, mut store) = create_temp_repository().unwrap();
+        let stream = b"\
+            # written by hand, in the style of git fast-export\n\
+            blob\nmark :1\ndata 6\nHello!\n\
+            reset refs/heads/master\n\
+            commit refs/heads/master\nmark :2\n\
+            author A U Thor <author@example.com> 1500000000 +0200\n\
+            committer A U Thor <author@example.com> 1500000000 +0200\n\
+            data 6\nFirst\n\
+            M 100644 :1 \"quoted\\tname\"\n\
+            M 100755 inline dir/script\ndata <<EOF\n#!/bin/sh\nEOF\n\n\
+            commit refs/heads/master\nmark :3\n\
+            committer A U Thor <author@example.com> 1500000000 +0200\n\
+            data 7\nSecond\n\n\
+            R dir moved\n\
+            D \"quoted\\tname\"\n\n\
+            tag v1\nfrom :2\ntagger Tagger <t@example.com> 0 +0000\n\
+            data 0\n\
+            done\n";
+
+        let result = import(&mut store, &stream[..]).unwrap();
+        assert_eq!(result.stats,
+                   StreamStats {
+                       commits: 2,
+                       files: 2,
+                   });
+        let master = store.try_find_ref("master").unwrap();
+        let second = store.open_commit(&master).unwrap();
+        assert_eq!(second.message, "Second\n");
+        assert_eq!(second.time, Some(1_500_000_000));
+        let files = flatten_tree(&store, &second.tree).unwrap();
+        assert_eq!(files.keys().collect::<Vec<_>>(), vec!["moved/script"]);
+
+        let first = store.open_commit(&second.parents[0]).unwrap();
+        let files = flatten_tree(&store, &first.tree).unwrap();
+        assert_eq!(files.keys().collect::<Vec<_>>(),
+                   vec!["dir/script", "quoted\tname"]);
+        assert_eq!(store.try_find_ref("tags/v1"), Some(second.parents[0]));
+    }
+
+    #[test]
+    fn test_ref_names() {
+        assert_eq!(git_ref_name("master"), "refs/heads/master");
+        assert_eq!(git_ref_name("remotes/origin/master"),
+                   "refs/remotes/origin/master");
+        assert_eq!(local_ref_name("refs/heads/master"), "master");
+        assert_eq!(local_ref_name("refs/remotes/origin/master"),
+                   "remotes/origin/master");
+        assert_eq!(local_ref_name("refs/tags/v1"), "tags/v1");
+    }
+}
@@ -0,0 +1,277 @@
+//! Grep: searching the text files of a revision, or of the work dir
+//!
+//! Each file is streamed through a line matcher rather than read whole, so
+//! large files do not have to fit in memory. Like git, a file with a NUL byte
+//! near its start is taken to be binary and skipped, which in a media
+//! repository is most of them, and stops reading it early.
+
+use constants::BINARY_CHECK_BYTES;
+use dag::ObjectKey;
+use error::*;
+use file_store::FileStore;
+use file_store::FileWalkNode;
+use git_stream::flatten_tree;
+use object_store::ObjectStore;
+use regex::Regex;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::mem;
+use std::path::Path;
+use std::path::PathBuf;
+use walker::*;
+
+/// A line that matches a pattern
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct GrepMatch {
+    pub path: PathBuf,
+    /// Line number, starting at 1
+    pub line_number: usize,
+    pub line: String,
+}
+
+/// Does content that starts with these bytes look binary?
+pub fn looks_binary(start: &[u8]) -> bool {
+    start.iter().take(BINARY_CHECK_BYTES).any(|&b| b == 0)
+}
+
+/// Matches lines of content as it is written
+///
+/// Once content is found to be binary, writes fail, to stop the copy early.
+struct LineMatcher<'a> {
+    pattern: &'a Regex,
+    /// Content not yet matched: the start of the file until it is checked,
+    /// then any partial line
+    pending: Vec<u8>,
+    checked: bool,
+    binary: bool,
+    line_number: usize,
+    matches: Vec<(usize, String)>,
+}
+
+impl<'a> LineMatcher<'a> {
+    fn new(pattern: &'a Regex) -> Self {
+        LineMatcher {
+            pattern: pattern,
+            pending: Vec::new(),
+            checked: false,
+            binary: false,
+            line_number: 0,
+            matches: Vec::new(),
+        }
+    }
+
+    fn match_line(&mut self, line: &[u8]) {
+        self.line_number += 1;
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim_end_matches(['\n', '\r']);
+        if self.pattern.is_match(line) {
+            self.matches.push((self.line_number, line.to_owned()));
+        }
+    }
+
+    /// Match each complete line that is pending
+    fn match_lines(&mut self) {
+        let pending = mem::take(&mut self.pending);
+        let mut lines = pending.split(|&b| b == b'\n').peekable();
+        while let Some(line) = lines.next() {
+            if lines.peek().is_none() {
+                self.pending = line.to_vec();
+            } else {
+                self.match_line(line);
+            }
+        }
+    }
+
+    /// Match the last line, returning the matches, or None if binary
+    fn finish(mut self) -> Option<Vec<(usize, String)>> {
+        if !self.checked {
+            self.binary = looks_binary(&self.pending);
+        }
+        if self.binary {
+            return None;
+        }
+        self.match_lines();
+        if !self.pending.is_empty() {
+            let last = mem::take(&mut self.pending);
+            self.match_line(&last);
+        }
+        Some(self.matches)
+    }
+}
+
+impl<'a> Write for LineMatcher<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.binary {
+            return Err(io::Error::other("binary file"));
+        }
+        self.pending.extend_from_slice(buf);
+        if !self.checked {
+            if self.pending.len() < BINARY_CHECK_BYTES {
+                return Ok(buf.len());
+            }
+            self.checked = true;
+            self.binary = looks_binary(&self.pending);
+            if self.binary {
+                return Err(io::Error::other("binary file"));
+            }
+        }
+        self.match_lines();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+/// Give each match from a finished matcher to a callback
+fn report<F>(path: &Path, matcher: LineMatcher, found: &mut F) -> Result<()>
+    where F: FnMut(GrepMatch) -> Result<()>
+{
+    for (line_number, line) in matcher.finish().unwrap_or_default() {
+        found(GrepMatch {
+                path: path.to_owned(),
+                line_number: line_number,
+                line: line,
+            })?;
+    }
+    Ok(())
+}
+
+/// Search the text files of a stored tree for lines matching a pattern
+pub fn grep_tree<F>(store: &ObjectStore,
+                    tree: &ObjectKey,
+                    pattern: &Regex,
+                    mut found: F)
+                    -> Result<()>
+    where F: FnMut(GrepMatch) -> Result<()>
+{
+    for (path, hash) in flatten_tree(store, tree)? {
+        let mut matcher = LineMatcher::new(pattern);
+        if let Err(e) = store.copy_blob_content(&hash, &mut matcher) {
+            if !matcher.binary {
+                return Err(e);
+            }
+        }
+        report(Path::new(&path), matcher, &mut found)?;
+    }
+    Ok(())
+}
+
+/// Search the text files in a directory for lines matching a pattern
+///
+/// Ignored files are skipped. Paths are given relative to the directory.
+pub fn grep_dir<F>(file_store: &FileStore,
+                   dir: &Path,
+                   pattern: &Regex,
+                   mut found: F)
+                   -> Result<()>
+    where F: FnMut(GrepMatch) -> Result<()>
+{
+    let node: FileWalkNode = file_store.lookup_node(dir.to_owned())?;
+    let mut op = ListFilesOp { files: Vec::new() };
+    file_store.walk_node(&mut op, node)?;
+    for path in op.files {
+        let mut matcher = LineMatcher::new(pattern);
+        let mut file = File::open(dir.join(&path))?;
+        if let Err(e) = io::copy(&mut file, &mut matcher) {
+            if !matcher.binary {
+                return Err(e.into());
+            }
+        }
+        report(&path, matcher, &mut found)?;
+    }
+    Ok(())
+}
+
+/// Lists the files that are not ignored, in the order they are walked
+struct ListFilesOp {
+    files: Vec<PathBuf>,
+}
+
+impl WalkOp<FileWalkNode> for ListFilesOp {
+    type VisitResult = ();
+
+    fn should_descend(&mut self, _ps: &Path, node: &FileWalkNode) -> bool {
+        node.metadata.is_dir() && !node.ignored
+    }
+
+    fn no_descend(&mut self,
+                  ps: &Path,
+                  node: FileWalkNode)
+                  -> Result<Option<Self::VisitResult>> {
+        if node.metadata.is_file() && !node.ignored {
+            self.files.push(ps.to_owned());
+        }
+        Ok(None)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use work_dir::WorkDir;
+    use work_dir::test::create_temp_work_dir;
+    use work_dir::test::write_wd_file;
+
+    fn grep_bytes(content: &[u8], pattern: &str) -> Option<Vec<usize>> {
+        let pattern = Regex::new(pattern).unwrap();
+        let mut matcher = LineMatcher::new(&pattern);
+        for chunk in content.chunks(3) {
+            if matcher.write_all(chunk).is_err() {
+                break;
+            }
+        }
+        matcher.finish().map(|m| m.into_iter().map(|(n, _)| n).collect())
+    }
+
+    #[test]
+    fn test_line_matcher() {
+        let text = b"one\r\ntwo\nthree\n\nfour";
+        assert_eq!(grep_bytes(text, "o"), Some(vec![1, 2, 5]));
+        assert_eq!(grep_bytes(text, "^$"), Some(vec![4]));
+        assert_eq!(grep_bytes(text, "e$"), Some(vec![1, 3]));
+        assert_eq!(grep_bytes(b"", "x"), Some(vec![]));
+        assert_eq!(grep_bytes(b"one\0two", "o"), None);
+
+        let mut long = vec![b'x'; BINARY_CHECK_BYTES * 2];
+        long.push(b'\n');
+        long[BINARY_CHECK_BYTES - 1] = 0;
+        assert_eq!(grep_bytes(&long, "x"), None);
+        long[BINARY_CHECK_BYTES - 1] = b'\n';
+        long[BINARY_CHECK_BYTES + 10] = 0;
+        assert_eq!(grep_bytes(&long, "x"),
+                   Some(vec![1, 2]),
+                   "Only the start of a file should be checked");
+    }
+
+    #[test]
+    fn test_grep_tree_and_dir() {
+        let (_temp, wd_path, mut wd) = create_temp_work_dir().unwrap();
+        write_wd_file(&wd_path, "notes/a.txt", "cat\ndog\n");
+        write_wd_file(&wd_path, "photo.jpg", "\0dog");
+        let head = wd.commit("Notes".to_owned(), false, None).unwrap().hash;
+        write_wd_file(&wd_path, "b.txt", "hotdog");
+
+        let pattern = Regex::new("dog").unwrap();
+        let grep = |in_dir: bool| {
+            let mut found = Vec::new();
+            let push = |m: GrepMatch| {
+                found.push(format!("{}:{}:{}",
+                                   m.path.display(),
+                                   m.line_number,
+                                   m.line));
+                Ok(())
+            };
+            match in_dir {
+                true => grep_dir(&wd.file_store, &wd_path, &pattern, push),
+                false => grep_tree(&wd, &head, &pattern, push),
+            }
+            .unwrap();
+            found.sort();
+            found
+        };
+        assert_eq!(grep(false), vec!["notes/a.txt:2:dog"]);
+        assert_eq!(grep(true), vec!["b.txt:1:hotdog", "notes/a.txt:2:dog"]);
+    }
+}
@@ -0,0 +1,234 @@
+//! Hooks: programs run at points in a command, kept in the hidden directory
+//!
+//! A hook is an executable file in `.dmv/hooks`, named for the point where
+//! it runs. It runs in the top of the work dir, with these environment
+//! variables:
+//!
+//! - `DMV_REV`: the commit involved, in full hex (empty if none yet)
+//! - `DMV_BRANCH`: the current branch (empty if detached)
+//!
+//! The hooks are:
+//!
+//! - `pre-commit`: before a commit, with `DMV_REV` the parent. If it fails,
+//!   the commit is abandoned.
+//! - `post-commit`: after a commit, with `DMV_REV` the new commit.
+//! - `post-checkout`: after a checkout, with the old and new commits as
+//!   arguments (old is empty if there was none), and `DMV_REV` the new one.
+//!
+//! A hook that runs longer than `DMV_HOOK_TIMEOUT` seconds (default 60) is
+//! killed and counts as failed. Only `pre-commit` can stop a command: the
+//! other hooks run after the fact, so their failures are only reported.
+
+use constants::DEFAULT_HOOK_TIMEOUT_SECS;
+use dag::ObjectKey;
+use error::*;
+use human_readable::human_duration;
+use messages::Msg;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Child;
+use std::process::Command;
+use std::process::ExitStatus;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Environment variable giving the hook timeout in seconds
+pub const HOOK_TIMEOUT_VAR: &'static str = "DMV_HOOK_TIMEOUT";
+
+/// Points where hooks run
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub enum Hook {
+    PreCommit,
+    PostCommit,
+    PostCheckout,
+}
+
+impl Hook {
+    /// File name of the hook in the hooks directory
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Hook::PreCommit => "pre-commit",
+            Hook::PostCommit => "post-commit",
+            Hook::PostCheckout => "post-checkout",
+        }
+    }
+}
+
+/// The hooks of one repository
+pub struct Hooks {
+    dir: PathBuf,
+    work_dir: PathBuf,
+    timeout: Duration,
+}
+
+impl Hooks {
+    /// Hooks in the given directory, run in the given work dir
+    pub fn new(dir: PathBuf, work_dir: PathBuf) -> Self {
+        let timeout = env::var(HOOK_TIMEOUT_VAR)
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .unwrap_or(DEFAULT_HOOK_TIMEOUT_SECS);
+        Hooks {
+            dir: dir,
+            work_dir: work_dir,
+            timeout: Duration::from_secs(timeout),
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Path of a hook's executable, if it is present
+    pub fn find(&self, hook: Hook) -> Option<PathBuf> {
+        let path = self.dir.join(hook.name());
+        if !path.is_file() {
+            return None;
+        }
+        if !is_executable(&path) {
+            stderrln!("{}",
+                      msg!(Msg::HookNotExecutable,
+                           hook.name(),
+                           path.display()));
+            return None;
+        }
+        Some(path)
+    }
+
+    /// Run a hook if present, failing if it fails
+    pub fn run(&self,
+               hook: Hook,
+               args: &[String],
+               rev: Option<&ObjectKey>,
+               branch: Option<&str>)
+               -> Result<()> {
+        let path = match self.find(hook) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        debug!("Running {} hook: {}", hook.name(), path.display());
+        let mut child = Command::new(&path)
+            .args(args)
+            .current_dir(&self.work_dir)
+            .env("DMV_REV", rev.map(|r| r.to_hex()).unwrap_or_default())
+            .env("DMV_BRANCH", branch.unwrap_or(""))
+            .spawn()
+            .chain_err(|| format!("Could not run {} hook", hook.name()))?;
+
+        match wait_with_timeout(&mut child, self.timeout)? {
+            Some(status) if !status.success() => {
+                bail!(ErrorKind::HookFailed(hook.name().to_owned(),
+                                            status.to_string()))
+            }
+            Some(_) => Ok(()),
+            None => {
+                let why = format!("timed out after {}",
+                                  human_duration(self.timeout));
+                bail!(ErrorKind::HookFailed(hook.name().to_owned(), why))
+            }
+        }
+    }
+
+    /// Run a hook that cannot stop the command, only warning if it fails
+    pub fn run_after(&self,
+                     hook: Hook,
+                     args: &[String],
+                     rev: Option<&ObjectKey>,
+                     branch: Option<&str>) {
+        if let Err(e) = self.run(hook, args, rev, branch) {
+            stderrln!("{}", e);
+        }
+    }
+}
+
+/// Wait for a child process, killing it if it runs past the timeout
+///
+/// Returns its exit status, or None if it was killed.
+pub fn wait_with_timeout(child: &mut Child,
+                         timeout: Duration)
+                         -> io::Result<Option<ExitStatus>> {
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if start.elapsed() >= timeout {
+            child.kill().ok();
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[cfg(unix)]
+pub fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path).map(|meta| meta.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+pub fn is_executable(_path: &Path) -> bool { true }
+
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use super::*;
+
+    #[cfg(unix)]
+    fn write_hook(dir: &Path, hook: Hook, script: &str) {
+        use std::os::unix::fs::PermissionsExt;
+        let path = dir.join(hook.name());
+        let mut file = fs::File::create(&path).unwrap();
+        writeln!(file, "#!/bin/sh\n{}", script).unwrap();
+        drop(file);
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_hooks() {
+        let temp = in_mem_tempdir!();
+        let dir = temp.path().join("hooks");
+        fs::create_dir(&dir).unwrap();
+        let hooks = Hooks::new(dir.clone(), temp.path().to_owned())
+            .with_timeout(Duration::from_millis(500));
+        let rev = ObjectKey::from("d3486ae9136e7856bc42212385ea797094475802");
+
+        hooks.run(Hook::PreCommit, &[], None, None)
+            .expect("Missing hook should succeed");
+
+        write_hook(&dir,
+                   Hook::PostCheckout,
+                   "echo \"$1 $DMV_REV $DMV_BRANCH\" > out");
+        hooks.run(Hook::PostCheckout,
+                 &["old".to_owned()],
+                 Some(&rev),
+                 Some("master"))
+            .unwrap();
+        let mut out = String::new();
+        fs::File::open(temp.path().join("out"))
+            .and_then(|mut f| ::std::io::Read::read_to_string(&mut f, &mut out))
+            .unwrap();
+        assert_eq!(out, format!("old {} master\n", rev.to_hex()));
+
+        write_hook(&dir, Hook::PreCommit, "exit 1");
+        match hooks.run(Hook::PreCommit, &[], None, None) {
+            Err(Error(ErrorKind::HookFailed(ref name, _), _)) => {
+                assert_eq!(name, "pre-commit")
+            }
+            other => panic!("Expected hook failure, got {:?}", other),
+        }
+
+        write_hook(&dir, Hook::PreCommit, "sleep 5");
+        assert!(hooks.run(Hook::PreCommit, &[], None, None).is_err(),
+                "Hook should time out");
+    }
+}
@@ -1,43 +1,162 @@
+//! Paths that status, hashing, and commits skip
+//!
+//! A pattern is either a single name, which matches that name at any depth,
+//! or a full path. Besides dmv's own files, patterns are read from the
+//! `.dmvignore` file at the top of the work dir, one per line. A line with a
+//! `/` in it is a path from the top of the work dir. Blank lines and lines
+//! starting with `#` are skipped.
+//!
+//! Names and paths may use the same wildcards as pathspecs: `*` matches any
+//! run of characters within one name, and `?` matches any one character. So
+//! `*.tmp` ignores files ending in `.tmp` at any depth, and `renders/*/draft`
+//! the `draft` directory of each render.
+
 use constants;
-use std::collections::BTreeSet;
+use error::*;
+use pathspec::glob_match;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::io::BufRead;
+use std::io::BufReader;
 use std::path::Path;
 use std::path::PathBuf;
 
-pub struct IgnoreList(BTreeSet<PathBuf>);
+/// Patterns to ignore, each with where it came from
+pub struct IgnoreList {
+    patterns: BTreeMap<PathBuf, IgnoreSource>,
+    /// Directory below which single names are matched, usually the work dir
+    top: Option<PathBuf>,
+}
+
+/// Where an ignore pattern came from, to explain why a path is ignored
+#[derive(Debug,Clone,PartialEq,Eq,PartialOrd,Ord)]
+pub enum IgnoreSource {
+    /// Ignored by dmv itself, such as its hidden directory
+    BuiltIn,
+    /// A line of an ignore file, counting from 1
+    File(PathBuf, usize),
+}
+
+impl fmt::Display for IgnoreSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IgnoreSource::BuiltIn => write!(f, "built in"),
+            IgnoreSource::File(ref path, line) => {
+                write!(f, "{}:{}", path.display(), line)
+            }
+        }
+    }
+}
 
 impl IgnoreList {
-    pub fn empty() -> Self { IgnoreList(BTreeSet::new()) }
+    pub fn empty() -> Self {
+        IgnoreList {
+            patterns: BTreeMap::new(),
+            top: None,
+        }
+    }
+
+    /// Match single names only in the part of a path below `top`
+    ///
+    /// Otherwise a pattern like `tmp` would ignore everything in a work dir
+    /// that happens to be kept under `/tmp`.
+    pub fn set_top<P>(&mut self, top: P)
+        where P: Into<PathBuf>
+    {
+        self.top = Some(top.into());
+    }
 
     pub fn ignores<P: ?Sized>(&self, path: &P) -> bool
         where P: AsRef<Path>
+    {
+        self.matching(path).is_some()
+    }
+
+    /// The pattern that ignores a path, and where it came from
+    pub fn matching<P>(&self, path: &P) -> Option<(&Path, &IgnoreSource)>
+        where P: AsRef<Path> + ?Sized
     {
         let path = path.as_ref();
 
-        for pattern in &self.0 {
-            // Match full paths
-            if pattern == path {
-                debug!("Ignoring '{}' (full match)", path.display());
-                return true;
+        let below_top = self.top
+            .as_ref()
+            .and_then(|top| path.strip_prefix(top).ok())
+            .unwrap_or(path);
+
+        for (pattern, source) in &self.patterns {
+            // Match full paths, and everything under them
+            if path_matches(pattern, path) {
+                debug!("Ignoring '{}' (path match: {:?})",
+                       path.display(),
+                       pattern.display());
+                return Some((pattern, source));
             }
             // Match single component names
-            for component in path.iter() {
-                if component == pattern {
+            for component in below_top.iter() {
+                if name_matches(pattern.as_os_str(), component) {
                     debug!("Ignoring '{}' (component match: {:?})",
                            path.display(),
                            Path::new(component).display());
-                    return true;
+                    return Some((pattern, source));
                 }
             }
         }
-        false
+        None
     }
 
     pub fn insert<P>(&mut self, pattern: P) -> bool
         where P: Into<PathBuf>
+    {
+        self.insert_from(pattern, IgnoreSource::BuiltIn)
+    }
+
+    /// Add a pattern, unless it is already there from another source
+    pub fn insert_from<P>(&mut self, pattern: P, source: IgnoreSource) -> bool
+        where P: Into<PathBuf>
     {
         let pattern = pattern.into();
-        self.0.insert(pattern)
+        if self.patterns.contains_key(&pattern) {
+            return false;
+        }
+        self.patterns.insert(pattern, source);
+        true
     }
+
+    /// Add the patterns in an ignore file, with paths relative to `top`
+    pub fn read_file(&mut self, file: &Path, top: &Path) -> Result<()> {
+        let reader = BufReader::new(fs::File::open(file)?);
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let source = IgnoreSource::File(file.to_owned(), i + 1);
+            let name = line.trim_start_matches("./").trim_end_matches('/');
+            if name.contains('/') {
+                self.insert_from(top.join(name), source);
+            } else {
+                self.insert_from(name, source);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Does a name match a pattern, with wildcards?
+fn name_matches(pattern: &OsStr, name: &OsStr) -> bool {
+    match (pattern.to_str(), name.to_str()) {
+        (Some(pattern), Some(name)) => glob_match(pattern, name),
+        _ => pattern == name,
+    }
+}
+
+/// Is a path, or one of its parents, matched by a pattern with wildcards?
+fn path_matches(pattern: &Path, path: &Path) -> bool {
+    let mut path = path.iter();
+    pattern.iter().all(|p| path.next().is_some_and(|n| name_matches(p, n)))
 }
 
 impl Default for IgnoreList {
@@ -52,6 +171,7 @@ impl Default for IgnoreList {
 #[cfg(test)]
 mod test {
     use std::path::Path;
+    use testutil;
     use std::path::PathBuf;
     use super::*;
 
@@ -76,6 +196,68 @@ mod test {
 
         ignore.insert("./fully/specified/path");
         assert!(ignore.ignores("./fully/specified/path"));
+        assert!(ignore.ignores("./fully/specified/path/child"));
+        assert!(!ignore.ignores("./fully/specified"));
+    }
+
+    #[test]
+    fn test_ignore_wildcards() {
+        let mut ignore = IgnoreList::empty();
+        ignore.insert("*.tmp");
+        ignore.insert("IMG_????.xmp");
+        ignore.insert("./renders/*/draft");
+
+        assert!(ignore.ignores("a.tmp"));
+        assert!(ignore.ignores("./subdir/b.tmp"));
+        assert!(ignore.ignores("./cache.tmp/child"));
+        assert!(!ignore.ignores("./a.tmpl"));
+        assert!(ignore.ignores("./photos/IMG_0001.xmp"));
+        assert!(!ignore.ignores("./photos/IMG_001.xmp"));
+
+        assert!(ignore.ignores("./renders/2024/draft"));
+        assert!(ignore.ignores("./renders/2024/draft/frame.png"));
+        assert!(!ignore.ignores("./renders/2024/final"));
+        assert!(!ignore.ignores("./renders/draft"));
+    }
+
+    #[test]
+    fn test_ignore_file() {
+        let temp = in_mem_tempdir!();
+        let file = temp.path().join(".dmvignore");
+        testutil::write_file(&file,
+                             "# Scratch files\n\ntmp\n./renders/draft/\n\
+                              *.bak\n")
+            .unwrap();
+        let mut ignore = IgnoreList::default();
+        ignore.read_file(&file, temp.path()).unwrap();
+
+        let (pattern, source) = ignore.matching("photos/tmp/a.jpg").unwrap();
+        assert_eq!(pattern, Path::new("tmp"));
+        assert_eq!(source, &IgnoreSource::File(file.clone(), 3));
+        let (_, source) = ignore.matching(&temp.path().join("renders/draft/x"))
+            .unwrap();
+        assert_eq!(source.to_string(), format!("{}:4", file.display()));
+        assert!(!ignore.ignores(&temp.path().join("renders/final")));
+        let (pattern, _) = ignore.matching(&temp.path().join("doc/a.bak"))
+            .unwrap();
+        assert_eq!(pattern, Path::new("*.bak"));
+        assert_eq!(ignore.matching(".dmv").unwrap().1, &IgnoreSource::BuiltIn);
+    }
+
+    #[test]
+    fn test_ignore_names_only_below_top() {
+        let temp = in_mem_tempdir!();
+        let top = temp.path().join("tmp/wd");
+        let file = top.join(".dmvignore");
+        testutil::write_file(&file, "tmp\n").unwrap();
+        let mut ignore = IgnoreList::default();
+        ignore.set_top(&top);
+        ignore.read_file(&file, &top).unwrap();
+
+        assert!(!ignore.ignores(&top.join("a.txt")),
+                "Should not match the work dir's own parents");
+        assert!(ignore.ignores(&top.join("sub/tmp/a.txt")));
+        assert!(ignore.ignores(&top.join("tmp")));
     }
 
     #[test]
@@ -0,0 +1,228 @@
+//! Journal: a record of a checkout or merge, to recover if it is interrupted
+//!
+//! Before a checkout or merge writes to the work dir, it records what it is
+//! about to do, and the hash that each path it may change had in HEAD. The
+//! record is removed when the operation finishes. If the operation is
+//! interrupted, such as by a crash or a full disk, the record stays behind,
+//! and `dmv recover` uses it to finish the operation or to put the files back
+//! as they were.
+
+use dag::ObjectKey;
+use disk_backed::DiskBacked;
+use error::*;
+use messages::Msg;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use work_dir::StateEvent;
+use work_dir::WorkDir;
+
+/// An operation that changes the files in the work dir
+#[derive(Debug,Clone,PartialEq,Eq,Hash,RustcEncodable,RustcDecodable)]
+pub enum JournalOp {
+    /// Extract a tree, then point HEAD at its commit
+    Checkout {
+        tree: ObjectKey,
+        commit: ObjectKey,
+        branch: Option<String>,
+        subtree: Option<PathBuf>,
+    },
+    /// Apply the changes from a common ancestor to each of theirs
+    Merge {
+        common: Option<ObjectKey>,
+        theirs: Vec<ObjectKey>,
+    },
+}
+
+impl fmt::Display for JournalOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            JournalOp::Checkout { .. } => write!(f, "checkout"),
+            JournalOp::Merge { .. } => write!(f, "merge"),
+        }
+    }
+}
+
+/// An operation in progress, with what is needed to undo it
+#[derive(Debug,Clone,PartialEq,Eq,Hash,RustcEncodable,RustcDecodable)]
+pub struct JournalState {
+    pub op: JournalOp,
+    /// Parents, branch, and subtree of the work dir before the operation
+    pub old_parents: Vec<ObjectKey>,
+    pub old_branch: Option<String>,
+    pub old_subtree: Option<PathBuf>,
+    /// Paths the operation may change, with their hashes before it began
+    pub planned: Vec<(PathBuf, Option<ObjectKey>)>,
+}
+
+/// The journal of an operation in progress, in the hidden directory
+pub struct Journal {
+    state: DiskBacked<JournalState>,
+}
+
+impl Journal {
+    fn state_path(work_dir: &WorkDir) -> PathBuf {
+        work_dir.object_store.path().join("journal")
+    }
+
+    /// Was an operation in this work dir interrupted?
+    pub fn in_progress(work_dir: &WorkDir) -> bool {
+        Self::state_path(work_dir).exists()
+    }
+
+    /// Record an operation, and the paths it plans to change, before it
+    /// writes anything
+    pub fn begin(work_dir: &WorkDir,
+                 op: JournalOp,
+                 planned: Vec<(PathBuf, Option<ObjectKey>)>)
+                 -> Result<Self> {
+        if Self::in_progress(work_dir) {
+            bail!(msg!(Msg::Interrupted, Self::open(work_dir)?.state().op));
+        }
+        let state = JournalState {
+            op: op,
+            old_parents: work_dir.state.parents.clone(),
+            old_branch: work_dir.state.branch.clone(),
+            old_subtree: work_dir.state.subtree.clone(),
+            planned: planned,
+        };
+        let mut state =
+            DiskBacked::init("journal", Self::state_path(work_dir), state);
+        state.flush()?;
+        Ok(Journal { state: state })
+    }
+
+    /// Open the journal of an interrupted operation
+    pub fn open(work_dir: &WorkDir) -> Result<Self> {
+        if !Self::in_progress(work_dir) {
+            bail!("No interrupted checkout or merge to recover");
+        }
+        let state = DiskBacked::read("journal", Self::state_path(work_dir))?;
+        Ok(Journal { state: state })
+    }
+
+    pub fn state(&self) -> &JournalState { &self.state }
+
+    /// The operation finished, so its journal is no longer needed
+    pub fn finish(self, work_dir: &WorkDir) -> Result<()> {
+        fs::remove_file(Self::state_path(work_dir))
+            .chain_err(|| "Could not remove journal")
+    }
+
+    /// Carry out the interrupted operation again, to finish it
+    ///
+    /// A merge that conflicts is finished, and returns a `MergeConflict`, as
+    /// it would have the first time.
+    pub fn resume(self, work_dir: &mut WorkDir) -> Result<()> {
+        let abs_path = work_dir.path().to_owned();
+        match self.state.op.clone() {
+            JournalOp::Checkout { tree, commit, branch, subtree } => {
+                work_dir.extract_object(&tree, &abs_path)?;
+                work_dir.update_state(Some(StateEvent::Checkout), |state| {
+                        state.parents = vec![commit];
+                        state.branch = branch;
+                        state.subtree = subtree;
+                    })?;
+                self.finish(work_dir)
+            }
+            JournalOp::Merge { common, theirs } => {
+                let mut parents = self.state.old_parents.clone();
+                parents.extend(theirs.iter().cloned());
+                work_dir.update_state(None, |state| state.parents = parents)?;
+                let result = work_dir.apply_changes(common, &theirs, "Merging");
+                match result {
+                    Ok(()) |
+                    Err(Error(ErrorKind::MergeConflict(_), _)) => {
+                        self.finish(work_dir)?;
+                    }
+                    Err(_) => {}
+                }
+                result
+            }
+        }
+    }
+
+    /// Undo the interrupted operation
+    ///
+    /// Each path it planned to change is put back as it was in HEAD, and the
+    /// work dir's parents and branch are restored. Other files are left
+    /// alone.
+    pub fn roll_back(self, work_dir: &mut WorkDir) -> Result<()> {
+        for &(ref path, old) in &self.state.planned {
+            let abs_path = work_dir.path().join(path);
+            match old {
+                Some(hash) => {
+                    if let Some(parent) = abs_path.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    work_dir.extract_object(&hash, &abs_path)?;
+                }
+                None if abs_path.is_dir() => fs::remove_dir_all(&abs_path)?,
+                None if abs_path.exists() => fs::remove_file(&abs_path)?,
+                None => {}
+            }
+        }
+        let state = self.state.clone();
+        work_dir.update_state(None, |wd_state| {
+                wd_state.parents = state.old_parents;
+                wd_state.branch = state.old_branch;
+                wd_state.subtree = state.old_subtree;
+            })?;
+        self.finish(work_dir)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use work_dir::test::create_temp_work_dir;
+    use work_dir::test::read_wd_file;
+    use work_dir::test::write_wd_file;
+
+    #[test]
+    fn test_journal() {
+        let (_temp, wd_path, mut wd) = create_temp_work_dir().unwrap();
+
+        write_wd_file(&wd_path, "foo", "base");
+        write_wd_file(&wd_path, "bar", "base");
+        let base = wd.commit("Base".to_owned(), false, None).unwrap().hash;
+        write_wd_file(&wd_path, "foo", "next");
+        write_wd_file(&wd_path, "new", "next");
+        let next = wd.commit("Next".to_owned(), false, None).unwrap().hash;
+        write_wd_file(&wd_path, "bar", "mine");
+
+        // Interrupted partway through checking out base
+        let tree = wd.open_commit(&base).unwrap().tree;
+        let planned = wd.plan_changes(Some(next), &[base]).unwrap();
+        assert_eq!(planned.iter().map(|p| &p.0).collect::<Vec<_>>(),
+                   vec![&PathBuf::from("foo"), &PathBuf::from("new")],
+                   "Locally changed files should not be planned");
+        let op = JournalOp::Checkout {
+            tree: tree,
+            commit: base,
+            branch: None,
+            subtree: None,
+        };
+        Journal::begin(&wd, op.clone(), planned.clone()).unwrap();
+        assert!(Journal::in_progress(&wd));
+        assert!(Journal::begin(&wd, op.clone(), planned.clone()).is_err());
+        write_wd_file(&wd_path, "foo", "base");
+
+        Journal::open(&wd).unwrap().roll_back(&mut wd).unwrap();
+        assert!(!Journal::in_progress(&wd));
+        assert_eq!(read_wd_file(&wd_path, "foo"), "next");
+        assert_eq!(read_wd_file(&wd_path, "new"), "next");
+        assert_eq!(read_wd_file(&wd_path, "bar"), "mine");
+        assert_eq!(wd.parents(), &vec![next]);
+
+        Journal::begin(&wd, op, planned).unwrap();
+        fs::remove_file(wd_path.join("new")).unwrap();
+        Journal::open(&wd).unwrap().resume(&mut wd).unwrap();
+        assert!(!Journal::in_progress(&wd));
+        assert_eq!(read_wd_file(&wd_path, "foo"), "base");
+        assert!(!wd_path.join("new").exists());
+        assert_eq!(wd.parents(), &vec![base]);
+        assert_eq!(wd.branch(), None);
+    }
+}
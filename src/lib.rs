@@ -4,6 +4,8 @@
 #![allow(bare_trait_objects, ellipsis_inclusive_range_patterns)]
 // Suppress warnings from using old crates and imports
 #![allow(deprecated, unused_imports)]
+// Keep the house style of explicit field inits and 'static in constants
+#![allow(clippy::redundant_field_names, clippy::redundant_static_lifetimes)]
 
 // error_chain uses macro with a lot of recursion
 #![recursion_limit = "1024"]
@@ -77,15 +79,46 @@ pub mod variance;
 pub mod error;
 pub mod constants;
 #[macro_use]
+pub mod messages;
+pub mod config;
+pub mod encryption;
+#[macro_use]
 pub mod dag;
 pub mod rolling_hash;
+pub mod media_type;
+pub mod exif;
+pub mod bench;
 pub mod revisions;
+pub mod lock;
+pub mod hooks;
+pub mod locations;
+pub mod reflog;
+pub mod store_format;
+pub mod policy;
+pub mod storage;
+pub mod parity;
 pub mod object_store;
+pub mod query;
+pub mod signing;
 pub mod cache;
 pub mod ignore;
+pub mod pathspec;
 pub mod file_store;
 pub mod status;
 pub mod fs_transfer;
+pub mod derive;
 pub mod work_dir;
+pub mod journal;
+pub mod bisect;
+pub mod rebase;
+pub mod stash;
+pub mod repository;
+pub mod transport;
+pub mod git_stream;
+pub mod archive;
+pub mod backup;
+pub mod grep;
+#[cfg(all(feature = "mount", target_os = "linux"))]
+pub mod mount;
 pub mod find_repo;
 pub mod cmd;
@@ -0,0 +1,92 @@
+//! Tracking which remotes are known to have which objects
+//!
+//! Every fetch and push records the objects it saw on the other side, so that
+//! `whereis` can say where a file's content can be found, and a partial
+//! store can say where to fetch the content it left out.
+//!
+//! The record is only as fresh as the last transfer: a remote may have
+//! removed objects since, or received them from somewhere else.
+
+use dag::ObjectKey;
+use disk_backed::DiskBacked;
+use error::*;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+
+/// Map of object to the remotes known to have it
+pub type LocationMap = BTreeMap<ObjectKey, BTreeSet<String>>;
+
+/// The on-disk location database for an object store
+pub struct Locations(DiskBacked<LocationMap>);
+
+impl Locations {
+    /// Open the database at the given path, or start an empty one
+    pub fn open(path: PathBuf) -> Result<Self> {
+        Ok(Locations(DiskBacked::read_or_default("locations", path)?))
+    }
+
+    /// Start an empty location database that is kept in memory only
+    #[cfg(any(test, feature = "testing"))]
+    pub fn in_memory() -> Self {
+        Locations(DiskBacked::in_memory("locations", Default::default()))
+    }
+
+    /// Remotes known to have the object, by name (or path/URL if unnamed)
+    pub fn remotes_for(&self, key: &ObjectKey) -> BTreeSet<String> {
+        self.0.get(key).cloned().unwrap_or_default()
+    }
+
+    /// Record that a remote has the given objects
+    pub fn record<I>(&mut self, remote: &str, keys: I) -> Result<()>
+        where I: IntoIterator<Item = ObjectKey>
+    {
+        for key in keys {
+            self.0
+                .entry(key)
+                .or_default()
+                .insert(remote.to_owned());
+        }
+        self.0.flush().map_err(|e| e.into())
+    }
+
+    /// Forget everything recorded about a remote, such as when it is removed
+    pub fn forget_remote(&mut self, remote: &str) -> Result<()> {
+        for remotes in self.0.values_mut() {
+            remotes.remove(remote);
+        }
+        self.0.retain(|_, remotes| !remotes.is_empty());
+        self.0.flush().map_err(|e| e.into())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use dag::ObjectKey;
+    use super::*;
+
+    #[test]
+    fn test_locations() {
+        let temp = in_mem_tempdir!();
+        let path = temp.path().join("locations");
+        let a = ObjectKey::from("d3486ae9136e7856bc42212385ea797094475802");
+        let b = ObjectKey::from("9b8d0d7e6b8e3c3a28c9c6b1d7c9a2d0e1f2a3b4");
+        {
+            let mut locations = Locations::open(path.clone()).unwrap();
+            locations.record("origin", vec![a, b]).unwrap();
+            locations.record("backup", vec![a]).unwrap();
+        }
+
+        let mut locations = Locations::open(path.clone()).unwrap();
+        let both: BTreeSet<String> = vec!["backup".to_owned(),
+                                          "origin".to_owned()]
+            .into_iter()
+            .collect();
+        assert_eq!(locations.remotes_for(&a), both);
+
+        locations.forget_remote("origin").unwrap();
+        assert_eq!(locations.remotes_for(&a).len(), 1);
+        assert!(locations.remotes_for(&b).is_empty());
+    }
+}
@@ -0,0 +1,283 @@
+//! Locking to keep concurrent commands from interfering with each other
+//!
+//! Commands that change the repository (commit, fetch, push, checkout...)
+//! take an exclusive lock, so that two writers can't interleave their
+//! changes. Only one exclusive lock can be held at a time.
+//!
+//! Read-only commands (status, log, ls-files) take a shared lock, which never
+//! waits for a writer. Objects are installed atomically and only after
+//! everything they reference, so a reader sees a consistent view, though it
+//! may be missing the results of a transfer that is still in progress. The
+//! shared lock reports the active writer, if any, so the command can warn
+//! that its output may be partial.
+//!
+//! The exclusive lock is a file in the hidden directory that records the
+//! process ID and command of the holder. If a process dies without releasing
+//! it, the file must be removed by hand.
+//!
+//! Small shared files that are rewritten whole, such as refs and the work dir
+//! state, are also locked for the moment it takes to update them. See
+//! `FileLock`.
+
+use error::*;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+/// Name of the exclusive lock file in the hidden directory
+pub const LOCK_FILE_NAME: &'static str = "lock";
+
+/// Whether a lock allows other holders
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum LockMode {
+    /// For read-only commands: does not block or wait for anyone
+    Shared,
+    /// For commands that write: excludes other exclusive locks
+    Exclusive,
+}
+
+/// Information about the process holding an exclusive lock
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct LockHolder {
+    pub pid: u32,
+    pub command: String,
+}
+
+impl LockHolder {
+    fn parse(s: &str) -> Self {
+        let mut parts = s.trim().splitn(2, ' ');
+        LockHolder {
+            pid: parts.next().and_then(|p| p.parse().ok()).unwrap_or(0),
+            command: parts.next().unwrap_or("unknown").to_owned(),
+        }
+    }
+}
+
+impl fmt::Display for LockHolder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (pid {})", self.command, self.pid)
+    }
+}
+
+/// A held lock on a repository, released when dropped
+#[derive(Debug)]
+pub struct RepoLock {
+    /// None for a repository that no other process can see
+    path: Option<PathBuf>,
+    mode: LockMode,
+    writer: Option<LockHolder>,
+}
+
+impl RepoLock {
+    /// Take a lock on the repository with the given hidden directory
+    ///
+    /// The command name is recorded for exclusive locks, to show in error
+    /// messages if another command tries to take the lock.
+    pub fn acquire(osd: &Path, mode: LockMode, command: &str) -> Result<Self> {
+        let path = osd.join(LOCK_FILE_NAME);
+        match mode {
+            LockMode::Shared => {
+                let writer = read_holder(&path)?;
+                if let Some(ref writer) = writer {
+                    debug!("Taking shared lock while {} is writing", writer);
+                }
+                Ok(RepoLock {
+                    path: Some(path),
+                    mode: mode,
+                    writer: writer,
+                })
+            }
+            LockMode::Exclusive => {
+                match try_lock_file(&path, command)? {
+                    None => {
+                        debug!("Took exclusive lock for {}", command);
+                        Ok(RepoLock {
+                            path: Some(path),
+                            mode: mode,
+                            writer: None,
+                        })
+                    }
+                    Some(holder) => {
+                        bail!(ErrorKind::RepoLocked(holder.to_string(), path))
+                    }
+                }
+            }
+        }
+    }
+
+    /// Take a lock on a repository that only this process can see, such as
+    /// one kept in memory, which is always free
+    pub fn in_process(mode: LockMode) -> Self {
+        RepoLock {
+            path: None,
+            mode: mode,
+            writer: None,
+        }
+    }
+
+    pub fn mode(&self) -> LockMode { self.mode }
+
+    /// For a shared lock, the writer that was active when it was taken
+    pub fn writer(&self) -> Option<&LockHolder> { self.writer.as_ref() }
+}
+
+impl Drop for RepoLock {
+    fn drop(&mut self) {
+        if let (LockMode::Exclusive, Some(ref path)) = (self.mode, &self.path) {
+            if let Err(e) = fs::remove_file(path) {
+                warn!("Could not remove lock file {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// A lock on a single file, held while it is read, changed, and rewritten
+///
+/// Small shared files such as refs are rewritten whole, so two processes that
+/// update them at the same time could each lose the other's change. Holding
+/// this lock around the read-modify-write cycle prevents that. The lock is a
+/// `<file>.lock` file that records the holder, like the exclusive repository
+/// lock, but it is only held briefly, so a busy lock is waited on for a
+/// moment before giving up.
+#[derive(Debug)]
+pub struct FileLock {
+    path: PathBuf,
+}
+
+/// How many times to try a busy file lock before giving up
+const FILE_LOCK_TRIES: u32 = 50;
+
+/// How long to wait between tries of a busy file lock
+const FILE_LOCK_WAIT_MS: u64 = 20;
+
+impl FileLock {
+    /// Lock the given file, recording the command in case of conflicts
+    pub fn acquire(target: &Path, command: &str) -> Result<Self> {
+        let mut path = target.as_os_str().to_owned();
+        path.push(".lock");
+        let path = PathBuf::from(path);
+        let mut tries = 0;
+        loop {
+            match try_lock_file(&path, command)? {
+                None => {
+                    trace!("Locked {} for {}", target.display(), command);
+                    return Ok(FileLock { path: path });
+                }
+                Some(holder) => {
+                    tries += 1;
+                    if tries >= FILE_LOCK_TRIES {
+                        bail!(ErrorKind::RepoLocked(holder.to_string(), path))
+                    }
+                    thread::sleep(Duration::from_millis(FILE_LOCK_WAIT_MS));
+                }
+            }
+        }
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Could not remove lock file {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Create a lock file recording this process, or give the current holder
+fn try_lock_file(path: &Path, command: &str) -> Result<Option<LockHolder>> {
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path);
+    match file {
+        Ok(mut file) => {
+            writeln!(file, "{} {}", process::id(), command)?;
+            Ok(None)
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            let holder = read_holder(path)?
+                .unwrap_or_else(|| LockHolder::parse(""));
+            Ok(Some(holder))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Read the holder of the exclusive lock, if any
+fn read_holder(path: &Path) -> Result<Option<LockHolder>> {
+    match fs::File::open(path) {
+        Ok(mut file) => {
+            let mut contents = String::new();
+            file.read_to_string(&mut contents)?;
+            Ok(Some(LockHolder::parse(&contents)))
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_shared_and_exclusive() {
+        let temp = in_mem_tempdir!();
+        let osd = temp.path();
+
+        let shared = RepoLock::acquire(osd, LockMode::Shared, "status")
+            .unwrap();
+        assert_eq!(shared.writer(), None);
+
+        let exclusive = RepoLock::acquire(osd, LockMode::Exclusive, "fetch")
+            .unwrap();
+        assert!(osd.join(LOCK_FILE_NAME).is_file());
+
+        match RepoLock::acquire(osd, LockMode::Exclusive, "commit") {
+            Err(Error(ErrorKind::RepoLocked(holder, _), _)) => {
+                assert!(holder.starts_with("fetch"), "{}", holder);
+            }
+            other => panic!("Expected RepoLocked, got {:?}", other),
+        }
+
+        let shared = RepoLock::acquire(osd, LockMode::Shared, "status")
+            .unwrap();
+        assert_eq!(shared.writer().map(|w| w.command.as_str()), Some("fetch"));
+        assert_eq!(shared.writer().map(|w| w.pid), Some(process::id()));
+
+        drop(exclusive);
+        assert!(!osd.join(LOCK_FILE_NAME).exists());
+        drop(shared);
+        RepoLock::acquire(osd, LockMode::Exclusive, "commit").unwrap();
+    }
+
+    #[test]
+    fn test_file_lock() {
+        let temp = in_mem_tempdir!();
+        let target = temp.path().join("refs");
+        let lock_path = temp.path().join("refs.lock");
+
+        let lock = FileLock::acquire(&target, "update ref").unwrap();
+        assert!(lock_path.is_file());
+        match FileLock::acquire(&target, "update ref") {
+            Err(Error(ErrorKind::RepoLocked(holder, path), _)) => {
+                assert!(holder.contains(&process::id().to_string()),
+                        "{}",
+                        holder);
+                assert_eq!(path, lock_path);
+            }
+            other => panic!("Expected RepoLocked, got {:?}", other),
+        }
+        drop(lock);
+        assert!(!lock_path.exists());
+        FileLock::acquire(&target, "update ref").unwrap();
+    }
+}
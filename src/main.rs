@@ -9,10 +9,24 @@ extern crate clap;
 extern crate error_chain;
 extern crate env_logger;
 extern crate dmv;
+extern crate human_readable;
 
+use dmv::archive::ArchiveFormat;
 use dmv::cmd;
+use dmv::config::EncryptionScope;
 use dmv::constants::*;
+use dmv::dag::HashAlgorithm;
 use dmv::error::*;
+use dmv::find_repo;
+use dmv::progress;
+use dmv::progress::ProgressMode;
+use dmv::object_store::CommitFilter;
+use dmv::object_store::FsckOptions;
+use dmv::pathspec::Pathspec;
+use dmv::query::FileQuery;
+use dmv::revisions::RevSpec;
+use dmv::store_format::FanOut;
+use std::path::Path;
 use std::path::PathBuf;
 
 // Have error_chain create a main() function that handles Results
@@ -21,28 +35,82 @@ quick_main!(run);
 fn run() -> Result<()> {
     env_logger::init();
 
-    let argmatch = clap_app!(
+    let version = format!("{} ({}) ({})",
+                          crate_version!(),
+                          PROJECT_GIT_LOG,
+                          BUILD_PROFILE);
+    let mut app = clap_app!(
         (crate_name!()) =>
             (author: crate_authors!())
-            (version: format!("{} ({}) ({})",
-                        crate_version!(), PROJECT_GIT_LOG, BUILD_PROFILE
-                        ).as_str())
+            (version: version.as_str())
             (about: crate_description!())
+            (@arg repo: --repo +takes_value +global
+                    "work dir of the repository to use, instead of searching \
+                     from the current directory (also set by DMV_DIR and \
+                     DMV_WORK_TREE)")
+            (@arg quiet: -q --quiet +global "do not show progress")
+            (@arg progress: --progress +takes_value +global
+                    possible_value[auto ansi plain json none]
+                    "how to show progress: redrawn with ANSI codes, as plain \
+                     lines now and then, as JSON lines, or not at all \
+                     (default: auto, ANSI on a terminal and plain otherwise)")
         )
         .subcommand(clap_app!(init =>
                 (about: "initialize repository")
+                (@arg chunk_size: --("chunk-size") +takes_value
+                        "target chunk size in bytes for large files")
+                (@arg hash: --hash +takes_value
+                        possible_value[sha1 sha256]
+                        "hash algorithm for object keys (default sha256)")
+                (@arg preserve_metadata: --("preserve-metadata")
+                        "record and restore file times and permissions")
+                (@arg extract_metadata: --("extract-metadata")
+                        "record EXIF date, camera, and GPS position of \
+                         photos when committing")
+                (@arg encrypted: --encrypted
+                        "encrypt file contents with a passphrase (taken from \
+                         DMV_PASSPHRASE, or asked for)")
+                (@arg encrypt_metadata: --("encrypt-metadata")
+                        requires[encrypted]
+                        "also encrypt file names and commit messages")
+                (@arg fan_out: --("fan-out") +takes_value
+                        "directory levels and digits per level for object \
+                         files, such as 2x2 (default 1x2)")
         ))
         .subcommand(clap_app!(
             ("hash-object") =>
                 (about: "store a file or directory in the object store")
-                (@arg filepath: +required)
+                (@arg filepath: required_unless[stdin]
+                        "path to store, or - for stdin")
+                (@arg stdin: --stdin conflicts_with[filepath]
+                        "store content read from stdin")
+        ))
+        .subcommand(clap_app!(
+            ("import-dir") =>
+                (about: "store a directory in the object store, printing \
+                         the hash of each file and then of the directory")
+                (@arg dir: +required "directory to store")
         ))
         .subcommand(clap_app!(
             ("show-object") =>
                 (about: "print information about an object")
                 (@arg type: -t "print just type information")
+                (@arg raw: --raw conflicts_with[type]
+                        "print raw object content (ala git cat-file)")
+                (@arg recursive: -r --recursive conflicts_with[type raw]
+                        "list every object below a tree or commit, with its \
+                         hash and size")
+                (@arg depth: --depth +takes_value conflicts_with[type raw]
+                        "list only this many levels below (implies \
+                         --recursive)")
                 (@arg obj: +required)
         ))
+        .subcommand(clap_app!(
+            show =>
+                (about: "print a file from any revision (rev:path), without \
+                         checking it out")
+                (@arg rev: +required "revision and path, like master~1:a.txt")
+        ))
         .subcommand(clap_app!(
             parents =>
                 (about: "show current parent commits")
@@ -51,40 +119,222 @@ fn run() -> Result<()> {
             ("ls-files") =>
                 (about: "list files")
                 (@arg verbose: -v "include additional information")
+                (@arg tree: --tree conflicts_with[verbose]
+                        "show as a tree, with cumulative directory sizes")
+                (@arg sort_size: --("sort-size") requires[tree]
+                        "list largest files and directories first")
                 (@arg obj:)
+                (@arg paths: +multiple +last conflicts_with[tree]
+                        "list only these paths, or paths matching these \
+                         patterns, such as photos/2024 or '*.jpg'")
+        ))
+        .subcommand(clap_app!(
+            ("wait-for-ref") =>
+                (about: "wait until a ref moves, then print its new commit")
+                (@arg name: +required "ref to watch, such as master")
+                (@arg from: --from +takes_value
+                        "wait until it points somewhere other than this \
+                         (default: where it points now)")
+        ))
+        .subcommand(clap_app!(
+            grep =>
+                (about: "print lines of text files that match a pattern")
+                (@arg pattern: +required "regular expression to search for")
+                (@arg rev: "revision to search (default: the work dir)")
+                (@arg cached: --cached conflicts_with[rev]
+                        "search HEAD rather than the work dir")
+        ))
+        .subcommand(clap_app!(
+            du =>
+                (about: "show storage retained by each top-level directory")
+                (@arg obj: "revision to measure (default: HEAD)")
+        ))
+        .subcommand(clap_app!(
+            ("find-duplicates") =>
+                (about: "list files stored under more than one path, \
+                         anywhere in history")
+                (@arg min_size: --("min-size") +takes_value
+                        "ignore files smaller than this, such as 1M \
+                         (default 1 byte)")
+        ))
+        .subcommand(clap_app!(
+            query =>
+                (about: "find files in history by size, media type, or \
+                         metadata, without checking them out")
+                (@arg min_size: --("min-size") +takes_value
+                        "only files at least this big, such as 100MiB")
+                (@arg max_size: --("max-size") +takes_value
+                        "only files at most this big")
+                (@arg type: --type +takes_value
+                        "only files of this media type, such as video or \
+                         image/jpeg")
+                (@arg meta: --meta +takes_value +multiple
+                        number_of_values(1)
+                        "only files whose metadata field contains the text, \
+                         such as camera-model=EOS (may be repeated)")
+                (@arg rev: --rev +takes_value
+                        "search the history of this revision, instead of \
+                         every ref")
         ))
         .subcommand(clap_app!(
             ("extract-object") =>
                 (about: "extract a file or tree")
                 (@arg obj: +required)
-                (@arg filepath: +required)
+                (@arg filepath: +required
+                        "path to extract to, or - for stdout (files only)")
+        ))
+        .subcommand(clap_app!(
+            archive =>
+                (about: "write the files of a revision to a tar or zip archive")
+                (@arg rev: +required "revision, or revision:path")
+                (@arg output: -o --output +takes_value
+                        "file to write (default: stdout)")
+                (@arg format: --format +takes_value possible_value[tar zip]
+                        "archive format (default: from output file name)")
+                (@arg prefix: --prefix +takes_value
+                        "prefix for paths in the archive, such as 'snap/'")
         ))
         .subcommand(clap_app!(
             ("cache-status") =>
                 (about: "show cache status of a file")
+                (@arg paranoid: --paranoid
+                        "rehash the file to verify the cached hash")
                 (@arg filepath: +required)
         ))
+        .subcommand(clap_app!(
+            ("prime-cache") =>
+                (about: "cache hashes of files that match HEAD, \
+                         such as after copying the work dir to a new disk")
+        ))
+        .subcommand(clap_app!(
+            ("verify-work-dir") =>
+                (about: "rehash a random sample of files that the cache says \
+                         are unchanged, to find corruption on disk")
+                (@arg all: --all conflicts_with[sample]
+                        "rehash all cached files")
+                (@arg sample: --sample +takes_value
+                        "percent of cached files to rehash (default 5)")
+        ))
+        .subcommand(clap_app!(
+            ("prune-cache") =>
+                (about: "drop cached hashes of files that no longer exist, \
+                         and evict the least recently used over a limit")
+                (@arg max_entries: --("max-entries") +takes_value
+                        "most entries to keep (default 1000000)")
+        ))
         .subcommand(clap_app!(
             status =>
                 (about: "show status of files")
                 (@arg ignored: -i --ignored "show ignored files")
-                (@arg rev1:)
-                (@arg rev2:)
+                (@arg watch: -w --watch conflicts_with[args]
+                        "keep watching, and show status again when files \
+                         change")
+                (@arg args: +multiple
+                        "up to two revisions to compare, then paths or \
+                         patterns to show, such as photos/2024 or '*.jpg'")
+                (@arg paths: +multiple +last
+                        "paths or patterns, for paths that could be taken \
+                         for revisions")
         ))
         .subcommand(clap_app!(
             commit =>
                 (about: "commit current files to the repository")
-                (@arg message: -m <MESSAGE> +required)
+                (@arg message: -m +takes_value required_unless[amend]
+                        "commit message")
+                (@arg amend: --amend
+                        "replace the last commit, keeping its message unless \
+                         -m is given")
+                (@arg verbose: -v --verbose
+                        "report how many chunks of large files were reused")
+                (@arg verify: --verify
+                        "re-read and check new objects before updating refs")
+                (@arg sign: -S --sign
+                        "sign the commit, with the key named by \
+                         DMV_SIGNING_KEY (default: gpg's default key)")
+                (@arg force: -f --force
+                        "commit even if over the limits of the size policy")
+                (@arg paths: +multiple
+                        "commit only these paths, or paths matching these \
+                         patterns, keeping the last commit's version of the \
+                         rest")
+        ))
+        .subcommand(clap_app!(
+            policy =>
+                (about: "show or set limits on how much a commit may add")
+                (@arg max_file_size: --("max-file-size") +takes_value
+                        "largest new or changed file, such as 2G, or none")
+                (@arg max_commit_size: --("max-commit-size") +takes_value
+                        "most bytes of new or changed files, or none")
+                (@arg refuse: --refuse conflicts_with[warn]
+                        "refuse commits over a limit, unless forced")
+                (@arg warn: --warn "only warn about commits over a limit")
+        ))
+        .subcommand(clap_app!(
+            mv =>
+                (about: "move a file or directory in the work dir, keeping \
+                         its cached hashes so it is not hashed again")
+                (@arg from: +required "file or directory to move")
+                (@arg to: +required "new path, or a directory to move into")
+        ))
+        .subcommand(clap_app!(
+            rm =>
+                (about: "remove files or directories from the work dir")
+                (@arg force: -f --force
+                        "remove even files with changes that are not \
+                         committed")
+                (@arg paths: +required +multiple "files or directories")
+        ))
+        .subcommand(clap_app!(
+            clean =>
+                (about: "list, or with -f remove, files that are not \
+                         committed")
+                (@arg force: -f --force "remove the files, not just list them")
+                (@arg dirs: -d "also remove directories that are not committed")
+                (@arg ignored: -x "also remove ignored files")
+        ))
+        .subcommand(clap_app!(
+            reflog =>
+                (about: "show earlier commits of a branch, such as ones \
+                         replaced by commit --amend")
+                (@arg branch: "branch to show (default: current branch)")
         ))
         .subcommand(clap_app!(
             log =>
                 (about: "show commit history")
                 (@arg hash_only: --("hash-only")
                         "print only hash IDs (ala git rev-list)")
+                (@arg path: --path +takes_value
+                        "show only commits that changed this path (relative \
+                         to the top of the repository)")
+                (@arg max_count: -n --("max-count") +takes_value
+                        "show only this many commits")
+                (@arg since: --since +takes_value
+                        "show only commits made at or after this UTC time \
+                         (YYYY-MM-DD or YYYY-MM-DD HH:MM:SS)")
+                (@arg until: --until +takes_value
+                        "show only commits made at or before this UTC time")
+                (@arg verify: --verify conflicts_with[hash_only]
+                        "check commit signatures, and show the signers")
+                (@arg stat: --stat conflicts_with[hash_only]
+                        "count the files each commit added, modified, and \
+                         deleted, compared to its first parent")
+                (@arg range: "revision or range (from..to) to show \
+                              (default: all branches)")
+        ))
+        .subcommand(clap_app!(
+            ("gen-signing-key") =>
+                (about: "generate an ed25519 key for signing commits")
+                (@arg path: +required "key file to create")
         ))
         .subcommand(clap_app!(
             branch =>
                 (about: "show/update branch information")
+                (@arg verbose: -v --verbose
+                        "show each branch's commit, when it was stored, and \
+                         how it compares to HEAD and its tracking ref")
+                (@arg contains: --contains +takes_value conflicts_with[branch]
+                        "list only branches whose history includes this \
+                         commit")
                 (@arg branch:)
                 (@arg rev:)
         ))
@@ -92,26 +342,319 @@ fn run() -> Result<()> {
             ("show-ref") =>
                 (about: "show refs")
         ))
+        .subcommand(clap_app!(
+            ("ls-remote") =>
+                (about: "list refs in another repository")
+                (@arg remote: +required "remote name, path, or URL")
+        ))
+        .subcommand(clap_app!(
+            remote =>
+                (about: "list, add, or remove named remotes")
+                (@arg verbose: -v --verbose "show URLs and default branches")
+                (@subcommand add =>
+                    (about: "add a named remote")
+                    (@arg name: +required "name for the remote")
+                    (@arg url: +required "path or URL of the repository")
+                    (@arg branch: -b --branch +takes_value
+                            "branch to push when not on a branch"))
+                (@subcommand remove =>
+                    (about: "remove a remote and its tracking refs")
+                    (@arg name: +required "name of the remote"))
+                (@subcommand list =>
+                    (about: "list remotes")
+                    (@arg verbose: -v --verbose
+                            "show URLs and default branches"))
+        ))
+        .subcommand(clap_app!(
+            fetch =>
+                (about: "download objects and update remote-tracking refs")
+                (@arg remote: +required "remote name, path, or URL")
+                (@arg depth: --depth +takes_value
+                        "fetch only this many commits of history")
+                (@arg partial: --partial
+                        "fetch commits and trees, but not file content")
+                (@arg limit_rate: --("limit-rate") +takes_value
+                        value_name("RATE")
+                        "limit transfer to RATE bytes per second, with an \
+                         optional suffix like 500k or 2M")
+        ))
+        .subcommand(clap_app!(
+            missing =>
+                (about: "list files whose content was left out by a partial \
+                         fetch, and the remotes that have it")
+                (@arg rev: +required "revision to check")
+        ))
+        .subcommand(clap_app!(
+            ("check-ignore") =>
+                (about: "show which ignore pattern applies to each path, and \
+                         where it came from")
+                (@arg paths: +multiple +required "paths to check")
+        ))
+        .subcommand(clap_app!(
+            whereis =>
+                (about: "list the remotes known to have a file's content")
+                (@arg target: +required "revision, or path of a file in HEAD")
+        ))
+        .subcommand(clap_app!(
+            meta =>
+                (about: "work with metadata extracted from media files")
+                (@setting SubcommandRequiredElseHelp)
+                (@subcommand show =>
+                    (about: "print the metadata recorded for a file")
+                    (@arg target: +required
+                            "path of a file in HEAD, or rev:path"))
+        ))
+        .subcommand(clap_app!(
+            derive =>
+                (about: "print the hash of an object derived from a file, \
+                         such as a thumbnail, deriving it if needed")
+                (@arg target: +required "path of a file in HEAD, or rev:path")
+                (@arg name: -n --name +takes_value
+                        "derivation to make, from .dmv/derivers/<name> \
+                         (default: thumbnail)")
+                (@arg output: -o --output +takes_value
+                        "extract the derived object to this path instead")
+        ))
+        .subcommand(clap_app!(
+            cat =>
+                (about: "write a file's content to stdout")
+                (@arg target: +required "revision, or path of a file in HEAD")
+                (@arg range: --range +takes_value value_name("OFFSET[+LEN]")
+                        "write only LEN bytes starting at OFFSET, each with \
+                         an optional suffix like 500k or 2M")
+        ))
+        .subcommand(clap_app!(
+            clone =>
+                (about: "copy a repository into a new work dir")
+                (@arg source: +required "path or URL of repository to copy")
+                (@arg dest: +required "directory to create")
+                (@arg depth: --depth +takes_value
+                        "fetch only this many commits of history")
+                (@arg limit_rate: --("limit-rate") +takes_value
+                        value_name("RATE")
+                        "limit transfer to RATE bytes per second, with an \
+                         optional suffix like 500k or 2M")
+        ))
+        .subcommand(clap_app!(
+            ("fast-export") =>
+                (about: "write history as a git fast-import stream to stdout")
+                (@arg branch: ... "branches to export (default: all local)")
+        ))
+        .subcommand(clap_app!(
+            ("fast-import") =>
+                (about: "read a git fast-export stream from stdin")
+        ))
+        .subcommand(clap_app!(
+            push =>
+                (about: "upload a branch to another repository")
+                (@arg remote: +required "remote name, path, or URL")
+                (@arg branch: "branch to push (default: current branch)")
+                (@arg force: -f --force
+                        "overwrite the remote branch even if not fast-forward")
+                (@arg lease: --("force-with-lease") +takes_value
+                        conflicts_with[force]
+                        value_name("REF:HASH")
+                        "overwrite only if the remote branch is at HASH \
+                         (default: its remote-tracking ref)")
+                (@arg limit_rate: --("limit-rate") +takes_value
+                        value_name("RATE")
+                        "limit transfer to RATE bytes per second, with an \
+                         optional suffix like 500k or 2M")
+        ))
+        .subcommand(clap_app!(
+            bundle =>
+                (about: "carry history to a repository with no connection, \
+                         as a file")
+                (@setting SubcommandRequiredElseHelp)
+                (@subcommand create =>
+                    (about: "write the objects of a branch to a bundle, \
+                             leaving out those before the start of a range")
+                    (@arg range: +required
+                            "branch, or range such as backup/master..master")
+                    (@arg output: -o --output +takes_value +required
+                            "file to write, or - for stdout"))
+                (@subcommand apply =>
+                    (about: "store the objects of a bundle, checking their \
+                             hashes, and update its branches")
+                    (@arg bundle: +required "file to read, or - for stdin")
+                    (@arg force: -f --force
+                            "update branches even if not fast-forward"))
+        ))
+        .subcommand(clap_app!(
+            serve =>
+                (about: "serve a repository to another dmv, as for ssh \
+                         remotes")
+                (@arg stdio: --stdio +required
+                        "speak the transfer protocol on stdin and stdout")
+                (@arg path: "repository to serve (default: current \
+                             directory)")
+        ))
         .subcommand(clap_app!(
             fsck =>
                 (about: "verify repository integrity")
+                (@arg repair: --repair
+                        "move corrupt objects into the corrupt directory, \
+                         and rebuild objects from parity")
+                (@arg porcelain: --porcelain
+                        "give output in an easy-to-parse format for scripts")
+                (@arg jobs: -j --jobs +takes_value
+                        "objects to verify at once (default: one per CPU)")
+                (@arg incremental: --incremental
+                        "only verify objects written since the last check; \
+                         run a full check now and then to catch decay in \
+                         older objects")
+        ))
+        .subcommand(clap_app!(
+            parity =>
+                (about: "erasure-code objects, so that fsck --repair can \
+                         rebuild corrupt or missing ones")
+                (@setting SubcommandRequiredElseHelp)
+                (@subcommand build =>
+                    (about: "cover objects that are not yet in a parity \
+                             group")
+                    (@arg group_size: --("group-size") +takes_value
+                            "objects in each group (default 16)")
+                    (@arg parity: --parity +takes_value
+                            "parity shards in each group, which is how many \
+                             of its objects can be lost (default 2)"))
+        ))
+        .subcommand(clap_app!(
+            backup =>
+                (about: "copy new objects, then refs, to a backup directory")
+                (@arg dest: +required "backup directory")
+        ))
+        .subcommand(clap_app!(
+            repack =>
+                (about: "move object files into a new directory layout")
+                (@arg fan_out: --("fan-out") +takes_value
+                        "directory levels and digits per level, such as 2x2 \
+                         (default: finish an interrupted repack)")
         ))
         .subcommand(clap_app!(
             checkout =>
                 (about: "check out another revision")
                 (@arg rev:)
+                (@arg force: -f --force
+                        "check out even if local changes would be lost")
+                (@arg detach: --detach
+                        "check out a branch's commit without switching to \
+                         the branch")
+        ))
+        .subcommand(clap_app!(
+            bisect =>
+                (about: "binary search history for the commit that \
+                         introduced a change, such as a corrupted file")
+                (@setting SubcommandRequiredElseHelp)
+                (@subcommand start =>
+                    (about: "start a bisect")
+                    (@arg bad: "a commit with the change")
+                    (@arg good: +multiple "commits without the change"))
+                (@subcommand bad =>
+                    (about: "mark a commit as having the change")
+                    (@arg rev: "commit to mark (default: HEAD)"))
+                (@subcommand good =>
+                    (about: "mark a commit as not having the change")
+                    (@arg rev: "commit to mark (default: HEAD)"))
+                (@subcommand reset =>
+                    (about: "end the bisect and check out the original \
+                             branch"))
+        ))
+        .subcommand(clap_app!(
+            rebase =>
+                (about: "replay the current branch's commits on top of \
+                         another commit")
+                (@arg onto: "commit to replay onto")
+                (@arg continue: --continue conflicts_with[onto abort]
+                        "commit the resolved conflicts and carry on")
+                (@arg abort: --abort conflicts_with[onto]
+                        "give up, and go back to where the branch was")
+        ))
+        .subcommand(clap_app!(
+            recover =>
+                (about: "finish a checkout or merge that was interrupted")
+                (@arg rollback: --rollback
+                        "undo it instead, putting back the files it changed")
+        ))
+        .subcommand(clap_app!(
+            stash =>
+                (about: "set uncommitted changes aside, to bring them back \
+                         later")
+                (@setting SubcommandRequiredElseHelp)
+                (@subcommand save =>
+                    (about: "save the changes, and go back to HEAD")
+                    (@arg message: -m --message +takes_value
+                            "description of the changes"))
+                (@subcommand pop =>
+                    (about: "apply a stash to the work dir, and drop it")
+                    (@arg name: "stash to apply (default: the newest)"))
+                (@subcommand list =>
+                    (about: "list stashes, newest first"))
         ))
         .subcommand(clap_app!(
             ("merge-base") =>
                 (about: "find common ancestor")
-                (@arg rev: +multiple +required)
+                (@arg rev: +multiple +required
+                        "revisions, or ranges (a..b) standing for both ends")
         ))
         .subcommand(clap_app!(
             merge =>
                 (about: "combine revisions")
                 (@arg rev: +multiple +required)
         ))
-        .get_matches();
+        .subcommand(clap_app!(
+            info =>
+                (about: "show where the repository is kept, and how much is \
+                         in it")
+                (@arg recount: --recount
+                        "count every object again, rather than trusting the \
+                         running totals")
+        ))
+        .subcommand(clap_app!(
+            stats =>
+                (about: "show how much space deduplication is saving")
+                (@arg top: -n --top +takes_value
+                        "number of largest files to list (default: 10)")
+        ))
+        .subcommand(clap_app!(
+            ("verify-chunking") =>
+                (about: "re-chunk stored files to check that chunking has \
+                         not changed")
+                (@arg rev: +required "file or tree to check")
+        ))
+        .subcommand(clap_app!(
+            bench =>
+                (about: "measure performance on this machine")
+                (@setting SubcommandRequiredElseHelp)
+                (@subcommand hash =>
+                    (about: "measure hashing and chunking throughput for \
+                             each digest backend (chosen by \
+                             DMV_DIGEST_BACKEND)")
+                    (@arg size: --size +takes_value
+                            "MiB of sample data to process (default: 64)"))
+        ))
+        .subcommand(clap_app!(
+            doctor =>
+                (about: "check the repository for things that need attention")
+        ));
+    #[cfg(all(feature = "mount", target_os = "linux"))]
+    {
+        app = app.subcommand(clap_app!(
+            mount =>
+                (about: "mount the tree of a revision as a read-only \
+                         filesystem, until unmounted")
+                (@arg rev: +required "revision to mount")
+                (@arg mountpoint: +required "empty directory to mount it on")
+        ));
+    }
+    let argmatch = app.clone().get_matches();
+    if let Some(repo) = argmatch.value_of("repo") {
+        find_repo::set_repo_path(PathBuf::from(repo));
+    }
+    if argmatch.is_present("quiet") {
+        progress::set_progress_mode(ProgressMode::Quiet);
+    } else if let Some(mode) = argmatch.value_of("progress") {
+        progress::set_progress_mode(mode.parse()?);
+    }
 
     match argmatch.subcommand_name() {
         Some(name) => {
@@ -119,51 +662,148 @@ fn run() -> Result<()> {
             let subfn = match name {
                 "init" => cmd_init,
                 "hash-object" => cmd_hash_object,
+                "import-dir" => cmd_import_dir,
                 "show-object" => cmd_show_object,
+                "show" => cmd_show,
                 "parents" => cmd_parents,
                 "ls-files" => cmd_ls_files,
+                "du" => cmd_du,
+                "find-duplicates" => cmd_find_duplicates,
+                "query" => cmd_query,
                 "extract-object" => cmd_extract_object,
+                "archive" => cmd_archive,
                 "cache-status" => cmd_cache_status,
+                "prime-cache" => cmd_prime_cache,
+                "verify-work-dir" => cmd_verify_work_dir,
+                "prune-cache" => cmd_prune_cache,
                 "status" => cmd_status,
                 "commit" => cmd_commit,
+                "mv" => cmd_mv,
+                "rm" => cmd_rm,
+                "policy" => cmd_policy,
+                "clean" => cmd_clean,
                 "log" => cmd_log,
+                "reflog" => cmd_reflog,
+                "gen-signing-key" => cmd_gen_signing_key,
                 "branch" => cmd_branch,
                 "show-ref" => cmd_show_ref,
+                "ls-remote" => cmd_ls_remote,
+                "remote" => cmd_remote,
+                "fetch" => cmd_fetch,
+                "missing" => cmd_missing,
+                "check-ignore" => cmd_check_ignore,
+                "whereis" => cmd_whereis,
+                "meta" => cmd_meta,
+                "derive" => cmd_derive,
+                "cat" => cmd_cat,
+                #[cfg(all(feature = "mount", target_os = "linux"))]
+                "mount" => cmd_mount,
+                "clone" => cmd_clone,
+                "fast-export" => cmd_fast_export,
+                "fast-import" => cmd_fast_import,
+                "push" => cmd_push,
+                "bundle" => cmd_bundle,
+                "serve" => cmd_serve,
                 "fsck" => cmd_fsck,
+                "parity" => cmd_parity,
+                "repack" => cmd_repack,
+                "backup" => cmd_backup,
                 "checkout" => cmd_checkout,
+                "bisect" => cmd_bisect,
+                "rebase" => cmd_rebase,
+                "grep" => cmd_grep,
+                "wait-for-ref" => cmd_wait_for_ref,
+                "recover" => cmd_recover,
+                "stash" => cmd_stash,
                 "merge-base" => cmd_merge_base,
                 "merge" => cmd_merge,
+                "info" => cmd_info,
+                "stats" => cmd_stats,
+                "verify-chunking" => cmd_verify_chunking,
+                "bench" => cmd_bench,
+                "doctor" => cmd_doctor,
                 _ => unimplemented!(),
             };
             let submatch = argmatch.subcommand_matches(name)
                 .expect("just matched");
             subfn(&argmatch, submatch)
         }
-        None => unimplemented!(),
+        None => {
+            // No subcommand: show help, then hints for where the user is
+            app.print_help().chain_err(|| "Could not print help")?;
+            println!("\n");
+            cmd::overview()
+        }
     }
 }
 
 fn cmd_init(_argmatch: &clap::ArgMatches,
-            _submatch: &clap::ArgMatches)
+            submatch: &clap::ArgMatches)
             -> Result<()> {
-    cmd::init()
+    let chunk_size = submatch.value_of("chunk_size")
+        .and_then_try(|s| {
+            s.parse::<usize>()
+                .map_err(|e| Error::from(format!("Bad chunk size: {}", e)))
+        })?;
+    let hash = submatch.value_of("hash")
+        .and_then_try(|s| s.parse::<HashAlgorithm>())?;
+    let preserve_metadata = submatch.is_present("preserve_metadata");
+    let extract_metadata = submatch.is_present("extract_metadata");
+    let encryption = match (submatch.is_present("encrypted"),
+                            submatch.is_present("encrypt_metadata")) {
+        (_, true) => Some(EncryptionScope::All),
+        (true, false) => Some(EncryptionScope::Content),
+        (false, false) => None,
+    };
+    let fan_out = submatch.value_of("fan_out")
+        .and_then_try(|s| s.parse::<FanOut>())?;
+    cmd::init(chunk_size,
+              hash,
+              preserve_metadata,
+              extract_metadata,
+              encryption,
+              fan_out)
 }
 
 fn cmd_hash_object(_argmatch: &clap::ArgMatches,
                    submatch: &clap::ArgMatches)
                    -> Result<()> {
-    let file_path = submatch.value_of("filepath").expect("required");
+    let file_path = match submatch.is_present("stdin") {
+        true => "-",
+        false => submatch.value_of("filepath").expect("required"),
+    };
     let file_path = PathBuf::from(file_path);
 
     cmd::hash_object(file_path)
 }
 
+fn cmd_import_dir(_argmatch: &clap::ArgMatches,
+                  submatch: &clap::ArgMatches)
+                  -> Result<()> {
+    let dir = submatch.value_of("dir").expect("required");
+    cmd::import_dir(Path::new(dir))
+}
+
 fn cmd_show_object(_argmatch: &clap::ArgMatches,
                    submatch: &clap::ArgMatches)
                    -> Result<()> {
     let obj_spec = submatch.value_of("obj").expect("required").parse()?;
     let type_only = submatch.is_present("type");
-    cmd::show_object(&obj_spec, type_only)
+    let raw = submatch.is_present("raw");
+    let depth = submatch.value_of("depth")
+        .and_then_try(|s| {
+            s.parse::<usize>()
+                .map_err(|e| Error::from(format!("Bad depth: {}", e)))
+        })?;
+    let recursive = submatch.is_present("recursive") || depth.is_some();
+    cmd::show_object(&obj_spec, type_only, raw, recursive, depth)
+}
+
+fn cmd_show(_argmatch: &clap::ArgMatches,
+            submatch: &clap::ArgMatches)
+            -> Result<()> {
+    let rev = submatch.value_of("rev").expect("required").parse()?;
+    cmd::show(&rev)
 }
 
 fn cmd_parents(_argmatch: &clap::ArgMatches,
@@ -177,8 +817,75 @@ fn cmd_ls_files(_argmatch: &clap::ArgMatches,
                 -> Result<()> {
     let obj_spec = submatch.value_of("obj").and_then_try(|r| r.parse())?;
     let verbose = submatch.is_present("verbose");
+    let tree = submatch.is_present("tree");
+    let sort_size = submatch.is_present("sort_size");
+    let pathspec = pathspec_arg(submatch);
+
+    cmd::ls_files(obj_spec, verbose, tree, sort_size, &pathspec)
+}
+
+fn cmd_wait_for_ref(_argmatch: &clap::ArgMatches,
+                    submatch: &clap::ArgMatches)
+                    -> Result<()> {
+    let name = submatch.value_of("name").expect("required");
+    let from = submatch.value_of("from").and_then_try(|r| r.parse())?;
+    cmd::wait_for_ref(name, from.as_ref())
+}
+
+fn cmd_grep(_argmatch: &clap::ArgMatches,
+            submatch: &clap::ArgMatches)
+            -> Result<()> {
+    let pattern = submatch.value_of("pattern").expect("required");
+    let rev = submatch.value_of("rev").and_then_try(|r| r.parse())?;
+    cmd::grep(pattern, rev, submatch.is_present("cached"))
+}
+
+fn cmd_du(_argmatch: &clap::ArgMatches,
+          submatch: &clap::ArgMatches)
+          -> Result<()> {
+    let obj_spec = submatch.value_of("obj").and_then_try(|r| r.parse())?;
+    cmd::du(obj_spec)
+}
 
-    cmd::ls_files(obj_spec, verbose)
+fn cmd_find_duplicates(_argmatch: &clap::ArgMatches,
+                       submatch: &clap::ArgMatches)
+                       -> Result<()> {
+    let min_size = match submatch.value_of("min_size") {
+        None => 1,
+        Some(s) => {
+            human_readable::parse_bytes(s)
+                .ok_or_else(|| format!("Bad minimum size: {}", s))?
+        }
+    };
+    cmd::find_duplicates(min_size)
+}
+
+fn cmd_query(_argmatch: &clap::ArgMatches,
+             submatch: &clap::ArgMatches)
+             -> Result<()> {
+    let size = |name: &str| -> Result<Option<u64>> {
+        submatch.value_of(name).and_then_try(|s| {
+            human_readable::parse_bytes(s)
+                .ok_or_else(|| format!("Bad size: {}", s).into())
+        })
+    };
+    let mut query = FileQuery {
+        min_size: size("min_size")?,
+        max_size: size("max_size")?,
+        media_type: submatch.value_of("type").map(|s| s.to_owned()),
+        metadata: Vec::new(),
+    };
+    for meta in submatch.values_of("meta").into_iter().flat_map(|v| v) {
+        let mut split = meta.splitn(2, '=');
+        match (split.next(), split.next()) {
+            (Some(field), Some(text)) => {
+                query.metadata.push((field.to_owned(), text.to_owned()))
+            }
+            _ => bail!("Bad metadata condition, expected field=text: {}", meta),
+        }
+    }
+    let rev = submatch.value_of("rev").and_then_try(|s| s.parse::<RevSpec>())?;
+    cmd::query(rev.as_ref(), &query)
 }
 
 fn cmd_extract_object(_argmatch: &clap::ArgMatches,
@@ -191,36 +898,191 @@ fn cmd_extract_object(_argmatch: &clap::ArgMatches,
     cmd::extract_object(&obj_spec, &file_path)
 }
 
+fn cmd_archive(_argmatch: &clap::ArgMatches,
+               submatch: &clap::ArgMatches)
+               -> Result<()> {
+    let rev = submatch.value_of("rev").expect("required").parse()?;
+    let output = submatch.value_of("output").map(PathBuf::from);
+    let format = submatch.value_of("format")
+        .and_then_try(|s| s.parse::<ArchiveFormat>())?;
+    let prefix = submatch.value_of("prefix");
+    cmd::archive(&rev, output.as_ref().map(|p| p.as_path()), format, prefix)
+}
+
 fn cmd_cache_status(_argmatch: &clap::ArgMatches,
                     submatch: &clap::ArgMatches)
                     -> Result<()> {
     let file_path = submatch.value_of("filepath").expect("required");
     let file_path = PathBuf::from(file_path);
+    let paranoid = submatch.is_present("paranoid");
+
+    cmd::cache_status(file_path, paranoid)
+}
+
+fn cmd_prime_cache(_argmatch: &clap::ArgMatches,
+                   _submatch: &clap::ArgMatches)
+                   -> Result<()> {
+    cmd::prime_cache()
+}
 
-    cmd::cache_status(file_path)
+fn cmd_verify_work_dir(_argmatch: &clap::ArgMatches,
+                       submatch: &clap::ArgMatches)
+                       -> Result<()> {
+    let sample_percent = match submatch.value_of("sample") {
+        _ if submatch.is_present("all") => None,
+        None => Some(DEFAULT_VERIFY_SAMPLE_PERCENT),
+        Some(s) => {
+            match s.parse() {
+                Ok(percent) if percent <= 100 => Some(percent),
+                _ => bail!("Bad sample percent: {}", s),
+            }
+        }
+    };
+    cmd::verify_work_dir(sample_percent)
+}
+
+fn cmd_prune_cache(_argmatch: &clap::ArgMatches,
+                   submatch: &clap::ArgMatches)
+                   -> Result<()> {
+    let max_entries = match submatch.value_of("max_entries") {
+        None => DEFAULT_MAX_CACHE_ENTRIES,
+        Some(s) => {
+            s.parse()
+                .chain_err(|| format!("Bad maximum cache entries: {}", s))?
+        }
+    };
+    cmd::prune_cache(max_entries)
 }
 
 fn cmd_status(_argmatch: &clap::ArgMatches,
               submatch: &clap::ArgMatches)
               -> Result<()> {
     let show_ignored = submatch.is_present("ignored");
-    let rev1 = submatch.value_of("rev1").and_then_try(|s| s.parse())?;
-    let rev2 = submatch.value_of("rev2").and_then_try(|s| s.parse())?;
-    cmd::status(show_ignored, rev1, rev2)
+    if submatch.is_present("watch") {
+        return cmd::status_watch(show_ignored);
+    }
+    let args = submatch.values_of("args")
+        .map_or(Vec::new(), |args| args.map(|a| a.to_owned()).collect());
+    cmd::status(show_ignored, &args, &path_args(submatch))
 }
 
 fn cmd_commit(_argmatch: &clap::ArgMatches,
               submatch: &clap::ArgMatches)
               -> Result<()> {
-    let message = submatch.value_of("message").expect("required").to_owned();
-    cmd::commit(message)
+    let message = submatch.value_of("message").map(|m| m.to_owned());
+    let amend = submatch.is_present("amend");
+    let verbose = submatch.is_present("verbose");
+    let verify = submatch.is_present("verify");
+    let sign = submatch.is_present("sign");
+    let force = submatch.is_present("force");
+    cmd::commit(message,
+                amend,
+                verbose,
+                verify,
+                sign,
+                force,
+                &path_args(submatch))
+}
+
+fn cmd_policy(_argmatch: &clap::ArgMatches,
+              submatch: &clap::ArgMatches)
+              -> Result<()> {
+    let limit = |name: &str| -> Result<Option<Option<u64>>> {
+        submatch.value_of(name).and_then_try(|s| match s {
+            "none" => Ok(None),
+            _ => {
+                human_readable::parse_bytes(s)
+                    .map(Some)
+                    .ok_or_else(|| format!("Bad size: {}", s).into())
+            }
+        })
+    };
+    let refuse = match (submatch.is_present("refuse"),
+                        submatch.is_present("warn")) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    };
+    cmd::policy(limit("max_file_size")?, limit("max_commit_size")?, refuse)
+}
+
+fn cmd_mv(_argmatch: &clap::ArgMatches,
+          submatch: &clap::ArgMatches)
+          -> Result<()> {
+    let from = submatch.value_of("from").expect("required");
+    let to = submatch.value_of("to").expect("required");
+    cmd::mv(Path::new(from), Path::new(to))
+}
+
+fn cmd_rm(_argmatch: &clap::ArgMatches,
+          submatch: &clap::ArgMatches)
+          -> Result<()> {
+    let paths = submatch.values_of("paths")
+        .expect("required")
+        .map(PathBuf::from)
+        .collect::<Vec<_>>();
+    cmd::rm(&paths, submatch.is_present("force"))
+}
+
+fn cmd_clean(_argmatch: &clap::ArgMatches,
+             submatch: &clap::ArgMatches)
+             -> Result<()> {
+    cmd::clean(submatch.is_present("dirs"),
+               submatch.is_present("ignored"),
+               submatch.is_present("force"))
+}
+
+/// Paths or patterns given as the `paths` argument, or everything if none
+fn pathspec_arg(submatch: &clap::ArgMatches) -> Pathspec {
+    submatch.values_of("paths").map_or(Pathspec::all(), Pathspec::new)
+}
+
+/// Paths or patterns given as the `paths` argument, as given
+///
+/// These are taken from the current directory, by `WorkDir::pathspec`.
+fn path_args(submatch: &clap::ArgMatches) -> Vec<String> {
+    submatch.values_of("paths")
+        .map_or(Vec::new(), |paths| paths.map(|p| p.to_owned()).collect())
+}
+
+fn cmd_reflog(_argmatch: &clap::ArgMatches,
+              submatch: &clap::ArgMatches)
+              -> Result<()> {
+    cmd::reflog(submatch.value_of("branch"))
 }
 
 fn cmd_log(_argmatch: &clap::ArgMatches,
            submatch: &clap::ArgMatches)
            -> Result<()> {
     let hash_only = submatch.is_present("hash_only");
-    cmd::log(hash_only)
+    let max_count = submatch.value_of("max_count")
+        .and_then_try(|s| {
+            s.parse::<usize>()
+                .map_err(|e| Error::from(format!("Bad count: {}", e)))
+        })?;
+    let date = |name: &str| -> Result<Option<u64>> {
+        submatch.value_of(name).and_then_try(|s| {
+            human_readable::parse_date(s)
+                .ok_or_else(|| format!("Bad date: {}", s).into())
+        })
+    };
+    let filter = CommitFilter {
+        path: submatch.value_of("path").map(PathBuf::from),
+        since: date("since")?,
+        until: date("until")?,
+        max_count: max_count,
+    };
+    let range = submatch.value_of("range").and_then_try(|r| r.parse())?;
+    let verify = submatch.is_present("verify");
+    let stat = submatch.is_present("stat");
+    cmd::log(hash_only, range, filter, verify, stat)
+}
+
+fn cmd_gen_signing_key(_argmatch: &clap::ArgMatches,
+                       submatch: &clap::ArgMatches)
+                       -> Result<()> {
+    let path = Path::new(submatch.value_of("path").expect("required"));
+    cmd::gen_signing_key(path)
 }
 
 fn cmd_branch(_argmatch: &clap::ArgMatches,
@@ -228,8 +1090,11 @@ fn cmd_branch(_argmatch: &clap::ArgMatches,
               -> Result<()> {
     let branch_name = submatch.value_of("branch").map(|s| s.to_owned());
     let target_rev = submatch.value_of("rev").and_then_try(|r| r.parse())?;
+    let contains = submatch.value_of("contains").and_then_try(|r| r.parse())?;
     match (branch_name, target_rev) {
-        (None, None) => cmd::branch_list(),
+        (None, None) => {
+            cmd::branch_list(submatch.is_present("verbose"), contains.as_ref())
+        }
         (Some(branch_name), None) => cmd::branch_set_to_head(branch_name),
         (Some(branch_name), Some(target)) => {
             cmd::branch_set(branch_name, target)
@@ -244,17 +1109,290 @@ fn cmd_show_ref(_argmatch: &clap::ArgMatches,
     cmd::show_ref()
 }
 
+fn cmd_ls_remote(_argmatch: &clap::ArgMatches,
+                 submatch: &clap::ArgMatches)
+                 -> Result<()> {
+    let remote = submatch.value_of("remote").expect("required");
+    cmd::ls_remote(remote)
+}
+
+fn cmd_remote(_argmatch: &clap::ArgMatches,
+              submatch: &clap::ArgMatches)
+              -> Result<()> {
+    match submatch.subcommand() {
+        ("add", Some(m)) => {
+            cmd::remote_add(m.value_of("name").expect("required"),
+                            m.value_of("url").expect("required"),
+                            m.value_of("branch"))
+        }
+        ("remove", Some(m)) => {
+            cmd::remote_remove(m.value_of("name").expect("required"))
+        }
+        ("list", Some(m)) => cmd::remote_list(m.is_present("verbose")),
+        _ => cmd::remote_list(submatch.is_present("verbose")),
+    }
+}
+
+fn cmd_fetch(_argmatch: &clap::ArgMatches,
+             submatch: &clap::ArgMatches)
+             -> Result<()> {
+    let remote = submatch.value_of("remote").expect("required");
+    let depth = submatch.value_of("depth")
+        .and_then_try(|s| {
+            s.parse::<usize>()
+                .map_err(|e| Error::from(format!("Bad depth: {}", e)))
+        })?;
+    if depth == Some(0) {
+        bail!("Depth must be at least 1");
+    }
+    let partial = submatch.is_present("partial");
+    let limit_rate = parse_limit_rate(submatch)?;
+    cmd::fetch(remote, depth, partial, limit_rate)
+}
+
+fn cmd_missing(_argmatch: &clap::ArgMatches,
+               submatch: &clap::ArgMatches)
+               -> Result<()> {
+    let rev = submatch.value_of("rev").expect("required").parse()?;
+    cmd::missing(&rev)
+}
+
+fn cmd_check_ignore(_argmatch: &clap::ArgMatches,
+                    submatch: &clap::ArgMatches)
+                    -> Result<()> {
+    let paths = submatch.values_of("paths")
+        .expect("required")
+        .map(PathBuf::from)
+        .collect::<Vec<_>>();
+    cmd::check_ignore(&paths)
+}
+
+fn cmd_whereis(_argmatch: &clap::ArgMatches,
+               submatch: &clap::ArgMatches)
+               -> Result<()> {
+    let target = submatch.value_of("target").expect("required");
+    cmd::whereis(target)
+}
+
+fn cmd_meta(_argmatch: &clap::ArgMatches,
+            submatch: &clap::ArgMatches)
+            -> Result<()> {
+    match submatch.subcommand() {
+        ("show", Some(m)) => {
+            cmd::meta_show(m.value_of("target").expect("required"))
+        }
+        _ => unreachable!("subcommand is required"),
+    }
+}
+
+fn cmd_derive(_argmatch: &clap::ArgMatches,
+              submatch: &clap::ArgMatches)
+              -> Result<()> {
+    let target = submatch.value_of("target").expect("required");
+    let name = submatch.value_of("name").unwrap_or("thumbnail");
+    let output = submatch.value_of("output").map(Path::new);
+    cmd::derive(target, name, output)
+}
+
+fn cmd_cat(_argmatch: &clap::ArgMatches,
+           submatch: &clap::ArgMatches)
+           -> Result<()> {
+    let target = submatch.value_of("target").expect("required");
+    let range = submatch.value_of("range").and_then_try(parse_range)?;
+    cmd::cat(target, range)
+}
+
+#[cfg(all(feature = "mount", target_os = "linux"))]
+fn cmd_mount(_argmatch: &clap::ArgMatches,
+             submatch: &clap::ArgMatches)
+             -> Result<()> {
+    let rev = submatch.value_of("rev").expect("required").parse()?;
+    let mountpoint = submatch.value_of("mountpoint").expect("required");
+    cmd::mount(&rev, Path::new(mountpoint))
+}
+
+fn cmd_clone(_argmatch: &clap::ArgMatches,
+             submatch: &clap::ArgMatches)
+             -> Result<()> {
+    let source = submatch.value_of("source").expect("required");
+    let dest = submatch.value_of("dest").expect("required");
+    let depth = submatch.value_of("depth")
+        .and_then_try(|s| {
+            s.parse::<usize>()
+                .map_err(|e| Error::from(format!("Bad depth: {}", e)))
+        })?;
+    if depth == Some(0) {
+        bail!("Depth must be at least 1");
+    }
+    let limit_rate = parse_limit_rate(submatch)?;
+    cmd::clone(source, &PathBuf::from(dest), depth, limit_rate)
+}
+
+fn cmd_fast_export(_argmatch: &clap::ArgMatches,
+                   submatch: &clap::ArgMatches)
+                   -> Result<()> {
+    let branches = submatch.values_of("branch")
+        .map(|values| values.collect())
+        .unwrap_or_else(Vec::new);
+    cmd::fast_export(branches)
+}
+
+fn cmd_fast_import(_argmatch: &clap::ArgMatches,
+                   _submatch: &clap::ArgMatches)
+                   -> Result<()> {
+    cmd::fast_import()
+}
+
+fn cmd_push(_argmatch: &clap::ArgMatches,
+            submatch: &clap::ArgMatches)
+            -> Result<()> {
+    let remote = submatch.value_of("remote").expect("required");
+    let branch = submatch.value_of("branch");
+    let force = submatch.is_present("force");
+    let lease = submatch.value_of("lease");
+    let limit_rate = parse_limit_rate(submatch)?;
+    cmd::push(remote, branch, force, lease, limit_rate)
+}
+
+fn cmd_bundle(_argmatch: &clap::ArgMatches,
+              submatch: &clap::ArgMatches)
+              -> Result<()> {
+    match submatch.subcommand() {
+        ("create", Some(m)) => {
+            let range = m.value_of("range").expect("required").parse()?;
+            let output = m.value_of("output").expect("required");
+            cmd::bundle_create(&range, Path::new(output))
+        }
+        ("apply", Some(m)) => {
+            let bundle = m.value_of("bundle").expect("required");
+            cmd::bundle_apply(Path::new(bundle), m.is_present("force"))
+        }
+        _ => unreachable!("subcommand is required"),
+    }
+}
+
+fn cmd_serve(_argmatch: &clap::ArgMatches,
+             submatch: &clap::ArgMatches)
+             -> Result<()> {
+    let path = submatch.value_of("path").unwrap_or(".");
+    cmd::serve_stdio(&PathBuf::from(path))
+}
+
+fn cmd_parity(_argmatch: &clap::ArgMatches,
+              submatch: &clap::ArgMatches)
+              -> Result<()> {
+    match submatch.subcommand() {
+        ("build", Some(m)) => {
+            let group_size = match m.value_of("group_size") {
+                None => DEFAULT_PARITY_GROUP_SIZE,
+                Some(s) => {
+                    s.parse()
+                        .chain_err(|| format!("Bad parity group size: {}", s))?
+                }
+            };
+            let parity = match m.value_of("parity") {
+                None => DEFAULT_PARITY_SHARDS,
+                Some(s) => {
+                    s.parse()
+                        .chain_err(|| format!("Bad parity shard count: {}", s))?
+                }
+            };
+            cmd::parity_build(group_size, parity)
+        }
+        _ => unreachable!("subcommand is required"),
+    }
+}
+
 fn cmd_fsck(_argmatch: &clap::ArgMatches,
-            _submatch: &clap::ArgMatches)
+            submatch: &clap::ArgMatches)
             -> Result<()> {
-    cmd::fsck()
+    let mut options = FsckOptions::default();
+    options.repair = submatch.is_present("repair");
+    options.incremental = submatch.is_present("incremental");
+    let porcelain = submatch.is_present("porcelain");
+    if let Some(jobs) = submatch.value_of("jobs") {
+        options.jobs = jobs.parse::<usize>()
+            .map_err(|e| Error::from(format!("Bad job count: {}", e)))?;
+        if options.jobs == 0 {
+            bail!("Job count must be at least 1");
+        }
+    }
+    cmd::fsck(&options, porcelain)
+}
+
+fn cmd_backup(_argmatch: &clap::ArgMatches,
+              submatch: &clap::ArgMatches)
+              -> Result<()> {
+    let dest = submatch.value_of("dest").expect("required");
+    cmd::backup(Path::new(dest))
+}
+
+fn cmd_repack(_argmatch: &clap::ArgMatches,
+              submatch: &clap::ArgMatches)
+              -> Result<()> {
+    let fan_out = submatch.value_of("fan_out")
+        .and_then_try(|s| s.parse::<FanOut>())?;
+    cmd::repack(fan_out)
 }
 
 fn cmd_checkout(_argmatch: &clap::ArgMatches,
                 submatch: &clap::ArgMatches)
                 -> Result<()> {
     let target = submatch.value_of("rev").expect("required").parse()?;
-    cmd::checkout(&target)
+    cmd::checkout(&target,
+                  submatch.is_present("force"),
+                  submatch.is_present("detach"))
+}
+
+fn cmd_bisect(_argmatch: &clap::ArgMatches,
+              submatch: &clap::ArgMatches)
+              -> Result<()> {
+    let parse_rev = |m: &clap::ArgMatches, name| -> Result<Option<RevSpec>> {
+        m.value_of(name).and_then_try(|rev| rev.parse())
+    };
+    match submatch.subcommand() {
+        ("start", Some(m)) => {
+            let mut good = Vec::new();
+            for rev in m.values_of("good").into_iter().flat_map(|v| v) {
+                good.push(rev.parse()?);
+            }
+            cmd::bisect_start(parse_rev(m, "bad")?.as_ref(), &good)
+        }
+        (mark @ "bad", Some(m)) |
+        (mark @ "good", Some(m)) => {
+            cmd::bisect_mark(parse_rev(m, "rev")?.as_ref(), mark == "good")
+        }
+        ("reset", Some(_)) => cmd::bisect_reset(),
+        _ => unreachable!("subcommand is required"),
+    }
+}
+
+fn cmd_rebase(_argmatch: &clap::ArgMatches,
+              submatch: &clap::ArgMatches)
+              -> Result<()> {
+    let onto = submatch.value_of("onto").and_then_try(|r| r.parse())?;
+    cmd::rebase(onto.as_ref(),
+                submatch.is_present("continue"),
+                submatch.is_present("abort"))
+}
+
+fn cmd_recover(_argmatch: &clap::ArgMatches,
+               submatch: &clap::ArgMatches)
+               -> Result<()> {
+    cmd::recover(submatch.is_present("rollback"))
+}
+
+fn cmd_stash(_argmatch: &clap::ArgMatches,
+             submatch: &clap::ArgMatches)
+             -> Result<()> {
+    match submatch.subcommand() {
+        ("save", Some(m)) => {
+            cmd::stash_save(m.value_of("message").map(|s| s.to_owned()))
+        }
+        ("pop", Some(m)) => cmd::stash_pop(m.value_of("name")),
+        ("list", Some(_)) => cmd::stash_list(),
+        _ => unreachable!("subcommand is required"),
+    }
 }
 
 fn cmd_merge_base(_argmatch: &clap::ArgMatches,
@@ -273,3 +1411,80 @@ fn cmd_merge(_argmatch: &clap::ArgMatches,
     }
     cmd::merge(revs.iter())
 }
+
+fn cmd_info(_argmatch: &clap::ArgMatches,
+            submatch: &clap::ArgMatches)
+            -> Result<()> {
+    cmd::info(submatch.is_present("recount"))
+}
+
+fn cmd_stats(_argmatch: &clap::ArgMatches,
+             submatch: &clap::ArgMatches)
+             -> Result<()> {
+    let top_n = submatch.value_of("top")
+        .and_then_try(|s| {
+            s.parse::<usize>()
+                .map_err(|e| Error::from(format!("Bad count: {}", e)))
+        })?
+        .unwrap_or(10);
+    cmd::stats(top_n)
+}
+
+fn cmd_verify_chunking(_argmatch: &clap::ArgMatches,
+                       submatch: &clap::ArgMatches)
+                       -> Result<()> {
+    let rev = submatch.value_of("rev").expect("required").parse()?;
+    cmd::verify_chunking(&rev)
+}
+
+fn cmd_bench(_argmatch: &clap::ArgMatches,
+             submatch: &clap::ArgMatches)
+             -> Result<()> {
+    match submatch.subcommand() {
+        ("hash", Some(m)) => {
+            let mib = m.value_of("size")
+                .and_then_try(|s| {
+                    s.parse::<usize>()
+                        .map_err(|e| Error::from(format!("Bad size: {}", e)))
+                })?
+                .unwrap_or(64);
+            if mib == 0 {
+                bail!("Size must be at least 1 MiB");
+            }
+            cmd::bench_hash(mib * 1024 * 1024)
+        }
+        _ => unreachable!("subcommand is required"),
+    }
+}
+
+fn cmd_doctor(_argmatch: &clap::ArgMatches,
+              _submatch: &clap::ArgMatches)
+              -> Result<()> {
+    cmd::doctor()
+}
+
+/// Parse a byte range given as `OFFSET[+LEN]`, such as `1G+500k`
+fn parse_range(s: &str) -> Result<(u64, Option<u64>)> {
+    let bytes = |part: &str| -> Result<u64> {
+        human_readable::parse_bytes(part)
+            .ok_or_else(|| format!("Bad range: {}", s).into())
+    };
+    let mut split = s.splitn(2, '+');
+    let offset = bytes(split.next().expect("splitn gives at least one"))?;
+    let len = split.next().and_then_try(&bytes)?;
+    Ok((offset, len))
+}
+
+/// Parse the `--limit-rate` option shared by transfer commands
+fn parse_limit_rate(submatch: &clap::ArgMatches) -> Result<Option<u64>> {
+    match submatch.value_of("limit_rate") {
+        None => Ok(None),
+        Some(s) => {
+            match human_readable::parse_bytes(s) {
+                Some(0) => bail!("Rate limit must be more than 0"),
+                Some(rate) => Ok(Some(rate)),
+                None => bail!("Bad rate limit: {}", s),
+            }
+        }
+    }
+}
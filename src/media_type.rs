@@ -0,0 +1,321 @@
+//! Content sniffing, to choose how each kind of file is broken into chunks
+//!
+//! Media files are already compressed, so the rolling hash finds little to
+//! deduplicate inside them. A small image is cheaper to store as one blob than
+//! as a handful of chunks plus an index, and a video is cheaper to track in
+//! fewer, larger chunks.
+
+use dag::Blob;
+use dag::HashAlgorithm;
+use dag::HashedObject;
+use dag::ToHashed;
+use rolling_hash::ChunkParams;
+use error::*;
+use rolling_hash::read_file_objects_with;
+use std::fmt;
+use std::io;
+use std::io::BufRead;
+use std::io::Cursor;
+use std::io::Read;
+use std::str::FromStr;
+
+/// Bytes read from the start of a file to recognize its type
+pub const SNIFF_SIZE: u64 = 16;
+
+/// Largest compressed image that is stored whole, as a single blob
+pub const WHOLE_IMAGE_MAX_SIZE: u64 = 16 * 1024 * 1024;
+
+/// How many times larger than usual the chunks of a video are
+pub const VIDEO_CHUNK_SCALE: usize = 16;
+
+/// Every media type, for looking one up by name
+const ALL_MEDIA_TYPES: &'static [MediaType] = &[MediaType::Unknown,
+                                                MediaType::Jpeg,
+                                                MediaType::Png,
+                                                MediaType::Gif,
+                                                MediaType::Webp,
+                                                MediaType::Mp4,
+                                                MediaType::QuickTime,
+                                                MediaType::Matroska,
+                                                MediaType::Avi];
+
+/// A file type recognized by the magic bytes at the start of its content
+#[derive(Debug,Clone,Copy,Hash,PartialEq,Eq,PartialOrd,Ord,Default,
+         RustcEncodable,RustcDecodable)]
+pub enum MediaType {
+    #[default]
+    Unknown,
+    Jpeg,
+    Png,
+    Gif,
+    Webp,
+    Mp4,
+    QuickTime,
+    Matroska,
+    Avi,
+}
+
+impl MediaType {
+    /// Recognize a file type from the first bytes of its content
+    ///
+    /// ```
+    /// use dmv::media_type::MediaType;
+    ///
+    /// assert_eq!(MediaType::sniff(b"\xff\xd8\xff\xe0"), MediaType::Jpeg);
+    /// assert_eq!(MediaType::sniff(b"Hello world!"), MediaType::Unknown);
+    /// ```
+    pub fn sniff(head: &[u8]) -> Self {
+        let at = |offset: usize, magic: &[u8]| {
+            head.len() >= offset + magic.len() &&
+            &head[offset..offset + magic.len()] == magic
+        };
+        if at(0, b"\xff\xd8\xff") {
+            MediaType::Jpeg
+        } else if at(0, b"\x89PNG\r\n\x1a\n") {
+            MediaType::Png
+        } else if at(0, b"GIF87a") || at(0, b"GIF89a") {
+            MediaType::Gif
+        } else if at(0, b"RIFF") && at(8, b"WEBP") {
+            MediaType::Webp
+        } else if at(0, b"RIFF") && at(8, b"AVI ") {
+            MediaType::Avi
+        } else if at(4, b"ftypqt  ") {
+            MediaType::QuickTime
+        } else if at(4, b"ftyp") {
+            MediaType::Mp4
+        } else if at(0, b"\x1a\x45\xdf\xa3") {
+            MediaType::Matroska
+        } else {
+            MediaType::Unknown
+        }
+    }
+
+    /// The MIME type name, such as `image/jpeg`
+    pub fn mime(&self) -> &'static str {
+        match *self {
+            MediaType::Unknown => "application/octet-stream",
+            MediaType::Jpeg => "image/jpeg",
+            MediaType::Png => "image/png",
+            MediaType::Gif => "image/gif",
+            MediaType::Webp => "image/webp",
+            MediaType::Mp4 => "video/mp4",
+            MediaType::QuickTime => "video/quicktime",
+            MediaType::Matroska => "video/x-matroska",
+            MediaType::Avi => "video/x-msvideo",
+        }
+    }
+
+    pub fn is_compressed_image(&self) -> bool {
+        matches!(*self,
+                 MediaType::Jpeg | MediaType::Png | MediaType::Gif |
+                 MediaType::Webp)
+    }
+
+    pub fn is_video(&self) -> bool {
+        matches!(*self,
+                 MediaType::Mp4 | MediaType::QuickTime | MediaType::Matroska |
+                 MediaType::Avi)
+    }
+
+    /// How files of this type are chunked, given the repository's parameters
+    pub fn chunk_strategy(&self, params: &ChunkParams) -> ChunkStrategy {
+        if self.is_compressed_image() {
+            ChunkStrategy {
+                params: *params,
+                whole_max_size: WHOLE_IMAGE_MAX_SIZE,
+            }
+        } else if self.is_video() {
+            let target = params.target_size() * VIDEO_CHUNK_SCALE;
+            ChunkStrategy::untyped(&ChunkParams::for_target_size(target))
+        } else {
+            ChunkStrategy::untyped(params)
+        }
+    }
+}
+
+impl fmt::Display for MediaType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.mime())
+    }
+}
+
+/// Parse a MIME type name, as written by `Display`
+impl FromStr for MediaType {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        ALL_MEDIA_TYPES.iter()
+            .find(|media_type| media_type.mime() == s)
+            .cloned()
+            .ok_or_else(|| format!("Unknown media type: {}", s).into())
+    }
+}
+
+/// How a file is broken into chunks
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct ChunkStrategy {
+    /// Rolling hash parameters for files that are chunked
+    pub params: ChunkParams,
+    /// Files up to this size are stored whole, as a single blob
+    pub whole_max_size: u64,
+}
+
+impl ChunkStrategy {
+    /// Chunk every file with the given parameters, whatever its type
+    pub fn untyped(params: &ChunkParams) -> Self {
+        ChunkStrategy {
+            params: *params,
+            whole_max_size: 0,
+        }
+    }
+}
+
+/// Objects for a file, chunked according to its sniffed type
+pub struct TypedObjects<'a> {
+    pub media_type: MediaType,
+    objects: Box<Iterator<Item = io::Result<HashedObject>> + 'a>,
+}
+
+impl<'a> Iterator for TypedObjects<'a> {
+    type Item = io::Result<HashedObject>;
+    fn next(&mut self) -> Option<Self::Item> { self.objects.next() }
+}
+
+/// Sniff a file's type, and break it into objects accordingly
+///
+/// Like `rolling_hash::read_file_objects_with`, the objects depend only on the
+/// content, so a file hashes the same whether it is read from disk, a pipe, or
+/// an import stream. If `typed` is false, the type is still sniffed, but every
+/// file is chunked with the given parameters.
+pub fn read_typed_objects<'a, R>(mut reader: R,
+                                 params: &ChunkParams,
+                                 typed: bool,
+                                 algorithm: HashAlgorithm)
+                                 -> io::Result<TypedObjects<'a>>
+    where R: BufRead + 'a
+{
+    let mut head = Vec::new();
+    (&mut reader).take(SNIFF_SIZE).read_to_end(&mut head)?;
+    let media_type = MediaType::sniff(&head);
+    let strategy = match typed {
+        true => media_type.chunk_strategy(params),
+        false => ChunkStrategy::untyped(params),
+    };
+
+    let mut reader: Box<BufRead + 'a> = Box::new(Cursor::new(head)
+        .chain(reader));
+    if strategy.whole_max_size > 0 {
+        let mut content = Vec::new();
+        (&mut reader).take(strategy.whole_max_size + 1)
+            .read_to_end(&mut content)?;
+        if content.len() as u64 <= strategy.whole_max_size {
+            let blob = Blob::from(content).to_hashed_with(algorithm);
+            return Ok(TypedObjects {
+                media_type: media_type,
+                objects: Box::new(Some(Ok(blob)).into_iter()),
+            });
+        }
+        reader = Box::new(Cursor::new(content).chain(reader));
+    }
+    Ok(TypedObjects {
+        media_type: media_type,
+        objects: Box::new(read_file_objects_with(reader,
+                                                 &strategy.params,
+                                                 algorithm)),
+    })
+}
+
+
+#[cfg(test)]
+mod test {
+    use dag::HashAlgorithm;
+    use dag::ObjectCommon;
+    use dag::ObjectType;
+    use rolling_hash::CHUNK_TARGET_SIZE;
+    use rolling_hash::ChunkParams;
+    use rolling_hash::read_file_objects;
+    use super::*;
+    use testutil::TestRand;
+    use testutil::rand::Rng;
+
+    fn object_types<'a>(objects: TypedObjects<'a>) -> Vec<ObjectType> {
+        objects.map(|o| o.unwrap().object_type()).collect()
+    }
+
+    #[test]
+    fn test_sniff() {
+        assert_eq!(MediaType::sniff(b"\x89PNG\r\n\x1a\n...."), MediaType::Png);
+        assert_eq!(MediaType::sniff(b"GIF89a"), MediaType::Gif);
+        assert_eq!(MediaType::sniff(b"RIFF\0\0\0\0WEBPVP8 "),
+                   MediaType::Webp);
+        assert_eq!(MediaType::sniff(b"RIFF\0\0\0\0AVI LIST"), MediaType::Avi);
+        assert_eq!(MediaType::sniff(b"\0\0\0\x18ftypmp42"), MediaType::Mp4);
+        assert_eq!(MediaType::sniff(b"\0\0\0\x14ftypqt  "),
+                   MediaType::QuickTime);
+        assert_eq!(MediaType::sniff(b"\x1a\x45\xdf\xa3"), MediaType::Matroska);
+        assert_eq!(MediaType::sniff(b"\xff\xd8"), MediaType::Unknown);
+        assert_eq!(MediaType::sniff(b""), MediaType::Unknown);
+    }
+
+    #[test]
+    fn test_parse_mime() {
+        for media_type in ALL_MEDIA_TYPES {
+            assert_eq!(media_type.to_string().parse::<MediaType>().unwrap(),
+                       *media_type);
+        }
+        assert!("text/plain".parse::<MediaType>().is_err());
+    }
+
+    #[test]
+    fn test_chunk_strategy() {
+        let params = ChunkParams::default();
+        assert_eq!(MediaType::Unknown.chunk_strategy(&params),
+                   ChunkStrategy::untyped(&params));
+        assert_eq!(MediaType::Jpeg.chunk_strategy(&params).whole_max_size,
+                   WHOLE_IMAGE_MAX_SIZE);
+        let video = MediaType::Mp4.chunk_strategy(&params);
+        assert_eq!(video.whole_max_size, 0);
+        assert_eq!(video.params.target_size(),
+                   CHUNK_TARGET_SIZE * VIDEO_CHUNK_SCALE);
+    }
+
+    #[test]
+    fn test_read_typed_objects() {
+        let params = ChunkParams::default();
+        let algorithm = HashAlgorithm::default();
+        let mut jpeg = b"\xff\xd8\xff\xe0".to_vec();
+        jpeg.extend(TestRand::default()
+            .gen_iter::<u8>()
+            .take(CHUNK_TARGET_SIZE * 8));
+
+        // Untyped, a large enough JPEG is chunked like anything else
+        let objects = read_typed_objects(jpeg.as_slice(),
+                                         &params,
+                                         false,
+                                         algorithm)
+            .unwrap();
+        assert_eq!(objects.media_type, MediaType::Jpeg);
+        let untyped = object_types(objects);
+        assert_eq!(untyped.last(), Some(&ObjectType::ChunkedBlob));
+        assert_eq!(untyped.len(),
+                   read_file_objects(jpeg.as_slice()).count());
+
+        // Typed, it is stored whole
+        let objects = read_typed_objects(jpeg.as_slice(),
+                                         &params,
+                                         true,
+                                         algorithm)
+            .unwrap();
+        assert_eq!(object_types(objects), vec![ObjectType::Blob]);
+
+        // Anything else is chunked as usual, and an empty file is still a blob
+        let text: &[u8] = b"Hello world!";
+        let objects = read_typed_objects(text, &params, true, algorithm)
+            .unwrap();
+        assert_eq!(objects.media_type, MediaType::Unknown);
+        assert_eq!(object_types(objects), vec![ObjectType::Blob]);
+        let empty: &[u8] = b"";
+        let objects = read_typed_objects(empty, &params, true, algorithm)
+            .unwrap();
+        assert_eq!(object_types(objects), vec![ObjectType::Blob]);
+    }
+}
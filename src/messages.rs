@@ -0,0 +1,489 @@
+//! Catalog of human-readable messages shown to the user
+//!
+//! Human-facing text is looked up by key, so that it can be translated or
+//! reworded without touching the code that produces it. Machine-readable
+//! ("porcelain") output, such as status codes and hashes, should NOT go
+//! through the catalog, so that it stays stable for scripts.
+//!
+//! Templates use `{}` for the next argument, or `{0}`, `{1}`, etc. for a
+//! specific argument, so translations can reorder arguments. Use `{{` and `}}`
+//! for literal braces.
+//!
+//! ```
+//! #[macro_use]
+//! extern crate dmv;
+//! use dmv::messages::Msg;
+//!
+//! fn main() {
+//!     assert_eq!(msg!(Msg::OnBranch, "master"), "On branch master");
+//! }
+//! ```
+
+use std::fmt;
+use std::fmt::Write;
+use std::sync::RwLock;
+
+/// Keys for every human-readable message
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub enum Msg {
+    ChunkSizeTooSmall,
+    DetachedHead,
+    DetachedAt,
+    BranchIsNow,
+    NoCommitSpecified,
+    CorruptObject,
+    QuarantinedObject,
+    ReconstructedObject,
+    MissingObject,
+    OrphanObject,
+    AllObjectsOk,
+    RepoHasCorruptObjects,
+    RepoHasMissingObjects,
+    BadChunkIndex,
+    RewroteChunkIndex,
+    RepoHasBadChunkIndexes,
+    OnBranch,
+    Subtree,
+    FetchedObjects,
+    RemoteNotNamed,
+    PushedObjects,
+    PushUpToDate,
+    BundledObjects,
+    AppliedObjects,
+    BundleRefRejected,
+    NotOnBranch,
+    WriteInProgress,
+    ChunkReuse,
+    HistoryTruncated,
+    ClonedEmpty,
+    ExportedHistory,
+    ImportedHistory,
+    VerifiedObjects,
+    ArchivedFiles,
+    LastCommit,
+    Amended,
+    Rebased,
+    RebaseStopped,
+    RebaseAborted,
+    Stashed,
+    RefDeleted,
+    Interrupted,
+    Resumed,
+    RolledBack,
+    Unstashed,
+    UnstashConflict,
+    LastCheckout,
+    LastSync,
+    NoRemotes,
+    NeverSynced,
+    NotSyncedFor,
+    NoProblemsFound,
+    NotInRepo,
+    NoChanges,
+    ChangesSummary,
+    NoCommitsHint,
+    ChunkingDiffers,
+    ChunkingSummary,
+    ChunkingDrift,
+    StatsSummary,
+    CountingObjects,
+    NotIgnored,
+    WouldRemove,
+    SizeWarning,
+    CleanHint,
+    DuplicatesSkipped,
+    SharedChunks,
+    LargestFiles,
+    DiskUsageSummary,
+    DuplicatesSummary,
+    UpToDateWith,
+    AheadOf,
+    BehindOf,
+    DivergedFrom,
+    BranchTracking,
+    BranchAheadBehind,
+    BranchVersusHead,
+    MoreChanges,
+    WatchingForChanges,
+    StaleCacheEntry,
+    CacheEntryVerified,
+    PrimedCache,
+    CorruptFile,
+    VerifiedFiles,
+    WorkDirHasCorruptFiles,
+    PrunedCache,
+    Repacked,
+    BackedUp,
+    ParityBuilt,
+    LeftOutContent,
+    FilesMissingContent,
+    ContentHere,
+    ContentNotHere,
+    NoKnownLocations,
+    NoMediaMetadata,
+    Derived,
+    Moved,
+    Removed,
+    Mounted,
+    BenchSample,
+    QueryMatches,
+    Unsigned,
+    GoodSignature,
+    BadSignature,
+    LogStat,
+    GeneratedSigningKey,
+    BisectNeedBad,
+    BisectNeedGood,
+    Bisecting,
+    FirstBadCommit,
+    BisectReset,
+    HookNotExecutable,
+}
+
+impl Msg {
+    /// The default (English) template for this message
+    pub fn default_template(&self) -> &'static str {
+        match *self {
+            Msg::ChunkSizeTooSmall => {
+                "Chunk size must be at least {} bytes"
+            }
+            Msg::DetachedHead => "<detached head>",
+            Msg::DetachedAt => "HEAD detached at {}",
+            Msg::BranchIsNow => "{} is now {}",
+            Msg::NoCommitSpecified => {
+                "No commit specified and no parent commit"
+            }
+            Msg::CorruptObject => "Corrupt object {}: expected {}, actual {}",
+            Msg::QuarantinedObject => "Moved corrupt object {} to quarantine",
+            Msg::ReconstructedObject => "Rebuilt object {} from parity",
+            Msg::MissingObject => "Missing object {} (referenced by {})",
+            Msg::OrphanObject => "Unreachable {} {}",
+            Msg::AllObjectsOk => "All objects OK",
+            Msg::RepoHasCorruptObjects => "Repository has corrupt objects",
+            Msg::RepoHasMissingObjects => "Repository has missing objects",
+            Msg::BadChunkIndex => {
+                "Chunk index {} records {} bytes, but its chunks add up to {}"
+            }
+            Msg::RewroteChunkIndex => {
+                "Rewrote chunk index {} with corrected sizes as {}"
+            }
+            Msg::RepoHasBadChunkIndexes => {
+                "Repository has chunk indexes with inconsistent sizes"
+            }
+            Msg::OnBranch => "On branch {}",
+            Msg::Subtree => "Subtree: {}",
+            Msg::FetchedObjects => "Fetched {} objects, {}",
+            Msg::RemoteNotNamed => {
+                "{} is not a named remote, so no tracking refs were updated"
+            }
+            Msg::PushedObjects => "Sent {} objects, {}",
+            Msg::PushUpToDate => "{} is already up to date",
+            Msg::BundledObjects => "Bundled {} objects, {}",
+            Msg::AppliedObjects => "Stored {} new objects, {}",
+            Msg::BundleRefRejected => {
+                "Left {} at {}, since {} does not follow from it. Use --force \
+                 to update it anyway."
+            }
+            Msg::NotOnBranch => "Not on a branch. Specify which branch to push",
+            Msg::WriteInProgress => {
+                "Note: {} is in progress, so results may be incomplete"
+            }
+            Msg::ChunkReuse => "{}: {} of {} chunks reused, {} new ({})",
+            Msg::HistoryTruncated => {
+                "History truncated at {} commits (shallow fetch)"
+            }
+            Msg::ClonedEmpty => "Cloned {}, which has no branches yet",
+            Msg::ExportedHistory => "Exported {} commits and {} files",
+            Msg::ImportedHistory => "Imported {} commits and {} files",
+            Msg::VerifiedObjects => "Verified {} new objects",
+            Msg::ArchivedFiles => "Archived {} files in {} directories, {}",
+            Msg::LastCommit => "Last commit: {} ago",
+            Msg::Amended => "Replaced {}. Run `dmv reflog` to find it again",
+            Msg::Rebased => "Rebased {} onto {}, and it is now {}",
+            Msg::RebaseStopped => {
+                "Stopped at {} {}\nResolve the conflicts, then run `dmv \
+                 rebase --continue`, or `dmv rebase --abort` to give up"
+            }
+            Msg::RebaseAborted => "Rebase aborted. Back on {}",
+            Msg::Stashed => "Saved changes as {}: {}",
+            Msg::RefDeleted => "{} was deleted",
+            Msg::Interrupted => {
+                "An interrupted {} left the work dir half updated. Run `dmv \
+                 recover` to finish it, or `dmv recover --rollback` to undo it"
+            }
+            Msg::Resumed => "Finished the interrupted {}",
+            Msg::RolledBack => "Rolled back the interrupted {}",
+            Msg::Unstashed => "Applied and dropped {}: {}",
+            Msg::UnstashConflict => {
+                "The stash conflicts with changes in the work dir, so it was \
+                 kept. Resolve the conflicts, then commit or stash again"
+            }
+            Msg::LastCheckout => "Last checkout: {} ago",
+            Msg::LastSync => "Last fetch or push: {} ago",
+            Msg::NoRemotes => {
+                "No remotes are configured, so nothing is backed up elsewhere"
+            }
+            Msg::NeverSynced => {
+                "Never fetched or pushed. Push to a remote to back up your work"
+            }
+            Msg::NotSyncedFor => {
+                "Last fetch or push was {} ago. Push to a remote to back up \
+                 your work"
+            }
+            Msg::NoProblemsFound => "No problems found",
+            Msg::NotInRepo => {
+                "Not inside a dmv repository. Run `dmv init` to start one \
+                 here, or `dmv clone <source>` to copy an existing one"
+            }
+            Msg::NoChanges => "No changes since HEAD",
+            Msg::ChangesSummary => {
+                "{} files changed since HEAD: {} added, {} modified, {} \
+                 deleted. Run `dmv status` to see them"
+            }
+            Msg::NoCommitsHint => {
+                "No commits yet. Run `dmv commit -m <message>` to record the \
+                 files in this directory"
+            }
+            Msg::ChunkingDiffers => {
+                "{}: chunks differ from byte {3} ({1} of {2} new chunks match \
+                 stored chunks)"
+            }
+            Msg::ChunkingSummary => "{} of {} files reproduce their chunks",
+            Msg::ChunkingDrift => {
+                "{} files no longer chunk the same way, so new copies will not \
+                 deduplicate against them"
+            }
+            Msg::StatsSummary => {
+                "Logical size: {}, stored size: {}, deduplication ratio {}x"
+            }
+            Msg::CountingObjects => "Counting objects...",
+            Msg::NotIgnored => "{}: not ignored",
+            Msg::WouldRemove => "Would remove {}",
+            Msg::SizeWarning => "Warning: {}",
+            Msg::CleanHint => "Use `dmv clean -f` to remove them",
+            Msg::DuplicatesSkipped => {
+                "{} was already stored when added again ({}x deduplication)"
+            }
+            Msg::SharedChunks => "{} chunks are shared by more than one file",
+            Msg::LargestFiles => "Largest files:",
+            Msg::DiskUsageSummary => {
+                "Total stored size: {}, of which {} is shared between entries"
+            }
+            Msg::DuplicatesSummary => {
+                "{} files are stored under more than one path, {} paths in all"
+            }
+            Msg::UpToDateWith => "Your branch is up to date with {}",
+            Msg::AheadOf => "Your branch is ahead of {} by {}",
+            Msg::BehindOf => "Your branch is behind {} by {}",
+            Msg::DivergedFrom => {
+                "Your branch and {} have diverged, and have {} and {} \
+                 different commits each"
+            }
+            Msg::BranchTracking => "[{}]",
+            Msg::BranchAheadBehind => "[{}: ahead {}, behind {}]",
+            Msg::BranchVersusHead => "[HEAD: ahead {}, behind {}]",
+            Msg::MoreChanges => "... and {} more",
+            Msg::WatchingForChanges => {
+                "{} changed. Watching for changes (Ctrl-C to stop)"
+            }
+            Msg::StaleCacheEntry => {
+                "Cache entry for {} is stale: cached {}, actual {}"
+            }
+            Msg::CacheEntryVerified => "Cached hash verified",
+            Msg::PrimedCache => {
+                "Cached {} files, {} already cached, {} changed, {} missing"
+            }
+            Msg::CorruptFile => "Corrupt file {}: expected {}",
+            Msg::VerifiedFiles => {
+                "Rehashed {} of {} cached files; {} not cached, {} missing"
+            }
+            Msg::WorkDirHasCorruptFiles => "{} files have changed on disk",
+            Msg::Repacked => "Moved {} objects. Fan-out is now {}",
+            Msg::BackedUp => {
+                "Copied {} objects ({}) to {}. {} were already there"
+            }
+            Msg::ParityBuilt => {
+                "Covered {} more objects in {} parity groups, with {} of parity"
+            }
+            Msg::PrunedCache => {
+                "Pruned {} cache files: dropped {} entries for missing files, \
+                 evicted {} least recently used, kept {}. Reclaimed {}"
+            }
+            Msg::LeftOutContent => {
+                "Left out content of {} blobs, {}. Run `dmv missing <rev>` to \
+                 see which files"
+            }
+            Msg::FilesMissingContent => "{} files are missing content",
+            Msg::ContentHere => "{} is present in this repository",
+            Msg::ContentNotHere => {
+                "Some content of {} is not in this repository"
+            }
+            Msg::NoKnownLocations => "No remotes are known to have {}",
+            Msg::NoMediaMetadata => "No metadata was recorded for {}",
+            Msg::Derived => "Derived {} of {}",
+            Msg::Moved => "Moved {} to {}",
+            Msg::Removed => "Removed {}",
+            Msg::Mounted => "Mounted {} at {}, read-only. Unmount it to stop.",
+            Msg::BenchSample => "Timing each step over {} of sample data",
+            Msg::QueryMatches => "{} matching files, {} in all",
+            Msg::Unsigned => "[unsigned]",
+            Msg::GoodSignature => "[good signature from {}]",
+            Msg::BadSignature => "[BAD SIGNATURE: {}]",
+            Msg::LogStat => "[{} added, {} modified, {} deleted, {}]",
+            Msg::GeneratedSigningKey => {
+                "Wrote signing key to {}. Sign commits with it by setting \
+                 DMV_SIGNING_KEY to that path. Its public key is:"
+            }
+            Msg::BisectNeedBad => {
+                "Mark a commit that has the change with `dmv bisect bad \
+                 [<rev>]`"
+            }
+            Msg::BisectNeedGood => {
+                "Mark a commit without the change with `dmv bisect good \
+                 [<rev>]`"
+            }
+            Msg::Bisecting => {
+                "Bisecting: about {} commits left to test after this one"
+            }
+            Msg::FirstBadCommit => {
+                "{} is the first bad commit. Run `dmv bisect reset` to go back \
+                 to {}"
+            }
+            Msg::BisectReset => "Ended bisect, back on {}",
+            Msg::HookNotExecutable => {
+                "Ignoring the {} hook, because {} is not executable"
+            }
+        }
+    }
+}
+
+/// A source of message templates, such as a translation
+pub trait Catalog: Send + Sync {
+    /// Give the template for a message, or None to use the default
+    fn template(&self, msg: Msg) -> Option<&str>;
+}
+
+/// The built-in catalog, which always uses the default templates
+pub struct DefaultCatalog;
+
+impl Catalog for DefaultCatalog {
+    fn template(&self, _msg: Msg) -> Option<&str> { None }
+}
+
+lazy_static!{
+    static ref CATALOG: RwLock<Box<Catalog>> =
+        RwLock::new(Box::new(DefaultCatalog));
+}
+
+/// Replace the catalog used for all messages
+pub fn set_catalog(catalog: Box<Catalog>) {
+    *CATALOG.write().unwrap() = catalog;
+}
+
+/// Format a message using the current catalog
+pub fn format_msg(msg: Msg, args: &[&fmt::Display]) -> String {
+    let catalog = CATALOG.read().unwrap();
+    format_with(&**catalog, msg, args)
+}
+
+/// Format a message using the given catalog
+pub fn format_with(catalog: &Catalog,
+                   msg: Msg,
+                   args: &[&fmt::Display])
+                   -> String {
+    let template = catalog.template(msg).unwrap_or(msg.default_template());
+    fill_template(template, args)
+}
+
+/// Substitute arguments into a template
+///
+/// Placeholders that refer to missing arguments are left in place, so a bad
+/// translation gives odd output rather than a crash.
+pub fn fill_template(template: &str, args: &[&fmt::Display]) -> String {
+    let mut output = String::with_capacity(template.len());
+    let mut chars = template.chars().peekable();
+    let mut next_arg = 0;
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                output.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                output.push('}');
+            }
+            '{' => {
+                let mut index = String::new();
+                while let Some(&c) = chars.peek() {
+                    chars.next();
+                    if c == '}' {
+                        break;
+                    }
+                    index.push(c);
+                }
+                let i = match index.parse::<usize>() {
+                    Ok(i) => i,
+                    Err(_) if index.is_empty() => {
+                        next_arg += 1;
+                        next_arg - 1
+                    }
+                    Err(_) => args.len(),
+                };
+                match args.get(i) {
+                    Some(arg) => write!(output, "{}", arg).unwrap(),
+                    None => write!(output, "{{{}}}", index).unwrap(),
+                }
+            }
+            c => output.push(c),
+        }
+    }
+    output
+}
+
+/// Format a message from the catalog, with arguments
+#[macro_export]
+macro_rules! msg {
+    ($msg:expr) => {
+        $crate::messages::format_msg($msg, &[])
+    };
+    ($msg:expr, $($arg:expr),+) => {
+        $crate::messages::format_msg(
+            $msg, &[ $( &$arg as &::std::fmt::Display ),+ ])
+    };
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fill_template() {
+        assert_eq!(fill_template("{} is now {}", &[&"master", &"abc123"]),
+                   "master is now abc123");
+        assert_eq!(fill_template("{1} <- {0}", &[&"a", &"b"]), "b <- a");
+        assert_eq!(fill_template("{{literal}} {}", &[&1]), "{literal} 1");
+        assert_eq!(fill_template("missing {} {}", &[&1]), "missing 1 {}");
+    }
+
+    struct ShoutingCatalog;
+    impl Catalog for ShoutingCatalog {
+        fn template(&self, msg: Msg) -> Option<&str> {
+            match msg {
+                Msg::OnBranch => Some("ON BRANCH {}!"),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn test_custom_catalog() {
+        let catalog = ShoutingCatalog;
+        assert_eq!(format_with(&catalog, Msg::OnBranch, &[&"master"]),
+                   "ON BRANCH master!");
+        assert_eq!(format_with(&catalog, Msg::AllObjectsOk, &[]),
+                   "All objects OK",
+                   "Should fall back to default template");
+    }
+}
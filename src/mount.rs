@@ -0,0 +1,663 @@
+//! Mounting a revision as a read-only filesystem, with FUSE
+//!
+//! Speaks the FUSE kernel protocol directly over `/dev/fuse`. Listing a
+//! directory reads its Tree object, and reading a file reads only the chunks
+//! covering the requested range (see `ObjectStore::copy_blob_range`), so a
+//! media player can browse history and seek through a large video without a
+//! checkout. Objects never change, so the kernel may cache everything.
+//!
+//! Mounting needs permission to call mount(2), or else the `fusermount`
+//! helper that comes with FUSE. Only on Linux, with the `mount` feature.
+
+use dag::ObjectKey;
+use dag::ObjectSize;
+use error::*;
+use object_store::ObjectStore;
+use object_store::ObjectWalkNode;
+use std::ffi::CString;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::mem;
+use std::os::raw::c_char;
+use std::os::raw::c_int;
+use std::os::raw::c_ulong;
+use std::os::raw::c_void;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::FromRawFd;
+use std::path::Path;
+use std::ptr;
+use std::process::Command;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+use walker::NodeLookup;
+
+extern "C" {
+    fn mount(source: *const c_char,
+             target: *const c_char,
+             fstype: *const c_char,
+             flags: c_ulong,
+             data: *const c_void)
+             -> c_int;
+    fn getuid() -> u32;
+    fn getgid() -> u32;
+    fn socketpair(domain: c_int,
+                  kind: c_int,
+                  protocol: c_int,
+                  fds: *mut c_int)
+                  -> c_int;
+    fn recvmsg(fd: c_int, msg: *mut MsgHdr, flags: c_int) -> isize;
+}
+
+const FUSE_DEVICE: &'static str = "/dev/fuse";
+
+const MS_RDONLY: c_ulong = 1;
+const MS_NOSUID: c_ulong = 2;
+const MS_NODEV: c_ulong = 4;
+
+const AF_UNIX: c_int = 1;
+const SOCK_STREAM: c_int = 1;
+const SOL_SOCKET: c_int = 1;
+const SCM_RIGHTS: c_int = 1;
+
+/// Environment variable telling `fusermount` where to send the device
+const FUSERMOUNT_FD_VAR: &'static str = "_FUSECOMMFD";
+
+const ENOENT: c_int = 2;
+const EINTR: c_int = 4;
+const EIO: c_int = 5;
+const EAGAIN: c_int = 11;
+const ENODEV: c_int = 19;
+const ENOTDIR: c_int = 20;
+const EISDIR: c_int = 21;
+const EROFS: c_int = 30;
+const ENOSYS: c_int = 38;
+const EPROTO: c_int = 71;
+
+const FUSE_LOOKUP: u32 = 1;
+const FUSE_FORGET: u32 = 2;
+const FUSE_GETATTR: u32 = 3;
+const FUSE_OPEN: u32 = 14;
+const FUSE_READ: u32 = 15;
+const FUSE_STATFS: u32 = 17;
+const FUSE_RELEASE: u32 = 18;
+const FUSE_INIT: u32 = 26;
+const FUSE_OPENDIR: u32 = 27;
+const FUSE_READDIR: u32 = 28;
+const FUSE_RELEASEDIR: u32 = 29;
+const FUSE_INTERRUPT: u32 = 36;
+const FUSE_DESTROY: u32 = 38;
+const FUSE_BATCH_FORGET: u32 = 42;
+
+const PROTOCOL_MAJOR: u32 = 7;
+const PROTOCOL_MINOR: u32 = 26;
+
+/// Size of `struct fuse_in_header`
+const IN_HEADER_SIZE: usize = 40;
+/// Size of `struct fuse_out_header`
+const OUT_HEADER_SIZE: usize = 16;
+/// Size of the fixed part of `struct fuse_dirent`
+const DIRENT_HEADER_SIZE: usize = 24;
+
+/// Largest write the kernel may send, though a read-only mount gets none
+const MAX_WRITE: u32 = 128 * 1024;
+/// Room for the largest request, with its headers
+const REQUEST_BUFFER_SIZE: usize = MAX_WRITE as usize + 4096;
+
+/// Seconds the kernel may cache names and attributes
+const TTL_SECS: u64 = 3600;
+
+/// Keep cached pages of a file when it is opened again
+const FOPEN_KEEP_CACHE: u32 = 2;
+
+const O_ACCMODE: u32 = 3;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const DT_DIR: u32 = 4;
+const DT_REG: u32 = 8;
+
+#[repr(C)]
+struct IoVec {
+    base: *mut c_void,
+    len: usize,
+}
+
+#[repr(C)]
+struct MsgHdr {
+    name: *mut c_void,
+    namelen: u32,
+    iov: *mut IoVec,
+    iovlen: usize,
+    control: *mut c_void,
+    controllen: usize,
+    flags: c_int,
+}
+
+/// Mount a FUSE filesystem, returning the device to serve it through
+///
+/// Tries mount(2) first, then `fusermount3` or `fusermount`, which let users
+/// without privileges mount FUSE filesystems.
+pub fn fuse_mount(mountpoint: &Path) -> Result<fs::File> {
+    let device = fs::OpenOptions::new().read(true)
+        .write(true)
+        .open(FUSE_DEVICE)
+        .chain_err(|| format!("Could not open {}", FUSE_DEVICE))?;
+    let options = format!("fd={},rootmode=40000,user_id={},group_id={}",
+                          device.as_raw_fd(),
+                          unsafe { getuid() },
+                          unsafe { getgid() });
+    let source = CString::new("dmv").expect("no nul");
+    let fstype = CString::new("fuse.dmv").expect("no nul");
+    let target = CString::new(mountpoint.as_os_str().as_bytes())
+        .chain_err(|| format!("Bad mount point: {}", mountpoint.display()))?;
+    let options = CString::new(options).expect("no nul");
+    let result = unsafe {
+        mount(source.as_ptr(),
+              target.as_ptr(),
+              fstype.as_ptr(),
+              MS_RDONLY | MS_NOSUID | MS_NODEV,
+              options.as_ptr() as *const c_void)
+    };
+    if result == 0 {
+        return Ok(device);
+    }
+    debug!("Could not mount directly, trying fusermount: {}",
+           io::Error::last_os_error());
+    fusermount(mountpoint)
+        .chain_err(|| format!("Could not mount at {}", mountpoint.display()))
+}
+
+/// Mount with the `fusermount` helper, which sends back the open device
+fn fusermount(mountpoint: &Path) -> Result<fs::File> {
+    let mut fds = [0 as c_int; 2];
+    if unsafe { socketpair(AF_UNIX, SOCK_STREAM, 0, fds.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    // Not close-on-exec, so that the helper inherits its end
+    let (ours, theirs) = unsafe {
+        (fs::File::from_raw_fd(fds[0]), fs::File::from_raw_fd(fds[1]))
+    };
+    let mut status = None;
+    for program in &["fusermount3", "fusermount"] {
+        let result = Command::new(program)
+            .arg("-o")
+            .arg("ro,nosuid,nodev,fsname=dmv,subtype=dmv")
+            .arg("--")
+            .arg(mountpoint)
+            .env(FUSERMOUNT_FD_VAR, theirs.as_raw_fd().to_string())
+            .status();
+        match result {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+            Ok(s) => {
+                status = Some((program, s));
+                break;
+            }
+        }
+    }
+    drop(theirs);
+    match status {
+        None => bail!("Could not find fusermount"),
+        Some((program, status)) if !status.success() => {
+            bail!("{} failed: {}", program, status)
+        }
+        Some(_) => receive_fd(&ours),
+    }
+}
+
+/// Receive a file descriptor sent over a Unix socket
+fn receive_fd(socket: &fs::File) -> Result<fs::File> {
+    let mut byte = 0u8;
+    let mut iov = IoVec {
+        base: &mut byte as *mut u8 as *mut c_void,
+        len: 1,
+    };
+    // Room for `struct cmsghdr` and one descriptor, suitably aligned
+    let mut control = [0usize; 8];
+    let mut msg = MsgHdr {
+        name: ptr::null_mut(),
+        namelen: 0,
+        iov: &mut iov,
+        iovlen: 1,
+        control: control.as_mut_ptr() as *mut c_void,
+        controllen: mem::size_of_val(&control),
+        flags: 0,
+    };
+    if unsafe { recvmsg(socket.as_raw_fd(), &mut msg, 0) } <= 0 {
+        bail!("fusermount did not send the FUSE device");
+    }
+    let control = unsafe {
+        ::std::slice::from_raw_parts(control.as_ptr() as *const u8,
+                                     mem::size_of_val(&control))
+    };
+    let header_size = mem::size_of::<usize>() + 8;
+    let level = i32_at(control, mem::size_of::<usize>());
+    let kind = i32_at(control, mem::size_of::<usize>() + 4);
+    if msg.controllen < header_size + 4 || level != SOL_SOCKET ||
+       kind != SCM_RIGHTS {
+        bail!("fusermount did not send the FUSE device");
+    }
+    Ok(unsafe { fs::File::from_raw_fd(i32_at(control, header_size)) })
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    let mut field = [0u8; 4];
+    field.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_ne_bytes(field)
+}
+
+fn i32_at(bytes: &[u8], offset: usize) -> i32 { u32_at(bytes, offset) as i32 }
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    let mut field = [0u8; 8];
+    field.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_ne_bytes(field)
+}
+
+fn put_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_ne_bytes())
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_ne_bytes())
+}
+
+fn put_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_ne_bytes())
+}
+
+/// A reply payload, or an errno to reply with instead
+type Reply = ::std::result::Result<Vec<u8>, c_int>;
+
+/// A file or directory handed out to the kernel, numbered from 1
+#[derive(Debug,Clone)]
+struct Inode {
+    hash: ObjectKey,
+    parent: u64,
+    is_dir: bool,
+    size: ObjectSize,
+    mtime_secs: u64,
+    mtime_nanos: u32,
+    /// Permission bits, without any write permission
+    perms: u32,
+    /// Entries by name, once the directory has been read
+    children: Option<Vec<(OsString, u64)>>,
+}
+
+/// The tree of one object, served read-only through FUSE
+pub struct RevisionFs<'a> {
+    store: &'a ObjectStore,
+    inodes: Vec<Inode>,
+    uid: u32,
+    gid: u32,
+}
+
+impl<'a> RevisionFs<'a> {
+    /// A filesystem of the tree of a commit, or of a tree itself
+    ///
+    /// The root directory has the time it was mounted.
+    pub fn new(store: &'a ObjectStore, root: ObjectKey) -> Result<Self> {
+        let node: ObjectWalkNode = store.lookup_node(root)?;
+        if !node.object_type.is_treeish() {
+            bail!("{} is a {:?}, but only trees can be mounted",
+                  root,
+                  node.object_type);
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Ok(RevisionFs {
+            store: store,
+            inodes: vec![Inode {
+                             hash: root,
+                             parent: 1,
+                             is_dir: true,
+                             size: 0,
+                             mtime_secs: now.as_secs(),
+                             mtime_nanos: now.subsec_nanos(),
+                             perms: 0o555,
+                             children: None,
+                         }],
+            uid: unsafe { getuid() },
+            gid: unsafe { getgid() },
+        })
+    }
+
+    /// Answer requests from the kernel until the filesystem is unmounted
+    pub fn serve(mut self, mut device: fs::File) -> Result<()> {
+        let mut buf = vec![0u8; REQUEST_BUFFER_SIZE];
+        loop {
+            let len = match device.read(&mut buf) {
+                Ok(len) => len,
+                Err(e) => {
+                    match e.raw_os_error() {
+                        // Interrupted, or the request was withdrawn
+                        Some(EINTR) | Some(EAGAIN) | Some(ENOENT) => continue,
+                        Some(ENODEV) => return Ok(()),
+                        _ => return Err(e.into()),
+                    }
+                }
+            };
+            if len < IN_HEADER_SIZE {
+                bail!("Short FUSE request: {} bytes", len);
+            }
+            let request = &buf[..len];
+            let opcode = u32_at(request, 4);
+            let unique = u64_at(request, 8);
+            let ino = u64_at(request, 16);
+            let reply = match opcode {
+                FUSE_FORGET | FUSE_BATCH_FORGET | FUSE_INTERRUPT => continue,
+                FUSE_DESTROY => return Ok(()),
+                _ => self.handle(opcode, ino, &request[IN_HEADER_SIZE..]),
+            };
+            let (error, payload) = match reply {
+                Ok(payload) => (0, payload),
+                Err(errno) => (-errno, Vec::new()),
+            };
+            let mut out = Vec::with_capacity(OUT_HEADER_SIZE + payload.len());
+            put_u32(&mut out, (OUT_HEADER_SIZE + payload.len()) as u32);
+            put_u32(&mut out, error as u32);
+            put_u64(&mut out, unique);
+            out.extend(payload);
+            match device.write(&out) {
+                Err(ref e) if e.raw_os_error() == Some(ENOENT) => {}
+                Err(e) => return Err(e.into()),
+                Ok(_) => {}
+            }
+        }
+    }
+
+    fn handle(&mut self, opcode: u32, ino: u64, body: &[u8]) -> Reply {
+        trace!("FUSE request {} for inode {}", opcode, ino);
+        match opcode {
+            FUSE_INIT => init(body),
+            FUSE_LOOKUP => {
+                let name = body.split(|&b| b == 0).next().unwrap_or(&[]);
+                let child = self.children(ino)?
+                    .iter()
+                    .find(|entry| entry.0.as_bytes() == name)
+                    .map(|entry| entry.1)
+                    .ok_or(ENOENT)?;
+                let mut out = Vec::new();
+                put_u64(&mut out, child);
+                put_u64(&mut out, 0);
+                put_u64(&mut out, TTL_SECS);
+                put_u64(&mut out, TTL_SECS);
+                put_u32(&mut out, 0);
+                put_u32(&mut out, 0);
+                self.put_attr(&mut out, child)?;
+                Ok(out)
+            }
+            FUSE_GETATTR => {
+                let mut out = Vec::new();
+                put_u64(&mut out, TTL_SECS);
+                put_u32(&mut out, 0);
+                put_u32(&mut out, 0);
+                self.put_attr(&mut out, ino)?;
+                Ok(out)
+            }
+            FUSE_OPEN => {
+                if self.inode(ino)?.is_dir {
+                    return Err(EISDIR);
+                }
+                if u32_at(body, 0) & O_ACCMODE != 0 {
+                    return Err(EROFS);
+                }
+                let mut out = Vec::new();
+                put_u64(&mut out, 0);
+                put_u32(&mut out, FOPEN_KEEP_CACHE);
+                put_u32(&mut out, 0);
+                Ok(out)
+            }
+            FUSE_READ => {
+                let hash = self.inode(ino)?.hash;
+                let offset = u64_at(body, 8);
+                let size = u32_at(body, 16) as ObjectSize;
+                let mut out = Vec::new();
+                self.store
+                    .copy_blob_range(&hash, offset, size, &mut out)
+                    .map_err(|e| {
+                        warn!("Could not read {}: {}", hash, e);
+                        EIO
+                    })?;
+                Ok(out)
+            }
+            FUSE_OPENDIR => {
+                if !self.inode(ino)?.is_dir {
+                    return Err(ENOTDIR);
+                }
+                Ok(vec![0; 16])
+            }
+            FUSE_READDIR => {
+                self.read_dir(ino, u64_at(body, 8), u32_at(body, 16))
+            }
+            FUSE_RELEASE | FUSE_RELEASEDIR => Ok(Vec::new()),
+            FUSE_STATFS => {
+                let mut out = vec![0; 40];
+                put_u32(&mut out, 4096);
+                put_u32(&mut out, 255);
+                put_u32(&mut out, 4096);
+                out.extend(&[0; 28]);
+                Ok(out)
+            }
+            _ => Err(ENOSYS),
+        }
+    }
+
+    fn inode(&self, ino: u64) -> ::std::result::Result<&Inode, c_int> {
+        match ino {
+            0 => Err(ENOENT),
+            _ => self.inodes.get(ino as usize - 1).ok_or(ENOENT),
+        }
+    }
+
+    /// Entries of a directory, reading its tree the first time
+    fn children(&mut self,
+                ino: u64)
+                -> ::std::result::Result<&[(OsString, u64)], c_int> {
+        if !self.inode(ino)?.is_dir {
+            return Err(ENOTDIR);
+        }
+        if self.inodes[ino as usize - 1].children.is_none() {
+            let hash = self.inodes[ino as usize - 1].hash;
+            self.read_tree(ino, &hash).map_err(|e| {
+                    warn!("Could not read tree {}: {}", hash, e);
+                    EIO
+                })?;
+        }
+        Ok(self.inodes[ino as usize - 1].children.as_ref().expect("just read"))
+    }
+
+    fn read_tree(&mut self, ino: u64, hash: &ObjectKey) -> Result<()> {
+        let tree = self.store.open_tree(hash)?;
+        let mut children = Vec::new();
+        // Without recorded metadata, entries have the root's time
+        let (root_secs, root_nanos) = (self.inodes[0].mtime_secs,
+                                       self.inodes[0].mtime_nanos);
+        for (name, key) in tree.iter() {
+            // Left out of a shallow fetch, so not even its size is known
+            if !self.store.has_object(key) && !self.store.is_absent(key) {
+                continue;
+            }
+            let node: ObjectWalkNode = self.store.lookup_node(*key)?;
+            let is_dir = node.object_type.is_treeish();
+            let default_perms = if is_dir { 0o555 } else { 0o444 };
+            let metadata = tree.metadata.get(name);
+            self.inodes.push(Inode {
+                hash: *key,
+                parent: ino,
+                is_dir: is_dir,
+                size: node.file_size,
+                mtime_secs: metadata.map_or(root_secs, |m| m.mtime_secs),
+                mtime_nanos: metadata.map_or(root_nanos, |m| m.mtime_nanos),
+                perms: metadata.map_or(default_perms, |m| m.mode & 0o555),
+                children: None,
+            });
+            children.push((name.clone(), self.inodes.len() as u64));
+        }
+        self.inodes[ino as usize - 1].children = Some(children);
+        Ok(())
+    }
+
+    /// Write a `struct fuse_attr`
+    fn put_attr(&self,
+                out: &mut Vec<u8>,
+                ino: u64)
+                -> ::std::result::Result<(), c_int> {
+        let inode = self.inode(ino)?;
+        let (kind, nlink) = match inode.is_dir {
+            true => (S_IFDIR, 2),
+            false => (S_IFREG, 1),
+        };
+        put_u64(out, ino);
+        put_u64(out, inode.size);
+        put_u64(out, (inode.size + 511) / 512);
+        for _ in 0..3 {
+            put_u64(out, inode.mtime_secs);
+        }
+        for _ in 0..3 {
+            put_u32(out, inode.mtime_nanos);
+        }
+        put_u32(out, kind | inode.perms);
+        put_u32(out, nlink);
+        put_u32(out, self.uid);
+        put_u32(out, self.gid);
+        put_u32(out, 0);
+        put_u32(out, 4096);
+        put_u32(out, 0);
+        Ok(())
+    }
+
+    /// Write as many `struct fuse_dirent` as fit, starting at an offset
+    fn read_dir(&mut self, ino: u64, offset: u64, size: u32) -> Reply {
+        let parent = self.inode(ino)?.parent;
+        let mut entries = vec![(OsString::from("."), ino),
+                               (OsString::from(".."), parent)];
+        entries.extend(self.children(ino)?.iter().cloned());
+        let mut out = Vec::new();
+        for (i, (name, child)) in entries.into_iter()
+            .enumerate()
+            .skip(offset as usize) {
+            let name = name.as_bytes();
+            let len = DIRENT_HEADER_SIZE + name.len();
+            let padded = (len + 7) & !7;
+            if out.len() + padded > size as usize {
+                break;
+            }
+            let kind = match self.inode(child)?.is_dir {
+                true => DT_DIR,
+                false => DT_REG,
+            };
+            put_u64(&mut out, child);
+            put_u64(&mut out, i as u64 + 1);
+            put_u32(&mut out, name.len() as u32);
+            put_u32(&mut out, kind);
+            out.extend_from_slice(name);
+            out.resize(out.len() + padded - len, 0);
+        }
+        Ok(out)
+    }
+}
+
+/// Reply to `FUSE_INIT` with a `struct fuse_init_out`
+fn init(body: &[u8]) -> Reply {
+    let major = u32_at(body, 0);
+    let max_readahead = u32_at(body, 8);
+    if major != PROTOCOL_MAJOR {
+        warn!("Unsupported FUSE protocol: {}.{}", major, u32_at(body, 4));
+        return Err(EPROTO);
+    }
+    let mut out = Vec::new();
+    put_u32(&mut out, PROTOCOL_MAJOR);
+    put_u32(&mut out, PROTOCOL_MINOR);
+    put_u32(&mut out, max_readahead);
+    put_u32(&mut out, 0);
+    put_u16(&mut out, 16);
+    put_u16(&mut out, 12);
+    put_u32(&mut out, MAX_WRITE);
+    put_u32(&mut out, 1);
+    out.resize(64, 0);
+    Ok(out)
+}
+
+
+#[cfg(test)]
+mod test {
+    use dag::Blob;
+    use dag::ChunkedBlob;
+    use object_store::test::create_temp_repository;
+    use super::*;
+
+    fn read_in(offset: u64, size: u32) -> Vec<u8> {
+        let mut body = Vec::new();
+        put_u64(&mut body, 0);
+        put_u64(&mut body, offset);
+        put_u32(&mut body, size);
+        body.resize(40, 0);
+        body
+    }
+
+    #[test]
+    fn test_revision_fs() {
+        let (_tempdir, store) = create_temp_repository().unwrap();
+        let mut index = ChunkedBlob::new();
+        for chunk in &["one ", "two ", "three"] {
+            let hash = store.store_object(&Blob::from(*chunk)).unwrap();
+            index.add_chunk(chunk.len() as ObjectSize, hash);
+        }
+        let video = store.store_object(&index).unwrap();
+        let subdir = store.store_object(&tree_object!{ "clip.mp4" => video, })
+            .unwrap();
+        let root = store.store_object(&tree_object!{ "videos" => subdir, })
+            .unwrap();
+        assert!(RevisionFs::new(&store, video).is_err());
+        let mut fs = RevisionFs::new(&store, root).unwrap();
+
+        let init = fs.handle(FUSE_INIT, 0, &[7, 0, 0, 0, 31, 0, 0, 0,
+                                                    0, 0, 2, 0, 0, 0, 0, 0])
+            .unwrap();
+        assert_eq!(init.len(), 64);
+        assert_eq!(u32_at(&init, 0), PROTOCOL_MAJOR);
+
+        // Look up by name, getting inode numbers and attributes
+        assert_eq!(fs.handle(FUSE_LOOKUP, 1, b"nothing\0"),
+                   Err(ENOENT));
+        let entry = fs.handle(FUSE_LOOKUP, 1, b"videos\0").unwrap();
+        let videos = u64_at(&entry, 0);
+        assert_eq!(u32_at(&entry, 40 + 60) & S_IFDIR, S_IFDIR);
+        let entry = fs.handle(FUSE_LOOKUP, videos, b"clip.mp4\0")
+            .unwrap();
+        let clip = u64_at(&entry, 0);
+        assert_eq!(u64_at(&entry, 40 + 8), 13, "Size of the file");
+        let attr = fs.handle(FUSE_GETATTR, clip, &[0; 16]).unwrap();
+        assert_eq!(u32_at(&attr, 16 + 60), S_IFREG | 0o444);
+        assert_eq!(fs.handle(FUSE_LOOKUP, clip, b"x\0"),
+                   Err(ENOTDIR));
+
+        // List a directory, starting again from an offset
+        let listing = fs.handle(FUSE_READDIR, videos, &read_in(0, 4096))
+            .unwrap();
+        assert_eq!(listing.len(), 32 + 32 + 32);
+        assert_eq!(&listing[64 + 24..64 + 32], b"clip.mp4");
+        let rest = fs.handle(FUSE_READDIR, videos, &read_in(2, 4096))
+            .unwrap();
+        assert_eq!(rest, &listing[64..]);
+        let part = fs.handle(FUSE_READDIR, videos, &read_in(0, 40))
+            .unwrap();
+        assert_eq!(part.len(), 32, "Only whole entries that fit");
+
+        // Read part of the file, and refuse to write
+        assert_eq!(fs.handle(FUSE_OPEN, clip, &[1, 0, 0, 0, 0, 0, 0, 0]),
+                   Err(EROFS));
+        assert!(fs.handle(FUSE_OPEN, clip, &[0; 8]).is_ok());
+        assert_eq!(fs.handle(FUSE_READ, clip, &read_in(6, 5)).unwrap(),
+                   b"o thr");
+        assert_eq!(fs.handle(FUSE_READ, clip, &read_in(20, 5)).unwrap(),
+                   b"");
+        let setxattr = 21;
+        assert_eq!(fs.handle(setxattr, clip, &[]), Err(ENOSYS));
+    }
+}
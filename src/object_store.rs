@@ -1,291 +1,1664 @@
+use config::EncryptionScope;
+use config::RepoConfig;
+use constants::TREE_MEMO_LIMIT;
 use dag::*;
 use disk_backed::DiskBacked;
+use encryption;
+use encryption::KeyFile;
+use encryption::RepoKey;
 use error::*;
-use filebuffer::FileBuffer;
+use exif::MediaMetadata;
 use fsutil;
 use human_readable::human_bytes;
+use human_readable::human_duration;
+use human_readable::human_rate;
+use locations::Locations;
+use lock::FileLock;
+use lock::LockMode;
+use lock::RepoLock;
 use log::LogLevel;
+use media_type::ChunkStrategy;
+use media_type::MediaType;
+use parity::Parity;
+use pathspec::Pathspec;
+use policy::SizePolicy;
 use progress::*;
+use reflog::Reflog;
 use regex::Regex;
 use revisions::*;
+use rolling_hash::ChunkWriter;
 use status::ComparableNode;
+use std::cmp;
 use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
 use std::collections::HashSet;
 use std::collections::VecDeque;
+use std::ffi::OsString;
 use std::fmt;
 use std::fs;
 use std::io;
+use std::io::BufRead;
 use std::io::Read;
 use std::io::Write;
 use std::iter;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
 use std::thread;
+use std::time::Duration;
 use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+use storage::DiskStorage;
+#[cfg(any(test, feature = "testing"))]
+use storage::MemoryStorage;
+use storage::StorageBackend;
+use store_format::FanOut;
+use store_format::StoreFormat;
+use transport::Remote;
+use transport::RemoteMap;
+use transport::TRACKING_REF_PREFIX;
+use transport::is_tracking_ref;
+use transport::tracking_ref_name;
 use variance::VarianceCalc;
 use walker::*;
 
-type RefMap = BTreeMap<String, ObjectKey>;
+pub type RefMap = BTreeMap<String, ObjectKey>;
+
+/// Set of commits whose parents are not present, due to a shallow fetch
+pub type ShallowSet = BTreeSet<ObjectKey>;
+
+/// Blobs whose content was left out by a partial fetch, with their sizes
+///
+/// Trees and chunk indexes are always fetched, so the store knows which files
+/// it lacks. Each absent blob's size is kept so that trees can still be
+/// compared and sized without it. The locations database records where the
+/// content can be fetched from.
+pub type AbsentMap = BTreeMap<ObjectKey, ObjectSize>;
+
+/// Media types of files, by their hashes
+///
+/// Each file's type is sniffed from its content as it is stored, and kept in
+/// a small record of its own in `media-types`, beside the objects. Records
+/// travel with the files in fetches and pushes, and are sealed in an
+/// encrypted store.
+pub type MediaTypeMap = BTreeMap<ObjectKey, MediaType>;
+
+/// Size of a media type record, padded so that a sealed record's length does
+/// not give the type away
+const MEDIA_RECORD_SIZE: usize = 32;
+
+/// Objects derived from files, by source file and then derivation name
+///
+/// Keys are content hashes, so an edited file has a new key, and anything
+/// derived from its old content is simply never looked up again.
+pub type DerivedMap = BTreeMap<ObjectKey, BTreeMap<String, ObjectKey>>;
+
+/// Objects found reachable, and missing objects with what references them
+pub type Reachable = (HashSet<ObjectKey>, Vec<(ObjectKey, ObjectKey)>);
+
+/// Files changed by a commit, compared to its first parent
+#[derive(Debug,Clone,Copy,Default,PartialEq,Eq,Hash)]
+#[derive(RustcEncodable,RustcDecodable)]
+pub struct ChangeStats {
+    pub added: u64,
+    pub modified: u64,
+    pub deleted: u64,
+    /// Change in the total size of all files, in bytes
+    pub byte_delta: i64,
+}
+
+/// Change summaries, by commit
+///
+/// Commits never change, so neither do their summaries, and they are kept
+/// to make listing history again quick.
+pub type ChangeStatsMap = BTreeMap<ObjectKey, ChangeStats>;
+
+/// Running totals of the objects in a store, kept up to date as they are
+/// stored, so that `dmv info` need not read every object
+///
+/// A store from before these were kept only counts objects stored since, and
+/// is not `complete` until `ObjectStore::recount_objects` has been run.
+#[derive(Debug,Clone,Default,PartialEq,Eq,Hash)]
+#[derive(RustcEncodable,RustcDecodable)]
+pub struct ObjectCounts {
+    /// Were all objects counted, rather than only those stored since?
+    pub complete: bool,
+    pub by_type: BTreeMap<ObjectType, ObjectCount>,
+    /// Content that was already stored when asked to store it again
+    pub duplicate_bytes: ObjectSize,
+}
+
+/// Count and sizes of the objects of one type
+#[derive(Debug,Clone,Copy,Default,PartialEq,Eq,Hash)]
+#[derive(RustcEncodable,RustcDecodable)]
+pub struct ObjectCount {
+    pub objects: u64,
+    /// Total size of the objects' content
+    pub content_bytes: ObjectSize,
+    /// Total size of the object files on disk, with headers and sealing
+    pub stored_bytes: ObjectSize,
+}
+
+impl ObjectCounts {
+    pub fn add(&mut self,
+               object_type: ObjectType,
+               content_size: ObjectSize,
+               stored_size: ObjectSize) {
+        let count = self.by_type
+            .entry(object_type)
+            .or_default();
+        count.objects += 1;
+        count.content_bytes += content_size;
+        count.stored_bytes += stored_size;
+    }
+
+    /// Totals over all types
+    pub fn total(&self) -> ObjectCount {
+        let mut total = ObjectCount::default();
+        for count in self.by_type.values() {
+            total.objects += count.objects;
+            total.content_bytes += count.content_bytes;
+            total.stored_bytes += count.stored_bytes;
+        }
+        total
+    }
+
+    /// Content offered to the store, over content actually stored
+    pub fn dedup_ratio(&self) -> f64 {
+        let stored = self.total().content_bytes;
+        ratio(stored + self.duplicate_bytes, stored)
+    }
+}
 
 pub struct ObjectStore {
     path: PathBuf,
+    /// Is everything kept in memory, with nothing in `path`? See `in_memory`
+    in_memory: bool,
+    /// Where object files are kept, by default in `path`
+    storage: Box<StorageBackend>,
+    config: DiskBacked<RepoConfig>,
+    format: DiskBacked<StoreFormat>,
     refs: DiskBacked<RefMap>,
+    remotes: DiskBacked<RemoteMap>,
+    shallow: DiskBacked<ShallowSet>,
+    absent: DiskBacked<AbsentMap>,
+    locations: Locations,
+    reflog: Reflog,
+    derived: DiskBacked<DerivedMap>,
+    size_policy: DiskBacked<SizePolicy>,
+    /// Filled in while listing history, which only borrows the store
+    change_stats: Mutex<DiskBacked<ChangeStatsMap>>,
+    /// Updated as objects are stored, which only borrows the store
+    object_counts: Mutex<DiskBacked<ObjectCounts>>,
+    /// Children of trees already walked, see `tree_children`
+    tree_memo: Mutex<HashMap<ObjectKey, ChildMap<ObjectWalkNode>>>,
+    /// Key of an encrypted store, once unlocked
+    repo_key: Mutex<Option<RepoKey>>,
+    /// What `HEAD` refers to in revisions, see `set_head`
+    head: Option<(ObjectKey, Option<RevNameBuf>)>,
 }
 
 impl ObjectStore {
     pub fn init(path: PathBuf) -> Result<Self> {
+        Self::init_with_config(path, RepoConfig::default())
+    }
+
+    /// Create a store with the given config
+    ///
+    /// If the config asks for encryption, a new passphrase is read from the
+    /// environment or the terminal.
+    pub fn init_with_config(path: PathBuf, config: RepoConfig) -> Result<Self> {
+        if config.encryption.is_some() {
+            let passphrase = encryption::read_new_passphrase()?;
+            return Self::init_encrypted(path, config, &passphrase);
+        }
+        let storage = DiskStorage::new(path.clone());
+        Self::init_with_storage(path, config, Box::new(storage))
+    }
+
+    /// Create an unencrypted store whose object files are kept in other
+    /// storage, such as `MemoryStorage` in tests
+    pub fn init_with_storage(path: PathBuf,
+                             config: RepoConfig,
+                             storage: Box<StorageBackend>)
+                             -> Result<Self> {
+        if config.encryption.is_some() {
+            bail!("Create encrypted stores with init_encrypted");
+        }
         try!(fs::create_dir_all(&path));
-        Self::open(path)
+        DiskBacked::init("config", path.join("config"), config).flush()?;
+        let store = Self::open_with_storage(path, storage)?;
+        store.object_counts.lock().unwrap().complete = true;
+        Ok(store)
+    }
+
+    /// Create an encrypted store, with a new key wrapped by the passphrase
+    ///
+    /// File contents are encrypted, and metadata too if the config says so.
+    pub fn init_encrypted(path: PathBuf,
+                          mut config: RepoConfig,
+                          passphrase: &str)
+                          -> Result<Self> {
+        if config.encryption.is_none() {
+            config.encryption = Some(EncryptionScope::Content);
+        }
+        fs::create_dir_all(&path)?;
+        let key = RepoKey::generate()?;
+        DiskBacked::init("key",
+                         path.join("key"),
+                         KeyFile::wrap(&key, passphrase)?)
+            .flush()?;
+        DiskBacked::init("config", path.join("config"), config).flush()?;
+        let store = Self::open(path)?;
+        store.object_counts.lock().unwrap().complete = true;
+        *store.repo_key.lock().unwrap() = Some(key);
+        Ok(store)
     }
 
     pub fn open(path: PathBuf) -> Result<Self> {
+        let storage = DiskStorage::new(path.clone());
+        Self::open_with_storage(path, Box::new(storage))
+    }
+
+    /// Open a store whose object files are kept in other storage
+    ///
+    /// Refs and other metadata are still kept in `path`.
+    pub fn open_with_storage(path: PathBuf,
+                             storage: Box<StorageBackend>)
+                             -> Result<Self> {
         Ok(ObjectStore {
+            storage: storage,
+            config: DiskBacked::read_or("config",
+                                        path.join("config"),
+                                        RepoConfig::legacy())?,
+            format: DiskBacked::read_or_default("store format",
+                                                path.join("format"))?,
             refs: DiskBacked::read_or_default("refs", path.join("refs"))?,
+            remotes: DiskBacked::read_or_default("remotes",
+                                                 path.join("remotes"))?,
+            shallow: DiskBacked::read_or_default("shallow",
+                                                 path.join("shallow"))?,
+            absent: DiskBacked::read_or_default("absent",
+                                                path.join("absent"))?,
+            locations: Locations::open(path.join("locations"))?,
+            reflog: Reflog::open(path.join("reflog"))?,
+            derived: DiskBacked::read_or_default("derived objects",
+                                                 path.join("derived"))?,
+            size_policy: DiskBacked::read_or_default("size policy",
+                                                     path.join("policy"))?,
+            change_stats:
+                Mutex::new(DiskBacked::read_or_default("log stats",
+                                                       path.join("changes"))?),
+            object_counts:
+                Mutex::new(DiskBacked::read_or_default("object counts",
+                                                       path.join("counts"))?),
+            tree_memo: Mutex::new(HashMap::new()),
+            repo_key: Mutex::new(None),
+            head: None,
             path: path,
+            in_memory: false,
+        })
+    }
+
+    /// Create an unencrypted store that keeps everything in memory
+    ///
+    /// Object files are kept in a `MemoryStorage`, and refs and other
+    /// metadata are never written, so tests of code that takes an
+    /// `ObjectStore` need no directory. The store's path is empty, and its
+    /// locks are always free. Files kept beside a store, such as parity, are
+    /// not available.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn in_memory(config: RepoConfig) -> Result<Self> {
+        if config.encryption.is_some() {
+            bail!("An encrypted store cannot be kept in memory");
+        }
+        let counts = ObjectCounts {
+            complete: true,
+            ..ObjectCounts::default()
+        };
+        Ok(ObjectStore {
+            storage: Box::new(MemoryStorage::new()),
+            config: DiskBacked::in_memory("config", config),
+            format: DiskBacked::in_memory("store format", Default::default()),
+            refs: DiskBacked::in_memory("refs", Default::default()),
+            remotes: DiskBacked::in_memory("remotes", Default::default()),
+            shallow: DiskBacked::in_memory("shallow", Default::default()),
+            absent: DiskBacked::in_memory("absent", Default::default()),
+            locations: Locations::in_memory(),
+            reflog: Reflog::in_memory(),
+            derived: DiskBacked::in_memory("derived objects",
+                                           Default::default()),
+            size_policy: DiskBacked::in_memory("size policy",
+                                               Default::default()),
+            change_stats:
+                Mutex::new(DiskBacked::in_memory("log stats",
+                                                 Default::default())),
+            object_counts:
+                Mutex::new(DiskBacked::in_memory("object counts", counts)),
+            tree_memo: Mutex::new(HashMap::new()),
+            repo_key: Mutex::new(None),
+            head: None,
+            path: PathBuf::new(),
+            in_memory: true,
         })
     }
 
+    /// Unlock an encrypted store with its passphrase
+    ///
+    /// Otherwise, the passphrase is read from the environment or the terminal
+    /// when the key is first needed.
+    pub fn unlock(&self, passphrase: &str) -> Result<()> {
+        let key = self.read_key_file()?.unwrap(passphrase)?;
+        *self.repo_key.lock().unwrap() = Some(key);
+        Ok(())
+    }
+
+    fn read_key_file(&self) -> Result<KeyFile> {
+        let key_file: DiskBacked<KeyFile> =
+            DiskBacked::read("key", self.path.join("key"))?;
+        Ok(key_file.clone())
+    }
+
+    /// The key of an encrypted store, asking for the passphrase if needed
+    fn repo_key(&self) -> Result<RepoKey> {
+        let mut repo_key = self.repo_key.lock().unwrap();
+        if repo_key.is_none() {
+            let prompt = format!("Passphrase for {}: ", self.path.display());
+            let passphrase = encryption::read_passphrase(&prompt)?;
+            *repo_key = Some(self.read_key_file()?.unwrap(&passphrase)?);
+        }
+        Ok(repo_key.clone().expect("just unlocked"))
+    }
+
+    /// Should objects of this type be encrypted when stored?
+    fn seals(&self, object_type: ObjectType) -> bool {
+        self.config.encryption.is_some_and(|scope| scope.covers(object_type))
+    }
+
+    /// Decrypt a sealed object file
+    fn unseal(&self, key: &ObjectKey, sealed: &[u8]) -> Result<Vec<u8>> {
+        self.repo_key()?
+            .open(sealed)
+            .chain_err(|| format!("Could not decrypt object {}", key))
+    }
+
     pub fn path(&self) -> &Path { &self.path }
 
+    /// Is everything kept in memory, with nothing on disk?
+    pub fn is_in_memory(&self) -> bool { self.in_memory }
+
+    pub fn config(&self) -> &RepoConfig { &self.config }
+
+    /// Limits on how much a commit may add (see `policy`)
+    pub fn size_policy(&self) -> &SizePolicy { &self.size_policy }
+
+    pub fn set_size_policy(&mut self, policy: SizePolicy) -> Result<()> {
+        *self.size_policy = policy;
+        self.size_policy.flush().map_err(|e| e.into())
+    }
+
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        self.config.hash_algorithm()
+    }
+
+    /// Take a lock on the repository, named for the given command
+    pub fn lock(&self, mode: LockMode, command: &str) -> Result<RepoLock> {
+        if self.in_memory {
+            return Ok(RepoLock::in_process(mode));
+        }
+        RepoLock::acquire(&self.path, mode, command)
+    }
+
+    /// How object files are spread across directories
+    pub fn fan_out(&self) -> FanOut { self.format.fan_out }
+
+    /// Where object files are kept
+    pub fn storage(&self) -> &StorageBackend { &*self.storage }
+
+    /// Name of an object file in storage, whether present or not
+    ///
+    /// During a repack, an object not yet moved is found in the old layout.
+    fn object_name(&self, key: &ObjectKey) -> String {
+        let hex = key.to_hex();
+        let name = self.object_name_in(self.format.fan_out, &hex);
+        if let Some(old) = self.format.migrating_from {
+            let old_name = self.object_name_in(old, &hex);
+            if !self.storage.exists(&name) && self.storage.exists(&old_name) {
+                return old_name;
+            }
+        }
+        name
+    }
+
+    fn object_name_in(&self, fan_out: FanOut, hex: &str) -> String {
+        let parts = fan_out.object_path(hex)
+            .iter()
+            .map(|part| part.to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        format!("objects/{}", parts.join("/"))
+    }
+
+    /// Path of an object file on disk, for tests that damage it
+    #[cfg(test)]
     fn object_path(&self, key: &ObjectKey) -> PathBuf {
-        self.object_path_sloppy(&key.to_hex())
+        self.path.join(self.object_name(key))
+    }
+
+    #[cfg(test)]
+    fn object_path_in(&self, fan_out: FanOut, hex: &str) -> PathBuf {
+        self.path.join(self.object_name_in(fan_out, hex))
+    }
+
+    fn object_from_name(&self, name: &str) -> Result<ObjectKey> {
+        if !name.starts_with("objects/") {
+            bail!("Not an object file: {}", name);
+        }
+        ObjectKey::parse(&name["objects/".len()..].replace("/", ""))
     }
 
-    fn object_path_sloppy(&self, key: &str) -> PathBuf {
-        self.path
-            .join("objects")
-            .join(&key[0..2])
-            .join(&key[2..])
+    /// Name of the directory holding an object file, within `objects`
+    fn object_dir_key(&self, name: &str) -> String {
+        match name.rfind('/') {
+            Some(end) if end > "objects".len() => {
+                name["objects/".len()..end].to_owned()
+            }
+            _ => String::new(),
+        }
+    }
+
+    /// Names of all object files in storage, in any layout
+    pub fn object_names(&self) -> Result<Vec<String>> {
+        self.storage.list("objects")
+    }
+
+    /// Move objects into a new layout, or finish an interrupted move
+    ///
+    /// The new layout is recorded before any objects move, with the old one
+    /// as where to look for objects not yet moved, so the store stays usable
+    /// if this is interrupted. Running it again finishes the job. Returns the
+    /// number of objects moved.
+    pub fn repack(&mut self, fan_out: Option<FanOut>) -> Result<usize> {
+        let mut moved = 0;
+        if self.format.migrating_from.is_some() {
+            moved += self.move_objects()?;
+        }
+        match fan_out {
+            Some(fan_out) if fan_out != self.format.fan_out => {
+                let old = self.format.fan_out;
+                self.format.migrating_from = Some(old);
+                self.format.fan_out = fan_out;
+                self.format.flush()?;
+                moved += self.move_objects()?;
+            }
+            _ => {}
+        }
+        Ok(moved)
     }
 
-    fn object_from_path(&self, path: &Path) -> Result<ObjectKey> {
-        let key_path = path.strip_prefix(&self.path)
-            .and_then(|p| p.strip_prefix("objects"))?;
-        let key_str = key_path.to_str()
-            .expect("should be ascii")
-            .replace("/", "");
-        ObjectKey::parse(&key_str)
+    /// Move every object file that is not where the layout puts it
+    fn move_objects(&mut self) -> Result<usize> {
+        let mut moved = 0;
+        for name in self.object_names()? {
+            let key = self.object_from_name(&name)?;
+            let dest = self.object_name_in(self.format.fan_out, &key.to_hex());
+            if name != dest {
+                trace!("Moving {} to {}", name, dest);
+                self.storage.rename(&name, &dest)?;
+                moved += 1;
+            }
+        }
+        self.storage.tidy("objects")?;
+        self.format.migrating_from = None;
+        self.format.flush()?;
+        Ok(moved)
     }
 
     /// Check all stored objects
     ///
-    /// Returns a list of bad objects: file name key vs actual hash
-    pub fn fsck(&self) -> Result<Vec<(ObjectKey, ObjectKey)>> {
-        let mut obj_count = 0;
+    /// Verifies the hash of every object, then walks everything reachable
+    /// from the refs, the given extra roots (such as work dir parents), and
+    /// objects derived from files (see `derive`) to find missing and
+    /// unreachable objects.
+    ///
+    /// If `repair` is set, corrupt objects are moved out of the way into the
+    /// `corrupt` directory, and chunked blob indexes whose sizes do not add up
+    /// are rewritten from their chunk sizes. Since objects are addressed by
+    /// content, the corrected index is a new object, and the old one is left
+    /// in place. Then, corrupt and missing objects covered by parity (see
+    /// `parity`) are rebuilt. Statistics are printed to stderr.
+    pub fn fsck(&self,
+                repair: bool,
+                extra_roots: &[ObjectKey])
+                -> Result<FsckReport> {
+        let options = FsckOptions {
+            repair: repair,
+            ..FsckOptions::default()
+        };
+        self.fsck_with(&options, extra_roots)
+    }
+
+    /// Check stored objects, as set by the options
+    ///
+    /// Each fan-out directory of objects that checks out without problems
+    /// is given a watermark: the time that the check started. An incremental
+    /// check only verifies the hashes of objects written since their
+    /// directory's watermark. Missing and unreachable objects are still
+    /// found by walking from the refs, which only reads trees and indexes.
+    pub fn fsck_with(&self,
+                     options: &FsckOptions,
+                     extra_roots: &[ObjectKey])
+                     -> Result<FsckReport> {
+        let repair = options.repair;
+        let started = SystemTime::now();
+        let mut watermarks: DiskBacked<FsckWatermarks> = if self.in_memory {
+            DiskBacked::in_memory("fsck watermarks", FsckWatermarks::new())
+        } else {
+            DiskBacked::read_or_default("fsck watermarks",
+                                        self.path.join("fsck-watermarks"))?
+        };
+
+        let mut report = FsckReport::default();
+        let all_files = self.object_names()?;
+        let mut object_files = Vec::new();
         let mut total_bytes = 0;
-        for dir1 in fs::read_dir(self.path.join("objects"))? {
-            let dir1 = dir1?;
-            for obj_file in fs::read_dir(dir1.path())? {
-                let obj_file = obj_file?;
-                obj_count += 1;
-                total_bytes += obj_file.metadata()?.len();
+        for obj_file in &all_files {
+            let stored = self.storage.stat(obj_file)?;
+            if options.incremental {
+                let watermark = watermarks.get(&self.object_dir_key(obj_file));
+                if watermark.is_some_and(|&mark| stored.modified < mark) {
+                    continue;
+                }
             }
+            total_bytes += stored.size;
+            object_files.push(obj_file.clone());
         }
 
-        stderrln!("{} objects, {}", obj_count, human_bytes(total_bytes));
+        if options.incremental {
+            stderrln!("{} of {} objects written since last checked, {}",
+                      object_files.len(),
+                      all_files.len(),
+                      human_bytes(total_bytes));
+        } else {
+            stderrln!("{} objects, {}",
+                      object_files.len(),
+                      human_bytes(total_bytes));
+        }
+        if self.config.encryption.is_some() {
+            // Unlock now, so a wrong passphrase fails rather than making
+            // every object look corrupt
+            self.repo_key()?;
+        }
 
         let prog = ProgressCounter::arc("Verifying", total_bytes);
         let prog_clone = prog.clone();
         let prog_thread = thread::spawn(move || std_err_watch(prog_clone));
-        let mut bad_hashes = Vec::new();
 
-        let mut size_stats = VarianceCalc::new();
-        let mut stats_by_type = BTreeMap::<ObjectType, VarianceCalc>::new();
-        for t in &[ObjectType::Blob,
-                   ObjectType::ChunkedBlob,
-                   ObjectType::Tree,
-                   ObjectType::Commit] {
-            stats_by_type.insert(*t, VarianceCalc::new());
-        }
-
-        for dir1 in fs::read_dir(self.path.join("objects"))? {
-            let dir1 = dir1?;
-            for obj_file in fs::read_dir(dir1.path())? {
-                let obj_file = obj_file?;
+        // Each worker takes the next file to verify, and keeps its own tally
+        let next_file = AtomicUsize::new(0);
+        let failed = AtomicBool::new(false);
+        let tallies = thread::scope(|scope| {
+            let workers = (0..options.jobs.max(1))
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut tally = VerifyTally::new();
+                        while !failed.load(Ordering::Relaxed) {
+                            let i = next_file.fetch_add(1, Ordering::Relaxed);
+                            let obj_name = match object_files.get(i) {
+                                Some(obj_name) => obj_name,
+                                None => break,
+                            };
+                            let result =
+                                self.verify_object_file(obj_name,
+                                                        &prog,
+                                                        &mut tally);
+                            if let Err(e) = result {
+                                failed.store(true, Ordering::Relaxed);
+                                return Err(e);
+                            }
+                        }
+                        Ok(tally)
+                    })
+                })
+                .collect::<Vec<_>>();
+            workers.into_iter()
+                .map(|worker| worker.join().expect("fsck worker panicked"))
+                .collect::<Vec<Result<VerifyTally>>>()
+        });
+        prog.finish();
+        prog_thread.join().unwrap();
 
-                let size = obj_file.metadata()?.len();
-                size_stats.item(size as i64);
+        let mut tally = VerifyTally::new();
+        for worker_tally in tallies {
+            tally.merge(worker_tally?);
+        }
+        let VerifyTally { mut all_objects,
+                          size_stats,
+                          chunk_stats,
+                          stats_by_type,
+                          corrupt,
+                          bad_chunk_sizes } = tally;
+        report.corrupt = corrupt;
+        report.corrupt.sort();
+        report.bad_chunk_sizes = bad_chunk_sizes;
+        report.bad_chunk_sizes.sort();
 
-                let hash = self.object_from_path(&obj_file.path())?;
-                let obj_file = FileBuffer::open(&obj_file.path())?;
-                let mut obj_file = ProgressReader::new(&*obj_file, &prog);
-                let mut hasher = HashWriter::wrap(io::sink());
+        // Objects skipped by an incremental check are still present
+        for obj_file in &all_files {
+            all_objects.insert(self.object_from_name(obj_file)?);
+        }
 
-                let mut header_buf = [0u8; 12];
-                obj_file.read_exact(&mut header_buf)?;
-                let object_type =
-                    ObjectHeader::read_from(&mut header_buf.as_ref())
-                        ?
-                        .object_type;
-                hasher.write_all(header_buf.as_ref())?;
-                stats_by_type.get_mut(&object_type)
-                    .unwrap()
-                    .item(size as i64);
+        // Any directory without problems is good up to the start time
+        let problem_dirs = report.corrupt
+            .iter()
+            .map(|&(hash, _)| hash)
+            .chain(report.bad_chunk_sizes.iter().map(|&(hash, _, _)| hash))
+            .map(|hash| self.object_dir_key(&self.object_name(&hash)))
+            .collect::<HashSet<_>>();
+        let started_secs = started.duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let mut new_watermarks = FsckWatermarks::new();
+        for obj_file in &all_files {
+            let dir = self.object_dir_key(obj_file);
+            if problem_dirs.contains(&dir) {
+                if let Some(&mark) = watermarks.get(&dir) {
+                    new_watermarks.insert(dir, mark);
+                }
+            } else {
+                new_watermarks.insert(dir, started_secs);
+            }
+        }
+        *watermarks = new_watermarks;
+        watermarks.flush()?;
 
+        if repair {
+            for &(hash, _) in &report.corrupt {
+                self.quarantine_object(&hash)?;
+                all_objects.remove(&hash);
+                report.quarantined.push(hash);
+            }
+            for &(hash, _, _) in &report.bad_chunk_sizes {
+                let index = self.open_chunked_blob_unvalidated(&hash)?;
+                let rewritten =
+                    self.store_object(&index.with_corrected_sizes())?;
+                all_objects.insert(rewritten);
+                report.rewritten.push((hash, rewritten));
+            }
+        }
 
-                io::copy(&mut obj_file, &mut hasher)?;
-                let actual = hasher.hash();
-                if actual != hash {
-                    warn!("Corrupt object {0}: expected {0:x}, actual \
-                               {1:x}",
-                          hash,
-                          actual);
-                    bad_hashes.push((hash, actual));
+        let roots = self.refs
+            .values()
+            .chain(extra_roots.iter())
+            .chain(self.derived.values().flat_map(|by_name| by_name.values()))
+            .cloned()
+            .collect::<Vec<_>>();
+        let (mut reachable, mut missing) =
+            self.find_reachable(roots.iter().cloned())?;
+        if repair && !self.in_memory && self.path.join("parity").is_dir() {
+            // A rebuilt tree can lead to more missing objects, so repeat
+            loop {
+                let mut rebuilt = false;
+                for &(hash, _) in &missing {
+                    match self.reconstruct_object(&hash) {
+                        Ok(true) => {
+                            all_objects.insert(hash);
+                            report.reconstructed.push(hash);
+                            rebuilt = true;
+                        }
+                        Ok(false) => (),
+                        Err(e) => warn!("{}", e),
+                    }
                 }
+                if !rebuilt {
+                    break;
+                }
+                let found = self.find_reachable(roots.iter().cloned())?;
+                reachable = found.0;
+                missing = found.1;
             }
         }
-        prog.finish();
-        prog_thread.join().unwrap();
+        report.missing = missing;
+        report.orphans = all_objects.difference(&reachable)
+            .cloned()
+            .collect();
+        report.orphans.sort();
 
-        println!("{:4}  {:>10} {:^23} {:^23}", "", "count", "mean", "std");
+        stderrln!("{:4}  {:>10} {:^23} {:^23}", "", "count", "mean", "std");
         for (type_str, size_stats) in stats_by_type.iter()
             .map(|(t, s)| (t.code(), s))
             .chain(iter::once(("all", &size_stats))) {
-            println!("{:4}: {:10} {:10.1} ({:>10}) {:10.1} ({:>10})",
-                     type_str,
-                     size_stats.count(),
-                     size_stats.mean(),
-                     human_bytes(size_stats.mean().round() as u64),
-                     size_stats.std(),
-                     human_bytes(size_stats.std().round() as u64));
+            stderrln!("{:4}: {:10} {:10.1} ({:>10}) {:10.1} ({:>10})",
+                       type_str,
+                       size_stats.count(),
+                       size_stats.mean(),
+                       human_bytes(size_stats.mean().round() as u64),
+                       size_stats.std(),
+                       human_bytes(size_stats.std().round() as u64));
         }
-        Ok(bad_hashes)
+        self.check_chunk_sizes(&chunk_stats);
+        Ok(report)
     }
 
-    pub fn has_object(&self, key: &ObjectKey) -> bool {
-        self.object_path(key).is_file()
+    /// Verify one object file for fsck, counting it in the tally
+    fn verify_object_file(&self,
+                          obj_name: &str,
+                          prog: &ProgressCounter,
+                          tally: &mut VerifyTally)
+                          -> Result<()> {
+        let size = self.storage.stat(obj_name)?.size;
+        tally.size_stats.item(size as i64);
+
+        let hash = self.object_from_name(obj_name)?;
+        tally.all_objects.insert(hash);
+        let mut obj_file = Vec::new();
+        self.storage.get(obj_name)?.read_to_end(&mut obj_file)?;
+        let unsealed;
+        let obj_bytes = if encryption::is_sealed(&obj_file) {
+            // Sealed files that fail to decrypt have been tampered with, so
+            // report them as corrupt, with the hash of the file as it is
+            unsealed = match self.unseal(&hash, &obj_file) {
+                Ok(unsealed) => unsealed,
+                Err(e) => {
+                    warn!("{}", e);
+                    let mut hasher = HashWriter::wrap_with(io::sink(),
+                                                           hash.algorithm());
+                    hasher.write_all(&obj_file)?;
+                    prog.add(size);
+                    tally.corrupt.push((hash, hasher.hash()));
+                    return Ok(());
+                }
+            };
+            &unsealed
+        } else {
+            &*obj_file
+        };
+        let mut obj_file = ProgressReader::new(obj_bytes, prog);
+        let mut hasher = HashWriter::wrap_with(io::sink(), hash.algorithm());
+
+        let mut header_buf = [0u8; 12];
+        obj_file.read_exact(&mut header_buf)?;
+        let object_type = ObjectHeader::read_from(&mut header_buf.as_ref())
+            ?
+            .object_type;
+        hasher.write_all(header_buf.as_ref())?;
+        tally.stats_by_type
+            .get_mut(&object_type)
+            .unwrap()
+            .item(size as i64);
+
+        io::copy(&mut obj_file, &mut hasher)?;
+        let actual = hasher.hash();
+        if actual != hash {
+            warn!("Corrupt object {0}: expected {0:x}, actual {1:x}",
+                  hash,
+                  actual);
+            tally.corrupt.push((hash, actual));
+        } else if object_type == ObjectType::ChunkedBlob {
+            // Last chunk is cut short by the end of file, so skip it
+            let index = self.open_chunked_blob_unvalidated(&hash)?;
+            if let Err(e) = index.validate() {
+                warn!("Bad chunk index {}: {}", hash, e);
+                tally.bad_chunk_sizes
+                    .push((hash, index.total_size, index.chunk_sum()));
+            }
+            let len = index.chunks.len();
+            for chunk in index.chunks.iter().take(len.saturating_sub(1)) {
+                tally.chunk_stats.item(chunk.size as i64);
+            }
+        }
+        Ok(())
     }
 
-    /// Look up a RevSpec
+    /// Re-read an object from disk and check its hash
     ///
-    /// Returns a three-value tuple:
+    /// The file is synced first, so a verified object is also durable.
+    pub fn verify_object(&self, key: &ObjectKey) -> Result<()> {
+        let name = self.object_name(key);
+        self.storage.sync(&name)?;
+        let mut hasher = HashWriter::wrap_with(io::sink(), key.algorithm());
+        io::copy(&mut self.open_object_file(key)?, &mut hasher)?;
+        let actual = hasher.hash();
+        if actual != *key {
+            let path = self.path.join(name);
+            bail!(ErrorKind::CorruptObject(*key, actual, path));
+        }
+        Ok(())
+    }
+
+    /// Verify the objects of a new tree that aren't in an old one
     ///
-    /// - Hash of exact object specified, which may be a subtree of a commit
-    /// - Hash of the commit specified
-    /// - The ref name specified
+    /// Subtrees with the same hash as in the old tree are skipped, since
+    /// they were already stored. Returns the number of objects verified.
+    pub fn verify_new_tree(&self,
+                           new: &ObjectKey,
+                           old: Option<&ObjectKey>)
+                           -> Result<usize> {
+        if Some(new) == old {
+            return Ok(0);
+        }
+        self.verify_object(new)?;
+        let mut count = 1;
+        let node: ObjectWalkNode = self.lookup_node(*new)?;
+        match node.object_type {
+            ObjectType::Tree => {
+                let mut old_tree = Tree::new();
+                if let Some(old) = old {
+                    let old_node: ObjectWalkNode = self.lookup_node(*old)?;
+                    if old_node.object_type == ObjectType::Tree {
+                        old_tree = self.open_tree(old)?;
+                    }
+                }
+                for (name, hash) in self.open_tree(new)?.iter() {
+                    count += self.verify_new_tree(hash, old_tree.get(name))?;
+                }
+            }
+            ObjectType::ChunkedBlob => {
+                for chunk in self.open_chunked_blob(new)?.chunks {
+                    self.verify_object(&chunk.hash)?;
+                    count += 1;
+                }
+            }
+            _ => {}
+        }
+        Ok(count)
+    }
+
+    /// Re-chunk a stored file with the repository's chunk parameters
     ///
-    pub fn lookup(&self,
-                  rev: &RevSpec)
-                  -> Result<(ObjectKey, ObjectKey, Option<RevNameBuf>)> {
-        self.lookup_ref_or_hash(&rev.rev_name)
-            .and_then(|(hash, ref_name)| match &rev.path {
-                &None => Ok((hash, hash, ref_name)),
-                &Some(ref path) => {
-                    self.lookup_rev_path(&hash, path)
-                        .map(|subtree| (subtree, hash, ref_name))
+    /// The file is reassembled from its chunks and broken into chunks again.
+    /// If the chunker has changed since the file was stored, the boundaries
+    /// will differ, and new copies of the file will not deduplicate against
+    /// the stored one.
+    pub fn verify_chunking(&self, key: &ObjectKey) -> Result<ChunkingCheck> {
+        let stored = match self.open_object(key)? {
+            ObjectHandle::Blob(handle) => {
+                let mut index = ChunkedBlob::new();
+                index.add_chunk(handle.header().content_size, *key);
+                index
+            }
+            ObjectHandle::ChunkedBlob(handle) => handle.read_content()?,
+            other => {
+                bail!(ErrorKind::WrongObjectType(*key,
+                                                 ObjectType::Blob,
+                                                 other.header().object_type))
+            }
+        };
+        let strategy = match self.config.typed_chunking() {
+            true => {
+                self.detect_media_type(key)?
+                    .chunk_strategy(&self.config.chunking)
+            }
+            false => ChunkStrategy::untyped(&self.config.chunking),
+        };
+        let mut writer = match stored.total_size <= strategy.whole_max_size {
+            true => ChunkWriter::unchunked(self.hash_algorithm()),
+            false => ChunkWriter::new(&strategy.params, self.hash_algorithm()),
+        };
+        self.copy_blob_content(key, &mut writer)?;
+        let mut rechunked = writer.finish();
+        if rechunked.chunks.is_empty() {
+            // An empty file is stored as one empty blob
+            rechunked.add_blob(Blob::empty(), self.hash_algorithm());
+        }
+
+        let stored_hashes = stored.chunks
+            .iter()
+            .map(|chunk| chunk.hash)
+            .collect::<HashSet<ObjectKey>>();
+        // Chunks before the first difference are identical, so the
+        // difference starts at the same offset in both lists
+        let first_difference = stored.chunks
+            .iter()
+            .zip(rechunked.chunks.iter())
+            .position(|(a, b)| a != b)
+            .or_else(|| {
+                let common = stored.chunks.len().min(rechunked.chunks.len());
+                if stored.chunks.len() != rechunked.chunks.len() {
+                    Some(common)
+                } else {
+                    None
                 }
             })
+            .map(|i| {
+                stored.chunks.get(i).or(rechunked.chunks.get(i)).unwrap().offset
+            });
+        Ok(ChunkingCheck {
+            stored_chunks: stored.chunks.len(),
+            rechunked: rechunked.chunks.len(),
+            reproduced: rechunked.chunks
+                .iter()
+                .filter(|chunk| stored_hashes.contains(&chunk.hash))
+                .count(),
+            first_difference: first_difference,
+        })
     }
 
-    fn lookup_ref_or_hash(&self,
-                          name: &RevNameStr)
-                          -> Result<(ObjectKey, Option<RevNameBuf>)> {
-        self.lookup_ref(name)
-            .map(|hash| (hash, Some(name.to_owned())))
-            .or_else(|_| self.lookup_short_hash(name).map(|hash| (hash, None)))
-    }
+    /// Gather deduplication statistics for all objects reachable from refs
+    ///
+    /// Each object is counted once as stored, but once per reference to it as
+    /// logical data, so the ratio between the two shows how much
+    /// deduplication is saving. Missing objects are skipped.
+    pub fn stats(&self, top_n: usize) -> Result<StoreStats> {
+        let mut stats = StoreStats::default();
+        let mut sizes = HashMap::<ObjectKey, (ObjectType, ObjectSize)>::new();
+        let mut file_sizes = HashMap::<ObjectKey, ObjectSize>::new();
+        let mut tree_entries = HashMap::<ObjectKey, Vec<ObjectKey>>::new();
+        let mut versions = Vec::new();
+        let mut refcounts = HashMap::<ObjectKey, u64>::new();
+        let mut chunk_refs = HashMap::<ObjectKey, u64>::new();
+        let mut names = HashMap::<ObjectKey, OsString>::new();
+        let mut stack: Vec<ObjectKey> = self.refs.values().cloned().collect();
 
-    fn lookup_ref(&self, rev: &RevNameStr) -> Result<ObjectKey> {
-        self.refs
-            .get(rev)
-            .map(|hash| *hash)
-            .ok_or_else(|| format!("Not a ref: {}", rev).into())
-    }
+        while let Some(key) = stack.pop() {
+            if sizes.contains_key(&key) || !self.has_object(&key) {
+                continue;
+            }
+            let handle = self.open_object(&key)?;
+            let header = handle.header().clone();
+            let stored_size = self.object_file_size(&key)?;
+            sizes.insert(key, (header.object_type, stored_size));
+            stats.stored_bytes += stored_size;
+            {
+                let type_stats = stats.by_type
+                    .entry(header.object_type)
+                    .or_insert_with(TypeStats::default);
+                type_stats.stored_bytes += stored_size;
+                type_stats.sizes.item(header.content_size as i64);
+            }
 
-    fn lookup_short_hash(&self, s: &RevNameStr) -> Result<ObjectKey> {
-        fn get_fn_str(path: &Path) -> &str {
-            path.file_name()
-                .expect("should have a file_name")
-                .to_str()
-                .expect("should be ascii")
+            let children = match handle {
+                ObjectHandle::Blob(_) => {
+                    file_sizes.insert(key, header.content_size);
+                    continue;
+                }
+                ObjectHandle::Commit(_) => {
+                    let commit = self.open_commit(&key)?;
+                    versions.push(commit.tree);
+                    let mut children = vec![commit.tree];
+                    if !self.is_shallow(&key) {
+                        children.extend(commit.parents);
+                    }
+                    children
+                }
+                ObjectHandle::Tree(_) => {
+                    let tree = self.open_tree(&key)?;
+                    for (name, child) in tree.iter() {
+                        names.entry(*child).or_insert_with(|| name.clone());
+                    }
+                    let children: Vec<ObjectKey> =
+                        tree.values().cloned().collect();
+                    tree_entries.insert(key, children.clone());
+                    children
+                }
+                ObjectHandle::ChunkedBlob(_) => {
+                    let index = self.open_chunked_blob(&key)?;
+                    file_sizes.insert(key, index.total_size);
+                    let chunks: Vec<ObjectKey> =
+                        index.chunks.iter().map(|chunk| chunk.hash).collect();
+                    let distinct: HashSet<&ObjectKey> = chunks.iter().collect();
+                    for &chunk in distinct {
+                        *chunk_refs.entry(chunk).or_insert(0) += 1;
+                    }
+                    chunks
+                }
+            };
+            for child in children {
+                *refcounts.entry(child).or_insert(0) += 1;
+                stack.push(child);
+            }
         }
 
-        let path = self.object_path_sloppy(s);
-        let dir = path.parent_or_err()?;
-        let short_name = get_fn_str(&path);
+        // Each commit is one version of the files, as is a ref that points
+        // directly at a tree or file
+        versions.extend(self.refs
+            .values()
+            .filter(|key| match sizes.get(key) {
+                Some(&(ObjectType::Commit, _)) | None => false,
+                Some(_) => true,
+            })
+            .cloned());
+        let mut checkout_sizes = HashMap::new();
+        for version in versions {
+            stats.logical_bytes += checkout_size(&version,
+                                                 &tree_entries,
+                                                 &file_sizes,
+                                                 &mut checkout_sizes);
+        }
 
-        if dir.exists() {
-            for entry in dir.read_dir()? {
-                let entry = entry?.path();
-                trace!("Looking for '{}', checking: {}",
-                       s,
-                       entry.strip_prefix(&self.path)?.display());
-                if get_fn_str(&entry).starts_with(&short_name) {
-                    return self.object_from_path(&entry);
-                }
+        let mut files = Vec::new();
+        for (key, &(object_type, stored_size)) in &sizes {
+            let refs = refcounts.get(key).cloned().unwrap_or(1);
+            stats.by_type
+                .get_mut(&object_type)
+                .expect("every stored type has stats")
+                .logical_bytes += stored_size * refs;
+            if let (Some(name), Some(&size)) = (names.get(key),
+                                                file_sizes.get(key)) {
+                files.push((size, *key, name.to_string_lossy().into_owned()));
             }
         }
-        Err(format!("Hash not found: {}", s).into())
+        stats.shared_chunks =
+            chunk_refs.values().filter(|&&refs| refs > 1).count();
+        files.sort_by(|a, b| b.cmp(a));
+        files.truncate(top_n);
+        stats.largest_files = files.into_iter()
+            .map(|(size, key, name)| (key, name, size))
+            .collect();
+        Ok(stats)
     }
 
-    pub fn lookup_rev_path(&self,
-                           hash: &ObjectKey,
-                           path: &Path)
-                           -> Result<ObjectKey> {
-        use std::path::Component;
-        let mut components = path.components();
-        match components.next() {
-
-                None => Ok(*hash),
+    /// Storage retained by each entry at the top of a tree
+    ///
+    /// An entry retains the objects that only it reaches, which are what
+    /// would be freed if it were dropped from the tree. Objects reached from
+    /// more than one entry, like chunks shared between files in different
+    /// directories, are counted as shared instead. Sizes are of the object
+    /// files on disk. A commit is taken as its tree, and objects that are
+    /// missing or left out by a partial fetch count as empty.
+    pub fn disk_usage(&self, hash: &ObjectKey) -> Result<DiskUsage> {
+        let tree_key = match self.open_object(hash)? {
+            ObjectHandle::Commit(_) => self.open_commit(hash)?.tree,
+            _ => *hash,
+        };
+        let tree = self.open_tree(&tree_key)?;
+        let mut usage = DiskUsage::default();
+        let mut file_sizes = HashMap::<ObjectKey, ObjectSize>::new();
+        let mut owners = HashMap::<ObjectKey, Option<usize>>::new();
 
-                Some(Component::Normal(ch_path)) => {
-                    self.open_tree(hash)
-                        .and_then(|tree| {
-                            tree.get(ch_path)
-                                .map(|ch_hash| *ch_hash)
-                                .ok_or_else(|| {
-                                    format!("Tree {} has no child {}",
-                                            hash,
-                                            ch_path.to_string_lossy())
-                                        .into()
-                                })
-                        })
-                        .and_then(|ch_hash| {
-                            self.lookup_rev_path(&ch_hash, components.as_path())
-                        })
+        for (entry, (name, key)) in tree.iter().enumerate() {
+            let mut total = 0;
+            let mut seen = HashSet::new();
+            let mut stack = vec![*key];
+            while let Some(key) = stack.pop() {
+                if !seen.insert(key) || !self.has_object(&key) {
+                    continue;
+                }
+                let size = match file_sizes.get(&key) {
+                    Some(&size) => size,
+                    None => self.object_file_size(&key)?,
+                };
+                file_sizes.insert(key, size);
+                total += size;
+                let owner = owners.entry(key).or_insert(Some(entry));
+                if *owner != Some(entry) {
+                    *owner = None;
                 }
 
-                Some(other) => {
-                    Err(format!("Unexpected component type {:?} in path: {}",
-                                other,
-                                path.display())
-                        .into())
+                match self.open_object(&key)? {
+                    ObjectHandle::Tree(_) => {
+                        stack.extend(self.open_tree(&key)?.values());
+                    }
+                    ObjectHandle::ChunkedBlob(_) => {
+                        let index = self.open_chunked_blob(&key)?;
+                        stack.extend(index.chunks.iter().map(|c| c.hash));
+                    }
+                    _ => {}
                 }
             }
-            .chain_err(|| format!("Could not open {}:{}", hash, path.display()))
+            let is_dir = self.open_object(key)
+                .map(|handle| handle.header().object_type == ObjectType::Tree)
+                .unwrap_or(false);
+            usage.entries.push(EntryUsage {
+                name: name.to_string_lossy().into_owned(),
+                is_dir: is_dir,
+                retained: 0,
+                total: total,
+            });
+        }
+
+        usage.total = file_sizes.values().sum::<ObjectSize>() +
+                      self.object_file_size(&tree_key)?;
+        for (key, owner) in &owners {
+            match *owner {
+                Some(entry) => usage.entries[entry].retained += file_sizes[key],
+                None => usage.shared += file_sizes[key],
+            }
+        }
+        usage.entries.sort_by(|a, b| {
+            b.retained.cmp(&a.retained).then_with(|| a.name.cmp(&b.name))
+        });
+        Ok(usage)
     }
 
-    pub fn open_object_file(&self,
-                            key: &ObjectKey)
-                            -> Result<io::BufReader<fs::File>> {
+    /// Every file in the given commits and their history
+    ///
+    /// The starting commits are walked in order, and then back through
+    /// history. Each tree is read once per path it appears at, so a file is
+    /// only given again where some commit changed its directory. Entries whose
+    /// objects are neither present nor recorded as absent are skipped.
+    pub fn history_files(&self, starts: &[ObjectKey]) -> HistoryFiles<'_> {
+        HistoryFiles {
+            object_store: self,
+            queue: starts.iter().cloned().collect(),
+            pending: Vec::new(),
+            ready: VecDeque::new(),
+            seen_commits: HashSet::new(),
+            seen_trees: HashSet::new(),
+        }
+    }
 
-        if !self.has_object(&key) {
-            bail!(ErrorKind::ObjectNotFound(key.to_owned()))
+    /// Find files stored under more than one path, anywhere in history
+    ///
+    /// Every commit reachable from the refs is walked, and files are grouped
+    /// by content. Each path is listed once, with the first commit found to
+    /// have it, searching the refs in name order and then back through
+    /// history. Only groups with more than one path, of files of at least
+    /// `min_size` bytes, are returned, largest files first.
+    pub fn find_duplicates(&self,
+                           min_size: ObjectSize)
+                           -> Result<Vec<DuplicateGroup>> {
+        let mut paths: HashMap<ObjectKey, BTreeMap<PathBuf, ObjectKey>> =
+            HashMap::new();
+        let mut sizes = HashMap::<ObjectKey, ObjectSize>::new();
+        let refs = self.refs.values().cloned().collect::<Vec<_>>();
+        for file in self.history_files(&refs) {
+            let file = file?;
+            if file.node.file_size < min_size {
+                continue;
+            }
+            sizes.insert(file.node.hash, file.node.file_size);
+            paths.entry(file.node.hash)
+                .or_default()
+                .entry(file.path)
+                .or_insert(file.commit);
         }
 
-        let file = fs::File::open(self.object_path(key))?;
-        Ok(io::BufReader::new(file))
+        let mut groups = paths.into_iter()
+            .filter(|(_, paths)| paths.len() > 1)
+            .map(|(hash, paths)| {
+                DuplicateGroup {
+                    hash: hash,
+                    size: sizes[&hash],
+                    paths: paths.into_iter().collect(),
+                }
+            })
+            .collect::<Vec<_>>();
+        groups.sort_by(|a, b| {
+            b.size.cmp(&a.size).then_with(|| a.hash.cmp(&b.hash))
+        });
+        Ok(groups)
     }
 
-    pub fn open_object(&self, key: &ObjectKey) -> Result<ObjectHandle> {
-        self.open_object_file(key)
-            .and_then(|file| ObjectHandle::read_header(Box::new(file)))
-            .chain_err(|| format!("Could not open object {}", key))
+    /// Keys of all objects present, in any layout
+    pub fn list_objects(&self) -> Result<Vec<ObjectKey>> {
+        self.object_names()?
+            .iter()
+            .map(|name| self.object_from_name(name))
+            .collect()
     }
 
-    pub fn open_commit(&self, key: &ObjectKey) -> Result<Commit> {
-        match self.open_object(key) {
-                Ok(ObjectHandle::Commit(raw)) => raw.read_content(),
-                Ok(other) => {
-                    bail!("{} is a {:?}. Expected a commit.",
-                          key,
-                          other.header().object_type)
-                }
+    /// Size of an object file in storage, which is larger than the object
+    /// if it is sealed
+    pub fn object_file_size(&self, key: &ObjectKey) -> Result<u64> {
+        Ok(self.storage.stat(&self.object_name(key))?.size)
+    }
+
+    /// Read an object file exactly as it is stored, sealed or not
+    pub fn read_object_file_bytes(&self, key: &ObjectKey) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        self.storage.get(&self.object_name(key))?.read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Put back an object file exactly as it was stored
+    ///
+    /// The file is only kept if it verifies.
+    pub fn restore_object_file(&self,
+                               key: &ObjectKey,
+                               bytes: &[u8])
+                               -> Result<()> {
+        if self.has_object(key) {
+            bail!("Object {} is already present", key);
+        }
+        let temp = temp_name();
+        self.storage.put(&temp)?.write_all(bytes)?;
+        let name = self.object_name(key);
+        self.storage.rename(&temp, &name)?;
+        if let Err(e) = self.verify_object(key) {
+            self.storage.remove(&name)?;
+            return Err(e);
+        }
+        self.count_stored_object(key)
+    }
+
+    /// Rebuild a missing object from parity
+    ///
+    /// Returns false if no parity group covers it.
+    pub fn reconstruct_object(&self, key: &ObjectKey) -> Result<bool> {
+        match Parity::open(self)?.reconstruct(self, key)? {
+            Some(bytes) => {
+                self.restore_object_file(key, &bytes)?;
+                warn!("Reconstructed {} from parity", key);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Move an object file into the `corrupt` directory
+    ///
+    /// The file keeps its full hex key as its name, so it can be examined or
+    /// restored by hand.
+    pub fn quarantine_object(&self, key: &ObjectKey) -> Result<PathBuf> {
+        let dest_name = format!("corrupt/{}", key.to_hex());
+        self.storage.rename(&self.object_name(key), &dest_name)?;
+        let dest = self.path.join(dest_name);
+        // Its type and size cannot be trusted, so it cannot be subtracted
+        self.object_counts.lock().unwrap().complete = false;
+        warn!("Moved corrupt object {} to {}", key, dest.display());
+        Ok(dest)
+    }
+
+    /// Add an object that was just moved into place to the running counts
+    fn count_stored_object(&self, key: &ObjectKey) -> Result<()> {
+        let header = self.open_object(key)?.header().clone();
+        let stored_size = self.object_file_size(key)?;
+        self.object_counts
+            .lock()
+            .unwrap()
+            .add(header.object_type, header.content_size, stored_size);
+        Ok(())
+    }
+
+    /// Running totals of the objects in the store
+    pub fn object_counts(&self) -> ObjectCounts {
+        self.object_counts.lock().unwrap().clone()
+    }
+
+    /// Count every object file again, replacing the running totals
+    ///
+    /// Objects that cannot be opened are left out, as `fsck` will report
+    /// them.
+    pub fn recount_objects(&self) -> Result<ObjectCounts> {
+        let mut counts = ObjectCounts {
+            complete: true,
+            duplicate_bytes: self.object_counts().duplicate_bytes,
+            ..ObjectCounts::default()
+        };
+        for name in self.object_names()? {
+            let key = match self.object_from_name(&name) {
+                Ok(key) => key,
+                Err(_) => continue,
+            };
+            match self.open_object(&key) {
+                Ok(handle) => {
+                    let header = handle.header();
+                    counts.add(header.object_type,
+                               header.content_size,
+                               self.storage.stat(&name)?.size);
+                }
+                Err(e) => debug!("Not counting {}: {}", key, e),
+            }
+        }
+        **self.object_counts.lock().unwrap() = counts.clone();
+        Ok(counts)
+    }
+
+    /// Find all objects reachable from the given roots
+    ///
+    /// Returns the set of reachable objects that are present, and a list of
+    /// objects that are referenced but missing: (missing, referenced by).
+    /// Objects that cannot be parsed are counted as reachable, but their
+    /// children are not followed.
+    pub fn find_reachable<I>(&self, roots: I) -> Result<Reachable>
+        where I: IntoIterator<Item = ObjectKey>
+    {
+        let mut reachable = HashSet::new();
+        let mut missing = Vec::new();
+        let mut missing_set = HashSet::new();
+        let mut stack: Vec<(ObjectKey, Option<ObjectKey>)> =
+            roots.into_iter().map(|k| (k, None)).collect();
+
+        while let Some((key, referrer)) = stack.pop() {
+            if reachable.contains(&key) || missing_set.contains(&key) {
+                continue;
+            }
+            if self.is_absent(&key) {
+                // Left out by a partial fetch, so not a problem
+                reachable.insert(key);
+                continue;
+            }
+            if !self.has_object(&key) {
+                missing_set.insert(key);
+                // Roots with no referrer are listed as referring to themselves
+                missing.push((key, referrer.unwrap_or(key)));
+                continue;
+            }
+            reachable.insert(key);
+            let children = match self.open_object(&key) {
+                Ok(ObjectHandle::Blob(_)) => continue,
+                Ok(ObjectHandle::Commit(_)) if self.is_shallow(&key) => {
+                    self.open_commit(&key).map(|commit| vec![commit.tree])
+                }
+                Ok(handle) => handle.read_content().map(|o| o.child_keys()),
+                Err(e) => Err(e),
+            };
+            match children {
+                Ok(children) => {
+                    stack.extend(children.into_iter().map(|c| (c, Some(key))))
+                }
+                Err(e) => warn!("Could not read object {}: {}", key, e),
+            }
+        }
+        Ok((reachable, missing))
+    }
+
+    /// Compare observed chunk sizes with the repository's chunking parameters
+    ///
+    /// Chunks that are much larger or smaller than the configured target
+    /// suggest that objects were stored with different parameters, and will
+    /// not deduplicate against newly-stored files.
+    fn check_chunk_sizes(&self, chunk_stats: &VarianceCalc) {
+        let target = self.config.chunking.target_size() as f64;
+        stderrln!("Chunk target size: {}, mean stored chunk size: {} ({} \
+                   chunks)",
+                  human_bytes(target as u64),
+                  human_bytes(chunk_stats.mean().round() as u64),
+                  chunk_stats.count());
+        let ratio = chunk_stats.mean() / target;
+        if chunk_stats.count() > 0 && !(0.5..=2.0).contains(&ratio) {
+            warn!("Mean chunk size is far from the configured target. \
+                   Objects may have been stored with different chunking \
+                   parameters.");
+        }
+    }
+
+    pub fn has_object(&self, key: &ObjectKey) -> bool {
+        self.storage.exists(&self.object_name(key))
+    }
+
+    /// Look up a RevSpec
+    ///
+    /// Returns a three-value tuple:
+    ///
+    /// - Hash of exact object specified, which may be a subtree of a commit
+    /// - Hash of the commit specified
+    /// - The ref name specified, unless ancestry steps led away from it
+    ///
+    /// `HEAD` is the commit given to `set_head`, and names its branch if it
+    /// was given one.
+    pub fn lookup(&self,
+                  rev: &RevSpec)
+                  -> Result<(ObjectKey, ObjectKey, Option<RevNameBuf>)> {
+        let (hash, ref_name) = if rev.rev_name == HEAD_NAME {
+            self.lookup_head()?
+        } else {
+            self.lookup_ref_or_hash(&rev.rev_name)?
+        };
+        let (hash, ref_name) = if rev.ancestry.is_empty() {
+            (hash, ref_name)
+        } else {
+            (self.follow_ancestry(hash, &rev.ancestry)?, None)
+        };
+        match rev.path {
+            None => Ok((hash, hash, ref_name)),
+            Some(ref path) => {
+                Ok((self.lookup_rev_path(&hash, path)?, hash, ref_name))
+            }
+        }
+    }
+
+    /// Set the commit that `HEAD` refers to in revisions, and its branch
+    ///
+    /// HEAD belongs to the work dir, which keeps this up to date. Commands
+    /// that open only the store get it from `find_object_store`.
+    pub fn set_head(&mut self, head: Option<(ObjectKey, Option<RevNameBuf>)>) {
+        self.head = head;
+    }
+
+    fn lookup_head(&self) -> Result<(ObjectKey, Option<RevNameBuf>)> {
+        match self.head {
+            Some(ref head) => Ok(head.clone()),
+            None => bail!(ErrorKind::RefNotFound(HEAD_NAME.to_owned())),
+        }
+    }
+
+    /// Look up a ref, remote-tracking ref, or hash
+    ///
+    /// Tracking refs can be given without their `remotes/` prefix, so
+    /// `origin/master` finds `remotes/origin/master`. No ref name is returned
+    /// for them, since they should only change by fetching, and not by
+    /// committing on top of them.
+    fn lookup_ref_or_hash(&self,
+                          name: &RevNameStr)
+                          -> Result<(ObjectKey, Option<RevNameBuf>)> {
+        let tracking = format!("{}{}", TRACKING_REF_PREFIX, name);
+        match self.lookup_ref(name) {
+            Ok(hash) if is_tracking_ref(name) => Ok((hash, None)),
+            Ok(hash) => Ok((hash, Some(name.to_owned()))),
+            Err(_) => {
+                self.lookup_ref(&tracking)
+                    .map(|hash| (hash, None))
+                    .or_else(|_| {
+                        self.lookup_short_hash(name).map(|hash| (hash, None))
+                    })
+            }
+        }
+    }
+
+    fn follow_ancestry(&self,
+                       mut hash: ObjectKey,
+                       steps: &[Ancestry])
+                       -> Result<ObjectKey> {
+        for step in steps {
+            let (nth, generations) = match *step {
+                Ancestry::Parent(0) => continue,
+                Ancestry::Parent(n) => (n, 1),
+                Ancestry::Generations(n) => (1, n),
+            };
+            for _ in 0..generations {
+                let parents = self.commit_parents(&hash)?;
+                hash = *parents.get(nth - 1)
+                    .ok_or_else(|| {
+                        format!("Commit {} has no parent {}{}",
+                                hash,
+                                nth,
+                                if self.is_shallow(&hash) {
+                                    " (history was cut off by a shallow fetch)"
+                                } else {
+                                    ""
+                                })
+                    })?;
+            }
+        }
+        Ok(hash)
+    }
+
+    /// Resolve a range to its end commit and the commits it excludes
+    pub fn lookup_range(&self,
+                        range: &RevRange)
+                        -> Result<(ObjectKey, HashSet<ObjectKey>)> {
+        let (_, to, _) = self.lookup(&range.to)?;
+        let excluded = match range.from {
+            Some(ref from) => self.history(&self.lookup(from)?.1)?,
+            None => HashSet::new(),
+        };
+        Ok((to, excluded))
+    }
+
+    fn lookup_ref(&self, rev: &RevNameStr) -> Result<ObjectKey> {
+        self.refs
+            .get(rev)
+            .map(|hash| *hash)
+            .ok_or_else(|| ErrorKind::RefNotFound(rev.to_string()).into())
+    }
+
+    fn lookup_short_hash(&self, s: &RevNameStr) -> Result<ObjectKey> {
+        if !s.is_empty() && s.chars().all(|c| c.is_ascii_hexdigit()) {
+            let prefix = s.to_lowercase();
+            let layouts = iter::once(self.format.fan_out)
+                .chain(self.format.migrating_from);
+            for fan_out in layouts {
+                // Only list the directory that the prefix covers
+                let levels = cmp::min(prefix.len() / fan_out.width,
+                                      fan_out.depth);
+                let mut dir = "objects".to_owned();
+                for level in 0..levels {
+                    dir.push('/');
+                    dir.push_str(&prefix[level * fan_out.width..
+                                         (level + 1) * fan_out.width]);
+                }
+                for name in self.storage.list(&dir)? {
+                    let key = self.object_from_name(&name)?;
+                    if key.to_hex().starts_with(&prefix) {
+                        return Ok(key);
+                    }
+                }
+            }
+        }
+        bail!(ErrorKind::RefNotFound(s.to_string()))
+    }
+
+    /// Metadata extracted from a file when it was committed, if any
+    pub fn lookup_media_metadata(&self,
+                                 rev: &ObjectKey,
+                                 path: &Path)
+                                 -> Result<Option<MediaMetadata>> {
+        self.lookup_rev_path(rev, path)?;
+        let name = path.file_name()
+            .ok_or_else(|| format!("{} is not a file", path.display()))?;
+        let parent = path.parent().unwrap_or(Path::new(""));
+        let tree = self.open_tree(&self.lookup_rev_path(rev, parent)?)?;
+        match tree.media_metadata.get(name) {
+            Some(link) => self.read_media_metadata(link).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Read an object of metadata extracted from a media file
+    pub fn read_media_metadata(&self,
+                               key: &ObjectKey)
+                               -> Result<MediaMetadata> {
+        let mut text = Vec::new();
+        self.copy_blob_content(key, &mut text)?;
+        MediaMetadata::parse(&String::from_utf8_lossy(&text))
+            .chain_err(|| format!("Could not read media metadata {}", key))
+    }
+
+    pub fn lookup_rev_path(&self,
+                           hash: &ObjectKey,
+                           path: &Path)
+                           -> Result<ObjectKey> {
+        use std::path::Component;
+        let mut components = path.components();
+        match components.next() {
+
+                None => Ok(*hash),
+
+                Some(Component::Normal(ch_path)) => {
+                    self.open_tree(hash)
+                        .and_then(|tree| {
+                            tree.get(ch_path)
+                                .map(|ch_hash| *ch_hash)
+                                .ok_or_else(|| {
+                                    format!("Tree {} has no child {}",
+                                            hash,
+                                            ch_path.to_string_lossy())
+                                        .into()
+                                })
+                        })
+                        .and_then(|ch_hash| {
+                            self.lookup_rev_path(&ch_hash, components.as_path())
+                        })
+                }
+
+                Some(other) => {
+                    Err(format!("Unexpected component type {:?} in path: {}",
+                                other,
+                                path.display())
+                        .into())
+                }
+            }
+            .chain_err(|| format!("Could not open {}:{}", hash, path.display()))
+    }
+
+    /// Open a raw object file (header and content), decrypting if needed
+    pub fn open_object_file(&self,
+                            key: &ObjectKey)
+                            -> Result<Box<io::BufRead>> {
+
+        if self.is_absent(key) {
+            bail!(ErrorKind::ContentAbsent(key.to_string(),
+                                           self.locations
+                                               .remotes_for(key)
+                                               .into_iter()
+                                               .collect()))
+        }
+        if !self.has_object(&key) {
+            bail!(ErrorKind::ObjectNotFound(key.to_owned()))
+        }
+
+        let file = self.storage.get(&self.object_name(key))?;
+        let mut file = io::BufReader::new(file);
+        if encryption::is_sealed(file.fill_buf()?) {
+            let mut sealed = Vec::new();
+            file.read_to_end(&mut sealed)?;
+            let raw = self.unseal(key, &sealed)?;
+            return Ok(Box::new(io::Cursor::new(raw)));
+        }
+        Ok(Box::new(file))
+    }
+
+    pub fn open_object(&self, key: &ObjectKey) -> Result<ObjectHandle> {
+        self.open_object_file(key)
+            .and_then(|file| {
+                ObjectHandle::read_header(file, self.hash_algorithm())
+            })
+            .chain_err(|| format!("Could not open object {}", key))
+    }
+
+    pub fn open_commit(&self, key: &ObjectKey) -> Result<Commit> {
+        match self.open_object(key) {
+                Ok(ObjectHandle::Commit(raw)) => raw.read_content(),
+                Ok(other) => {
+                    bail!(ErrorKind::WrongObjectType(*key,
+                                                     ObjectType::Commit,
+                                                     other.header()
+                                                         .object_type))
+                }
+                Err(e) => Err(e),
+            }
+            .chain_err(|| format!("Could not open object {}", key))
+    }
+
+    pub fn open_chunked_blob(&self, key: &ObjectKey) -> Result<ChunkedBlob> {
+        match self.open_object(key) {
+                Ok(ObjectHandle::ChunkedBlob(raw)) => raw.read_content(),
+                Ok(other) => {
+                    bail!(ErrorKind::WrongObjectType(*key,
+                                                     ObjectType::ChunkedBlob,
+                                                     other.header()
+                                                         .object_type))
+                }
                 Err(e) => Err(e),
             }
             .chain_err(|| format!("Could not open object {}", key))
     }
 
+    /// Open a chunked blob index without checking that its sizes add up
+    pub fn open_chunked_blob_unvalidated(&self,
+                                         key: &ObjectKey)
+                                         -> Result<ChunkedBlob> {
+        match self.open_object(key) {
+                Ok(ObjectHandle::ChunkedBlob(raw)) => {
+                    raw.read_content_unvalidated()
+                }
+                Ok(other) => {
+                    bail!(ErrorKind::WrongObjectType(*key,
+                                                     ObjectType::ChunkedBlob,
+                                                     other.header()
+                                                         .object_type))
+                }
+                Err(e) => Err(e),
+            }
+            .chain_err(|| format!("Could not read chunk index {}", key))
+    }
+
     pub fn open_tree(&self, key: &ObjectKey) -> Result<Tree> {
         match self.open_object(key) {
                 Ok(ObjectHandle::Tree(raw)) => raw.read_content(),
@@ -294,9 +1667,10 @@ impl ObjectStore {
                         .and_then(|commit| self.open_tree(&commit.tree))
                 }
                 Ok(other) => {
-                    bail!("{} is a {:?}. Expected a tree.",
-                          key,
-                          other.header().object_type)
+                    bail!(ErrorKind::WrongObjectType(*key,
+                                                     ObjectType::Tree,
+                                                     other.header()
+                                                         .object_type))
                 }
                 Err(e) => Err(e),
             }
@@ -309,39 +1683,43 @@ impl ObjectStore {
     pub fn store_object(&self, obj: &ObjectCommon) -> Result<ObjectKey> {
 
         // If object already exists, no need to store
-        let key = obj.calculate_hash();
+        let algorithm = self.hash_algorithm();
+        let key = obj.calculate_hash_with(algorithm);
         if self.has_object(&key) {
             trace!("store {} {} -- already exists",
                    obj.object_type().code(),
                    key);
+            self.object_counts.lock().unwrap().duplicate_bytes +=
+                obj.content_size();
             return Ok(key);
         }
 
         let start_time = Instant::now();
 
-        // Create temporary file
-        let temp_path = self.path.join("tmp");
-        try!(fsutil::create_parents(&temp_path));
-        let mut file = try!(fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(&temp_path)
-            .map_err(|e| {
-                io::Error::new(e.kind(), format!("{}", &temp_path.display()))
-            }));
-
         // Write object to temporary file
-        let key = try!(obj.write_to(&mut file));
+        let temp = temp_name();
+        let key = {
+            let mut file = self.storage.put(&temp)?;
+            if self.seals(obj.object_type()) {
+                let mut raw = Vec::new();
+                let key = obj.write_to_with(&mut raw, algorithm)?;
+                file.write_all(&self.repo_key()?.seal_object(&key, &raw))?;
+                key
+            } else {
+                try!(obj.write_to_with(&mut file, algorithm))
+            }
+        };
 
-        // Move file to permanent path
-        let permpath = self.object_path(&key);
-        try!(fsutil::create_parents(&permpath));
-        try!(fs::rename(&temp_path, &permpath));
+        // Move file to permanent name
+        let stored_size = self.storage.stat(&temp)?.size;
+        try!(self.storage.rename(&temp, &self.object_name(&key)));
+        self.object_counts
+            .lock()
+            .unwrap()
+            .add(obj.object_type(), obj.content_size(), stored_size);
 
         let elapsed = Instant::now().duration_since(start_time);
-        let secs = elapsed.as_secs() as f32 +
-                   elapsed.subsec_nanos() as f32 / 1e9;
-        let per_sec = obj.content_size() as f32 / secs;
+        let secs = StopWatch::float_secs(&elapsed);
 
         let log_level = match secs {
             _ if secs > 1.0 => LogLevel::Warn,
@@ -350,28 +1728,145 @@ impl ObjectStore {
             _ => LogLevel::Trace,
         };
         log!(log_level,
-             "store {} {} -- {:>10} stored in {:0.3}s ({:>10}/s)",
+             "store {} {} -- {:>10} stored in {} ({:>12})",
              obj.object_type().code(),
              key,
              human_bytes(obj.content_size()),
-             secs,
-             human_bytes(per_sec as u64));
+             human_duration(elapsed),
+             human_rate(obj.content_size(), elapsed));
         Ok(key)
     }
 
+    /// Writes a raw object file (header and content) into the object store
+    ///
+    /// The bytes are stored as-is under the given key, so this is suitable for
+    /// copying objects between repositories without re-serializing them.
+    ///
+    /// The content is hashed as it is written, and only moved into place if
+    /// it matches the key. On a mismatch, the file is moved into the
+    /// `quarantine` directory along with a report, and a `CorruptTransfer`
+    /// error is returned.
+    ///
+    /// Returns the number of bytes written.
+    pub fn store_raw_object(&self,
+                            key: &ObjectKey,
+                            reader: &mut io::Read)
+                            -> Result<ObjectSize> {
+        if self.has_object(key) {
+            trace!("store raw {} -- already exists", key);
+            io::copy(reader, &mut io::sink())?;
+            return Ok(0);
+        }
+
+        let temp = temp_name();
+        let mut file = self.storage.put(&temp)?;
+        let (size, actual) = if self.config.encryption.is_some() {
+            // Sealing needs the whole object, and its type from the header
+            let mut raw = Vec::new();
+            let size = reader.read_to_end(&mut raw)? as ObjectSize;
+            let mut hasher = HashWriter::wrap_with(io::sink(), key.algorithm());
+            hasher.write_all(&raw)?;
+            let seal = ObjectHeader::read_from(&mut raw.as_slice())
+                .map(|header| self.seals(header.object_type))
+                .unwrap_or(true);
+            if seal {
+                file.write_all(&self.repo_key()?.seal_object(key, &raw))?;
+            } else {
+                file.write_all(&raw)?;
+            }
+            drop(file);
+            (size, hasher.hash())
+        } else {
+            let mut writer = HashWriter::wrap_with(file, key.algorithm());
+            let size = io::copy(reader, &mut writer)?;
+            writer.flush()?;
+            (size, writer.hash())
+        };
+
+        if actual != *key {
+            let dest =
+                self.quarantine_received(&temp, key, &actual, size)?;
+            bail!(ErrorKind::CorruptTransfer(*key, actual, dest));
+        }
+
+        try!(self.storage.rename(&temp, &self.object_name(key)));
+        self.count_stored_object(key)?;
+        trace!("store raw {} -- {:>10}", key, human_bytes(size));
+        Ok(size)
+    }
+
+    /// Move a received object that failed verification into `quarantine`
+    ///
+    /// Writes a `<key>.report` file next to it describing the mismatch.
+    fn quarantine_received(&self,
+                           temp: &str,
+                           expected: &ObjectKey,
+                           actual: &ObjectKey,
+                           size: ObjectSize)
+                           -> Result<PathBuf> {
+        let dest_name = format!("quarantine/{}", expected.to_hex());
+        self.storage.rename(temp, &dest_name)?;
+        let dest = self.path.join(&dest_name);
+
+        let report_name = format!("{}.report", dest_name);
+        let mut report = self.storage.put(&report_name)?;
+        writeln!(report, "expected {:x}", expected)?;
+        writeln!(report, "actual {:x}", actual)?;
+        writeln!(report, "size {}", size)?;
+        warn!("Received corrupt object {}: actual {}. Moved to {}",
+              expected,
+              actual,
+              dest.display());
+        Ok(dest)
+    }
+
 
     /// Give a Display object that will walk the tree and list its contents
-    pub fn ls_files(&self,
-                    hash: ObjectKey,
-                    verbose: bool)
-                    -> Result<TreeDisplay> {
+    pub fn ls_files<'a>(&'a self,
+                        hash: ObjectKey,
+                        verbose: bool,
+                        pathspec: &'a Pathspec)
+                        -> Result<TreeDisplay<'a>> {
         Ok(TreeDisplay {
             node: self.lookup_node(hash)?,
             object_store: self,
             verbose: verbose,
+            pathspec: pathspec,
         })
     }
 
+    /// Walk a tree to find cumulative sizes of each subtree
+    ///
+    /// Children are in name order, or largest first if `sort_by_size` is set.
+    /// The result displays as an indented tree.
+    pub fn size_tree(&self,
+                     hash: ObjectKey,
+                     sort_by_size: bool)
+                     -> Result<SizeTree> {
+        let node = self.lookup_node(hash)?;
+        let mut op = SizeTreeOp { sort_by_size: sort_by_size };
+        let mut tree = self.walk_node(&mut op, node)?
+            .expect("size tree op always gives a result");
+        tree.name = ".".to_owned();
+        Ok(tree)
+    }
+
+    /// Walk an object's hierarchy, with each entry's hash and size
+    ///
+    /// Trees deeper than `max_depth` levels below the root are listed but not
+    /// walked, so their sizes are unknown.
+    pub fn object_tree(&self,
+                       hash: ObjectKey,
+                       max_depth: Option<usize>)
+                       -> Result<ObjectTree> {
+        let node = self.lookup_node(hash)?;
+        let mut op = ObjectTreeOp { max_depth: max_depth };
+        let mut tree = self.walk_node(&mut op, node)?
+            .expect("object tree op always gives a result");
+        tree.name = ".".to_owned();
+        Ok(tree)
+    }
+
 
     /// Extract binary content from a Blob or ChunkedBlob to a Write stream
     pub fn copy_blob_content(&self,
@@ -400,317 +1895,3010 @@ impl ObjectStore {
         Ok(())
     }
 
+    /// Extract part of a file's content to a Write stream
+    ///
+    /// Only the chunks that overlap the range are read, so a preview from
+    /// the middle of a large video does not read the rest of it. A range
+    /// past the end of the file is cut short.
+    pub fn copy_blob_range(&self,
+                           hash: &ObjectKey,
+                           offset: ObjectSize,
+                           len: ObjectSize,
+                           writer: &mut io::Write)
+                           -> Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        let end = offset.saturating_add(len);
+        match self.open_object(hash)? {
+            ObjectHandle::Blob(blob) => {
+                let blob = blob.read_content()?;
+                let content = blob.content();
+                let size = content.len() as ObjectSize;
+                let start = cmp::min(offset, size) as usize;
+                let end = cmp::min(end, size) as usize;
+                writer.write_all(&content[start..end])?;
+            }
+            ObjectHandle::ChunkedBlob(index) => {
+                let index =
+                    index.read_content()
+                        .chain_err(|| {
+                            format!("While reading ChunkedBlob {}", hash)
+                        })?;
+                let overlapping = index.chunks
+                    .iter()
+                    .filter(|c| c.offset < end && c.offset + c.size > offset);
+                for chunk in overlapping {
+                    let start = offset.saturating_sub(chunk.offset);
+                    let chunk_end = cmp::min(end - chunk.offset, chunk.size);
+                    trace!("Reading {} bytes of {}", chunk_end - start, chunk);
+                    self.copy_blob_range(&chunk.hash,
+                                         start,
+                                         chunk_end - start,
+                                         writer)?;
+                }
+            }
+            other => bail!("Expected a Blob or ChunkedBlob, got: {:?}", other),
+        };
+        Ok(())
+    }
+
+    /// Read part of a file's content (see `copy_blob_range`)
+    pub fn read_blob_range(&self,
+                           hash: &ObjectKey,
+                           offset: ObjectSize,
+                           len: ObjectSize)
+                           -> Result<Vec<u8>> {
+        let mut content = Vec::new();
+        self.copy_blob_range(hash, offset, len, &mut content)?;
+        Ok(content)
+    }
+
     /// Get all refs
     pub fn refs(&self) -> &RefMap { &self.refs }
 
-    pub fn refs_for(&self, hash: &ObjectKey) -> Vec<String> {
-        self.refs
-            .iter()
-            .filter(|&(_, v)| v == hash)
-            .map(|(k, _)| k.to_owned())
-            .collect::<Vec<_>>()
-    }
+    pub fn remotes(&self) -> &RemoteMap { &self.remotes }
 
-    pub fn update_ref(&mut self, name: String, hash: ObjectKey) -> Result<()> {
-        self.refs.insert(name, hash);
-        self.refs.flush().map_err(|e| e.into())
+    pub fn update_remote(&mut self,
+                         name: String,
+                         remote: Remote)
+                         -> Result<()> {
+        self.remotes.insert(name, remote);
+        self.remotes.flush().map_err(|e| e.into())
     }
 
-    pub fn try_find_ref(&self, name: &str) -> Option<ObjectKey> {
-        self.refs.get(name).cloned()
+    /// Forget a remote, along with its tracking refs
+    pub fn remove_remote(&mut self, name: &str) -> Result<Remote> {
+        let remote = self.remotes
+            .remove(name)
+            .ok_or_else(|| format!("No such remote: {}", name))?;
+        self.remotes.flush()?;
+
+        let prefix = tracking_ref_name(name, "");
+        let tracking = self.refs
+            .keys()
+            .filter(|ref_name| ref_name.starts_with(&prefix))
+            .cloned()
+            .collect::<Vec<_>>();
+        for ref_name in tracking {
+            self.remove_ref(&ref_name)?;
+        }
+        self.locations.forget_remote(name)?;
+        Ok(remote)
     }
 
-    pub fn find_common_ancestor<I, S>(&self,
-                                      revs: I)
-                                      -> Result<Option<ObjectKey>>
-        where I: IntoIterator<Item = S>,
-              S: AsRef<str>
+    /// Which remotes are known to have which objects
+    pub fn locations(&self) -> &Locations { &self.locations }
+
+    /// Record that a remote has the given objects
+    pub fn record_locations<I>(&mut self, remote: &str, keys: I) -> Result<()>
+        where I: IntoIterator<Item = ObjectKey>
     {
-        let mut search_queues = Vec::new();
-        for rev in revs {
-            let (hash, _) = self.lookup_ref_or_hash(rev.as_ref())?;
+        self.locations.record(remote, keys)
+    }
 
-            let mut queue = VecDeque::new();
-            queue.push_back(hash);
-            search_queues.push(queue);
-        }
-        search_queues.dedup();
+    /// Earlier values of refs
+    pub fn reflog(&self) -> &Reflog { &self.reflog }
 
-        let mut seen_sets = vec![HashSet::new(); search_queues.len()];
-        let mut depth = 0;
-        loop {
-            depth += 1;
-
-            let mut seen_a_hash = false;
-            for (i, queue) in search_queues.iter_mut().enumerate() {
-                match queue.pop_front() {
-                    None => (),
-                    Some(hash) => {
-                        seen_a_hash = true;
-                        seen_sets[i].insert(hash);
-                        if seen_sets.iter().all(|set| set.contains(&hash)) {
-                            debug!("Ancestor: Found it! {}", hash);
-                            return Ok(Some(hash));
-                        }
-                        let commit = self.open_commit(&hash)?;
-                        trace!("Ancestor: Round {}, Slot {}: {} {}",
-                               depth,
-                               i,
-                               hash,
-                               commit.message);
-                        queue.extend(commit.parents);
+    /// Record that a ref moved, so that its old commit can be found again
+    pub fn record_reflog(&mut self,
+                         name: &str,
+                         old: Option<ObjectKey>,
+                         new: ObjectKey,
+                         reason: &str)
+                         -> Result<()> {
+        self.reflog.record(name, old, new, reason)
+    }
+
+    /// Commits whose parents were left out by a shallow fetch
+    pub fn shallow(&self) -> &ShallowSet { &self.shallow }
+
+    pub fn is_shallow(&self, hash: &ObjectKey) -> bool {
+        self.shallow.contains(hash)
+    }
+
+    /// Record commits whose parents are not present
+    pub fn add_shallow<I>(&mut self, hashes: I) -> Result<()>
+        where I: IntoIterator<Item = ObjectKey>
+    {
+        self.shallow.extend(hashes);
+        self.shallow.flush().map_err(|e| e.into())
+    }
+
+    /// Forget shallow commits whose parents have all been fetched since
+    pub fn remove_complete_shallow(&mut self) -> Result<()> {
+        let mut complete = Vec::new();
+        for hash in self.shallow.iter() {
+            let commit = self.open_commit(hash)?;
+            if commit.parents.iter().all(|p| self.has_object(p)) {
+                complete.push(*hash);
+            }
+        }
+        if complete.is_empty() {
+            return Ok(());
+        }
+        for hash in complete {
+            debug!("History of {} is now complete", hash);
+            self.shallow.remove(&hash);
+        }
+        self.shallow.flush().map_err(|e| e.into())
+    }
+
+    /// Blobs whose content was left out by a partial fetch
+    pub fn absent(&self) -> &AbsentMap { &self.absent }
+
+    /// The size of a blob that was left out, unless it has been stored since
+    pub fn absent_size(&self, key: &ObjectKey) -> Option<ObjectSize> {
+        match self.has_object(key) {
+            true => None,
+            false => self.absent.get(key).cloned(),
+        }
+    }
+
+    pub fn is_absent(&self, key: &ObjectKey) -> bool {
+        self.absent_size(key).is_some()
+    }
+
+    /// Record blobs, with their sizes, that this store lacks
+    pub fn add_absent<I>(&mut self, blobs: I) -> Result<()>
+        where I: IntoIterator<Item = (ObjectKey, ObjectSize)>
+    {
+        self.absent.extend(blobs);
+        self.tree_memo.get_mut().unwrap().clear();
+        self.absent.flush().map_err(|e| e.into())
+    }
+
+    /// Name of a file's media type record in storage
+    fn media_type_name(&self, key: &ObjectKey) -> String {
+        let hex = key.to_hex();
+        format!("media-types/{}/{}", &hex[..2], &hex[2..])
+    }
+
+    /// The media type of a file, if it was recognized when stored
+    pub fn media_type(&self, key: &ObjectKey) -> Result<MediaType> {
+        let name = self.media_type_name(key);
+        if !self.storage.exists(&name) {
+            return Ok(MediaType::Unknown);
+        }
+        let mut record = Vec::new();
+        self.storage.get(&name)?.read_to_end(&mut record)?;
+        if encryption::is_sealed(&record) {
+            record = self.repo_key()?
+                .open(&record)
+                .chain_err(|| {
+                    format!("Could not decrypt media type of {}", key)
+                })?;
+        }
+        String::from_utf8_lossy(&record).trim().parse()
+    }
+
+    /// Media types recorded for any of the given files
+    pub fn media_types(&self, keys: &[ObjectKey]) -> Result<MediaTypeMap> {
+        let mut types = MediaTypeMap::new();
+        for key in keys {
+            let media_type = self.media_type(key)?;
+            if media_type != MediaType::Unknown {
+                types.insert(*key, media_type);
+            }
+        }
+        Ok(types)
+    }
+
+    /// The media type of a file, as recorded or else sniffed from its content
+    ///
+    /// Files fetched from elsewhere have no record here, so their first chunk
+    /// is read. A file whose content is absent is `Unknown`.
+    pub fn detect_media_type(&self, key: &ObjectKey) -> Result<MediaType> {
+        let recorded = self.media_type(key)?;
+        if recorded != MediaType::Unknown || !self.has_object(key) {
+            return Ok(recorded);
+        }
+        let first_chunk = match self.open_object(key)? {
+            ObjectHandle::Blob(_) => Some(*key),
+            ObjectHandle::ChunkedBlob(handle) => {
+                handle.read_content()?.chunks.first().map(|chunk| chunk.hash)
+            }
+            _ => None,
+        };
+        let mut head = Vec::new();
+        if let Some(chunk) = first_chunk {
+            if self.has_object(&chunk) {
+                self.copy_blob_content(&chunk, &mut head)?;
+            }
+        }
+        Ok(MediaType::sniff(&head))
+    }
+
+    /// Record the media type of a file
+    ///
+    /// A file's type follows from its content, so a record is never
+    /// replaced.
+    pub fn record_media_type(&self,
+                             key: ObjectKey,
+                             media_type: MediaType)
+                             -> Result<()> {
+        let name = self.media_type_name(&key);
+        if self.storage.exists(&name) {
+            return Ok(());
+        }
+        let mut record = format!("{:1$}", media_type.mime(), MEDIA_RECORD_SIZE)
+            .into_bytes();
+        if self.config.encryption.is_some() {
+            record = self.repo_key()?.seal(&record)?;
+        }
+        let temp = temp_name();
+        self.storage.put(&temp)?.write_all(&record)?;
+        self.storage.rename(&temp, &name)
+    }
+
+    /// An object derived from a file under the given name, if still present
+    pub fn derived(&self, source: &ObjectKey, name: &str) -> Option<ObjectKey> {
+        self.derived
+            .get(source)
+            .and_then(|by_name| by_name.get(name))
+            .cloned()
+            .filter(|key| self.has_object(key))
+    }
+
+    /// Record an object derived from a file under the given name
+    pub fn record_derived(&mut self,
+                          source: ObjectKey,
+                          name: &str,
+                          derived: ObjectKey)
+                          -> Result<()> {
+        self.derived
+            .entry(source)
+            .or_default()
+            .insert(name.to_owned(), derived);
+        self.derived.flush().map_err(|e| e.into())
+    }
+
+    /// Forget absent blobs that have been stored since
+    pub fn remove_present_absent(&mut self) -> Result<()> {
+        let present = self.absent
+            .keys()
+            .filter(|key| self.has_object(key))
+            .cloned()
+            .collect::<Vec<_>>();
+        for key in present {
+            debug!("Content of {} is now present", key);
+            self.absent.remove(&key);
+        }
+        self.tree_memo.get_mut().unwrap().clear();
+        self.absent.flush().map_err(|e| e.into())
+    }
+
+    /// Files under a tree or commit whose content is not all stored
+    ///
+    /// Gives the path of each file, and the remotes known to have its missing
+    /// blobs.
+    pub fn missing_content(&self,
+                           hash: &ObjectKey)
+                           -> Result<Vec<(PathBuf, BTreeSet<String>)>> {
+        let mut missing = Vec::new();
+        let mut stack = vec![(PathBuf::new(), *hash)];
+        while let Some((path, hash)) = stack.pop() {
+            let node: ObjectWalkNode = self.lookup_node(hash)?;
+            let blobs = match node.object_type {
+                ObjectType::Tree | ObjectType::Commit => {
+                    for (name, child) in self.open_tree(&hash)? {
+                        stack.push((path.join(name), child));
                     }
+                    continue;
+                }
+                ObjectType::ChunkedBlob => {
+                    self.open_chunked_blob(&hash)?
+                        .chunks
+                        .iter()
+                        .map(|chunk| chunk.hash)
+                        .collect()
+                }
+                ObjectType::Blob => vec![hash],
+            };
+            let mut remotes = BTreeSet::new();
+            let mut is_missing = false;
+            for blob in blobs.iter().filter(|blob| !self.has_object(blob)) {
+                is_missing = true;
+                remotes.extend(self.locations.remotes_for(blob));
+            }
+            if is_missing {
+                missing.push((path, remotes));
+            }
+        }
+        missing.sort();
+        Ok(missing)
+    }
+
+    /// Parents of a commit, or none if its history was cut off
+    pub fn commit_parents(&self, hash: &ObjectKey) -> Result<Vec<ObjectKey>> {
+        if self.is_shallow(hash) {
+            return Ok(Vec::new());
+        }
+        Ok(self.open_commit(hash)?.parents)
+    }
+
+    pub fn refs_for(&self, hash: &ObjectKey) -> Vec<String> {
+        self.refs
+            .iter()
+            .filter(|&(_, v)| v == hash)
+            .map(|(k, _)| k.to_owned())
+            .collect::<Vec<_>>()
+    }
+
+    /// Set a ref, keeping changes made by other processes to other refs
+    pub fn update_ref(&mut self, name: String, hash: ObjectKey) -> Result<()> {
+        let _lock = self.lock_refs()?;
+        self.refs.reload()?;
+        self.refs.insert(name, hash);
+        self.refs.flush().map_err(|e| e.into())
+    }
+
+    /// Set a ref, but only if it still points at `expected`
+    ///
+    /// None for `expected` means the ref must not exist yet. The check and the
+    /// update happen while the refs file is locked, so two processes that
+    /// both read the old value can't both move the ref.
+    pub fn update_ref_if(&mut self,
+                         name: String,
+                         expected: Option<ObjectKey>,
+                         hash: ObjectKey)
+                         -> Result<()> {
+        let _lock = self.lock_refs()?;
+        self.refs.reload()?;
+        let current = self.refs.get(&name).cloned();
+        if current != expected {
+            bail!("Ref {} changed: expected {}, but it is now {}",
+                  name,
+                  expected.map_or("nothing".to_owned(), |h| h.to_string()),
+                  current.map_or("nothing".to_owned(), |h| h.to_string()));
+        }
+        self.refs.insert(name, hash);
+        self.refs.flush().map_err(|e| e.into())
+    }
+
+    /// Delete a ref, returning where it pointed
+    pub fn remove_ref(&mut self, name: &str) -> Result<Option<ObjectKey>> {
+        let _lock = self.lock_refs()?;
+        self.refs.reload()?;
+        let old = self.refs.remove(name);
+        self.refs.flush()?;
+        Ok(old)
+    }
+
+    /// Lock the refs file while it is reread and rewritten
+    fn lock_refs(&self) -> Result<Option<FileLock>> {
+        if self.in_memory {
+            return Ok(None);
+        }
+        FileLock::acquire(&self.path.join("refs"), "update ref").map(Some)
+    }
+
+    pub fn try_find_ref(&self, name: &str) -> Option<ObjectKey> {
+        self.refs.get(name).cloned()
+    }
+
+    /// Block until a ref no longer points at `from`, then return where it
+    /// points, or None if it was deleted
+    ///
+    /// The files at the top of the store are watched (see
+    /// `fsutil::watch_dir`), and the refs are reread after each change, so
+    /// another process moving the ref wakes this one.
+    pub fn wait_for_ref(&mut self,
+                        name: &str,
+                        from: Option<ObjectKey>)
+                        -> Result<Option<ObjectKey>> {
+        if self.in_memory {
+            bail!("Cannot wait for changes to a store kept in memory");
+        }
+        // Only the top directory matters, so skip the large object tree
+        let mut subdirs = Vec::new();
+        for entry in fs::read_dir(&self.path)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                subdirs.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+        let ignore = subdirs.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+        let mut watcher = fsutil::watch_dir(&self.path, &ignore)?;
+        loop {
+            self.refs.reload()?;
+            let current = self.try_find_ref(name);
+            if current != from {
+                return Ok(current);
+            }
+            watcher.wait()?;
+        }
+    }
+
+    /// Is `ancestor` reachable by following parents from `descendant`?
+    ///
+    /// A commit counts as its own ancestor.
+    pub fn is_ancestor(&self,
+                       ancestor: &ObjectKey,
+                       descendant: &ObjectKey)
+                       -> Result<bool> {
+        let mut queue = VecDeque::new();
+        let mut seen = HashSet::new();
+        queue.push_back(*descendant);
+        while let Some(hash) = queue.pop_front() {
+            if hash == *ancestor {
+                return Ok(true);
+            }
+            if seen.insert(hash) {
+                queue.extend(self.commit_parents(&hash)?);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Names of the refs that pass a filter and whose history includes a
+    /// commit
+    ///
+    /// The filter comes first, so refs it leaves out cost no history walk.
+    pub fn refs_containing<F>(&self,
+                              commit: &ObjectKey,
+                              filter: F)
+                              -> Result<Vec<String>>
+        where F: Fn(&str) -> bool
+    {
+        let mut names = Vec::new();
+        for (name, hash) in self.refs.iter() {
+            if filter(name) && self.is_ancestor(commit, hash)? {
+                names.push(name.clone());
+            }
+        }
+        Ok(names)
+    }
+
+    /// When an object was written to this store
+    ///
+    /// This is not a commit's own `time`, which older commits lack: it is
+    /// when the commit was made here, or when it arrived by fetch.
+    pub fn stored_time(&self, key: &ObjectKey) -> Result<SystemTime> {
+        let stored = self.storage.stat(&self.object_name(key))?;
+        Ok(UNIX_EPOCH + Duration::from_secs(stored.modified))
+    }
+
+    /// Keep only the commits that match a filter
+    ///
+    /// Commits are given and returned in `DepthFirstCommitSort` order, with
+    /// parents before children, so the newest commits are at the end and are
+    /// the ones kept by a maximum count. Commits made before times were
+    /// recorded don't match a time range.
+    pub fn filter_commits(&self,
+                          commits: Vec<(ObjectKey, Commit)>,
+                          filter: &CommitFilter)
+                          -> Result<Vec<(ObjectKey, Commit)>> {
+        let mut kept = Vec::new();
+        for (hash, commit) in commits.into_iter().rev() {
+            if filter.max_count.is_some_and(|max| kept.len() >= max) {
+                break;
+            }
+            if !filter.matches_time(commit.time) {
+                continue;
+            }
+            if let Some(ref path) = filter.path {
+                if !self.commit_changes_path(&commit, path)? {
+                    continue;
+                }
+            }
+            kept.push((hash, commit));
+        }
+        kept.reverse();
+        Ok(kept)
+    }
+
+    /// Did a commit change the given path, compared to all of its parents?
+    ///
+    /// The path's hash is compared, so an unchanged subtree is found without
+    /// walking it. A merge that kept one parent's version is not counted, and
+    /// a root commit counts as changing every path that it has.
+    pub fn commit_changes_path(&self,
+                               commit: &Commit,
+                               path: &Path)
+                               -> Result<bool> {
+        let at_path = |tree: &ObjectKey| self.lookup_rev_path(tree, path).ok();
+        let ours = at_path(&commit.tree);
+        if commit.parents.is_empty() {
+            return Ok(ours.is_some());
+        }
+        for parent in &commit.parents {
+            if at_path(&self.open_commit(parent)?.tree) == ours {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Count the files a commit added, modified, and deleted
+    ///
+    /// The commit is compared to its first parent, or to an empty tree if it
+    /// has none. Results are cached, and written out when the store is
+    /// closed. A first parent left out by a shallow fetch is compared as an
+    /// empty tree too, but then the result is not cached, so that it is
+    /// counted again once the history is fetched.
+    pub fn commit_change_stats(&self,
+                               commit: &ObjectKey)
+                               -> Result<ChangeStats> {
+        if let Some(stats) = self.change_stats.lock().unwrap().get(commit) {
+            return Ok(*stats);
+        }
+        let parent = self.commit_parents(commit)?.into_iter().next();
+        let parent_absent = parent.is_some_and(|p| !self.has_object(&p));
+        let parent_tree = match parent {
+            Some(ref parent) if !parent_absent => {
+                Some(self.open_commit(parent)?.tree)
+            }
+            _ => None,
+        };
+        let mut stats = ChangeStats::default();
+        let tree = self.open_commit(commit)?.tree;
+        for delta in self.diff_trees(parent_tree, Some(tree)) {
+            match delta? {
+                TreeDelta::Added(_, after) => {
+                    stats.added += 1;
+                    stats.byte_delta += after.file_size as i64;
+                }
+                TreeDelta::Modified(_, before, after) => {
+                    stats.modified += 1;
+                    stats.byte_delta += after.file_size as i64 -
+                                        before.file_size as i64;
+                }
+                TreeDelta::Deleted(_, before) => {
+                    stats.deleted += 1;
+                    stats.byte_delta -= before.file_size as i64;
+                }
+            }
+        }
+        if !parent_absent {
+            self.change_stats.lock().unwrap().insert(*commit, stats);
+        }
+        Ok(stats)
+    }
+
+    /// Files that differ between two trees, either of which may be missing
+    ///
+    /// Subtrees with the same hash on both sides are skipped without being
+    /// read. A tree added or deleted as a whole gives an entry for each file
+    /// in it, and a file replaced by a directory (or the other way around) is
+    /// deleted and then added.
+    pub fn diff_trees(&self,
+                      before: Option<ObjectKey>,
+                      after: Option<ObjectKey>)
+                      -> TreeDiff<'_> {
+        TreeDiff {
+            object_store: self,
+            pending: vec![(PathBuf::new(), before, after)],
+            ready: VecDeque::new(),
+        }
+    }
+
+    /// Count commits that each of two commits has and the other lacks
+    ///
+    /// Returns (ahead, behind): the number of commits in the history of `a`
+    /// but not `b`, and in the history of `b` but not `a`. History cut off
+    /// by a shallow fetch is not counted.
+    pub fn count_ahead_behind(&self,
+                              a: &ObjectKey,
+                              b: &ObjectKey)
+                              -> Result<(usize, usize)> {
+        let a_history = self.history(a)?;
+        let b_history = self.history(b)?;
+        Ok((a_history.difference(&b_history).count(),
+            b_history.difference(&a_history).count()))
+    }
+
+    /// All commits reachable from the given commit, including itself
+    fn history(&self, hash: &ObjectKey) -> Result<HashSet<ObjectKey>> {
+        let mut seen = HashSet::new();
+        let mut stack = vec![*hash];
+        while let Some(hash) = stack.pop() {
+            if seen.insert(hash) {
+                stack.extend(self.commit_parents(&hash)?);
+            }
+        }
+        Ok(seen)
+    }
+
+    /// Find the tracking ref that a local branch should be compared to
+    ///
+    /// This is the branch of the same name on a remote that has it as its
+    /// default branch, or else on `origin`, or else on the first remote that
+    /// has it. Returns the short name (`origin/master`) and hash.
+    pub fn upstream(&self, branch: &str) -> Option<(String, ObjectKey)> {
+        let tracked = |name: &String| {
+            self.try_find_ref(&tracking_ref_name(name, branch))
+                .map(|hash| (format!("{}/{}", name, branch), hash))
+        };
+        self.remotes
+            .iter()
+            .filter(|&(_, remote)| remote.branch.as_ref().is_some_and(|b| {
+                b == branch
+            }))
+            .chain(self.remotes.iter().filter(|&(name, _)| name == "origin"))
+            .chain(self.remotes.iter())
+            .filter_map(|(name, _)| tracked(name))
+            .next()
+    }
+
+    /// Find the lowest common ancestor of the given revisions
+    ///
+    /// If there is more than one, returns the one nearest the first
+    /// revision. See `lowest_common_ancestors`.
+    pub fn find_common_ancestor<I, S>(&self,
+                                      revs: I)
+                                      -> Result<Option<ObjectKey>>
+        where I: IntoIterator<Item = S>,
+              S: AsRef<str>
+    {
+        let mut commits = Vec::new();
+        for rev in revs {
+            let (_, hash, _) = self.lookup(&rev.as_ref().parse()?)?;
+            commits.push(hash);
+        }
+        Ok(self.lowest_common_ancestors(&commits)?.into_iter().next())
+    }
+
+    /// Find the lowest common ancestors of any number of commits
+    ///
+    /// These are the commits reachable from all of the given commits that are
+    /// not ancestors of another such commit. There is usually one, but
+    /// criss-cross merges can leave several. They are returned in
+    /// breadth-first order from the first commit, nearest first.
+    pub fn lowest_common_ancestors(&self,
+                                   commits: &[ObjectKey])
+                                   -> Result<Vec<ObjectKey>> {
+        let first = match commits.first() {
+            Some(first) => *first,
+            None => return Ok(Vec::new()),
+        };
+
+        // Ancestors of the first commit, in breadth-first order
+        let mut order = Vec::new();
+        let mut common = HashSet::new();
+        let mut queue = VecDeque::new();
+        queue.push_back(first);
+        while let Some(hash) = queue.pop_front() {
+            if common.insert(hash) {
+                order.push(hash);
+                queue.extend(self.commit_parents(&hash)?);
+            }
+        }
+
+        // Narrowed to those reachable from each of the others
+        for commit in &commits[1..] {
+            let mut reachable = HashSet::new();
+            queue.push_back(*commit);
+            while let Some(hash) = queue.pop_front() {
+                if reachable.insert(hash) {
+                    queue.extend(self.commit_parents(&hash)?);
+                }
+            }
+            common = common.intersection(&reachable).cloned().collect();
+        }
+
+        // Drop those that are ancestors of other common ancestors
+        let mut below = HashSet::new();
+        for hash in &common {
+            queue.extend(self.commit_parents(hash)?);
+        }
+        while let Some(hash) = queue.pop_front() {
+            if below.insert(hash) {
+                queue.extend(self.commit_parents(&hash)?);
+            }
+        }
+
+        let lowest = order.into_iter()
+            .filter(|hash| common.contains(hash) && !below.contains(hash))
+            .collect::<Vec<_>>();
+        debug!("Lowest common ancestors of {:?}: {:?}", commits, lowest);
+        Ok(lowest)
+    }
+
+    /// Find one base for merging the given commits
+    ///
+    /// After a criss-cross merge, commits can have several lowest common
+    /// ancestors, and each is missing changes that the others have. Rather
+    /// than pick one, they are merged into a virtual ancestor, using their
+    /// own merge base found the same way. The virtual ancestor's tree is
+    /// stored, but no commit is made for it.
+    ///
+    /// Returns the hash of a commit, or of a virtual ancestor's tree, or None
+    /// if the commits have no history in common.
+    pub fn merge_base(&self,
+                      commits: &[ObjectKey])
+                      -> Result<Option<ObjectKey>> {
+        let mut bases = self.lowest_common_ancestors(commits)?.into_iter();
+        let mut virtual_base = match bases.next() {
+            Some(base) => base,
+            None => return Ok(None),
+        };
+        let mut merged = vec![virtual_base];
+        for base in bases {
+            merged.push(base);
+            let inner = self.merge_base(&merged)?;
+            debug!("Merging base {} into virtual ancestor, from {:?}",
+                   base,
+                   inner);
+            virtual_base = self.merge_trees(inner, virtual_base, base)?;
+        }
+        Ok(Some(virtual_base))
+    }
+
+    /// Merge two trees with their common ancestor, within the store
+    ///
+    /// Any of the three may be a commit, for its tree. An entry changed on
+    /// only one side takes that side's version, and a subtree changed on both
+    /// sides is merged in turn. Any other entry changed on both sides keeps
+    /// ours. Returns the hash of the merged tree.
+    pub fn merge_trees(&self,
+                       base: Option<ObjectKey>,
+                       ours: ObjectKey,
+                       theirs: ObjectKey)
+                       -> Result<ObjectKey> {
+        let base = match base {
+            Some(hash) => self.open_tree(&hash)?,
+            None => Tree::new(),
+        };
+        let ours = self.open_tree(&ours)?;
+        let theirs = self.open_tree(&theirs)?;
+
+        let names = base.keys()
+            .chain(ours.keys())
+            .chain(theirs.keys())
+            .collect::<BTreeSet<_>>();
+        let mut merged = Tree::new();
+        for name in names {
+            let (o, t) = (ours.get(name).cloned(), theirs.get(name).cloned());
+            let entry = self.merge_entry(base.get(name).cloned(), o, t)?;
+            if let Some(hash) = entry {
+                merged.insert(name.to_owned(), hash);
+                let side = if entry == o { &ours } else { &theirs };
+                if let Some(metadata) = side.metadata.get(name) {
+                    merged.insert_metadata(name.to_owned(), *metadata);
                 }
+                if let Some(link) = side.media_metadata.get(name) {
+                    merged.insert_media_metadata(name.to_owned(), *link);
+                }
+            }
+        }
+        self.store_object(&merged)
+    }
+
+    fn merge_entry(&self,
+                   base: Option<ObjectKey>,
+                   ours: Option<ObjectKey>,
+                   theirs: Option<ObjectKey>)
+                   -> Result<Option<ObjectKey>> {
+        if ours == theirs || base == theirs {
+            return Ok(ours);
+        }
+        if base == ours {
+            return Ok(theirs);
+        }
+        let is_tree = |hash: &ObjectKey| -> Result<bool> {
+            Ok(self.open_object(hash)?.header().object_type.is_treeish())
+        };
+        match (ours, theirs) {
+            (Some(o), Some(t)) if is_tree(&o)? && is_tree(&t)? => {
+                let base = match base {
+                    Some(b) if is_tree(&b)? => Some(b),
+                    _ => None,
+                };
+                Ok(Some(self.merge_trees(base, o, t)?))
             }
-            if !seen_a_hash {
-                debug!("Ancestor: end of history. No common ancestor found.");
-                return Ok(None);
+            _ => Ok(ours),
+        }
+    }
+}
+
+/// Result of `ObjectStore::verify_chunking` for one file
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct ChunkingCheck {
+    /// Number of chunks the file is stored as
+    pub stored_chunks: usize,
+    /// Number of chunks the current chunker produces
+    pub rechunked: usize,
+    /// Number of the new chunks that are identical to stored chunks
+    pub reproduced: usize,
+    /// Byte offset of the first chunk boundary or hash that differs
+    pub first_difference: Option<ObjectSize>,
+}
+
+impl ChunkingCheck {
+    /// True if the current chunker produces exactly the stored chunks
+    pub fn matches(&self) -> bool { self.first_difference.is_none() }
+}
+
+/// Criteria for choosing which commits to show in a log
+#[derive(Debug,Clone,Default,PartialEq,Eq)]
+pub struct CommitFilter {
+    /// Only commits that changed this path
+    pub path: Option<PathBuf>,
+    /// Only commits made at or after this time, in seconds since the epoch
+    pub since: Option<u64>,
+    /// Only commits made at or before this time, in seconds since the epoch
+    pub until: Option<u64>,
+    /// Only this many of the newest matching commits
+    pub max_count: Option<usize>,
+}
+
+impl CommitFilter {
+    pub fn is_empty(&self) -> bool {
+        self.path.is_none() && self.since.is_none() && self.until.is_none() &&
+        self.max_count.is_none()
+    }
+
+    /// Is a commit time within the range, if any?
+    fn matches_time(&self, time: Option<u64>) -> bool {
+        if self.since.is_none() && self.until.is_none() {
+            return true;
+        }
+        match time {
+            Some(time) => {
+                self.since.is_none_or(|since| time >= since) &&
+                self.until.is_none_or(|until| time <= until)
+            }
+            None => false,
+        }
+    }
+}
+
+/// Deduplication statistics gathered by `ObjectStore::stats`
+#[derive(Debug,Clone,Default)]
+pub struct StoreStats {
+    /// Total size of the files in every commit, as if each were checked out
+    pub logical_bytes: ObjectSize,
+    /// Total size of the reachable object files on disk
+    pub stored_bytes: ObjectSize,
+    /// Counts and sizes broken down by object type
+    pub by_type: BTreeMap<ObjectType, TypeStats>,
+    /// Number of chunks that appear in more than one chunked file
+    pub shared_chunks: usize,
+    /// Largest distinct files: (hash, a name it appears under, size)
+    pub largest_files: Vec<(ObjectKey, String, ObjectSize)>,
+}
+
+/// Statistics for one type of object
+#[derive(Debug,Clone,Default)]
+pub struct TypeStats {
+    /// Content sizes of each distinct object
+    pub sizes: VarianceCalc,
+    /// Total size of the object files on disk
+    pub stored_bytes: ObjectSize,
+    /// Total size on disk if each reference had its own copy of the object
+    pub logical_bytes: ObjectSize,
+}
+
+impl StoreStats {
+    pub fn dedup_ratio(&self) -> f64 {
+        ratio(self.logical_bytes, self.stored_bytes)
+    }
+}
+
+impl TypeStats {
+    pub fn dedup_ratio(&self) -> f64 {
+        ratio(self.logical_bytes, self.stored_bytes)
+    }
+}
+
+/// Storage used by a tree, broken down by its entries, from `disk_usage`
+#[derive(Debug,Clone,Default,PartialEq,Eq)]
+pub struct DiskUsage {
+    /// Entries at the top of the tree, retaining the most first
+    pub entries: Vec<EntryUsage>,
+    /// Size of objects reached from more than one entry
+    pub shared: ObjectSize,
+    /// Size of every object in the tree, including the tree itself
+    pub total: ObjectSize,
+}
+
+/// Storage used by one entry at the top of a tree
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct EntryUsage {
+    pub name: String,
+    pub is_dir: bool,
+    /// Size of objects only this entry reaches
+    pub retained: ObjectSize,
+    /// Size of every object this entry reaches
+    pub total: ObjectSize,
+}
+
+/// Paths with the same content, from `find_duplicates`
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct DuplicateGroup {
+    pub hash: ObjectKey,
+    /// Size of the file
+    pub size: ObjectSize,
+    /// Each path, with the first commit found to have it there
+    pub paths: Vec<(PathBuf, ObjectKey)>,
+}
+
+/// Ratio of logical to stored bytes, or 1.0 if nothing is stored
+fn ratio(logical: ObjectSize, stored: ObjectSize) -> f64 {
+    if stored == 0 {
+        1.0
+    } else {
+        logical as f64 / stored as f64
+    }
+}
+
+/// Threads to use for work that can be split up, one per CPU
+pub fn default_jobs() -> usize {
+    thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+}
+
+/// How `ObjectStore::fsck_with` checks the store
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct FsckOptions {
+    /// Move corrupt objects aside, rewrite bad indexes, rebuild from parity
+    pub repair: bool,
+    /// Threads verifying objects at once
+    pub jobs: usize,
+    /// Only verify objects written since their directory was last checked
+    pub incremental: bool,
+}
+
+impl Default for FsckOptions {
+    fn default() -> Self {
+        FsckOptions {
+            repair: false,
+            jobs: default_jobs(),
+            incremental: false,
+        }
+    }
+}
+
+/// When each directory of objects was last checked without problems
+///
+/// Keyed by directory within `objects`, with times in seconds since the
+/// epoch. Objects written before a directory's time have been verified.
+pub type FsckWatermarks = BTreeMap<String, u64>;
+
+/// What one fsck worker found in the object files it verified
+struct VerifyTally {
+    all_objects: HashSet<ObjectKey>,
+    size_stats: VarianceCalc,
+    chunk_stats: VarianceCalc,
+    stats_by_type: BTreeMap<ObjectType, VarianceCalc>,
+    corrupt: Vec<(ObjectKey, ObjectKey)>,
+    bad_chunk_sizes: Vec<(ObjectKey, ObjectSize, ObjectSize)>,
+}
+
+impl VerifyTally {
+    fn new() -> Self {
+        let mut stats_by_type = BTreeMap::new();
+        for t in &[ObjectType::Blob,
+                   ObjectType::ChunkedBlob,
+                   ObjectType::Tree,
+                   ObjectType::Commit] {
+            stats_by_type.insert(*t, VarianceCalc::new());
+        }
+        VerifyTally {
+            all_objects: HashSet::new(),
+            size_stats: VarianceCalc::new(),
+            chunk_stats: VarianceCalc::new(),
+            stats_by_type: stats_by_type,
+            corrupt: Vec::new(),
+            bad_chunk_sizes: Vec::new(),
+        }
+    }
+
+    fn merge(&mut self, other: VerifyTally) {
+        self.all_objects.extend(other.all_objects);
+        self.size_stats.merge(&other.size_stats);
+        self.chunk_stats.merge(&other.chunk_stats);
+        for (t, stats) in &other.stats_by_type {
+            self.stats_by_type
+                .entry(*t)
+                .or_default()
+                .merge(stats);
+        }
+        self.corrupt.extend(other.corrupt);
+        self.bad_chunk_sizes.extend(other.bad_chunk_sizes);
+    }
+}
+
+/// Problems found by `ObjectStore::fsck`
+#[derive(Debug,Clone,Default,PartialEq,Eq)]
+pub struct FsckReport {
+    /// Objects whose content does not match their key: (expected, actual)
+    pub corrupt: Vec<(ObjectKey, ObjectKey)>,
+    /// Corrupt objects that were moved into the `corrupt` directory
+    pub quarantined: Vec<ObjectKey>,
+    /// Corrupt or missing objects that were rebuilt from parity
+    pub reconstructed: Vec<ObjectKey>,
+    /// Referenced objects that are not present: (missing, referenced by)
+    pub missing: Vec<(ObjectKey, ObjectKey)>,
+    /// Objects that are not reachable from any ref
+    pub orphans: Vec<ObjectKey>,
+    /// Chunk indexes whose sizes do not add up: (index, recorded, actual)
+    pub bad_chunk_sizes: Vec<(ObjectKey, ObjectSize, ObjectSize)>,
+    /// Chunk indexes stored again with corrected sizes: (old, new)
+    pub rewritten: Vec<(ObjectKey, ObjectKey)>,
+}
+
+impl FsckReport {
+    /// True if no objects are corrupt or missing (orphans are harmless)
+    ///
+    /// Corrupt objects that were rebuilt from parity are not counted.
+    pub fn is_ok(&self) -> bool {
+        self.unrepaired_corrupt().next().is_none() &&
+        self.missing.is_empty() && self.bad_chunk_sizes.is_empty()
+    }
+
+    /// Corrupt objects that were not rebuilt from parity
+    pub fn unrepaired_corrupt<'a>(&'a self)
+                                  -> Box<Iterator<Item = &'a ObjectKey> + 'a> {
+        Box::new(self.corrupt
+            .iter()
+            .map(|(hash, _)| hash)
+            .filter(move |hash| !self.reconstructed.contains(hash)))
+    }
+}
+
+/// Count of temporary object files started, to give each its own name
+static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Name for a new temporary object file
+///
+/// Each is different, so objects can be stored from several threads at once.
+fn temp_name() -> String {
+    format!("tmp.{}", TEMP_FILES.fetch_add(1, Ordering::Relaxed))
+}
+
+lazy_static!{
+    pub static ref SHORT_OBJECT_KEY_PAT:Regex = Regex::new(
+        &format!("[[:xdigit:]]{{ {},{} }}",
+                    KEY_SHORT_LEN, MAX_KEY_SIZE_HEX_DIGITS)).unwrap();
+
+    pub static ref REF_NAME_PAT:Regex = Regex::new("[[:word:]/-]+").unwrap();
+}
+
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct ObjectWalkNode {
+    pub hash: ObjectKey,
+    pub object_type: ObjectType,
+    pub file_size: ObjectSize,
+}
+
+impl Into<ComparableNode> for ObjectWalkNode {
+    fn into(self) -> ComparableNode {
+        ComparableNode {
+            is_treeish: self.object_type.is_treeish(),
+            file_size: self.file_size,
+            hash: Some(self.hash),
+            fs_path: None,
+            is_ignored: false,
+        }
+    }
+}
+
+
+impl NodeLookup<ObjectKey, ObjectWalkNode> for ObjectStore {
+    fn lookup_node(&self, handle: ObjectKey) -> Result<ObjectWalkNode> {
+        if let Some(size) = self.absent_size(&handle) {
+            return Ok(ObjectWalkNode {
+                hash: handle,
+                object_type: ObjectType::Blob,
+                file_size: size,
+            });
+        }
+        let opened = self.open_object(&handle)?;
+        let object_type = opened.header().object_type;
+        let file_size;
+        match opened {
+            ObjectHandle::Blob(_) => file_size = opened.header().content_size,
+            ObjectHandle::ChunkedBlob(raw) => {
+                let chunked = raw.read_content()?;
+                file_size = chunked.total_size
+            }
+            _ => file_size = 0,
+        }
+        Ok(ObjectWalkNode {
+            hash: handle,
+            object_type: object_type,
+            file_size: file_size,
+        })
+    }
+}
+
+impl ObjectStore {
+    /// Read the children of a tree, or of a commit's tree, as walk nodes
+    ///
+    /// Looking up each child means opening it, which is most of the cost of
+    /// a walk. Walks of several revisions, or of several parents, meet the
+    /// same subtrees again and again, and objects never change, so the
+    /// children of each tree are kept for the life of the store.
+    fn tree_children(&self,
+                     tree: &ObjectKey)
+                     -> Result<ChildMap<ObjectWalkNode>> {
+        if let Some(children) = self.tree_memo.lock().unwrap().get(tree) {
+            return Ok(children.clone());
+        }
+        let mut children = BTreeMap::new();
+        for (name, hash) in self.open_tree(tree)? {
+            let name = name.into_string()
+                .map_err(|e| format!("Bad UTF-8 in name: {:?}", e))?;
+            let node = self.lookup_node(hash.clone())?;
+            children.insert(name, node);
+        }
+        let mut memo = self.tree_memo.lock().unwrap();
+        if memo.len() >= TREE_MEMO_LIMIT {
+            memo.clear();
+        }
+        memo.insert(*tree, children.clone());
+        Ok(children)
+    }
+}
+
+impl NodeReader<ObjectWalkNode> for ObjectStore {
+    fn read_children(&self,
+                     node: &ObjectWalkNode)
+                     -> Result<ChildMap<ObjectWalkNode>> {
+        self.tree_children(&node.hash)
+    }
+}
+
+
+impl NodeLookup<ObjectKey, ComparableNode> for ObjectStore {
+    fn lookup_node(&self, handle: ObjectKey) -> Result<ComparableNode> {
+        let node = <Self as NodeLookup<ObjectKey,ObjectWalkNode>>
+                    ::lookup_node(&self, handle)?;
+        Ok(node.into())
+    }
+}
+
+impl NodeReader<ComparableNode> for ObjectStore {
+    fn read_children(&self,
+                     node: &ComparableNode)
+                     -> Result<ChildMap<ComparableNode>> {
+        let hash = node.hash.expect("Object should have hash");
+        Ok(self.tree_children(&hash)?
+            .into_iter()
+            .map(|(name, node)| (name, node.into()))
+            .collect())
+    }
+}
+
+
+/// A file that differs between two trees
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum TreeDelta {
+    Added(PathBuf, ObjectWalkNode),
+    /// Before and after
+    Modified(PathBuf, ObjectWalkNode, ObjectWalkNode),
+    Deleted(PathBuf, ObjectWalkNode),
+}
+
+impl TreeDelta {
+    pub fn path(&self) -> &Path {
+        match *self {
+            TreeDelta::Added(ref path, _) |
+            TreeDelta::Modified(ref path, _, _) |
+            TreeDelta::Deleted(ref path, _) => path,
+        }
+    }
+}
+
+/// Iterator over the differences between two trees, in path order
+///
+/// See `ObjectStore::diff_trees`.
+pub struct TreeDiff<'a> {
+    object_store: &'a ObjectStore,
+    /// Pairs of objects still to compare, with the next on the end
+    pending: Vec<(PathBuf, Option<ObjectKey>, Option<ObjectKey>)>,
+    ready: VecDeque<TreeDelta>,
+}
+
+impl<'a> TreeDiff<'a> {
+    fn lookup(&self, key: Option<ObjectKey>) -> Result<Option<ObjectWalkNode>> {
+        match key {
+            Some(key) => self.object_store.lookup_node(key).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn open_tree(&self, node: &Option<ObjectWalkNode>) -> Result<Tree> {
+        match *node {
+            Some(ref node) if node.object_type.is_treeish() => {
+                self.object_store.open_tree(&node.hash)
+            }
+            _ => Ok(Tree::new()),
+        }
+    }
+
+    /// Compare one pair, queueing file changes and the subtrees to compare
+    fn compare(&mut self,
+               path: PathBuf,
+               before: Option<ObjectKey>,
+               after: Option<ObjectKey>)
+               -> Result<()> {
+        if before == after {
+            return Ok(());
+        }
+        let before = self.lookup(before)?;
+        let after = self.lookup(after)?;
+        let file = |node: &Option<ObjectWalkNode>| {
+            node.filter(|node| !node.object_type.is_treeish())
+        };
+        match (file(&before), file(&after)) {
+            (Some(b), Some(a)) => {
+                self.ready.push_back(TreeDelta::Modified(path.clone(), b, a))
+            }
+            (Some(b), None) => {
+                self.ready.push_back(TreeDelta::Deleted(path.clone(), b))
+            }
+            (None, Some(a)) => {
+                self.ready.push_back(TreeDelta::Added(path.clone(), a))
+            }
+            (None, None) => {}
+        }
+
+        let before = self.open_tree(&before)?;
+        let after = self.open_tree(&after)?;
+        let names = before.keys().chain(after.keys()).collect::<BTreeSet<_>>();
+        for name in names.into_iter().rev() {
+            self.pending.push((path.join(name),
+                               before.get(name).cloned(),
+                               after.get(name).cloned()));
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for TreeDiff<'a> {
+    type Item = Result<TreeDelta>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.ready.is_empty() {
+            let (path, before, after) = self.pending.pop()?;
+            if let Err(e) = self.compare(path, before, after) {
+                self.pending.clear();
+                return Some(Err(e));
+            }
+        }
+        self.ready.pop_front().map(Ok)
+    }
+}
+
+
+/// A file found in history, from `history_files`
+#[derive(Debug,Clone,PartialEq)]
+pub struct HistoryFile {
+    /// The commit being walked when the file was found
+    pub commit: ObjectKey,
+    pub path: PathBuf,
+    pub node: ObjectWalkNode,
+    /// Link to the media metadata recorded for the file, if any
+    pub media_metadata: Option<ObjectKey>,
+}
+
+/// Iterator over every file in some commits and their history
+///
+/// See `ObjectStore::history_files`.
+pub struct HistoryFiles<'a> {
+    object_store: &'a ObjectStore,
+    /// Commits still to walk, with the next at the front
+    queue: VecDeque<ObjectKey>,
+    /// Trees still to walk, each with the commit it was found in, with the
+    /// next on the end
+    pending: Vec<(ObjectKey, ObjectKey, PathBuf)>,
+    ready: VecDeque<HistoryFile>,
+    seen_commits: HashSet<ObjectKey>,
+    seen_trees: HashSet<(ObjectKey, PathBuf)>,
+}
+
+impl<'a> HistoryFiles<'a> {
+    /// Queue a commit's parents and its tree, if it has not been walked
+    fn visit_commit(&mut self, commit: ObjectKey) -> Result<()> {
+        if !self.seen_commits.insert(commit) ||
+           !self.object_store.has_object(&commit) {
+            return Ok(());
+        }
+        let node: ObjectWalkNode = self.object_store.lookup_node(commit)?;
+        match node.object_type {
+            ObjectType::Commit => {
+                let parents = self.object_store.commit_parents(&commit)?;
+                self.queue.extend(parents);
+            }
+            ObjectType::Tree => {}
+            _ => return Ok(()),
+        }
+        self.pending.push((commit, commit, PathBuf::new()));
+        Ok(())
+    }
+
+    /// Read one tree, queueing its files and the subtrees to walk
+    fn visit_tree(&mut self,
+                  commit: ObjectKey,
+                  tree_key: ObjectKey,
+                  path: PathBuf)
+                  -> Result<()> {
+        if !self.seen_trees.insert((tree_key, path.clone())) {
+            return Ok(());
+        }
+        let tree = self.object_store.open_tree(&tree_key)?;
+        for (name, key) in tree.iter() {
+            if !self.object_store.has_object(key) &&
+               !self.object_store.is_absent(key) {
+                continue;
+            }
+            let node: ObjectWalkNode = self.object_store.lookup_node(*key)?;
+            let entry_path = path.join(name);
+            if node.object_type.is_treeish() {
+                self.pending.push((commit, *key, entry_path));
+            } else {
+                self.ready.push_back(HistoryFile {
+                    commit: commit,
+                    path: entry_path,
+                    node: node,
+                    media_metadata: tree.media_metadata.get(name).cloned(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for HistoryFiles<'a> {
+    type Item = Result<HistoryFile>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.ready.is_empty() {
+            let result = match self.pending.pop() {
+                Some((commit, tree, path)) => {
+                    self.visit_tree(commit, tree, path)
+                }
+                None => {
+                    match self.queue.pop_front() {
+                        Some(commit) => self.visit_commit(commit),
+                        None => return None,
+                    }
+                }
+            };
+            if let Err(e) = result {
+                self.pending.clear();
+                self.queue.clear();
+                return Some(Err(e));
+            }
+        }
+        self.ready.pop_front().map(Ok)
+    }
+}
+
+pub struct DepthFirstCommitSort<'a> {
+    object_store: &'a ObjectStore,
+    unvisited: Vec<ObjectKey>,
+    visited: HashSet<ObjectKey>,
+    sorted: Vec<(ObjectKey, Commit)>,
+}
+impl<'a> DepthFirstCommitSort<'a> {
+    pub fn new(object_store: &'a ObjectStore,
+               unvisited: Vec<ObjectKey>)
+               -> Self {
+        DepthFirstCommitSort {
+            object_store: object_store,
+            unvisited: unvisited,
+            visited: HashSet::new(),
+            sorted: Vec::new(),
+        }
+    }
+
+    pub fn run(mut self) -> Result<Vec<(ObjectKey, Commit)>> {
+        debug!("Sorting commits, starting with: {:?}", self.unvisited);
+        for hash in &self.unvisited.clone() {
+            self.visit(&hash)?;
+        }
+        Ok(self.sorted)
+    }
+
+    fn visit(&mut self, hash: &ObjectKey) -> Result<()> {
+        if self.visited.contains(hash) {
+            trace!("Already visited: {}", hash);
+            return Ok(());
+        }
+        self.visited.insert(*hash);
+        let mut commit = self.object_store.open_commit(hash)?;
+        if self.object_store.is_shallow(hash) {
+            // History stops here, so treat it like a root commit
+            commit.parents.clear();
+        }
+        trace!("Visiting {} {}, parents: {:?}",
+               hash,
+               commit.message,
+               commit.parents);
+        for parent in &commit.parents {
+            self.visit(parent)?;
+        }
+        trace!("Pushing  {} {}", hash, commit.message);
+        self.sorted.push((*hash, commit));
+        Ok(())
+    }
+}
+
+
+/// A wrapper to Display a LsTree, with options
+pub struct TreeDisplay<'a> {
+    object_store: &'a ObjectStore,
+    node: ObjectWalkNode,
+    verbose: bool,
+    pathspec: &'a Pathspec,
+}
+impl<'a> fmt::Display for TreeDisplay<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut op = TreeDisplayOp {
+            formatter: f,
+            verbose: self.verbose,
+            pathspec: self.pathspec,
+        };
+        match self.object_store.walk_node(&mut op, self.node) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(fmt::Error),
+        }
+    }
+}
+
+
+/// An operation that walks an Object Tree to Display it
+struct TreeDisplayOp<'s, 'f: 's> {
+    formatter: &'s mut fmt::Formatter<'f>,
+    verbose: bool,
+    pathspec: &'s Pathspec,
+}
+impl<'a, 'b> WalkOp<ObjectWalkNode> for TreeDisplayOp<'a, 'b> {
+    type VisitResult = ();
+
+    fn should_descend(&mut self, ps: &Path, node: &ObjectWalkNode) -> bool {
+        node.object_type.is_treeish() && self.pathspec.matches_within(ps)
+    }
+
+    fn no_descend(&mut self,
+                  ps: &Path,
+                  node: ObjectWalkNode)
+                  -> Result<Option<Self::VisitResult>> {
+        if !self.pathspec.matches(ps) {
+            return Ok(None);
+        }
+        if self.verbose {
+            writeln!(self.formatter,
+                     "{} {} {}",
+                     node.hash,
+                     node.object_type.code(),
+                     ps.display())?;
+        } else {
+            writeln!(self.formatter, "{}", ps.display())?;
+        }
+        Ok(None)
+    }
+}
+
+
+/// A file or directory with its cumulative size, for a tree-style listing
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct SizeTree {
+    pub name: String,
+    pub size: ObjectSize,
+    pub is_dir: bool,
+    pub children: Vec<SizeTree>,
+}
+
+impl SizeTree {
+    fn fmt_indented(&self,
+                    f: &mut fmt::Formatter,
+                    prefix: &str,
+                    connector: &str)
+                    -> fmt::Result {
+        writeln!(f,
+                 "{:>10}  {}{}{}",
+                 human_bytes(self.size),
+                 connector,
+                 self.name,
+                 if self.is_dir { "/" } else { "" })?;
+        let count = self.children.len();
+        for (i, child) in self.children.iter().enumerate() {
+            let last = i + 1 == count;
+            let (connector, extend) = if last {
+                ("└── ", "    ")
+            } else {
+                ("├── ", "│   ")
+            };
+            child.fmt_indented(f,
+                               &format!("{}{}", prefix, extend),
+                               &format!("{}{}", prefix, connector))?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for SizeTree {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_indented(f, "", "")
+    }
+}
+
+/// An operation that walks an Object Tree to total up subtree sizes
+struct SizeTreeOp {
+    sort_by_size: bool,
+}
+impl WalkOp<ObjectWalkNode> for SizeTreeOp {
+    type VisitResult = SizeTree;
+
+    fn should_descend(&mut self, _ps: &Path, node: &ObjectWalkNode) -> bool {
+        node.object_type.is_treeish()
+    }
+
+    fn no_descend(&mut self,
+                  ps: &Path,
+                  node: ObjectWalkNode)
+                  -> Result<Option<Self::VisitResult>> {
+        Ok(Some(SizeTree {
+            name: path_name(ps),
+            size: node.file_size,
+            is_dir: false,
+            children: Vec::new(),
+        }))
+    }
+
+    fn post_descend(&mut self,
+                    ps: &Path,
+                    _node: ObjectWalkNode,
+                    children: ChildMap<Self::VisitResult>)
+                    -> Result<Option<Self::VisitResult>> {
+        let mut children = children.into_values().collect::<Vec<_>>();
+        if self.sort_by_size {
+            children.sort_by_key(|c| cmp::Reverse(c.size));
+        }
+        Ok(Some(SizeTree {
+            name: path_name(ps),
+            size: children.iter().map(|c| c.size).sum(),
+            is_dir: true,
+            children: children,
+        }))
+    }
+}
+
+/// Total size of the files in a tree, as if it were checked out
+///
+/// Results for subtrees are memoized, so shared subtrees are only added up
+/// once. Missing objects count as empty.
+fn checkout_size(key: &ObjectKey,
+                 tree_entries: &HashMap<ObjectKey, Vec<ObjectKey>>,
+                 file_sizes: &HashMap<ObjectKey, ObjectSize>,
+                 memo: &mut HashMap<ObjectKey, ObjectSize>)
+                 -> ObjectSize {
+    if let Some(&size) = file_sizes.get(key).or_else(|| memo.get(key)) {
+        return size;
+    }
+    let size = match tree_entries.get(key) {
+        Some(entries) => {
+            entries.iter()
+                .map(|e| checkout_size(e, tree_entries, file_sizes, memo))
+                .sum()
+        }
+        None => 0,
+    };
+    memo.insert(*key, size);
+    size
+}
+
+/// An object and the objects below it, for a recursive listing
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct ObjectTree {
+    pub name: String,
+    pub node: ObjectWalkNode,
+    /// Size of a file, or total size of the files below a tree, if known
+    pub size: Option<ObjectSize>,
+    pub children: Vec<ObjectTree>,
+}
+
+impl ObjectTree {
+    fn fmt_indented(&self,
+                    f: &mut fmt::Formatter,
+                    depth: usize)
+                    -> fmt::Result {
+        writeln!(f,
+                 "{} {} {:>10}  {:indent$}{}{}",
+                 self.node.hash,
+                 self.node.object_type.code(),
+                 self.size.map_or("-".to_owned(), human_bytes),
+                 "",
+                 self.name,
+                 if self.node.object_type.is_treeish() { "/" } else { "" },
+                 indent = depth * 2)?;
+        for child in &self.children {
+            child.fmt_indented(f, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ObjectTree {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_indented(f, 0)
+    }
+}
+
+/// An operation that walks an Object Tree to list every object in it
+struct ObjectTreeOp {
+    max_depth: Option<usize>,
+}
+impl WalkOp<ObjectWalkNode> for ObjectTreeOp {
+    type VisitResult = ObjectTree;
+
+    fn should_descend(&mut self, ps: &Path, node: &ObjectWalkNode) -> bool {
+        node.object_type.is_treeish() &&
+        self.max_depth.is_none_or(|max| ps.components().count() < max)
+    }
+
+    fn no_descend(&mut self,
+                  ps: &Path,
+                  node: ObjectWalkNode)
+                  -> Result<Option<Self::VisitResult>> {
+        Ok(Some(ObjectTree {
+            name: path_name(ps),
+            size: match node.object_type.is_treeish() {
+                true => None,
+                false => Some(node.file_size),
+            },
+            node: node,
+            children: Vec::new(),
+        }))
+    }
+
+    fn post_descend(&mut self,
+                    ps: &Path,
+                    node: ObjectWalkNode,
+                    children: ChildMap<Self::VisitResult>)
+                    -> Result<Option<Self::VisitResult>> {
+        let children = children.into_values().collect::<Vec<_>>();
+        Ok(Some(ObjectTree {
+            name: path_name(ps),
+            size: children.iter().map(|c| c.size).sum(),
+            node: node,
+            children: children,
+        }))
+    }
+}
+
+fn path_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+
+#[cfg(test)]
+pub mod test {
+    use dag::Blob;
+    use dag::Object;
+    use dag::ObjectCommon;
+    use dag::ToHashed;
+    use std::ffi::OsString;
+    use std::time::Duration;
+    use storage::MemoryStorage;
+    use super::*;
+    use testutil::tempdir::TempDir;
+
+    pub fn create_temp_repository() -> Result<(TempDir, ObjectStore)> {
+        let wd_temp = in_mem_tempdir!();
+        let os_path = wd_temp.path().join("object_store");
+        let os = try!(ObjectStore::init(os_path));
+
+        Ok((wd_temp, os))
+    }
+
+    #[test]
+    fn test_store_and_retrieve() {
+        let (_tempdir, store) = create_temp_repository().unwrap();
+
+        let obj = Blob::from("Hello!").to_hashed();
+
+        assert!(!store.has_object(obj.hash()),
+                "Store should not have key at first");
+
+        let stored_key = store.store_object(&obj).unwrap();
+        assert_eq!(stored_key,
+                   *obj.hash(),
+                   "Key when stored should be the same as given by \
+                    calculate_hash");
+        assert!(store.has_object(&stored_key),
+                "Store should report that key is present");
+
+        let mut reader = store.open_object_file(&stored_key).unwrap();
+        let retrieved = Object::read_from(&mut reader, store.hash_algorithm())
+            .unwrap();
+        assert_eq!(retrieved,
+                   *obj,
+                   "Retrieved object should be the same as stored object");
+    }
+
+    #[test]
+    fn test_hash_algorithm_from_config() {
+        let (_tempdir, store) = create_temp_repository().unwrap();
+        assert_eq!(store.hash_algorithm(), HashAlgorithm::Sha256);
+        let key = store.store_object(&Blob::from("Hello!")).unwrap();
+        assert_eq!(key.algorithm(), HashAlgorithm::Sha256);
+
+        // Legacy repository without a config file should use SHA-1
+        let temp = in_mem_tempdir!();
+        let legacy_path = temp.path().join("object_store");
+        fs::create_dir_all(&legacy_path).unwrap();
+        let store = ObjectStore::open(legacy_path).unwrap();
+        assert_eq!(store.hash_algorithm(), HashAlgorithm::Sha1);
+
+        let blob_key = store.store_object(&Blob::from("Hello!")).unwrap();
+        assert_eq!(blob_key.algorithm(), HashAlgorithm::Sha1);
+        let tree_key = store.store_object(&tree_object!{
+                "hello.txt" => blob_key,
+            })
+            .unwrap();
+        let tree = store.open_tree(&tree_key).unwrap();
+        assert_eq!(tree.get(&OsString::from("hello.txt")), Some(&blob_key));
+    }
+
+    #[test]
+    fn test_encrypted_store() {
+        let temp = in_mem_tempdir!();
+        let path = temp.path().join("object_store");
+        let store = ObjectStore::init_encrypted(path.clone(),
+                                                RepoConfig::default(),
+                                                "secret")
+            .unwrap();
+        let blob = store.store_object(&Blob::from("Hello!")).unwrap();
+        let tree = store.store_object(&tree_object!{
+                "hello.txt" => blob,
+            })
+            .unwrap();
+        let blob_file = fs::read(store.object_path(&blob)).unwrap();
+        assert!(encryption::is_sealed(&blob_file), "Content is encrypted");
+        let tree_file = fs::read(store.object_path(&tree)).unwrap();
+        assert!(!encryption::is_sealed(&tree_file), "Metadata is not");
+        store.record_media_type(blob, MediaType::Png).unwrap();
+        let record = fs::read(path.join(store.media_type_name(&blob)))
+            .unwrap();
+        assert!(encryption::is_sealed(&record), "Nor are media types");
+
+        // Objects copied in raw are sealed too
+        let (_plain_temp, plain) = create_temp_repository().unwrap();
+        let other = plain.store_object(&Blob::from("Hi!")).unwrap();
+        store.store_raw_object(&other, &mut plain.open_object_file(&other)
+                .unwrap())
+            .unwrap();
+        let other_file = fs::read(store.object_path(&other)).unwrap();
+        assert!(encryption::is_sealed(&other_file));
+
+        let store = ObjectStore::open(path).unwrap();
+        match store.unlock("wrong") {
+            Err(Error(ErrorKind::WrongPassphrase, _)) => (),
+            other => panic!("Expected WrongPassphrase, got {:?}", other),
+        }
+        store.unlock("secret").unwrap();
+        let tree = store.open_tree(&tree).unwrap();
+        assert_eq!(tree.get(&OsString::from("hello.txt")), Some(&blob));
+        let mut content = Vec::new();
+        store.copy_blob_content(&blob, &mut content).unwrap();
+        assert_eq!(content, b"Hello!");
+        assert_eq!(store.media_type(&blob).unwrap(), MediaType::Png);
+        store.verify_object(&other).unwrap();
+        assert!(store.fsck(false, &[]).unwrap().corrupt.is_empty());
+    }
+
+    #[test]
+    fn test_fsck_report() {
+        let (_tempdir, mut store) = create_temp_repository().unwrap();
+
+        let good = store.store_object(&Blob::from("good")).unwrap();
+        let corrupt = store.store_object(&Blob::from("corrupt")).unwrap();
+        let gone = Blob::from("gone").calculate_hash();
+        let tree = store.store_object(&tree_object!{
+                "good" => good,
+                "corrupt" => corrupt,
+                "gone" => gone,
+            })
+            .unwrap();
+        let orphan = store.store_object(&Blob::from("orphan")).unwrap();
+        store.update_ref("master".to_owned(), tree).unwrap();
+
+        fs::OpenOptions::new()
+            .append(true)
+            .open(store.object_path(&corrupt))
+            .and_then(|mut f| f.write_all(b"extra bytes"))
+            .unwrap();
+
+        let report = store.fsck(false, &[]).unwrap();
+        assert!(!report.is_ok());
+        assert_eq!(report.corrupt.len(), 1);
+        assert_eq!(report.corrupt[0].0, corrupt);
+        assert_eq!(report.missing, vec![(gone, tree)]);
+        assert_eq!(report.orphans, vec![orphan]);
+        assert_eq!(report.quarantined, vec![]);
+        for jobs in 1..4 {
+            let options = FsckOptions {
+                jobs: jobs,
+                ..FsckOptions::default()
+            };
+            assert_eq!(store.fsck_with(&options, &[]).unwrap(),
+                       report,
+                       "Same report with {} jobs",
+                       jobs);
+        }
+
+        let report = store.fsck(true, &[]).unwrap();
+        assert_eq!(report.quarantined, vec![corrupt]);
+        assert!(!store.has_object(&corrupt));
+        assert!(store.path().join("corrupt").join(corrupt.to_hex()).is_file());
+        assert_eq!(report.missing.len(), 2, "Quarantined object now missing");
+
+        let report = store.fsck(false, &[orphan]).unwrap();
+        assert_eq!(report.orphans, vec![], "Extra roots are reachable");
+    }
+
+    #[test]
+    fn test_fsck_incremental() {
+        let (_tempdir, store) = create_temp_repository().unwrap();
+        let corrupt = |key: &ObjectKey, mtime: Option<SystemTime>| {
+            let mut file = fs::OpenOptions::new()
+                .append(true)
+                .open(store.object_path(key))
+                .unwrap();
+            file.write_all(b"extra bytes").unwrap();
+            if let Some(mtime) = mtime {
+                file.set_modified(mtime).unwrap();
+            }
+        };
+        let long_ago = UNIX_EPOCH + Duration::from_secs(1000);
+        let old = store.store_object(&Blob::from("old")).unwrap();
+        fs::File::open(store.object_path(&old))
+            .and_then(|f| f.set_modified(long_ago))
+            .unwrap();
+        let incremental = FsckOptions {
+            incremental: true,
+            ..FsckOptions::default()
+        };
+        assert!(store.fsck(false, &[]).unwrap().is_ok());
+
+        // Decay that keeps the old time is missed until a full check
+        corrupt(&old, Some(long_ago));
+        assert!(store.fsck_with(&incremental, &[]).unwrap().is_ok());
+
+        let new = store.store_object(&Blob::from("new")).unwrap();
+        corrupt(&new, None);
+        let report = store.fsck_with(&incremental, &[]).unwrap();
+        assert_eq!(report.corrupt.iter().map(|c| c.0).collect::<Vec<_>>(),
+                   vec![new]);
+        assert_eq!(store.fsck(false, &[]).unwrap().corrupt.len(), 2);
+    }
+
+    #[test]
+    fn test_fsck_rebuilds_from_parity() {
+        let (_tempdir, mut store) = create_temp_repository().unwrap();
+        let corrupt = store.store_object(&Blob::from("corrupt")).unwrap();
+        let gone = store.store_object(&Blob::from("gone")).unwrap();
+        let tree = store.store_object(&tree_object!{
+                "corrupt" => corrupt,
+                "gone" => gone,
+            })
+            .unwrap();
+        store.update_ref("master".to_owned(), tree).unwrap();
+        Parity::open(&store).unwrap().build(&store, 4, 2).unwrap();
+
+        fs::OpenOptions::new()
+            .append(true)
+            .open(store.object_path(&corrupt))
+            .and_then(|mut f| f.write_all(b"extra bytes"))
+            .unwrap();
+        fs::remove_file(store.object_path(&gone)).unwrap();
+
+        let report = store.fsck(false, &[]).unwrap();
+        assert!(!report.is_ok());
+        assert!(report.reconstructed.is_empty(), "Only rebuilds on repair");
+
+        let report = store.fsck(true, &[]).unwrap();
+        assert_eq!(report.quarantined, vec![corrupt]);
+        let mut reconstructed = report.reconstructed.clone();
+        reconstructed.sort();
+        let mut expected = vec![corrupt, gone];
+        expected.sort();
+        assert_eq!(reconstructed, expected);
+        assert!(report.is_ok(), "Rebuilt: {:?}", report);
+        store.verify_object(&corrupt).unwrap();
+        store.verify_object(&gone).unwrap();
+    }
+
+    #[test]
+    fn test_fsck_bad_chunk_sizes() {
+        let (_tempdir, store) = create_temp_repository().unwrap();
+
+        let mut index = ChunkedBlob::new();
+        for chunk in &["one", "two", "three"] {
+            let hash = store.store_object(&Blob::from(*chunk)).unwrap();
+            index.add_chunk(chunk.len() as ObjectSize, hash);
+        }
+        index.total_size = 100;
+        let bad = store.store_object(&index).unwrap();
+
+        assert!(store.open_chunked_blob(&bad).is_err());
+
+        let report = store.fsck(false, &[bad]).unwrap();
+        assert!(!report.is_ok());
+        assert_eq!(report.bad_chunk_sizes, vec![(bad, 100, 11)]);
+        assert_eq!(report.rewritten, vec![]);
+
+        let report = store.fsck(true, &[bad]).unwrap();
+        assert_eq!(report.rewritten.len(), 1);
+        let (old, new) = report.rewritten[0];
+        assert_eq!(old, bad);
+        let fixed = store.open_chunked_blob(&new).unwrap();
+        assert_eq!(fixed.total_size, 11);
+        assert_eq!(fixed.chunks, index.chunks);
+    }
+
+    #[test]
+    fn test_fsck_empty_chunk_index() {
+        let (_tempdir, store) = create_temp_repository().unwrap();
+        let empty = store.store_object(&ChunkedBlob::new()).unwrap();
+
+        let report = store.fsck(false, &[empty]).unwrap();
+        assert!(report.is_ok(), "An index with no chunks is still valid");
+        assert_eq!(report.bad_chunk_sizes, vec![]);
+    }
+
+    #[test]
+    fn test_verify_chunking() {
+        let (_tempdir, store) = create_temp_repository().unwrap();
+
+        let blob = store.store_object(&Blob::from("one two three")).unwrap();
+        let check = store.verify_chunking(&blob).unwrap();
+        assert!(check.matches());
+        assert_eq!(check.stored_chunks, 1);
+        assert_eq!(check.reproduced, 1);
+
+        // Split by hand, much finer than the chunker would
+        let mut index = ChunkedBlob::new();
+        for chunk in &["one ", "two ", "three"] {
+            let hash = store.store_object(&Blob::from(*chunk)).unwrap();
+            index.add_chunk(chunk.len() as ObjectSize, hash);
+        }
+        let drifted = store.store_object(&index).unwrap();
+        let check = store.verify_chunking(&drifted).unwrap();
+        assert!(!check.matches());
+        assert_eq!(check.stored_chunks, 3);
+        assert_eq!(check.rechunked, 1);
+        assert_eq!(check.reproduced, 0);
+        assert_eq!(check.first_difference, Some(0));
+    }
+
+    #[test]
+    fn test_read_blob_range() {
+        let (_tempdir, store) = create_temp_repository().unwrap();
+
+        let blob = store.store_object(&Blob::from("one two three")).unwrap();
+        assert_eq!(store.read_blob_range(&blob, 4, 3).unwrap(), b"two");
+        assert_eq!(store.read_blob_range(&blob, 8, 100).unwrap(), b"three");
+        assert_eq!(store.read_blob_range(&blob, 100, 5).unwrap(), b"");
+
+        // The middle chunk is missing, so reading it would fail
+        let missing = Blob::from("MISSING ").calculate_hash();
+        let mut index = ChunkedBlob::new();
+        for chunk in &["one ", "MISSING ", "three"] {
+            let hash = match *chunk {
+                "MISSING " => missing,
+                _ => store.store_object(&Blob::from(*chunk)).unwrap(),
+            };
+            index.add_chunk(chunk.len() as ObjectSize, hash);
+        }
+        let chunked = store.store_object(&index).unwrap();
+        assert_eq!(store.read_blob_range(&chunked, 1, 3).unwrap(), b"ne ");
+        assert_eq!(store.read_blob_range(&chunked, 13, 3).unwrap(), b"hre");
+        assert_eq!(store.read_blob_range(&chunked, 14, 0).unwrap(), b"");
+        assert!(store.read_blob_range(&chunked, 2, 4).is_err());
+    }
+
+    #[test]
+    fn test_stats() {
+        let (_tempdir, mut store) = create_temp_repository().unwrap();
+
+        let mut index = ChunkedBlob::new();
+        for chunk in &["shared", "unique"] {
+            let hash = store.store_object(&Blob::from(*chunk)).unwrap();
+            index.add_chunk(chunk.len() as ObjectSize, hash);
+        }
+        let big = store.store_object(&index).unwrap();
+        let mut index = ChunkedBlob::new();
+        for chunk in &["shared", "other"] {
+            let hash = store.store_object(&Blob::from(*chunk)).unwrap();
+            index.add_chunk(chunk.len() as ObjectSize, hash);
+        }
+        let big2 = store.store_object(&index).unwrap();
+        let small = store.store_object(&Blob::from("small")).unwrap();
+        let subdir = store.store_object(&tree_object!{
+                "small" => small,
+                "big" => big,
+            })
+            .unwrap();
+        let root = store.store_object(&tree_object!{
+                "a" => subdir,
+                "b" => subdir,
+                "big2" => big2,
+                "copy" => small,
+            })
+            .unwrap();
+        store.store_object(&Blob::from("unreachable")).unwrap();
+        store.update_ref("master".to_owned(), root).unwrap();
+
+        let stats = store.stats(2).unwrap();
+        assert_eq!(stats.logical_bytes, 2 * (12 + 5) + 11 + 5);
+        assert_eq!(stats.shared_chunks, 1);
+        assert_eq!(stats.largest_files,
+                   vec![(big, "big".to_owned(), 12),
+                        (big2, "big2".to_owned(), 11)]);
+        assert_eq!(stats.by_type[&ObjectType::ChunkedBlob].sizes.count(), 2);
+        assert_eq!(stats.by_type[&ObjectType::Blob].sizes.count(),
+                   4,
+                   "Unreachable blob should not be counted");
+        assert!(stats.by_type[&ObjectType::Tree].dedup_ratio() > 1.0,
+                "Subdir is referenced twice");
+        let stored: ObjectSize = stats.by_type
+            .values()
+            .map(|s| s.stored_bytes)
+            .sum();
+        assert_eq!(stats.stored_bytes, stored);
+    }
+
+    #[test]
+    fn test_size_tree() {
+        let (_tempdir, store) = create_temp_repository().unwrap();
+        let small = store.store_object(&Blob::from("small")).unwrap();
+        let big = store.store_object(&Blob::from("big big big")).unwrap();
+        let subdir = store.store_object(&tree_object!{
+                "b_small" => small,
+                "c_big" => big,
+            })
+            .unwrap();
+        let root = store.store_object(&tree_object!{
+                "a_small" => small,
+                "z_dir" => subdir,
+            })
+            .unwrap();
+
+        let tree = store.size_tree(root, false).unwrap();
+        assert_eq!(tree.size, 21);
+        assert_eq!(tree.children
+                       .iter()
+                       .map(|c| (c.name.as_str(), c.size, c.is_dir))
+                       .collect::<Vec<_>>(),
+                   vec![("a_small", 5, false), ("z_dir", 16, true)]);
+
+        let tree = store.size_tree(root, true).unwrap();
+        assert_eq!(tree.children[0].name, "z_dir");
+        assert_eq!(tree.children[0].children[0].name, "c_big");
+
+        let display = format!("{}", tree);
+        assert_eq!(display.lines().collect::<Vec<_>>(),
+                   vec!["  21 bytes  ./",
+                        "  16 bytes  ├── z_dir/",
+                        "  11 bytes  │   ├── c_big",
+                        "   5 bytes  │   └── b_small",
+                        "   5 bytes  └── a_small"]);
+    }
+
+    #[test]
+    fn test_object_tree() {
+        let (_tempdir, store) = create_temp_repository().unwrap();
+        let small = store.store_object(&Blob::from("small")).unwrap();
+        let big = store.store_object(&Blob::from("big big big")).unwrap();
+        let subdir = store.store_object(&tree_object!{
+                "big" => big,
+            })
+            .unwrap();
+        let root = store.store_object(&tree_object!{
+                "small" => small,
+                "subdir" => subdir,
+            })
+            .unwrap();
+
+        let tree = store.object_tree(root, None).unwrap();
+        assert_eq!(tree.size, Some(16));
+        let display = format!("{}", tree);
+        assert_eq!(display.lines().collect::<Vec<_>>(),
+                   vec![format!("{} tree   16 bytes  ./", root),
+                        format!("{} blob    5 bytes    small", small),
+                        format!("{} tree   11 bytes    subdir/", subdir),
+                        format!("{} blob   11 bytes      big", big)]);
+
+        let tree = store.object_tree(root, Some(1)).unwrap();
+        assert_eq!(tree.size, None, "Size is unknown below the limit");
+        assert_eq!(tree.children[1].node.hash, subdir);
+        assert!(tree.children[1].children.is_empty());
+        assert_eq!(store.object_tree(root, Some(0)).unwrap().children, vec![]);
+    }
+
+    #[test]
+    fn test_verify_new_tree() {
+        let (_tempdir, store) = create_temp_repository().unwrap();
+        let old_file = store.store_object(&Blob::from("old")).unwrap();
+        let new_file = store.store_object(&Blob::from("new")).unwrap();
+        let unchanged = store.store_object(&tree_object!{
+                "file" => old_file,
+            })
+            .unwrap();
+        let old_root = store.store_object(&tree_object!{
+                "dir" => unchanged,
+                "file" => old_file,
+            })
+            .unwrap();
+        let new_root = store.store_object(&tree_object!{
+                "dir" => unchanged,
+                "file" => new_file,
+            })
+            .unwrap();
+
+        assert_eq!(store.verify_new_tree(&new_root, Some(&old_root)).unwrap(),
+                   2,
+                   "Should verify only the new root and the changed file");
+        assert_eq!(store.verify_new_tree(&new_root, None).unwrap(), 4);
+
+        // Corrupt the new file on disk
+        let path = store.object_path(&new_file);
+        let mut perms = fs::metadata(&path).unwrap().permissions();
+        perms.set_readonly(false);
+        fs::set_permissions(&path, perms).unwrap();
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"garbage")
+            .unwrap();
+
+        match store.verify_new_tree(&new_root, Some(&old_root)) {
+            Err(Error(ErrorKind::CorruptObject(expected, _, _), _)) => {
+                assert_eq!(expected, new_file);
+            }
+            other => panic!("Expected CorruptObject, got {:?}", other),
+        }
+        assert_eq!(store.verify_new_tree(&old_root, None).unwrap(),
+                   4,
+                   "Old objects should still verify");
+    }
+
+    #[test]
+    fn test_store_raw_object_verifies_hash() {
+        let (_tempdir, store) = create_temp_repository().unwrap();
+        let blob = Blob::from("Hello!");
+        let key = blob.calculate_hash();
+        let mut raw = Vec::new();
+        blob.write_to(&mut raw).unwrap();
+
+        // Flip a bit, as a flaky network might
+        let mut damaged = raw.clone();
+        let last = damaged.len() - 1;
+        damaged[last] ^= 1;
+        let result = store.store_raw_object(&key, &mut damaged.as_slice());
+        match result {
+            Err(Error(ErrorKind::CorruptTransfer(expected, actual, path),
+                      _)) => {
+                assert_eq!(expected, key);
+                assert_ne!(actual, key);
+                assert!(path.is_file());
+                assert!(path.with_extension("report").is_file());
+            }
+            other => panic!("Expected CorruptTransfer, got {:?}", other),
+        }
+        assert!(!store.has_object(&key),
+                "Corrupt object should not be installed");
+
+        // Intact copy is accepted
+        let size = store.store_raw_object(&key, &mut raw.as_slice()).unwrap();
+        assert_eq!(size, raw.len() as ObjectSize);
+        assert!(store.has_object(&key));
+        assert!(store.fsck(false, &[]).unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_copy_raw_content() {
+        let (_tempdir, store) = create_temp_repository().unwrap();
+
+        let blob = Blob::from("Hello!");
+        let blob_key = store.store_object(&blob).unwrap();
+        let tree = tree_object!{
+            "hello.txt" => blob_key,
+        };
+        let tree_key = store.store_object(&tree).unwrap();
+
+        let mut raw = Vec::new();
+        store.open_object(&blob_key)
+            .and_then(|handle| handle.copy_raw_content(&mut raw))
+            .unwrap();
+        assert_eq!(raw, b"Hello!", "Blob raw content should be file data");
+
+        let mut expected = Vec::new();
+        tree.write_content(&mut expected).unwrap();
+        let mut raw = Vec::new();
+        store.open_object(&tree_key)
+            .and_then(|handle| handle.copy_raw_content(&mut raw))
+            .unwrap();
+        assert_eq!(raw, expected, "Tree raw content should be serialized form");
+    }
+
+
+    #[test]
+    fn test_update_and_read_ref() {
+        let (_tempdir, mut store) = create_temp_repository().unwrap();
+        let hash = Blob::from("Hello!").to_hashed().hash().to_owned();
+
+        let result = store.try_find_ref("master");
+        assert_match!(result, None);
+
+        let result = store.update_ref("master".to_string(), hash);
+        assert_match!(result, Ok(()));
+
+        let result = store.try_find_ref("master");
+        assert_match!(result, Some(x) if x==hash);
+    }
+
+    #[test]
+    fn test_lookup_tracking_ref() {
+        let (_tempdir, mut store) = create_temp_repository().unwrap();
+        let ours = store.store_object(&Blob::from("ours")).unwrap();
+        let theirs = store.store_object(&Blob::from("theirs")).unwrap();
+        store.update_ref("master".to_owned(), ours).unwrap();
+        store.update_ref("remotes/origin/master".to_owned(), theirs).unwrap();
+
+        let lookup = |rev: &str| store.lookup(&rev.parse().unwrap()).unwrap();
+        assert_eq!(lookup("master"), (ours, ours, Some("master".to_owned())));
+        assert_eq!(lookup("origin/master"), (theirs, theirs, None));
+        assert_eq!(lookup("remotes/origin/master"),
+                   (theirs, theirs, None),
+                   "Tracking refs should not be returned as branch names");
+    }
+
+    #[test]
+    fn test_typed_errors() {
+        let (_tempdir, store) = create_temp_repository().unwrap();
+        let blob = store.store_object(&Blob::from("blob")).unwrap();
+        let missing = Blob::from("missing").calculate_hash();
+
+        match store.lookup(&"nosuchref".parse().unwrap()) {
+            Err(Error(ErrorKind::RefNotFound(ref name), _)) => {
+                assert_eq!(name, "nosuchref")
             }
+            other => panic!("Expected RefNotFound, got {:?}", other),
         }
-    }
-}
 
-lazy_static!{
-    pub static ref SHORT_OBJECT_KEY_PAT:Regex = Regex::new(
-        &format!("[[:xdigit:]]{{ {},{} }}",
-                    KEY_SHORT_LEN, KEY_SIZE_HEX_DIGITS)).unwrap();
+        let err = store.open_commit(&missing).unwrap_err();
+        assert_match!(err.root_kind(), &ErrorKind::ObjectNotFound(h)
+                                           if h == missing);
 
-    pub static ref REF_NAME_PAT:Regex = Regex::new("[[:word:]/-]+").unwrap();
-}
+        let err = store.open_commit(&blob).unwrap_err();
+        assert_match!(err.root_kind(),
+                      &ErrorKind::WrongObjectType(h,
+                                                  ObjectType::Commit,
+                                                  ObjectType::Blob)
+                          if h == blob);
 
+        let tree = store.store_object(&tree_object!{ "blob" => blob, })
+            .unwrap();
+        let err = store.verify_chunking(&tree).unwrap_err();
+        assert_match!(err.root_kind(),
+                      &ErrorKind::WrongObjectType(h,
+                                                  ObjectType::Blob,
+                                                  ObjectType::Tree)
+                          if h == tree);
+    }
 
-#[derive(Debug,Clone,Copy)]
-pub struct ObjectWalkNode {
-    pub hash: ObjectKey,
-    pub object_type: ObjectType,
-    pub file_size: ObjectSize,
-}
+    #[test]
+    fn test_disk_usage() {
+        let (_tempdir, store) = create_temp_repository().unwrap();
+        let blob = |s: &str| store.store_object(&Blob::from(s)).unwrap();
+        let (shared, only_a, only_b) =
+            (blob("shared"), blob("only in a"), blob("only in b, longer"));
+        let a = store.store_object(&tree_object!{
+                "shared" => shared,
+                "only" => only_a,
+            })
+            .unwrap();
+        let b = store.store_object(&tree_object!{
+                "shared" => shared,
+                "only" => only_b,
+            })
+            .unwrap();
+        let root = store.store_object(&tree_object!{
+                "a" => a,
+                "b" => b,
+                "c" => only_b,
+            })
+            .unwrap();
+        let commit = store.store_object(&Commit {
+                tree: root,
+                parents: vec![],
+                message: "du".to_owned(),
+                time: None,
+                signature: None,
+            })
+            .unwrap();
+        let size = |key: &ObjectKey| {
+            fs::metadata(store.object_path(key)).unwrap().len()
+        };
 
-impl Into<ComparableNode> for ObjectWalkNode {
-    fn into(self) -> ComparableNode {
-        ComparableNode {
-            is_treeish: self.object_type.is_treeish(),
-            file_size: self.file_size,
-            hash: Some(self.hash),
-            fs_path: None,
-            is_ignored: false,
-        }
+        let usage = store.disk_usage(&commit).unwrap();
+        assert_eq!(usage, store.disk_usage(&root).unwrap());
+        assert_eq!(usage.entries,
+                   vec![EntryUsage {
+                            name: "a".to_owned(),
+                            is_dir: true,
+                            retained: size(&a) + size(&only_a),
+                            total: size(&a) + size(&only_a) + size(&shared),
+                        },
+                        EntryUsage {
+                            name: "b".to_owned(),
+                            is_dir: true,
+                            retained: size(&b),
+                            total: size(&b) + size(&only_b) + size(&shared),
+                        },
+                        EntryUsage {
+                            name: "c".to_owned(),
+                            is_dir: false,
+                            retained: 0,
+                            total: size(&only_b),
+                        }]);
+        assert_eq!(usage.shared, size(&shared) + size(&only_b));
+        assert_eq!(usage.total,
+                   [root, a, b, shared, only_a, only_b].iter().map(size).sum());
+        assert!(store.disk_usage(&shared).is_err());
     }
-}
 
+    #[test]
+    fn test_find_duplicates() {
+        let (_tempdir, mut store) = create_temp_repository().unwrap();
+        let blob = |s: &str| store.store_object(&Blob::from(s)).unwrap();
+        let (photo, other, tiny) = (blob("photo"), blob("other"), blob("x"));
+        let commit = |tree: ObjectKey, parents: Vec<ObjectKey>| {
+            store.store_object(&Commit {
+                    tree: tree,
+                    parents: parents,
+                    message: "dup".to_owned(),
+                    time: None,
+                    signature: None,
+                })
+                .unwrap()
+        };
+        let dir = store.store_object(&tree_object!{
+                "copy.jpg" => photo,
+                "y" => tiny,
+            })
+            .unwrap();
+        let first_tree = store.store_object(&tree_object!{
+                "a.jpg" => photo,
+                "other" => other,
+                "x" => tiny,
+            })
+            .unwrap();
+        // Same paths again in the next commit are not duplicates
+        let second_tree = store.store_object(&tree_object!{
+                "a.jpg" => photo,
+                "dir" => dir,
+                "other" => other,
+            })
+            .unwrap();
+        let first = commit(first_tree, vec![]);
+        let second = commit(second_tree, vec![first]);
+        store.update_ref("master".to_owned(), second).unwrap();
+
+        let groups = store.find_duplicates(2).unwrap();
+        assert_eq!(groups,
+                   vec![DuplicateGroup {
+                            hash: photo,
+                            size: 5,
+                            paths: vec![(PathBuf::from("a.jpg"), second),
+                                        (PathBuf::from("dir/copy.jpg"),
+                                         second)],
+                        }]);
+        let groups = store.find_duplicates(0).unwrap();
+        assert_eq!(groups.len(), 2, "Tiny file too");
+        assert_eq!(groups[1].paths,
+                   vec![(PathBuf::from("dir/y"), second),
+                        (PathBuf::from("x"), first)]);
 
-impl NodeLookup<ObjectKey, ObjectWalkNode> for ObjectStore {
-    fn lookup_node(&self, handle: ObjectKey) -> Result<ObjectWalkNode> {
-        let opened = self.open_object(&handle)?;
-        let object_type = opened.header().object_type;
-        let file_size;
-        match opened {
-            ObjectHandle::Blob(_) => file_size = opened.header().content_size,
-            ObjectHandle::ChunkedBlob(raw) => {
-                let chunked = raw.read_content()?;
-                file_size = chunked.total_size
-            }
-            _ => file_size = 0,
-        }
-        Ok(ObjectWalkNode {
-            hash: handle,
-            object_type: object_type,
-            file_size: file_size,
-        })
+        let files = store.history_files(&[second])
+            .map(|file| file.map(|f| (f.commit, f.path)))
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(files,
+                   vec![(second, PathBuf::from("a.jpg")),
+                        (second, PathBuf::from("other")),
+                        (second, PathBuf::from("dir/copy.jpg")),
+                        (second, PathBuf::from("dir/y")),
+                        (first, PathBuf::from("a.jpg")),
+                        (first, PathBuf::from("other")),
+                        (first, PathBuf::from("x"))]);
     }
-}
 
-impl NodeReader<ObjectWalkNode> for ObjectStore {
-    fn read_children(&self,
-                     node: &ObjectWalkNode)
-                     -> Result<ChildMap<ObjectWalkNode>> {
-        let mut children = BTreeMap::new();
-        for (name, hash) in self.open_tree(&node.hash)? {
-            let name = name.into_string()
-                .map_err(|e| format!("Bad UTF-8 in name: {:?}", e))?;
-            let node = self.lookup_node(hash.clone())?;
-            children.insert(name, node);
+    #[test]
+    fn test_repack_fan_out() {
+        let (_tempdir, mut store) = create_temp_repository().unwrap();
+        let objects = store.path.join("objects");
+        let keys: Vec<ObjectKey> = (0..20)
+            .map(|i| {
+                store.store_object(&Blob::from(i.to_string().as_str()))
+                    .unwrap()
+            })
+            .collect();
+        let short = |key: &ObjectKey| key.to_hex()[..8].parse().unwrap();
+
+        let fan_out = FanOut::new(2, 2).unwrap();
+        assert_eq!(store.repack(Some(fan_out)).unwrap(), keys.len());
+        assert_eq!(store.fan_out(), fan_out);
+        for key in &keys {
+            let hex = key.to_hex();
+            assert!(objects.join(&hex[0..2]).join(&hex[2..4]).join(&hex[4..])
+                        .is_file(),
+                    "{} should be in the new layout",
+                    hex);
+            assert!(store.has_object(key));
+            store.open_object(key).unwrap();
+            assert_eq!(store.lookup(&short(key)).unwrap().0, *key);
         }
-        Ok(children)
-    }
-}
 
+        // Reopened store reads the layout from its format file
+        let reopened = ObjectStore::open(store.path.clone()).unwrap();
+        assert_eq!(reopened.fan_out(), fan_out);
+        assert_eq!(reopened.fsck(false, &[]).unwrap().corrupt, vec![]);
+        drop(reopened);
 
-impl NodeLookup<ObjectKey, ComparableNode> for ObjectStore {
-    fn lookup_node(&self, handle: ObjectKey) -> Result<ComparableNode> {
-        let node = <Self as NodeLookup<ObjectKey,ObjectWalkNode>>
-                    ::lookup_node(&self, handle)?;
-        Ok(node.into())
+        // Interrupted repack: only some objects have moved
+        let old = fan_out;
+        let fan_out = FanOut::new(1, 3).unwrap();
+        store.format.migrating_from = Some(old);
+        store.format.fan_out = fan_out;
+        for key in &keys[..5] {
+            let dest = store.object_path_in(fan_out, &key.to_hex());
+            fsutil::create_parents(&dest).unwrap();
+            fs::rename(store.object_path_in(old, &key.to_hex()), dest)
+                .unwrap();
+        }
+        for key in &keys {
+            assert!(store.has_object(key));
+            assert_eq!(store.lookup(&short(key)).unwrap().0, *key);
+        }
+        assert_eq!(store.repack(None).unwrap(), keys.len() - 5);
+        assert_eq!(store.format.migrating_from, None);
+        for key in &keys {
+            let hex = key.to_hex();
+            assert!(objects.join(&hex[0..3]).join(&hex[3..]).is_file());
+        }
+        assert!(!objects.join(&keys[0].to_hex()[0..2]).exists(),
+                "Old directories should be removed");
     }
-}
 
-impl NodeReader<ComparableNode> for ObjectStore {
-    fn read_children(&self,
-                     node: &ComparableNode)
-                     -> Result<ChildMap<ComparableNode>> {
-        let mut children = BTreeMap::new();
-        for (name, hash) in
-            self.open_tree(&node.hash.expect("Object should have hash"))? {
-            let name = name.into_string()
-                .map_err(|e| format!("Bad UTF-8 in name: {:?}", e))?;
-            let node = self.lookup_node(hash.clone())?;
-            children.insert(name, node);
+    #[test]
+    fn test_filter_commits() {
+        let (_tempdir, store) = create_temp_repository().unwrap();
+        let blob = |s: &str| store.store_object(&Blob::from(s)).unwrap();
+        let (a1, a2, b1) = (blob("a1"), blob("a2"), blob("b1"));
+        let dir = |a: ObjectKey| {
+            store.store_object(&tree_object!{ "a" => a, }).unwrap()
+        };
+        let trees = vec![tree_object!{ "b" => b1, },
+                         tree_object!{ "b" => b1, "dir" => dir(a1), },
+                         tree_object!{ "b" => b1, "dir" => dir(a2), },
+                         tree_object!{ "b" => a1, "dir" => dir(a2), }];
+        let mut commits = Vec::new();
+        let mut parents = vec![];
+        for (i, tree) in trees.into_iter().enumerate() {
+            let commit = Commit {
+                tree: store.store_object(&tree).unwrap(),
+                parents: parents,
+                message: format!("{}", i),
+                // The first commit predates recorded times
+                time: match i {
+                    0 => None,
+                    _ => Some(1000 * i as u64),
+                },
+                signature: None,
+            };
+            let hash = store.store_object(&commit).unwrap();
+            parents = vec![hash];
+            commits.push((hash, commit));
         }
-        Ok(children)
+        let messages = |filter: CommitFilter| {
+            store.filter_commits(commits.clone(), &filter)
+                .unwrap()
+                .into_iter()
+                .map(|(_, commit)| commit.message)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(messages(CommitFilter::default()), vec!["0", "1", "2", "3"]);
+        assert_eq!(messages(CommitFilter {
+                       path: Some(PathBuf::from("dir")),
+                       max_count: None,
+                       ..CommitFilter::default()
+                   }),
+                   vec!["1", "2"]);
+        assert_eq!(messages(CommitFilter {
+                       path: Some(PathBuf::from("b")),
+                       max_count: None,
+                       ..CommitFilter::default()
+                   }),
+                   vec!["0", "3"]);
+        assert_eq!(messages(CommitFilter {
+                       path: Some(PathBuf::from("dir")),
+                       max_count: Some(1),
+                       ..CommitFilter::default()
+                   }),
+                   vec!["2"],
+                   "Should keep the newest matching commits");
+        assert_eq!(messages(CommitFilter {
+                       since: Some(2000),
+                       ..CommitFilter::default()
+                   }),
+                   vec!["2", "3"]);
+        assert_eq!(messages(CommitFilter {
+                       until: Some(1000),
+                       ..CommitFilter::default()
+                   }),
+                   vec!["1"],
+                   "Commits without a time should not match a time range");
+        assert_eq!(messages(CommitFilter {
+                       since: Some(1000),
+                       until: Some(2000),
+                       max_count: Some(1),
+                       ..CommitFilter::default()
+                   }),
+                   vec!["2"]);
     }
-}
 
+    #[test]
+    fn test_diff_trees() {
+        let (_tempdir, store) = create_temp_repository().unwrap();
+        let blob = |s: &str| store.store_object(&Blob::from(s)).unwrap();
+        let (a1, a2, b1) = (blob("a1"), blob("a2"), blob("b1"));
+        let same = store.store_object(&tree_object!{ "a" => a1, }).unwrap();
+        let dir = store.store_object(&tree_object!{ "b" => b1, }).unwrap();
+        let before = store.store_object(&tree_object!{
+                "a" => a1,
+                "c" => b1,
+                "same" => same,
+                "x" => dir,
+            })
+            .unwrap();
+        let after = store.store_object(&tree_object!{
+                "a" => a2,
+                "c" => dir,
+                "same" => same,
+                "z" => b1,
+            })
+            .unwrap();
+        let node = |key| -> ObjectWalkNode { store.lookup_node(key).unwrap() };
+        let path = PathBuf::from;
 
-pub struct DepthFirstCommitSort<'a> {
-    object_store: &'a ObjectStore,
-    unvisited: Vec<ObjectKey>,
-    visited: HashSet<ObjectKey>,
-    sorted: Vec<(ObjectKey, Commit)>,
-}
-impl<'a> DepthFirstCommitSort<'a> {
-    pub fn new(object_store: &'a ObjectStore,
-               unvisited: Vec<ObjectKey>)
-               -> Self {
-        DepthFirstCommitSort {
-            object_store: object_store,
-            unvisited: unvisited,
-            visited: HashSet::new(),
-            sorted: Vec::new(),
-        }
+        let deltas = store.diff_trees(Some(before), Some(after))
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(deltas,
+                   vec![TreeDelta::Modified(path("a"), node(a1), node(a2)),
+                        TreeDelta::Deleted(path("c"), node(b1)),
+                        TreeDelta::Added(path("c/b"), node(b1)),
+                        TreeDelta::Deleted(path("x/b"), node(b1)),
+                        TreeDelta::Added(path("z"), node(b1))]);
+
+        let paths = store.diff_trees(None, Some(after))
+            .map(|delta| delta.unwrap().path().to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(paths,
+                   vec![path("a"), path("c/b"), path("same/a"), path("z")]);
+        assert_eq!(store.diff_trees(Some(after), Some(after)).count(), 0);
     }
 
-    pub fn run(mut self) -> Result<Vec<(ObjectKey, Commit)>> {
-        debug!("Sorting commits, starting with: {:?}", self.unvisited);
-        for hash in &self.unvisited.clone() {
-            self.visit(&hash)?;
+    #[test]
+    fn test_commit_change_stats() {
+        let (_tempdir, store) = create_temp_repository().unwrap();
+        let blob = |s: &str| store.store_object(&Blob::from(s)).unwrap();
+        let (a1, a2, b1) = (blob("a1"), blob("a2...."), blob("b1"));
+        let dir = store.store_object(&tree_object!{ "a" => a1, "b" => b1, })
+            .unwrap();
+        let trees = vec![tree_object!{ "a" => a1, "dir" => dir, },
+                         tree_object!{ "a" => a2, "b" => b1, },
+                         tree_object!{ "a" => a2, "b" => b1, }];
+        let mut stats = Vec::new();
+        let mut parents = vec![];
+        for (i, tree) in trees.into_iter().enumerate() {
+            let hash = store.store_object(&Commit {
+                    tree: store.store_object(&tree).unwrap(),
+                    parents: parents,
+                    message: format!("{}", i),
+                    time: None,
+                    signature: None,
+                })
+                .unwrap();
+            parents = vec![hash];
+            stats.push(store.commit_change_stats(&hash).unwrap());
         }
-        Ok(self.sorted)
+        let change = |added, modified, deleted, byte_delta| {
+            ChangeStats {
+                added: added,
+                modified: modified,
+                deleted: deleted,
+                byte_delta: byte_delta,
+            }
+        };
+        assert_eq!(stats,
+                   vec![change(3, 0, 0, 6),
+                        change(1, 1, 2, 2),
+                        change(0, 0, 0, 0)]);
+        assert_eq!(store.change_stats.lock().unwrap().len(), 3);
+
+        // As if the parent was left out by a shallow fetch
+        let cut = store.store_object(&Commit {
+                tree: store.store_object(&tree_object!{ "a" => a1, })
+                    .unwrap(),
+                parents: vec![Blob::from("gone").calculate_hash()],
+                message: "Cut".to_owned(),
+                time: None,
+                signature: None,
+            })
+            .unwrap();
+        assert_eq!(store.commit_change_stats(&cut).unwrap(),
+                   change(1, 0, 0, 2));
+        assert_eq!(store.change_stats.lock().unwrap().len(),
+                   3,
+                   "Stats against a missing parent should not be cached");
     }
 
-    fn visit(&mut self, hash: &ObjectKey) -> Result<()> {
-        if self.visited.contains(hash) {
-            trace!("Already visited: {}", hash);
-            return Ok(());
-        }
-        self.visited.insert(*hash);
-        let commit = self.object_store.open_commit(hash)?;
-        trace!("Visiting {} {}, parents: {:?}",
-               hash,
-               commit.message,
-               commit.parents);
-        for parent in &commit.parents {
-            self.visit(parent)?;
-        }
-        trace!("Pushing  {} {}", hash, commit.message);
-        self.sorted.push((*hash, commit));
-        Ok(())
+    #[test]
+    fn test_object_counts() {
+        let (tempdir, store) = create_temp_repository().unwrap();
+        assert!(store.object_counts().complete);
+        let a = store.store_object(&Blob::from("aaaa")).unwrap();
+        store.store_object(&Blob::from("aaaa")).unwrap();
+        let b = store.store_object(&Blob::from("bb")).unwrap();
+        let tree = store.store_object(&tree_object!{ "a" => a, "b" => b, })
+            .unwrap();
+        let raw = store.read_object_file_bytes(&tree).unwrap();
+
+        let counts = store.object_counts();
+        let blobs = counts.by_type[&ObjectType::Blob];
+        assert_eq!((blobs.objects, blobs.content_bytes), (2, 6));
+        assert_eq!(counts.total().objects, 3);
+        assert_eq!(counts.duplicate_bytes, 4);
+        assert_eq!(store.recount_objects().unwrap(), counts);
+
+        // Counts persist, and follow objects copied in raw
+        drop(store);
+        let store = ObjectStore::open(tempdir.path().join("object_store"))
+            .unwrap();
+        assert_eq!(store.object_counts(), counts);
+        let (_tempdir, other) = create_temp_repository().unwrap();
+        other.store_raw_object(&tree, &mut raw.as_slice()).unwrap();
+        assert_eq!(other.object_counts().by_type[&ObjectType::Tree],
+                   counts.by_type[&ObjectType::Tree]);
+
+        // Removing an object leaves the totals to be recounted
+        store.quarantine_object(&b).unwrap();
+        assert!(!store.object_counts().complete);
+        let recounted = store.recount_objects().unwrap();
+        assert!(recounted.complete);
+        assert_eq!(recounted.by_type[&ObjectType::Blob].objects, 1);
     }
-}
 
+    #[test]
+    fn test_tree_memo() {
+        let (_tempdir, store) = create_temp_repository().unwrap();
+        let blob = store.store_object(&Blob::from("content")).unwrap();
+        let subtree = store.store_object(&tree_object!{ "file" => blob, })
+            .unwrap();
+        let tree = tree_object!{ "a" => subtree, "b" => subtree, };
+        let tree = store.store_object(&tree).unwrap();
+        let node: ObjectWalkNode = store.lookup_node(tree).unwrap();
+        let children = store.read_children(&node).unwrap();
+        assert_eq!(children["a"], children["b"]);
+        store.read_children(&children["a"]).unwrap();
 
-/// A wrapper to Display a LsTree, with options
-pub struct TreeDisplay<'a> {
-    object_store: &'a ObjectStore,
-    node: ObjectWalkNode,
-    verbose: bool,
-}
-impl<'a> fmt::Display for TreeDisplay<'a> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let mut op = TreeDisplayOp {
-            formatter: f,
-            verbose: self.verbose,
-        };
-        match self.object_store.walk_node(&mut op, self.node) {
-            Ok(_) => Ok(()),
-            Err(_) => Err(fmt::Error),
-        }
+        // The subtree is the same under either name, so it is not read again
+        fs::remove_file(store.object_path(&blob)).unwrap();
+        let grandchildren = store.read_children(&children["b"]).unwrap();
+        assert_eq!(grandchildren["file"].hash, blob);
+        let node: ComparableNode = store.lookup_node(subtree).unwrap();
+        let children = store.read_children(&node).unwrap();
+        assert_eq!(children["file"].file_size, 7);
     }
-}
 
+    #[test]
+    fn test_count_ahead_behind() {
+        let (_tempdir, mut store) = create_temp_repository().unwrap();
+        let tree = store.store_object(&Tree::new()).unwrap();
+        let commit = |message: &str, parents: Vec<ObjectKey>| {
+            store.store_object(&Commit {
+                    tree: tree,
+                    parents: parents,
+                    message: message.to_owned(),
+                    time: None,
+                    signature: None,
+                })
+                .unwrap()
+        };
+        // a - b - c - d (mine)
+        //      \
+        //       e - f (theirs)
+        let a = commit("a", vec![]);
+        let b = commit("b", vec![a]);
+        let c = commit("c", vec![b]);
+        let d = commit("d", vec![c]);
+        let e = commit("e", vec![b]);
+        let f = commit("f", vec![e]);
 
-/// An operation that walks an Object Tree to Display it
-struct TreeDisplayOp<'s, 'f: 's> {
-    formatter: &'s mut fmt::Formatter<'f>,
-    verbose: bool,
-}
-impl<'a, 'b> WalkOp<ObjectWalkNode> for TreeDisplayOp<'a, 'b> {
-    type VisitResult = ();
+        assert_eq!(store.count_ahead_behind(&d, &d).unwrap(), (0, 0));
+        assert_eq!(store.count_ahead_behind(&d, &b).unwrap(), (2, 0));
+        assert_eq!(store.count_ahead_behind(&b, &d).unwrap(), (0, 2));
+        assert_eq!(store.count_ahead_behind(&d, &f).unwrap(), (2, 2));
 
-    fn should_descend(&mut self, _ps: &Path, node: &ObjectWalkNode) -> bool {
-        node.object_type.is_treeish()
+        store.update_remote("backup".to_owned(), Remote::new("/b".to_owned()))
+            .unwrap();
+        store.update_remote("origin".to_owned(), Remote::new("/o".to_owned()))
+            .unwrap();
+        assert_eq!(store.upstream("master"), None);
+        store.update_ref("remotes/backup/master".to_owned(), e).unwrap();
+        assert_eq!(store.upstream("master"),
+                   Some(("backup/master".to_owned(), e)));
+        store.update_ref("remotes/origin/master".to_owned(), f).unwrap();
+        assert_eq!(store.upstream("master"),
+                   Some(("origin/master".to_owned(), f)),
+                   "Should prefer origin");
+        let mut backup = Remote::new("/b".to_owned());
+        backup.branch = Some("master".to_owned());
+        store.update_remote("backup".to_owned(), backup).unwrap();
+        assert_eq!(store.upstream("master"),
+                   Some(("backup/master".to_owned(), e)),
+                   "Should prefer a remote with this as its default branch");
     }
 
-    fn no_descend(&mut self,
-                  ps: &Path,
-                  node: ObjectWalkNode)
-                  -> Result<Option<Self::VisitResult>> {
-        if self.verbose {
-            writeln!(self.formatter,
-                     "{} {} {}",
-                     node.hash,
-                     node.object_type.code(),
-                     ps.display())?;
-        } else {
-            writeln!(self.formatter, "{}", ps.display())?;
-        }
-        Ok(None)
-    }
-}
+    #[test]
+    fn test_refs_containing() {
+        let (_tempdir, mut store) = create_temp_repository().unwrap();
+        let tree = store.store_object(&Tree::new()).unwrap();
+        let commit = |message: &str, parents: Vec<ObjectKey>| {
+            store.store_object(&Commit {
+                    tree: tree,
+                    parents: parents,
+                    message: message.to_owned(),
+                    time: None,
+                    signature: None,
+                })
+                .unwrap()
+        };
+        // a - b - c (master)
+        //      \
+        //       d (topic)
+        let a = commit("a", vec![]);
+        let b = commit("b", vec![a]);
+        let c = commit("c", vec![b]);
+        let d = commit("d", vec![b]);
+        store.update_ref("master".to_owned(), c).unwrap();
+        store.update_ref("topic".to_owned(), d).unwrap();
+        store.update_ref("stash/0".to_owned(), d).unwrap();
 
+        let all = |_: &str| true;
+        assert_eq!(store.refs_containing(&a, all).unwrap(),
+                   vec!["master", "stash/0", "topic"]);
+        assert_eq!(store.refs_containing(&c, all).unwrap(), vec!["master"]);
+        let not_stash = |name: &str| !name.starts_with("stash/");
+        assert_eq!(store.refs_containing(&d, not_stash).unwrap(),
+                   vec!["topic"]);
 
-#[cfg(test)]
-pub mod test {
-    use dag::Blob;
-    use dag::Object;
-    use dag::ToHashed;
-    use super::*;
-    use testutil::tempdir::TempDir;
+        let stored = store.stored_time(&c).unwrap();
+        let age = SystemTime::now().duration_since(stored).unwrap();
+        assert!(age < Duration::from_secs(60), "stored {:?} ago", age);
+    }
 
-    pub fn create_temp_repository() -> Result<(TempDir, ObjectStore)> {
-        let wd_temp = in_mem_tempdir!();
-        let os_path = wd_temp.path().join("object_store");
-        let os = try!(ObjectStore::init(os_path));
+    #[test]
+    fn test_lookup_ancestry() {
+        let (_tempdir, mut store) = create_temp_repository().unwrap();
+        let tree = store.store_object(&Tree::new()).unwrap();
+        let commit = |message: &str, parents: Vec<ObjectKey>| {
+            store.store_object(&Commit {
+                    tree: tree,
+                    parents: parents,
+                    message: message.to_owned(),
+                    time: None,
+                    signature: None,
+                })
+                .unwrap()
+        };
+        // a - b - m (master)
+        //   \    /
+        //     c
+        let a = commit("a", vec![]);
+        let b = commit("b", vec![a]);
+        let c = commit("c", vec![a]);
+        let m = commit("m", vec![b, c]);
+        store.update_ref("master".to_owned(), m).unwrap();
 
-        Ok((wd_temp, os))
+        let lookup = |rev: &str| store.lookup(&rev.parse().unwrap());
+        assert_eq!(lookup("master^0").unwrap(), (m, m, None));
+        assert_eq!(lookup("master^").unwrap().0, b);
+        assert_eq!(lookup("master^2").unwrap().0, c);
+        assert_eq!(lookup("master~2").unwrap().0, a);
+        assert_eq!(lookup("master^2~1").unwrap().0, a);
+        assert!(lookup("master^3").is_err());
+        assert!(lookup("master~3").is_err());
+
+        let range = "master^2..master".parse().unwrap();
+        let (to, excluded) = store.lookup_range(&range).unwrap();
+        assert_eq!(to, m);
+        assert_eq!(excluded, [a, c].iter().cloned().collect());
+        assert_eq!(store.find_common_ancestor(&["master^", "master^2"])
+                       .unwrap(),
+                   Some(a));
     }
 
     #[test]
-    fn test_store_and_retrieve() {
+    fn test_lowest_common_ancestors() {
         let (_tempdir, store) = create_temp_repository().unwrap();
+        let tree = store.store_object(&Tree::new()).unwrap();
+        let commit = |message: &str, parents: Vec<ObjectKey>| {
+            store.store_object(&Commit {
+                    tree: tree,
+                    parents: parents,
+                    message: message.to_owned(),
+                    time: None,
+                    signature: None,
+                })
+                .unwrap()
+        };
+        // a - b - c - d
+        //      \   \
+        //       e   f
+        let a = commit("a", vec![]);
+        let b = commit("b", vec![a]);
+        let c = commit("c", vec![b]);
+        let d = commit("d", vec![c]);
+        let e = commit("e", vec![b]);
+        let f = commit("f", vec![c]);
 
-        let obj = Blob::from("Hello!").to_hashed();
+        assert_eq!(store.lowest_common_ancestors(&[d, f]).unwrap(), vec![c]);
+        assert_eq!(store.lowest_common_ancestors(&[d, e, f]).unwrap(),
+                   vec![b]);
+        assert_eq!(store.lowest_common_ancestors(&[d, c]).unwrap(), vec![c]);
+        assert_eq!(store.lowest_common_ancestors(&[e]).unwrap(), vec![e]);
+        assert!(store.lowest_common_ancestors(&[]).unwrap().is_empty());
 
-        assert!(!store.has_object(obj.hash()),
-                "Store should not have key at first");
+        // Criss-cross: x and y each merge both of p and q
+        let p = commit("p", vec![a]);
+        let q = commit("q", vec![a]);
+        let x = commit("x", vec![p, q]);
+        let y = commit("y", vec![q, p]);
+        let lowest = store.lowest_common_ancestors(&[x, y]).unwrap();
+        assert_eq!(lowest, vec![p, q]);
+    }
 
-        let stored_key = store.store_object(&obj).unwrap();
-        assert_eq!(stored_key,
-                   *obj.hash(),
-                   "Key when stored should be the same as given by \
-                    calculate_hash");
-        assert!(store.has_object(&stored_key),
-                "Store should report that key is present");
+    #[test]
+    fn test_remove_remote() {
+        let (_tempdir, mut store) = create_temp_repository().unwrap();
+        let hash = Blob::from("Hello!").calculate_hash();
+        store.update_remote("origin".to_owned(), Remote::new("/a".to_owned()))
+            .unwrap();
+        store.update_remote("origin2".to_owned(), Remote::new("/b".to_owned()))
+            .unwrap();
+        for name in &["master", "remotes/origin/master", "remotes/origin2/x"] {
+            store.update_ref(name.to_string(), hash).unwrap();
+        }
+        store.record_locations("origin", vec![hash]).unwrap();
 
-        let mut reader = store.open_object_file(&stored_key).unwrap();
-        let retrieved = Object::read_from(&mut reader).unwrap();
-        assert_eq!(retrieved,
-                   *obj,
-                   "Retrieved object should be the same as stored object");
+        let removed = store.remove_remote("origin").unwrap();
+        assert_eq!(removed.url, "/a");
+        assert_eq!(store.remotes().keys().collect::<Vec<_>>(), vec!["origin2"]);
+        assert_eq!(store.refs().keys().collect::<Vec<_>>(),
+                   vec!["master", "remotes/origin2/x"],
+                   "Only the removed remote's tracking refs should go");
+        assert!(store.locations().remotes_for(&hash).is_empty());
+        assert!(store.remove_remote("origin").is_err());
     }
 
-
     #[test]
-    fn test_update_and_read_ref() {
+    fn test_wait_for_ref() {
         let (_tempdir, mut store) = create_temp_repository().unwrap();
-        let hash = Blob::from("Hello!").to_hashed().hash().to_owned();
+        let hash = store.store_object(&Blob::from("one")).unwrap();
+        store.update_ref("master".to_owned(), hash).unwrap();
+        assert_eq!(store.wait_for_ref("master", None).unwrap(),
+                   Some(hash),
+                   "Should return at once if the ref already differs");
 
-        let result = store.try_find_ref("master");
-        assert_match!(result, None);
+        let path = store.path().to_owned();
+        let mover = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            let mut other = ObjectStore::open(path).unwrap();
+            let next = other.store_object(&Blob::from("two")).unwrap();
+            other.update_ref("master".to_owned(), next).unwrap();
+            next
+        });
+        let moved = store.wait_for_ref("master", Some(hash)).unwrap();
+        assert_eq!(moved, Some(mover.join().unwrap()));
+    }
 
-        let result = store.update_ref("master".to_string(), hash);
-        assert_match!(result, Ok(()));
+    #[test]
+    fn test_memory_storage() {
+        let temp = in_mem_tempdir!();
+        let storage = MemoryStorage::new();
+        let store = ObjectStore::init_with_storage(temp.path().join("store"),
+                                                   RepoConfig::default(),
+                                                   Box::new(storage.clone()))
+            .unwrap();
+        let blob = store.store_object(&Blob::from("in memory")).unwrap();
+        let mut tree_obj = Tree::new();
+        tree_obj.insert("file", blob);
+        let tree = store.store_object(&tree_obj).unwrap();
 
-        let result = store.try_find_ref("master");
-        assert_match!(result, Some(x) if x==hash);
+        assert!(store.has_object(&blob));
+        assert_eq!(store.open_tree(&tree).unwrap(), tree_obj);
+        let mut expected = vec![blob, tree];
+        expected.sort();
+        let mut listed = store.list_objects().unwrap();
+        listed.sort();
+        assert_eq!(listed, expected);
+        let short: RevSpec = blob.to_short().parse().unwrap();
+        assert_eq!(store.lookup(&short).unwrap().0, blob);
+
+        assert_eq!(storage.list("objects").unwrap().len(), 2);
+        assert!(!store.path().join("objects").exists(),
+                "Objects should not be written to disk");
+    }
+
+    #[test]
+    fn test_store_in_memory() {
+        let mut store = ObjectStore::in_memory(RepoConfig::default()).unwrap();
+        assert!(store.is_in_memory());
+        let blob = store.store_object(&Blob::from("in memory")).unwrap();
+        let mut tree_obj = Tree::new();
+        tree_obj.insert("file", blob);
+        let tree = store.store_object(&tree_obj).unwrap();
+        let commit = store.store_object(&Commit {
+                tree: tree,
+                parents: vec![],
+                message: "In memory".to_owned(),
+                time: None,
+                signature: None,
+            })
+            .unwrap();
+        assert_eq!(store.storage().list("objects").unwrap().len(), 3);
+        let counts = store.object_counts();
+        assert!(counts.complete);
+        assert_eq!(counts.total().objects, 3);
+
+        let lock = store.lock(LockMode::Exclusive, "test").unwrap();
+        store.update_ref("master".to_owned(), commit).unwrap();
+        drop(lock);
+        store.record_reflog("master", None, commit, "commit").unwrap();
+        assert_eq!(store.reflog().entries("master").len(), 1);
+        let rev: RevSpec = "master:file".parse().unwrap();
+        assert_eq!(store.lookup(&rev).unwrap().0, blob);
+        assert!(store.lookup(&"HEAD".parse().unwrap()).is_err(),
+                "A store in memory has no work dir, so no HEAD");
+
+        assert!(store.fsck(false, &[]).unwrap().is_ok());
+        assert_eq!(store.remove_ref("master").unwrap(), Some(commit));
+        assert!(Parity::open(&store).is_err());
+        assert!(store.path().as_os_str().is_empty());
     }
 }
@@ -0,0 +1,441 @@
+//! Parity: Reed-Solomon erasure coding of object files, to repair bit rot
+//!
+//! Objects are covered in groups. Each object file, exactly as it is stored
+//! on disk, is one data shard of its group, padded with zeros to the size of
+//! the largest. From those, a number of parity shards are computed, and any
+//! lost objects up to that number can be rebuilt from the rest of the group
+//! and the parity. Since the shards are the files as stored, the parity of an
+//! encrypted store reveals nothing that the object files do not.
+//!
+//! Parity is kept in the `parity` directory of the store: an index listing
+//! each group's objects, and one file per group with its parity shards. It is
+//! built by `dmv parity build`, which covers objects that are not yet in a
+//! group, and used by `dmv fsck --repair` to rebuild missing and corrupt
+//! objects.
+//!
+//! The code is over GF(2^8), so a group can have at most 256 shards in all.
+//! The encoding matrix is an identity matrix over a Cauchy matrix, any square
+//! submatrix of which can be inverted, so any data shards can be recovered
+//! from any equal number of surviving shards.
+
+use dag::ObjectKey;
+use disk_backed::DiskBacked;
+use error::*;
+use fsutil;
+use object_store::ObjectStore;
+use std::collections::HashSet;
+use std::fs;
+use std::io::Read;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Most shards, data and parity, in one group
+pub const MAX_SHARDS: usize = 256;
+
+lazy_static!{
+    /// Powers of the generator, doubled in length to skip a modulo in `mul`
+    static ref EXP: [u8; 512] = {
+        let mut exp = [0u8; 512];
+        let mut x: u16 = 1;
+        for i in 0..255 {
+            exp[i] = x as u8;
+            exp[i + 255] = x as u8;
+            x <<= 1;
+            if x & 0x100 != 0 {
+                x ^= 0x11d;
+            }
+        }
+        exp
+    };
+
+    static ref LOG: [u8; 256] = {
+        let mut log = [0u8; 256];
+        for i in 0..255 {
+            log[EXP[i] as usize] = i as u8;
+        }
+        log
+    };
+}
+
+fn mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        0
+    } else {
+        EXP[LOG[a as usize] as usize + LOG[b as usize] as usize]
+    }
+}
+
+fn inv(a: u8) -> u8 {
+    assert!(a != 0, "zero has no inverse");
+    EXP[255 - LOG[a as usize] as usize]
+}
+
+/// Invert a square matrix by Gauss-Jordan elimination
+fn invert(mut m: Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>> {
+    let n = m.len();
+    let mut out = (0..n)
+        .map(|i| (0..n).map(|j| if i == j { 1 } else { 0 }).collect())
+        .collect::<Vec<Vec<u8>>>();
+    for col in 0..n {
+        let pivot = match (col..n).find(|&row| m[row][col] != 0) {
+            Some(pivot) => pivot,
+            None => bail!("Parity matrix is singular"),
+        };
+        m.swap(col, pivot);
+        out.swap(col, pivot);
+        let scale = inv(m[col][col]);
+        for j in 0..n {
+            m[col][j] = mul(m[col][j], scale);
+            out[col][j] = mul(out[col][j], scale);
+        }
+        for row in 0..n {
+            let factor = m[row][col];
+            if row == col || factor == 0 {
+                continue;
+            }
+            for j in 0..n {
+                let (m_col, out_col) = (m[col][j], out[col][j]);
+                m[row][j] ^= mul(factor, m_col);
+                out[row][j] ^= mul(factor, out_col);
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// A systematic Reed-Solomon code with a given number of data and parity
+/// shards
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct ReedSolomon {
+    data: usize,
+    parity: usize,
+}
+
+impl ReedSolomon {
+    pub fn new(data: usize, parity: usize) -> Result<Self> {
+        if data == 0 || parity == 0 || data + parity > MAX_SHARDS {
+            bail!("Bad parity group: {} data and {} parity shards. Need at \
+                   least one of each, and no more than {} in all",
+                  data,
+                  parity,
+                  MAX_SHARDS);
+        }
+        Ok(ReedSolomon {
+            data: data,
+            parity: parity,
+        })
+    }
+
+    /// Row of the encoding matrix for a shard
+    fn row(&self, shard: usize) -> Vec<u8> {
+        if shard < self.data {
+            (0..self.data).map(|j| if j == shard { 1 } else { 0 }).collect()
+        } else {
+            // Cauchy: 1 / (x_i + y_j), with x_i and y_j all distinct
+            (0..self.data).map(|j| inv((shard as u8) ^ (j as u8))).collect()
+        }
+    }
+
+    /// Combine shards by the rows of a matrix
+    fn apply(matrix: &[Vec<u8>], shards: &[&[u8]]) -> Vec<Vec<u8>> {
+        let len = shards.first().map_or(0, |s| s.len());
+        matrix.iter()
+            .map(|row| {
+                let mut out = vec![0u8; len];
+                for (&coef, shard) in row.iter().zip(shards) {
+                    if coef == 0 {
+                        continue;
+                    }
+                    for (o, &b) in out.iter_mut().zip(shard.iter()) {
+                        *o ^= mul(coef, b);
+                    }
+                }
+                out
+            })
+            .collect()
+    }
+
+    /// Compute parity shards from data shards, which must all be one size
+    pub fn encode(&self, data: &[&[u8]]) -> Vec<Vec<u8>> {
+        assert_eq!(data.len(), self.data);
+        let rows = (self.data..self.data + self.parity)
+            .map(|shard| self.row(shard))
+            .collect::<Vec<_>>();
+        Self::apply(&rows, data)
+    }
+
+    /// Fill in lost data shards, given all shards with lost ones as None
+    ///
+    /// Lost parity shards are left as None. Fails if fewer shards survive
+    /// than there are data shards.
+    pub fn reconstruct(&self, shards: &mut [Option<Vec<u8>>]) -> Result<()> {
+        assert_eq!(shards.len(), self.data + self.parity);
+        if shards[..self.data].iter().all(|s| s.is_some()) {
+            return Ok(());
+        }
+        let survivors = (0..shards.len())
+            .filter(|&i| shards[i].is_some())
+            .take(self.data)
+            .collect::<Vec<_>>();
+        if survivors.len() < self.data {
+            bail!("Too many shards lost: {} of {} survive, and {} are needed",
+                  shards.iter().filter(|s| s.is_some()).count(),
+                  shards.len(),
+                  self.data);
+        }
+        let decode = invert(survivors.iter().map(|&i| self.row(i)).collect())?;
+        let recovered = {
+            let inputs = survivors.iter()
+                .map(|&i| shards[i].as_ref().expect("survivor").as_slice())
+                .collect::<Vec<_>>();
+            Self::apply(&decode, &inputs)
+        };
+        for (i, shard) in recovered.into_iter().enumerate() {
+            if shards[i].is_none() {
+                shards[i] = Some(shard);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Objects covered together by one set of parity shards
+#[derive(Debug,Clone,PartialEq,Eq,Hash,RustcEncodable,RustcDecodable)]
+pub struct ParityGroup {
+    /// Objects in shard order, with the sizes of their files
+    pub objects: Vec<(ObjectKey, u64)>,
+    /// Number of parity shards
+    pub parity: usize,
+}
+
+impl ParityGroup {
+    /// Size of each shard: the largest object file
+    pub fn shard_size(&self) -> u64 {
+        self.objects.iter().map(|&(_, size)| size).max().unwrap_or(0)
+    }
+
+    fn code(&self) -> Result<ReedSolomon> {
+        ReedSolomon::new(self.objects.len(), self.parity)
+    }
+}
+
+/// Counts from `Parity::build`
+#[derive(Debug,Clone,Copy,Default,PartialEq,Eq)]
+pub struct ParityBuildStats {
+    pub groups: usize,
+    pub objects: usize,
+    /// Bytes of parity written
+    pub bytes: u64,
+}
+
+/// The parity index and shard files of an object store
+pub struct Parity {
+    path: PathBuf,
+    groups: DiskBacked<Vec<ParityGroup>>,
+}
+
+impl Parity {
+    pub fn open(store: &ObjectStore) -> Result<Self> {
+        if store.is_in_memory() {
+            bail!("Parity is not kept for a store in memory");
+        }
+        let path = store.path().join("parity");
+        Ok(Parity {
+            groups: DiskBacked::read_or_default("parity index",
+                                                path.join("index"))?,
+            path: path,
+        })
+    }
+
+    pub fn groups(&self) -> &[ParityGroup] { &self.groups }
+
+    fn shard_file(&self, group: usize) -> PathBuf {
+        self.path.join(group.to_string())
+    }
+
+    /// Cover objects that are not yet in a group
+    ///
+    /// Objects are grouped by size, so that little space goes to padding.
+    /// The index is saved after each group, so an interrupted build keeps
+    /// the groups it finished.
+    pub fn build(&mut self,
+                 store: &ObjectStore,
+                 group_size: usize,
+                 parity: usize)
+                 -> Result<ParityBuildStats> {
+        ReedSolomon::new(group_size, parity)?;
+        let covered = self.groups
+            .iter()
+            .flat_map(|group| group.objects.iter().map(|&(key, _)| key))
+            .collect::<HashSet<_>>();
+        let mut objects = Vec::new();
+        for key in store.list_objects()? {
+            if !covered.contains(&key) {
+                objects.push((key, store.object_file_size(&key)?));
+            }
+        }
+        objects.sort_by_key(|&(key, size)| (size, key));
+
+        let mut stats = ParityBuildStats::default();
+        for chunk in objects.chunks(group_size) {
+            let group = ParityGroup {
+                objects: chunk.to_vec(),
+                parity: parity,
+            };
+            let shard_size = group.shard_size() as usize;
+            let mut data = Vec::new();
+            for &(key, _) in &group.objects {
+                let mut bytes = store.read_object_file_bytes(&key)?;
+                bytes.resize(shard_size, 0);
+                data.push(bytes);
+            }
+            let shards = group.code()?
+                .encode(&data.iter().map(|d| d.as_slice()).collect::<Vec<_>>());
+
+            let file = self.shard_file(self.groups.len());
+            fsutil::create_parents(&file)?;
+            let mut out = fs::File::create(&file)?;
+            for shard in &shards {
+                out.write_all(shard)?;
+            }
+            out.sync_all()?;
+
+            stats.groups += 1;
+            stats.objects += group.objects.len();
+            stats.bytes += (shard_size * shards.len()) as u64;
+            self.groups.push(group);
+            self.groups.flush()?;
+        }
+        Ok(stats)
+    }
+
+    /// Rebuild an object file from the rest of its group and the parity
+    ///
+    /// Returns the file's bytes as they were stored, or None if no group
+    /// covers the object. Other objects of the group that are missing or
+    /// corrupt are lost shards too, as is a damaged parity file, so this
+    /// fails if the group has lost more shards than it has parity.
+    pub fn reconstruct(&self,
+                       store: &ObjectStore,
+                       key: &ObjectKey)
+                       -> Result<Option<Vec<u8>>> {
+        let (index, group) = match self.groups
+            .iter()
+            .enumerate()
+            .find(|&(_, g)| g.objects.iter().any(|&(k, _)| k == *key)) {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        let shard_size = group.shard_size() as usize;
+
+        let mut shards = Vec::new();
+        let mut position = 0;
+        for (i, &(other, _)) in group.objects.iter().enumerate() {
+            if other == *key {
+                position = i;
+                shards.push(None);
+            } else if store.has_object(&other) &&
+                      store.verify_object(&other).is_ok() {
+                let mut bytes = store.read_object_file_bytes(&other)?;
+                bytes.resize(shard_size, 0);
+                shards.push(Some(bytes));
+            } else {
+                debug!("Parity group {}: also lost {}", index, other);
+                shards.push(None);
+            }
+        }
+
+        let mut parity_bytes = Vec::new();
+        match fs::File::open(self.shard_file(index)) {
+            Ok(mut file) => {
+                file.read_to_end(&mut parity_bytes)?;
+            }
+            Err(e) => warn!("Could not read parity group {}: {}", index, e),
+        }
+        if shard_size > 0 && parity_bytes.len() == shard_size * group.parity {
+            shards.extend(parity_bytes.chunks(shard_size)
+                .map(|chunk| Some(chunk.to_vec())));
+        } else {
+            shards.extend((0..group.parity).map(|_| None));
+        }
+
+        group.code()?
+            .reconstruct(&mut shards)
+            .chain_err(|| {
+                format!("Could not rebuild {} from parity group {}", key, index)
+            })?;
+        let mut bytes = shards[position].take().expect("reconstructed");
+        bytes.truncate(group.objects[position].1 as usize);
+        Ok(Some(bytes))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use dag::Blob;
+    use dag::ObjectCommon;
+    use object_store::test::create_temp_repository;
+    use super::*;
+
+    #[test]
+    fn test_reed_solomon() {
+        let code = ReedSolomon::new(4, 2).unwrap();
+        let data = vec![b"abcd".to_vec(),
+                        b"efgh".to_vec(),
+                        b"ijkl".to_vec(),
+                        b"\0\0\xff\x01".to_vec()];
+        let parity = code.encode(&data.iter()
+            .map(|d| d.as_slice())
+            .collect::<Vec<_>>());
+        assert_eq!(parity.len(), 2);
+
+        let all = data.iter().chain(parity.iter()).cloned().collect::<Vec<_>>();
+        for lost in &[vec![0], vec![3], vec![1, 2], vec![0, 5], vec![4, 5]] {
+            let mut shards = all.iter().cloned().map(Some).collect::<Vec<_>>();
+            for &i in lost {
+                shards[i] = None;
+            }
+            code.reconstruct(&mut shards).unwrap();
+            for i in 0..4 {
+                assert_eq!(shards[i].as_ref(),
+                           Some(&data[i]),
+                           "Lost {:?}",
+                           lost);
+            }
+        }
+
+        let mut shards = all.iter().cloned().map(Some).collect::<Vec<_>>();
+        for i in 0..3 {
+            shards[i] = None;
+        }
+        assert!(code.reconstruct(&mut shards).is_err(), "Three lost of two");
+        assert!(ReedSolomon::new(250, 7).is_err());
+    }
+
+    #[test]
+    fn test_parity_reconstruct() {
+        let (_tempdir, store) = create_temp_repository().unwrap();
+        let keys = ["a", "bb", "ccc", "dddd", "eeeee"]
+            .iter()
+            .map(|s| store.store_object(&Blob::from(*s)).unwrap())
+            .collect::<Vec<_>>();
+
+        let mut parity = Parity::open(&store).unwrap();
+        let stats = parity.build(&store, 3, 1).unwrap();
+        assert_eq!((stats.groups, stats.objects), (2, 5));
+        assert_eq!(parity.build(&store, 3, 1).unwrap().objects,
+                   0,
+                   "Already covered");
+
+        let lost = keys[2];
+        let stored = store.read_object_file_bytes(&lost).unwrap();
+        store.quarantine_object(&lost).unwrap();
+        let parity = Parity::open(&store).unwrap();
+        let rebuilt = parity.reconstruct(&store, &lost).unwrap();
+        assert_eq!(rebuilt, Some(stored));
+
+        let uncovered = Blob::from("new").calculate_hash();
+        assert_eq!(parity.reconstruct(&store, &uncovered).unwrap(), None);
+    }
+}
@@ -0,0 +1,210 @@
+//! Limiting commands to some files, by patterns given on the command line
+//!
+//! A pattern with a `/` in it, or without any wildcards, matches paths from
+//! the top of the tree one component at a time, so `photos/2024` matches that
+//! directory and everything in it, and `photos/*/raw` matches the `raw`
+//! directory of each year. A pattern without a `/` but with wildcards, such
+//! as `*.jpg`, matches names at any depth. In either kind, `*` matches any run
+//! of characters within one name, and `?` matches any one character.
+//!
+//! Patterns given on the command line are taken from the current directory,
+//! with `Pathspec::new_in`, so from `photos`, `2024` matches `photos/2024`,
+//! and `*.jpg` matches names at any depth within `photos`.
+//!
+//! A pathspec with no patterns matches everything.
+
+use std::path::Path;
+
+/// A set of patterns, matching a path if any one of them does
+#[derive(Debug,Clone,Default,PartialEq,Eq)]
+pub struct Pathspec {
+    patterns: Vec<Pattern>,
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+enum Pattern {
+    /// Names to match from the top of the tree, in order
+    Anchored(Vec<String>),
+    /// A name to match at any depth below a directory, given by its names
+    Name(Vec<String>, String),
+}
+
+impl Pathspec {
+    /// A pathspec that matches everything
+    pub fn all() -> Self { Pathspec::default() }
+
+    /// Patterns taken from the top of the tree
+    pub fn new<I, S>(patterns: I) -> Self
+        where I: IntoIterator<Item = S>,
+              S: AsRef<str>
+    {
+        Pathspec::new_in(Path::new(""), patterns)
+    }
+
+    /// Patterns taken from a directory, given by its path from the top
+    ///
+    /// A `..` in a pattern goes up from the directory, but not past the top.
+    pub fn new_in<I, S>(dir: &Path, patterns: I) -> Self
+        where I: IntoIterator<Item = S>,
+              S: AsRef<str>
+    {
+        let dir = names(dir);
+        let patterns = patterns.into_iter()
+            .map(|pattern| {
+                let pattern = pattern.as_ref();
+                let has_wildcard = pattern.contains(['*', '?']);
+                if has_wildcard && !pattern.contains('/') {
+                    return Pattern::Name(dir.clone(), pattern.to_owned());
+                }
+                let mut names = dir.clone();
+                for name in pattern.split('/') {
+                    match name {
+                        "" | "." => {}
+                        ".." => {
+                            names.pop();
+                        }
+                        name => names.push(name.to_owned()),
+                    }
+                }
+                Pattern::Anchored(names)
+            })
+            .collect();
+        Pathspec { patterns: patterns }
+    }
+
+    pub fn is_all(&self) -> bool { self.patterns.is_empty() }
+
+    /// Does the path, or a directory containing it, match?
+    pub fn matches(&self, path: &Path) -> bool {
+        if self.is_all() {
+            return true;
+        }
+        let names = names(path);
+        self.patterns.iter().any(|pattern| match *pattern {
+            Pattern::Anchored(ref pattern) => {
+                names.len() >= pattern.len() &&
+                pattern.iter().zip(&names).all(|(p, n)| glob_match(p, n))
+            }
+            Pattern::Name(ref dir, ref pattern) => {
+                names.starts_with(dir) &&
+                names[dir.len()..].iter().any(|name| glob_match(pattern, name))
+            }
+        })
+    }
+
+    /// Could the directory, or anything in it, match?
+    ///
+    /// Walks use this to skip directories that cannot contain a match.
+    pub fn matches_within(&self, dir: &Path) -> bool {
+        if self.is_all() {
+            return true;
+        }
+        let names = names(dir);
+        self.patterns.iter().any(|pattern| match *pattern {
+            Pattern::Anchored(ref pattern) => {
+                pattern.iter().zip(&names).all(|(p, n)| glob_match(p, n))
+            }
+            Pattern::Name(ref pattern_dir, _) => {
+                pattern_dir.iter().zip(&names).all(|(p, n)| p == n)
+            }
+        })
+    }
+}
+
+fn names(path: &Path) -> Vec<String> {
+    path.iter()
+        .map(|name| name.to_string_lossy().into_owned())
+        .filter(|name| name != ".")
+        .collect()
+}
+
+/// Match a name against a pattern with `*` and `?` wildcards
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+    let (mut p, mut n) = (0, 0);
+    // Position after the last `*` seen, and where in the name it matched up to
+    let mut star = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            p += 1;
+            star = Some((p, n));
+        } else if let Some((star_p, star_n)) = star {
+            // Let the last `*` match one more character, and try again
+            p = star_p;
+            n = star_n + 1;
+            star = Some((star_p, n));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*.jpg", "a.jpg"));
+        assert!(glob_match("*.jpg", ".jpg"));
+        assert!(!glob_match("*.jpg", "a.jpeg"));
+        assert!(glob_match("IMG_????.*", "IMG_0001.CR2"));
+        assert!(!glob_match("IMG_????.*", "IMG_001.CR2"));
+        assert!(glob_match("*a*b*", "xxaxxbxx"));
+        assert!(!glob_match("*a*b*", "xxbxxaxx"));
+        assert!(glob_match("exact", "exact"));
+        assert!(!glob_match("exact", "exactly"));
+    }
+
+    #[test]
+    fn test_pathspec() {
+        let all = Pathspec::all();
+        assert!(all.matches(Path::new("anything/at/all")));
+        assert!(all.matches_within(Path::new("")));
+
+        let spec = Pathspec::new(&["photos/2024", "*.jpg"]);
+        assert!(spec.matches(Path::new("photos/2024")));
+        assert!(spec.matches(Path::new("photos/2024/beach.png")));
+        assert!(spec.matches(Path::new("./photos/2024/beach.png")));
+        assert!(spec.matches(Path::new("video/still.jpg")));
+        assert!(!spec.matches(Path::new("photos")));
+        assert!(!spec.matches(Path::new("photos/2023/beach.png")));
+        assert!(!spec.matches(Path::new("notes.txt")));
+
+        let spec = Pathspec::new(&["photos/*/raw/"]);
+        assert!(spec.matches(Path::new("photos/2023/raw/a.cr2")));
+        assert!(!spec.matches(Path::new("photos/2023/edited/a.jpg")));
+        assert!(spec.matches_within(Path::new("")));
+        assert!(spec.matches_within(Path::new("photos")));
+        assert!(spec.matches_within(Path::new("photos/2023")));
+        assert!(spec.matches_within(Path::new("photos/2023/raw/more")));
+        assert!(!spec.matches_within(Path::new("photos/2023/edited")));
+        assert!(!spec.matches_within(Path::new("music")));
+    }
+
+    #[test]
+    fn test_pathspec_in_dir() {
+        let dir = Path::new("photos");
+        let spec = Pathspec::new_in(dir, &["2024", "*.jpg", "../notes.txt"]);
+        assert!(spec.matches(Path::new("photos/2024/beach.png")));
+        assert!(!spec.matches(Path::new("2024/beach.png")));
+        assert!(spec.matches(Path::new("photos/2023/beach.jpg")));
+        assert!(!spec.matches(Path::new("video/still.jpg")));
+        assert!(spec.matches(Path::new("notes.txt")));
+        assert!(spec.matches_within(Path::new("photos/2023")));
+        assert!(!spec.matches_within(Path::new("video")));
+
+        let spec = Pathspec::new_in(dir, &["."]);
+        assert!(spec.matches(Path::new("photos/2023/beach.png")));
+        assert!(!spec.matches(Path::new("notes.txt")));
+        assert!(Pathspec::new_in(dir, &[".."])
+            .matches(Path::new("notes.txt")));
+    }
+}
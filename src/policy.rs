@@ -0,0 +1,128 @@
+//! Limits on how much a commit may add, to catch accidents
+//!
+//! Media repositories are meant to hold large files, but committing a card's
+//! worth of raw footage by mistake is costly to undo once it has been pushed.
+//! A size policy sets a limit on each file and on each commit. By default a
+//! commit over a limit only warns, but a policy can refuse it instead, unless
+//! forced.
+
+use dag::ObjectSize;
+use human_readable::human_bytes;
+use std::fmt;
+use std::path::PathBuf;
+
+/// Size limits for commits, kept in the hidden directory
+#[derive(Debug,Clone,Copy,Default,PartialEq,Eq,Hash)]
+#[derive(RustcEncodable,RustcDecodable)]
+pub struct SizePolicy {
+    /// Largest new or changed file a commit may include
+    pub max_file_size: Option<ObjectSize>,
+    /// Most bytes of new or changed files a commit may include
+    pub max_commit_size: Option<ObjectSize>,
+    /// Refuse commits over a limit, rather than only warning
+    pub refuse: bool,
+}
+
+/// A limit that a commit would go over
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum SizeViolation {
+    /// A file, with its size and the limit
+    LargeFile(PathBuf, ObjectSize, ObjectSize),
+    /// Total size of new or changed files, and the limit
+    LargeCommit(ObjectSize, ObjectSize),
+}
+
+impl SizePolicy {
+    pub fn is_empty(&self) -> bool {
+        self.max_file_size.is_none() && self.max_commit_size.is_none()
+    }
+
+    /// Check the new or changed files of a commit against the limits
+    pub fn check<I>(&self, files: I) -> Vec<SizeViolation>
+        where I: IntoIterator<Item = (PathBuf, ObjectSize)>
+    {
+        let mut violations = Vec::new();
+        let mut total = 0;
+        for (path, size) in files {
+            total += size;
+            match self.max_file_size {
+                Some(max) if size > max => {
+                    violations.push(SizeViolation::LargeFile(path, size, max))
+                }
+                _ => {}
+            }
+        }
+        match self.max_commit_size {
+            Some(max) if total > max => {
+                violations.push(SizeViolation::LargeCommit(total, max))
+            }
+            _ => {}
+        }
+        violations
+    }
+}
+
+impl fmt::Display for SizePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let limit = |max: Option<ObjectSize>| {
+            max.map_or("none".to_owned(), human_bytes)
+        };
+        writeln!(f, "Largest file:   {}", limit(self.max_file_size))?;
+        writeln!(f, "Largest commit: {}", limit(self.max_commit_size))?;
+        write!(f,
+               "Over a limit:   {}",
+               match self.refuse {
+                   true => "refuse the commit, unless forced",
+                   false => "warn",
+               })
+    }
+}
+
+impl fmt::Display for SizeViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SizeViolation::LargeFile(ref path, size, max) => {
+                write!(f,
+                       "{} is {}, over the limit of {} per file",
+                       path.display(),
+                       human_bytes(size),
+                       human_bytes(max))
+            }
+            SizeViolation::LargeCommit(size, max) => {
+                write!(f,
+                       "Commit adds {}, over the limit of {} per commit",
+                       human_bytes(size),
+                       human_bytes(max))
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+    use super::*;
+
+    #[test]
+    fn test_size_policy() {
+        let files = || {
+            vec![(PathBuf::from("small.jpg"), 1000),
+                 (PathBuf::from("raw/huge.mov"), 5000),
+                 (PathBuf::from("raw/big.mov"), 3000)]
+        };
+        assert!(SizePolicy::default().check(files()).is_empty());
+
+        let policy = SizePolicy {
+            max_file_size: Some(4000),
+            max_commit_size: Some(8000),
+            refuse: false,
+        };
+        assert_eq!(policy.check(files()),
+                   vec![SizeViolation::LargeFile(PathBuf::from("raw/huge.mov"),
+                                                 5000,
+                                                 4000),
+                        SizeViolation::LargeCommit(9000, 8000)]);
+        assert!(policy.check(files().into_iter().take(1)).is_empty());
+    }
+}
@@ -1,10 +1,17 @@
+use constants::PLAIN_PROGRESS_INTERVAL_SECS;
+use error::*;
 use human_readable::human_bytes;
+use human_readable::human_duration;
+use human_readable::human_rate;
+use rustc_serialize::json;
 use std::fmt;
 use std::io;
 use std::io::BufRead;
 use std::io::Read;
 use std::io::Write;
 use std::io::stderr;
+use std::mem;
+use std::str::FromStr;
 use std::sync::*;
 use std::thread;
 use std::time::Duration;
@@ -33,11 +40,22 @@ impl StopWatch {
     }
 }
 
+/// Thread-safe counter of progress toward an estimated total of bytes
+///
+/// Also counts completed files, to show a file rate alongside the byte rate.
 pub struct ProgressCounter {
     desc: String,
     start: Instant,
     estimate: u64,
-    state: RwLock<(u64, bool)>,
+    state: RwLock<ProgressState>,
+    notes: Mutex<Vec<String>>,
+}
+
+#[derive(Debug,Clone,Copy,Default)]
+struct ProgressState {
+    count: u64,
+    files: u64,
+    finished: bool,
 }
 
 impl ProgressCounter {
@@ -48,20 +66,33 @@ impl ProgressCounter {
             desc: desc.into(),
             start: Instant::now(),
             estimate: estimate,
-            state: RwLock::new((0, false)),
+            state: RwLock::new(ProgressState::default()),
+            notes: Mutex::new(Vec::new()),
         })
     }
     pub fn add(&self, progress: u64) {
-        self.state.write().unwrap().0 += progress
+        self.state.write().unwrap().count += progress
+    }
+    /// Count one file as complete
+    pub fn add_file(&self) { self.state.write().unwrap().files += 1 }
+    pub fn finish(&self) { self.state.write().unwrap().finished = true; }
+    pub fn is_finished(&self) -> bool { self.state.read().unwrap().finished }
+    /// Queue a message to be shown above the progress display
+    pub fn note(&self, message: String) {
+        self.notes.lock().unwrap().push(message)
+    }
+    /// Take all queued messages
+    pub fn take_notes(&self) -> Vec<String> {
+        mem::take(&mut *self.notes.lock().unwrap())
     }
-    pub fn finish(&self) { self.state.write().unwrap().1 = true; }
     pub fn read(&self) -> ProgressReport {
         let state = *self.state.read().unwrap();
         ProgressReport {
             desc: self.desc.as_str(),
             estimate: self.estimate,
-            count: state.0,
-            finished: state.1,
+            count: state.count,
+            files: state.files,
+            finished: state.finished,
             elapsed: Instant::now().duration_since(self.start),
         }
     }
@@ -71,10 +102,38 @@ pub struct ProgressReport<'a> {
     desc: &'a str,
     estimate: u64,
     count: u64,
+    files: u64,
     finished: bool,
     elapsed: Duration,
 }
 
+impl<'a> ProgressReport<'a> {
+    /// The report as a line of JSON, with the phase's nesting depth
+    fn to_json(&self, depth: usize) -> String {
+        json::encode(&ReportJson {
+                phase: self.desc.to_owned(),
+                depth: depth,
+                count: self.count,
+                estimate: self.estimate,
+                files: self.files,
+                elapsed_secs: StopWatch::float_secs(&self.elapsed),
+                finished: self.finished,
+            })
+            .expect("reports encode")
+    }
+}
+
+#[derive(RustcEncodable)]
+struct ReportJson {
+    phase: String,
+    depth: usize,
+    count: u64,
+    estimate: u64,
+    files: u64,
+    elapsed_secs: f32,
+    finished: bool,
+}
+
 impl<'a> fmt::Display for ProgressReport<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}:", self.desc)?;
@@ -87,18 +146,27 @@ impl<'a> fmt::Display for ProgressReport<'a> {
             return Ok(());
         }
 
-        let secs = self.elapsed.as_secs() as f32 +
-                   self.elapsed.subsec_nanos() as f32 / 1e9;
-        let percent = self.count as f32 / self.estimate as f32 * 100_f32;
+        // Count can overshoot the estimate (when hashing files during a
+        // merge, for instance), so clamp to keep the display sensible
+        let count = self.count.min(self.estimate);
+        let secs = StopWatch::float_secs(&self.elapsed);
+        let percent = count as f32 / self.estimate as f32 * 100_f32;
         write!(f, " {:5.1}%", percent)?;
-        write!(f, " {:0.1}s", secs)?;
+        write!(f, " {}", human_duration(self.elapsed))?;
 
         if secs >= 0.5 {
             let per_sec = self.count as f32 / secs;
-            let remain_secs = (self.estimate - self.count) as f32 / per_sec;
-            write!(f, " {:>10}/s", human_bytes(per_sec as u64))?;
-            if !self.finished {
-                write!(f, " {:0.0}s", remain_secs)?;
+            let files_per_sec = self.files as f32 / secs;
+            write!(f, " {:>12}", human_rate(self.count, self.elapsed))?;
+            write!(f,
+                   " {} files ({:0.1} files/s)",
+                   self.files,
+                   files_per_sec)?;
+            if !self.finished && per_sec > 0.0 {
+                let remain_secs = (self.estimate - count) as f32 / per_sec;
+                let remain =
+                    Duration::from_millis((remain_secs * 1000.0) as u64);
+                write!(f, " ETA {}", human_duration(remain))?;
             }
         }
         if self.finished {
@@ -108,22 +176,292 @@ impl<'a> fmt::Display for ProgressReport<'a> {
     }
 }
 
+/// Thread-safe set of progress counters, for a command with several phases
+///
+/// Each phase is a `ProgressCounter` of its own, such as estimating, then
+/// hashing, then storing. A phase can be nested under another, to count a
+/// part of it, and is shown indented beneath it. Active phases are redrawn in
+/// place, and each is left on screen with its final figures once it
+/// finishes.
+pub struct ProgressSet {
+    phases: Mutex<Vec<Phase>>,
+    notes: Mutex<Vec<String>>,
+    finished: RwLock<bool>,
+    /// Is the set finished as soon as all of its phases are?
+    ends_with_phases: bool,
+}
+
+struct Phase {
+    counter: Arc<ProgressCounter>,
+    depth: usize,
+    shown_done: bool,
+}
+
+impl ProgressSet {
+    pub fn arc() -> Arc<Self> { Self::new(Vec::new(), false) }
+
+    /// A set of just one counter, to show it alone until it finishes
+    pub fn single(counter: Arc<ProgressCounter>) -> Arc<Self> {
+        let phase = Phase {
+            counter: counter,
+            depth: 0,
+            shown_done: false,
+        };
+        Self::new(vec![phase], true)
+    }
+
+    fn new(phases: Vec<Phase>, ends_with_phases: bool) -> Arc<Self> {
+        Arc::new(ProgressSet {
+            phases: Mutex::new(phases),
+            notes: Mutex::new(Vec::new()),
+            finished: RwLock::new(false),
+            ends_with_phases: ends_with_phases,
+        })
+    }
+
+    /// Start a new top-level phase
+    pub fn phase<S>(&self, desc: S, estimate: u64) -> Arc<ProgressCounter>
+        where S: Into<String>
+    {
+        let counter = ProgressCounter::arc(desc, estimate);
+        self.add_phase(counter.clone(), None);
+        counter
+    }
+
+    /// Start a phase nested under another, shown after its other sub-phases
+    pub fn nested<S>(&self,
+                     parent: &Arc<ProgressCounter>,
+                     desc: S,
+                     estimate: u64)
+                     -> Arc<ProgressCounter>
+        where S: Into<String>
+    {
+        let counter = ProgressCounter::arc(desc, estimate);
+        self.add_phase(counter.clone(), Some(parent));
+        counter
+    }
+
+    fn add_phase(&self,
+                 counter: Arc<ProgressCounter>,
+                 parent: Option<&Arc<ProgressCounter>>) {
+        let mut phases = self.phases.lock().unwrap();
+        let parent_pos = parent.and_then(|parent| {
+            phases.iter().position(|p| Arc::ptr_eq(&p.counter, parent))
+        });
+        let (pos, depth) = match parent_pos {
+            Some(parent_pos) => {
+                let depth = phases[parent_pos].depth + 1;
+                let end = phases[parent_pos + 1..]
+                    .iter()
+                    .position(|p| p.depth < depth)
+                    .map_or(phases.len(), |i| parent_pos + 1 + i);
+                (end, depth)
+            }
+            None => (phases.len(), 0),
+        };
+        phases.insert(pos,
+                      Phase {
+                          counter: counter,
+                          depth: depth,
+                          shown_done: false,
+                      });
+    }
+
+    /// Queue a message to be shown above the progress display
+    pub fn note(&self, message: String) {
+        self.notes.lock().unwrap().push(message)
+    }
+
+    /// Finish the whole set, along with any phases still running
+    pub fn finish(&self) {
+        for phase in self.phases.lock().unwrap().iter() {
+            phase.counter.finish();
+        }
+        *self.finished.write().unwrap() = true;
+    }
+
+    /// Write one frame of the display, in the given mode
+    ///
+    /// Notes, and phases that have finished since the last frame, are
+    /// written as permanent lines. In ANSI mode, the active phases are
+    /// written after them, to be redrawn next time. Other modes write active
+    /// phases only when `periodic` is set, as lines of their own. Returns
+    /// the number of lines to redraw, or None once the whole set is
+    /// finished.
+    fn render(&self,
+              mode: ProgressMode,
+              periodic: bool,
+              out: &mut Write)
+              -> io::Result<Option<usize>> {
+        // Read state before notes, so none are missed after finishing
+        let mut phases = self.phases.lock().unwrap();
+        let finished = *self.finished.read().unwrap() ||
+                       self.ends_with_phases &&
+                       phases.iter().all(|p| p.counter.is_finished());
+        let reports = phases.iter()
+            .map(|p| {
+                let report = p.counter.read();
+                let line = match mode {
+                    ProgressMode::Json => report.to_json(p.depth),
+                    _ => {
+                        format!("{:indent$}{}",
+                                "",
+                                report,
+                                indent = 2 + p.depth * 2)
+                    }
+                };
+                (line, report.finished)
+            })
+            .collect::<Vec<_>>();
+        let mut notes = mem::take(&mut *self.notes.lock().unwrap());
+        for phase in phases.iter() {
+            notes.extend(phase.counter.take_notes());
+        }
+
+        if mode == ProgressMode::Ansi {
+            write!(out, "{}", ANSI_CLEAR_TO_END)?;
+        }
+        for note in notes {
+            match mode {
+                ProgressMode::Json => {
+                    let note = json::encode(&NoteJson { note: note })
+                        .expect("notes encode");
+                    writeln!(out, "{}", note)?
+                }
+                _ => writeln!(out, "{}", note)?,
+            }
+        }
+        let mut active = Vec::new();
+        for (phase, (line, done)) in phases.iter_mut().zip(reports) {
+            if phase.shown_done {
+                continue;
+            } else if done || finished {
+                phase.shown_done = true;
+                if mode != ProgressMode::Quiet {
+                    writeln!(out, "{}", line)?;
+                }
+            } else {
+                active.push(line);
+            }
+        }
+        let redraw = match mode {
+            ProgressMode::Ansi => active.len(),
+            _ => 0,
+        };
+        if mode == ProgressMode::Ansi ||
+           periodic && mode != ProgressMode::Quiet {
+            for line in &active {
+                writeln!(out, "{}", line)?;
+            }
+        }
+        Ok(match finished {
+            true => None,
+            false => Some(redraw),
+        })
+    }
+}
+
 const ANSI_CLEAR_TO_END: &'static str = "\x1b[0J";
 fn ansi_up_lines(n: usize) -> String { format!("\x1b[{}F", n) }
 
+#[derive(RustcEncodable)]
+struct NoteJson {
+    note: String,
+}
+
+
+/// How progress is shown on stderr
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum ProgressMode {
+    /// Redrawn in place with ANSI escape codes, for a terminal
+    Ansi,
+    /// Plain lines now and then, for a log file
+    Plain,
+    /// A JSON object per line, for another program to read
+    Json,
+    /// Nothing but notes
+    Quiet,
+}
+
+impl ProgressMode {
+    /// ANSI if stderr is a terminal, plain otherwise
+    pub fn detect() -> Self {
+        use std::io::IsTerminal;
+        match stderr().is_terminal() {
+            true => ProgressMode::Ansi,
+            false => ProgressMode::Plain,
+        }
+    }
 
+    /// How often a frame is written, and active phases in it
+    fn intervals(&self) -> (Duration, Duration) {
+        match *self {
+            ProgressMode::Ansi => {
+                (Duration::from_millis(100), Duration::from_millis(100))
+            }
+            ProgressMode::Json => {
+                (Duration::from_millis(100), Duration::from_secs(1))
+            }
+            ProgressMode::Plain | ProgressMode::Quiet => {
+                (Duration::from_millis(100),
+                 Duration::from_secs(PLAIN_PROGRESS_INTERVAL_SECS))
+            }
+        }
+    }
+}
+
+impl FromStr for ProgressMode {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(ProgressMode::detect()),
+            "ansi" => Ok(ProgressMode::Ansi),
+            "plain" => Ok(ProgressMode::Plain),
+            "json" => Ok(ProgressMode::Json),
+            "none" => Ok(ProgressMode::Quiet),
+            _ => bail!("Unknown progress mode: {}", s),
+        }
+    }
+}
+
+lazy_static!{
+    static ref PROGRESS_MODE: RwLock<Option<ProgressMode>> = RwLock::new(None);
+}
+
+/// Show progress in the given mode, rather than detecting it
+pub fn set_progress_mode(mode: ProgressMode) {
+    *PROGRESS_MODE.write().unwrap() = Some(mode);
+}
+
+/// The mode set for this process, or else detected
+pub fn progress_mode() -> ProgressMode {
+    PROGRESS_MODE.read().unwrap().unwrap_or_else(ProgressMode::detect)
+}
+
+
+/// Show a single counter on stderr until it finishes
 pub fn std_err_watch(p: Arc<ProgressCounter>) {
-    let refresh_per_sec = 10;
-    let sleep = Duration::from_millis(1000 / refresh_per_sec);
+    std_err_watch_set(ProgressSet::single(p))
+}
+
+/// Show every phase of a set on stderr until the set finishes
+pub fn std_err_watch_set(set: Arc<ProgressSet>) {
+    let mode = progress_mode();
+    let (frame, period) = mode.intervals();
+    let mut last_periodic = Instant::now();
     loop {
-        let p = p.read();
-        write!(stderr(), "{}", ANSI_CLEAR_TO_END).unwrap();
-        writeln!(stderr(), "  {}", p).unwrap();
-        if p.finished {
-            break;
-        } else {
-            write!(stderr(), "{}", ansi_up_lines(1)).unwrap();
-            thread::sleep(sleep);
+        let periodic = last_periodic.elapsed() >= period;
+        if periodic {
+            last_periodic = Instant::now();
+        }
+        match set.render(mode, periodic, &mut stderr()).unwrap() {
+            None => break,
+            Some(redraw) => {
+                if redraw > 0 {
+                    eprint!("{}", ansi_up_lines(redraw));
+                }
+                thread::sleep(frame);
+            }
         }
     }
 }
@@ -174,3 +512,236 @@ impl<'a, W: Write> Write for ProgressWriter<'a, W> {
     }
     fn flush(&mut self) -> io::Result<()> { self.w.flush() }
 }
+
+
+/// Thread-safe token bucket, for limiting the rate of a transfer
+///
+/// The bucket holds up to one second's worth of bytes, so short bursts go at
+/// full speed while the average stays under the limit.
+pub struct RateLimiter {
+    bytes_per_sec: u64,
+    state: Mutex<RateState>,
+}
+
+struct RateState {
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn arc(bytes_per_sec: u64) -> Arc<Self> {
+        assert!(bytes_per_sec > 0, "Rate limit must be positive");
+        Arc::new(RateLimiter {
+            bytes_per_sec: bytes_per_sec,
+            state: Mutex::new(RateState {
+                tokens: bytes_per_sec as f64,
+                last: Instant::now(),
+            }),
+        })
+    }
+
+    /// Account for bytes transferred, sleeping if over the limit
+    pub fn take(&self, bytes: u64) {
+        let rate = self.bytes_per_sec as f64;
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let refill = StopWatch::float_secs(&now.duration_since(state.last));
+        state.tokens = (state.tokens + refill as f64 * rate).min(rate);
+        state.tokens -= bytes as f64;
+        state.last = now;
+        if state.tokens < 0.0 {
+            let wait = -state.tokens / rate;
+            thread::sleep(Duration::new(wait as u64,
+                                        (wait.fract() * 1e9) as u32));
+            state.tokens = 0.0;
+            state.last = Instant::now();
+        }
+    }
+}
+
+
+pub struct RateLimitedReader<R: Read> {
+    limiter: Arc<RateLimiter>,
+    r: R,
+}
+impl<R: Read> RateLimitedReader<R> {
+    pub fn new(r: R, limiter: Arc<RateLimiter>) -> Self {
+        RateLimitedReader {
+            r: r,
+            limiter: limiter,
+        }
+    }
+}
+impl<R: Read> Read for RateLimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.r.read(buf)?;
+        self.limiter.take(count as u64);
+        Ok(count)
+    }
+}
+impl<R> BufRead for RateLimitedReader<R>
+    where R: BufRead
+{
+    fn fill_buf(&mut self) -> io::Result<&[u8]> { self.r.fill_buf() }
+    fn consume(&mut self, amt: usize) {
+        self.limiter.take(amt as u64);
+        self.r.consume(amt)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_progress_report_display() {
+        let report = ProgressReport {
+            desc: "Extracting",
+            estimate: 2048,
+            count: 1024,
+            files: 4,
+            finished: false,
+            elapsed: Duration::from_secs(2),
+        };
+        let display = format!("{}", report);
+        assert!(display.contains(" 50.0% "), "{}", display);
+        assert!(display.contains(" 4 files (2.0 files/s)"), "{}", display);
+        assert!(display.contains(" 2.0s "), "{}", display);
+        assert!(display.contains(" 512 bytes/s "), "{}", display);
+        assert!(display.ends_with(" ETA 2.0s"), "{}", display);
+
+        let report = ProgressReport {
+            desc: "Merging",
+            estimate: 1024,
+            count: 4096,
+            files: 1,
+            finished: true,
+            elapsed: Duration::from_secs(1),
+        };
+        let display = format!("{}", report);
+        assert!(display.contains(" 100.0% "),
+                "Overshoot should be clamped, not underflow: {}",
+                display);
+        assert!(!display.contains("ETA"), "{}", display);
+    }
+
+    fn render(set: &ProgressSet) -> (Vec<String>, Option<usize>) {
+        let mut out = Vec::new();
+        let active = set.render(ProgressMode::Ansi, true, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines = out.trim_left_matches(ANSI_CLEAR_TO_END)
+            .lines()
+            .map(|line| line.split(':').next().unwrap().to_owned())
+            .collect();
+        (lines, active)
+    }
+
+    #[test]
+    fn test_progress_set() {
+        let set = ProgressSet::arc();
+        let hashing = set.phase("Hashing", 100);
+        let storing = set.phase("Storing", 100);
+        set.nested(&hashing, "Files", 10);
+        let bytes = set.nested(&hashing, "Bytes", 100);
+        set.nested(&bytes, "Chunks", 10);
+        storing.note("Stored something".to_owned());
+        assert_eq!(render(&set),
+                   (vec!["Stored something".to_owned(),
+                         "  Hashing".to_owned(),
+                         "    Files".to_owned(),
+                         "    Bytes".to_owned(),
+                         "      Chunks".to_owned(),
+                         "  Storing".to_owned()],
+                    Some(5)));
+
+        // Finished phases are written once, and no longer redrawn
+        hashing.finish();
+        let (lines, active) = render(&set);
+        assert_eq!(lines[0], "  Hashing");
+        assert_eq!(active, Some(4));
+        assert_eq!(render(&set).1, Some(4));
+
+        set.finish();
+        assert_eq!(render(&set),
+                   (vec!["    Files".to_owned(),
+                         "    Bytes".to_owned(),
+                         "      Chunks".to_owned(),
+                         "  Storing".to_owned()],
+                    None));
+        assert!(storing.is_finished());
+
+        let single = ProgressCounter::arc("Single", 10);
+        let set = ProgressSet::single(single.clone());
+        assert_eq!(render(&set).1, Some(1));
+        single.finish();
+        assert_eq!(render(&set), (vec!["  Single".to_owned()], None));
+    }
+
+    #[test]
+    fn test_progress_modes() {
+        let render = |set: &ProgressSet, mode, periodic| {
+            let mut out = Vec::new();
+            let redraw = set.render(mode, periodic, &mut out).unwrap();
+            (String::from_utf8(out).unwrap(), redraw)
+        };
+        let counters = || {
+            let set = ProgressSet::arc();
+            let done = set.phase("Hashing", 100);
+            set.phase("Storing", 100);
+            done.note("Note".to_owned());
+            done.finish();
+            set
+        };
+
+        let set = counters();
+        let (out, redraw) = render(&set, ProgressMode::Plain, false);
+        assert_eq!(redraw, Some(0));
+        assert!(!out.contains('\x1b'), "{:?}", out);
+        assert!(out.starts_with("Note\n  Hashing:"), "{:?}", out);
+        assert_eq!(out.lines().count(), 2, "{:?}", out);
+        let (out, _) = render(&set, ProgressMode::Plain, true);
+        assert!(out.starts_with("  Storing:"), "{:?}", out);
+
+        let set = counters();
+        let (out, _) = render(&set, ProgressMode::Json, true);
+        let lines = out.lines()
+            .map(|line| json::Json::from_str(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0].find("note").and_then(|n| n.as_string()),
+                   Some("Note"));
+        assert_eq!(lines[1].find("phase").and_then(|p| p.as_string()),
+                   Some("Hashing"));
+        assert_eq!(lines[1].find("finished").and_then(|f| f.as_boolean()),
+                   Some(true));
+        assert_eq!(lines[2].find("estimate").and_then(|e| e.as_u64()),
+                   Some(100));
+
+        let set = counters();
+        assert_eq!(render(&set, ProgressMode::Quiet, true),
+                   ("Note\n".to_owned(), Some(0)));
+        set.finish();
+        assert_eq!(render(&set, ProgressMode::Quiet, true),
+                   ("".to_owned(), None));
+
+        assert_eq!("json".parse::<ProgressMode>().unwrap(), ProgressMode::Json);
+        assert!("fancy".parse::<ProgressMode>().is_err());
+    }
+
+    #[test]
+    fn test_rate_limited_reader() {
+        let limiter = RateLimiter::arc(1000);
+        let data = vec![0u8; 1500];
+        let watch = StopWatch::new();
+        let mut reader = RateLimitedReader::new(data.as_slice(), limiter);
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out.len(), 1500);
+
+        // The first 1000 bytes are a burst, the other 500 take half a second
+        let elapsed = StopWatch::float_secs(&watch.elapsed());
+        assert!(elapsed >= 0.45, "Too fast: {} seconds", elapsed);
+        assert!(elapsed < 2.0, "Too slow: {} seconds", elapsed);
+    }
+}
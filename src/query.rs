@@ -0,0 +1,217 @@
+//! Finding files in history by size, media type, or extracted metadata
+//!
+//! Queries read only trees and the small objects linked from them, plus the
+//! first chunk of a file whose media type was not recorded when it was
+//! stored, so they work without checking anything out, and even on files
+//! whose content was left out by a partial fetch.
+
+use dag::ObjectKey;
+use dag::ObjectSize;
+use error::*;
+use exif::MediaMetadata;
+use media_type::MediaType;
+use object_store::ObjectStore;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::hash_map::Entry;
+use std::path::PathBuf;
+use walker::*;
+
+/// Conditions that a file must meet to match a query
+#[derive(Debug,Clone,Default,PartialEq)]
+pub struct FileQuery {
+    pub min_size: Option<ObjectSize>,
+    pub max_size: Option<ObjectSize>,
+    /// A MIME type, such as `image/jpeg`, or just its kind, such as `video`
+    pub media_type: Option<String>,
+    /// Metadata fields that must contain the given text, ignoring case
+    pub metadata: Vec<(String, String)>,
+}
+
+impl FileQuery {
+    fn size_matches(&self, size: ObjectSize) -> bool {
+        self.min_size.is_none_or(|min| size >= min) &&
+        self.max_size.is_none_or(|max| size <= max)
+    }
+
+    fn type_matches(&self, media_type: MediaType) -> bool {
+        match self.media_type {
+            None => true,
+            Some(ref wanted) => {
+                let mime = media_type.mime();
+                mime == wanted.as_str() ||
+                mime.split('/').next() == Some(wanted.as_str())
+            }
+        }
+    }
+
+    fn metadata_matches(&self, metadata: Option<&MediaMetadata>) -> bool {
+        self.metadata.iter().all(|(field, text)| {
+            metadata.and_then(|m| m.field(field))
+                .is_some_and(|value| {
+                    value.to_lowercase().contains(&text.to_lowercase())
+                })
+        })
+    }
+}
+
+/// A file that matched a query
+#[derive(Debug,Clone,PartialEq)]
+pub struct QueryMatch {
+    /// The first commit found to have the file at this path
+    pub commit: ObjectKey,
+    pub path: PathBuf,
+    pub hash: ObjectKey,
+    pub size: ObjectSize,
+    pub media_type: MediaType,
+    pub metadata: Option<MediaMetadata>,
+}
+
+/// Find files matching a query, in the given commits and their history
+///
+/// Matches are passed to `found` as they are found, searching the starting
+/// commits in order and then back through history. Each file is reported
+/// once per path, with the first commit found to have it there.
+pub fn query_files<F>(store: &ObjectStore,
+                      starts: &[ObjectKey],
+                      query: &FileQuery,
+                      mut found: F)
+                      -> Result<()>
+    where F: FnMut(&QueryMatch) -> Result<()>
+{
+    let mut media_types = HashMap::<ObjectKey, MediaType>::new();
+    let mut reported = HashSet::<(PathBuf, ObjectKey)>::new();
+    for file in store.history_files(starts) {
+        let file = file?;
+        let key = file.node.hash;
+        if !query.size_matches(file.node.file_size) ||
+           reported.contains(&(file.path.clone(), key)) {
+            continue;
+        }
+        if let Entry::Vacant(entry) = media_types.entry(key) {
+            let media_type = match query.media_type {
+                Some(_) => store.detect_media_type(&key)?,
+                None => store.media_type(&key)?,
+            };
+            entry.insert(media_type);
+        }
+        let media_type = media_types[&key];
+        if !query.type_matches(media_type) {
+            continue;
+        }
+        let metadata = match file.media_metadata {
+            Some(ref link) => Some(store.read_media_metadata(link)?),
+            None => None,
+        };
+        if !query.metadata_matches(metadata.as_ref()) {
+            continue;
+        }
+        reported.insert((file.path.clone(), key));
+        found(&QueryMatch {
+                commit: file.commit,
+                path: file.path,
+                hash: key,
+                size: file.node.file_size,
+                media_type: media_type,
+                metadata: metadata,
+            })?;
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod test {
+    use dag::Blob;
+    use dag::Commit;
+    use dag::Tree;
+    use exif::MediaMetadata;
+    use object_store::test::create_temp_repository;
+    use super::*;
+
+    #[test]
+    fn test_query_files() {
+        let (_temp, store) = create_temp_repository().unwrap();
+        let mut video_content = b"\0\0\0\x18ftypmp42".to_vec();
+        video_content.extend(vec![0; 1000]);
+        let video = store.store_object(&Blob::from(video_content)).unwrap();
+        let photo = store.store_object(&Blob::from(b"\xff\xd8\xff\xe0 small"
+                .to_vec()))
+            .unwrap();
+        let text = store.store_object(&Blob::from("Hello!")).unwrap();
+        let metadata = MediaMetadata {
+            camera_model: Some("Canon EOS 5D".to_owned()),
+            ..MediaMetadata::default()
+        };
+        let link = store.store_object(&Blob::from(metadata.to_string()
+                .into_bytes()))
+            .unwrap();
+
+        let mut tree = tree_object!{
+            "clip.mp4" => video,
+            "photo.jpg" => photo,
+            "notes.txt" => text,
+        };
+        tree.insert_media_metadata("photo.jpg", link);
+        let tree = store.store_object(&tree).unwrap();
+        let first = store.store_object(&Commit {
+                tree: tree,
+                parents: vec![],
+                message: "First".to_owned(),
+                time: None,
+                signature: None,
+            })
+            .unwrap();
+        let dir = store.store_object(&tree_object!{ "moved.mp4" => video, })
+            .unwrap();
+        let tree = store.store_object(&tree_object!{ "dir" => dir, })
+            .unwrap();
+        let second = store.store_object(&Commit {
+                tree: tree,
+                parents: vec![first],
+                message: "Second".to_owned(),
+                time: None,
+                signature: None,
+            })
+            .unwrap();
+
+        let run = |query: &FileQuery| {
+            let mut matches = Vec::new();
+            query_files(&store, &[second], query, |m| {
+                    matches.push((m.commit, m.path.clone()));
+                    Ok(())
+                })
+                .unwrap();
+            matches
+        };
+
+        let videos = FileQuery {
+            media_type: Some("video".to_owned()),
+            ..FileQuery::default()
+        };
+        assert_eq!(run(&videos),
+                   vec![(second, PathBuf::from("dir/moved.mp4")),
+                        (first, PathBuf::from("clip.mp4"))]);
+
+        let big = FileQuery {
+            min_size: Some(100),
+            max_size: Some(2000),
+            ..FileQuery::default()
+        };
+        assert_eq!(run(&big).len(), 2);
+
+        let canon = FileQuery {
+            media_type: Some("image/jpeg".to_owned()),
+            metadata: vec![("camera-model".to_owned(), "eos".to_owned())],
+            ..FileQuery::default()
+        };
+        assert_eq!(run(&canon), vec![(first, PathBuf::from("photo.jpg"))]);
+
+        let nikon = FileQuery {
+            metadata: vec![("camera-model".to_owned(), "Nikon".to_owned())],
+            ..FileQuery::default()
+        };
+        assert_eq!(run(&nikon), vec![]);
+        assert_eq!(run(&FileQuery::default()).len(), 4);
+    }
+}
@@ -0,0 +1,302 @@
+//! Rebase: replay a branch's commits on top of another commit
+//!
+//! The commits on the current branch that are not on the target are applied
+//! one at a time, oldest first. Each is a three-way merge of the changes it
+//! made to its parent into the new history so far, committed with its
+//! original message. At the end, the branch is moved to the last new commit,
+//! and the move is recorded in the reflog.
+//!
+//! Only linear history can be rebased: a merge commit among those to replay
+//! stops the rebase before it starts. If a commit's changes conflict, the
+//! rebase stops with that commit partly applied, and its state is kept in the
+//! hidden directory. Resolve the conflicts and run `dmv rebase --continue`,
+//! or run `dmv rebase --abort` to go back to where the branch was.
+
+use dag::ObjectKey;
+use disk_backed::DiskBacked;
+use error::*;
+use std::fs;
+use std::path::PathBuf;
+use work_dir::StatusOptions;
+use work_dir::WorkDir;
+
+/// State of a rebase in progress
+#[derive(Debug,Clone,PartialEq,Eq,Hash,RustcEncodable,RustcDecodable)]
+pub struct RebaseState {
+    /// Branch being rebased, or None if detached
+    pub branch: Option<String>,
+    /// Commit checked out when the rebase started
+    pub orig_head: ObjectKey,
+    /// Commit the branch is being replayed onto
+    pub onto: ObjectKey,
+    /// Commits still to replay, oldest first
+    pub todo: Vec<ObjectKey>,
+    /// Commit whose changes conflicted, to be committed by `--continue`
+    pub stopped_at: Option<ObjectKey>,
+    /// Number of commits replayed so far
+    pub done: usize,
+}
+
+/// A rebase in progress, stored in a file in the hidden directory
+pub struct Rebase {
+    state: DiskBacked<RebaseState>,
+}
+
+impl Rebase {
+    fn state_path(work_dir: &WorkDir) -> PathBuf {
+        work_dir.object_store.path().join("rebase")
+    }
+
+    /// Is a rebase in progress in this work dir?
+    pub fn in_progress(work_dir: &WorkDir) -> bool {
+        Self::state_path(work_dir).exists()
+    }
+
+    /// Start rebasing the current branch onto a commit
+    ///
+    /// The work dir must have no uncommitted changes. The target is checked
+    /// out, and the commits to replay are recorded, but none are replayed
+    /// until `run`.
+    pub fn start(work_dir: &mut WorkDir, onto: ObjectKey) -> Result<Self> {
+        if Self::in_progress(work_dir) {
+            bail!("A rebase is already in progress. Run `dmv rebase \
+                   --continue` or `dmv rebase --abort`");
+        }
+        if work_dir.parents().len() > 1 {
+            bail!("Cannot rebase during a merge. Commit the merge first");
+        }
+        if work_dir.state.subtree.is_some() {
+            bail!("Cannot rebase with only a subtree checked out");
+        }
+        let head = match work_dir.head() {
+            Some(head) => head,
+            None => bail!("No commits to rebase"),
+        };
+        if !work_dir.status(&StatusOptions::default(), None, None)?.is_empty() {
+            bail!("Cannot rebase with uncommitted changes. Commit them first");
+        }
+
+        let mut todo = Vec::new();
+        let mut next = Some(head);
+        while let Some(hash) = next {
+            if work_dir.is_ancestor(&hash, &onto)? {
+                break;
+            }
+            let commit = work_dir.open_commit(&hash)?;
+            if commit.parents.len() > 1 {
+                bail!("Cannot rebase merge commit {}. Only linear history \
+                       can be rebased",
+                      hash);
+            }
+            next = commit.parents.first().cloned();
+            todo.push(hash);
+        }
+        todo.reverse();
+
+        let state = RebaseState {
+            branch: work_dir.branch().map(|b| b.to_owned()),
+            orig_head: head,
+            onto: onto,
+            todo: todo,
+            stopped_at: None,
+            done: 0,
+        };
+        work_dir.checkout(&onto.to_hex().parse()?, false)?;
+        let mut state = DiskBacked::init("rebase state",
+                                         Self::state_path(work_dir),
+                                         state);
+        state.flush()?;
+        Ok(Rebase { state: state })
+    }
+
+    /// Open the rebase in progress
+    pub fn open(work_dir: &WorkDir) -> Result<Self> {
+        if !Self::in_progress(work_dir) {
+            bail!("No rebase in progress");
+        }
+        let state = DiskBacked::read("rebase state",
+                                     Self::state_path(work_dir))?;
+        Ok(Rebase { state: state })
+    }
+
+    pub fn state(&self) -> &RebaseState { &self.state }
+
+    /// Replay the remaining commits, then move the branch and finish
+    ///
+    /// Commits whose parent is already the new head are kept as they are.
+    /// If the rebase stopped on a conflict, the work dir is committed first
+    /// with the stopped commit's message, unless it has no changes. Stops
+    /// again, with a `MergeConflict`, at the next conflict. Returns the new
+    /// head.
+    pub fn run(mut self, work_dir: &mut WorkDir) -> Result<ObjectKey> {
+        if let Some(stopped_at) = self.state.stopped_at {
+            let options = StatusOptions::default();
+            if !work_dir.status(&options, None, None)?.is_empty() {
+                let message = work_dir.open_commit(&stopped_at)?.message;
+                work_dir.commit(message, false, None)?;
+            }
+            self.state.stopped_at = None;
+            self.state.done += 1;
+            self.state.flush()?;
+        }
+        while !self.state.todo.is_empty() {
+            let commit = self.state.todo.remove(0);
+            self.state.stopped_at = Some(commit);
+            self.state.flush()?;
+            if work_dir.commit_parents(&commit)? == work_dir.parents()[..] {
+                // Already on top of the new history, so keep it as it is
+                debug!("Fast-forwarding to {}", commit);
+                work_dir.checkout(&commit.to_hex().parse()?, false)?;
+            } else {
+                debug!("Replaying {}", commit);
+                work_dir.cherry_pick(&commit)?;
+            }
+            self.state.stopped_at = None;
+            self.state.done += 1;
+            self.state.flush()?;
+        }
+
+        let new_head = work_dir.head().expect("rebase target is a commit");
+        let ref_name = match self.state.branch {
+            Some(ref branch) => {
+                work_dir.update_ref(branch.to_owned(), new_head)?;
+                work_dir.checkout(&branch.parse()?, false)?;
+                branch.to_owned()
+            }
+            None => "HEAD".to_owned(),
+        };
+        work_dir.record_reflog(&ref_name,
+                               Some(self.state.orig_head),
+                               new_head,
+                               "rebase")?;
+        self.remove(work_dir)?;
+        Ok(new_head)
+    }
+
+    /// Give up, checking out what was checked out before the rebase started
+    ///
+    /// Changes left in the work dir by a conflicting commit are discarded.
+    pub fn abort(self, work_dir: &mut WorkDir) -> Result<()> {
+        let back_to = match self.state.branch {
+            Some(ref branch) => branch.to_owned(),
+            None => self.state.orig_head.to_hex(),
+        };
+        work_dir.checkout(&back_to.parse()?, true)?;
+        self.remove(work_dir)
+    }
+
+    fn remove(self, work_dir: &WorkDir) -> Result<()> {
+        fs::remove_file(Self::state_path(work_dir))
+            .chain_err(|| "Could not remove rebase state")
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use work_dir::test::create_temp_work_dir;
+    use work_dir::test::read_wd_file;
+    use work_dir::test::write_wd_file;
+
+    #[test]
+    fn test_rebase() {
+        let (_temp, wd_path, mut wd) = create_temp_work_dir().unwrap();
+
+        write_wd_file(&wd_path, "shared", "base");
+        let base = wd.commit("Base".to_owned(), false, None).unwrap().hash;
+        wd.update_ref("topic".to_owned(), base).unwrap();
+        write_wd_file(&wd_path, "upstream", "upstream");
+        let upstream = wd.commit("Upstream".to_owned(), false, None)
+            .unwrap()
+            .hash;
+
+        wd.checkout(&"topic".parse().unwrap(), false).unwrap();
+        write_wd_file(&wd_path, "topic", "one");
+        wd.commit("One".to_owned(), false, None).unwrap();
+        write_wd_file(&wd_path, "topic", "two");
+        wd.commit("Two".to_owned(), false, None).unwrap();
+
+        let rebase = Rebase::start(&mut wd, upstream).unwrap();
+        assert_eq!(rebase.state().todo.len(), 2);
+        let new_head = rebase.run(&mut wd).unwrap();
+        assert!(!Rebase::in_progress(&wd));
+        assert_eq!(wd.branch(), Some("topic"));
+        assert_eq!(wd.try_find_ref("topic"), Some(new_head));
+        let two = wd.open_commit(&new_head).unwrap();
+        assert_eq!(two.message, "Two");
+        let one = two.parents[0];
+        let one_again = wd.open_commit(&one).unwrap();
+        assert_eq!(one_again.message, "One");
+        assert_eq!(one_again.parents, vec![upstream]);
+        assert_eq!(read_wd_file(&wd_path, "upstream"), "upstream");
+        assert_eq!(read_wd_file(&wd_path, "topic"), "two");
+        assert_eq!(wd.reflog().entries("topic")[0].new, new_head);
+
+        let again = Rebase::start(&mut wd, upstream).unwrap().run(&mut wd);
+        assert_eq!(again.unwrap(),
+                   new_head,
+                   "Commits already on the target should be kept");
+
+        // Conflict: master changes the same file
+        wd.checkout(&"master".parse().unwrap(), false).unwrap();
+        write_wd_file(&wd_path, "topic", "master");
+        let master = wd.commit("Master".to_owned(), false, None).unwrap().hash;
+        wd.checkout(&"topic".parse().unwrap(), false).unwrap();
+        let rebase = Rebase::start(&mut wd, master).unwrap();
+        match rebase.run(&mut wd) {
+            Err(Error(ErrorKind::MergeConflict(paths), _)) => {
+                assert_eq!(paths, vec![PathBuf::from("topic")])
+            }
+            other => panic!("Expected MergeConflict, got {:?}", other),
+        }
+        let rebase = Rebase::open(&wd).unwrap();
+        assert_eq!(rebase.state().stopped_at, Some(one));
+
+        // Abort goes back to the original branch
+        rebase.abort(&mut wd).unwrap();
+        assert!(!Rebase::in_progress(&wd));
+        assert_eq!(wd.branch(), Some("topic"));
+        assert_eq!(wd.head(), Some(new_head));
+        assert_eq!(read_wd_file(&wd_path, "topic"), "two");
+
+        // Resolve and continue
+        let rebase = Rebase::start(&mut wd, master).unwrap();
+        assert!(rebase.run(&mut wd).is_err());
+        write_wd_file(&wd_path, "topic", "fix");
+        let rebase = Rebase::open(&wd).unwrap();
+        assert!(rebase.run(&mut wd).is_err(), "Second commit conflicts too");
+        write_wd_file(&wd_path, "topic", "two");
+        let resolved = Rebase::open(&wd).unwrap().run(&mut wd).unwrap();
+        let commit = wd.open_commit(&resolved).unwrap();
+        assert_eq!(commit.message, "Two");
+        assert_eq!(wd.open_commit(&commit.parents[0]).unwrap().parents,
+                   vec![master]);
+        assert_eq!(read_wd_file(&wd_path, "topic"), "two");
+    }
+
+    #[test]
+    fn test_rebase_keeps_local_changes() {
+        let (_temp, wd_path, mut wd) = create_temp_work_dir().unwrap();
+        write_wd_file(&wd_path, "foo", "base");
+        let base = wd.commit("Base".to_owned(), false, None).unwrap().hash;
+        wd.update_ref("topic".to_owned(), base).unwrap();
+        write_wd_file(&wd_path, "foo", "upstream");
+        let upstream = wd.commit("Upstream".to_owned(), false, None)
+            .unwrap()
+            .hash;
+        wd.checkout(&"topic".parse().unwrap(), false).unwrap();
+
+        write_wd_file(&wd_path, "foo", "mine");
+        assert!(Rebase::start(&mut wd, upstream).is_err());
+        assert!(!Rebase::in_progress(&wd));
+        assert_eq!(read_wd_file(&wd_path, "foo"), "mine");
+
+        // Nor can the checkouts it makes along the way lose them
+        assert_match!(wd.checkout(&upstream.to_hex().parse().unwrap(), false)
+                          .unwrap_err()
+                          .root_kind(),
+                      &ErrorKind::CheckoutConflict(_));
+        assert_eq!(read_wd_file(&wd_path, "foo"), "mine");
+    }
+}
@@ -0,0 +1,112 @@
+//! Reflog: earlier values of refs, so that commits left behind can be found
+//!
+//! Commands that move a ref in a way that can leave a commit unreachable,
+//! like `commit --amend`, record the move here. The old commit stays in the
+//! store, and `dmv reflog` shows its hash so that it can be checked out or
+//! branched from again.
+//!
+//! A detached work dir's moves are recorded under `HEAD`.
+
+use dag::ObjectKey;
+use disk_backed::DiskBacked;
+use encodable;
+use error::*;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+/// Most entries kept for each ref, after which the oldest are dropped
+pub const MAX_REFLOG_ENTRIES: usize = 1000;
+
+/// One move of a ref
+#[derive(Debug,Clone,PartialEq,Eq,Hash,RustcEncodable,RustcDecodable)]
+pub struct ReflogEntry {
+    /// Where the ref pointed before, or None if it was new
+    pub old: Option<ObjectKey>,
+    pub new: ObjectKey,
+    pub time: encodable::SystemTime,
+    /// Command that moved the ref, such as "amend"
+    pub reason: String,
+}
+
+/// Map of ref name to its moves, oldest first
+pub type ReflogMap = BTreeMap<String, Vec<ReflogEntry>>;
+
+/// The on-disk reflog for an object store
+pub struct Reflog(DiskBacked<ReflogMap>);
+
+impl Reflog {
+    /// Open the reflog at the given path, or start an empty one
+    pub fn open(path: PathBuf) -> Result<Self> {
+        Ok(Reflog(DiskBacked::read_or_default("reflog", path)?))
+    }
+
+    /// Start an empty reflog that is kept in memory only
+    #[cfg(any(test, feature = "testing"))]
+    pub fn in_memory() -> Self {
+        Reflog(DiskBacked::in_memory("reflog", Default::default()))
+    }
+
+    /// Record that a ref moved
+    pub fn record(&mut self,
+                  name: &str,
+                  old: Option<ObjectKey>,
+                  new: ObjectKey,
+                  reason: &str)
+                  -> Result<()> {
+        let entries = self.0.entry(name.to_owned()).or_default();
+        entries.push(ReflogEntry {
+            old: old,
+            new: new,
+            time: SystemTime::now().into(),
+            reason: reason.to_owned(),
+        });
+        if entries.len() > MAX_REFLOG_ENTRIES {
+            let excess = entries.len() - MAX_REFLOG_ENTRIES;
+            entries.drain(..excess);
+        }
+        self.0.flush().map_err(|e| e.into())
+    }
+
+    /// Moves of a ref, newest first
+    pub fn entries(&self, name: &str) -> Vec<&ReflogEntry> {
+        self.0
+            .get(name)
+            .map(|entries| entries.iter().rev().collect())
+            .unwrap_or_default()
+    }
+
+    /// Names of refs with recorded moves
+    pub fn names(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use dag::ObjectKey;
+    use super::*;
+
+    #[test]
+    fn test_reflog() {
+        let temp = in_mem_tempdir!();
+        let path = temp.path().join("reflog");
+        let a = ObjectKey::from("d3486ae9136e7856bc42212385ea797094475802");
+        let b = ObjectKey::from("9b8d0d7e6b8e3c3a28c9c6b1d7c9a2d0e1f2a3b4");
+        {
+            let mut reflog = Reflog::open(path.clone()).unwrap();
+            reflog.record("master", None, a, "commit").unwrap();
+            reflog.record("master", Some(a), b, "amend").unwrap();
+        }
+
+        let reflog = Reflog::open(path).unwrap();
+        assert_eq!(reflog.names(), vec!["master"]);
+        let entries = reflog.entries("master");
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].old, entries[0].new), (Some(a), b));
+        assert_eq!(entries[0].reason, "amend");
+        assert_eq!((entries[1].old, entries[1].new), (None, a));
+        assert!(reflog.entries("other").is_empty());
+    }
+}
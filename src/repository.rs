@@ -0,0 +1,494 @@
+//! Repository: a typed interface to a work dir, for embedding dmv
+//!
+//! The `cmd` module prints for the command line. This module does the same
+//! operations, but returns structured results instead of printing, and takes
+//! the repository lock itself, so a program can use a repository without
+//! going through the CLI.
+//!
+//! ```no_run
+//! use dmv::repository::{Repository, CommitOptions};
+//! use dmv::object_store::CommitFilter;
+//!
+//! let mut repo = Repository::open("/path/to/photos").unwrap();
+//! if !repo.status(false).unwrap().changes.is_empty() {
+//!     let outcome = repo.commit("Import from camera".to_owned(),
+//!                               &CommitOptions::default())
+//!         .unwrap();
+//!     println!("Committed {}", outcome.hash);
+//! }
+//! for entry in repo.log(None, &CommitFilter::default()).unwrap() {
+//!     println!("{} {}", entry.hash, entry.commit.message);
+//! }
+//! ```
+
+use config::RepoConfig;
+use dag::Commit;
+use dag::ObjectKey;
+use error::*;
+use find_repo::RepoLayout;
+use find_repo::find_work_dir_from;
+use lock::LockHolder;
+use lock::LockMode;
+use object_store::CommitFilter;
+use object_store::DepthFirstCommitSort;
+use pathspec::Pathspec;
+use revisions::RevRange;
+use revisions::RevSpec;
+use signing;
+use signing::SignatureStatus;
+use signing::Signer;
+use status::StatusEntry;
+use std::collections::HashSet;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use walker::ErrorPolicy;
+use work_dir::Head;
+use work_dir::StateEvent;
+use work_dir::StatusOptions;
+use work_dir::UpstreamStatus;
+use work_dir::WorkDir;
+
+pub use work_dir::CommitOutcome;
+
+/// A work dir and its object store
+pub struct Repository {
+    work_dir: WorkDir,
+}
+
+/// State of the work dir, and what has changed since its last commit
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct StatusReport {
+    /// Current branch, or None if detached
+    pub branch: Option<String>,
+    /// What HEAD refers to, or None if detached with no commit
+    pub head: Option<Head>,
+    /// Path within the commit, if only a subtree is checked out
+    pub subtree: Option<PathBuf>,
+    /// Commits the work dir is based on, with their messages
+    pub parents: Vec<(ObjectKey, String)>,
+    pub upstream: Option<UpstreamStatus>,
+    pub last_commit: Option<SystemTime>,
+    pub last_checkout: Option<SystemTime>,
+    pub last_sync: Option<SystemTime>,
+    /// Changed files, each with one status per parent
+    pub changes: Vec<StatusEntry>,
+    /// Another process that was writing, so the status may be incomplete
+    pub write_in_progress: Option<LockHolder>,
+}
+
+/// Options for `Repository::commit`
+#[derive(Default)]
+pub struct CommitOptions<'a> {
+    /// Re-read and check new objects before updating the branch
+    pub verify: bool,
+    /// Sign the commit
+    pub signer: Option<&'a Signer>,
+    /// Report on stderr how many chunks of large files were reused
+    pub verbose: bool,
+    /// Commit only these paths, keeping the parent's version of all others
+    pub pathspec: Pathspec,
+    /// Commit even if the size policy would refuse it
+    pub force: bool,
+}
+
+/// One commit in the history, as given by `LogIter`
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct LogEntry {
+    pub hash: ObjectKey,
+    pub commit: Commit,
+    /// Refs pointing to the commit, and HEAD if it is checked out
+    pub refs: Vec<String>,
+}
+
+impl LogEntry {
+    /// Check the commit's signature
+    pub fn signature_status(&self) -> Result<SignatureStatus> {
+        signing::verify_commit(&self.commit)
+    }
+}
+
+/// Commit history, newest first
+pub struct LogIter<'a> {
+    work_dir: &'a WorkDir,
+    /// Commits with parents first, so the next one is popped from the end
+    sorted: Vec<(ObjectKey, Commit)>,
+    complete: bool,
+    /// Another process that was writing, so history may be incomplete
+    pub write_in_progress: Option<LockHolder>,
+}
+
+impl<'a> LogIter<'a> {
+    /// Does the history include every ancestor of its commits?
+    ///
+    /// A filter, or a range with a start, leaves gaps, so the history can
+    /// only be shown as a list rather than a graph.
+    pub fn is_complete(&self) -> bool { self.complete }
+}
+
+impl<'a> Iterator for LogIter<'a> {
+    type Item = LogEntry;
+    fn next(&mut self) -> Option<LogEntry> {
+        self.sorted.pop().map(|(hash, commit)| {
+            LogEntry {
+                refs: self.work_dir.ref_names(&hash),
+                hash: hash,
+                commit: commit,
+            }
+        })
+    }
+}
+
+impl Repository {
+    /// Open the repository containing the given path
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Repository { work_dir: find_work_dir_from(path.as_ref())? })
+    }
+
+    /// Create a new repository with the given work dir
+    pub fn init(path: PathBuf, config: RepoConfig) -> Result<Self> {
+        let layout = RepoLayout::in_work_dir(path);
+        Ok(Repository { work_dir: WorkDir::init(layout, config)? })
+    }
+
+    pub fn from_work_dir(work_dir: WorkDir) -> Self {
+        Repository { work_dir: work_dir }
+    }
+
+    /// The work dir, for operations this interface does not cover
+    pub fn work_dir(&self) -> &WorkDir { &self.work_dir }
+
+    pub fn work_dir_mut(&mut self) -> &mut WorkDir { &mut self.work_dir }
+
+    pub fn into_work_dir(self) -> WorkDir { self.work_dir }
+
+    pub fn branch(&self) -> Option<&str> { self.work_dir.branch() }
+
+    pub fn head(&self) -> Option<ObjectKey> { self.work_dir.head() }
+
+    /// State of the work dir, and files changed since its parents
+    pub fn status(&mut self, show_ignored: bool) -> Result<StatusReport> {
+        self.status_paths(show_ignored, &Pathspec::all())
+    }
+
+    /// Like `status`, but only report changes to paths matching a pathspec
+    pub fn status_paths(&mut self,
+                        show_ignored: bool,
+                        pathspec: &Pathspec)
+                        -> Result<StatusReport> {
+        let lock = self.work_dir.lock(LockMode::Shared, "status")?;
+        let options = StatusOptions {
+            show_ignored: show_ignored,
+            pathspec: pathspec.clone(),
+            // Report what cannot be read, rather than failing the whole status
+            errors: ErrorPolicy::Continue,
+        };
+        let changes = self.work_dir.status(&options, None, None);
+        let mut parents = Vec::new();
+        for parent in self.work_dir.parents() {
+            let message = self.work_dir.open_commit(parent)?.message;
+            parents.push((*parent, message));
+        }
+        Ok(StatusReport {
+            branch: self.work_dir.branch().map(|b| b.to_owned()),
+            head: self.work_dir.head_ref(),
+            subtree: self.work_dir.state.subtree.clone(),
+            parents: parents,
+            upstream: self.work_dir.upstream_status()?,
+            last_commit: self.work_dir.state.last(StateEvent::Commit),
+            last_checkout: self.work_dir.state.last(StateEvent::Checkout),
+            last_sync: self.work_dir.state.last(StateEvent::Sync),
+            changes: changes?,
+            write_in_progress: lock.writer().cloned(),
+        })
+    }
+
+    /// Commit history
+    ///
+    /// Without a range, history of all refs and the work dir's parents is
+    /// given.
+    pub fn log<'a>(&'a self,
+                   range: Option<&RevRange>,
+                   filter: &CommitFilter)
+                   -> Result<LogIter<'a>> {
+        let lock = self.work_dir.lock(LockMode::Shared, "log")?;
+        let (start_refs, excluded) = match range {
+            Some(range) => {
+                let (to, excluded) = self.work_dir.lookup_range(range)?;
+                (vec![to], excluded)
+            }
+            None => {
+                let mut start_refs: Vec<ObjectKey> =
+                    self.work_dir.parents().clone();
+                start_refs.extend(self.work_dir.refs().values());
+                start_refs.dedup();
+                (start_refs, HashSet::new())
+            }
+        };
+
+        let mut sorted = DepthFirstCommitSort::new(&self.work_dir,
+                                                   start_refs).run()?;
+        if !excluded.is_empty() {
+            sorted.retain(|(hash, _)| !excluded.contains(hash));
+        }
+        if !filter.is_empty() {
+            sorted = self.work_dir.filter_commits(sorted, filter)?;
+        }
+        Ok(LogIter {
+            work_dir: &self.work_dir,
+            sorted: sorted,
+            complete: filter.is_empty() && excluded.is_empty(),
+            write_in_progress: lock.writer().cloned(),
+        })
+    }
+
+    /// Commit the work dir and advance the current branch
+    pub fn commit(&mut self,
+                  message: String,
+                  options: &CommitOptions)
+                  -> Result<CommitOutcome> {
+        let _lock = self.work_dir.lock(LockMode::Exclusive, "commit")?;
+        self.work_dir.file_store.verbose = options.verbose;
+        let warnings = self.work_dir
+            .check_size_policy(&options.pathspec, options.force)?;
+        let mut outcome = self.work_dir
+            .commit_paths(message,
+                          &options.pathspec,
+                          options.verify,
+                          options.signer)?;
+        outcome.size_warnings = warnings;
+        Ok(outcome)
+    }
+
+    /// Replace the last commit with a commit of the work dir
+    ///
+    /// The old message is kept unless a new one is given.
+    pub fn amend(&mut self,
+                 message: Option<String>,
+                 options: &CommitOptions)
+                 -> Result<CommitOutcome> {
+        let _lock = self.work_dir.lock(LockMode::Exclusive, "commit")?;
+        self.work_dir.file_store.verbose = options.verbose;
+        let warnings = self.work_dir
+            .check_size_policy(&options.pathspec, options.force)?;
+        let mut outcome = self.work_dir
+            .amend(message, &options.pathspec, options.verify, options.signer)?;
+        outcome.size_warnings = warnings;
+        Ok(outcome)
+    }
+
+    /// Check out a revision, with HEAD detached if `detach` is set
+    ///
+    /// Unless forced, this fails with a `CheckoutConflict` rather than lose
+    /// local changes (see `WorkDir::checkout_conflicts`).
+    pub fn checkout(&mut self,
+                    rev: &RevSpec,
+                    force: bool,
+                    detach: bool)
+                    -> Result<()> {
+        let _lock = self.work_dir.lock(LockMode::Exclusive, "checkout")?;
+        if detach {
+            self.work_dir.checkout_detached(rev, force)
+        } else {
+            self.work_dir.checkout(rev, force)
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use constants::HIDDEN_DIR_NAME;
+    use policy::{SizePolicy, SizeViolation};
+    use status::Status;
+    use std::env;
+    use super::*;
+    use work_dir::test::create_temp_work_dir;
+    use work_dir::test::write_wd_file;
+
+    #[test]
+    fn test_repository() {
+        let (_temp, wd_path, wd) = create_temp_work_dir().unwrap();
+        let mut repo = Repository::from_work_dir(wd);
+
+        write_wd_file(&wd_path, "foo", "foo");
+        let status = repo.status(false).unwrap();
+        assert_eq!(status.branch, Some("master".to_owned()));
+        assert_eq!(status.parents, vec![]);
+
+        let outcome = repo.commit("Initial".to_owned(),
+                    &CommitOptions {
+                        verify: true,
+                        ..CommitOptions::default()
+                    })
+            .unwrap();
+        assert_eq!(outcome.branch, Some("master".to_owned()));
+        assert_eq!(outcome.verified, Some(3));
+
+        let status = repo.status(false).unwrap();
+        assert_eq!(status.parents,
+                   vec![(outcome.hash, "Initial".to_owned())]);
+        assert!(status.changes.is_empty(), "{:?}", status.changes);
+        assert!(status.last_commit.is_some());
+
+        write_wd_file(&wd_path, "foo", "changed");
+        let changes = repo.status(false).unwrap().changes;
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].path, PathBuf::from("foo"));
+        assert_eq!(changes[0].status, vec![Status::MaybeModified]);
+        assert_eq!(changes[0].size, 7);
+        assert_eq!(changes[0].to_string(), "m foo");
+
+        let reopened = Repository::open(wd_path.join(HIDDEN_DIR_NAME))
+            .unwrap();
+        let log = reopened.log(None, &CommitFilter::default()).unwrap();
+        assert!(log.is_complete());
+        let entries: Vec<LogEntry> = log.collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].hash, outcome.hash);
+        assert_eq!(entries[0].refs, vec!["HEAD -> master".to_owned()]);
+        assert_eq!(entries[0].signature_status().unwrap(),
+                   SignatureStatus::Unsigned);
+    }
+
+    #[test]
+    fn test_pathspec_status_and_commit() {
+        let (_temp, wd_path, wd) = create_temp_work_dir().unwrap();
+        let mut repo = Repository::from_work_dir(wd);
+        for path in &["photos/2023/a.jpg", "photos/2024/b.jpg", "notes.txt"] {
+            write_wd_file(&wd_path, path, "first");
+        }
+        repo.commit("Initial".to_owned(), &CommitOptions::default())
+            .unwrap();
+        for path in &["photos/2023/a.jpg", "photos/2024/b.jpg", "notes.txt"] {
+            write_wd_file(&wd_path, path, "second!");
+        }
+        write_wd_file(&wd_path, "photos/2024/c.png", "new");
+
+        let changed = |repo: &mut Repository, patterns: &[&str]| {
+            repo.status_paths(false, &Pathspec::new(patterns))
+                .unwrap()
+                .changes
+                .into_iter()
+                .map(|entry| entry.path)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(changed(&mut repo, &["photos/2024"]),
+                   vec![PathBuf::from("photos/2024/b.jpg"),
+                        PathBuf::from("photos/2024/c.png")]);
+        assert_eq!(changed(&mut repo, &["*.jpg"]),
+                   vec![PathBuf::from("photos/2023/a.jpg"),
+                        PathBuf::from("photos/2024/b.jpg")]);
+        assert_eq!(changed(&mut repo, &[]).len(), 4);
+
+        // Other paths keep their committed version
+        let options = CommitOptions {
+            pathspec: Pathspec::new(&["photos/2024/*.jpg", "notes.txt"]),
+            ..CommitOptions::default()
+        };
+        repo.commit("Partial".to_owned(), &options).unwrap();
+        assert_eq!(changed(&mut repo, &[]),
+                   vec![PathBuf::from("photos/2023/a.jpg"),
+                        PathBuf::from("photos/2024/c.png")]);
+    }
+
+    #[test]
+    fn test_pathspec_outside_work_dir() {
+        // As with `--repo`: tests run from the source tree, not the work dir
+        let (_temp, wd_path, wd) = create_temp_work_dir().unwrap();
+        assert!(!env::current_dir().unwrap().starts_with(&wd_path));
+        let mut repo = Repository::from_work_dir(wd);
+        write_wd_file(&wd_path, "dir/foo", "foo");
+        write_wd_file(&wd_path, "bar", "bar");
+        repo.commit("Initial".to_owned(), &CommitOptions::default())
+            .unwrap();
+        write_wd_file(&wd_path, "dir/foo", "changed");
+        write_wd_file(&wd_path, "bar", "changed");
+
+        let none: &[&str] = &[];
+        let all = repo.work_dir().pathspec(none).unwrap();
+        assert_eq!(repo.status_paths(false, &all).unwrap().changes.len(), 2);
+        let dir = repo.work_dir().pathspec(&["dir"]).unwrap();
+        let changes = repo.status_paths(false, &dir).unwrap().changes;
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].path, PathBuf::from("dir/foo"));
+        assert!(repo.work_dir().pathspec(&["../elsewhere"]).is_err());
+
+        let options = CommitOptions {
+            pathspec: all,
+            ..CommitOptions::default()
+        };
+        repo.commit("All".to_owned(), &options).unwrap();
+        assert!(repo.status(false).unwrap().changes.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_status_unreadable() {
+        use std::os::unix::fs::symlink;
+
+        let (temp, wd_path, wd) = create_temp_work_dir().unwrap();
+        let mut repo = Repository::from_work_dir(wd);
+        write_wd_file(&wd_path, "foo", "foo");
+        repo.commit("Initial".to_owned(), &CommitOptions::default())
+            .unwrap();
+        write_wd_file(&wd_path, "broken/file", "file");
+        write_wd_file(&wd_path, "fine/file", "file");
+        symlink(temp.path().join("missing"), wd_path.join("broken/link"))
+            .unwrap();
+
+        let options = StatusOptions::default();
+        assert!(repo.work_dir_mut().status(&options, None, None).is_err(),
+                "Work dir status should fail fast by default");
+        let changes = repo.status(false)
+            .unwrap()
+            .changes
+            .into_iter()
+            .map(|entry| entry.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(changes,
+                   vec!["a broken/file", "! broken/link", "a fine/file"]);
+        assert!(repo.commit("Broken".to_owned(), &CommitOptions::default())
+                    .is_err(),
+                "Commit should still fail");
+    }
+
+    #[test]
+    fn test_size_policy_commit() {
+        let (_temp, wd_path, wd) = create_temp_work_dir().unwrap();
+        let mut repo = Repository::from_work_dir(wd);
+        write_wd_file(&wd_path, "small", "small");
+        repo.commit("Initial".to_owned(), &CommitOptions::default())
+            .unwrap();
+
+        let mut policy = SizePolicy {
+            max_file_size: Some(10),
+            ..SizePolicy::default()
+        };
+        repo.work_dir_mut().set_size_policy(policy).unwrap();
+        write_wd_file(&wd_path, "large", "much too large");
+        let outcome =
+            repo.commit("Warned".to_owned(), &CommitOptions::default())
+                .unwrap();
+        assert_eq!(outcome.size_warnings,
+                   vec![SizeViolation::LargeFile(PathBuf::from("large"),
+                                                 14,
+                                                 10)]);
+
+        policy.refuse = true;
+        repo.work_dir_mut().set_size_policy(policy).unwrap();
+        write_wd_file(&wd_path, "large", "also much too large");
+        write_wd_file(&wd_path, "small", "SMALL");
+        let result = repo.commit("Refused".to_owned(),
+                                 &CommitOptions::default());
+        assert!(result.is_err());
+
+        let options = CommitOptions {
+            force: true,
+            ..CommitOptions::default()
+        };
+        let outcome = repo.commit("Forced".to_owned(), &options).unwrap();
+        assert_eq!(outcome.size_warnings.len(), 1);
+        assert!(repo.status(false).unwrap().changes.is_empty());
+    }
+}
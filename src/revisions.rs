@@ -11,9 +11,25 @@ use std::str::FromStr;
 pub type RevNameBuf = String;
 pub type RevNameStr = str;
 
+/// The name that refers to the work dir's current commit
+pub const HEAD_NAME: &'static str = "HEAD";
+
+/// A step from a commit to one of its ancestors
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum Ancestry {
+    /// `^N`: the Nth parent, counting from 1 (`^0` is the commit itself)
+    Parent(usize),
+    /// `~N`: the Nth generation back, following first parents
+    Generations(usize),
+}
+
+/// A revision, with optional steps back through history and a path
+///
+/// Written as `name`, `name~2^2`, `name:path`, or `name~1:path`.
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub struct RevSpec {
     pub rev_name: RevNameBuf,
+    pub ancestry: Vec<Ancestry>,
     pub path: Option<PathBuf>,
 }
 impl RevSpec {
@@ -30,24 +46,129 @@ impl FromStr for RevSpec {
             || Error::from(format!("Could not parse revision \"{}\"", s));
 
         let mut split = s.split(":");
-        let rev_name = split.next().ok_or_else(&could_not_parse)?.to_owned();
+        let rev = split.next().ok_or_else(&could_not_parse)?;
         let path = split.next().map(|s| PathBuf::from(s));
         if split.next().is_some() {
             return Err(could_not_parse());
         }
+
+        let name_end = rev.find(['~', '^']).unwrap_or(rev.len());
+        let (rev_name, mut suffix) = rev.split_at(name_end);
+        if rev_name.is_empty() {
+            return Err(could_not_parse());
+        }
+        let mut ancestry = Vec::new();
+        while let Some(op) = suffix.chars().next() {
+            let rest = &suffix[op.len_utf8()..];
+            let digits_end = rest.find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            let count = match &rest[..digits_end] {
+                "" => 1,
+                digits => digits.parse().map_err(|_| could_not_parse())?,
+            };
+            match op {
+                '^' => ancestry.push(Ancestry::Parent(count)),
+                '~' => ancestry.push(Ancestry::Generations(count)),
+                _ => return Err(could_not_parse()),
+            }
+            suffix = &rest[digits_end..];
+        }
+
         Ok(RevSpec {
-            rev_name: rev_name,
+            rev_name: rev_name.to_owned(),
+            ancestry: ancestry,
             path: path,
         })
     }
 }
 impl fmt::Display for RevSpec {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            &RevSpec { ref rev_name, path: None } => write!(f, "{}", rev_name),
-            &RevSpec { ref rev_name, path: Some(ref path) } => {
-                write!(f, "{}:{}", rev_name, path.display())
+        write!(f, "{}", self.rev_name)?;
+        for step in &self.ancestry {
+            match *step {
+                Ancestry::Parent(n) => write!(f, "^{}", n)?,
+                Ancestry::Generations(n) => write!(f, "~{}", n)?,
             }
         }
+        if let Some(ref path) = self.path {
+            write!(f, ":{}", path.display())?;
+        }
+        Ok(())
+    }
+}
+
+/// A range of commits: `from..to` means those in the history of `to` but not
+/// in the history of `from`. A single revision means all of its history.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct RevRange {
+    pub from: Option<RevSpec>,
+    pub to: RevSpec,
+}
+impl FromStr for RevRange {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s.find("..") {
+            Some(i) => {
+                Ok(RevRange {
+                    from: Some(s[..i].parse()?),
+                    to: s[i + 2..].parse()?,
+                })
+            }
+            None => {
+                Ok(RevRange {
+                    from: None,
+                    to: s.parse()?,
+                })
+            }
+        }
+    }
+}
+impl fmt::Display for RevRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.from {
+            Some(ref from) => write!(f, "{}..{}", from, self.to),
+            None => write!(f, "{}", self.to),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_rev_spec() {
+        let rev: RevSpec = "master".parse().unwrap();
+        assert_eq!(rev.rev_name, "master");
+        assert_eq!(rev.ancestry, vec![]);
+        assert_eq!(rev.path, None);
+
+        let rev: RevSpec = "abc123~3^2^~:some/path".parse().unwrap();
+        assert_eq!(rev.rev_name, "abc123");
+        assert_eq!(rev.ancestry,
+                   vec![Ancestry::Generations(3),
+                        Ancestry::Parent(2),
+                        Ancestry::Parent(1),
+                        Ancestry::Generations(1)]);
+        assert_eq!(rev.path, Some(PathBuf::from("some/path")));
+        assert_eq!(rev.to_string(), "abc123~3^2^1~1:some/path");
+
+        assert!("~2".parse::<RevSpec>().is_err());
+        assert!("a:b:c".parse::<RevSpec>().is_err());
+        assert!("master~1é".parse::<RevSpec>().is_err());
+        assert!("master^é".parse::<RevSpec>().is_err());
+    }
+
+    #[test]
+    fn test_parse_rev_range() {
+        let range: RevRange = "origin/master..master~1".parse().unwrap();
+        assert_eq!(range.from, Some("origin/master".parse().unwrap()));
+        assert_eq!(range.to, "master~1".parse().unwrap());
+        assert_eq!(range.to_string(), "origin/master..master~1");
+
+        let range: RevRange = "master".parse().unwrap();
+        assert_eq!(range.from, None);
+        assert!("..master".parse::<RevRange>().is_err());
     }
 }
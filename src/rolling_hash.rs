@@ -2,7 +2,7 @@
 
 use dag;
 use dag::ToHashed;
-use std::io::{BufRead, Result};
+use std::io::{BufRead, Result, Write};
 
 /// The integer/byte type used to store a rolling hash's value
 pub type RollingHashValue = u32;
@@ -62,6 +62,61 @@ pub const CHUNK_TARGET_SIZE: usize = 19 * 1024;
 const WINDOW_SIZE: usize = 32 * 1024;
 const WINDOW_MATCH_SIZE: RollingHashValue = 16 * 1024;
 
+/// Smallest chunk target size that makes sense for ChunkParams
+pub const MIN_CHUNK_TARGET_SIZE: usize = 1024;
+
+/// Parameters that control where chunk boundaries fall
+///
+/// The defaults are the constants above. Each repository records its own
+/// parameters in its config, because changing them moves chunk boundaries and
+/// defeats deduplication against objects that are already stored.
+#[derive(Debug,Clone,Copy,Hash,PartialEq,Eq,RustcEncodable,RustcDecodable)]
+pub struct ChunkParams {
+    /// Size of the rolling hash window, in bytes
+    pub window_size: usize,
+    /// A boundary is flagged when the hash value is a multiple of this number
+    pub match_size: RollingHashValue,
+}
+
+impl ChunkParams {
+    /// Scale the default parameters to aim for the given mean chunk size
+    ///
+    /// Mean chunk size grows linearly with the match size, so both values are
+    /// scaled by the same factor as the target.
+    ///
+    /// ```
+    /// use dmv::rolling_hash::ChunkParams;
+    ///
+    /// let params = ChunkParams::for_target_size(64 * 1024);
+    /// assert_eq!(params.target_size(), 64 * 1024);
+    /// ```
+    pub fn for_target_size(target_size: usize) -> Self {
+        assert!(target_size >= MIN_CHUNK_TARGET_SIZE,
+                "chunk target size too small: {}",
+                target_size);
+        let scale = |n: usize| n * target_size / CHUNK_TARGET_SIZE;
+        ChunkParams {
+            window_size: scale(WINDOW_SIZE),
+            match_size: scale(WINDOW_MATCH_SIZE as usize) as RollingHashValue,
+        }
+    }
+
+    /// The approximate mean chunk size that these parameters produce
+    pub fn target_size(&self) -> usize {
+        let scale = self.match_size as f64 / WINDOW_MATCH_SIZE as f64;
+        (CHUNK_TARGET_SIZE as f64 * scale).round() as usize
+    }
+}
+
+impl Default for ChunkParams {
+    fn default() -> Self {
+        ChunkParams {
+            window_size: WINDOW_SIZE,
+            match_size: WINDOW_MATCH_SIZE,
+        }
+    }
+}
+
 /// Flags chunk boundaries where the rolling hash has enough zero bits
 pub struct ChunkFlagger {
     hasher: RollingHasher,
@@ -81,6 +136,9 @@ impl ChunkFlagger {
             mask: mask,
         }
     }
+    pub fn with_params(params: &ChunkParams) -> Self {
+        ChunkFlagger::window_match(params.window_size, params.match_size)
+    }
 
     /// Adds a byte to the hash, returns true if this byte triggers a flag
     pub fn slide(&mut self, byte: u8) { self.hasher.slide(byte); }
@@ -112,9 +170,13 @@ pub struct ChunkReader<R: BufRead> {
 
 impl<R: BufRead> ChunkReader<R> {
     pub fn wrap(reader: R) -> Self {
+        ChunkReader::wrap_with(reader, &ChunkParams::default())
+    }
+
+    pub fn wrap_with(reader: R, params: &ChunkParams) -> Self {
         ChunkReader {
             reader: reader,
-            flagger: ChunkFlagger::new(),
+            flagger: ChunkFlagger::with_params(params),
         }
     }
 
@@ -160,6 +222,69 @@ impl<R: BufRead> Iterator for ChunkReader<R> {
     }
 }
 
+/// Breaks bytes written to it into chunks, and indexes them
+///
+/// This is the write-side counterpart of `ChunkReader`, for content that is
+/// produced by writing, such as a file being reassembled from the object
+/// store. Chunk contents are hashed and dropped, so only the index is kept.
+pub struct ChunkWriter {
+    flagger: Option<ChunkFlagger>,
+    algorithm: dag::HashAlgorithm,
+    current: Vec<u8>,
+    index: dag::ChunkedBlob,
+}
+
+impl ChunkWriter {
+    pub fn new(params: &ChunkParams, algorithm: dag::HashAlgorithm) -> Self {
+        ChunkWriter {
+            flagger: Some(ChunkFlagger::with_params(params)),
+            algorithm: algorithm,
+            current: Vec::new(),
+            index: dag::ChunkedBlob::new(),
+        }
+    }
+
+    /// Like `new`, but keeps everything written in a single chunk
+    pub fn unchunked(algorithm: dag::HashAlgorithm) -> Self {
+        ChunkWriter {
+            flagger: None,
+            algorithm: algorithm,
+            current: Vec::new(),
+            index: dag::ChunkedBlob::new(),
+        }
+    }
+
+    fn end_chunk(&mut self) {
+        let chunk = ::std::mem::take(&mut self.current);
+        self.index.add_blob(dag::Blob::from(chunk), self.algorithm);
+    }
+
+    /// End the last chunk and give the index of all chunks
+    pub fn finish(mut self) -> dag::ChunkedBlob {
+        if !self.current.is_empty() {
+            self.end_chunk();
+        }
+        self.index
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let mut rest = buf;
+        while let Some(pos) = self.flagger
+            .as_mut()
+            .and_then(|flagger| flagger.slide_until(rest)) {
+            self.current.extend_from_slice(&rest[..pos + 1]);
+            self.end_chunk();
+            rest = &rest[pos + 1..];
+        }
+        self.current.extend_from_slice(rest);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> { Ok(()) }
+}
+
 /// Breaks a file into chunks and emits them as Objects
 ///
 /// - If the stream is empty, emits one empty Blob.
@@ -182,19 +307,38 @@ pub fn read_file_objects<R: BufRead>(reader: R) -> ObjectReader<R> {
     ObjectReader::wrap(reader)
 }
 
+/// Like `read_file_objects`, but with the given chunking parameters and hash
+pub fn read_file_objects_with<R: BufRead>(reader: R,
+                                          params: &ChunkParams,
+                                          algorithm: dag::HashAlgorithm)
+                                          -> ObjectReader<R> {
+    ObjectReader::wrap_with(reader, params, algorithm)
+}
+
 /// Breaks a file into chunks and emits them as Objects
 ///
 /// Usually created by the `read_file_objects` function.
 pub struct ObjectReader<R: BufRead> {
     chunker: ChunkReader<R>,
     chunk_index: Option<dag::ChunkedBlob>,
+    algorithm: dag::HashAlgorithm,
 }
 
 impl<R: BufRead> ObjectReader<R> {
     pub fn wrap(reader: R) -> Self {
+        ObjectReader::wrap_with(reader,
+                                &ChunkParams::default(),
+                                dag::HashAlgorithm::default())
+    }
+
+    pub fn wrap_with(reader: R,
+                     params: &ChunkParams,
+                     algorithm: dag::HashAlgorithm)
+                     -> Self {
         ObjectReader {
-            chunker: ChunkReader::wrap(reader),
+            chunker: ChunkReader::wrap_with(reader, params),
             chunk_index: Some(dag::ChunkedBlob::new()),
+            algorithm: algorithm,
         }
     }
 }
@@ -211,7 +355,7 @@ impl<R: BufRead> Iterator for ObjectReader<R> {
                 match self.chunk_index {
                     Some(ref mut index) => {
                         let blob = dag::Blob::from(chunk);
-                        let hashed = index.add_blob(blob);
+                        let hashed = index.add_blob(blob, self.algorithm);
                         Some(Ok(hashed))
                     }
                     None => unreachable!(), // Index is not consumed until end
@@ -225,13 +369,14 @@ impl<R: BufRead> Iterator for ObjectReader<R> {
                         // Chunks finished, but index pending
                         if index.chunks.len() == 0 {
                             // Zero chunks, file was empty: Emit one empty blob
-                            Some(Ok(dag::Blob::empty().to_hashed()))
+                            let empty = dag::Blob::empty();
+                            Some(Ok(empty.to_hashed_with(self.algorithm)))
                         } else if index.chunks.len() == 1 {
                             // Just one chunk: End without index
                             None
                         } else {
                             // Multiple chunks: Emit index object
-                            Some(Ok(index.to_hashed()))
+                            Some(Ok(index.to_hashed_with(self.algorithm)))
                         }
                     }
                 }
@@ -329,6 +474,21 @@ mod test {
     }
 
 
+    #[test]
+    fn test_chunk_params_target_size() {
+        for &target in &[8 * 1024, 64 * 1024] {
+            let params = ChunkParams::for_target_size(target);
+            let vcalc = measure_chunk_size(ChunkFlagger::with_params(&params),
+                                           &mut TestRand::default());
+            let mean = vcalc.mean() as usize;
+            assert!(target * 7 / 10 < mean && mean < target * 13 / 10,
+                    "target: {}, mean: {}",
+                    human_bytes(target as u64),
+                    human_bytes(mean as u64));
+        }
+    }
+
+
     #[ignore]
     #[test]
     fn chunk_size_experiment() {
@@ -384,6 +544,27 @@ mod test {
         assert_eq!(reconstructed, rand_bytes);
     }
 
+    #[test]
+    fn test_chunk_writer_matches_reader() {
+        let mut rng = TestRand::default();
+        let rand_bytes = rng.gen_byte_vec(10 * CHUNK_TARGET_SIZE);
+        let params = ChunkParams::default();
+
+        let mut expected = dag::ChunkedBlob::new();
+        for chunk in ChunkReader::wrap(rand_bytes.as_slice()) {
+            expected.add_blob(dag::Blob::from(chunk.unwrap()),
+                              dag::HashAlgorithm::default());
+        }
+
+        // Write in odd-sized pieces, so chunk ends fall mid-write
+        let mut writer = ChunkWriter::new(&params,
+                                          dag::HashAlgorithm::default());
+        for piece in rand_bytes.chunks(1000) {
+            writer.write_all(piece).unwrap();
+        }
+        assert_eq!(writer.finish(), expected);
+    }
+
     #[test]
     fn test_object_iterator_empty() {
         let input_bytes = Vec::<u8>::new();
@@ -0,0 +1,290 @@
+//! Signing commits, and checking their signatures
+//!
+//! A signature is a text block stored in the commit, over the raw object of
+//! the commit without its signature. The block says what kind of signature
+//! it is, so checking needs no configuration:
+//!
+//! - `ed25519 <public key> <signature>`, both in hex, made with a key file
+//!   generated by `dmv gen-signing-key`
+//! - An ASCII-armored OpenPGP signature, made and checked by running `gpg`
+//!
+//! An ed25519 signature names its own public key, so a good signature only
+//! shows that the commit was signed by whoever holds that key. Compare the
+//! key shown by `dmv log --verify` against the keys you trust.
+
+use crypto::ed25519;
+use dag::Commit;
+use dag::ObjectCommon;
+use encryption::random_bytes;
+use error::*;
+use rustc_serialize::hex::FromHex;
+use rustc_serialize::hex::ToHex;
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+use std::process::Stdio;
+
+/// Environment variable naming the key to sign with
+///
+/// This is either the path of an ed25519 key file, or a gpg key ID. If unset,
+/// gpg's default key is used.
+pub const SIGNING_KEY_VAR: &'static str = "DMV_SIGNING_KEY";
+
+const ED25519_PREFIX: &'static str = "ed25519 ";
+const PGP_PREFIX: &'static str = "-----BEGIN PGP SIGNATURE-----";
+
+/// Something that can sign commits
+pub trait Signer {
+    /// Sign data, giving a text signature block to store in the commit
+    fn sign(&self, data: &[u8]) -> Result<String>;
+}
+
+/// Outcome of checking a commit's signature
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum SignatureStatus {
+    Unsigned,
+    /// Good signature, with a description of the signer
+    Good(String),
+    /// Bad or uncheckable signature, with the reason
+    Bad(String),
+}
+
+/// The bytes that a commit's signature covers
+pub fn signed_data(commit: &Commit) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    commit.unsigned().write_to(&mut data)?;
+    Ok(data)
+}
+
+/// Sign a commit, replacing any signature it had
+pub fn sign_commit(commit: &mut Commit, signer: &Signer) -> Result<()> {
+    let data = signed_data(commit)?;
+    commit.signature = Some(signer.sign(&data)?);
+    Ok(())
+}
+
+/// Check a commit's signature
+pub fn verify_commit(commit: &Commit) -> Result<SignatureStatus> {
+    match commit.signature {
+        None => Ok(SignatureStatus::Unsigned),
+        Some(ref signature) => verify(&signed_data(commit)?, signature),
+    }
+}
+
+/// Check a signature block over some data
+pub fn verify(data: &[u8], signature: &str) -> Result<SignatureStatus> {
+    if let Some(sig) = signature.strip_prefix(ED25519_PREFIX) {
+        Ok(verify_ed25519(data, sig))
+    } else if signature.starts_with(PGP_PREFIX) {
+        verify_gpg(data, signature)
+    } else {
+        Ok(SignatureStatus::Bad("unknown kind of signature".to_owned()))
+    }
+}
+
+/// The signer chosen by `DMV_SIGNING_KEY`
+pub fn signer_from_env() -> Result<Box<Signer>> {
+    match env::var(SIGNING_KEY_VAR) {
+        Ok(ref key) if Path::new(key).is_file() => {
+            Ok(Box::new(Ed25519Signer::read_key_file(Path::new(key))?))
+        }
+        Ok(key) => Ok(Box::new(GpgSigner { key_id: Some(key) })),
+        Err(_) => Ok(Box::new(GpgSigner { key_id: None })),
+    }
+}
+
+
+/// Signs with an ed25519 key kept in a file
+pub struct Ed25519Signer {
+    secret: [u8; 64],
+    public: [u8; 32],
+}
+
+impl Ed25519Signer {
+    fn from_seed(seed: &[u8]) -> Self {
+        let (secret, public) = ed25519::keypair(seed);
+        Ed25519Signer {
+            secret: secret,
+            public: public,
+        }
+    }
+
+    /// Generate a new key, and write its seed (in hex) to a new file
+    ///
+    /// The file is only readable by its owner.
+    pub fn generate(path: &Path) -> Result<Self> {
+        let mut seed = [0u8; 32];
+        random_bytes(&mut seed)?;
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(path)
+            .chain_err(|| format!("Could not create {}", path.display()))?;
+        writeln!(file, "{}", seed.to_hex())?;
+        Ok(Self::from_seed(&seed))
+    }
+
+    pub fn read_key_file(path: &Path) -> Result<Self> {
+        let mut hex = String::new();
+        fs::File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut hex))
+            .chain_err(|| format!("Could not read key {}", path.display()))?;
+        match hex.trim().from_hex() {
+            Ok(ref seed) if seed.len() == 32 => Ok(Self::from_seed(seed)),
+            _ => bail!("{} is not an ed25519 key file", path.display()),
+        }
+    }
+
+    /// Public key, in hex
+    pub fn public_key(&self) -> String { self.public.to_hex() }
+}
+
+impl Signer for Ed25519Signer {
+    fn sign(&self, data: &[u8]) -> Result<String> {
+        let signature = ed25519::signature(data, &self.secret);
+        Ok(format!("{}{} {}",
+                   ED25519_PREFIX,
+                   self.public.to_hex(),
+                   signature.to_hex()))
+    }
+}
+
+fn verify_ed25519(data: &[u8], block: &str) -> SignatureStatus {
+    let mut parts = block.split(' ').map(|part| part.from_hex());
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(Ok(ref public)), Some(Ok(ref signature)), None)
+            if public.len() == 32 && signature.len() == 64 => {
+            if ed25519::verify(data, public, signature) {
+                SignatureStatus::Good(format!("ed25519 key {}",
+                                              public.to_hex()))
+            } else {
+                SignatureStatus::Bad("signature does not match".to_owned())
+            }
+        }
+        _ => SignatureStatus::Bad("malformed ed25519 signature".to_owned()),
+    }
+}
+
+
+/// Signs by running `gpg`, with its default key or a given one
+pub struct GpgSigner {
+    pub key_id: Option<String>,
+}
+
+impl Signer for GpgSigner {
+    fn sign(&self, data: &[u8]) -> Result<String> {
+        let mut command = Command::new("gpg");
+        command.arg("--detach-sign").arg("--armor");
+        if let Some(ref key_id) = self.key_id {
+            command.arg("--local-user").arg(key_id);
+        }
+        let output = run_with_input(command, data)
+            .chain_err(|| "Could not run gpg to sign")?;
+        if !output.status.success() {
+            bail!("gpg could not sign: {}",
+                  String::from_utf8_lossy(&output.stderr).trim());
+        }
+        Ok(String::from_utf8(output.stdout)?)
+    }
+}
+
+fn verify_gpg(data: &[u8], signature: &str) -> Result<SignatureStatus> {
+    // gpg needs the signature in a file when the data comes on stdin
+    let sig_path = env::temp_dir()
+        .join(format!("dmv-signature-{}.asc", ::std::process::id()));
+    fs::write(&sig_path, signature)?;
+    let mut command = Command::new("gpg");
+    command.arg("--batch")
+        .arg("--status-fd")
+        .arg("1")
+        .arg("--verify")
+        .arg(&sig_path)
+        .arg("-");
+    let output = run_with_input(command, data);
+    fs::remove_file(&sig_path).ok();
+    let output = output.chain_err(|| "Could not run gpg to check signature")?;
+
+    let status = String::from_utf8_lossy(&output.stdout);
+    for line in status.lines() {
+        let mut words = line.splitn(4, ' ');
+        match (words.next(), words.next(), words.next(), words.next()) {
+            (Some("[GNUPG:]"), Some("GOODSIG"), Some(key_id), Some(user)) => {
+                return Ok(SignatureStatus::Good(format!("{} (gpg key {})",
+                                                        user,
+                                                        key_id)));
+            }
+            (Some("[GNUPG:]"), Some("BADSIG"), Some(key_id), _) => {
+                return Ok(SignatureStatus::Bad(format!("does not match gpg \
+                                                        key {}",
+                                                       key_id)));
+            }
+            (Some("[GNUPG:]"), Some("NO_PUBKEY"), Some(key_id), _) => {
+                return Ok(SignatureStatus::Bad(format!("no public key for \
+                                                        gpg key {}",
+                                                       key_id)));
+            }
+            _ => (),
+        }
+    }
+    Ok(SignatureStatus::Bad("gpg could not check signature".to_owned()))
+}
+
+/// Run a command with data on its stdin, collecting its output
+fn run_with_input(mut command: Command,
+                  data: &[u8])
+                  -> ::std::io::Result<::std::process::Output> {
+    let mut child = command.stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    child.stdin.take().expect("stdin is piped").write_all(data)?;
+    child.wait_with_output()
+}
+
+
+#[cfg(test)]
+mod test {
+    use dag::ObjectKey;
+    use super::*;
+
+    #[test]
+    fn test_ed25519_signature() {
+        let temp = in_mem_tempdir!();
+        let key_path = temp.path().join("key");
+        let signer = Ed25519Signer::generate(&key_path).unwrap();
+        assert!(Ed25519Signer::generate(&key_path).is_err(),
+                "Should not overwrite an existing key");
+        let reread = Ed25519Signer::read_key_file(&key_path).unwrap();
+        assert_eq!(reread.public_key(), signer.public_key());
+
+        let mut commit = Commit {
+            tree: ObjectKey::from("d3486ae9136e7856bc42212385ea797094475802"),
+            parents: vec![],
+            message: "Signed".to_owned(),
+            time: None,
+            signature: None,
+        };
+        assert_eq!(verify_commit(&commit).unwrap(), SignatureStatus::Unsigned);
+
+        sign_commit(&mut commit, &signer).unwrap();
+        match verify_commit(&commit).unwrap() {
+            SignatureStatus::Good(who) => {
+                assert!(who.contains(&signer.public_key()))
+            }
+            other => panic!("Expected good signature, got {:?}", other),
+        }
+
+        commit.message = "Tampered".to_owned();
+        match verify_commit(&commit).unwrap() {
+            SignatureStatus::Bad(_) => (),
+            other => panic!("Expected bad signature, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,187 @@
+//! Stash: set uncommitted changes aside, to bring them back later
+//!
+//! Saving a stash commits the work dir on top of HEAD, without moving the
+//! branch, and keeps that commit under a ref in the `stash/` namespace. The
+//! work dir then goes back to HEAD. Popping a stash merges the changes it made
+//! to its parent back into the work dir, even if HEAD has moved since, and
+//! deletes its ref.
+
+use dag::Commit;
+use dag::ObjectKey;
+use error::*;
+use object_store::ObjectStore;
+use pathspec::Pathspec;
+use std::cmp;
+use work_dir::WorkDir;
+
+/// Prefix of the refs that hold stashes, followed by a number
+pub const STASH_REF_PREFIX: &'static str = "stash/";
+
+pub fn is_stash_ref(name: &str) -> bool { name.starts_with(STASH_REF_PREFIX) }
+
+fn stash_number(name: &str) -> Option<u64> {
+    match is_stash_ref(name) {
+        true => name[STASH_REF_PREFIX.len()..].parse().ok(),
+        false => None,
+    }
+}
+
+/// A saved stash
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct StashEntry {
+    /// Name of the ref that holds it, such as `stash/0`
+    pub name: String,
+    pub hash: ObjectKey,
+    pub commit: Commit,
+}
+
+/// All stashes, newest first
+pub fn list(store: &ObjectStore) -> Result<Vec<StashEntry>> {
+    let mut stashes = Vec::new();
+    for (name, hash) in store.refs() {
+        if let Some(number) = stash_number(name) {
+            let entry = StashEntry {
+                name: name.to_owned(),
+                hash: *hash,
+                commit: store.open_commit(hash)?,
+            };
+            stashes.push((number, entry));
+        }
+    }
+    stashes.sort_by_key(|&(number, _)| cmp::Reverse(number));
+    Ok(stashes.into_iter().map(|(_, entry)| entry).collect())
+}
+
+/// Save the changes in the work dir as a new stash, then go back to HEAD
+///
+/// New files are saved along with changed ones, and removed from the work
+/// dir, but ignored files are left alone.
+pub fn save(work_dir: &mut WorkDir,
+            message: Option<String>)
+            -> Result<StashEntry> {
+    if work_dir.parents().len() > 1 {
+        bail!("Cannot stash during a merge. Commit the merge first");
+    }
+    if work_dir.state.subtree.is_some() {
+        bail!("Cannot stash with only a subtree checked out");
+    }
+    let head = match work_dir.head() {
+        Some(head) => head,
+        None => bail!("No commit to stash changes on"),
+    };
+    let path = work_dir.path().to_owned();
+    let tree = work_dir.hash_obj_file(Some(head), &path, &Pathspec::all())?;
+    if tree == work_dir.open_commit(&head)?.tree {
+        bail!("No changes to stash");
+    }
+
+    let message = message.unwrap_or_else(|| match work_dir.branch() {
+        Some(branch) => format!("WIP on {}", branch),
+        None => format!("WIP on {}", head),
+    });
+    let commit = Commit {
+        tree: tree,
+        parents: vec![head],
+        message: message,
+        time: Commit::current_time(),
+        signature: None,
+    };
+    let hash = work_dir.store_object(&commit)?;
+    let number = work_dir.refs()
+        .keys()
+        .filter_map(|name| stash_number(name))
+        .max()
+        .map_or(0, |n| n + 1);
+    let name = format!("{}{}", STASH_REF_PREFIX, number);
+    work_dir.update_ref(name.clone(), hash)?;
+    work_dir.extract_object(&head, &path)?;
+    Ok(StashEntry {
+        name: name,
+        hash: hash,
+        commit: commit,
+    })
+}
+
+/// Apply a stash to the work dir, by default the newest, and delete it
+///
+/// The stash can be named by its ref or by its number alone. If applying it
+/// conflicts with changes in the work dir, the stash is kept and a
+/// `MergeConflict` is returned.
+pub fn pop(work_dir: &mut WorkDir, name: Option<&str>) -> Result<StashEntry> {
+    if work_dir.parents().len() > 1 {
+        bail!("Cannot apply a stash during a merge. Commit the merge first");
+    }
+    let mut stashes = list(work_dir)?.into_iter();
+    let stash = match name {
+        Some(name) => {
+            let name = match is_stash_ref(name) {
+                true => name.to_owned(),
+                false => format!("{}{}", STASH_REF_PREFIX, name),
+            };
+            stashes.find(|stash| stash.name == name)
+                .ok_or_else(|| format!("No such stash: {}", name))?
+        }
+        None => stashes.next().ok_or("No stashes")?,
+    };
+    let base = stash.commit.parents.first().cloned();
+    work_dir.apply_changes(base, &[stash.hash], "Unstashing")?;
+    work_dir.remove_ref(&stash.name)?;
+    Ok(stash)
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+    use super::*;
+    use work_dir::StatusOptions;
+    use work_dir::test::create_temp_work_dir;
+    use work_dir::test::read_wd_file;
+    use work_dir::test::write_wd_file;
+
+    #[test]
+    fn test_stash() {
+        let (_temp, wd_path, mut wd) = create_temp_work_dir().unwrap();
+
+        write_wd_file(&wd_path, "shared", "base");
+        write_wd_file(&wd_path, "other", "base");
+        let base = wd.commit("Base".to_owned(), false, None).unwrap().hash;
+        assert!(save(&mut wd, None).is_err(), "Nothing to stash");
+
+        write_wd_file(&wd_path, "shared", "mine");
+        write_wd_file(&wd_path, "new", "new");
+        let stash = save(&mut wd, None).unwrap();
+        assert_eq!(stash.name, "stash/0");
+        assert_eq!(stash.commit.message, "WIP on master");
+        assert_eq!(stash.commit.parents, vec![base]);
+        assert_eq!(read_wd_file(&wd_path, "shared"), "base");
+        assert!(!wd_path.join("new").exists());
+        assert!(wd.status(&StatusOptions::default(), None, None)
+            .unwrap()
+            .is_empty());
+        assert_eq!(wd.try_find_ref("master"), Some(base));
+
+        write_wd_file(&wd_path, "other", "more");
+        let second = save(&mut wd, Some("Other".to_owned())).unwrap();
+        assert_eq!(second.name, "stash/1");
+        assert_eq!(list(&wd).unwrap(), vec![second, stash.clone()]);
+
+        // Pop onto a newer commit, which changed a different file
+        write_wd_file(&wd_path, "other", "next");
+        wd.commit("Next".to_owned(), false, None).unwrap();
+        assert_eq!(pop(&mut wd, Some("0")).unwrap(), stash);
+        assert_eq!(read_wd_file(&wd_path, "shared"), "mine");
+        assert_eq!(read_wd_file(&wd_path, "other"), "next");
+        assert_eq!(read_wd_file(&wd_path, "new"), "new");
+        assert_eq!(list(&wd).unwrap().len(), 1);
+
+        // The remaining stash changed the same file as the new commit
+        match pop(&mut wd, None) {
+            Err(Error(ErrorKind::MergeConflict(paths), _)) => {
+                assert_eq!(paths, vec![PathBuf::from("other")])
+            }
+            other => panic!("Expected MergeConflict, got {:?}", other),
+        }
+        assert_eq!(list(&wd).unwrap().len(), 1, "Stash should be kept");
+    }
+}
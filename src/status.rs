@@ -2,6 +2,7 @@
 
 use dag::ObjectKey;
 use dag::ObjectSize;
+use std::fmt;
 use std::path::PathBuf;
 
 /// Status of an individual file or dir, as compared to a commit
@@ -14,6 +15,8 @@ pub enum Status {
     Unchanged,
     Modified,
     MaybeModified,
+    /// Could not be read, so its status is not known
+    Unreadable,
 }
 
 impl Status {
@@ -27,6 +30,7 @@ impl Status {
             &Status::Unchanged => " ",
             &Status::Modified => "M",
             &Status::MaybeModified => "m",
+            &Status::Unreadable => "!",
         }
     }
 
@@ -40,7 +44,8 @@ impl Status {
             &Status::MaybeModified => true,
 
             &Status::Ignored |
-            &Status::Delete => false,
+            &Status::Delete |
+            &Status::Unreadable => false,
         }
     }
 
@@ -53,12 +58,75 @@ impl Status {
 
             &Status::Unchanged |
             &Status::Ignored |
-            &Status::Delete => false,
+            &Status::Delete |
+            &Status::Unreadable => false,
         }
     }
 }
 
 
+/// A changed (or ignored) file or directory, as reported by status
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct StatusEntry {
+    /// Path relative to the top of the comparison
+    pub path: PathBuf,
+    pub is_dir: bool,
+    /// Status compared to each source, such as each parent of the work dir
+    pub status: Vec<Status>,
+    /// Size of the new version, or of the old version if deleted
+    pub size: ObjectSize,
+    /// Hash of the new version, or of the old version if deleted
+    ///
+    /// Files in the work dir only have a hash if it is cached.
+    pub hash: Option<ObjectKey>,
+}
+
+impl StatusEntry {
+    /// Entry for a path, from the nodes that were compared
+    pub fn from_nodes(path: PathBuf,
+                      status: Vec<Status>,
+                      srcs: &[Option<ComparableNode>],
+                      targ: Option<&ComparableNode>)
+                      -> Self {
+        let node = targ.or(srcs.iter().filter_map(|n| n.as_ref()).next());
+        StatusEntry {
+            path: path,
+            is_dir: targ.map(|n| n.is_treeish).unwrap_or(false),
+            status: status,
+            size: node.map(|n| n.file_size).unwrap_or(0),
+            hash: node.and_then(|n| n.hash),
+        }
+    }
+
+    /// Entry for a path that could not be read, compared to each source
+    pub fn unreadable(path: PathBuf, is_dir: bool, sources: usize) -> Self {
+        StatusEntry {
+            path: path,
+            is_dir: is_dir,
+            status: vec![Status::Unreadable; sources],
+            size: 0,
+            hash: None,
+        }
+    }
+
+    /// Status codes, one per source
+    pub fn codes(&self) -> String {
+        self.status.iter().map(|s| s.code()).collect()
+    }
+}
+
+/// Status codes and path, as printed by `status`
+impl fmt::Display for StatusEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.codes(), self.path.display())?;
+        if self.is_dir {
+            write!(f, "/")?;
+        }
+        Ok(())
+    }
+}
+
+
 #[derive(Debug,Clone)]
 pub struct ComparableNode {
     pub is_treeish: bool,
@@ -0,0 +1,355 @@
+//! Storage backends: where an object store keeps its object files
+//!
+//! The object store names each object file by a relative path with `/`
+//! separators, such as `objects/ab/cdef...`, and reads and writes it through a
+//! `StorageBackend`. The default, `DiskStorage`, keeps them in the store's
+//! directory. Other backends can keep them elsewhere, without the layers above
+//! the object store knowing.
+//!
+//! New files are written under a temporary name, then renamed into place once
+//! complete and verified, so a reader never sees a partial object. Refs and
+//! other small metadata are not object files, and stay in the store's
+//! directory whatever the backend.
+//!
+//! With the `testing` feature, `MemoryStorage` keeps object files in memory,
+//! and `ObjectStore::in_memory` keeps its refs and other metadata there too,
+//! so that tests of code that takes an `ObjectStore` need not touch the disk.
+
+use error::*;
+use fsutil;
+#[cfg(any(test, feature = "testing"))]
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+#[cfg(any(test, feature = "testing"))]
+use std::sync::Arc;
+#[cfg(any(test, feature = "testing"))]
+use std::sync::Mutex;
+#[cfg(any(test, feature = "testing"))]
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+/// Size and modification time of a stored file
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub struct StoredFile {
+    pub size: u64,
+    /// Seconds since the Unix epoch
+    pub modified: u64,
+}
+
+/// Somewhere to keep object files by name
+///
+/// A backend is shared between threads, for example by fsck's workers.
+pub trait StorageBackend: Send + Sync {
+    /// Open a file for reading
+    fn get(&self, name: &str) -> Result<Box<io::Read + Send>>;
+
+    /// Create a file for writing, replacing any file already there
+    fn put(&self, name: &str) -> Result<Box<io::Write + Send>>;
+
+    /// Is there a complete file with this name?
+    fn exists(&self, name: &str) -> bool;
+
+    fn stat(&self, name: &str) -> Result<StoredFile>;
+
+    /// Names of all files below a directory, in no particular order
+    fn list(&self, dir: &str) -> Result<Vec<String>>;
+
+    /// Move a file, replacing any already at the destination
+    ///
+    /// This is how a finished temporary file is put in place, so it should
+    /// be atomic if the backend allows.
+    fn rename(&self, from: &str, to: &str) -> Result<()>;
+
+    fn remove(&self, name: &str) -> Result<()>;
+
+    /// Make sure a file has reached durable storage
+    fn sync(&self, _name: &str) -> Result<()> { Ok(()) }
+
+    /// Remove anything left empty by renames and removals, such as empty
+    /// directories
+    fn tidy(&self, _dir: &str) -> Result<()> { Ok(()) }
+}
+
+/// Storage in a directory on disk
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct DiskStorage {
+    root: PathBuf,
+}
+
+impl DiskStorage {
+    pub fn new(root: PathBuf) -> Self { DiskStorage { root: root } }
+
+    pub fn root(&self) -> &Path { &self.root }
+
+    fn path(&self, name: &str) -> PathBuf { self.root.join(name) }
+
+    /// Name of a file from its path under the root
+    fn name(&self, path: &Path) -> Result<String> {
+        let parts = path.strip_prefix(&self.root)?
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        Ok(parts.join("/"))
+    }
+}
+
+impl StorageBackend for DiskStorage {
+    fn get(&self, name: &str) -> Result<Box<io::Read + Send>> {
+        Ok(Box::new(fs::File::open(self.path(name))?))
+    }
+
+    fn put(&self, name: &str) -> Result<Box<io::Write + Send>> {
+        let path = self.path(name);
+        fsutil::create_parents(&path)?;
+        let file = fs::File::create(&path).map_err(|e| {
+                io::Error::new(e.kind(), format!("{}", path.display()))
+            })?;
+        Ok(Box::new(file))
+    }
+
+    fn exists(&self, name: &str) -> bool { self.path(name).is_file() }
+
+    fn stat(&self, name: &str) -> Result<StoredFile> {
+        let meta = self.path(name).metadata()?;
+        Ok(StoredFile {
+            size: meta.len(),
+            modified: meta.modified()?
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        })
+    }
+
+    fn list(&self, dir: &str) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        let mut dirs = vec![self.path(dir)];
+        while let Some(dir) = dirs.pop() {
+            if !dir.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(&dir)? {
+                let entry = entry?;
+                if entry.file_type()?.is_dir() {
+                    dirs.push(entry.path());
+                } else {
+                    names.push(self.name(&entry.path())?);
+                }
+            }
+        }
+        Ok(names)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let dest = self.path(to);
+        fsutil::create_parents(&dest)?;
+        fs::rename(self.path(from), &dest)?;
+        Ok(())
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        fs::remove_file(self.path(name))?;
+        Ok(())
+    }
+
+    fn sync(&self, name: &str) -> Result<()> {
+        fs::File::open(self.path(name))?.sync_all()?;
+        Ok(())
+    }
+
+    fn tidy(&self, dir: &str) -> Result<()> {
+        let dir = self.path(dir);
+        if dir.is_dir() {
+            fsutil::remove_empty_dirs(&dir)?;
+        }
+        Ok(())
+    }
+}
+
+/// Storage in memory, for tests
+///
+/// Clones share the same files, so a test can keep one to examine what the
+/// store wrote. A file being written appears when its writer is dropped.
+#[cfg(any(test, feature = "testing"))]
+#[derive(Debug,Clone,Default)]
+pub struct MemoryStorage {
+    files: Arc<Mutex<BTreeMap<String, MemoryFile>>>,
+}
+
+#[cfg(any(test, feature = "testing"))]
+#[derive(Debug,Clone)]
+struct MemoryFile {
+    content: SharedBytes,
+    modified: u64,
+}
+
+/// File content that readers can share without copying
+#[cfg(any(test, feature = "testing"))]
+#[derive(Debug,Clone)]
+struct SharedBytes(Arc<Vec<u8>>);
+
+#[cfg(any(test, feature = "testing"))]
+impl AsRef<[u8]> for SharedBytes {
+    fn as_ref(&self) -> &[u8] { &self.0 }
+}
+
+/// Writes a file to memory storage when dropped
+#[cfg(any(test, feature = "testing"))]
+struct MemoryWriter {
+    files: Arc<Mutex<BTreeMap<String, MemoryFile>>>,
+    name: String,
+    content: Vec<u8>,
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl io::Write for MemoryWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.content.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl Drop for MemoryWriter {
+    fn drop(&mut self) {
+        let content = ::std::mem::replace(&mut self.content, Vec::new());
+        let file = MemoryFile {
+            content: SharedBytes(Arc::new(content)),
+            modified: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        };
+        self.files.lock().unwrap().insert(self.name.clone(), file);
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl MemoryStorage {
+    pub fn new() -> Self { Self::default() }
+
+    fn not_found(name: &str) -> Error {
+        io::Error::new(io::ErrorKind::NotFound, name.to_owned()).into()
+    }
+
+    fn file(&self, name: &str) -> Result<MemoryFile> {
+        self.files
+            .lock()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| Self::not_found(name))
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl StorageBackend for MemoryStorage {
+    fn get(&self, name: &str) -> Result<Box<io::Read + Send>> {
+        Ok(Box::new(io::Cursor::new(self.file(name)?.content)))
+    }
+
+    fn put(&self, name: &str) -> Result<Box<io::Write + Send>> {
+        Ok(Box::new(MemoryWriter {
+            files: self.files.clone(),
+            name: name.to_owned(),
+            content: Vec::new(),
+        }))
+    }
+
+    fn exists(&self, name: &str) -> bool {
+        self.files.lock().unwrap().contains_key(name)
+    }
+
+    fn stat(&self, name: &str) -> Result<StoredFile> {
+        let file = self.file(name)?;
+        Ok(StoredFile {
+            size: file.content.0.len() as u64,
+            modified: file.modified,
+        })
+    }
+
+    fn list(&self, dir: &str) -> Result<Vec<String>> {
+        let prefix = format!("{}/", dir);
+        Ok(self.files
+            .lock()
+            .unwrap()
+            .range(prefix.clone()..)
+            .map(|(name, _)| name)
+            .take_while(|name| name.starts_with(&prefix))
+            .cloned()
+            .collect())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let mut files = self.files.lock().unwrap();
+        let file = files.remove(from).ok_or_else(|| Self::not_found(from))?;
+        files.insert(to.to_owned(), file);
+        Ok(())
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        self.files
+            .lock()
+            .unwrap()
+            .remove(name)
+            .map(|_| ())
+            .ok_or_else(|| Self::not_found(name))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+    use std::io::Write;
+    use super::*;
+
+    /// Check the behavior that every backend should share
+    fn check_storage<S: StorageBackend>(storage: &S) {
+        storage.put("tmp").unwrap().write_all(b"content").unwrap();
+        assert!(storage.exists("tmp"));
+        storage.rename("tmp", "objects/ab/cdef").unwrap();
+        assert!(!storage.exists("tmp"));
+        assert_eq!(storage.stat("objects/ab/cdef").unwrap().size, 7);
+
+        let mut content = String::new();
+        storage.get("objects/ab/cdef")
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "content");
+        assert_eq!(storage.list("objects").unwrap(), vec!["objects/ab/cdef"]);
+        assert!(storage.list("objects/cd").unwrap().is_empty());
+
+        assert!(storage.list("obj").unwrap().is_empty());
+
+        storage.remove("objects/ab/cdef").unwrap();
+        assert!(!storage.exists("objects/ab/cdef"));
+        assert!(storage.get("objects/ab/cdef").is_err());
+        assert!(storage.rename("objects/ab/cdef", "other").is_err());
+    }
+
+    #[test]
+    fn test_disk_storage() {
+        let temp = in_mem_tempdir!();
+        let storage = DiskStorage::new(temp.path().to_owned());
+        check_storage(&storage);
+        storage.tidy("objects").unwrap();
+        assert!(!temp.path().join("objects/ab").exists());
+    }
+
+    #[test]
+    fn test_memory_storage() {
+        let storage = MemoryStorage::new();
+        check_storage(&storage);
+        {
+            let mut writer = storage.put("tmp").unwrap();
+            writer.write_all(b"partial").unwrap();
+            assert!(!storage.exists("tmp"), "Not there until written");
+        }
+        assert!(storage.clone().exists("tmp"), "Clones share files");
+    }
+}
@@ -0,0 +1,122 @@
+//! Layout of object files on disk, recorded in the store's `format` file
+//!
+//! Object files are spread across directories named for the first digits of
+//! their hashes, so that no one directory gets too large. The default fan-out
+//! of one level of two digits gives 256 directories, which is plenty for most
+//! repositories. With millions of objects, each of those holds thousands of
+//! files, which some filesystems handle badly, so a store can use more
+//! levels or wider names.
+//!
+//! Unlike the config, the format can change over the life of a store, when
+//! `dmv repack` moves the objects into a new layout. Stores from before the
+//! format file use the default layout.
+
+use error::*;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Most hex digits used for directory names, over all levels
+///
+/// This keeps a short hash (8 digits) enough to find an object.
+pub const MAX_FAN_OUT_DIGITS: usize = 8;
+
+/// How object files are spread across directories
+///
+/// Written as `<depth>x<width>`, so `2x2` puts object `abcdef...` at
+/// `ab/cd/ef...`.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash,RustcEncodable,RustcDecodable)]
+pub struct FanOut {
+    /// Levels of directories
+    pub depth: usize,
+    /// Hex digits in each directory name
+    pub width: usize,
+}
+
+impl FanOut {
+    pub fn new(depth: usize, width: usize) -> Result<Self> {
+        if depth == 0 || width == 0 || depth * width > MAX_FAN_OUT_DIGITS {
+            bail!("Bad fan-out {}x{}: need at least one level of at least one \
+                   digit, and no more than {} digits in all",
+                  depth,
+                  width,
+                  MAX_FAN_OUT_DIGITS);
+        }
+        Ok(FanOut {
+            depth: depth,
+            width: width,
+        })
+    }
+
+    /// Hex digits used for directory names
+    pub fn digits(&self) -> usize { self.depth * self.width }
+
+    /// Path of an object file, relative to the objects directory
+    pub fn object_path(&self, hex: &str) -> PathBuf {
+        let mut path = PathBuf::new();
+        for level in 0..self.depth {
+            path.push(&hex[level * self.width..(level + 1) * self.width]);
+        }
+        path.push(&hex[self.digits()..]);
+        path
+    }
+}
+
+/// One level of two digits, as used by stores from before the format file
+impl Default for FanOut {
+    fn default() -> Self {
+        FanOut {
+            depth: 1,
+            width: 2,
+        }
+    }
+}
+
+impl fmt::Display for FanOut {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}x{}", self.depth, self.width)
+    }
+}
+
+impl FromStr for FanOut {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let mut split = s.splitn(2, 'x');
+        match (split.next().map(str::parse), split.next().map(str::parse)) {
+            (Some(Ok(depth)), Some(Ok(width))) => FanOut::new(depth, width),
+            _ => bail!("Bad fan-out '{}': expected <depth>x<width>", s),
+        }
+    }
+}
+
+/// Contents of the store's format file
+#[derive(Debug,Clone,Default,PartialEq,Eq,Hash,RustcEncodable,RustcDecodable)]
+pub struct StoreFormat {
+    pub fan_out: FanOut,
+    /// Layout being moved away from by an unfinished repack
+    ///
+    /// Objects not yet moved are still found there.
+    pub migrating_from: Option<FanOut>,
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fan_out() {
+        let hex = "d3486ae9136e7856bc42212385ea797094475802";
+        assert_eq!(FanOut::default().object_path(hex),
+                   PathBuf::from("d3/486ae9136e7856bc42212385ea797094475802"));
+        let fan_out: FanOut = "3x2".parse().unwrap();
+        assert_eq!(fan_out.to_string(), "3x2");
+        assert_eq!(fan_out.object_path(hex).to_str().unwrap(),
+                   "d3/48/6a/e9136e7856bc42212385ea797094475802");
+
+        assert!("0x2".parse::<FanOut>().is_err());
+        assert!("3x3".parse::<FanOut>().is_err(), "Too many digits");
+        assert!("2".parse::<FanOut>().is_err());
+        assert!("axb".parse::<FanOut>().is_err());
+    }
+}
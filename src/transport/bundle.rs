@@ -0,0 +1,295 @@
+//! Bundles: objects and ref updates in one file, to carry between repositories
+//!
+//! A bundle moves history with no connection between the repositories, such
+//! as on an external disk. It starts with a line `dmv bundle <version>
+//! <hash algorithm>`, followed by frames of the pipe protocol (see
+//! `protocol`):
+//!
+//! - `Have` for each commit the receiver must already have, from the start of
+//!   the range the bundle was made from
+//! - `UpdateRef` for each ref to set once the objects are stored
+//! - `Put` for each object, children first, as in any other transfer
+//!
+//! Applying a bundle stores each object in turn, checking it against its
+//! hash, so an interrupted apply can simply be run again.
+
+use dag::HashAlgorithm;
+use dag::ObjectKey;
+use dag::ObjectSize;
+use error::*;
+use object_store::ObjectStore;
+use object_store::RefMap;
+use std::collections::HashSet;
+use std::io;
+use std::io::BufRead;
+use std::io::Read;
+use std::io::Write;
+use super::ObjectPeer;
+use super::PeerStore;
+use super::RefUpdate;
+use super::Request;
+use super::TransferStats;
+use super::transfer_objects;
+
+/// Version of the bundle format written by this build
+pub const BUNDLE_VERSION: u32 = 1;
+
+/// Start of the first line of a bundle
+const BUNDLE_MAGIC: &'static str = "dmv bundle";
+
+/// Write a bundle of everything needed to set the given refs
+///
+/// Nothing reachable from the `prerequisites` is included, and the receiver
+/// must have them before applying the bundle.
+pub fn create_bundle(object_store: &ObjectStore,
+                     refs: &RefMap,
+                     prerequisites: &[ObjectKey],
+                     writer: &mut Write)
+                     -> Result<TransferStats> {
+    let algorithm = object_store.hash_algorithm();
+    writeln!(writer, "{} {} {}", BUNDLE_MAGIC, BUNDLE_VERSION, algorithm)?;
+    for prerequisite in prerequisites {
+        Request::Have(*prerequisite).write_to(writer)?;
+    }
+    for (name, hash) in refs {
+        Request::UpdateRef(name.to_owned(), *hash).write_to(writer)?;
+    }
+    let (known, missing) =
+        object_store.find_reachable(prerequisites.iter().cloned())?;
+    if let Some(&(missing, _)) = missing.first() {
+        bail!(ErrorKind::ObjectNotFound(missing));
+    }
+    let mut bundle = BundleWriter {
+        writer: writer,
+        algorithm: algorithm,
+        known: known,
+    };
+    transfer_objects(&mut PeerStore(object_store),
+                     &mut bundle,
+                     refs.values(),
+                     None,
+                     false)
+        .map(|outcome| outcome.stats)
+}
+
+/// The receiving end of a transfer into a bundle
+struct BundleWriter<'a> {
+    writer: &'a mut Write,
+    algorithm: HashAlgorithm,
+    /// Objects the receiver will have: reachable from prerequisites, or
+    /// already written
+    known: HashSet<ObjectKey>,
+}
+
+impl<'a> ObjectPeer for BundleWriter<'a> {
+    fn hash_algorithm(&mut self) -> Result<HashAlgorithm> {
+        Ok(self.algorithm)
+    }
+    fn has_object(&mut self, key: &ObjectKey) -> Result<bool> {
+        Ok(self.known.contains(key))
+    }
+    fn missing_objects(&mut self,
+                       keys: &[ObjectKey])
+                       -> Result<Vec<ObjectKey>> {
+        Ok(keys.iter().filter(|k| !self.known.contains(k)).cloned().collect())
+    }
+    fn read_object_file(&mut self,
+                        key: &ObjectKey)
+                        -> Result<Box<io::BufRead>> {
+        bail!("Cannot read {} back from a bundle being written", key)
+    }
+    fn write_object_file(&mut self,
+                         key: &ObjectKey,
+                         reader: &mut io::Read)
+                         -> Result<ObjectSize> {
+        let mut raw = Vec::new();
+        reader.read_to_end(&mut raw)?;
+        Request::Put(*key, raw.clone()).write_to(self.writer)?;
+        self.known.insert(*key);
+        Ok(raw.len() as ObjectSize)
+    }
+}
+
+/// Result of applying a bundle
+#[derive(Debug,Clone,Default)]
+pub struct AppliedBundle {
+    /// Refs that were set
+    pub updates: Vec<RefUpdate>,
+    /// Refs that were left alone, because setting them would lose commits
+    pub rejected: Vec<RefUpdate>,
+    /// Objects stored, not counting those the repository already had
+    pub stats: TransferStats,
+}
+
+/// Store the objects of a bundle, then set its refs
+///
+/// A ref is only set if it is new or the change is a fast-forward, unless
+/// `force` is given.
+pub fn apply_bundle(object_store: &mut ObjectStore,
+                    reader: &mut BufRead,
+                    force: bool)
+                    -> Result<AppliedBundle> {
+    let mut first_line = String::new();
+    reader.read_line(&mut first_line)?;
+    if !first_line.starts_with(BUNDLE_MAGIC) {
+        bail!("Not a dmv bundle");
+    }
+    let mut words = first_line[BUNDLE_MAGIC.len()..].split_whitespace();
+    let version = words.next()
+        .and_then(|v| v.parse::<u32>().ok())
+        .ok_or("Bundle has no version")?;
+    if version > BUNDLE_VERSION {
+        bail!("Bundle is version {}, but this dmv only reads up to version {}",
+              version,
+              BUNDLE_VERSION);
+    }
+    let algorithm = words.next()
+        .ok_or("Bundle has no hash algorithm")?
+        .parse::<HashAlgorithm>()?;
+    if algorithm != object_store.hash_algorithm() {
+        bail!("Bundle uses {} hashes, but this repository uses {}",
+              algorithm,
+              object_store.hash_algorithm());
+    }
+
+    let mut applied = AppliedBundle::default();
+    let mut refs = RefMap::new();
+    while let Some(request) = Request::read_from(reader)? {
+        match request {
+            Request::Have(ref key) if !object_store.has_object(key) => {
+                bail!("Bundle needs commit {}, which this repository does \
+                       not have. Fetch it or apply an earlier bundle first.",
+                      key)
+            }
+            Request::Have(_) => {}
+            Request::UpdateRef(name, key) => {
+                refs.insert(name, key);
+            }
+            Request::Put(key, raw) => {
+                let size = object_store.store_raw_object(&key,
+                                                      &mut raw.as_slice())?;
+                if size > 0 {
+                    applied.stats.objects += 1;
+                    applied.stats.bytes += size;
+                }
+            }
+            _ => bail!("Unexpected request in bundle"),
+        }
+    }
+
+    for (name, new) in refs {
+        if !object_store.has_object(&new) {
+            bail!("Bundle sets {} to {}, but does not contain it", name, new);
+        }
+        let old = object_store.try_find_ref(&name);
+        if old == Some(new) {
+            continue;
+        }
+        let fast_forward = match old {
+            None => true,
+            Some(ref old) => {
+                object_store.has_object(old) &&
+                object_store.is_ancestor(old, &new)?
+            }
+        };
+        let update = RefUpdate {
+            name: name,
+            old: old,
+            new: new,
+        };
+        if fast_forward || force {
+            object_store.update_ref(update.name.clone(), new)?;
+            applied.updates.push(update);
+        } else {
+            applied.rejected.push(update);
+        }
+    }
+    Ok(applied)
+}
+
+
+#[cfg(test)]
+mod test {
+    use dag::Blob;
+    use dag::Commit;
+    use dag::Tree;
+    use object_store::test::create_temp_repository;
+    use super::*;
+
+    fn commit(store: &ObjectStore,
+              files: &[(&str, &str)],
+              parents: Vec<ObjectKey>)
+              -> ObjectKey {
+        let mut tree = Tree::new();
+        for &(name, content) in files {
+            let blob = store.store_object(&Blob::from(content)).unwrap();
+            tree.insert(name, blob);
+        }
+        let tree = store.store_object(&tree).unwrap();
+        store.store_object(&Commit {
+                tree: tree,
+                parents: parents,
+                message: "Commit".to_owned(),
+                time: None,
+                signature: None,
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_bundle() {
+        let (_temp, source) = create_temp_repository().unwrap();
+        let first = commit(&source, &[("a", "one")], vec![]);
+        let second = commit(&source,
+                            &[("a", "one"), ("b", "two")],
+                            vec![first]);
+        let refs = map!{ RefMap::new(), "master".to_owned() => second, };
+
+        // An incremental bundle leaves out what the receiver has
+        let mut incremental = Vec::new();
+        let stats = create_bundle(&source, &refs, &[first], &mut incremental)
+            .unwrap();
+        assert_eq!(stats.objects, 3, "Commit, tree, and the new blob");
+        let (_temp, mut dest) = create_temp_repository().unwrap();
+        match apply_bundle(&mut dest, &mut incremental.as_slice(), false) {
+            Err(ref e) if e.to_string().contains("does not have") => {}
+            other => panic!("Expected missing prerequisite, got {:?}", other),
+        }
+
+        let mut full = Vec::new();
+        create_bundle(&source, &refs, &[], &mut full).unwrap();
+        let applied = apply_bundle(&mut dest, &mut full.as_slice(), false)
+            .unwrap();
+        assert_eq!(applied.stats.objects, 6);
+        assert_eq!(dest.try_find_ref("master"), Some(second));
+        assert!(dest.find_reachable(vec![second]).unwrap().1.is_empty());
+        let again = apply_bundle(&mut dest, &mut full.as_slice(), false)
+            .unwrap();
+        assert_eq!(again.stats.objects, 0);
+        assert!(again.updates.is_empty());
+
+        // Diverged history is only applied with force
+        let other = commit(&source, &[("c", "three")], vec![first]);
+        let refs = map!{ RefMap::new(), "master".to_owned() => other, };
+        let mut bundle = Vec::new();
+        create_bundle(&source, &refs, &[first], &mut bundle).unwrap();
+        let applied = apply_bundle(&mut dest, &mut bundle.as_slice(), false)
+            .unwrap();
+        assert_eq!(applied.rejected.len(), 1);
+        assert_eq!(dest.try_find_ref("master"), Some(second));
+        let applied = apply_bundle(&mut dest, &mut bundle.as_slice(), true)
+            .unwrap();
+        assert_eq!(applied.updates[0].old, Some(second));
+        assert_eq!(dest.try_find_ref("master"), Some(other));
+
+        // Corruption is caught by the hash check
+        let mut corrupt = full.clone();
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 1;
+        let (_temp, mut dest) = create_temp_repository().unwrap();
+        assert!(apply_bundle(&mut dest, &mut corrupt.as_slice(), false)
+            .is_err());
+        assert!(apply_bundle(&mut dest, &mut &b"not a bundle\n"[..], false)
+            .is_err());
+    }
+}
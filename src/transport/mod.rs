@@ -0,0 +1,1607 @@
+//! Access to other repositories, for listing refs and transferring objects
+
+use constants::DEFAULT_BRANCH_NAME;
+use constants::HIDDEN_DIR_NAME;
+use dag::HashAlgorithm;
+use dag::Object;
+use dag::ObjectHeader;
+use dag::ObjectKey;
+use dag::ObjectSize;
+use dag::ObjectType;
+use error::*;
+use lock::LockMode;
+use object_store::MediaTypeMap;
+use object_store::ObjectStore;
+use object_store::RefMap;
+use progress::RateLimitedReader;
+use progress::RateLimiter;
+use rolling_hash::ChunkParams;
+use stash::is_stash_ref;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashSet;
+use std::io;
+use std::io::Read;
+use std::iter;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+mod protocol;
+pub use self::protocol::*;
+
+mod bundle;
+pub use self::bundle::*;
+
+mod ssh;
+pub use self::ssh::*;
+
+/// Prefix for refs that track the branches of remotes
+pub const TRACKING_REF_PREFIX: &'static str = "remotes/";
+
+/// Name of the local ref that tracks a branch on a remote
+pub fn tracking_ref_name(remote: &str, branch: &str) -> String {
+    format!("{}{}/{}", TRACKING_REF_PREFIX, remote, branch)
+}
+
+/// Is this the name of a remote-tracking ref?
+pub fn is_tracking_ref(name: &str) -> bool {
+    name.starts_with(TRACKING_REF_PREFIX)
+}
+
+/// Configuration for a named remote repository
+#[derive(Debug,Clone,PartialEq,Eq,Hash,RustcEncodable,RustcDecodable)]
+pub struct Remote {
+    /// Path or URL of the remote repository
+    pub url: String,
+    /// Branch to push when not on a branch, and to compare against
+    pub branch: Option<String>,
+}
+
+impl Remote {
+    pub fn new(url: String) -> Self {
+        Remote {
+            url: url,
+            branch: None,
+        }
+    }
+}
+
+/// Can this be used as a remote name?
+///
+/// Slashes are not allowed, since the name becomes part of tracking ref
+/// names, and neither are `:` or `.`, so names can't be mistaken for
+/// paths or URLs.
+pub fn is_valid_remote_name(name: &str) -> bool {
+    !name.is_empty() &&
+    name.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Map of remote name to remote configuration
+pub type RemoteMap = BTreeMap<String, Remote>;
+
+/// A connection to another repository
+pub trait Transport {
+    /// Human-readable description of the other repository
+    fn describe(&self) -> String;
+
+    /// List all refs in the other repository
+    fn list_refs(&mut self) -> Result<RefMap>;
+
+    /// Hash algorithm used by the other repository
+    fn hash_algorithm(&mut self) -> Result<HashAlgorithm>;
+
+    /// Chunking parameters used by the other repository
+    fn chunking(&mut self) -> Result<ChunkParams>;
+
+    /// Media types the other repository recorded for any of these files
+    fn media_types(&mut self, keys: &[ObjectKey]) -> Result<MediaTypeMap>;
+
+    /// Record media types of files in the other repository
+    fn record_media_types(&mut self, types: &MediaTypeMap) -> Result<()>;
+
+    /// Open a raw object file (header and content) for reading
+    fn read_object_file(&mut self,
+                        key: &ObjectKey)
+                        -> Result<Box<io::BufRead>>;
+
+    /// Does the other repository have the given object?
+    fn has_object(&mut self, key: &ObjectKey) -> Result<bool>;
+
+    /// Which of the given objects does the other repository lack?
+    ///
+    /// This asks about each object in turn. Transports over a connection
+    /// should override it to ask in one round trip, since a large file can
+    /// have thousands of chunks.
+    fn missing_objects(&mut self,
+                       keys: &[ObjectKey])
+                       -> Result<Vec<ObjectKey>> {
+        let mut missing = Vec::new();
+        for key in keys {
+            if !self.has_object(key)? {
+                missing.push(*key);
+            }
+        }
+        Ok(missing)
+    }
+
+    /// Store a raw object file (header and content) in the other repository
+    fn write_object_file(&mut self,
+                         key: &ObjectKey,
+                         reader: &mut io::Read)
+                         -> Result<ObjectSize>;
+
+    /// Set a ref in the other repository, if it still points at `expected`
+    ///
+    /// None for `expected` means the ref must not exist yet. This is a
+    /// compare-and-swap: if another client has moved the ref since it was
+    /// listed, the update fails and the ref is left alone.
+    fn update_ref(&mut self,
+                  name: &str,
+                  expected: Option<ObjectKey>,
+                  hash: ObjectKey)
+                  -> Result<()>;
+}
+
+/// Connect to a repository given a path or URL
+///
+/// Supported forms:
+///
+/// - Local path, either to a work dir or to a hidden object store dir
+/// - `file://` URL of a local path
+/// - `ssh://[user@]host[:port]/path` or `[user@]host:path`, which runs
+///   `dmv serve --stdio` on the host
+pub fn connect(url: &str) -> Result<Box<Transport>> {
+    if let Some(path) = url.strip_prefix("file://") {
+        LocalTransport::open(Path::new(path))
+            .map(|t| Box::new(t) as Box<Transport>)
+    } else if let Some(ssh_url) = SshUrl::parse(url) {
+        SshTransport::connect(&ssh_url).map(|t| Box::new(t) as Box<Transport>)
+    } else if url.contains("://") {
+        bail!("Unsupported transport for remote URL: {}", url)
+    } else {
+        LocalTransport::open(Path::new(url))
+            .map(|t| Box::new(t) as Box<Transport>)
+    }
+}
+
+/// Make a path or URL usable from any directory
+///
+/// Relative local paths are made absolute. URLs are returned unchanged.
+pub fn absolute_url(url: &str) -> Result<String> {
+    if url.contains("://") || SshUrl::parse(url).is_some() {
+        return Ok(url.to_owned());
+    }
+    let path = Path::new(url).canonicalize()
+        .chain_err(|| format!("Could not find repository {}", url))?;
+    Ok(path.to_string_lossy().into_owned())
+}
+
+/// Pick the branch to check out from a set of remote branches
+///
+/// This is the default branch name if present, or else the first branch,
+/// not counting tracking refs or stashes.
+pub fn default_branch(refs: &RefMap) -> Option<(String, ObjectKey)> {
+    refs.get(DEFAULT_BRANCH_NAME)
+        .map(|hash| (DEFAULT_BRANCH_NAME.to_owned(), *hash))
+        .or_else(|| {
+            refs.iter()
+                .find(|&(name, _)| {
+                    !is_tracking_ref(name) && !is_stash_ref(name)
+                })
+                .map(|(name, hash)| (name.to_owned(), *hash))
+        })
+}
+
+/// Connect to a named remote, or else treat the name as a path or URL
+pub fn connect_remote(object_store: &ObjectStore,
+                      name: &str)
+                      -> Result<Box<Transport>> {
+    match object_store.remotes().get(name) {
+            Some(remote) => connect(&remote.url),
+            None => connect(name),
+        }
+        .chain_err(|| format!("Could not connect to remote {}", name))
+}
+
+/// Transport to a repository on the local filesystem
+pub struct LocalTransport {
+    object_store: ObjectStore,
+}
+
+impl LocalTransport {
+    /// Open the repository at the given path
+    ///
+    /// The path may be a work dir containing a hidden dir, or an object store
+    /// directory itself.
+    pub fn open(path: &Path) -> Result<Self> {
+        let osd = find_object_store_dir(path)?;
+        Ok(LocalTransport { object_store: ObjectStore::open(osd)? })
+    }
+
+    pub fn object_store(&self) -> &ObjectStore { &self.object_store }
+}
+
+impl Transport for LocalTransport {
+    fn describe(&self) -> String {
+        format!("{}", self.object_store.path().display())
+    }
+
+    fn list_refs(&mut self) -> Result<RefMap> {
+        Ok(self.object_store.refs().clone())
+    }
+
+    fn hash_algorithm(&mut self) -> Result<HashAlgorithm> {
+        Ok(self.object_store.hash_algorithm())
+    }
+
+    fn chunking(&mut self) -> Result<ChunkParams> {
+        Ok(self.object_store.config().chunking)
+    }
+
+    fn media_types(&mut self, keys: &[ObjectKey]) -> Result<MediaTypeMap> {
+        self.object_store.media_types(keys)
+    }
+
+    fn record_media_types(&mut self, types: &MediaTypeMap) -> Result<()> {
+        for (key, media_type) in types {
+            self.object_store.record_media_type(*key, *media_type)?;
+        }
+        Ok(())
+    }
+
+    fn read_object_file(&mut self,
+                        key: &ObjectKey)
+                        -> Result<Box<io::BufRead>> {
+        let file = self.object_store.open_object_file(key)?;
+        Ok(Box::new(file))
+    }
+
+    fn has_object(&mut self, key: &ObjectKey) -> Result<bool> {
+        Ok(self.object_store.has_object(key))
+    }
+
+    fn write_object_file(&mut self,
+                         key: &ObjectKey,
+                         reader: &mut io::Read)
+                         -> Result<ObjectSize> {
+        self.object_store.store_raw_object(key, reader)
+    }
+
+    fn update_ref(&mut self,
+                  name: &str,
+                  expected: Option<ObjectKey>,
+                  hash: ObjectKey)
+                  -> Result<()> {
+        let _lock = self.object_store.lock(LockMode::Exclusive, "push")?;
+        self.object_store.update_ref_if(name.to_owned(), expected, hash)
+    }
+}
+
+/// Transport that limits the rate of object data in both directions
+///
+/// Only object contents count toward the limit, not ref listings or other
+/// requests. Transports that receive a whole object before handing it over,
+/// such as ssh, still go over the limit for a single large object, but the
+/// next object waits until the average is back under it.
+pub struct RateLimitedTransport {
+    inner: Box<Transport>,
+    limiter: Arc<RateLimiter>,
+}
+
+impl RateLimitedTransport {
+    pub fn new(inner: Box<Transport>, bytes_per_sec: u64) -> Self {
+        RateLimitedTransport {
+            inner: inner,
+            limiter: RateLimiter::arc(bytes_per_sec),
+        }
+    }
+}
+
+impl Transport for RateLimitedTransport {
+    fn describe(&self) -> String { self.inner.describe() }
+
+    fn list_refs(&mut self) -> Result<RefMap> { self.inner.list_refs() }
+
+    fn hash_algorithm(&mut self) -> Result<HashAlgorithm> {
+        self.inner.hash_algorithm()
+    }
+
+    fn chunking(&mut self) -> Result<ChunkParams> { self.inner.chunking() }
+
+    fn media_types(&mut self, keys: &[ObjectKey]) -> Result<MediaTypeMap> {
+        self.inner.media_types(keys)
+    }
+
+    fn record_media_types(&mut self, types: &MediaTypeMap) -> Result<()> {
+        self.inner.record_media_types(types)
+    }
+
+    fn read_object_file(&mut self,
+                        key: &ObjectKey)
+                        -> Result<Box<io::BufRead>> {
+        let file = self.inner.read_object_file(key)?;
+        Ok(Box::new(RateLimitedReader::new(file, self.limiter.clone())))
+    }
+
+    fn has_object(&mut self, key: &ObjectKey) -> Result<bool> {
+        self.inner.has_object(key)
+    }
+
+    fn missing_objects(&mut self,
+                       keys: &[ObjectKey])
+                       -> Result<Vec<ObjectKey>> {
+        self.inner.missing_objects(keys)
+    }
+
+    fn write_object_file(&mut self,
+                         key: &ObjectKey,
+                         reader: &mut io::Read)
+                         -> Result<ObjectSize> {
+        let mut reader = RateLimitedReader::new(reader, self.limiter.clone());
+        self.inner.write_object_file(key, &mut reader)
+    }
+
+    fn update_ref(&mut self,
+                  name: &str,
+                  expected: Option<ObjectKey>,
+                  hash: ObjectKey)
+                  -> Result<()> {
+        self.inner.update_ref(name, expected, hash)
+    }
+}
+
+/// Connect to a remote as `connect_remote`, with an optional rate limit
+fn connect_limited(object_store: &ObjectStore,
+                   name: &str,
+                   limit_rate: Option<u64>)
+                   -> Result<Box<Transport>> {
+    let transport = connect_remote(object_store, name)?;
+    Ok(match limit_rate {
+        Some(rate) => Box::new(RateLimitedTransport::new(transport, rate)),
+        None => transport,
+    })
+}
+
+/// Totals for an object transfer
+#[derive(Debug,Clone,Copy,Default,PartialEq,Eq)]
+pub struct TransferStats {
+    pub objects: usize,
+    pub bytes: ObjectSize,
+}
+
+/// Copy all objects reachable from the given heads that the store lacks
+///
+/// Objects are stored only after everything they reference has been stored.
+/// So if an object is present, its descendants are too, and an interrupted
+/// transfer can simply be restarted.
+pub fn copy_objects<'a, I>(transport: &mut Transport,
+                           object_store: &ObjectStore,
+                           heads: I)
+                           -> Result<TransferStats>
+    where I: IntoIterator<Item = &'a ObjectKey>
+{
+    transfer_objects(&mut PeerTransport(transport),
+                     &mut PeerStore(object_store),
+                     heads,
+                     None,
+                     false)
+        .map(|outcome| outcome.stats)
+}
+
+/// Copy objects like `copy_objects`, but only `depth` commits of history
+///
+/// A depth of 1 copies just the head commits and their trees. Returns the
+/// commits whose parents were left out, which the caller should record with
+/// `ObjectStore::add_shallow`.
+pub fn copy_objects_shallow<'a, I>(transport: &mut Transport,
+                                   object_store: &ObjectStore,
+                                   heads: I,
+                                   depth: usize)
+                                   -> Result<(TransferStats, Vec<ObjectKey>)>
+    where I: IntoIterator<Item = &'a ObjectKey>
+{
+    transfer_objects(&mut PeerTransport(transport),
+                     &mut PeerStore(object_store),
+                     heads,
+                     Some(depth),
+                     false)
+        .map(|outcome| (outcome.stats, outcome.shallow))
+}
+
+/// Send all objects reachable from the given heads that the remote lacks
+///
+/// The reverse of `copy_objects`, with the same ordering guarantee.
+pub fn send_objects<'a, I>(object_store: &ObjectStore,
+                           transport: &mut Transport,
+                           heads: I)
+                           -> Result<TransferStats>
+    where I: IntoIterator<Item = &'a ObjectKey>
+{
+    transfer_objects(&mut PeerStore(object_store),
+                     &mut PeerTransport(transport),
+                     heads,
+                     None,
+                     false)
+        .map(|outcome| outcome.stats)
+}
+
+/// One end of an object transfer, either a local store or a transport
+trait ObjectPeer {
+    fn hash_algorithm(&mut self) -> Result<HashAlgorithm>;
+    fn has_object(&mut self, key: &ObjectKey) -> Result<bool>;
+    fn missing_objects(&mut self,
+                       keys: &[ObjectKey])
+                       -> Result<Vec<ObjectKey>>;
+    fn read_object_file(&mut self,
+                        key: &ObjectKey)
+                        -> Result<Box<io::BufRead>>;
+    fn write_object_file(&mut self,
+                         key: &ObjectKey,
+                         reader: &mut io::Read)
+                         -> Result<ObjectSize>;
+
+    /// Is this a commit whose parents were left out by a shallow fetch?
+    fn is_shallow(&mut self, _key: &ObjectKey) -> bool { false }
+
+    /// Media types recorded for any of these files
+    fn media_types(&mut self, _keys: &[ObjectKey]) -> Result<MediaTypeMap> {
+        Ok(MediaTypeMap::new())
+    }
+
+    /// Record media types of files received
+    fn record_media_types(&mut self, _types: &MediaTypeMap) -> Result<()> {
+        Ok(())
+    }
+}
+
+struct PeerStore<'a>(&'a ObjectStore);
+
+impl<'a> ObjectPeer for PeerStore<'a> {
+    fn hash_algorithm(&mut self) -> Result<HashAlgorithm> {
+        Ok(self.0.hash_algorithm())
+    }
+    fn has_object(&mut self, key: &ObjectKey) -> Result<bool> {
+        Ok(self.0.has_object(key))
+    }
+    fn missing_objects(&mut self,
+                       keys: &[ObjectKey])
+                       -> Result<Vec<ObjectKey>> {
+        Ok(keys.iter().filter(|k| !self.0.has_object(k)).cloned().collect())
+    }
+    fn read_object_file(&mut self,
+                        key: &ObjectKey)
+                        -> Result<Box<io::BufRead>> {
+        Ok(Box::new(self.0.open_object_file(key)?))
+    }
+    fn write_object_file(&mut self,
+                         key: &ObjectKey,
+                         reader: &mut io::Read)
+                         -> Result<ObjectSize> {
+        self.0.store_raw_object(key, reader)
+    }
+    fn is_shallow(&mut self, key: &ObjectKey) -> bool {
+        self.0.is_shallow(key)
+    }
+    fn media_types(&mut self, keys: &[ObjectKey]) -> Result<MediaTypeMap> {
+        self.0.media_types(keys)
+    }
+    fn record_media_types(&mut self, types: &MediaTypeMap) -> Result<()> {
+        for (key, media_type) in types {
+            self.0.record_media_type(*key, *media_type)?;
+        }
+        Ok(())
+    }
+}
+
+struct PeerTransport<'a>(&'a mut Transport);
+
+impl<'a> ObjectPeer for PeerTransport<'a> {
+    fn hash_algorithm(&mut self) -> Result<HashAlgorithm> {
+        self.0.hash_algorithm()
+    }
+    fn has_object(&mut self, key: &ObjectKey) -> Result<bool> {
+        self.0.has_object(key)
+    }
+    fn missing_objects(&mut self,
+                       keys: &[ObjectKey])
+                       -> Result<Vec<ObjectKey>> {
+        self.0.missing_objects(keys)
+    }
+    fn read_object_file(&mut self,
+                        key: &ObjectKey)
+                        -> Result<Box<io::BufRead>> {
+        self.0.read_object_file(key)
+    }
+    fn write_object_file(&mut self,
+                         key: &ObjectKey,
+                         reader: &mut io::Read)
+                         -> Result<ObjectSize> {
+        self.0.write_object_file(key, reader)
+    }
+    fn media_types(&mut self, keys: &[ObjectKey]) -> Result<MediaTypeMap> {
+        self.0.media_types(keys)
+    }
+    fn record_media_types(&mut self, types: &MediaTypeMap) -> Result<()> {
+        self.0.record_media_types(types)
+    }
+}
+
+/// What `transfer_objects` copied, and what it left out
+struct TransferOutcome {
+    stats: TransferStats,
+    /// Objects copied to the receiver
+    copied: Vec<ObjectKey>,
+    /// Objects the receiver already had
+    present: Vec<ObjectKey>,
+    /// Commits whose parents were cut off by a depth limit
+    shallow: Vec<ObjectKey>,
+    /// Blobs left out of a partial transfer, with their sizes
+    absent: Vec<(ObjectKey, ObjectSize)>,
+}
+
+/// An object waiting on the transfer stack: key, generation, whether the
+/// receiver is known to lack it, and its raw header and content once read
+type PendingObject = (ObjectKey, usize, bool, Option<(Vec<u8>, Vec<u8>)>);
+
+/// Copy objects from one peer to another, children first
+///
+/// With a depth limit, commits more than `depth` generations from the heads
+/// are not copied, and the commits whose parents were cut off are returned.
+/// A sender's own shallow commits are a limit too, but the receiver must
+/// already have their parents.
+/// With `partial`, blobs are not copied either, only listed, so the
+/// receiver gets commits, trees, and chunk indexes, but no file content.
+/// The sender's record of each file's media type goes with it, even if its
+/// content is left out.
+fn transfer_objects<'a, I>(from: &mut ObjectPeer,
+                           to: &mut ObjectPeer,
+                           heads: I,
+                           depth: Option<usize>,
+                           partial: bool)
+                           -> Result<TransferOutcome>
+    where I: IntoIterator<Item = &'a ObjectKey>
+{
+    let algorithm = to.hash_algorithm()?;
+    let from_algorithm = from.hash_algorithm()?;
+    if from_algorithm != algorithm {
+        bail!("Remote uses {} hashes, but this repository uses {}",
+              from_algorithm,
+              algorithm);
+    }
+
+    let mut stats = TransferStats::default();
+    let mut copied = Vec::new();
+    let mut present = Vec::new();
+    let mut shallow = Vec::new();
+    let mut absent = Vec::new();
+    // Files copied or left out, whose media types go with them
+    let mut files = Vec::new();
+
+    // Stack of objects to visit. Objects that have been read but not yet
+    // stored are waiting on their children.
+    let mut stack: Vec<PendingObject> =
+        heads.into_iter().map(|k| (*k, 1, false, None)).collect();
+    // Objects stored by this transfer, which may be listed more than once
+    let mut sent = HashSet::new();
+
+    while let Some((key, generation, known_missing, loaded)) = stack.pop() {
+        if let Some((header_buf, content)) = loaded {
+            // Children are stored, so now store this object
+            let mut raw = header_buf.as_slice().chain(content.as_slice());
+            stats.bytes += to.write_object_file(&key, &mut raw)?;
+            stats.objects += 1;
+            copied.push(key);
+            sent.insert(key);
+            continue;
+        }
+        let have = if known_missing {
+            sent.contains(&key)
+        } else {
+            to.has_object(&key)?
+        };
+        if have {
+            present.push(key);
+            continue;
+        }
+
+        let mut file = from.read_object_file(&key)?;
+        let mut header_buf = vec![0u8; 12];
+        file.read_exact(&mut header_buf)?;
+        let header = ObjectHeader::read_from(&mut header_buf.as_slice())
+            .chain_err(|| format!("Bad header for remote object {}", key))?;
+
+        if header.object_type == ObjectType::Blob ||
+           header.object_type == ObjectType::ChunkedBlob {
+            files.push(key);
+        }
+        if header.object_type == ObjectType::Blob && partial {
+            absent.push((key, header.content_size));
+            continue;
+        }
+        if header.object_type == ObjectType::Blob {
+            // Blobs have no children, so stream them straight to the store
+            let mut raw = header_buf.as_slice().chain(file);
+            stats.bytes += to.write_object_file(&key, &mut raw)?;
+            stats.objects += 1;
+            copied.push(key);
+            sent.insert(key);
+            continue;
+        }
+
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)?;
+        let object = header.read_content(&mut content.as_slice(), algorithm)
+            .chain_err(|| format!("Could not parse remote object {}", key))?;
+
+        let mut children = object.child_keys();
+        if let Object::Commit(ref commit) = object {
+            if from.is_shallow(&key) {
+                // The sender's history stops here, so the receiver must
+                // already have the rest
+                if !to.missing_objects(&commit.parents)?.is_empty() {
+                    bail!("History before {} was left out by a shallow \
+                           fetch, so it cannot be sent. Fetch the full \
+                           history first.",
+                          key);
+                }
+                children = vec![commit.tree];
+            } else if depth.is_some_and(|depth| generation >= depth) {
+                let mut have_parents = true;
+                for parent in &commit.parents {
+                    have_parents &= to.has_object(parent)?;
+                }
+                if !have_parents {
+                    debug!("Truncating history at {}", key);
+                    shallow.push(key);
+                    children = vec![commit.tree];
+                }
+            }
+        }
+
+        // Ask about all the children at once, so that a changed file only
+        // costs one round trip to find which of its chunks must be sent
+        stack.push((key, generation, false, Some((header_buf, content))));
+        let missing: HashSet<ObjectKey> =
+            to.missing_objects(&children)?.into_iter().collect();
+        for child in children {
+            if missing.contains(&child) {
+                // Generation only matters for commits, whose parents are the
+                // next generation
+                stack.push((child, generation + 1, true, None));
+            } else {
+                present.push(child);
+            }
+        }
+    }
+
+    let media_types = from.media_types(&files)?;
+    if !media_types.is_empty() {
+        to.record_media_types(&media_types)?;
+    }
+    Ok(TransferOutcome {
+        stats: stats,
+        copied: copied,
+        present: present,
+        shallow: shallow,
+        absent: absent,
+    })
+}
+
+/// A change to a ref made by a fetch or push
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct RefUpdate {
+    pub name: String,
+    pub old: Option<ObjectKey>,
+    pub new: ObjectKey,
+}
+
+/// Result of a fetch
+#[derive(Debug,Clone)]
+pub struct FetchResult {
+    /// Branches in the remote and the commits they point to
+    pub remote_refs: RefMap,
+    /// Tracking refs that changed (empty if the remote is not named)
+    pub updates: Vec<RefUpdate>,
+    /// Tracking refs deleted because the remote no longer has the branch
+    pub pruned: Vec<(String, ObjectKey)>,
+    pub stats: TransferStats,
+    /// Commits whose history was cut off by a depth limit
+    pub shallow: Vec<ObjectKey>,
+    /// Blobs left out of a partial fetch, with their sizes
+    pub absent: Vec<(ObjectKey, ObjectSize)>,
+}
+
+/// Fetch objects for all branches of a remote
+///
+/// The remote may be a configured remote name, in which case its
+/// `remotes/<name>/<branch>` tracking refs are updated, or a path or URL, in
+/// which case only the objects are fetched. Tracking refs for branches that
+/// were deleted on the remote are removed. Local branches and the work dir
+/// are never touched.
+///
+/// With a depth, only that many commits of each branch's history are
+/// fetched, and the commits at the cut-off are recorded as shallow. Without
+/// a depth, the missing history of any shallow commits is fetched too, if the
+/// remote has it.
+///
+/// A partial fetch leaves out file content, and records the blobs it left
+/// out as absent, along with the remote that has them. A full fetch also
+/// fetches any absent blobs that the remote has.
+///
+/// With `limit_rate`, object data is transferred at no more than that many
+/// bytes per second.
+pub fn fetch(object_store: &mut ObjectStore,
+             remote: &str,
+             depth: Option<usize>,
+             partial: bool,
+             limit_rate: Option<u64>)
+             -> Result<FetchResult> {
+    let mut transport = connect_limited(object_store, remote, limit_rate)?;
+    let remote_refs = transport.list_refs()?
+        .into_iter()
+        .filter(|(name, _)| !is_tracking_ref(name) && !is_stash_ref(name))
+        .collect::<RefMap>();
+
+    // History cut off by a shallow fetch, and content left out by a partial
+    // one, are fetched too if the remote has them, asked in one round trip
+    let mut wanted = Vec::new();
+    if depth.is_none() {
+        for commit in object_store.shallow() {
+            wanted.extend(object_store.open_commit(commit)?.parents);
+        }
+    }
+    if !partial {
+        wanted.extend(object_store.absent()
+            .keys()
+            .filter(|key| object_store.is_absent(key)));
+    }
+    let missing = transport.missing_objects(&wanted)?
+        .into_iter()
+        .collect::<HashSet<_>>();
+    let mut heads = remote_refs.values().cloned().collect::<Vec<_>>();
+    heads.extend(wanted.into_iter().filter(|key| !missing.contains(key)));
+    let outcome = transfer_objects(&mut PeerTransport(&mut *transport),
+                                   &mut PeerStore(object_store),
+                                   &heads,
+                                   depth,
+                                   partial)
+        .chain_err(|| {
+            format!("Could not fetch objects from {}", transport.describe())
+        })?;
+    object_store.add_shallow(outcome.shallow.iter().cloned())?;
+    object_store.remove_complete_shallow()?;
+    object_store.add_absent(outcome.absent.iter().cloned())?;
+    object_store.remove_present_absent()?;
+    let fetched = outcome.copied
+        .iter()
+        .cloned()
+        .chain(outcome.absent.iter().map(|&(key, _)| key));
+    object_store.record_locations(remote, fetched)?;
+
+    let mut updates = Vec::new();
+    let mut pruned = Vec::new();
+    if object_store.remotes().contains_key(remote) {
+        let prefix = tracking_ref_name(remote, "");
+        let stale = object_store.refs()
+            .keys()
+            .filter(|name| {
+                name.starts_with(&prefix) &&
+                !remote_refs.contains_key(&name[prefix.len()..])
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        for name in stale {
+            if let Some(old) = object_store.remove_ref(&name)? {
+                pruned.push((name, old));
+            }
+        }
+        for (branch, hash) in &remote_refs {
+            let name = tracking_ref_name(remote, branch);
+            let old = object_store.try_find_ref(&name);
+            if old != Some(*hash) {
+                object_store.update_ref(name.clone(), *hash)?;
+                updates.push(RefUpdate {
+                    name: name,
+                    old: old,
+                    new: *hash,
+                });
+            }
+        }
+    }
+
+    Ok(FetchResult {
+        remote_refs: remote_refs,
+        updates: updates,
+        pruned: pruned,
+        stats: outcome.stats,
+        shallow: outcome.shallow,
+        absent: outcome.absent,
+    })
+}
+
+/// How to treat a push that is not a fast-forward
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum ForceMode {
+    /// Only allow fast-forward updates
+    FastForward,
+    /// Overwrite the remote branch no matter what
+    Force,
+    /// Overwrite only if the remote branch is at the expected commit
+    ///
+    /// The expected commit is usually the tracking ref from the last fetch.
+    /// This keeps a force push from clobbering work pushed by someone else
+    /// since then. `None` expects the branch not to exist.
+    WithLease(Option<ObjectKey>),
+}
+
+/// Result of a push
+#[derive(Debug,Clone)]
+pub struct PushResult {
+    /// The change to the remote branch, or None if it was already up to date
+    pub update: Option<RefUpdate>,
+    /// True if the update was not a fast-forward
+    pub forced: bool,
+    pub stats: TransferStats,
+}
+
+/// Push a local branch to the branch of the same name on a remote
+///
+/// The push is rejected unless the remote branch is new or is an ancestor of
+/// the local branch, as decided by `force`, or if it needs content that was
+/// left out by a partial fetch and the remote lacks. Rejection happens before
+/// any objects are sent. The remote branch is only moved if it still points
+/// where it did when it was checked, so if another push lands in the
+/// meantime, this one is rejected after sending its objects. If the remote is
+/// a configured remote name, its tracking ref is updated to match. Object data
+/// is sent at no more than `limit_rate` bytes per second, if given.
+pub fn push(object_store: &mut ObjectStore,
+            remote: &str,
+            branch: &str,
+            force: ForceMode,
+            limit_rate: Option<u64>)
+            -> Result<PushResult> {
+    let new = match object_store.try_find_ref(branch) {
+        Some(hash) => hash,
+        None => bail!("No such branch: {}", branch),
+    };
+    let mut transport = connect_limited(object_store, remote, limit_rate)?;
+    let old = transport.list_refs()?.get(branch).cloned();
+
+    if old == Some(new) {
+        return Ok(PushResult {
+            update: None,
+            forced: false,
+            stats: TransferStats::default(),
+        });
+    }
+
+    let fast_forward = match old {
+        None => true,
+        Some(ref old) => {
+            object_store.has_object(old) &&
+            object_store.is_ancestor(old, &new)?
+        }
+    };
+
+    match force {
+        ForceMode::FastForward if !fast_forward => {
+            bail!("Rejected non-fast-forward push to {} {}. Fetch and merge \
+                   first, or use --force.",
+                  transport.describe(),
+                  branch)
+        }
+        ForceMode::WithLease(expected) if expected != old => {
+            bail!("Rejected push to {} {}: expected {}, but remote has {}. \
+                   Fetch and check the remote's changes first.",
+                  transport.describe(),
+                  branch,
+                  expected.map_or("nothing".to_owned(), |h| h.to_string()),
+                  old.map_or("nothing".to_owned(), |h| h.to_string()))
+        }
+        _ => (),
+    }
+    check_absent_content(object_store, &mut *transport, remote, &new)?;
+
+    let outcome = transfer_objects(&mut PeerStore(object_store),
+                                   &mut PeerTransport(&mut *transport),
+                                   &[new],
+                                   None,
+                                   false)
+        .chain_err(|| {
+            format!("Could not send objects to {}", transport.describe())
+        })?;
+    transport.update_ref(branch, old, new)
+        .chain_err(|| {
+            format!("Rejected push to {} {}: the remote branch changed \
+                     during the push. Fetch and check the remote's changes \
+                     first.",
+                    transport.describe(),
+                    branch)
+        })?;
+    // The remote had everything below the objects it already had, so record
+    // those too, not just the tops where the transfer stopped
+    let (present, _) = object_store.find_reachable(outcome.present)?;
+    object_store.record_locations(remote,
+                                  outcome.copied
+                                      .into_iter()
+                                      .chain(present))?;
+
+    if object_store.remotes().contains_key(remote) {
+        object_store.update_ref(tracking_ref_name(remote, branch), new)?;
+    }
+
+    Ok(PushResult {
+        update: Some(RefUpdate {
+            name: branch.to_owned(),
+            old: old,
+            new: new,
+        }),
+        forced: !fast_forward,
+        stats: outcome.stats,
+    })
+}
+
+/// Refuse to send history whose content was left out by a partial fetch,
+/// unless the remote already has that content
+///
+/// Absent blobs that the remote is recorded as having are taken on trust, and
+/// the remote is asked about the rest. Only if it lacks some is the history
+/// walked, to see whether sending it would need them.
+fn check_absent_content(object_store: &ObjectStore,
+                        transport: &mut Transport,
+                        remote: &str,
+                        head: &ObjectKey)
+                        -> Result<()> {
+    let unrecorded = object_store.absent()
+        .keys()
+        .filter(|key| object_store.is_absent(key))
+        .filter(|key| {
+            !object_store.locations().remotes_for(key).contains(remote)
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    if unrecorded.is_empty() {
+        return Ok(());
+    }
+    let lacking = transport.missing_objects(&unrecorded)?;
+    if lacking.is_empty() {
+        return Ok(());
+    }
+    let (reachable, _) = object_store.find_reachable(iter::once(*head))?;
+    let needed = lacking.into_iter()
+        .filter(|key| reachable.contains(key))
+        .collect::<Vec<_>>();
+    if let Some(first) = needed.first() {
+        let what = match needed.len() {
+            1 => first.to_string(),
+            n => format!("{} and {} other blobs", first, n - 1),
+        };
+        let remotes = needed.iter()
+            .flat_map(|key| object_store.locations().remotes_for(key))
+            .collect::<BTreeSet<_>>();
+        bail!(ErrorKind::ContentAbsent(what, remotes.into_iter().collect()));
+    }
+    Ok(())
+}
+
+/// Find the object store directory for a work dir or object store path
+fn find_object_store_dir(path: &Path) -> Result<PathBuf> {
+    let hidden = path.join(HIDDEN_DIR_NAME);
+    if hidden.is_dir() {
+        return Ok(hidden);
+    }
+    let looks_like_store = ["config", "refs", "objects"]
+        .iter()
+        .any(|name| path.join(name).exists());
+    if path.is_dir() && looks_like_store {
+        return Ok(path.to_owned());
+    }
+    bail!("Not a repository: {}", path.display())
+}
+
+
+#[cfg(test)]
+mod test {
+    use dag::Blob;
+    use dag::Commit;
+    use dag::Tree;
+    use dag::ObjectCommon;
+    use object_store::test::create_temp_repository;
+    use rolling_hash::CHUNK_TARGET_SIZE;
+    use rolling_hash::read_file_objects;
+    use std::fs;
+    use std::io::Write;
+    use super::*;
+    use testutil::TestRand;
+
+    #[test]
+    fn test_local_transport_list_refs() {
+        let temp = in_mem_tempdir!();
+        let wd_path = temp.path().join("repo");
+        let osd_path = wd_path.join(HIDDEN_DIR_NAME);
+        let mut store = ObjectStore::init(osd_path.clone()).unwrap();
+        let hash = store.store_object(&Blob::from("Hello!")).unwrap();
+        store.update_ref("master".to_owned(), hash).unwrap();
+
+        for path in &[&wd_path, &osd_path] {
+            let mut transport = connect(path.to_str().unwrap()).unwrap();
+            let refs = transport.list_refs().unwrap();
+            assert_eq!(refs.get("master"), Some(&hash));
+        }
+
+        assert!(connect(temp.path().to_str().unwrap()).is_err(),
+                "Should not connect to a non-repository");
+        assert!(connect("xyz://host/path").is_err(),
+                "Should not connect with an unknown transport");
+    }
+
+    #[test]
+    fn test_fetch_named_remote() {
+        let (_remote_temp, mut remote) = create_temp_repository().unwrap();
+        let (_local_temp, mut local) = create_temp_repository().unwrap();
+
+        // Remote history: a large chunked file plus a small one, two commits
+        let mut rng = TestRand::default();
+        let big = rng.gen_byte_vec(CHUNK_TARGET_SIZE * 4);
+        let mut big_hash = None;
+        for object in read_file_objects(big.as_slice()) {
+            let object = object.unwrap();
+            big_hash = Some(remote.store_object(&object).unwrap());
+        }
+        let small = remote.store_object(&Blob::from("Hello!")).unwrap();
+        let tree1 = remote.store_object(&tree_object!{
+                "small" => small,
+            })
+            .unwrap();
+        let commit1 = remote.store_object(&Commit {
+                tree: tree1,
+                parents: vec![],
+                message: "First".to_owned(),
+                time: None,
+                signature: None,
+            })
+            .unwrap();
+        let tree2 = remote.store_object(&tree_object!{
+                "small" => small,
+                "big" => big_hash.unwrap(),
+            })
+            .unwrap();
+        let commit2 = remote.store_object(&Commit {
+                tree: tree2,
+                parents: vec![commit1],
+                message: "Second".to_owned(),
+                time: None,
+                signature: None,
+            })
+            .unwrap();
+        remote.update_ref("master".to_owned(), commit2).unwrap();
+        remote.update_ref("remotes/other/master".to_owned(), commit1).unwrap();
+        remote.update_ref("stash/0".to_owned(), commit1).unwrap();
+
+        let remote_url = remote.path().to_str().unwrap().to_owned();
+        local.update_remote("origin".to_owned(), Remote::new(remote_url))
+            .unwrap();
+        local.update_ref("master".to_owned(), commit1).unwrap();
+
+        let result = fetch(&mut local, "origin", None, false, None).unwrap();
+        assert_eq!(result.remote_refs.len(),
+                   1,
+                   "Remote's own tracking refs and stashes should not be \
+                    fetched");
+        assert_eq!(result.updates,
+                   vec![RefUpdate {
+                            name: "remotes/origin/master".to_owned(),
+                            old: None,
+                            new: commit2,
+                        }]);
+        assert_eq!(local.try_find_ref("master"),
+                   Some(commit1),
+                   "Local branch should not change");
+        assert!(result.stats.objects > 6, "Should copy chunks too");
+        assert!(local.locations().remotes_for(&small).contains("origin"));
+
+        // All objects should be readable and intact
+        assert!(local.fsck(false, &[]).unwrap().is_ok());
+        assert_eq!(local.open_commit(&commit2).unwrap().parents,
+                   vec![commit1]);
+
+        // Second fetch should have nothing to do
+        let result = fetch(&mut local, "origin", None, false, None).unwrap();
+        assert_eq!(result.updates, vec![]);
+        assert_eq!(result.stats, TransferStats::default());
+
+        // Branch deleted on the remote should lose its tracking ref
+        local.update_ref("remotes/origin/gone".to_owned(), commit1).unwrap();
+        let result = fetch(&mut local, "origin", None, false, None).unwrap();
+        assert_eq!(result.pruned,
+                   vec![("remotes/origin/gone".to_owned(), commit1)]);
+        assert_eq!(local.try_find_ref("remotes/origin/gone"), None);
+        assert_eq!(local.try_find_ref("remotes/origin/master"),
+                   Some(commit2));
+    }
+
+    #[test]
+    fn test_default_branch() {
+        let hash = Blob::from("x").calculate_hash();
+        let mut refs = RefMap::new();
+        refs.insert("remotes/origin/a".to_owned(), hash);
+        refs.insert("stash/0".to_owned(), hash);
+        assert_eq!(default_branch(&refs), None);
+        refs.insert("topic".to_owned(), hash);
+        assert_eq!(default_branch(&refs), Some(("topic".to_owned(), hash)));
+        refs.insert(DEFAULT_BRANCH_NAME.to_owned(), hash);
+        assert_eq!(default_branch(&refs),
+                   Some((DEFAULT_BRANCH_NAME.to_owned(), hash)));
+    }
+
+    #[test]
+    fn test_fetch_rejects_hash_mismatch() {
+        let temp = in_mem_tempdir!();
+        let mut config = ::config::RepoConfig::default();
+        config.hash_algorithm = Some(HashAlgorithm::Sha1);
+        let remote = ObjectStore::init_with_config(temp.path().join("remote"),
+                                                   config)
+            .unwrap();
+        let (_local_temp, mut local) = create_temp_repository().unwrap();
+
+        let remote_url = remote.path().to_str().unwrap().to_owned();
+        assert!(fetch(&mut local, &remote_url, None, false, None).is_err());
+    }
+
+    #[test]
+    fn test_fetch_quarantines_corrupt_object() {
+        let (_remote_temp, mut remote) = create_temp_repository().unwrap();
+        let (_local_temp, mut local) = create_temp_repository().unwrap();
+
+        let blob = remote.store_object(&Blob::from("Hello!")).unwrap();
+        let tree = remote.store_object(&tree_object!{
+                "hello.txt" => blob,
+            })
+            .unwrap();
+        remote.update_ref("master".to_owned(), tree).unwrap();
+
+        let hex = blob.to_hex();
+        let blob_path =
+            remote.path().join("objects").join(&hex[..2]).join(&hex[2..]);
+        fs::OpenOptions::new()
+            .append(true)
+            .open(blob_path)
+            .and_then(|mut f| f.write_all(b"noise"))
+            .unwrap();
+
+        let remote_url = remote.path().to_str().unwrap().to_owned();
+        assert!(fetch(&mut local, &remote_url, None, false, None).is_err());
+        assert!(!local.has_object(&blob));
+        assert!(!local.has_object(&tree),
+                "Parent should not be stored without its children");
+        assert!(local.path().join("quarantine").join(&hex).is_file());
+    }
+
+    #[test]
+    fn test_shallow_fetch() {
+        use object_store::DepthFirstCommitSort;
+
+        let (_remote_temp, mut remote) = create_temp_repository().unwrap();
+        let (_local_temp, mut local) = create_temp_repository().unwrap();
+        let chain = commit_chain(&remote, None, &["A", "B", "C"]);
+        remote.update_ref("master".to_owned(), chain[2]).unwrap();
+        let remote_url = remote.path().to_str().unwrap().to_owned();
+
+        let result = fetch(&mut local, &remote_url, Some(2), false, None)
+            .unwrap();
+        assert_eq!(result.shallow, vec![chain[1]]);
+        assert!(local.has_object(&chain[2]));
+        assert!(local.has_object(&chain[1]));
+        assert!(!local.has_object(&chain[0]));
+        assert!(local.is_shallow(&chain[1]));
+
+        // History operations stop at the shallow commit
+        let sorted = DepthFirstCommitSort::new(&local, vec![chain[2]])
+            .run()
+            .unwrap();
+        assert_eq!(sorted.iter().map(|&(h, _)| h).collect::<Vec<_>>(),
+                   vec![chain[1], chain[2]]);
+        assert!(!local.is_ancestor(&chain[0], &chain[2]).unwrap());
+        local.update_ref("master".to_owned(), chain[2]).unwrap();
+        assert!(local.fsck(false, &[]).unwrap().is_ok(),
+                "Parents of shallow commits should not count as missing");
+
+        // Full fetch fills in the history
+        let result = fetch(&mut local, &remote_url, None, false, None).unwrap();
+        assert_eq!(result.stats.objects, 1);
+        assert!(local.has_object(&chain[0]));
+        assert!(local.shallow().is_empty());
+        assert!(local.is_ancestor(&chain[0], &chain[2]).unwrap());
+    }
+
+    #[test]
+    fn test_shallow_push() {
+        let (_remote_temp, mut remote) = create_temp_repository().unwrap();
+        let (_local_temp, mut local) = create_temp_repository().unwrap();
+        let (_other_temp, other) = create_temp_repository().unwrap();
+        let chain = commit_chain(&remote, None, &["A", "B", "C"]);
+        remote.update_ref("master".to_owned(), chain[2]).unwrap();
+        let remote_url = remote.path().to_str().unwrap().to_owned();
+        let other_url = other.path().to_str().unwrap().to_owned();
+
+        fetch(&mut local, &remote_url, Some(1), false, None).unwrap();
+        assert!(local.is_shallow(&chain[2]));
+        let ahead = commit_chain(&local, Some(chain[2]), &["D"]);
+        local.update_ref("master".to_owned(), ahead[0]).unwrap();
+        local.update_ref("topic".to_owned(), chain[2]).unwrap();
+
+        let result = push(&mut local,
+                          &remote_url,
+                          "topic",
+                          ForceMode::FastForward,
+                          None)
+            .unwrap();
+        assert_eq!(result.stats.objects, 0,
+                   "The remote already has the shallow commit's history");
+        let result = push(&mut local,
+                          &remote_url,
+                          "master",
+                          ForceMode::FastForward,
+                          None)
+            .unwrap();
+        assert_eq!(result.stats.objects, 1);
+
+        let err = push(&mut local,
+                       &other_url,
+                       "master",
+                       ForceMode::FastForward,
+                       None)
+            .unwrap_err();
+        assert_match!(err.root_kind(),
+                      &ErrorKind::Msg(ref msg) if msg.contains("shallow"));
+        let other = ObjectStore::open(other.path().to_owned()).unwrap();
+        assert_eq!(other.try_find_ref("master"), None);
+    }
+
+    #[test]
+    fn test_partial_fetch() {
+        use object_store::ObjectWalkNode;
+        use walker::NodeLookup;
+
+        let (_remote_temp, mut remote) = create_temp_repository().unwrap();
+        let (_local_temp, mut local) = create_temp_repository().unwrap();
+
+        let mut rng = TestRand::default();
+        let big = rng.gen_byte_vec(CHUNK_TARGET_SIZE * 4);
+        let mut big_hash = None;
+        for object in read_file_objects(big.as_slice()) {
+            let object = object.unwrap();
+            big_hash = Some(remote.store_object(&object).unwrap());
+        }
+        let big_hash = big_hash.unwrap();
+        let small = remote.store_object(&Blob::from("Hello!")).unwrap();
+        let tree = remote.store_object(&tree_object!{
+                "small" => small,
+                "big" => big_hash,
+            })
+            .unwrap();
+        let commit = remote.store_object(&Commit {
+                tree: tree,
+                parents: vec![],
+                message: "First".to_owned(),
+                time: None,
+                signature: None,
+            })
+            .unwrap();
+        remote.update_ref("master".to_owned(), commit).unwrap();
+        let remote_url = remote.path().to_str().unwrap().to_owned();
+
+        let result = fetch(&mut local, &remote_url, None, true, None).unwrap();
+        assert_eq!(result.stats.objects, 3, "Commit, tree, and chunk index");
+        assert!(local.has_object(&big_hash));
+        assert!(!local.has_object(&small));
+        assert!(local.is_absent(&small));
+        assert!(result.absent.len() > 1);
+
+        let node: ObjectWalkNode = local.lookup_node(small).unwrap();
+        assert_eq!(node.file_size, 6, "Absent blob should keep its size");
+        match local.open_object_file(&small) {
+            Err(Error(ErrorKind::ContentAbsent(_, remotes), _)) => {
+                assert_eq!(remotes, vec![remote_url.clone()])
+            }
+            other => panic!("Expected ContentAbsent, got {:?}", other.err()),
+        }
+        let remotes = vec![remote_url.clone()]
+            .into_iter()
+            .collect::<::std::collections::BTreeSet<_>>();
+        assert_eq!(local.missing_content(&commit).unwrap(),
+                   vec![(PathBuf::from("big"), remotes.clone()),
+                        (PathBuf::from("small"), remotes)]);
+        local.update_ref("master".to_owned(), commit).unwrap();
+        assert!(local.fsck(false, &[]).unwrap().is_ok(),
+                "Absent blobs should not count as missing");
+
+        // Full fetch fills in the content
+        let result = fetch(&mut local, &remote_url, None, false, None).unwrap();
+        assert!(result.stats.objects > 1);
+        assert!(local.has_object(&small));
+        assert!(local.absent().is_empty());
+        assert_eq!(local.missing_content(&commit).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_partial_push() {
+        let (_remote_temp, mut remote) = create_temp_repository().unwrap();
+        let (_local_temp, mut local) = create_temp_repository().unwrap();
+        let (_other_temp, other) = create_temp_repository().unwrap();
+        let old = remote.store_object(&Blob::from("Old")).unwrap();
+        let tree = remote.store_object(&tree_object!{ "old" => old, })
+            .unwrap();
+        let first = remote.store_object(&Commit {
+                tree: tree,
+                parents: vec![],
+                message: "First".to_owned(),
+                time: None,
+                signature: None,
+            })
+            .unwrap();
+        remote.update_ref("master".to_owned(), first).unwrap();
+        let remote_url = remote.path().to_str().unwrap().to_owned();
+        let other_url = other.path().to_str().unwrap().to_owned();
+
+        fetch(&mut local, &remote_url, None, true, None).unwrap();
+        assert!(local.is_absent(&old));
+        let new = local.store_object(&Blob::from("New")).unwrap();
+        let tree = local.store_object(&tree_object!{
+                "old" => old,
+                "new" => new,
+            })
+            .unwrap();
+        let second = local.store_object(&Commit {
+                tree: tree,
+                parents: vec![first],
+                message: "Second".to_owned(),
+                time: None,
+                signature: None,
+            })
+            .unwrap();
+        local.update_ref("master".to_owned(), second).unwrap();
+
+        let result = push(&mut local,
+                          &remote_url,
+                          "master",
+                          ForceMode::FastForward,
+                          None)
+            .unwrap();
+        assert_eq!(result.stats.objects, 3, "Commit, tree, and new blob");
+
+        let err = push(&mut local,
+                       &other_url,
+                       "master",
+                       ForceMode::FastForward,
+                       None)
+            .unwrap_err();
+        assert_match!(err.root_kind(),
+                      &ErrorKind::ContentAbsent(_, ref remotes)
+                          if remotes == &vec![remote_url.clone()]);
+        let other = ObjectStore::open(other.path().to_owned()).unwrap();
+        assert!(other.list_objects().unwrap().is_empty(),
+                "Should refuse before sending anything");
+    }
+
+    /// Store a chain of commits with empty trees, returning their hashes
+    fn commit_chain(store: &ObjectStore,
+                    parent: Option<ObjectKey>,
+                    messages: &[&str])
+                    -> Vec<ObjectKey> {
+        let tree = store.store_object(&Tree::new()).unwrap();
+        let mut parents: Vec<ObjectKey> = parent.into_iter().collect();
+        let mut hashes = Vec::new();
+        for message in messages {
+            let hash = store.store_object(&Commit {
+                    tree: tree,
+                    parents: parents,
+                    message: message.to_string(),
+                    time: None,
+                    signature: None,
+                })
+                .unwrap();
+            parents = vec![hash];
+            hashes.push(hash);
+        }
+        hashes
+    }
+
+    #[test]
+    fn test_media_types_travel() {
+        use media_type::MediaType;
+
+        let (_remote_temp, remote) = create_temp_repository().unwrap();
+        let (_local_temp, mut local) = create_temp_repository().unwrap();
+        let (_other_temp, mut other) = create_temp_repository().unwrap();
+        let remote_url = remote.path().to_str().unwrap().to_owned();
+
+        let photo = local.store_object(&Blob::from(b"\xff\xd8\xff".to_vec()))
+            .unwrap();
+        local.record_media_type(photo, MediaType::Jpeg).unwrap();
+        let tree = local.store_object(&tree_object!{
+                "photo.jpg" => photo,
+            })
+            .unwrap();
+        let commit = local.store_object(&Commit {
+                tree: tree,
+                parents: vec![],
+                message: "Photo".to_owned(),
+                time: None,
+                signature: None,
+            })
+            .unwrap();
+        local.update_ref("master".to_owned(), commit).unwrap();
+
+        push(&mut local, &remote_url, "master", ForceMode::FastForward, None)
+            .unwrap();
+        assert_eq!(remote.media_type(&photo).unwrap(),
+                   MediaType::Jpeg,
+                   "Media types should be pushed with their files");
+
+        fetch(&mut other, &remote_url, None, true, None).unwrap();
+        assert!(other.is_absent(&photo));
+        assert_eq!(other.media_type(&photo).unwrap(),
+                   MediaType::Jpeg,
+                   "Media types should be fetched, even for files left out");
+    }
+
+    #[test]
+    fn test_push_records_present_subtrees() {
+        let (_remote_temp, remote) = create_temp_repository().unwrap();
+        let (_local_temp, mut local) = create_temp_repository().unwrap();
+        let remote_url = remote.path().to_str().unwrap().to_owned();
+
+        // The remote already has a directory, from some other source
+        let shared = Blob::from(b"shared".to_vec());
+        let blob = remote.store_object(&shared).unwrap();
+        local.store_object(&shared).unwrap();
+        let dir = tree_object!{
+            "shared.txt" => blob,
+        };
+        let subtree = remote.store_object(&dir).unwrap();
+        local.store_object(&dir).unwrap();
+        let tree = local.store_object(&tree_object!{
+                "dir" => subtree,
+            })
+            .unwrap();
+        let commit = local.store_object(&Commit {
+                tree: tree,
+                parents: vec![],
+                message: "Reuse".to_owned(),
+                time: None,
+                signature: None,
+            })
+            .unwrap();
+        local.update_ref("master".to_owned(), commit).unwrap();
+
+        push(&mut local, &remote_url, "master", ForceMode::FastForward, None)
+            .unwrap();
+        for hash in &[commit, tree, subtree, blob] {
+            assert!(local.locations().remotes_for(hash).contains(&remote_url),
+                    "Objects below ones the remote already had should be \
+                     recorded");
+        }
+    }
+
+    #[test]
+    fn test_push_fast_forward_and_force() {
+        use self::ForceMode::*;
+        let (_remote_temp, mut remote) = create_temp_repository().unwrap();
+        let (_local_temp, mut local) = create_temp_repository().unwrap();
+        let remote_url = remote.path().to_str().unwrap().to_owned();
+        local.update_remote("origin".to_owned(), Remote::new(remote_url))
+            .unwrap();
+
+        let base = commit_chain(&local, None, &["A", "B"]);
+        local.update_ref("master".to_owned(), base[1]).unwrap();
+
+        // New branch, then fast-forward
+        let result = push(&mut local, "origin", "master", FastForward, None)
+            .unwrap();
+        assert_eq!(result.update.map(|u| u.new), Some(base[1]));
+        assert!(!result.forced);
+        assert_eq!(local.try_find_ref("remotes/origin/master"), Some(base[1]));
+
+        let ahead = commit_chain(&local, Some(base[1]), &["C"]);
+        local.update_ref("master".to_owned(), ahead[0]).unwrap();
+        let result = push(&mut local, "origin", "master", FastForward, None)
+            .unwrap();
+        assert_eq!(result.update.map(|u| (u.old, u.new)),
+                   Some((Some(base[1]), ahead[0])));
+        assert_eq!(result.stats.objects, 1, "Should send only the new commit");
+        for hash in base.iter().chain(&ahead) {
+            assert!(local.locations().remotes_for(hash).contains("origin"),
+                    "Remote should be recorded as having sent and already \
+                     present objects");
+        }
+
+        let result = push(&mut local, "origin", "master", FastForward, None)
+            .unwrap();
+        assert!(result.update.is_none(), "Should be up to date");
+
+        // Someone else pushes a diverging commit to the remote
+        remote = ObjectStore::open(remote.path().to_owned()).unwrap();
+        let theirs = commit_chain(&remote, Some(ahead[0]), &["Theirs"]);
+        remote.update_ref("master".to_owned(), theirs[0]).unwrap();
+
+        let mine = commit_chain(&local, Some(ahead[0]), &["Mine"]);
+        local.update_ref("master".to_owned(), mine[0]).unwrap();
+        assert!(push(&mut local, "origin", "master", FastForward, None)
+                    .is_err(),
+                "Should reject non-fast-forward");
+
+        // Lease based on a stale fetch should not clobber their commit
+        let stale = local.try_find_ref("remotes/origin/master");
+        assert!(push(&mut local,
+                     "origin",
+                     "master",
+                     WithLease(stale),
+                     None)
+                    .is_err(),
+                "Should reject push with a stale lease");
+        let remote = ObjectStore::open(remote.path().to_owned()).unwrap();
+        assert_eq!(remote.try_find_ref("master"), Some(theirs[0]));
+
+        // Lease that matches the remote allows the overwrite
+        let result = push(&mut local,
+                          "origin",
+                          "master",
+                          WithLease(Some(theirs[0])),
+                          None)
+            .unwrap();
+        assert!(result.forced);
+        let remote = ObjectStore::open(remote.path().to_owned()).unwrap();
+        assert_eq!(remote.try_find_ref("master"), Some(mine[0]));
+
+        // Plain force overwrites anything
+        local.update_ref("master".to_owned(), base[0]).unwrap();
+        let result = push(&mut local, "origin", "master", Force, None)
+            .unwrap();
+        assert!(result.forced);
+        assert_eq!(local.try_find_ref("remotes/origin/master"), Some(base[0]));
+    }
+
+    #[test]
+    fn test_push_race_rejects_stale_update() {
+        let (_remote_temp, mut remote) = create_temp_repository().unwrap();
+        let (_first_temp, mut first) = create_temp_repository().unwrap();
+        let (_second_temp, second) = create_temp_repository().unwrap();
+        let remote_url = remote.path().to_str().unwrap().to_owned();
+
+        let base = commit_chain(&remote, None, &["Base"]);
+        remote.update_ref("master".to_owned(), base[0]).unwrap();
+        first.update_ref("master".to_owned(), base[0]).unwrap();
+        copy_objects(&mut LocalTransport::open(remote.path()).unwrap(),
+                     &first,
+                     &base)
+            .unwrap();
+        copy_objects(&mut LocalTransport::open(remote.path()).unwrap(),
+                     &second,
+                     &base)
+            .unwrap();
+
+        // The second push checks the remote, which is still at the base...
+        let mut transport = LocalTransport::open(remote.path()).unwrap();
+        let old = transport.list_refs().unwrap().get("master").cloned();
+        assert_eq!(old, Some(base[0]));
+        let theirs = commit_chain(&second, Some(base[0]), &["Second"]);
+        send_objects(&second, &mut transport, &theirs).unwrap();
+
+        // ...but the first push lands before it sets the ref
+        let ours = commit_chain(&first, Some(base[0]), &["First"]);
+        first.update_ref("master".to_owned(), ours[0]).unwrap();
+        push(&mut first,
+             &remote_url,
+             "master",
+             ForceMode::FastForward,
+             None)
+            .unwrap();
+
+        assert!(transport.update_ref("master", old, theirs[0]).is_err(),
+                "Should reject an update based on a stale ref");
+        let remote = ObjectStore::open(remote.path().to_owned()).unwrap();
+        assert_eq!(remote.try_find_ref("master"), Some(ours[0]));
+    }
+}
@@ -0,0 +1,879 @@
+//! A length-prefixed protocol for talking to another dmv through a pipe
+//!
+//! Each message is a frame: a one-byte kind, an 8-byte big-endian payload
+//! length, then the payload. The client sends one request at a time and reads
+//! its response before sending the next.
+//!
+//! The first request is always `Hello`, carrying the client's protocol
+//! version, and the server answers with the version to use: the lower of the
+//! two. Hashes are sent as hex, lists of hashes as one per line, and refs as
+//! lines of `<hash> <name>`. Objects are sent whole, as the raw object file
+//! (header and content), so the receiver can check them against their
+//! hashes.
+//!
+//! Version 2 added `Missing`, which asks which of a list of objects the
+//! server lacks. A changed file's chunk index is sent with the hashes of all
+//! its chunks, so this finds the few chunks that changed in one round trip
+//! rather than one per chunk.
+//!
+//! Version 3 added `Chunking`, which asks for the server's chunking
+//! parameters, so a clone chunks files the same way as its source, and
+//! `MediaTypes` and `RecordMediaTypes`, so the media types recorded for files
+//! go with them. Media types are sent as lines of `<hash> <MIME type>`.
+//!
+//! Version 4 added `SwapRef`, which sets a ref only if it still points where
+//! the client expects, as `<old hash> <new hash> <name>`, with `-` for a ref
+//! that should not exist yet. The server checks and sets the ref under the
+//! repository's lock, so two clients pushing at once can't both win.
+
+use byteorder::BigEndian;
+use byteorder::ReadBytesExt;
+use byteorder::WriteBytesExt;
+use dag::HashAlgorithm;
+use dag::ObjectKey;
+use dag::ObjectSize;
+use error::*;
+use media_type::MediaType;
+use object_store::MediaTypeMap;
+use object_store::RefMap;
+use rolling_hash::ChunkParams;
+use std::io;
+use std::io::BufRead;
+use std::io::Read;
+use std::io::Write;
+use super::Transport;
+
+/// Version of the protocol spoken by this build
+pub const PROTOCOL_VERSION: u32 = 4;
+
+/// Oldest version of the protocol this build can still speak
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Largest payload accepted, to fail fast on garbage rather than run out of
+/// memory
+///
+/// Chunks average 19 KiB, so the objects that come near this are chunk
+/// indexes, at 36 bytes per chunk: enough for a file of about 8 GiB.
+const MAX_FRAME_SIZE: u64 = 16 << 20;
+
+/// A request from client to server
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum Request {
+    /// Start of a session, with the client's protocol version
+    Hello(u32),
+    ListRefs,
+    HashAlgorithm,
+    /// What are the server's chunking parameters? (version 3)
+    Chunking,
+    /// Which media types has the server recorded for these files? (version 3)
+    MediaTypes(Vec<ObjectKey>),
+    /// Record these media types (version 3)
+    RecordMediaTypes(MediaTypeMap),
+    /// Does the server have this object?
+    Have(ObjectKey),
+    /// Which of these objects does the server lack? (version 2)
+    Missing(Vec<ObjectKey>),
+    /// Send this object
+    Want(ObjectKey),
+    /// Store this raw object
+    Put(ObjectKey, Vec<u8>),
+    /// Set this ref, whatever it points at now (versions 1 to 3)
+    UpdateRef(String, ObjectKey),
+    /// Set this ref if it points at the given hash, or doesn't exist if None
+    /// (version 4)
+    SwapRef(String, Option<ObjectKey>, ObjectKey),
+}
+
+/// A response from server to client
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum Response {
+    Hello(u32),
+    Refs(RefMap),
+    HashAlgorithm(HashAlgorithm),
+    Chunking(ChunkParams),
+    MediaTypes(MediaTypeMap),
+    Have(bool),
+    Missing(Vec<ObjectKey>),
+    Object(Vec<u8>),
+    Stored(ObjectSize),
+    Done,
+    Error(String),
+}
+
+/// Write one frame
+fn write_frame(w: &mut Write, kind: u8, payload: &[u8]) -> Result<()> {
+    w.write_u8(kind)?;
+    w.write_u64::<BigEndian>(payload.len() as u64)?;
+    w.write_all(payload)?;
+    Ok(())
+}
+
+/// Read one frame, or None if the stream ends cleanly before it
+fn read_frame(r: &mut BufRead) -> Result<Option<(u8, Vec<u8>)>> {
+    if r.fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+    let kind = r.read_u8()?;
+    let len = r.read_u64::<BigEndian>()?;
+    if len > MAX_FRAME_SIZE {
+        bail!("Protocol frame of {} bytes is too large", len);
+    }
+    // Grow the buffer as bytes arrive, so a bad length can't allocate it all
+    let mut payload = Vec::new();
+    r.take(len).read_to_end(&mut payload)?;
+    if payload.len() as u64 != len {
+        bail!("Connection closed in the middle of a protocol frame");
+    }
+    Ok(Some((kind, payload)))
+}
+
+fn payload_str(payload: &[u8]) -> Result<&str> {
+    ::std::str::from_utf8(payload)
+        .chain_err(|| "Bad UTF-8 in protocol message")
+}
+
+fn parse_key(payload: &[u8]) -> Result<ObjectKey> {
+    ObjectKey::parse(payload_str(payload)?)
+}
+
+fn write_keys(w: &mut Write, kind: u8, keys: &[ObjectKey]) -> Result<()> {
+    let mut payload = String::new();
+    for key in keys {
+        payload.push_str(&key.to_hex());
+        payload.push('\n');
+    }
+    write_frame(w, kind, payload.as_bytes())
+}
+
+fn parse_keys(payload: &[u8]) -> Result<Vec<ObjectKey>> {
+    payload_str(payload)?
+        .lines()
+        .map(ObjectKey::parse)
+        .collect()
+}
+
+/// Split a `<hash> <name>` line
+fn parse_key_and_name(line: &str) -> Result<(ObjectKey, String)> {
+    let mut split = line.splitn(2, ' ');
+    match (split.next(), split.next()) {
+        (Some(hash), Some(name)) => Ok((ObjectKey::parse(hash)?, name.into())),
+        _ => bail!("Bad ref line in protocol message: {}", line),
+    }
+}
+
+fn parse_version(payload: &[u8]) -> Result<u32> {
+    payload_str(payload)?
+        .parse()
+        .chain_err(|| "Bad protocol version")
+}
+
+fn write_media_types(w: &mut Write,
+                     kind: u8,
+                     types: &MediaTypeMap)
+                     -> Result<()> {
+    let mut payload = String::new();
+    for (key, media_type) in types {
+        payload.push_str(&format!("{} {}\n", key.to_hex(), media_type));
+    }
+    write_frame(w, kind, payload.as_bytes())
+}
+
+fn parse_media_types(payload: &[u8]) -> Result<MediaTypeMap> {
+    let mut types = MediaTypeMap::new();
+    for line in payload_str(payload)?.lines() {
+        let (key, name) = parse_key_and_name(line)?;
+        types.insert(key, name.parse::<MediaType>()?);
+    }
+    Ok(types)
+}
+
+/// Split a `<window size> <match size>` line
+fn parse_chunking(payload: &[u8]) -> Result<ChunkParams> {
+    let line = payload_str(payload)?;
+    let mut split = line.splitn(2, ' ');
+    match (split.next().map(str::parse), split.next().map(str::parse)) {
+        (Some(Ok(window_size)), Some(Ok(match_size))) => {
+            Ok(ChunkParams {
+                window_size: window_size,
+                match_size: match_size,
+            })
+        }
+        _ => bail!("Bad chunking parameters in protocol message: {}", line),
+    }
+}
+
+impl Request {
+    pub fn write_to(&self, w: &mut Write) -> Result<()> {
+        match *self {
+            Request::Hello(version) => {
+                write_frame(w, b'H', version.to_string().as_bytes())
+            }
+            Request::ListRefs => write_frame(w, b'L', &[]),
+            Request::HashAlgorithm => write_frame(w, b'A', &[]),
+            Request::Chunking => write_frame(w, b'C', &[]),
+            Request::MediaTypes(ref keys) => write_keys(w, b'T', keys),
+            Request::RecordMediaTypes(ref types) => {
+                write_media_types(w, b'R', types)
+            }
+            Request::Have(ref key) => {
+                write_frame(w, b'?', key.to_hex().as_bytes())
+            }
+            Request::Missing(ref keys) => write_keys(w, b'M', keys),
+            Request::Want(ref key) => {
+                write_frame(w, b'W', key.to_hex().as_bytes())
+            }
+            Request::Put(ref key, ref raw) => {
+                let mut payload = key.to_hex().into_bytes();
+                payload.push(b'\n');
+                payload.extend_from_slice(raw);
+                write_frame(w, b'P', &payload)
+            }
+            Request::UpdateRef(ref name, ref key) => {
+                let line = format!("{} {}", key.to_hex(), name);
+                write_frame(w, b'U', line.as_bytes())
+            }
+            Request::SwapRef(ref name, ref expected, ref key) => {
+                let line = format!("{} {} {}",
+                                   expected.map_or("-".to_owned(),
+                                                   |h| h.to_hex()),
+                                   key.to_hex(),
+                                   name);
+                write_frame(w, b'S', line.as_bytes())
+            }
+        }
+    }
+
+    /// Read a request, or None if the client has closed the stream
+    pub fn read_from(r: &mut BufRead) -> Result<Option<Self>> {
+        let (kind, payload) = match read_frame(r)? {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+        Ok(Some(match kind {
+            b'H' => Request::Hello(parse_version(&payload)?),
+            b'L' => Request::ListRefs,
+            b'A' => Request::HashAlgorithm,
+            b'C' => Request::Chunking,
+            b'T' => Request::MediaTypes(parse_keys(&payload)?),
+            b'R' => Request::RecordMediaTypes(parse_media_types(&payload)?),
+            b'?' => Request::Have(parse_key(&payload)?),
+            b'M' => Request::Missing(parse_keys(&payload)?),
+            b'W' => Request::Want(parse_key(&payload)?),
+            b'P' => {
+                let split = payload.iter()
+                    .position(|&b| b == b'\n')
+                    .ok_or("Put request has no hash")?;
+                let key = parse_key(&payload[..split])?;
+                Request::Put(key, payload[split + 1..].to_vec())
+            }
+            b'U' => {
+                let (key, name) = parse_key_and_name(payload_str(&payload)?)?;
+                Request::UpdateRef(name, key)
+            }
+            b'S' => {
+                let line = payload_str(&payload)?;
+                let mut split = line.splitn(2, ' ');
+                let expected = match split.next() {
+                    Some("-") => None,
+                    Some(hash) => Some(ObjectKey::parse(hash)?),
+                    None => bail!("Bad ref swap in protocol message: {}", line),
+                };
+                let (key, name) =
+                    parse_key_and_name(split.next().unwrap_or(""))?;
+                Request::SwapRef(name, expected, key)
+            }
+            other => bail!("Unknown request kind {:?}", other as char),
+        }))
+    }
+}
+
+impl Response {
+    pub fn write_to(&self, w: &mut Write) -> Result<()> {
+        match *self {
+            Response::Hello(version) => {
+                write_frame(w, b'h', version.to_string().as_bytes())
+            }
+            Response::Refs(ref refs) => {
+                let mut payload = String::new();
+                for (name, key) in refs {
+                    payload.push_str(&format!("{} {}\n", key.to_hex(), name));
+                }
+                write_frame(w, b'r', payload.as_bytes())
+            }
+            Response::HashAlgorithm(algorithm) => {
+                write_frame(w, b'a', algorithm.to_string().as_bytes())
+            }
+            Response::Chunking(params) => {
+                let line = format!("{} {}",
+                                   params.window_size,
+                                   params.match_size);
+                write_frame(w, b'c', line.as_bytes())
+            }
+            Response::MediaTypes(ref types) => {
+                write_media_types(w, b't', types)
+            }
+            Response::Have(have) => {
+                write_frame(w, b'y', if have { b"1" } else { b"0" })
+            }
+            Response::Missing(ref keys) => write_keys(w, b'm', keys),
+            Response::Object(ref raw) => write_frame(w, b'o', raw),
+            Response::Stored(size) => {
+                write_frame(w, b's', size.to_string().as_bytes())
+            }
+            Response::Done => write_frame(w, b'd', &[]),
+            Response::Error(ref message) => {
+                write_frame(w, b'e', message.as_bytes())
+            }
+        }
+    }
+
+    pub fn read_from(r: &mut BufRead) -> Result<Self> {
+        let (kind, payload) = match read_frame(r)? {
+            Some(frame) => frame,
+            None => bail!("Connection closed while waiting for a response"),
+        };
+        Ok(match kind {
+            b'h' => Response::Hello(parse_version(&payload)?),
+            b'r' => {
+                let mut refs = RefMap::new();
+                for line in payload_str(&payload)?.lines() {
+                    let (key, name) = parse_key_and_name(line)?;
+                    refs.insert(name, key);
+                }
+                Response::Refs(refs)
+            }
+            b'a' => Response::HashAlgorithm(payload_str(&payload)?.parse()?),
+            b'c' => Response::Chunking(parse_chunking(&payload)?),
+            b't' => Response::MediaTypes(parse_media_types(&payload)?),
+            b'y' => Response::Have(payload == b"1"),
+            b'm' => Response::Missing(parse_keys(&payload)?),
+            b'o' => Response::Object(payload),
+            b's' => {
+                Response::Stored(payload_str(&payload)?
+                    .parse()
+                    .chain_err(|| "Bad size in protocol message")?)
+            }
+            b'd' => Response::Done,
+            b'e' => Response::Error(payload_str(&payload)?.to_owned()),
+            other => bail!("Unknown response kind {:?}", other as char),
+        })
+    }
+
+    /// Short name of the response kind, for error messages
+    fn name(&self) -> &'static str {
+        match *self {
+            Response::Hello(_) => "hello",
+            Response::Refs(_) => "refs",
+            Response::HashAlgorithm(_) => "hash algorithm",
+            Response::Chunking(_) => "chunking",
+            Response::MediaTypes(_) => "media types",
+            Response::Have(_) => "have",
+            Response::Missing(_) => "missing",
+            Response::Object(_) => "object",
+            Response::Stored(_) => "stored",
+            Response::Done => "done",
+            Response::Error(_) => "error",
+        }
+    }
+}
+
+/// Client side of the protocol, over any pair of streams
+pub struct StreamTransport<R: BufRead, W: Write> {
+    description: String,
+    reader: R,
+    writer: W,
+    /// Protocol version agreed with the server
+    version: u32,
+}
+
+impl<R: BufRead, W: Write> StreamTransport<R, W> {
+    /// Start a session, checking that the server speaks the same protocol
+    pub fn new(description: String, reader: R, writer: W) -> Result<Self> {
+        let mut transport = StreamTransport {
+            description: description,
+            reader: reader,
+            writer: writer,
+            version: PROTOCOL_VERSION,
+        };
+        let supported = MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION;
+        match transport.call(Request::Hello(PROTOCOL_VERSION))? {
+            Response::Hello(version) if supported.contains(&version) => {
+                transport.version = version;
+                Ok(transport)
+            }
+            Response::Hello(version) => {
+                bail!("{} speaks protocol version {}, but this dmv speaks \
+                       versions {} to {}",
+                      transport.description,
+                      version,
+                      MIN_PROTOCOL_VERSION,
+                      PROTOCOL_VERSION)
+            }
+            other => Err(transport.unexpected(other)),
+        }
+    }
+
+    /// Send a request and wait for its response
+    fn call(&mut self, request: Request) -> Result<Response> {
+        request.write_to(&mut self.writer)?;
+        self.writer.flush()?;
+        match Response::read_from(&mut self.reader)
+            .chain_err(|| format!("Lost connection to {}", self.description))? {
+            Response::Error(message) => {
+                bail!("{} reported an error: {}", self.description, message)
+            }
+            response => Ok(response),
+        }
+    }
+
+    fn unexpected(&self, response: Response) -> Error {
+        format!("Unexpected {} response from {}",
+                response.name(),
+                self.description)
+            .into()
+    }
+}
+
+impl<R: BufRead, W: Write> Transport for StreamTransport<R, W> {
+    fn describe(&self) -> String { self.description.clone() }
+
+    fn list_refs(&mut self) -> Result<RefMap> {
+        match self.call(Request::ListRefs)? {
+            Response::Refs(refs) => Ok(refs),
+            other => Err(self.unexpected(other)),
+        }
+    }
+
+    fn hash_algorithm(&mut self) -> Result<HashAlgorithm> {
+        match self.call(Request::HashAlgorithm)? {
+            Response::HashAlgorithm(algorithm) => Ok(algorithm),
+            other => Err(self.unexpected(other)),
+        }
+    }
+
+    fn chunking(&mut self) -> Result<ChunkParams> {
+        if self.version < 3 {
+            // Older servers predate configurable chunking
+            return Ok(ChunkParams::default());
+        }
+        match self.call(Request::Chunking)? {
+            Response::Chunking(params) => Ok(params),
+            other => Err(self.unexpected(other)),
+        }
+    }
+
+    fn media_types(&mut self, keys: &[ObjectKey]) -> Result<MediaTypeMap> {
+        if self.version < 3 || keys.is_empty() {
+            return Ok(MediaTypeMap::new());
+        }
+        match self.call(Request::MediaTypes(keys.to_vec()))? {
+            Response::MediaTypes(types) => Ok(types),
+            other => Err(self.unexpected(other)),
+        }
+    }
+
+    fn record_media_types(&mut self, types: &MediaTypeMap) -> Result<()> {
+        if self.version < 3 {
+            // Older servers keep no media types
+            return Ok(());
+        }
+        match self.call(Request::RecordMediaTypes(types.clone()))? {
+            Response::Done => Ok(()),
+            other => Err(self.unexpected(other)),
+        }
+    }
+
+    fn read_object_file(&mut self,
+                        key: &ObjectKey)
+                        -> Result<Box<io::BufRead>> {
+        match self.call(Request::Want(*key))? {
+            Response::Object(raw) => Ok(Box::new(io::Cursor::new(raw))),
+            other => Err(self.unexpected(other)),
+        }
+    }
+
+    fn has_object(&mut self, key: &ObjectKey) -> Result<bool> {
+        match self.call(Request::Have(*key))? {
+            Response::Have(have) => Ok(have),
+            other => Err(self.unexpected(other)),
+        }
+    }
+
+    fn missing_objects(&mut self,
+                       keys: &[ObjectKey])
+                       -> Result<Vec<ObjectKey>> {
+        if self.version < 2 {
+            let mut missing = Vec::new();
+            for key in keys {
+                if !self.has_object(key)? {
+                    missing.push(*key);
+                }
+            }
+            return Ok(missing);
+        }
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        match self.call(Request::Missing(keys.to_vec()))? {
+            Response::Missing(missing) => Ok(missing),
+            other => Err(self.unexpected(other)),
+        }
+    }
+
+    fn write_object_file(&mut self,
+                         key: &ObjectKey,
+                         reader: &mut io::Read)
+                         -> Result<ObjectSize> {
+        let mut raw = Vec::new();
+        reader.read_to_end(&mut raw)?;
+        match self.call(Request::Put(*key, raw))? {
+            Response::Stored(size) => Ok(size),
+            other => Err(self.unexpected(other)),
+        }
+    }
+
+    fn update_ref(&mut self,
+                  name: &str,
+                  expected: Option<ObjectKey>,
+                  hash: ObjectKey)
+                  -> Result<()> {
+        let request = if self.version < 4 {
+            // Older servers can only set a ref outright, so check it here.
+            // Another client could still slip in between the two requests.
+            let current = self.list_refs()?.get(name).cloned();
+            if current != expected {
+                bail!("Ref {} changed: expected {}, but it is now {}",
+                      name,
+                      expected.map_or("nothing".to_owned(), |h| h.to_string()),
+                      current.map_or("nothing".to_owned(), |h| h.to_string()));
+            }
+            Request::UpdateRef(name.to_owned(), hash)
+        } else {
+            Request::SwapRef(name.to_owned(), expected, hash)
+        };
+        match self.call(request)? {
+            Response::Done => Ok(()),
+            other => Err(self.unexpected(other)),
+        }
+    }
+}
+
+/// Server side of the protocol: answer requests until the client hangs up
+///
+/// Requests are answered from the given repository, usually a
+/// `LocalTransport`. An error in handling one request is sent back to the
+/// client and the session goes on, but an error reading or writing the
+/// streams ends the session.
+pub fn serve(repo: &mut Transport,
+             reader: &mut BufRead,
+             writer: &mut Write)
+             -> Result<()> {
+    while let Some(request) = Request::read_from(reader)? {
+        let response = match handle_request(repo, request) {
+            Ok(response) => response,
+            Err(e) => {
+                let message = e.iter()
+                    .map(|e| e.to_string())
+                    .collect::<Vec<_>>()
+                    .join(": ");
+                debug!("Request failed: {}", message);
+                Response::Error(message)
+            }
+        };
+        response.write_to(writer)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+fn handle_request(repo: &mut Transport, request: Request) -> Result<Response> {
+    Ok(match request {
+        Request::Hello(version) => {
+            debug!("Client speaks protocol version {}", version);
+            Response::Hello(::std::cmp::min(version, PROTOCOL_VERSION))
+        }
+        Request::ListRefs => Response::Refs(repo.list_refs()?),
+        Request::HashAlgorithm => {
+            Response::HashAlgorithm(repo.hash_algorithm()?)
+        }
+        Request::Chunking => Response::Chunking(repo.chunking()?),
+        Request::MediaTypes(keys) => {
+            Response::MediaTypes(repo.media_types(&keys)?)
+        }
+        Request::RecordMediaTypes(types) => {
+            repo.record_media_types(&types)?;
+            Response::Done
+        }
+        Request::Have(key) => Response::Have(repo.has_object(&key)?),
+        Request::Missing(keys) => {
+            Response::Missing(repo.missing_objects(&keys)?)
+        }
+        Request::Want(key) => {
+            let mut raw = Vec::new();
+            repo.read_object_file(&key)?.read_to_end(&mut raw)?;
+            Response::Object(raw)
+        }
+        Request::Put(key, raw) => {
+            Response::Stored(repo.write_object_file(&key, &mut raw.as_slice())?)
+        }
+        Request::UpdateRef(name, key) => {
+            // Older clients don't say what they expect, so expect whatever is
+            // there now
+            let current = repo.list_refs()?.get(&name).cloned();
+            repo.update_ref(&name, current, key)?;
+            Response::Done
+        }
+        Request::SwapRef(name, expected, key) => {
+            repo.update_ref(&name, expected, key)?;
+            Response::Done
+        }
+    })
+}
+
+
+#[cfg(test)]
+mod test {
+    use dag::Blob;
+    use dag::ChunkedBlob;
+    use object_store::ObjectStore;
+    use object_store::test::create_temp_repository;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use std::sync::mpsc;
+    use std::thread;
+    use super::*;
+    use super::super::LocalTransport;
+    use super::super::copy_objects;
+    use super::super::send_objects;
+
+    #[test]
+    fn test_protocol_round_trip() {
+        let key = ObjectKey::from("d3486ae9136e7856bc42212385ea797094475802");
+        let mut types = MediaTypeMap::new();
+        types.insert(key, MediaType::QuickTime);
+        let requests = vec![Request::Hello(PROTOCOL_VERSION),
+                            Request::ListRefs,
+                            Request::Chunking,
+                            Request::MediaTypes(vec![key]),
+                            Request::RecordMediaTypes(types.clone()),
+                            Request::Have(key),
+                            Request::Missing(vec![key, key]),
+                            Request::Put(key, b"raw\nobject".to_vec()),
+                            Request::UpdateRef("feature/x y".to_owned(), key),
+                            Request::SwapRef("feature/x y".to_owned(),
+                                             None,
+                                             key),
+                            Request::SwapRef("master".to_owned(),
+                                             Some(key),
+                                             key)];
+        let mut buf = Vec::new();
+        for request in &requests {
+            request.write_to(&mut buf).unwrap();
+        }
+        let mut reader = buf.as_slice();
+        for request in &requests {
+            assert_eq!(Request::read_from(&mut reader).unwrap().as_ref(),
+                       Some(request));
+        }
+        assert_eq!(Request::read_from(&mut reader).unwrap(),
+                   None,
+                   "Clean end of stream");
+
+        let mut refs = RefMap::new();
+        refs.insert("master".to_owned(), key);
+        let responses = vec![Response::Refs(refs),
+                             Response::HashAlgorithm(HashAlgorithm::Sha1),
+                             Response::Chunking(ChunkParams {
+                                 window_size: 4096,
+                                 match_size: 1 << 14,
+                             }),
+                             Response::MediaTypes(types),
+                             Response::Have(true),
+                             Response::Missing(vec![]),
+                             Response::Missing(vec![key]),
+                             Response::Object(vec![0, 1, 2]),
+                             Response::Stored(1234),
+                             Response::Error("oops".to_owned())];
+        let mut buf = Vec::new();
+        for response in &responses {
+            response.write_to(&mut buf).unwrap();
+        }
+        let mut reader = buf.as_slice();
+        for response in &responses {
+            assert_eq!(&Response::read_from(&mut reader).unwrap(), response);
+        }
+
+        let mut truncated = &buf[..4];
+        assert!(Response::read_from(&mut truncated).is_err());
+
+        let mut oversized = Vec::new();
+        oversized.write_u8(b'o').unwrap();
+        oversized.write_u64::<BigEndian>(MAX_FRAME_SIZE + 1).unwrap();
+        assert!(Response::read_from(&mut oversized.as_slice()).is_err(),
+                "Should reject a frame larger than the limit");
+    }
+
+    /// One direction of an in-memory pipe
+    struct PipeWriter(mpsc::Sender<Vec<u8>>);
+    struct PipeReader(mpsc::Receiver<Vec<u8>>, io::Cursor<Vec<u8>>);
+
+    fn pipe() -> (PipeWriter, io::BufReader<PipeReader>) {
+        let (tx, rx) = mpsc::channel();
+        let reader = PipeReader(rx, io::Cursor::new(Vec::new()));
+        (PipeWriter(tx), io::BufReader::new(reader))
+    }
+
+    impl Write for PipeWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.send(buf.to_vec()).ok();
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    impl Read for PipeReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            loop {
+                let n = self.1.read(buf)?;
+                if n > 0 || buf.is_empty() {
+                    return Ok(n);
+                }
+                match self.0.recv() {
+                    Ok(bytes) => self.1 = io::Cursor::new(bytes),
+                    Err(_) => return Ok(0),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_serve() {
+        let (_server_temp, mut server_store) = create_temp_repository()
+            .unwrap();
+        let (_client_temp, client_store) = create_temp_repository().unwrap();
+        let hash = server_store.store_object(&Blob::from("Hello!")).unwrap();
+        server_store.update_ref("master".to_owned(), hash).unwrap();
+
+        let mut repo = LocalTransport::open(server_store.path()).unwrap();
+        let (request_tx, mut request_rx) = pipe();
+        let (mut response_tx, response_rx) = pipe();
+        let server = thread::spawn(move || {
+            serve(&mut repo, &mut request_rx, &mut response_tx)
+        });
+
+        {
+            let mut client = StreamTransport::new("server".to_owned(),
+                                                  response_rx,
+                                                  request_tx)
+                .unwrap();
+            assert_eq!(client.list_refs().unwrap().get("master"),
+                       Some(&hash));
+            assert_eq!(client.hash_algorithm().unwrap(),
+                       server_store.hash_algorithm());
+
+            let stats = copy_objects(&mut client, &client_store, &[hash])
+                .unwrap();
+            assert_eq!(stats.objects, 1);
+            assert!(client_store.has_object(&hash));
+
+            let other = client_store.store_object(&Blob::from("Hi!"))
+                .unwrap();
+            send_objects(&client_store, &mut client, &[other]).unwrap();
+            client.update_ref("other", None, other).unwrap();
+            assert!(client.update_ref("master", None, other).is_err(),
+                    "Should refuse to move a ref that has changed");
+
+            let absent =
+                ObjectKey::from("d3486ae9136e7856bc42212385ea797094475802");
+            assert!(client.read_object_file(&absent).is_err(),
+                    "Should report an error for a missing object");
+            assert!(client.list_refs().is_ok(),
+                    "Session should go on after an error");
+        }
+        server.join().unwrap().unwrap();
+
+        let server_store = ObjectStore::open(server_store.path().to_owned())
+            .unwrap();
+        let other = server_store.refs().get("other").cloned().unwrap();
+        assert!(server_store.has_object(&other));
+        assert_eq!(server_store.refs().get("master"), Some(&hash));
+    }
+
+    /// Counts bytes written, and flushes, which is one per request
+    struct CountingWriter(PipeWriter, Arc<(AtomicUsize, AtomicUsize)>);
+
+    impl Write for CountingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            (self.1).0.fetch_add(buf.len(), Ordering::SeqCst);
+            self.0.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            (self.1).1.fetch_add(1, Ordering::SeqCst);
+            self.0.flush()
+        }
+    }
+
+    #[test]
+    fn test_send_only_changed_chunks() {
+        let (_server_temp, server_store) = create_temp_repository().unwrap();
+        let (_client_temp, client_store) = create_temp_repository().unwrap();
+        let store_file = |changed: Option<usize>| {
+            let mut index = ChunkedBlob::new();
+            for i in 0..64 {
+                let fill = if Some(i) == changed { 'x' } else { 'a' };
+                let chunk = format!("chunk {:04} {}",
+                                    i,
+                                    ::std::iter::repeat(fill)
+                                        .take(4000)
+                                        .collect::<String>());
+                let hash = client_store.store_object(&Blob::from(&chunk[..]))
+                    .unwrap();
+                index.add_chunk(chunk.len() as ObjectSize, hash);
+            }
+            client_store.store_object(&index).unwrap()
+        };
+        let original = store_file(None);
+        let edited = store_file(Some(10));
+
+        let mut repo = LocalTransport::open(server_store.path()).unwrap();
+        let (request_tx, mut request_rx) = pipe();
+        let (mut response_tx, response_rx) = pipe();
+        let server = thread::spawn(move || {
+            serve(&mut repo, &mut request_rx, &mut response_tx)
+        });
+
+        let counts = Arc::new((AtomicUsize::new(0), AtomicUsize::new(0)));
+        let sent = || {
+            (counts.0.swap(0, Ordering::SeqCst),
+             counts.1.swap(0, Ordering::SeqCst))
+        };
+        {
+            let writer = CountingWriter(request_tx, counts.clone());
+            let mut client =
+                StreamTransport::new("server".to_owned(), response_rx, writer)
+                    .unwrap();
+            sent();
+
+            send_objects(&client_store, &mut client, &[original]).unwrap();
+            let (original_bytes, _) = sent();
+            assert!(original_bytes > 64 * 4000);
+
+            let stats = send_objects(&client_store, &mut client, &[edited])
+                .unwrap();
+            let (edited_bytes, edited_requests) = sent();
+            assert_eq!(stats.objects, 2, "Only the index and changed chunk");
+            assert!(edited_bytes < original_bytes / 10,
+                    "Sent {} bytes for one changed chunk, {} for the whole \
+                     file",
+                    edited_bytes,
+                    original_bytes);
+            assert_eq!(edited_requests,
+                       5,
+                       "HashAlgorithm, Have, Missing, and Put the chunk and \
+                        the index: one round trip to find the changed chunk, \
+                        not one per chunk");
+        }
+        server.join().unwrap().unwrap();
+    }
+}
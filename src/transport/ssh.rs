@@ -0,0 +1,212 @@
+//! Transport to a repository on another host, through ssh
+//!
+//! Runs `dmv serve --stdio <path>` on the other host, and speaks the stdio
+//! protocol over the ssh connection, so no server daemon is needed. The ssh
+//! command can be replaced with the `DMV_SSH` environment variable, such as
+//! `DMV_SSH="ssh -i key"`.
+
+use dag::HashAlgorithm;
+use dag::ObjectKey;
+use dag::ObjectSize;
+use error::*;
+use object_store::MediaTypeMap;
+use object_store::RefMap;
+use rolling_hash::ChunkParams;
+use std::env;
+use std::io;
+use std::process::Child;
+use std::process::ChildStdin;
+use std::process::ChildStdout;
+use std::process::Command;
+use std::process::Stdio;
+use super::StreamTransport;
+use super::Transport;
+
+/// Where to reach a repository by ssh
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct SshUrl {
+    /// Host, optionally with a user, as in `user@host`
+    pub host: String,
+    pub port: Option<u16>,
+    /// Path on the host, relative to the user's home if not absolute
+    pub path: String,
+}
+
+impl SshUrl {
+    /// Parse an ssh URL, or None if the URL is not for ssh
+    ///
+    /// Accepts `ssh://[user@]host[:port]/path`, and the scp-like
+    /// `[user@]host:path`. A local path with a colon before its first slash
+    /// looks like the second form, so write it as `./name:with:colons`.
+    pub fn parse(url: &str) -> Option<Self> {
+        if let Some(rest) = url.strip_prefix("ssh://") {
+            let slash = rest.find('/').unwrap_or(rest.len());
+            let (authority, path) = rest.split_at(slash);
+            let (host, port) = match authority.rfind(':') {
+                Some(i) => (&authority[..i], authority[i + 1..].parse().ok()),
+                None => (authority, None),
+            };
+            if host.is_empty() || path.is_empty() {
+                return None;
+            }
+            return Some(SshUrl {
+                host: host.to_owned(),
+                port: port,
+                path: path.to_owned(),
+            });
+        }
+        if url.contains("://") {
+            return None;
+        }
+        let colon = url.find(':')?;
+        let host = &url[..colon];
+        let is_drive_letter = cfg!(windows) && host.len() == 1;
+        if host.is_empty() || host.contains('/') || is_drive_letter {
+            return None;
+        }
+        Some(SshUrl {
+            host: host.to_owned(),
+            port: None,
+            path: url[colon + 1..].to_owned(),
+        })
+    }
+}
+
+/// Quote a string for the remote shell
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace("'", "'\\''"))
+}
+
+/// A dmv server running on another host, reached through ssh
+pub struct SshTransport {
+    /// Taken on drop, to close the server's stdin before waiting for it
+    stream: Option<StreamTransport<io::BufReader<ChildStdout>, ChildStdin>>,
+    child: Child,
+}
+
+impl SshTransport {
+    pub fn connect(url: &SshUrl) -> Result<Self> {
+        let ssh = env::var("DMV_SSH").unwrap_or("ssh".to_owned());
+        let mut words = ssh.split_whitespace();
+        let mut command =
+            Command::new(words.next().ok_or("DMV_SSH is empty")?);
+        command.args(words);
+        if let Some(port) = url.port {
+            command.arg("-p").arg(port.to_string());
+        }
+        command.arg(&url.host)
+            .arg(format!("dmv serve --stdio {}", shell_quote(&url.path)))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped());
+        debug!("Running {:?}", command);
+        let mut child = command.spawn()
+            .chain_err(|| format!("Could not run {}", ssh))?;
+
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let description = format!("{}:{}", url.host, url.path);
+        let stream = StreamTransport::new(description,
+                                          io::BufReader::new(stdout),
+                                          stdin)?;
+        Ok(SshTransport {
+            stream: Some(stream),
+            child: child,
+        })
+    }
+
+    fn stream(&mut self)
+              -> &mut StreamTransport<io::BufReader<ChildStdout>, ChildStdin> {
+        self.stream.as_mut().expect("stream is only taken on drop")
+    }
+}
+
+impl Drop for SshTransport {
+    fn drop(&mut self) {
+        self.stream.take();
+        if let Err(e) = self.child.wait() {
+            warn!("Could not wait for ssh to exit: {}", e);
+        }
+    }
+}
+
+impl Transport for SshTransport {
+    fn describe(&self) -> String {
+        self.stream.as_ref().expect("stream is only taken on drop").describe()
+    }
+
+    fn list_refs(&mut self) -> Result<RefMap> { self.stream().list_refs() }
+
+    fn hash_algorithm(&mut self) -> Result<HashAlgorithm> {
+        self.stream().hash_algorithm()
+    }
+
+    fn chunking(&mut self) -> Result<ChunkParams> {
+        self.stream().chunking()
+    }
+
+    fn media_types(&mut self, keys: &[ObjectKey]) -> Result<MediaTypeMap> {
+        self.stream().media_types(keys)
+    }
+
+    fn record_media_types(&mut self, types: &MediaTypeMap) -> Result<()> {
+        self.stream().record_media_types(types)
+    }
+
+    fn read_object_file(&mut self,
+                        key: &ObjectKey)
+                        -> Result<Box<io::BufRead>> {
+        self.stream().read_object_file(key)
+    }
+
+    fn has_object(&mut self, key: &ObjectKey) -> Result<bool> {
+        self.stream().has_object(key)
+    }
+
+    fn missing_objects(&mut self,
+                       keys: &[ObjectKey])
+                       -> Result<Vec<ObjectKey>> {
+        self.stream().missing_objects(keys)
+    }
+
+    fn write_object_file(&mut self,
+                         key: &ObjectKey,
+                         reader: &mut io::Read)
+                         -> Result<ObjectSize> {
+        self.stream().write_object_file(key, reader)
+    }
+
+    fn update_ref(&mut self,
+                  name: &str,
+                  expected: Option<ObjectKey>,
+                  hash: ObjectKey)
+                  -> Result<()> {
+        self.stream().update_ref(name, expected, hash)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_ssh_url() {
+        assert_eq!(SshUrl::parse("ssh://me@example.com:2222/srv/media"),
+                   Some(SshUrl {
+                       host: "me@example.com".to_owned(),
+                       port: Some(2222),
+                       path: "/srv/media".to_owned(),
+                   }));
+        assert_eq!(SshUrl::parse("example.com:media"),
+                   Some(SshUrl {
+                       host: "example.com".to_owned(),
+                       port: None,
+                       path: "media".to_owned(),
+                   }));
+        assert_eq!(SshUrl::parse("ssh://example.com"), None);
+        assert_eq!(SshUrl::parse("file:///srv/media"), None);
+        assert_eq!(SshUrl::parse("/srv/media"), None);
+        assert_eq!(SshUrl::parse("./name:with:colons"), None);
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+    }
+}
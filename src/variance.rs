@@ -1,3 +1,4 @@
+#[derive(Debug,Clone,Default)]
 pub struct VarianceCalc {
     k: i64,
     n: i64,
@@ -29,6 +30,24 @@ impl VarianceCalc {
             self.item(i);
         }
     }
+    /// Add all the items counted by another calculator
+    ///
+    /// Lets separate threads each count part of a set of items.
+    pub fn merge(&mut self, other: &VarianceCalc) {
+        if other.n == 0 {
+            return;
+        }
+        if self.n == 0 {
+            *self = other.clone();
+            return;
+        }
+        // Shift the other's sums to be relative to this one's k
+        let shift = other.k - self.k;
+        self.sum_sq += other.sum_sq + 2 * shift * other.sum +
+                       other.n * shift * shift;
+        self.sum += other.sum + other.n * shift;
+        self.n += other.n;
+    }
     pub fn count(&self) -> i64 { self.n }
     pub fn mean(&self) -> f64 {
         if self.n == 0 {
@@ -71,4 +90,17 @@ mod tests {
         assert_eq!(calc.mean(), 5.0, "mean");
         assert_eq!(calc.std(), 2.0, "std");
     }
+
+    #[test]
+    fn test_variance_calc_merge() {
+        let input: Vec<i64> = vec![2, 4, 4, 4, 5, 5, 7, 9];
+        let (mut left, mut right) = (VarianceCalc::new(), VarianceCalc::new());
+        left.items(input[..3].iter().cloned());
+        right.items(input[3..].iter().cloned());
+        left.merge(&right);
+        left.merge(&VarianceCalc::new());
+        assert_eq!(left.count(), 8);
+        assert_eq!(left.mean(), 5.0, "mean");
+        assert_eq!(left.std(), 2.0, "std");
+    }
 }
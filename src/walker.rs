@@ -2,13 +2,23 @@
 
 use error::*;
 use maputil::mux;
+use std::cmp;
 use std::collections::BTreeMap;
+use std::collections::btree_map;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::mpsc;
+use std::thread;
 
 /// Type for reading and iterating over a node's children
 pub type ChildMap<N> = BTreeMap<String, N>;
 
+/// Children read one at a time, failing on the first that could not be read
+pub fn all_children<N>(children: ChildMap<Result<N>>) -> Result<ChildMap<N>> {
+    children.into_iter().map(|(name, child)| Ok((name, child?))).collect()
+}
+
 
 /// A repository that can look up nodes by some handle
 pub trait NodeLookup<H, N>: NodeReader<N> {
@@ -33,6 +43,17 @@ pub trait NodeReader<N> {
     /// Given a node, read its children
     fn read_children(&self, node: &N) -> Result<ChildMap<N>>;
 
+    /// Given a node, read its children, each of which may fail on its own
+    ///
+    /// An error for the whole node means its children could not be listed.
+    /// Default implementation reads them all at once with `read_children`.
+    fn read_children_each(&self, node: &N) -> Result<ChildMap<Result<N>>> {
+        Ok(self.read_children(node)?
+            .into_iter()
+            .map(|(name, child)| (name, Ok(child)))
+            .collect())
+    }
+
     /// Do a walk operation, starting with the given node
     fn walk_node<O>(&self,
                     op: &mut O,
@@ -40,37 +61,358 @@ pub trait NodeReader<N> {
                     -> Result<Option<O::VisitResult>>
         where O: WalkOp<N>
     {
-        self.walk_node_stack(op, node, &mut PathBuf::new())
+        self.walk_node_with(op, node, &WalkOptions::default())
+            .map(|report| report.result)
     }
 
-    /// Do a walk operation, tracking the path stack
+    /// Do a walk operation, starting with the given node, with options
     ///
-    /// This is the inner function of the recursion
-    fn walk_node_stack<O>(&self,
-                          op: &mut O,
-                          node: N,
-                          path_stack: &mut PathBuf)
-                          -> Result<Option<O::VisitResult>>
+    /// The walk keeps its own stack of the directories it is in, rather than
+    /// recursing, so that a pathologically deep hierarchy cannot overflow the
+    /// thread's stack. It runs on the calling thread, whatever the number of
+    /// jobs in the options.
+    fn walk_node_with<O>(&self,
+                         op: &mut O,
+                         node: N,
+                         options: &WalkOptions)
+                         -> Result<WalkReport<O::VisitResult>>
         where O: WalkOp<N>
     {
-        if op.should_descend(path_stack, &node) {
-            trace!("-> {}", path_stack.display());
-            op.pre_descend(path_stack, &node)?;
-            let mut children = ChildMap::new();
-            for (name, node) in self.read_children(&node)? {
-                path_stack.push(name.to_owned());
-                if let Some(result) =
-                       self.walk_node_stack(op, node, path_stack)? {
-                    children.insert(name, result);
+        let mut errors = Vec::new();
+        let result = walk_from(self,
+                               op,
+                               Ok(node),
+                               PathBuf::new(),
+                               0,
+                               options,
+                               &mut errors)?;
+        Ok(WalkReport {
+            result: result,
+            errors: errors,
+        })
+    }
+
+    /// Do a walk operation, walking the start node's children in parallel
+    ///
+    /// Each child of the start node is walked by its own fork of the
+    /// operation, on up to `options.jobs` threads. The forks are joined back
+    /// into the operation in name order, each as soon as it and those before
+    /// it are done, so an operation that reports what it finds on joining
+    /// reports while the walk goes on. `post_descend` gets the children's
+    /// results as usual, so the outcome is the same as a walk on one thread.
+    /// If walking any child fails, and the error policy is to
+    /// fail fast, the first failure by name is returned.
+    fn walk_node_parallel<O>(&self,
+                             op: &mut O,
+                             node: N,
+                             options: &WalkOptions)
+                             -> Result<WalkReport<O::VisitResult>>
+        where Self: Sync,
+              N: Send,
+              O: ForkWalkOp<N>,
+              O::VisitResult: Send
+    {
+        if options.jobs <= 1 {
+            return self.walk_node_with(op, node, options);
+        }
+        let path_stack = PathBuf::new();
+        let mut errors = Vec::new();
+        let within_limit = options.max_depth.is_none_or(|max| max > 0);
+        if !within_limit || !op.should_descend(&path_stack, &node) {
+            trace!("** {}", path_stack.display());
+            let result = op.no_descend(&path_stack, node);
+            let result =
+                recover(result, &path_stack, options, &mut errors, op)?;
+            return Ok(WalkReport {
+                result: result.and_then(|r| r),
+                errors: errors,
+            });
+        }
+        trace!("-> {}", path_stack.display());
+        let children = op.pre_descend(&path_stack, &node)
+            .and_then(|_| self.read_children_each(&node));
+        let children =
+            match recover(children, &path_stack, options, &mut errors, op)? {
+                Some(children) => children,
+                None => {
+                    return Ok(WalkReport {
+                        result: None,
+                        errors: errors,
+                    })
                 }
-                path_stack.pop();
+            };
+        let threads = cmp::min(options.jobs, children.len());
+        let queue = children.into_iter()
+            .enumerate()
+            .map(|(index, (name, child))| (index, name, child, op.fork()))
+            .collect::<Vec<_>>()
+            .into_iter();
+        let queue = Mutex::new(queue);
+        let (done_tx, done_rx) = mpsc::channel();
+        let results = thread::scope(|scope| {
+            for _ in 0..threads {
+                let queue = &queue;
+                let done_tx = done_tx.clone();
+                scope.spawn(move || loop {
+                    let next = queue.lock().unwrap().next();
+                    let (index, name, child, mut fork) = match next {
+                        Some(next) => next,
+                        None => break,
+                    };
+                    let path = PathBuf::from(&name);
+                    let mut errors = Vec::new();
+                    let result = walk_from(self,
+                                           &mut fork,
+                                           child,
+                                           path,
+                                           1,
+                                           options,
+                                           &mut errors);
+                    if result.is_err() {
+                        // Stop the other threads taking more work
+                        *queue.lock().unwrap() = Vec::new().into_iter();
+                    }
+                    // Only fails if joining has already failed
+                    let _ = done_tx.send((index, name, result, errors, fork));
+                });
             }
-            trace!("<- {}", path_stack.display());
-            op.post_descend(path_stack, node, children)
-        } else {
-            trace!("** {}", path_stack.display());
-            op.no_descend(path_stack, node)
+            drop(done_tx);
+
+            // Join each fork as soon as those before it are joined, so what
+            // it found is not held back until the whole walk is done
+            let mut finished = BTreeMap::new();
+            let mut joined = 0;
+            let mut results = ChildMap::new();
+            for (index, name, result, child_errors, fork) in done_rx {
+                finished.insert(index, (name, result, child_errors, fork));
+                while let Some((name, result, child_errors, fork)) =
+                    finished.remove(&joined) {
+                    joined += 1;
+                    let result = result.and_then(|result| {
+                        errors.extend(child_errors);
+                        op.join(fork)?;
+                        Ok(result)
+                    });
+                    match result {
+                        Ok(Some(result)) => {
+                            results.insert(name, result);
+                        }
+                        Ok(None) => {}
+                        Err(e) => {
+                            *queue.lock().unwrap() = Vec::new().into_iter();
+                            return Err(e);
+                        }
+                    }
+                }
+            }
+            // Any left were cut short by a failure in one of them
+            for (_, (_, result, _, _)) in finished {
+                result?;
+            }
+            Ok(results)
+        })?;
+
+        trace!("<- {}", path_stack.display());
+        let result = op.post_descend(&path_stack, node, results);
+        let result = recover(result, &path_stack, options, &mut errors, op)?;
+        Ok(WalkReport {
+            result: result.and_then(|r| r),
+            errors: errors,
+        })
+    }
+}
+
+/// How `NodeReader::walk_node_with` walks a hierarchy
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct WalkOptions {
+    /// Do not descend into nodes this many levels below the start
+    ///
+    /// Nodes at the limit go to `no_descend`, as if `should_descend` had
+    /// returned false. With a limit of 0, not even the start is descended
+    /// into.
+    pub max_depth: Option<usize>,
+    /// Threads for `walk_node_parallel` to walk subtrees on
+    pub jobs: usize,
+    /// What to do when part of the hierarchy cannot be walked
+    pub errors: ErrorPolicy,
+}
+
+impl Default for WalkOptions {
+    fn default() -> Self {
+        WalkOptions {
+            max_depth: None,
+            jobs: 1,
+            errors: ErrorPolicy::default(),
+        }
+    }
+}
+
+/// What a walk does when visiting a node, or reading its children, fails
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Default)]
+pub enum ErrorPolicy {
+    /// Stop the walk, and return the error
+    #[default]
+    FailFast,
+    /// Record the error with the node's path, leave the node out of its
+    /// parent's results, and go on with the rest of the walk
+    ///
+    /// A node whose children could not be read is not given to
+    /// `post_descend`.
+    Continue,
+}
+
+/// What a walk with options found
+#[derive(Debug)]
+pub struct WalkReport<R> {
+    /// Result of the start node
+    pub result: Option<R>,
+    /// Errors recorded under `ErrorPolicy::Continue`, in the order walked,
+    /// with the path of the node each was for
+    pub errors: Vec<(PathBuf, Error)>,
+}
+
+/// Apply the error policy to the outcome of visiting a node
+///
+/// Returns None if the error was recorded for the walk to go on, after
+/// telling the operation with `unreadable`.
+fn recover<T, N, O>(result: Result<T>,
+                    path_stack: &Path,
+                    options: &WalkOptions,
+                    errors: &mut Vec<(PathBuf, Error)>,
+                    op: &mut O)
+                    -> Result<Option<T>>
+    where O: WalkOp<N>
+{
+    match (result, options.errors) {
+        (Ok(value), _) => Ok(Some(value)),
+        (Err(e), ErrorPolicy::FailFast) => Err(e),
+        (Err(e), ErrorPolicy::Continue) => {
+            debug!("Skipping {}: {}", path_stack.display(), e);
+            op.unreadable(path_stack, &e)?;
+            errors.push((path_stack.to_owned(), e));
+            Ok(None)
+        }
+    }
+}
+
+/// Walk from a node, keeping a stack of the nodes being descended into
+///
+/// The path and depth are those of the node within the whole walk, which may
+/// have failed to be read.
+fn walk_from<R, N, O>(reader: &R,
+                      op: &mut O,
+                      node: Result<N>,
+                      mut path_stack: PathBuf,
+                      depth: usize,
+                      options: &WalkOptions,
+                      errors: &mut Vec<(PathBuf, Error)>)
+                      -> Result<Option<O::VisitResult>>
+    where R: NodeReader<N> + ?Sized,
+          O: WalkOp<N>
+{
+    let mut stack = Vec::new();
+    let mut done = visit_node(reader,
+                              op,
+                              String::new(),
+                              node,
+                              &path_stack,
+                              depth,
+                              &mut stack,
+                              options,
+                              errors)?;
+    loop {
+        if let Some((name, result)) = done.take() {
+            match stack.last_mut() {
+                None => return Ok(result),
+                Some(parent) => {
+                    path_stack.pop();
+                    if let Some(result) = result {
+                        parent.results.insert(name, result);
+                    }
+                }
+            }
+        }
+        let next = stack.last_mut().and_then(|top| top.children.next());
+        match next {
+            Some((name, child)) => {
+                path_stack.push(&name);
+                done = visit_node(reader,
+                                  op,
+                                  name,
+                                  child,
+                                  &path_stack,
+                                  depth,
+                                  &mut stack,
+                                  options,
+                                  errors)?;
+            }
+            None => {
+                let frame = stack.pop().expect("walk stack is not empty");
+                trace!("<- {}", path_stack.display());
+                let result =
+                    op.post_descend(&path_stack, frame.node, frame.results);
+                let result =
+                    recover(result, &path_stack, options, errors, op)?;
+                done = Some((frame.name, result.and_then(|r| r)));
+            }
+        }
+    }
+}
+
+/// A node that is being descended into, waiting for its children's results
+struct WalkFrame<N, R> {
+    name: String,
+    node: N,
+    children: btree_map::IntoIter<String, Result<N>>,
+    results: ChildMap<R>,
+}
+
+/// Visit one node of an iterative walk, started at the given depth
+///
+/// A node to descend into is pushed on the stack, and None is returned. A
+/// node not descended into, or that could not be read, is finished
+/// immediately, so its name and result are returned.
+#[allow(clippy::too_many_arguments)]
+fn visit_node<R, N, O>(reader: &R,
+                       op: &mut O,
+                       name: String,
+                       node: Result<N>,
+                       path_stack: &Path,
+                       depth: usize,
+                       stack: &mut Vec<WalkFrame<N, O::VisitResult>>,
+                       options: &WalkOptions,
+                       errors: &mut Vec<(PathBuf, Error)>)
+                       -> Result<Option<(String, Option<O::VisitResult>)>>
+    where R: NodeReader<N> + ?Sized,
+          O: WalkOp<N>
+{
+    let node = match recover(node, path_stack, options, errors, op)? {
+        Some(node) => node,
+        None => return Ok(Some((name, None))),
+    };
+    let depth = depth + stack.len();
+    let within_limit = options.max_depth.is_none_or(|max| depth < max);
+    if within_limit && op.should_descend(path_stack, &node) {
+        trace!("-> {}", path_stack.display());
+        let children = op.pre_descend(path_stack, &node)
+            .and_then(|_| reader.read_children_each(&node));
+        match recover(children, path_stack, options, errors, op)? {
+            Some(children) => {
+                stack.push(WalkFrame {
+                    name: name,
+                    node: node,
+                    children: children.into_iter(),
+                    results: ChildMap::new(),
+                });
+                Ok(None)
+            }
+            None => Ok(Some((name, None))),
         }
+    } else {
+        trace!("** {}", path_stack.display());
+        let result = op.no_descend(path_stack, node);
+        let result = recover(result, path_stack, options, errors, op)?;
+        Ok(Some((name, result.and_then(|r| r))))
     }
 }
 
@@ -142,6 +484,29 @@ pub trait WalkOp<N> {
                     -> Result<Option<Self::VisitResult>> {
         Ok(None)
     }
+
+    /// Called, in walk order, for a node that failed and was skipped under
+    /// `ErrorPolicy::Continue`
+    ///
+    /// Default implementation is a no-op
+    fn unreadable(&mut self, _ps: &Path, _error: &Error) -> Result<()> {
+        Ok(())
+    }
+}
+
+
+/// An operation that can be split up, to walk sibling subtrees in parallel
+pub trait ForkWalkOp<N>: WalkOp<N> {
+    /// An operation that walks one subtree, on another thread
+    type Fork: WalkOp<N, VisitResult = Self::VisitResult> + Send;
+
+    /// Start an operation to walk one child of the start node
+    fn fork(&self) -> Self::Fork;
+
+    /// Take back what a fork gathered, once its subtree has been walked
+    ///
+    /// Forks are joined in the order of the children they walked.
+    fn join(&mut self, fork: Self::Fork) -> Result<()>;
 }
 
 
@@ -152,23 +517,34 @@ impl<'a, A, B, RA, RB> NodeReader<(Option<A>, Option<B>)> for (&'a RA, &'a RB)
     fn read_children(&self,
                      node: &(Option<A>, Option<B>))
                      -> Result<ChildMap<(Option<A>, Option<B>)>> {
+        all_children(self.read_children_each(node)?)
+    }
+
+    fn read_children_each(&self,
+                          node: &(Option<A>, Option<B>))
+                          -> Result<ChildMap<Result<(Option<A>, Option<B>)>>> {
         let mut children = ChildMap::new();
         match node {
             &(Some(ref a), Some(ref b)) => {
-                let a = self.0.read_children(a)?;
-                let b = self.1.read_children(b)?;
+                let a = self.0.read_children_each(a)?;
+                let b = self.1.read_children_each(b)?;
                 for (name, a, b) in mux(a.into_iter(), b.into_iter()) {
-                    children.insert(name, (a, b));
+                    let pair = match (a.and_then_try(|a| a),
+                                      b.and_then_try(|b| b)) {
+                        (Ok(a), Ok(b)) => Ok((a, b)),
+                        (Err(e), _) | (_, Err(e)) => Err(e),
+                    };
+                    children.insert(name, pair);
                 }
             }
             &(Some(ref a), None) => {
-                for (name, a) in self.0.read_children(a)? {
-                    children.insert(name, (Some(a), None));
+                for (name, a) in self.0.read_children_each(a)? {
+                    children.insert(name, a.map(|a| (Some(a), None)));
                 }
             }
             &(None, Some(ref b)) => {
-                for (name, b) in self.1.read_children(b)? {
-                    children.insert(name, (None, Some(b)));
+                for (name, b) in self.1.read_children_each(b)? {
+                    children.insert(name, b.map(|b| (None, Some(b))));
                 }
             }
             &(None, None) => {}
@@ -238,23 +614,345 @@ impl<'a, A, B, RA, RB> NodeReader<(Vec<Option<A>>, Option<B>)>
     fn read_children(&self,
                      node: &(Vec<Option<A>>, Option<B>))
                      -> Result<ChildMap<(Vec<Option<A>>, Option<B>)>> {
+        all_children(self.read_children_each(node)?)
+    }
+
+    fn read_children_each(&self,
+                          node: &(Vec<Option<A>>, Option<B>))
+                          -> Result<ChildMap<Result<(Vec<Option<A>>,
+                                                     Option<B>)>>> {
         let count_a = node.0.len();
         let mut children = ChildMap::new();
         match node {
             &(ref a, Some(ref b)) => {
                 let a: ChildMap<Vec<Option<A>>> = self.0.read_children(a)?;
-                let b: ChildMap<B> = self.1.read_children(b)?;
+                let b: ChildMap<Result<B>> = self.1.read_children_each(b)?;
                 for (name, a, b) in mux(a.into_iter(), b.into_iter()) {
                     let a = a.unwrap_or_else(|| vec![None;count_a]);
-                    children.insert(name, (a, b));
+                    let b = b.and_then_try(|b| b);
+                    children.insert(name, b.map(|b| (a, b)));
                 }
             }
             &(ref a, None) => {
                 for (name, a) in self.0.read_children(a)? {
-                    children.insert(name, (a, None));
+                    children.insert(name, Ok((a, None)));
                 }
             }
         }
         Ok(children)
     }
 }
+
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use super::*;
+
+    /// A chain of nodes, each named by its depth, with one child named "d"
+    struct Chain(usize);
+
+    impl NodeReader<usize> for Chain {
+        fn read_children(&self, node: &usize) -> Result<ChildMap<usize>> {
+            let mut children = ChildMap::new();
+            if *node < self.0 {
+                children.insert("d".to_owned(), node + 1);
+            }
+            Ok(children)
+        }
+    }
+
+    /// Nodes named by their depth, each with three children, to a given depth
+    struct Fan(usize);
+
+    impl NodeReader<usize> for Fan {
+        fn read_children(&self, node: &usize) -> Result<ChildMap<usize>> {
+            let mut children = ChildMap::new();
+            if *node < self.0 {
+                for name in &["c", "a", "b"] {
+                    children.insert(name.to_string(), node + 1);
+                }
+            }
+            Ok(children)
+        }
+    }
+
+    /// Like `Fan`, but children cannot be read at one depth
+    struct BrokenFan(usize, usize);
+
+    impl NodeReader<usize> for BrokenFan {
+        fn read_children(&self, node: &usize) -> Result<ChildMap<usize>> {
+            if *node == self.1 {
+                bail!("Cannot read depth {}", node);
+            }
+            Fan(self.0).read_children(node)
+        }
+    }
+
+    /// Like `Fan`, but every child named "b" cannot be read
+    struct BrokenChild(usize);
+
+    impl NodeReader<usize> for BrokenChild {
+        fn read_children(&self, node: &usize) -> Result<ChildMap<usize>> {
+            all_children(self.read_children_each(node)?)
+        }
+        fn read_children_each(&self,
+                              node: &usize)
+                              -> Result<ChildMap<Result<usize>>> {
+            let mut children = Fan(self.0).read_children_each(node)?;
+            if let Some(child) = children.get_mut("b") {
+                *child = Err("Cannot read b".into());
+            }
+            Ok(children)
+        }
+    }
+
+    /// Records the order of calls, and counts the nodes below each node
+    #[derive(Default)]
+    struct RecordOp {
+        calls: Vec<String>,
+    }
+
+    impl WalkOp<usize> for RecordOp {
+        type VisitResult = usize;
+
+        fn should_descend(&mut self, _ps: &Path, node: &usize) -> bool {
+            *node < 3
+        }
+        fn no_descend(&mut self,
+                      ps: &Path,
+                      node: usize)
+                      -> Result<Option<Self::VisitResult>> {
+            self.calls.push(format!("no {} {}", node, ps.display()));
+            Ok(Some(0))
+        }
+        fn pre_descend(&mut self, ps: &Path, node: &usize) -> Result<()> {
+            self.calls.push(format!("pre {} {}", node, ps.display()));
+            Ok(())
+        }
+        fn post_descend(&mut self,
+                        ps: &Path,
+                        node: usize,
+                        children: ChildMap<Self::VisitResult>)
+                        -> Result<Option<Self::VisitResult>> {
+            self.calls.push(format!("post {} {}", node, ps.display()));
+            Ok(Some(children.values().map(|n| n + 1).sum()))
+        }
+        fn unreadable(&mut self, ps: &Path, _error: &Error) -> Result<()> {
+            self.calls.push(format!("skip {}", ps.display()));
+            Ok(())
+        }
+    }
+
+    impl ForkWalkOp<usize> for RecordOp {
+        type Fork = RecordOp;
+
+        fn fork(&self) -> Self::Fork { RecordOp::default() }
+
+        fn join(&mut self, fork: Self::Fork) -> Result<()> {
+            self.calls.extend(fork.calls);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_walk_order() {
+        let mut op = RecordOp::default();
+        assert_eq!(Chain(10).walk_node(&mut op, 0).unwrap(), Some(3));
+        assert_eq!(op.calls,
+                   vec!["pre 0 ",
+                        "pre 1 d",
+                        "pre 2 d/d",
+                        "no 3 d/d/d",
+                        "post 2 d/d",
+                        "post 1 d",
+                        "post 0 "]);
+    }
+
+    #[test]
+    fn test_walk_deep() {
+        struct DescendAll;
+        impl WalkOp<usize> for DescendAll {
+            type VisitResult = usize;
+            fn should_descend(&mut self, _ps: &Path, _node: &usize) -> bool {
+                true
+            }
+            fn post_descend(&mut self,
+                            _ps: &Path,
+                            node: usize,
+                            children: ChildMap<usize>)
+                            -> Result<Option<usize>> {
+                Ok(Some(children.get("d").cloned().unwrap_or(node)))
+            }
+        }
+
+        let depth = 200000;
+        assert_eq!(Chain(depth).walk_node(&mut DescendAll, 0).unwrap(),
+                   Some(depth),
+                   "Deep trees should not overflow the stack");
+
+        let options = WalkOptions {
+            max_depth: Some(1),
+            ..WalkOptions::default()
+        };
+        let mut op = RecordOp::default();
+        let report = Chain(10).walk_node_with(&mut op, 0, &options).unwrap();
+        assert_eq!(report.result, Some(1));
+        assert_eq!(op.calls, vec!["pre 0 ", "no 1 d", "post 0 "]);
+    }
+
+    #[test]
+    fn test_walk_parallel() {
+        let mut serial = RecordOp::default();
+        let expected = Fan(4).walk_node(&mut serial, 0).unwrap();
+        assert_eq!(expected, Some(39));
+
+        let options = WalkOptions {
+            jobs: 4,
+            ..WalkOptions::default()
+        };
+        let mut parallel = RecordOp::default();
+        assert_eq!(Fan(4).walk_node_parallel(&mut parallel, 0, &options)
+                       .unwrap()
+                       .result,
+                   expected);
+        assert_eq!(parallel.calls,
+                   serial.calls,
+                   "Forks should be joined in name order");
+    }
+
+    #[test]
+    fn test_walk_parallel_joins_early() {
+        /// Children "a" and "b", where "b" waits for "a" to be joined
+        struct Gated(Mutex<mpsc::Receiver<()>>);
+        impl NodeReader<usize> for Gated {
+            fn read_children(&self, node: &usize) -> Result<ChildMap<usize>> {
+                let mut children = ChildMap::new();
+                match *node {
+                    0 => {
+                        children.insert("a".to_owned(), 1);
+                        children.insert("b".to_owned(), 2);
+                    }
+                    2 => {
+                        self.0
+                            .lock()
+                            .unwrap()
+                            .recv_timeout(Duration::from_secs(10))
+                            .chain_err(|| "a was not joined while b walked")?;
+                    }
+                    _ => {}
+                }
+                Ok(children)
+            }
+        }
+
+        /// Signals each join
+        struct SignalOp(mpsc::Sender<()>);
+        impl WalkOp<usize> for SignalOp {
+            type VisitResult = usize;
+            fn should_descend(&mut self, _ps: &Path, _node: &usize) -> bool {
+                true
+            }
+            fn post_descend(&mut self,
+                            _ps: &Path,
+                            _node: usize,
+                            children: ChildMap<usize>)
+                            -> Result<Option<usize>> {
+                Ok(Some(children.len()))
+            }
+        }
+        impl ForkWalkOp<usize> for SignalOp {
+            type Fork = RecordOp;
+            fn fork(&self) -> Self::Fork { RecordOp::default() }
+            fn join(&mut self, _fork: Self::Fork) -> Result<()> {
+                self.0.send(()).unwrap();
+                Ok(())
+            }
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let options = WalkOptions {
+            jobs: 2,
+            ..WalkOptions::default()
+        };
+        let report = Gated(Mutex::new(rx))
+            .walk_node_parallel(&mut SignalOp(tx), 0, &options)
+            .unwrap();
+        assert_eq!(report.result, Some(2));
+    }
+
+    #[test]
+    fn test_walk_error_policy() {
+        let reader = BrokenFan(3, 2);
+        assert!(reader.walk_node(&mut RecordOp::default(), 0).is_err());
+
+        let mut options = WalkOptions {
+            errors: ErrorPolicy::Continue,
+            ..WalkOptions::default()
+        };
+        let mut op = RecordOp::default();
+        let report = reader.walk_node_with(&mut op, 0, &options).unwrap();
+        assert_eq!(report.result, Some(3));
+        let paths = report.errors
+            .iter()
+            .map(|&(ref path, _)| path.to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(paths,
+                   vec!["a/a", "a/b", "a/c", "b/a", "b/b", "b/c", "c/a",
+                        "c/b", "c/c"]);
+        assert!(!op.calls.contains(&"post 2 a/a".to_owned()),
+                "Nodes that failed should not be finished");
+
+        options.jobs = 2;
+        let mut op = RecordOp::default();
+        let parallel = reader.walk_node_parallel(&mut op, 0, &options).unwrap();
+        assert_eq!(parallel.result, report.result);
+        assert_eq!(parallel.errors.len(), 9);
+        assert_eq!(parallel.errors[0].0, PathBuf::from("a/a"));
+    }
+
+    #[test]
+    fn test_walk_error_per_child() {
+        let reader = BrokenChild(2);
+        assert!(reader.walk_node(&mut RecordOp::default(), 0).is_err());
+
+        let mut options = WalkOptions {
+            errors: ErrorPolicy::Continue,
+            ..WalkOptions::default()
+        };
+        let mut op = RecordOp::default();
+        let report = reader.walk_node_with(&mut op, 0, &options).unwrap();
+        assert_eq!(report.result, Some(6));
+        let paths = report.errors
+            .iter()
+            .map(|&(ref path, _)| path.to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, vec!["a/b", "b", "c/b"]);
+        assert_eq!(op.calls,
+                   vec!["pre 0 ",
+                        "pre 1 a",
+                        "pre 2 a/a",
+                        "post 2 a/a",
+                        "skip a/b",
+                        "pre 2 a/c",
+                        "post 2 a/c",
+                        "post 1 a",
+                        "skip b",
+                        "pre 1 c",
+                        "pre 2 c/a",
+                        "post 2 c/a",
+                        "skip c/b",
+                        "pre 2 c/c",
+                        "post 2 c/c",
+                        "post 1 c",
+                        "post 0 "],
+                   "Only the unreadable children should be skipped, in order");
+
+        options.jobs = 2;
+        let mut parallel_op = RecordOp::default();
+        let parallel = reader.walk_node_parallel(&mut parallel_op, 0, &options)
+            .unwrap();
+        assert_eq!(parallel.result, report.result);
+        assert_eq!(parallel_op.calls, op.calls);
+    }
+}
@@ -1,31 +1,67 @@
 //! Working Directory: Files checked out from an ObjectStore
 
+use config::RepoConfig;
+use constants::CACHE_FILE_NAME;
 use constants::DEFAULT_BRANCH_NAME;
 use constants::HIDDEN_DIR_NAME;
+use constants::IGNORE_FILE_NAME;
+use constants::SYNC_WARNING_DAYS;
 use dag::Commit;
 use dag::ObjectKey;
+use derive::Derivers;
 use disk_backed::DiskBacked;
+use encodable;
 use error::*;
 use file_store::*;
 use find_repo::RepoLayout;
 use fs_transfer::*;
+use git_stream::flatten_tree;
+use hooks::Hook;
+use hooks::Hooks;
+use journal::Journal;
+use journal::JournalOp;
+use ignore::IgnoreSource;
+use lock::FileLock;
+use messages::Msg;
 use object_store::*;
+use pathspec::Pathspec;
+use policy::SizeViolation;
 use progress::*;
 use revisions::*;
+use signing;
+use signing::Signer;
 use status::*;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::hash_map::RandomState;
+use std::env;
+use std::fmt;
+use std::hash::BuildHasher;
 use std::io;
 use std::path::Path;
 use std::path::PathBuf;
 use std::thread;
+use std::time::Duration;
+use std::time::SystemTime;
+use transport;
+use transport::FetchResult;
+use transport::Remote;
 use walker::*;
 
 
 /// State stored in a file in the WorkDir, including current branch
+///
+/// Change it through `WorkDir::update_state`, which also records event times.
 #[derive(Debug,Clone,Hash,PartialEq,Eq,RustcEncodable,RustcDecodable)]
 pub struct WorkDirState {
     pub parents: Vec<ObjectKey>,
     pub branch: Option<String>,
     pub subtree: Option<PathBuf>,
+    pub last_checkout: Option<encodable::SystemTime>,
+    pub last_commit: Option<encodable::SystemTime>,
+    pub last_sync: Option<encodable::SystemTime>,
 }
 
 impl Default for WorkDirState {
@@ -34,11 +70,167 @@ impl Default for WorkDirState {
             parents: Vec::new(),
             branch: Some(DEFAULT_BRANCH_NAME.to_owned()),
             subtree: None,
+            last_checkout: None,
+            last_commit: None,
+            last_sync: None,
         }
     }
 }
 
+/// Events whose time of last success is recorded in the work dir state
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum StateEvent {
+    Checkout,
+    Commit,
+    /// A fetch or push
+    Sync,
+}
+
+impl WorkDirState {
+    /// When the event last succeeded, if ever
+    pub fn last(&self, event: StateEvent) -> Option<SystemTime> {
+        let time = match event {
+            StateEvent::Checkout => &self.last_checkout,
+            StateEvent::Commit => &self.last_commit,
+            StateEvent::Sync => &self.last_sync,
+        };
+        time.as_ref().map(|t| **t)
+    }
+
+    fn record(&mut self, event: StateEvent, time: SystemTime) {
+        let time = Some(encodable::SystemTime::from(time));
+        match event {
+            StateEvent::Checkout => self.last_checkout = time,
+            StateEvent::Commit => self.last_commit = time,
+            StateEvent::Sync => self.last_sync = time,
+        }
+    }
+
+    /// What HEAD refers to, or None if detached with no commit
+    pub fn head(&self) -> Option<Head> {
+        match (self.branch.as_ref(), self.parents.first()) {
+            (Some(branch), _) => Some(Head::Branch(branch.clone())),
+            (None, Some(&hash)) => Some(Head::Detached(hash)),
+            (None, None) => None,
+        }
+    }
+
+    /// Point HEAD at a branch, or detach it at a commit
+    ///
+    /// Detaching makes the commit the only parent. Attaching leaves the
+    /// parents alone, so the caller moves them if the branch is elsewhere.
+    pub fn set_head(&mut self, head: Head) {
+        match head {
+            Head::Branch(branch) => self.branch = Some(branch),
+            Head::Detached(hash) => {
+                self.branch = None;
+                self.parents = vec![hash];
+            }
+        }
+    }
+}
+
+/// What HEAD refers to
+///
+/// The work dir state keeps a branch name, or none if detached, alongside
+/// the parents. This puts the two together.
+#[derive(Debug,Clone,PartialEq,Eq,Hash)]
+pub enum Head {
+    /// On a branch, which each commit moves forward
+    ///
+    /// A new repository is on a branch that has no commits yet.
+    Branch(String),
+    /// At a commit, without a branch, so that commits only move HEAD
+    Detached(ObjectKey),
+}
+
+impl Head {
+    pub fn branch(&self) -> Option<&str> {
+        match *self {
+            Head::Branch(ref branch) => Some(branch),
+            Head::Detached(_) => None,
+        }
+    }
+}
+
+/// Where HEAD is, as status shows it
+impl fmt::Display for Head {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Head::Branch(ref branch) => {
+                write!(f, "{}", msg!(Msg::OnBranch, branch))
+            }
+            Head::Detached(hash) => {
+                write!(f, "{}", msg!(Msg::DetachedAt, hash))
+            }
+        }
+    }
+}
+
+
+
+/// Counts of files checked by `WorkDir::prime_cache`
+#[derive(Debug,Clone,Copy,Default,PartialEq,Eq)]
+pub struct PrimeCacheStats {
+    /// Files whose hashes matched HEAD and were cached
+    pub primed: usize,
+    /// Files that were already cached with HEAD's hash
+    pub already_cached: usize,
+    /// Files whose size or hash differs from HEAD
+    pub changed: usize,
+    /// Files in HEAD that are missing from the work dir
+    pub missing: usize,
+}
+
+/// Results of `WorkDir::verify_files`
+#[derive(Debug,Clone,Default,PartialEq,Eq)]
+pub struct VerifyFilesStats {
+    /// Files whose cache entries say they match HEAD
+    pub unchanged: usize,
+    /// Of those, files rehashed to check
+    pub rehashed: usize,
+    /// Files rehashed that no longer match HEAD, with their hashes in HEAD
+    pub corrupt: Vec<(PathBuf, ObjectKey)>,
+    /// Files that are not cached or have been modified, and were not checked
+    pub not_cached: usize,
+    /// Files in HEAD that are missing from the work dir
+    pub missing: usize,
+}
 
+/// How a branch compares to the tracking ref it follows
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct UpstreamStatus {
+    /// Name of the tracking ref, such as `origin/master`
+    pub name: String,
+    /// Commits on the branch that are not on the tracking ref
+    pub ahead: usize,
+    /// Commits on the tracking ref that are not on the branch
+    pub behind: usize,
+}
+
+/// Options for `WorkDir::status`
+#[derive(Debug,Clone,Default,PartialEq,Eq)]
+pub struct StatusOptions {
+    /// Report ignored files too
+    pub show_ignored: bool,
+    /// Report only paths matching this, everything by default
+    pub pathspec: Pathspec,
+    /// What to do with a path that cannot be read: stop, by default, or
+    /// report it as unreadable and go on
+    pub errors: ErrorPolicy,
+}
+
+/// Result of `WorkDir::commit`
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct CommitOutcome {
+    /// Branch that was advanced, or None if detached
+    pub branch: Option<String>,
+    pub hash: ObjectKey,
+    /// Number of new objects re-read and checked, if asked to verify
+    pub verified: Option<usize>,
+    /// Size limits the commit went over, but was allowed to
+    pub size_warnings: Vec<SizeViolation>,
+}
 
 /// The working directory, an ObjectStore plus FileStore plus state of branches
 pub struct WorkDir {
@@ -49,35 +241,212 @@ pub struct WorkDir {
 impl_deref_mut!(WorkDir => FsTransfer, fs_transfer);
 
 impl WorkDir {
-    fn state_path(wd_path: &Path) -> PathBuf {
-        wd_path.join(HIDDEN_DIR_NAME).join("work_dir_state")
+    /// Path of the state file, in the hidden directory
+    fn state_path(osd_path: &Path) -> PathBuf {
+        osd_path.join("work_dir_state")
+    }
+
+    /// Read the state file, for commands that open only the store
+    fn read_state(osd_path: &Path) -> Result<DiskBacked<WorkDirState>> {
+        DiskBacked::read_or_default("work dir state",
+                                    Self::state_path(osd_path))
+            .map_err(|e| e.into())
     }
 
-    pub fn init(layout: RepoLayout) -> Result<Self> {
-        let os = ObjectStore::init(layout.osd)?;
+    /// HEAD's commit, and its branch if attached, from the state file
+    ///
+    /// None if there is no work dir, or it has no commits yet.
+    pub fn read_head(osd_path: &Path)
+                     -> Result<Option<(ObjectKey, Option<String>)>> {
+        let state = Self::read_state(osd_path)?;
+        let branch = state.branch.clone();
+        Ok(state.parents.first().map(|&hash| (hash, branch)))
+    }
+
+    /// The work dir's parent commits, from the state file
+    ///
+    /// Empty if there is no work dir, or it has no commits yet.
+    pub fn read_parents(osd_path: &Path) -> Result<Vec<ObjectKey>> {
+        Ok(Self::read_state(osd_path)?.parents.clone())
+    }
+
+    pub fn init(layout: RepoLayout, config: RepoConfig) -> Result<Self> {
         let state = DiskBacked::new("work dir state",
-                                    Self::state_path(&layout.wd));
-        Ok(WorkDir {
+                                    Self::state_path(&layout.osd));
+        let os = ObjectStore::init_with_config(layout.osd, config)?;
+        let mut work_dir = WorkDir {
             fs_transfer: FsTransfer::with_object_store(os),
             path: layout.wd,
             state: state,
-        })
+        };
+        work_dir.read_ignore_file()?;
+        work_dir.set_store_head();
+        Ok(work_dir)
+    }
+
+    /// Create a new work dir as a copy of another repository
+    ///
+    /// The new repository uses the source's hash algorithm and chunking
+    /// parameters, so the objects it stores match the source's, and the
+    /// source becomes its `origin` remote. All branches are fetched as tracking
+    /// refs, optionally with limited history or a limited transfer rate,
+    /// and the default branch is created locally, checked out, and recorded
+    /// as origin's branch.
+    pub fn clone_repo(source: &str,
+                      layout: RepoLayout,
+                      depth: Option<usize>,
+                      limit_rate: Option<u64>)
+                      -> Result<(Self, FetchResult)> {
+        let url = transport::absolute_url(source)?;
+        let mut source_transport = transport::connect(&url)?;
+        let config = RepoConfig {
+            hash_algorithm: Some(source_transport.hash_algorithm()?),
+            chunking: source_transport.chunking()?,
+            ..RepoConfig::default()
+        };
+        drop(source_transport);
+
+        let mut work_dir = WorkDir::init(layout, config)?;
+        work_dir.update_remote("origin".to_owned(), Remote::new(url))?;
+        let result = transport::fetch(&mut work_dir,
+                                      "origin",
+                                      depth,
+                                      false,
+                                      limit_rate)?;
+
+        match transport::default_branch(&result.remote_refs) {
+            Some((branch, hash)) => {
+                let mut origin = work_dir.remotes()["origin"].clone();
+                origin.branch = Some(branch.clone());
+                work_dir.update_remote("origin".to_owned(), origin)?;
+                work_dir.update_ref(branch.clone(), hash)?;
+                work_dir.checkout(&branch.parse()?, false)?;
+            }
+            None => {
+                // Empty source: stay on the default branch with no commits
+                work_dir.update_state(None, |_| ())?;
+            }
+        }
+        work_dir.update_state(Some(StateEvent::Sync), |_| ())?;
+        Ok((work_dir, result))
     }
+
     pub fn open(layout: RepoLayout) -> Result<Self> {
-        Ok(WorkDir {
-            fs_transfer: FsTransfer::with_repo_path(layout.osd)?,
+        let mut work_dir = WorkDir {
             state: DiskBacked::read_or_default("work dir state",
-                                               Self::state_path(&layout.wd))?,
+                                               Self::state_path(&layout.osd))?,
+            fs_transfer: FsTransfer::with_repo_path(layout.osd)?,
             path: layout.wd,
-        })
+        };
+        work_dir.read_ignore_file()?;
+        work_dir.set_store_head();
+        Ok(work_dir)
+    }
+
+    /// Let the store resolve `HEAD`, see `ObjectStore::set_head`
+    fn set_store_head(&mut self) {
+        let head = self.head()
+            .map(|hash| (hash, self.branch().map(|b| b.to_owned())));
+        self.object_store.set_head(head);
+    }
+
+    /// Add the patterns from the ignore file at the top of the work dir
+    fn read_ignore_file(&mut self) -> Result<()> {
+        self.fs_transfer.file_store.ignored.set_top(&self.path);
+        let file = self.path.join(IGNORE_FILE_NAME);
+        if file.is_file() {
+            self.fs_transfer.file_store.ignored.read_file(&file, &self.path)?;
+        }
+        Ok(())
     }
 
     pub fn path(&self) -> &Path { &self.path }
 
+    /// Hooks in the hidden directory, which run in the work dir
+    pub fn hooks(&self) -> Hooks {
+        Hooks::new(self.object_store.path().join("hooks"), self.path.clone())
+    }
+
+    /// Programs in the hidden directory that derive objects from files
+    pub fn derivers(&self) -> Derivers {
+        Derivers::new(self.object_store.path().join("derivers"))
+    }
+
+    /// Change the state, record the event's time if given, and save
+    ///
+    /// Call this only once the event has succeeded, so that a failed command
+    /// leaves the state as it was. The state file is locked and re-read
+    /// before the change, so changes from other processes are not lost.
+    pub fn update_state<F>(&mut self,
+                           event: Option<StateEvent>,
+                           change: F)
+                           -> Result<()>
+        where F: FnOnce(&mut WorkDirState)
+    {
+        let state_path = Self::state_path(self.object_store.path());
+        let _lock = FileLock::acquire(&state_path, "update work dir state")?;
+        self.state.reload()?;
+        change(&mut self.state);
+        if let Some(event) = event {
+            self.state.record(event, SystemTime::now());
+        }
+        self.state.flush()?;
+        self.set_store_head();
+        Ok(())
+    }
+
+    /// Problems worth warning the user about, such as a long time since the
+    /// last sync
+    pub fn health_warnings(&self, now: SystemTime) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if let Ok(journal) = Journal::open(self) {
+            warnings.push(msg!(Msg::Interrupted, journal.state().op));
+        }
+        if self.remotes().is_empty() {
+            warnings.push(msg!(Msg::NoRemotes));
+        } else {
+            let limit = Duration::from_secs(SYNC_WARNING_DAYS * 24 * 60 * 60);
+            match self.state.last(StateEvent::Sync) {
+                None => warnings.push(msg!(Msg::NeverSynced)),
+                Some(last) => {
+                    let age = now.duration_since(last)
+                        .unwrap_or(Duration::from_secs(0));
+                    if age > limit {
+                        warnings.push(msg!(Msg::NotSyncedFor,
+                                           describe_age(age)));
+                    }
+                }
+            }
+        }
+        warnings
+    }
+
     pub fn branch(&self) -> Option<&str> {
         self.state.branch.as_ref().map(|s| s.as_str())
     }
 
+    /// What HEAD refers to: a branch, or a commit if detached
+    pub fn head_ref(&self) -> Option<Head> { self.state.head() }
+
+    /// Switch to a branch that points to HEAD, creating or moving it there
+    ///
+    /// The files are left alone, since HEAD does not move.
+    pub fn attach_head(&mut self, branch: RevNameBuf) -> Result<()> {
+        self.update_ref_to_head(branch.clone())?;
+        self.update_state(None, |state| state.set_head(Head::Branch(branch)))
+    }
+
+    /// Leave the current branch, staying at the same commit
+    pub fn detach_head(&mut self) -> Result<()> {
+        match self.head() {
+            Some(head) => {
+                self.update_state(None,
+                                  |state| state.set_head(Head::Detached(head)))
+            }
+            None => bail!("No commit to detach HEAD at"),
+        }
+    }
+
     pub fn head(&self) -> Option<ObjectKey> {
         match self.parents().len() {
             0 => None,
@@ -90,92 +459,122 @@ impl WorkDir {
     /// Assume a single parent and return that (for now)
     fn parent(&self) -> Option<ObjectKey> { self.head() }
 
+    /// Changed files, compared to the work dir's parents by default
+    ///
+    /// With one revision, the work dir is compared to it. With two, they are
+    /// compared to each other.
     pub fn status(&mut self,
-                  show_ignored: bool,
+                  options: &StatusOptions,
                   rev1: Option<RevSpec>,
                   rev2: Option<RevSpec>)
-                  -> Result<()> {
+                  -> Result<Vec<StatusEntry>> {
+        let mut entries = Vec::new();
+        self.status_each(options, rev1, rev2, |entry| {
+                entries.push(entry);
+                Ok(())
+            })?;
+        Ok(entries)
+    }
 
+    /// Like `status`, but give each changed file to a callback as it is found
+    pub fn status_each<F>(&mut self,
+                          options: &StatusOptions,
+                          rev1: Option<RevSpec>,
+                          rev2: Option<RevSpec>,
+                          mut sink: F)
+                          -> Result<()>
+        where F: FnMut(StatusEntry) -> Result<()>
+    {
         let abs_path = self.path().to_owned();
 
         match (rev1, rev2) {
             (None, None) => {
-                self.prett_print_state(&mut io::stderr())?;
-
                 let parents = self.parents()
                     .iter()
                     .map(|h| {
                         match self.state.subtree.as_ref() {
                             // If this is a subtree checkout, adjust all parents
-                            Some(ref path) => {
+                            Some(path) => {
                                 self.object_store.lookup_rev_path(h, path).ok()
                             }
                             None => Some(*h),
                         }
                     })
                     .collect();
-                self.status_many_objs_file(show_ignored, parents, abs_path)
+                self.status_many_objs_file(options,
+                                           parents,
+                                           abs_path,
+                                           &mut sink)
             }
             (Some(mut src_rev), None) => {
                 src_rev.set_path_if_none(|| self.state.subtree.clone());
                 let (src_rev, _, _) = self.object_store.lookup(&src_rev)?;
-                self.status_obj_file(show_ignored, Some(src_rev), abs_path)
+                self.status_obj_file(options,
+                                     Some(src_rev),
+                                     abs_path,
+                                     &mut sink)
             }
             (Some(src_rev), Some(targ_rev)) => {
                 let (src_rev, _, _) = self.object_store.lookup(&src_rev)?;
                 let (targ_rev, _, _) = self.object_store.lookup(&targ_rev)?;
-                self.status_obj_obj(show_ignored, src_rev, targ_rev)
+                self.status_obj_obj(options, src_rev, targ_rev, &mut sink)
             }
             (None, Some(_)) => unreachable!(),
         }
     }
 
-    fn prett_print_state<W>(&self, w: &mut W) -> Result<()>
-        where W: io::Write
-    {
-        writeln!(w,
-                 "On branch {}",
-                 self.branch().unwrap_or("<detached head>"))?;
-        if let &Some(ref subtree) = &self.state.subtree {
-            writeln!(w, "Subtree: {}", subtree.display())?;
-        }
-        for (i, parent) in self.parents().iter().enumerate() {
-            let commit = self.object_store.open_commit(parent)?;
-            let parent_name = match self.parents().len() {
-                1 => "HEAD".to_owned(),
-                _ => format!("P{}", i),
-            };
-            writeln!(w, "{}: {} {}", parent_name, parent, commit.message)?;
-        }
-        writeln!(w)?;
-        Ok(())
+    /// How the current branch compares to its tracking ref, if it has one
+    pub fn upstream_status(&self) -> Result<Option<UpstreamStatus>> {
+        let (branch, head) = match (self.branch(), self.head()) {
+            (Some(branch), Some(head)) => (branch, head),
+            _ => return Ok(None),
+        };
+        let (upstream, theirs) = match self.object_store.upstream(branch) {
+            Some(upstream) => upstream,
+            None => return Ok(None),
+        };
+        let (ahead, behind) = self.object_store
+            .count_ahead_behind(&head, &theirs)?;
+        Ok(Some(UpstreamStatus {
+            name: upstream,
+            ahead: ahead,
+            behind: behind,
+        }))
     }
 
     fn status_obj_file(&mut self,
-                       show_ignored: bool,
+                       options: &StatusOptions,
                        src: Option<ObjectKey>,
-                       targ: PathBuf)
+                       targ: PathBuf,
+                       sink: &mut FnMut(StatusEntry) -> Result<()>)
                        -> Result<()> {
 
         let src: Option<ComparableNode> =
             src.and_then_try(|hash| self.object_store.lookup_node(hash))?;
 
-        let targ: Option<ComparableNode> = Some(self.file_store
-            .lookup_node(targ)?);
+        let targ_node: Option<ComparableNode> = Some(self.file_store
+            .lookup_node(targ.clone())?);
 
-        let node = (src, targ);
+        let node = (src, targ_node);
 
         let combo = (&self.object_store, &self.file_store);
-        let mut writer = io::stdout();
-        let mut op = ComparePrintWalkOp::new(&mut writer, show_ignored);
-        combo.walk_node(&mut op, node)?;
+        let mut op = CompareWalkOp::new(sink, options.show_ignored)
+            .limit_to(options.pathspec.clone())
+            .in_dir(targ);
+        let walk_options = WalkOptions {
+            jobs: default_jobs(),
+            errors: options.errors,
+            ..WalkOptions::default()
+        };
+        combo.walk_node_parallel(&mut op, node, &walk_options)?;
         Ok(())
     }
 
     fn status_many_objs_file(&mut self,
-                             show_ignored: bool,
+                             options: &StatusOptions,
                              src: Vec<Option<ObjectKey>>,
-                             targ: PathBuf)
+                             targ: PathBuf,
+                             sink: &mut FnMut(StatusEntry) -> Result<()>)
                              -> Result<()> {
 
         let mut src_nodes = Vec::new();
@@ -185,22 +584,31 @@ impl WorkDir {
             );
         }
 
-        let targ: Option<ComparableNode> = Some(self.file_store
-            .lookup_node(targ)?);
+        let targ_node: Option<ComparableNode> = Some(self.file_store
+            .lookup_node(targ.clone())?);
 
-        let node = (src_nodes, targ);
+        let sources = src_nodes.len();
+        let node = (src_nodes, targ_node);
 
         let combo = (&self.object_store, &self.file_store);
-        let mut writer = io::stdout();
-        let mut op = MultiComparePrintWalkOp::new(&mut writer, show_ignored);
-        combo.walk_node(&mut op, node)?;
+        let mut op =
+            MultiCompareWalkOp::new(sink, sources, options.show_ignored)
+                .limit_to(options.pathspec.clone())
+                .in_dir(targ);
+        let walk_options = WalkOptions {
+            jobs: default_jobs(),
+            errors: options.errors,
+            ..WalkOptions::default()
+        };
+        combo.walk_node_parallel(&mut op, node, &walk_options)?;
         Ok(())
     }
 
     fn status_obj_obj(&mut self,
-                      show_ignored: bool,
+                      options: &StatusOptions,
                       src: ObjectKey,
-                      targ: ObjectKey)
+                      targ: ObjectKey,
+                      sink: &mut FnMut(StatusEntry) -> Result<()>)
                       -> Result<()> {
 
         let src: ComparableNode = self.object_store.lookup_node(src)?;
@@ -208,15 +616,472 @@ impl WorkDir {
         let node = (Some(src), Some(targ));
         let combo = (&self.object_store, &self.object_store);
 
-        let mut writer = io::stdout();
-        let mut op = ComparePrintWalkOp::new(&mut writer, show_ignored);
+        let mut op = CompareWalkOp::new(sink, options.show_ignored)
+            .limit_to(options.pathspec.clone());
         combo.walk_node(&mut op, node)?;
         Ok(())
     }
 
+    /// Hash of a file or directory in HEAD, given its path in the work dir
+    ///
+    /// The path may be relative to the current directory.
+    pub fn lookup_head_path(&self, path: &Path) -> Result<ObjectKey> {
+        let (head, tree_path) = self.head_tree_path(path)?;
+        self.object_store.lookup_rev_path(&head, &tree_path)
+    }
+
+    /// HEAD and the path within its tree, given a path in the work dir
+    ///
+    /// The path may be relative to the current directory.
+    pub fn head_tree_path(&self, path: &Path) -> Result<(ObjectKey, PathBuf)> {
+        let head = match self.head() {
+            Some(head) => head,
+            None => {
+                bail!("No commits yet, so {} is not in HEAD", path.display())
+            }
+        };
+        let rel_path = self.relative_path(path)?;
+        let tree_path = match self.state.subtree {
+            Some(ref subtree) => subtree.join(rel_path),
+            None => rel_path,
+        };
+        Ok((head, tree_path))
+    }
+
+    /// A path relative to the top of the work dir
+    ///
+    /// The path may be relative to the current directory.
+    pub fn relative_path(&self, path: &Path) -> Result<PathBuf> {
+        use std::path::Component;
+        let mut abs_path = PathBuf::new();
+        for component in env::current_dir()?.join(path).components() {
+            match component {
+                Component::CurDir => (),
+                Component::ParentDir => {
+                    abs_path.pop();
+                }
+                other => abs_path.push(other.as_os_str()),
+            }
+        }
+        let rel_path = abs_path.strip_prefix(&self.path)
+            .chain_err(|| {
+                format!("{} is outside the work dir {}",
+                        path.display(),
+                        self.path.display())
+            })?;
+        if rel_path.starts_with(HIDDEN_DIR_NAME) {
+            bail!("{} is inside the repository's hidden directory",
+                  path.display());
+        }
+        Ok(rel_path.to_owned())
+    }
+
+    /// A pathspec from patterns given relative to the current directory
+    ///
+    /// Each pattern must be within the work dir. See `Pathspec::new_in`.
+    /// When the current directory is outside the work dir, as it can be with
+    /// `--repo`, patterns are taken from the top of the work dir instead, so
+    /// no patterns still means the whole work dir.
+    pub fn pathspec<S: AsRef<str>>(&self, patterns: &[S]) -> Result<Pathspec> {
+        let cwd = env::current_dir()?;
+        let base = if cwd.starts_with(&self.path) {
+            cwd
+        } else {
+            self.path.clone()
+        };
+        for pattern in patterns {
+            self.relative_path(&base.join(pattern.as_ref()))?;
+        }
+        let dir = self.relative_path(&base)?;
+        Ok(Pathspec::new_in(&dir, patterns))
+    }
+
+    /// Move a file or directory within the work dir
+    ///
+    /// Moving onto an existing directory moves into it, as `mv` does. Cached
+    /// hashes go along (see `FileStore::move_path`), so the next status or
+    /// commit does not rehash what was only moved. Returns the new path,
+    /// relative to the top of the work dir.
+    pub fn move_path(&mut self, from: &Path, to: &Path) -> Result<PathBuf> {
+        let from = self.relative_path(from)?;
+        let mut to = self.relative_path(to)?;
+        if from.as_os_str().is_empty() {
+            bail!("Cannot move the whole work dir");
+        }
+        if self.path.join(&to).is_dir() {
+            to.push(from.file_name().expect("path is not empty"));
+        }
+        if to.starts_with(&from) {
+            bail!("Cannot move {} into itself", from.display());
+        }
+        if self.path.join(&to).symlink_metadata().is_ok() {
+            bail!("{} already exists", to.display());
+        }
+        let (abs_from, abs_to) = (self.path.join(&from), self.path.join(&to));
+        self.file_store.move_path(&abs_from, &abs_to)?;
+        Ok(to)
+    }
+
+    /// Remove a file or directory from the work dir
+    ///
+    /// Unless forced, this refuses to remove any file whose content is not
+    /// stored in the repository, such as new files and files changed since
+    /// the last commit, so that nothing is lost that cannot be extracted
+    /// again.
+    pub fn remove_path(&mut self, path: &Path, force: bool) -> Result<()> {
+        let rel_path = self.relative_path(path)?;
+        if rel_path.as_os_str().is_empty() {
+            bail!("Cannot remove the whole work dir");
+        }
+        let abs_path = self.path.join(&rel_path);
+        abs_path.symlink_metadata()
+            .chain_err(|| format!("Cannot remove {}", path.display()))?;
+        if !force {
+            let unsaved = self.unsaved_paths(&rel_path)?;
+            if let Some(first) = unsaved.first() {
+                bail!(ErrorKind::UnsavedChanges(first.display().to_string(),
+                                                unsaved.len()));
+            }
+        }
+        self.file_store.remove_path(&abs_path)
+    }
+
+    /// Find, and if `force` is given remove, files that are not in HEAD
+    ///
+    /// Directories that are not in HEAD are skipped, unless `dirs` is given,
+    /// in which case each is removed whole. Ignored files are kept, unless
+    /// `ignored` is given, and so is any untracked directory that holds
+    /// them, though its other files are still removed. dmv's own files are
+    /// always kept. Returns what was found, by path from the top of the work
+    /// dir.
+    pub fn clean(&mut self,
+                 dirs: bool,
+                 ignored: bool,
+                 force: bool)
+                 -> Result<Vec<StatusEntry>> {
+        let mut remove = Vec::new();
+        let mut keep = Vec::new();
+        let options = StatusOptions {
+            show_ignored: true,
+            ..StatusOptions::default()
+        };
+        for entry in self.status(&options, None, None)? {
+            match entry.status.first() {
+                Some(&Status::Add) => remove.push(entry),
+                Some(&Status::Ignored) => {
+                    let abs_path = self.path.join(&entry.path);
+                    let source = self.file_store
+                        .ignored
+                        .matching(&abs_path)
+                        .map(|(_, source)| source.clone());
+                    let is_cache = entry.path.file_name() ==
+                                   Some(CACHE_FILE_NAME.as_ref());
+                    match source {
+                        Some(IgnoreSource::File(..)) if ignored => {
+                            remove.push(entry)
+                        }
+                        _ if is_cache => {}
+                        _ => keep.push(entry.path),
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // Find the top untracked directory above each file
+        let mut tracked = HashMap::new();
+        let mut found = BTreeMap::new();
+        for entry in remove {
+            let parents = entry.path.ancestors().skip(1).collect::<Vec<_>>();
+            let untracked_dir = parents.into_iter()
+                .rev()
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .find(|dir| {
+                    let abs_path = self.path.join(dir);
+                    !*tracked.entry(abs_path.clone()).or_insert_with(|| {
+                        self.lookup_head_path(&abs_path).is_ok()
+                    })
+                })
+                .map(Path::to_path_buf);
+            match untracked_dir {
+                Some(dir) => {
+                    if dirs && !keep.iter().any(|path| path.starts_with(&dir)) {
+                        found.entry(dir.clone()).or_insert(StatusEntry {
+                            path: dir,
+                            is_dir: true,
+                            status: vec![Status::Add],
+                            size: 0,
+                            hash: None,
+                        });
+                    } else if dirs && !entry.is_dir {
+                        found.insert(entry.path.clone(), entry);
+                    }
+                }
+                None if entry.is_dir && !dirs => {}
+                None => {
+                    found.insert(entry.path.clone(), entry);
+                }
+            }
+        }
+        let found = found.into_values().collect::<Vec<_>>();
+        if force {
+            for entry in &found {
+                let abs_path = self.path.join(&entry.path);
+                self.file_store.remove_path(&abs_path)?;
+            }
+        }
+        Ok(found)
+    }
+
+    /// Check the files a commit would store against the size policy
+    ///
+    /// If the policy refuses the commit and it is not forced, this fails.
+    /// Otherwise the limits it goes over are returned as warnings. A file
+    /// that may have changed but has the same size as in HEAD is taken to be
+    /// unchanged, rather than hashing it to be sure.
+    pub fn check_size_policy(&mut self,
+                             pathspec: &Pathspec,
+                             force: bool)
+                             -> Result<Vec<SizeViolation>> {
+        let policy = *self.size_policy();
+        if policy.is_empty() {
+            return Ok(Vec::new());
+        }
+        let options = StatusOptions {
+            pathspec: pathspec.clone(),
+            ..StatusOptions::default()
+        };
+        let mut files = Vec::new();
+        for entry in self.status(&options, None, None)? {
+            if entry.is_dir {
+                continue;
+            }
+            match entry.status.first() {
+                Some(&Status::Add) | Some(&Status::Modified) => {}
+                Some(&Status::MaybeModified) => {
+                    let abs_path = self.path.join(&entry.path);
+                    let old = self.lookup_head_path(&abs_path)
+                        .and_then(|hash| self.lookup_node(hash))
+                        .ok();
+                    if old.is_some_and(|old: ObjectWalkNode| {
+                        old.file_size == entry.size
+                    }) {
+                        continue;
+                    }
+                }
+                _ => continue,
+            }
+            files.push((entry.path, entry.size));
+        }
+        let violations = policy.check(files);
+        if policy.refuse && !force && !violations.is_empty() {
+            bail!(ErrorKind::SizePolicyRefused(violations.iter()
+                .map(|v| v.to_string())
+                .collect()));
+        }
+        Ok(violations)
+    }
+
+    /// Files at or under the given path whose content is not stored
+    ///
+    /// Status finds the files that differ from the parents. Those that are
+    /// not cached are hashed, to see if their content is stored anyway, such
+    /// as after a file is moved.
+    fn unsaved_paths(&mut self, rel_path: &Path) -> Result<Vec<PathBuf>> {
+        let options = StatusOptions {
+            pathspec: Pathspec::new(&[rel_path.to_string_lossy()]),
+            ..StatusOptions::default()
+        };
+        let algorithm = self.config().hash_algorithm();
+        let mut unsaved = Vec::new();
+        for entry in self.status(&options, None, None)? {
+            if !entry.status.iter().any(|s| s.needs_transfer()) {
+                continue;
+            }
+            let hash = match entry.hash {
+                Some(hash) => Some(hash),
+                None if !entry.is_dir => {
+                    let abs_path = self.path.join(&entry.path);
+                    Some(self.file_store.rehash_file(&abs_path, algorithm)?)
+                }
+                None => None,
+            };
+            if !hash.is_some_and(|hash| self.has_object(&hash)) {
+                unsaved.push(entry.path);
+            }
+        }
+        Ok(unsaved)
+    }
+
+    /// Fill the hash cache for files that match HEAD, without storing them
+    ///
+    /// Each file in HEAD's tree whose work dir copy has the same size is
+    /// rehashed, and if the hash matches, it is cached. This is for when the
+    /// files have been copied to a new disk, which invalidates the whole
+    /// cache, so the next status does not have to hash them all again with
+    /// the full store machinery.
+    pub fn prime_cache(&mut self) -> Result<PrimeCacheStats> {
+        let mut stats = PrimeCacheStats::default();
+        let head = match self.head() {
+            Some(head) => head,
+            None => return Ok(stats),
+        };
+        let tree = match self.state.subtree {
+            Some(ref path) => self.object_store.lookup_rev_path(&head, path)?,
+            None => head,
+        };
+        let algorithm = self.config().hash_algorithm();
+        for (name, hash) in flatten_tree(&self.object_store, &tree)? {
+            let path = self.path.join(&name);
+            let meta = match path.symlink_metadata() {
+                Ok(ref meta) if meta.is_file() => meta.to_owned(),
+                _ => {
+                    debug!("Not a file in work dir: {}", path.display());
+                    stats.missing += 1;
+                    continue;
+                }
+            };
+            if self.file_store.cache.check(&path, &meta)? == Some(hash) {
+                stats.already_cached += 1;
+                continue;
+            }
+            let node: ObjectWalkNode = self.object_store.lookup_node(hash)?;
+            if node.file_size != meta.len() ||
+               self.file_store.rehash_file(&path, algorithm)? != hash {
+                debug!("Differs from HEAD: {}", path.display());
+                stats.changed += 1;
+                continue;
+            }
+            self.file_store.cache.insert(path, &meta, hash)?;
+            stats.primed += 1;
+        }
+        Ok(stats)
+    }
+
+    /// Look for silent corruption of checked-out files
+    ///
+    /// Files whose size and modification time match a cache entry for the
+    /// hash in HEAD are taken to be unchanged, so `status` never rereads
+    /// them. Here, a random sample of them (or all, with `None`) is rehashed
+    /// anyway, and any that no longer match HEAD are reported as corrupt.
+    /// Files that are not cached, or have been modified, are counted but not
+    /// checked.
+    pub fn verify_files(&self,
+                        sample_percent: Option<u32>)
+                        -> Result<VerifyFilesStats> {
+        let mut stats = VerifyFilesStats::default();
+        let head = match self.head() {
+            Some(head) => head,
+            None => return Ok(stats),
+        };
+        let tree = match self.state.subtree {
+            Some(ref path) => self.object_store.lookup_rev_path(&head, path)?,
+            None => head,
+        };
+        let algorithm = self.config().hash_algorithm();
+        // Randomly keyed for each run, so each run checks a different sample
+        let sampler = RandomState::new();
+        for (name, hash) in flatten_tree(&self.object_store, &tree)? {
+            let path = self.path.join(&name);
+            let meta = match path.symlink_metadata() {
+                Ok(ref meta) if meta.is_file() => meta.to_owned(),
+                _ => {
+                    stats.missing += 1;
+                    continue;
+                }
+            };
+            if self.file_store.cache.check(&path, &meta)? != Some(hash) {
+                stats.not_cached += 1;
+                continue;
+            }
+            stats.unchanged += 1;
+            if let Some(percent) = sample_percent {
+                if sampler.hash_one(&name) % 100 >= percent as u64 {
+                    continue;
+                }
+            }
+            stats.rehashed += 1;
+            let actual = self.file_store.rehash_file(&path, algorithm)?;
+            if actual != hash {
+                warn!("{} should be {}, but is now {}",
+                      path.display(),
+                      hash,
+                      actual);
+                stats.corrupt.push((PathBuf::from(name), hash));
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Commit the work dir and advance the current branch
+    ///
+    /// With `verify`, every newly stored object is re-read from disk and its
+    /// hash checked before the branch is updated. With a signer, the commit
+    /// is signed.
+    ///
+    /// The `pre-commit` hook runs first, and can stop the commit. The
+    /// `post-commit` hook runs after.
     pub fn commit(&mut self,
-                  message: String)
-                  -> Result<(Option<&str>, ObjectKey)> {
+                  message: String,
+                  verify: bool,
+                  signer: Option<&Signer>)
+                  -> Result<CommitOutcome> {
+        self.commit_paths(message, &Pathspec::all(), verify, signer)
+    }
+
+    /// Like `commit`, but only commit paths matching a pathspec
+    ///
+    /// All other paths keep their version from the parent.
+    pub fn commit_paths(&mut self,
+                        message: String,
+                        pathspec: &Pathspec,
+                        verify: bool,
+                        signer: Option<&Signer>)
+                        -> Result<CommitOutcome> {
+        let parents = self.parents().to_owned();
+        self.commit_with_parents(parents, message, pathspec, verify, signer)
+    }
+
+    /// Replace the last commit with a commit of the current files
+    ///
+    /// The new commit has the same parents as the one it replaces, and the
+    /// same message unless a new one is given. The branch, or HEAD if
+    /// detached, moves to the new commit, and the move is recorded in the
+    /// reflog so that the old commit can be found again. Only paths matching
+    /// the pathspec are changed.
+    pub fn amend(&mut self,
+                 message: Option<String>,
+                 pathspec: &Pathspec,
+                 verify: bool,
+                 signer: Option<&Signer>)
+                 -> Result<CommitOutcome> {
+        if self.parents().len() > 1 {
+            bail!("Cannot amend during a merge. Commit the merge first");
+        }
+        let old = self.head().ok_or_else(|| Error::from("No commit to amend"))?;
+        let old_commit = self.open_commit(&old)?;
+        let message = message.unwrap_or(old_commit.message);
+        let outcome = self.commit_with_parents(old_commit.parents,
+                                               message,
+                                               pathspec,
+                                               verify,
+                                               signer)?;
+        let ref_name = outcome.branch.clone().unwrap_or("HEAD".to_owned());
+        self.object_store
+            .record_reflog(&ref_name, Some(old), outcome.hash, "amend")?;
+        Ok(outcome)
+    }
+
+    fn commit_with_parents(&mut self,
+                           parents: Vec<ObjectKey>,
+                           message: String,
+                           pathspec: &Pathspec,
+                           verify: bool,
+                           signer: Option<&Signer>)
+                           -> Result<CommitOutcome> {
+        let hooks = self.hooks();
+        hooks.run(Hook::PreCommit, &[], self.head().as_ref(), self.branch())
+            .chain_err(|| "Commit abandoned")?;
 
         let abs_path = self.path().to_owned();
         let parent_commit = self.parent();
@@ -227,7 +1092,8 @@ impl WorkDir {
             (Some(parent_commit), &None) => Some(parent_commit),
             (None, _) => None,
         };
-        let mut tree_hash = self.hash_obj_file(parent_tree, &abs_path)?;
+        let mut tree_hash =
+            self.hash_obj_file(parent_tree, &abs_path, pathspec)?;
 
         // Patch subtree into parent commit if necessary
         if let (Some(parent_commit), &Some(ref path)) = (parent_commit,
@@ -246,78 +1112,313 @@ impl WorkDir {
             }
         }
 
-        let commit = Commit {
+        let mut commit = Commit {
             tree: tree_hash,
-            parents: self.parents().to_owned(),
+            parents: parents,
             message: message,
+            time: Commit::current_time(),
+            signature: None,
         };
+        if let Some(signer) = signer {
+            signing::sign_commit(&mut commit, signer)?;
+        }
         let hash = self.store_object(&commit)?;
-        self.state.parents = vec![hash];
-        if let Some(branch) = self.state.branch.clone() {
-            self.update_ref(branch, hash)?;
+        let mut verified = None;
+        if verify {
+            let old_tree = parent_commit.and_then_try(|parent| {
+                    self.open_commit(&parent).map(|commit| commit.tree)
+                })?;
+            let count = self.verify_new_tree(&commit.tree, old_tree.as_ref())?;
+            self.verify_object(&hash)?;
+            verified = Some(count + 1);
         }
-        self.state.flush()?;
-        Ok((self.branch(), hash))
+        match self.head_ref() {
+            Some(Head::Branch(branch)) => {
+                self.update_ref(branch, hash)?;
+                self.update_state(Some(StateEvent::Commit),
+                                  |state| state.parents = vec![hash])?;
+            }
+            _ => {
+                self.update_state(Some(StateEvent::Commit),
+                                  |state| state.set_head(Head::Detached(hash)))?
+            }
+        }
+        hooks.run_after(Hook::PostCommit, &[], Some(&hash), self.branch());
+        Ok(CommitOutcome {
+            branch: self.branch().map(|b| b.to_owned()),
+            hash: hash,
+            verified: verified,
+            size_warnings: Vec::new(),
+        })
+    }
+
+    /// Files with local changes that checking out a revision would lose
+    ///
+    /// Checkout makes the work dir match the revision, so a new or modified
+    /// file is overwritten or removed unless the revision has the same
+    /// content. Files that may have changed are hashed to be sure.
+    pub fn checkout_conflicts(&mut self,
+                              rev: &RevSpec)
+                              -> Result<Vec<PathBuf>> {
+        let (tree, commit, _) = self.object_store.lookup(rev)?;
+        if self.state.parents == [commit] && self.state.subtree == rev.path {
+            return Ok(Vec::new());
+        }
+        let algorithm = self.config().hash_algorithm();
+        let mut conflicts = Vec::new();
+        let options = StatusOptions::default();
+        for entry in self.status(&options, None, None)? {
+            if entry.is_dir {
+                continue;
+            }
+            match entry.status.first() {
+                Some(&Status::Add) |
+                Some(&Status::Modified) |
+                Some(&Status::MaybeModified) => {}
+                _ => continue,
+            }
+            let abs_path = self.path.join(&entry.path);
+            let hash = match entry.hash {
+                Some(hash) => hash,
+                None => self.file_store.rehash_file(&abs_path, algorithm)?,
+            };
+            let head = self.lookup_head_path(&abs_path).ok();
+            let theirs = self.object_store
+                .lookup_rev_path(&tree, &entry.path)
+                .ok();
+            if head != Some(hash) && theirs != Some(hash) {
+                conflicts.push(entry.path);
+            }
+        }
+        Ok(conflicts)
     }
 
-    pub fn checkout(&mut self, rev: &RevSpec) -> Result<()> {
+    /// Check out a revision, then run the `post-checkout` hook
+    ///
+    /// Unless forced, this fails with a `CheckoutConflict` rather than lose
+    /// local changes (see `checkout_conflicts`).
+    pub fn checkout(&mut self, rev: &RevSpec, force: bool) -> Result<()> {
+        self.checkout_head(rev, false, force)
+    }
+
+    /// Check out a revision with HEAD detached, even if it names a branch
+    pub fn checkout_detached(&mut self,
+                             rev: &RevSpec,
+                             force: bool)
+                             -> Result<()> {
+        self.checkout_head(rev, true, force)
+    }
+
+    fn checkout_head(&mut self,
+                     rev: &RevSpec,
+                     detach: bool,
+                     force: bool)
+                     -> Result<()> {
+        if !force {
+            let conflicts = self.checkout_conflicts(rev)?;
+            if !conflicts.is_empty() {
+                bail!(ErrorKind::CheckoutConflict(conflicts));
+            }
+        }
         let abs_path = self.path().to_owned();
         let (tree, commit, branch) = self.object_store.lookup(&rev)?;
+        let branch = if detach { None } else { branch };
+        let old_head = self.head();
+        let mut journal = None;
         if self.state.parents != [commit] || self.state.subtree != rev.path {
-            self.fs_transfer.extract_object(&tree, &abs_path)?;
-            self.state.parents = vec![commit];
+            self.fs_transfer.check_extractable(&tree)?;
+            let old_tree = old_head.and_then_try(|head| {
+                    match self.state.subtree {
+                        Some(ref path) => {
+                            self.object_store.lookup_rev_path(&head, path)
+                        }
+                        None => Ok(head),
+                    }
+                })?;
+            let planned = self.plan_changes(old_tree, &[tree])?;
+            let op = JournalOp::Checkout {
+                tree: tree,
+                commit: commit,
+                branch: branch.clone(),
+                subtree: rev.path.clone(),
+            };
+            journal = Some(Journal::begin(self, op, planned)?);
+            self.fs_transfer.extract_checked(&tree, &abs_path)?;
         }
-        self.state.branch = branch;
-        self.state.subtree = rev.path.clone();
-        self.state.flush()?;
+        let head = match branch {
+            Some(branch) => Head::Branch(branch),
+            None => Head::Detached(commit),
+        };
+        self.update_state(Some(StateEvent::Checkout), |state| {
+            state.parents = vec![commit];
+            state.set_head(head);
+            state.subtree = rev.path.clone();
+        })?;
+        if let Some(journal) = journal {
+            journal.finish(self)?;
+        }
+        let args = [old_head.map(|h| h.to_hex()).unwrap_or_default(),
+                    commit.to_hex()];
+        self.hooks()
+            .run_after(Hook::PostCheckout, &args, Some(&commit), self.branch());
         Ok(())
     }
 
+    /// Merge any number of revisions into the work dir, in one pass
+    ///
+    /// The changes each revision made since the merge base of all of them
+    /// and HEAD (see `ObjectStore::merge_base`) are applied together, and
+    /// all of them become parents of the next commit. Files changed
+    /// differently by more than one side are left as they are and reported
+    /// as a `MergeConflict`.
     pub fn merge<'a, I: 'a>(&mut self, revs: I) -> Result<()>
         where I: Iterator<Item = &'a RevSpec>
     {
-        let prog = ProgressCounter::arc("Merging", 0);
-        let prog_clone = prog.clone();
-        let prog_thread = thread::spawn(move || std_err_watch(prog_clone));
-
-        let wd_node: Option<FileWalkNode> = Some(self.file_store
-            .lookup_node(self.path().to_path_buf())?);
-
-        for theirs in revs {
-            debug!("Three-way merging {}", theirs);
-            let (theirs, _, _) = self.object_store.lookup(theirs)?;
+        let mut theirs = Vec::new();
+        for rev in revs {
+            let (hash, _, _) = self.object_store.lookup(rev)?;
+            theirs.push(hash);
+        }
+        let mut parents = self.state.parents.clone();
+        parents.extend(theirs.iter().cloned());
+        let common = self.object_store.merge_base(&parents)?;
+
+        debug!("Merging {:?} from common ancestor {:?}", theirs, common);
+        let planned = self.plan_changes(common, &theirs)?;
+        let op = JournalOp::Merge {
+            common: common,
+            theirs: theirs.clone(),
+        };
+        let journal = Journal::begin(self, op, planned)?;
+        self.update_state(None, |state| state.parents = parents)?;
+        let result = self.apply_changes(common, &theirs, "Merging");
+        match result {
+            Ok(()) |
+            Err(Error(ErrorKind::MergeConflict(_), _)) => journal.finish(self)?,
+            Err(_) => {}
+        }
+        result
+    }
 
-            self.state.parents.push(theirs);
-            self.state.flush()?;
+    /// Paths that changing the work dir from one tree to others would write,
+    /// with their hashes in HEAD
+    ///
+    /// Without a tree to start from, every top-level entry of the others is
+    /// included. Files with local changes are left out, since they cannot be
+    /// put back from the store.
+    pub fn plan_changes(&mut self,
+                        from: Option<ObjectKey>,
+                        to: &[ObjectKey])
+                        -> Result<Vec<(PathBuf, Option<ObjectKey>)>> {
+        let mut changed = BTreeSet::new();
+        let options = StatusOptions::default();
+        for targ in to {
+            match from {
+                Some(from) => {
+                    self.status_obj_obj(&options, from, *targ, &mut |entry| {
+                            changed.insert(entry.path);
+                            Ok(())
+                        })?
+                }
+                None => {
+                    let tree = self.object_store.open_tree(targ)?;
+                    changed.extend(tree.keys().map(PathBuf::from));
+                }
+            }
+        }
+        let local = self.status(&StatusOptions::default(), None, None)?
+            .into_iter()
+            .map(|entry| entry.path)
+            .collect::<HashSet<_>>();
+        let mut planned = Vec::new();
+        for path in changed {
+            if !local.contains(&path) {
+                let old = self.lookup_head_path(&self.path.join(&path)).ok();
+                planned.push((path, old));
+            }
+        }
+        Ok(planned)
+    }
 
-            let common = self.object_store
-                .find_common_ancestor(self.state
-                    .parents
-                    .iter()
-                    .map(|h| h.to_short()))?;
+    /// Apply the changes made by a commit to the work dir, and commit them
+    ///
+    /// The commit's changes from its parent are merged into the work dir,
+    /// then committed on top of HEAD with the commit's message. Files that
+    /// both the commit and the work dir changed are left as they are in the
+    /// work dir, and reported as a `MergeConflict` without committing.
+    pub fn cherry_pick(&mut self, commit: &ObjectKey) -> Result<CommitOutcome> {
+        let picked = self.open_commit(commit)?;
+        if picked.parents.len() > 1 {
+            bail!("Cannot replay merge commit {}", commit);
+        }
+        let common = picked.parents.first().cloned();
+        self.apply_changes(common, &[*commit], "Applying")?;
+        self.commit(picked.message, false, None)
+    }
 
-            let common_node: Option<ObjectWalkNode> =
+    /// Apply the changes from `common` to each of `theirs` to the work dir
+    ///
+    /// Shows progress under the given label. Files that more than one side
+    /// changed are left as they are, and reported as a `MergeConflict`.
+    pub fn apply_changes(&self,
+                         common: Option<ObjectKey>,
+                         theirs: &[ObjectKey],
+                         label: &str)
+                         -> Result<()> {
+        // Estimate the bytes to extract up front: everything that changed
+        // between the common ancestor and each of theirs
+        let mut estimate_op = TransferEstimateOp::new();
+        for hash in theirs {
+            let common_node: Option<ComparableNode> =
                 common.and_then_try(|hash|
                                     self.object_store.lookup_node(hash))?;
-
-            let theirs_node: Option<ObjectWalkNode> = Some(self.object_store
-                .lookup_node(theirs)?);
-
-            let node = (vec![common_node, theirs_node], wd_node.clone());
-            let combo = (&self.object_store, &self.file_store);
-
-            let mut op = ThreeWayMergeWalkOp::new(&self.fs_transfer,
-                                                  self.path(),
-                                                  &*prog);
-
-            combo.walk_node(&mut op, node)?;
+            let theirs_node: Option<ComparableNode> = Some(self.object_store
+                .lookup_node(*hash)?);
+            let combo = (&self.object_store, &self.object_store);
+            combo.walk_node(&mut estimate_op, (common_node, theirs_node))?;
         }
 
+        let prog = ProgressCounter::arc(label, estimate_op.estimate());
+        let prog_clone = prog.clone();
+        let prog_thread = thread::spawn(move || std_err_watch(prog_clone));
+        let conflicts = self.merge_into_work_dir(common, theirs, &prog);
         prog.finish();
         prog_thread.join().unwrap();
+        let conflicts = conflicts?;
+        if !conflicts.is_empty() {
+            bail!(ErrorKind::MergeConflict(conflicts));
+        }
         Ok(())
     }
 
+    /// Merge the changes from `common` to each of `theirs` into the work dir
+    ///
+    /// Returns the paths changed differently on more than one side, which
+    /// are left alone.
+    fn merge_into_work_dir(&self,
+                           common: Option<ObjectKey>,
+                           theirs: &[ObjectKey],
+                           prog: &ProgressCounter)
+                           -> Result<Vec<PathBuf>> {
+        let mut object_nodes: Vec<Option<ObjectWalkNode>> = Vec::new();
+        object_nodes.push(common.and_then_try(|hash| {
+                self.object_store.lookup_node(hash)
+            })?);
+        for hash in theirs {
+            object_nodes.push(Some(self.object_store.lookup_node(*hash)?));
+        }
+        let wd_node: Option<FileWalkNode> =
+            Some(self.file_store.lookup_node(self.path().to_path_buf())?);
+
+        let node = (object_nodes, wd_node);
+        let combo = (&self.object_store, &self.file_store);
+        let mut op = ThreeWayMergeWalkOp::new(&self.fs_transfer,
+                                              self.path(),
+                                              prog);
+        combo.walk_node(&mut op, node)?;
+        Ok(op.conflicts)
+    }
+
     pub fn update_ref_to_head(&mut self,
                               ref_name: RevNameBuf)
                               -> Result<ObjectKey> {
@@ -334,103 +1435,100 @@ impl WorkDir {
         }
     }
 
-    pub fn log(&self, hash_only: bool) -> Result<()> {
-        use object_store::DepthFirstCommitSort;
-
-        debug!("First pass: sort commits");
-        let mut start_refs: Vec<ObjectKey> = self.parents().clone();
-        start_refs.extend(self.object_store.refs().iter().map(|(_, v)| v));
-        start_refs.dedup();
-
-        let mut sorted = DepthFirstCommitSort::new(&self.object_store,
-                                                   start_refs).run()?;
+    /// Names for a commit: refs pointing to it, and HEAD (or PARENTn when
+    /// merging) if it is checked out
+    ///
+    /// When HEAD is on a branch, the two are shown together as
+    /// `HEAD -> branch`.
+    pub fn ref_names(&self, hash: &ObjectKey) -> Vec<String> {
+        let mut refs = self.object_store.refs_for(hash);
+        let parent_ref_name = self.parents()
+            .iter()
+            .enumerate()
+            .filter(|&(_, head_hash)| head_hash == hash)
+            .map(|(i, _)| i)
+            .take(1)
+            .next()
+            .map(|p| match (self.parents().len(), self.branch()) {
+                (1, Some(branch)) if refs.iter().any(|r| r == branch) => {
+                    refs.retain(|r| r != branch);
+                    format!("HEAD -> {}", branch)
+                }
+                (1, _) => "HEAD".to_owned(),
+                _ => format!("PARENT{}", p),
+            });
+        if let Some(s) = parent_ref_name {
+            refs.insert(0, s);
+        }
+        refs
+    }
+}
 
-        debug!("Second pass: print");
+/// Draws history as an ASCII graph, one commit at a time, newest first
+///
+/// History must be complete, with every commit's parents coming after it,
+/// or the graph will have loose ends.
+#[derive(Debug,Clone,Default)]
+pub struct LogGraph {
+    slots: Vec<ObjectKey>,
+}
 
-        if hash_only {
-            for (hash, _) in sorted {
-                println!("{:x}", hash);
-            }
-            return Ok(());
+impl LogGraph {
+    pub fn new() -> Self { LogGraph::default() }
+
+    /// Print a commit's line, with the graph lines before and after it
+    pub fn print_commit(&mut self,
+                        hash: &ObjectKey,
+                        parents: &[ObjectKey],
+                        line: &str) {
+        let slots = &mut self.slots;
+        if !slots.contains(hash) {
+            slots.push(*hash);
         }
 
-        let mut slots = Vec::new();
-        while let Some((hash, commit)) = sorted.pop() {
-            if !slots.contains(&hash) {
-                slots.push(hash);
-            }
+        let search = linear_search(slots, hash);
+        let slot = match search.len() {
+            1 => search[0],
+            _ => panic!("Unexpected. Hash: {}, Slots: {:?}", hash, slots),
+        };
 
-            let search = linear_search(&slots, &hash);
-            let slot = match search.len() {
-                1 => search[0],
-                _ => panic!("Unexpected. Hash: {}, Slots: {:?}", hash, slots),
-            };
+        LogDraw::print_ascii(&LogDraw::commit_pat(slots.len(), slot));
+        println!("{}", line);
 
-            LogDraw::print_ascii(&LogDraw::commit_pat(slots.len(), slot));
-
-            let mut refs = self.object_store.refs_for(&hash);
-            let parent_ref_name = self.parents()
-                .iter()
-                .enumerate()
-                .filter(|&(_, head_hash)| head_hash == &hash)
-                .map(|(i, _)| i)
-                .take(1)
-                .next()
-                .map(|p| match self.parents().len() {
-                    1 => "HEAD".to_owned(),
-                    _ => format!("PARENT{}", p),
-                });
-            if let Some(s) = parent_ref_name {
-                refs.insert(0, s);
-            }
-            match refs.len() {
-                0 => println!("{} {}", hash, commit.message),
-                _ => {
-                    println!("{} ({}) {}",
-                             hash,
-                             refs.join(", "),
-                             commit.message)
-                }
+        match parents.len() {
+            0 => {
+                // Dead end
+                let transition = LogDraw::dead_end_pat(slots.len(), slot);
+                LogDraw::println_ascii(&transition);
+                slots.remove(slot);
             }
-
-
-            match commit.parents.len() {
-                0 => {
-                    // Dead end
-                    let transition = LogDraw::dead_end_pat(slots.len(), slot);
-                    LogDraw::println_ascii(&transition);
-                    slots.remove(slot);
-                }
-                1 => {
-                    slots[slot] = commit.parents[0];
-                }
-                _ => {
-                    slots[slot] = commit.parents[0];
-                    for (i, parent) in commit.parents[1..].iter().enumerate() {
-                        // Expand
-                        slots.insert(slot + i, *parent);
-                        let transition = LogDraw::expand_pat(slots.len(), slot);
-                        LogDraw::println_ascii(&transition);
-                    }
-                }
+            1 => {
+                slots[slot] = parents[0];
             }
-            // Possible contractions
-            let mut i = 0;
-            while i < slots.len() {
-                let search = linear_search(&slots, &slots[i]);
-                if search.len() == 2 {
-                    let transition = LogDraw::contract_pat(slots.len(),
-                                                           search[0],
-                                                           search[1]);
+            _ => {
+                slots[slot] = parents[0];
+                for (i, parent) in parents[1..].iter().enumerate() {
+                    // Expand
+                    slots.insert(slot + i, *parent);
+                    let transition = LogDraw::expand_pat(slots.len(), slot);
                     LogDraw::println_ascii(&transition);
-                    slots.remove(search[1]);
-                } else {
-                    i += 1;
                 }
             }
         }
-
-        Ok(())
+        // Possible contractions
+        let mut i = 0;
+        while i < slots.len() {
+            let search = linear_search(slots, &slots[i]);
+            if search.len() == 2 {
+                let transition = LogDraw::contract_pat(slots.len(),
+                                                       search[0],
+                                                       search[1]);
+                LogDraw::println_ascii(&transition);
+                slots.remove(search[1]);
+            } else {
+                i += 1;
+            }
+        }
     }
 }
 
@@ -548,11 +1646,50 @@ impl LogDraw {
 }
 
 
+/// Describe how long ago something happened, in the largest whole unit
+pub fn describe_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    let (count, unit) = match secs {
+        s if s < 60 => (s, "second"),
+        s if s < 60 * 60 => (s / 60, "minute"),
+        s if s < 24 * 60 * 60 => (s / (60 * 60), "hour"),
+        s => (s / (24 * 60 * 60), "day"),
+    };
+    match count {
+        1 => format!("1 {}", unit),
+        n => format!("{} {}s", n, unit),
+    }
+}
+
 
 #[cfg(test)]
-mod test {
+pub mod test {
+    use constants::CACHE_FILE_NAME;
+    use dag::Blob;
+    use dag::ObjectCommon;
+    use rolling_hash::ChunkParams;
     use rustc_serialize::json;
+    use std::fs;
     use super::*;
+    use testutil;
+    use testutil::tempdir::TempDir;
+
+    pub fn create_temp_work_dir() -> Result<(TempDir, PathBuf, WorkDir)> {
+        let temp = in_mem_tempdir!();
+        let wd_path = temp.path().join("wd");
+        let wd = try!(WorkDir::init(RepoLayout::in_work_dir(wd_path.clone()),
+                                    RepoConfig::default()));
+
+        Ok((temp, wd_path, wd))
+    }
+
+    pub fn write_wd_file(wd_path: &Path, name: &str, content: &str) {
+        testutil::write_file(&wd_path.join(name), content).unwrap();
+    }
+
+    pub fn read_wd_file(wd_path: &Path, name: &str) -> String {
+        testutil::read_file_to_string(&wd_path.join(name)).unwrap()
+    }
 
     #[test]
     fn test_serialize_work_dir_state() {
@@ -563,4 +1700,517 @@ mod test {
         let decoded: WorkDirState = json::decode(&encoded).unwrap();
         assert_eq!(decoded, obj);
     }
+
+    #[test]
+    fn test_clone_repo() {
+        let temp = in_mem_tempdir!();
+        let src_path = temp.path().join("src");
+        let dest_path = temp.path().join("dest");
+
+        let config = RepoConfig {
+            chunking: ChunkParams::for_target_size(4 * 1024),
+            ..RepoConfig::default()
+        };
+        let mut src = WorkDir::init(RepoLayout::in_work_dir(src_path.clone()),
+                                    config.clone())
+            .unwrap();
+        testutil::write_file(&src_path.join("foo"), "foo").unwrap();
+        testutil::write_file(&src_path.join("bar/baz"), "baz").unwrap();
+        let commit =
+            src.commit("Initial".to_owned(), false, None).unwrap().hash;
+
+        let (dest, result) =
+            WorkDir::clone_repo(src_path.to_str().unwrap(),
+                                RepoLayout::in_work_dir(dest_path.clone()),
+                                None,
+                                None)
+                .unwrap();
+        assert!(result.shallow.is_empty());
+        assert_eq!(dest.config().chunking,
+                   config.chunking,
+                   "Clone should chunk files the same way as its source");
+        assert_eq!(dest.branch(), Some(DEFAULT_BRANCH_NAME));
+        assert_eq!(dest.head(), Some(commit));
+        let tracking = transport::tracking_ref_name("origin", "master");
+        assert_eq!(dest.refs().get(&tracking), Some(&commit));
+        assert_eq!(testutil::read_file_to_string(&dest_path.join("bar/baz"))
+                       .unwrap(),
+                   "baz");
+        assert_eq!(dest.remotes()["origin"].branch,
+                   Some("master".to_owned()),
+                   "Checked-out branch should be recorded for origin");
+        assert!(dest.state.last(StateEvent::Checkout).is_some());
+        assert!(dest.state.last(StateEvent::Sync).is_some());
+    }
+
+    #[test]
+    fn test_prime_cache() {
+        let (_temp, wd_path, mut wd) = create_temp_work_dir().unwrap();
+        write_wd_file(&wd_path, "foo", "foo");
+        write_wd_file(&wd_path, "bar/baz", "baz");
+        write_wd_file(&wd_path, "qux", "qux");
+        wd.commit("Initial".to_owned(), false, None).unwrap();
+        drop(wd);
+
+        // As if copied to a new disk
+        fs::remove_file(wd_path.join(CACHE_FILE_NAME)).unwrap();
+        fs::remove_file(wd_path.join("bar").join(CACHE_FILE_NAME)).unwrap();
+        write_wd_file(&wd_path, "bar/baz", "BAZ");
+        fs::remove_file(wd_path.join("qux")).unwrap();
+
+        let mut wd = WorkDir::open(RepoLayout::in_work_dir(wd_path.clone()))
+            .unwrap();
+        assert_eq!(wd.prime_cache().unwrap(),
+                   PrimeCacheStats {
+                       primed: 1,
+                       already_cached: 0,
+                       changed: 1,
+                       missing: 1,
+                   });
+        assert!(wd.file_store
+                    .cache
+                    .check(&wd_path.join("foo"),
+                           &wd_path.join("foo").metadata().unwrap())
+                    .unwrap()
+                    .is_some());
+        assert_eq!(wd.prime_cache().unwrap(),
+                   PrimeCacheStats {
+                       primed: 0,
+                       already_cached: 1,
+                       changed: 1,
+                       missing: 1,
+                   });
+    }
+
+    #[test]
+    fn test_move_and_remove() {
+        let (_temp, wd_path, mut wd) = create_temp_work_dir().unwrap();
+        write_wd_file(&wd_path, "foo", "foo");
+        write_wd_file(&wd_path, "bar/baz", "baz");
+        wd.commit("Initial".to_owned(), false, None).unwrap();
+        let cached = |wd: &WorkDir, path: &str| {
+            let path = wd_path.join(path);
+            wd.file_store.cache.check(&path, &path.metadata().unwrap()).unwrap()
+        };
+        let foo_hash = cached(&wd, "foo");
+        assert!(foo_hash.is_some());
+
+        // Moved into a directory, with its hash still cached
+        let moved = wd.move_path(&wd_path.join("foo"), &wd_path.join("bar"))
+            .unwrap();
+        assert_eq!(moved, PathBuf::from("bar/foo"));
+        assert!(!wd_path.join("foo").exists());
+        assert_eq!(cached(&wd, "bar/foo"), foo_hash);
+        assert!(wd.move_path(&wd_path.join("bar"), &wd_path.join("bar/sub"))
+            .is_err());
+        assert!(wd.move_path(&wd_path.join(".dmv"), &wd_path.join("x"))
+            .is_err());
+
+        // Only content that is stored can be removed without force
+        write_wd_file(&wd_path, "bar/new", "new");
+        match wd.remove_path(&wd_path.join("bar"), false) {
+            Err(Error(ErrorKind::UnsavedChanges(path, 1), _)) => {
+                assert_eq!(path, "bar/new")
+            }
+            other => panic!("Expected UnsavedChanges, got {:?}", other),
+        }
+        fs::remove_file(wd_path.join("bar").join(CACHE_FILE_NAME)).unwrap();
+        wd.file_store.cache.flush();
+        wd.remove_path(&wd_path.join("bar/foo"), false).unwrap();
+        assert!(!wd_path.join("bar/foo").exists());
+
+        write_wd_file(&wd_path, "bar/baz", "changed");
+        match wd.remove_path(&wd_path.join("bar"), false) {
+            Err(Error(ErrorKind::UnsavedChanges(_, 2), _)) => (),
+            other => panic!("Expected UnsavedChanges, got {:?}", other),
+        }
+        wd.remove_path(&wd_path.join("bar"), true).unwrap();
+        assert!(!wd_path.join("bar").exists());
+    }
+
+    #[test]
+    fn test_clean() {
+        let temp = in_mem_tempdir!();
+        let wd_path = temp.path().join("wd");
+        write_wd_file(&wd_path, ".dmvignore", "build\n");
+        let mut wd = WorkDir::init(RepoLayout::in_work_dir(wd_path.clone()),
+                                   RepoConfig::default())
+            .unwrap();
+        write_wd_file(&wd_path, "foo", "foo");
+        write_wd_file(&wd_path, "bar/baz", "baz");
+        wd.commit("Initial".to_owned(), false, None).unwrap();
+        for path in &["new", "bar/new", "build/out", "junk/a", "junk/b",
+                      "mixed/c", "mixed/build/out"] {
+            write_wd_file(&wd_path, path, path);
+        }
+
+        let mut clean = |dirs, ignored, force| {
+            wd.clean(dirs, ignored, force)
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.path.to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(clean(false, false, false), vec!["bar/new", "new"]);
+        assert_eq!(clean(false, true, false), vec!["bar/new", "new"]);
+        assert_eq!(clean(true, false, false),
+                   vec!["bar/new", "junk", "mixed/c", "new"]);
+        assert_eq!(clean(true, true, false),
+                   vec!["bar/new", "build", "junk", "mixed", "new"]);
+
+        clean(true, false, true);
+        assert!(!wd_path.join("junk").exists());
+        assert!(!wd_path.join("mixed/c").exists());
+        assert!(wd_path.join("mixed/build/out").exists());
+        assert!(wd_path.join("build/out").exists());
+        assert!(wd_path.join("foo").exists());
+        assert!(wd_path.join(".dmv").exists());
+        assert_eq!(clean(true, false, false), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_empty_dirs() {
+        let (temp, wd_path, mut wd) = create_temp_work_dir().unwrap();
+        write_wd_file(&wd_path, "dir/file", "file");
+        wd.commit("Initial".to_owned(), false, None).unwrap();
+        fs::create_dir_all(wd_path.join("dir/empty")).unwrap();
+        fs::create_dir_all(wd_path.join("top/empty")).unwrap();
+        let paths = |wd: &mut WorkDir| {
+            wd.status(&StatusOptions::default(), None, None)
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(paths(&mut wd),
+                   vec!["a dir/empty/", "a top/empty/"]);
+
+        let commit = wd.commit("Empty dirs".to_owned(), false, None)
+            .unwrap()
+            .hash;
+        assert_eq!(paths(&mut wd), Vec::<String>::new());
+        let empty = wd.lookup_rev_path(&commit, Path::new("top/empty"))
+            .unwrap();
+        assert!(wd.open_tree(&empty).unwrap().is_empty());
+
+        let copy = temp.path().join("copy");
+        wd.extract_object(&commit, &copy).unwrap();
+        assert!(copy.join("dir/empty").is_dir());
+        assert!(copy.join("top/empty").is_dir());
+
+        // Removing one is a change like any other
+        fs::remove_dir(wd_path.join("top/empty")).unwrap();
+        assert_eq!(paths(&mut wd), vec!["d top/empty"]);
+    }
+
+    #[test]
+    fn test_verify_files() {
+        let (_temp, wd_path, mut wd) = create_temp_work_dir().unwrap();
+        let foo = wd_path.join("foo");
+        testutil::write_file(&foo, "foo").unwrap();
+        write_wd_file(&wd_path, "bar/baz", "baz");
+        write_wd_file(&wd_path, "qux", "qux");
+        wd.commit("Initial".to_owned(), false, None).unwrap();
+        wd.prime_cache().unwrap();
+
+        let stats = wd.verify_files(None).unwrap();
+        assert_eq!((stats.unchanged, stats.rehashed), (3, 3));
+        assert!(stats.corrupt.is_empty());
+
+        // Bit rot: the content changes, but the cache still matches
+        let hash = wd.file_store
+            .cache
+            .check(&foo, &foo.metadata().unwrap())
+            .unwrap()
+            .unwrap();
+        testutil::write_file(&foo, "fob").unwrap();
+        wd.file_store.cache.insert(foo.clone(), &foo.metadata().unwrap(), hash)
+            .unwrap();
+        fs::remove_file(wd_path.join("qux")).unwrap();
+
+        let stats = wd.verify_files(None).unwrap();
+        assert_eq!(stats,
+                   VerifyFilesStats {
+                       unchanged: 2,
+                       rehashed: 2,
+                       corrupt: vec![(PathBuf::from("foo"), hash)],
+                       not_cached: 0,
+                       missing: 1,
+                   });
+        let stats = wd.verify_files(Some(0)).unwrap();
+        assert_eq!((stats.unchanged, stats.rehashed), (2, 0));
+    }
+
+    #[test]
+    fn test_merge_conflict() {
+        let (_temp, wd_path, mut wd) = create_temp_work_dir().unwrap();
+        write_wd_file(&wd_path, "foo", "base");
+        write_wd_file(&wd_path, "bar", "base");
+        let base = wd.commit("Base".to_owned(), false, None).unwrap().hash;
+        wd.update_ref("other".to_owned(), base).unwrap();
+
+        write_wd_file(&wd_path, "foo", "ours");
+        wd.commit("Ours".to_owned(), false, None).unwrap();
+        wd.checkout(&"other".parse().unwrap(), false).unwrap();
+        write_wd_file(&wd_path, "foo", "theirs");
+        write_wd_file(&wd_path, "bar", "theirs");
+        wd.commit("Theirs".to_owned(), false, None).unwrap();
+        wd.checkout(&"master".parse().unwrap(), false).unwrap();
+
+        match wd.merge(vec!["other".parse().unwrap()].iter()) {
+            Err(Error(ErrorKind::MergeConflict(paths), _)) => {
+                assert_eq!(paths, vec![PathBuf::from("foo")])
+            }
+            other => panic!("Expected MergeConflict, got {:?}", other),
+        }
+        assert_eq!(read_wd_file(&wd_path, "foo"),
+                   "ours",
+                   "Conflicting file should be left as it was");
+    }
+
+    #[test]
+    fn test_checkout_conflicts() {
+        let (_temp, wd_path, mut wd) = create_temp_work_dir().unwrap();
+        write_wd_file(&wd_path, "foo", "base");
+        write_wd_file(&wd_path, "bar", "base");
+        let base = wd.commit("Base".to_owned(), false, None).unwrap().hash;
+        wd.update_ref("other".to_owned(), base).unwrap();
+        write_wd_file(&wd_path, "foo", "next");
+        wd.commit("Next".to_owned(), false, None).unwrap();
+        let other = "other".parse().unwrap();
+        assert!(wd.checkout_conflicts(&other).unwrap().is_empty());
+
+        // Changed files would be overwritten, and new files removed
+        write_wd_file(&wd_path, "bar", "mine");
+        write_wd_file(&wd_path, "new", "mine");
+        assert_eq!(wd.checkout_conflicts(&other).unwrap(),
+                   vec![PathBuf::from("bar"), PathBuf::from("new")]);
+
+        // Unless the other revision has the same content
+        write_wd_file(&wd_path, "bar", "base");
+        write_wd_file(&wd_path, "foo", "base");
+        assert_eq!(wd.checkout_conflicts(&other).unwrap(),
+                   vec![PathBuf::from("new")]);
+        assert!(wd.checkout_conflicts(&"master".parse().unwrap())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_head_transitions() {
+        let (_temp, wd_path, mut wd) = create_temp_work_dir().unwrap();
+        let master = Head::Branch("master".to_owned());
+        assert_eq!(wd.head_ref(), Some(master.clone()));
+        assert!(wd.detach_head().is_err(), "No commit to detach at");
+
+        write_wd_file(&wd_path, "foo", "one");
+        let one = wd.commit("One".to_owned(), false, None).unwrap().hash;
+        assert_eq!(wd.head_ref(), Some(master.clone()));
+        assert_eq!(wd.ref_names(&one), vec!["HEAD -> master"]);
+
+        // Commits on a detached HEAD leave the branch behind
+        wd.checkout(&one.to_hex().parse().unwrap(), false).unwrap();
+        assert_eq!(wd.head_ref(), Some(Head::Detached(one)));
+        assert_eq!(wd.ref_names(&one), vec!["HEAD", "master"]);
+        write_wd_file(&wd_path, "foo", "two");
+        let two = wd.commit("Two".to_owned(), false, None).unwrap().hash;
+        assert_eq!(wd.head_ref(), Some(Head::Detached(two)));
+        assert_eq!(wd.head_ref().unwrap().to_string(),
+                   format!("HEAD detached at {}", two));
+        assert_eq!(wd.try_find_ref("master"), Some(one));
+
+        wd.attach_head("topic".to_owned()).unwrap();
+        assert_eq!(wd.head_ref(), Some(Head::Branch("topic".to_owned())));
+        assert_eq!(wd.try_find_ref("topic"), Some(two));
+        assert_eq!(wd.parents(), &vec![two]);
+
+        wd.checkout(&"master".parse().unwrap(), false).unwrap();
+        assert_eq!(wd.head_ref(), Some(master));
+        wd.detach_head().unwrap();
+        assert_eq!(wd.head_ref(), Some(Head::Detached(one)));
+
+        wd.checkout_detached(&"topic".parse().unwrap(), false).unwrap();
+        assert_eq!(wd.head_ref(), Some(Head::Detached(two)));
+        assert_eq!(wd.parents(), &vec![two]);
+    }
+
+    #[test]
+    fn test_lookup_head() {
+        let (_temp, wd_path, mut wd) = create_temp_work_dir().unwrap();
+        let lookup = |wd: &WorkDir, rev: &str| wd.lookup(&rev.parse().unwrap());
+        assert_match!(lookup(&wd, "HEAD").unwrap_err().root_kind(),
+                      &ErrorKind::RefNotFound(ref name) if name == "HEAD");
+
+        write_wd_file(&wd_path, "dir/foo", "one");
+        let one = wd.commit("One".to_owned(), false, None).unwrap().hash;
+        write_wd_file(&wd_path, "dir/foo", "two");
+        let two = wd.commit("Two".to_owned(), false, None).unwrap().hash;
+
+        assert_eq!(lookup(&wd, "HEAD").unwrap(),
+                   (two, two, Some("master".to_owned())));
+        assert_eq!(lookup(&wd, "HEAD~1").unwrap(), (one, one, None));
+        assert_eq!(lookup(&wd, "HEAD~:dir/foo").unwrap(),
+                   (Blob::from("one").calculate_hash(), one, None));
+
+        // A detached HEAD has no branch, and a store alone knows no HEAD
+        // unless it is given one from the work dir's state
+        wd.checkout(&one.to_hex().parse().unwrap(), false).unwrap();
+        assert_eq!(lookup(&wd, "HEAD").unwrap(), (one, one, None));
+        let osd = wd_path.join(HIDDEN_DIR_NAME);
+        let mut store = ObjectStore::open(osd.clone()).unwrap();
+        assert!(store.lookup(&"HEAD".parse().unwrap()).is_err());
+        store.set_head(WorkDir::read_head(&osd).unwrap());
+        assert_eq!(store.lookup(&"HEAD".parse().unwrap()).unwrap().0, one);
+    }
+
+    #[test]
+    fn test_octopus_merge() {
+        let (_temp, wd_path, mut wd) = create_temp_work_dir().unwrap();
+        for name in &["a", "b", "c", "shared"] {
+            write_wd_file(&wd_path, name, "base");
+        }
+        let base = wd.commit("Base".to_owned(), false, None).unwrap().hash;
+        wd.update_ref("one".to_owned(), base).unwrap();
+        wd.update_ref("two".to_owned(), base).unwrap();
+        write_wd_file(&wd_path, "c", "ours");
+        let ours = wd.commit("Ours".to_owned(), false, None).unwrap().hash;
+
+        // Both branches change `shared` the same way, which is no conflict
+        wd.checkout(&"one".parse().unwrap(), false).unwrap();
+        write_wd_file(&wd_path, "a", "one");
+        write_wd_file(&wd_path, "shared", "same");
+        let one = wd.commit("One".to_owned(), false, None).unwrap().hash;
+        wd.checkout(&"two".parse().unwrap(), false).unwrap();
+        write_wd_file(&wd_path, "b", "two");
+        write_wd_file(&wd_path, "shared", "same");
+        let two = wd.commit("Two".to_owned(), false, None).unwrap().hash;
+        wd.checkout(&"master".parse().unwrap(), false).unwrap();
+
+        assert_eq!(wd.lowest_common_ancestors(&[ours, one, two]).unwrap(),
+                   vec![base]);
+        let revs = vec!["one".parse().unwrap(), "two".parse().unwrap()];
+        wd.merge(revs.iter()).unwrap();
+        assert_eq!(wd.parents(), &[ours, one, two]);
+        assert_eq!(read_wd_file(&wd_path, "a"), "one");
+        assert_eq!(read_wd_file(&wd_path, "b"), "two");
+        assert_eq!(read_wd_file(&wd_path, "c"), "ours");
+        assert_eq!(read_wd_file(&wd_path, "shared"), "same");
+
+        let merged = wd.commit("Merge".to_owned(), false, None).unwrap().hash;
+        assert_eq!(wd.open_commit(&merged).unwrap().parents,
+                   vec![ours, one, two],
+                   "Should be one commit with all parents");
+    }
+
+    #[test]
+    fn test_criss_cross_merge() {
+        let (_temp, wd_path, mut wd) = create_temp_work_dir().unwrap();
+        let merge = |wd: &mut WorkDir, hash: ObjectKey| {
+            wd.merge(vec![hash.to_hex().parse().unwrap()].iter())
+        };
+        write_wd_file(&wd_path, "f", "a");
+        write_wd_file(&wd_path, "g", "a");
+        let base = wd.commit("Base".to_owned(), false, None).unwrap().hash;
+        wd.update_ref("q".to_owned(), base).unwrap();
+        write_wd_file(&wd_path, "f", "b");
+        let p = wd.commit("P".to_owned(), false, None).unwrap().hash;
+        wd.checkout(&"q".parse().unwrap(), false).unwrap();
+        write_wd_file(&wd_path, "g", "b");
+        let q = wd.commit("Q".to_owned(), false, None).unwrap().hash;
+
+        // Each branch merges the other's first commit
+        merge(&mut wd, p).unwrap();
+        wd.commit("Merge P".to_owned(), false, None).unwrap();
+        write_wd_file(&wd_path, "g", "c");
+        let y = wd.commit("Change g".to_owned(), false, None).unwrap().hash;
+        wd.checkout(&"master".parse().unwrap(), false).unwrap();
+        merge(&mut wd, q).unwrap();
+        let x = wd.commit("Merge Q".to_owned(), false, None).unwrap().hash;
+
+        assert_eq!(wd.lowest_common_ancestors(&[x, y]).unwrap().len(), 2);
+        let virtual_base = wd.merge_base(&[x, y]).unwrap().unwrap();
+        let tree = wd.open_tree(&virtual_base).unwrap();
+        assert_eq!(tree, wd.open_tree(&x).unwrap(), "Has both P and Q");
+
+        // Against P alone, the nearest of them, g would conflict
+        merge(&mut wd, y).unwrap();
+        assert_eq!(read_wd_file(&wd_path, "f"), "b");
+        assert_eq!(read_wd_file(&wd_path, "g"), "c");
+    }
+
+    #[test]
+    fn test_amend() {
+        let (_temp, wd_path, mut wd) = create_temp_work_dir().unwrap();
+        assert!(wd.amend(None, &Pathspec::all(), false, None).is_err(),
+                "Nothing to amend");
+
+        write_wd_file(&wd_path, "foo", "foo");
+        let first = wd.commit("First".to_owned(), false, None).unwrap().hash;
+        write_wd_file(&wd_path, "foo", "typo");
+        let second = wd.commit("Second".to_owned(), false, None).unwrap().hash;
+
+        write_wd_file(&wd_path, "foo", "fixed");
+        let amended = wd.amend(None, &Pathspec::all(), false, None).unwrap();
+        assert_eq!(amended.branch, Some("master".to_owned()));
+        assert_eq!(wd.head(), Some(amended.hash));
+        assert_eq!(wd.try_find_ref("master"), Some(amended.hash));
+        let commit = wd.open_commit(&amended.hash).unwrap();
+        assert_eq!(commit.parents, vec![first], "Should replace, not extend");
+        assert_eq!(commit.message, "Second");
+        assert!(wd.status(&StatusOptions::default(), None, None)
+            .unwrap()
+            .is_empty());
+
+        let entries = wd.reflog().entries("master");
+        assert_eq!(entries.len(), 1);
+        assert_eq!((entries[0].old, entries[0].new),
+                   (Some(second), amended.hash));
+
+        let reworded =
+            wd.amend(Some("Reworded".to_owned()), &Pathspec::all(), false, None)
+                .unwrap();
+        assert_eq!(wd.open_commit(&reworded.hash).unwrap().message,
+                   "Reworded");
+        assert_eq!(wd.reflog().entries("master").len(), 2);
+    }
+
+    #[test]
+    fn test_state_event_times() {
+        let (_temp, wd_path, mut wd) = create_temp_work_dir().unwrap();
+        assert_eq!(wd.state.last(StateEvent::Commit), None);
+        assert_eq!(wd.health_warnings(SystemTime::now()),
+                   vec![msg!(Msg::NoRemotes)]);
+
+        write_wd_file(&wd_path, "foo", "foo");
+        wd.commit("Initial".to_owned(), false, None).unwrap();
+        let committed = wd.state.last(StateEvent::Commit).unwrap();
+        assert_eq!(wd.state.last(StateEvent::Checkout), None);
+
+        let reopened = WorkDir::open(RepoLayout::in_work_dir(wd_path.clone()))
+            .unwrap();
+        assert_eq!(reopened.state.last(StateEvent::Commit), Some(committed),
+                   "Time should be saved");
+
+        wd.update_remote("origin".to_owned(),
+                         Remote::new("/nowhere".to_owned()))
+            .unwrap();
+        assert_eq!(wd.health_warnings(SystemTime::now()),
+                   vec![msg!(Msg::NeverSynced)]);
+
+        wd.update_state(Some(StateEvent::Sync), |_| ()).unwrap();
+        assert!(wd.health_warnings(SystemTime::now()).is_empty());
+        let later = SystemTime::now() +
+                    Duration::from_secs((SYNC_WARNING_DAYS + 2) * 24 * 60 *
+                                        60);
+        assert_eq!(wd.health_warnings(later),
+                   vec![msg!(Msg::NotSyncedFor,
+                             format!("{} days", SYNC_WARNING_DAYS + 2))]);
+    }
+
+    #[test]
+    fn test_describe_age() {
+        assert_eq!(describe_age(Duration::from_secs(1)), "1 second");
+        assert_eq!(describe_age(Duration::from_secs(150)), "2 minutes");
+        assert_eq!(describe_age(Duration::from_secs(3 * 24 * 3600)), "3 days");
+    }
 }
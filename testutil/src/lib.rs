@@ -288,6 +288,7 @@ macro_rules! in_mem_tempdir {
 /// possible. This function will...
 ///
 /// - create the file if it does not exist.
+/// - truncate the file if it does exist.
 /// - create parent directories if they do not exist.
 ///
 /// ```
@@ -325,6 +326,7 @@ pub fn write_file<P, R, S>(path: P, source: S) -> io::Result<u64>
 
     let mut file = OpenOptions::new().write(true)
         .create(true)
+        .truncate(true)
         .open(&path)
         .map_err(|e| {
             let msg = format!("Could not open/create file '{}': '{:?}'",